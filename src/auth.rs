@@ -0,0 +1,117 @@
+// Optional packet authentication with a pre-shared key. Protected commands carry a nonce and a truncated HMAC-SHA256
+// after their payload: sequence (u16), command byte, payload, nonce (u32), tag, CRC-8 (when enabled).
+// The tag covers everything in front of it, and each nonce must be larger than the last one accepted.
+use std::time::{Duration, Instant};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::protocol;
+use crate::settings::Settings;
+use crate::CONFIG;
+
+pub const NONCE_SIZE: usize = 4;
+pub const TAG_SIZE: usize = 8; // Leftmost bytes of the HMAC
+pub const AUTH_SIZE: usize = NONCE_SIZE + TAG_SIZE;
+pub const MAX_KEY_SIZE: usize = 64; // One SHA-256 block, longer keys would be hashed down anyway
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthError {
+    Missing,  // Too short to carry a nonce and tag
+    BadTag,   // Wrong key or a modified packet
+    Replayed, // Nonce not above the last one accepted
+}
+
+pub struct Authenticator {
+    key: Vec<u8>,
+    // Only kept in RAM, so clients should seed their nonce from the clock rather than count from zero
+    last_nonce: Option<u32>,
+}
+
+impl Authenticator {
+    // The key stored in NVS, falling back to the compiled one. None leaves every command unauthenticated
+    pub fn from_settings(settings: Option<&Settings>) -> Option<Authenticator> {
+        let stored = settings.and_then(|settings| settings.load_auth_key().unwrap_or(None));
+        let key = match stored {
+            Some(key) => key,
+            None if !CONFIG.auth_key.is_empty() => CONFIG.auth_key.as_bytes().to_vec(),
+            None => return None,
+        };
+        Some(Authenticator { key, last_nonce: None })
+    }
+
+    // Checks the nonce and tag of a packet whose CRC has been stripped, returning the packet without them
+    pub fn verify<'a>(&mut self, packet: &'a [u8]) -> Result<&'a [u8], AuthError> {
+        if packet.len() < protocol::HEADER_SIZE + 1 + AUTH_SIZE {
+            return Err(AuthError::Missing);
+        }
+        let (signed, tag) = packet.split_at(packet.len() - TAG_SIZE);
+        let (data, nonce) = signed.split_at(signed.len() - NONCE_SIZE);
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes keys of any length");
+        mac.update(signed);
+        mac.verify_truncated_left(tag).map_err(|_| AuthError::BadTag)?;
+
+        // Only checked once the tag is good, so a forged packet can't push the nonce forward
+        let nonce = u32::from_be_bytes([nonce[0], nonce[1], nonce[2], nonce[3]]);
+        if self.last_nonce.is_some_and(|last| nonce <= last) {
+            return Err(AuthError::Replayed);
+        }
+        self.last_nonce = Some(nonce);
+        Ok(data)
+    }
+
+    // Appends the nonce and tag to a packet, for frames this limb sends to another
+    #[cfg(not(feature = "sim"))]
+    pub fn sign(&self, packet: &mut Vec<u8>, nonce: u32) {
+        packet.extend_from_slice(&nonce.to_be_bytes());
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes keys of any length");
+        mac.update(packet);
+        let tag = mac.finalize().into_bytes();
+        packet.extend_from_slice(&tag[..TAG_SIZE]);
+    }
+
+    // Accepts any nonce next, for a sender known to have restarted
+    #[cfg(not(feature = "sim"))]
+    pub fn forget_nonce(&mut self) {
+        self.last_nonce = None;
+    }
+}
+
+// Pings, telemetry, status and e-stops stay open so clients can find the limb and anyone can stop it
+pub fn is_protected(command: u8) -> bool {
+    !matches!(
+        command,
+        protocol::PING_COMMAND
+            | protocol::TELEMETRY_COMMAND
+            | protocol::TELEMETRY_TENTHS_COMMAND
+            | protocol::STATUS_COMMAND
+            | protocol::ESTOP_COMMAND
+    )
+}
+
+// Lets a log line through at most once per interval, counting the ones it held back
+pub struct LogLimiter {
+    interval: Duration,
+    last: Option<Instant>,
+    suppressed: u32,
+}
+
+impl LogLimiter {
+    pub fn new(interval: Duration) -> LogLimiter {
+        LogLimiter { interval, last: None, suppressed: 0 }
+    }
+
+    // Some with the number suppressed since the last line when this one should be logged
+    pub fn allow(&mut self, now: Instant) -> Option<u32> {
+        match self.last {
+            Some(last) if now.duration_since(last) < self.interval => {
+                self.suppressed += 1;
+                None
+            }
+            _ => {
+                self.last = Some(now);
+                Some(std::mem::take(&mut self.suppressed))
+            }
+        }
+    }
+}
@@ -0,0 +1,144 @@
+#[cfg(not(feature = "sim"))]
+use esp_idf_hal::ledc::LedcDriver;
+#[cfg(not(feature = "sim"))]
+use esp_idf_sys::{EspError, ESP_ERR_INVALID_ARG, ESP_ERR_INVALID_STATE};
+#[cfg(not(feature = "sim"))]
+use log::{debug, info};
+
+#[cfg(not(feature = "sim"))]
+use crate::crash;
+use crate::qr::QrCode;
+
+// Error reported by a servo backend, the esp-idf error code on hardware
+#[cfg(not(feature = "sim"))]
+pub type DriverError = esp_idf_sys::EspError;
+#[cfg(feature = "sim")]
+pub type DriverError = crate::sim::SimError;
+
+// PWM output driving a single servo, mirroring the parts of LedcDriver that Servo uses
+pub trait ServoBackend {
+    fn set_duty(&mut self, duty: u32) -> Result<(), DriverError>;
+    fn get_duty(&self) -> u32;
+    fn get_max_duty(&self) -> u32;
+    // Stops the output without forgetting the duty
+    fn disable(&mut self) -> Result<(), DriverError>;
+    // Restarts the output at the stored duty
+    fn enable(&mut self) -> Result<(), DriverError>;
+}
+
+#[cfg(not(feature = "sim"))]
+impl ServoBackend for LedcDriver<'static> {
+    fn set_duty(&mut self, duty: u32) -> Result<(), DriverError> {
+        // Updating the duty restarts a channel the panic hook stopped
+        if crash::in_progress() {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+        LedcDriver::set_duty(self, duty)
+    }
+
+    fn get_duty(&self) -> u32 {
+        LedcDriver::get_duty(self)
+    }
+
+    fn get_max_duty(&self) -> u32 {
+        LedcDriver::get_max_duty(self)
+    }
+
+    fn disable(&mut self) -> Result<(), DriverError> {
+        LedcDriver::disable(self)
+    }
+
+    fn enable(&mut self) -> Result<(), DriverError> {
+        if crash::in_progress() {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+        LedcDriver::enable(self)
+    }
+}
+
+// Stands in for a servo on a board with nothing attached, "mock" in servo_backends. It drives no pin and only logs
+// what it would have output, so the firmware can be brought up on a bare dev board
+#[cfg(not(feature = "sim"))]
+pub struct LoggingMock {
+    name: String,
+    duty: u32,
+    max_duty: u32,
+}
+
+#[cfg(not(feature = "sim"))]
+impl LoggingMock {
+    // `bits` of resolution, like an LEDC timer's
+    pub fn new(name: &str, bits: u8) -> LoggingMock {
+        info!("{} is a mock, nothing is driven", name);
+        LoggingMock { name: name.to_string(), duty: 0, max_duty: (1 << bits) - 1 }
+    }
+}
+
+#[cfg(not(feature = "sim"))]
+impl ServoBackend for LoggingMock {
+    // Only changes are logged, a servo at rest is written every poll
+    fn set_duty(&mut self, duty: u32) -> Result<(), DriverError> {
+        if duty > self.max_duty {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+        }
+        if duty != self.duty {
+            debug!("{} mock duty {}", self.name, duty);
+        }
+        self.duty = duty;
+        Ok(())
+    }
+
+    fn get_duty(&self) -> u32 {
+        self.duty
+    }
+
+    fn get_max_duty(&self) -> u32 {
+        self.max_duty
+    }
+
+    fn disable(&mut self) -> Result<(), DriverError> {
+        debug!("{} mock output off", self.name);
+        Ok(())
+    }
+
+    fn enable(&mut self) -> Result<(), DriverError> {
+        debug!("{} mock output on at duty {}", self.name, self.duty);
+        Ok(())
+    }
+}
+
+// One row of the servo bar graph
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServoBar {
+    pub name: String,
+    pub angle: u16,
+    pub goal: u16, // Marked with a tick, the bar catches up with it as the move is interpolated
+    pub max: u16,  // Angle of a full bar
+}
+
+// Where each line of draw_lines goes across the display
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Align {
+    Left,
+    Center,
+}
+
+// What the control loop draws, implemented by the SSD1306 Display and by the simulator's mock
+pub trait DisplayBackend {
+    // Text with its first baseline at y, lines are broken at each newline
+    fn draw_new_text(&mut self, x: i32, y: i32, text: &str);
+    // One line per row from the top, spaced by the font's height
+    fn draw_lines(&mut self, lines: &[&str], align: Align);
+    fn draw_banner(&mut self, banner: &str, text: &str);
+    fn draw_servo_bars(&mut self, bars: &[ServoBar]);
+    // The code down the left of the display, with the caption beside it
+    fn draw_qr(&mut self, code: &QrCode, caption: &[&str]);
+    // Contrast from 0 to 255
+    fn set_brightness(&mut self, brightness: u8);
+    // Off blanks the panel without losing what was drawn, so it can sleep while idle
+    fn set_display_on(&mut self, on: bool);
+    // Turned 180 degrees for a panel mounted upside down
+    fn set_flipped(&mut self, flipped: bool);
+    // Lines of text draw_lines fits down the display, the pages are laid out to it
+    fn rows(&self) -> usize;
+}
@@ -0,0 +1,192 @@
+// Battery voltage through a divider on an ADC pin, averaged by a task of its own and checked against a cutoff
+#[cfg(not(feature = "sim"))]
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+#[cfg(not(feature = "sim"))]
+use std::thread;
+#[cfg(not(feature = "sim"))]
+use std::time::Duration;
+
+#[cfg(not(feature = "sim"))]
+use log::error;
+
+#[cfg(not(feature = "sim"))]
+use crate::backend::DriverError;
+
+#[cfg(not(feature = "sim"))]
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+pub const CONFIG_SIZE: usize = 7;
+const CONFIG_VERSION: u8 = 1; // Bump when the stored battery config layout changes
+
+// Divider and cutoff, set from the config command and kept in NVS
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BatteryConfig {
+    pub divider: u16,       // Battery millivolts per volt at the pin, so 3000 for a 3:1 divider
+    pub cutoff_mv: u16,     // 0 only monitors
+    pub hysteresis_mv: u16, // Above the cutoff the battery must recover to before motion is allowed again
+}
+
+impl BatteryConfig {
+    // Layout: version, divider, cutoff, hysteresis, all little-endian
+    pub fn to_bytes(self) -> [u8; CONFIG_SIZE] {
+        let mut bytes = [0u8; CONFIG_SIZE];
+        bytes[0] = CONFIG_VERSION;
+        bytes[1..3].copy_from_slice(&self.divider.to_le_bytes());
+        bytes[3..5].copy_from_slice(&self.cutoff_mv.to_le_bytes());
+        bytes[5..7].copy_from_slice(&self.hysteresis_mv.to_le_bytes());
+        bytes
+    }
+
+    // Returns None for blobs of the wrong size or version, or without a divider
+    pub fn from_bytes(bytes: &[u8]) -> Option<BatteryConfig> {
+        if bytes.len() != CONFIG_SIZE || bytes[0] != CONFIG_VERSION {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        let config = BatteryConfig {
+            divider: u16_at(1),
+            cutoff_mv: u16_at(3),
+            hysteresis_mv: u16_at(5),
+        };
+        match config.divider {
+            0 => None,
+            _ => Some(config),
+        }
+    }
+}
+
+// Averaged pin voltage, written by the sample task and read by the control loop
+#[derive(Clone, Default)]
+pub struct Battery {
+    pin_mv: Arc<Mutex<Option<u16>>>, // None until the first sample, and always in builds without a battery pin
+}
+
+impl Battery {
+    pub fn get_pin_mv(&self) -> Option<u16> {
+        *self.pin_mv.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    #[cfg(not(feature = "sim"))]
+    fn set_pin_mv(&self, pin_mv: u16) {
+        *self.pin_mv.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(pin_mv);
+    }
+
+    // Stands in for the sample task in the host tests
+    #[cfg(all(test, feature = "sim"))]
+    pub fn set_sampled_mv(&self, pin_mv: u16) {
+        *self.pin_mv.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(pin_mv);
+    }
+}
+
+// Reads the pin every SAMPLE_INTERVAL and publishes the mean of the last `window` readings
+#[cfg(not(feature = "sim"))]
+pub fn sample_task(mut read_mv: impl FnMut() -> Result<u16, DriverError>, battery: Battery, window: usize) {
+    let window = window.max(1);
+    let mut samples: VecDeque<u16> = VecDeque::with_capacity(window);
+    let mut failing = false;
+    loop {
+        match read_mv() {
+            Ok(pin_mv) => {
+                failing = false;
+                if samples.len() == window {
+                    samples.pop_front();
+                }
+                samples.push_back(pin_mv);
+                let total: u32 = samples.iter().map(|&sample| sample as u32).sum();
+                battery.set_pin_mv((total / samples.len() as u32) as u16);
+            }
+            // The last average stands, only the first failure is logged
+            Err(e) if !failing => {
+                error!("Failed to read the battery voltage: {}", e);
+                failing = true;
+            }
+            Err(_) => {},
+        }
+        thread::sleep(SAMPLE_INTERVAL);
+    }
+}
+
+// Low-voltage cutoff with hysteresis, so a battery sagging under load doesn't flap in and out of it
+pub struct Cutoff {
+    config: BatteryConfig,
+    low: bool,
+}
+
+impl Cutoff {
+    pub fn new(config: BatteryConfig) -> Cutoff {
+        Cutoff { config, low: false }
+    }
+
+    pub fn voltage_mv(&self, pin_mv: u16) -> u16 {
+        (pin_mv as u32 * self.config.divider as u32 / 1000).min(u16::MAX as u32) as u16
+    }
+
+    // Returns true when the battery has just crossed the cutoff in either direction
+    pub fn update(&mut self, voltage_mv: u16) -> bool {
+        let recover_mv = self.config.cutoff_mv.saturating_add(self.config.hysteresis_mv);
+        let low = match self.low {
+            _ if self.config.cutoff_mv == 0 => false,
+            true => voltage_mv < recover_mv,
+            false => voltage_mv < self.config.cutoff_mv,
+        };
+        let changed = low != self.low;
+        self.low = low;
+        changed
+    }
+
+    pub fn is_low(&self) -> bool {
+        self.low
+    }
+
+    pub fn set_config(&mut self, config: BatteryConfig) {
+        self.config = config;
+    }
+
+    pub fn get_config(&self) -> BatteryConfig {
+        self.config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: BatteryConfig = BatteryConfig { divider: 3000, cutoff_mv: 7000, hysteresis_mv: 200 };
+
+    #[test]
+    fn voltage_is_scaled_by_the_divider() {
+        let cutoff = Cutoff::new(CONFIG);
+        assert_eq!(cutoff.voltage_mv(2400), 7200);
+        assert_eq!(cutoff.voltage_mv(u16::MAX), u16::MAX);
+    }
+
+    #[test]
+    fn cutoff_trips_below_and_recovers_past_the_hysteresis() {
+        let mut cutoff = Cutoff::new(CONFIG);
+        assert!(!cutoff.update(7000));
+        assert!(cutoff.update(6999));
+        assert!(cutoff.is_low());
+        // Back over the cutoff isn't enough, it has to clear the hysteresis too
+        assert!(!cutoff.update(7100));
+        assert!(cutoff.is_low());
+        assert!(cutoff.update(7200));
+        assert!(!cutoff.is_low());
+    }
+
+    #[test]
+    fn zero_cutoff_only_monitors() {
+        let mut cutoff = Cutoff::new(BatteryConfig { cutoff_mv: 0, ..CONFIG });
+        assert!(!cutoff.update(0));
+        assert!(!cutoff.is_low());
+    }
+
+    #[test]
+    fn config_round_trips_and_refuses_bad_blobs() {
+        assert_eq!(BatteryConfig::from_bytes(&CONFIG.to_bytes()), Some(CONFIG));
+        assert_eq!(BatteryConfig::from_bytes(&BatteryConfig { divider: 0, ..CONFIG }.to_bytes()), None);
+        assert_eq!(BatteryConfig::from_bytes(&CONFIG.to_bytes()[..CONFIG_SIZE - 1]), None);
+        let mut bytes = CONFIG.to_bytes();
+        bytes[0] = CONFIG_VERSION + 1;
+        assert_eq!(BatteryConfig::from_bytes(&bytes), None);
+    }
+}
@@ -0,0 +1,193 @@
+// BLE GATT control service with pose, telemetry and info characteristics, for phones that can't send raw UDP. Poses
+// are queued like any other command, from an IPv6 link-local address made from the phone's Bluetooth address
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use esp32_nimble::utilities::mutex::Mutex as NimbleMutex;
+use esp32_nimble::{uuid128, BLECharacteristic, BLEDevice, NimbleProperties};
+use log::{error, info, warn};
+
+use crate::network::{self, Command, NO_ADDR};
+use crate::protocol::{self, ControlPacket, ReplyPacket, ReplyPayload, Status};
+use crate::{tasks, CONFIG, VERSION_MAJ, VERSION_MIN};
+
+const TELEMETRY_INTERVAL: Duration = Duration::from_secs(1);
+const EVENT_QUEUE_SIZE: usize = 8; // Writes the NimBLE host hands the BLE task, a full queue drops the newest
+const DEFAULT_NAME: &str = "Limb Controller";
+
+type Characteristic = Arc<NimbleMutex<BLECharacteristic>>;
+
+// What the NimBLE callbacks hand the BLE task, they run on the host task and mustn't wait on the control task
+enum BleEvent {
+    Connected(SocketAddr),
+    Disconnected(SocketAddr),
+    Pose(SocketAddr, Vec<u8>),
+}
+
+// Starts advertising the service, the limb carries on over WiFi alone if that fails. Poses are refused when commands
+// need authentication, a bare angle write has no tag to check
+pub fn start(commands: SyncSender<Command>, authenticated: bool) {
+    let name = match (CONFIG.ble_name, CONFIG.mdns_instance) {
+        ("", "") => DEFAULT_NAME,
+        ("", instance) => instance,
+        (name, _) => name,
+    };
+    let device = BLEDevice::take();
+    match BLEDevice::set_device_name(name) {
+        Ok(_) => {},
+        Err(e) => warn!("Failed to set the BLE device name: {:?}", e),
+    }
+    let (sender, events) = mpsc::sync_channel(EVENT_QUEUE_SIZE);
+
+    let server = device.get_server();
+    let connect_sender = sender.clone();
+    server.on_connect(move |_, desc| {
+        let _ = connect_sender.try_send(BleEvent::Connected(peer_addr(desc.address().as_be_bytes())));
+    });
+    let disconnect_sender = sender.clone();
+    server.on_disconnect(move |desc, _| {
+        let _ = disconnect_sender.try_send(BleEvent::Disconnected(peer_addr(desc.address().as_be_bytes())));
+    });
+
+    let service = server.create_service(uuid128!("6c1d0001-4a8e-4e4b-9d7c-6c696d620000"));
+    let pose = service.lock().create_characteristic(
+        uuid128!("6c1d0002-4a8e-4e4b-9d7c-6c696d620000"),
+        NimbleProperties::WRITE | NimbleProperties::WRITE_NO_RSP | NimbleProperties::NOTIFY,
+    );
+    pose.lock().on_write(move |args| {
+        let event = BleEvent::Pose(peer_addr(args.desc().address().as_be_bytes()), args.recv_data().to_vec());
+        match sender.try_send(event) {
+            Ok(_) => {},
+            Err(TrySendError::Full(_)) => warn!("BLE queue full, dropping a pose"),
+            Err(TrySendError::Disconnected(_)) => {}, // The BLE task has stopped, it logged why
+        }
+    });
+    let telemetry = service.lock().create_characteristic(
+        uuid128!("6c1d0003-4a8e-4e4b-9d7c-6c696d620000"),
+        NimbleProperties::READ | NimbleProperties::NOTIFY,
+    );
+    let info = service
+        .lock()
+        .create_characteristic(uuid128!("6c1d0004-4a8e-4e4b-9d7c-6c696d620000"), NimbleProperties::READ);
+    info.lock().set_value(
+        format!("{} firmware {}.{} protocol {}", name, VERSION_MAJ, VERSION_MIN, protocol::PROTOCOL_VERSION).as_bytes(),
+    );
+
+    let advertising = device.get_advertising();
+    let advertised = advertising
+        .lock()
+        .name(name)
+        .add_service_uuid(uuid128!("6c1d0001-4a8e-4e4b-9d7c-6c696d620000"))
+        .start();
+    match advertised {
+        Ok(_) => info!("Advertising the BLE control service as {}", name),
+        Err(e) => {
+            error!("Failed to advertise the BLE control service: {:?}", e);
+            return;
+        }
+    }
+    tasks::spawn(&tasks::BLE_TASK, move || run(events, commands, pose, telemetry, authenticated));
+}
+
+fn run(
+    events: Receiver<BleEvent>,
+    commands: SyncSender<Command>,
+    pose: Characteristic,
+    telemetry: Characteristic,
+    authenticated: bool,
+) {
+    let mut connected: Vec<(SocketAddr, bool)> = Vec::new(); // Each phone and whether it has claimed the session
+    let mut last_telemetry = Instant::now();
+    loop {
+        match events.recv_timeout(TELEMETRY_INTERVAL.saturating_sub(last_telemetry.elapsed())) {
+            Ok(BleEvent::Connected(addr)) => {
+                info!("BLE client {} connected", addr);
+                connected.push((addr, false));
+            }
+            Ok(BleEvent::Disconnected(addr)) => {
+                info!("BLE client {} disconnected", addr);
+                // Its claim goes with it, rather than holding UDP clients off for the session timeout
+                if connected.iter().any(|&(client, claimed)| client == addr && claimed) {
+                    match network::request(&commands, addr, ControlPacket::Release) {
+                        Ok(_) => {},
+                        Err(e) => warn!("Failed to release the session held by BLE client {}: {:?}", addr, e),
+                    }
+                }
+                connected.retain(|(client, _)| *client != addr);
+            }
+            Ok(BleEvent::Pose(addr, angles)) => {
+                let reply = handle_pose(&commands, &mut connected, addr, &angles, authenticated);
+                pose.lock().set_value(&network::encode_reply(0, &reply)).notify();
+            }
+            Err(RecvTimeoutError::Timeout) => {},
+            Err(RecvTimeoutError::Disconnected) => {
+                error!("BLE callbacks dropped, stopping the BLE task");
+                return;
+            }
+        }
+        if last_telemetry.elapsed() < TELEMETRY_INTERVAL {
+            continue;
+        }
+        last_telemetry = Instant::now();
+        if connected.is_empty() {
+            continue;
+        }
+        match network::request(&commands, NO_ADDR, ControlPacket::Telemetry { tenths: false }) {
+            Ok(reply) => {
+                telemetry.lock().set_value(&network::encode_reply(0, &reply)).notify();
+            }
+            Err(e) => warn!("No telemetry to notify: {:?}", e),
+        }
+    }
+}
+
+// Claims the session on the phone's first pose, then queues the move under the phone's address
+fn handle_pose(
+    commands: &SyncSender<Command>,
+    connected: &mut [(SocketAddr, bool)],
+    addr: SocketAddr,
+    angles: &[u8],
+    authenticated: bool,
+) -> ReplyPacket {
+    if authenticated {
+        return ReplyPacket::new(protocol::MOVE_COMMAND, Status::Unauthorized, ReplyPayload::Empty);
+    }
+    if angles.len() != protocol::SERVO_COUNT * 2 {
+        return ReplyPacket::new(protocol::MOVE_COMMAND, Status::BadLength, ReplyPayload::Empty);
+    }
+    if let Some((_, claimed)) = connected.iter_mut().find(|(client, claimed)| *client == addr && !*claimed) {
+        match network::request(commands, addr, ControlPacket::Claim) {
+            Ok(reply) if reply.status == Status::Ok => *claimed = true,
+            Ok(reply) => return reply,
+            Err(e) => {
+                warn!("BLE client {} couldn't claim the session: {:?}", addr, e);
+                return ReplyPacket::new(protocol::CLAIM_COMMAND, Status::Busy, ReplyPayload::Empty);
+            }
+        }
+    }
+    match network::request(commands, addr, ControlPacket::SetAngles(protocol::decode_angles(angles))) {
+        Ok(reply) => reply,
+        Err(e) => {
+            warn!("BLE pose from {} got no answer: {:?}", addr, e);
+            ReplyPacket::new(protocol::MOVE_COMMAND, Status::Busy, ReplyPayload::Empty)
+        }
+    }
+}
+
+// fe80:: with the Bluetooth address as the interface identifier, FFFE in the middle
+fn peer_addr(bluetooth: [u8; 6]) -> SocketAddr {
+    let [a, b, c, d, e, f] = bluetooth;
+    let ip = Ipv6Addr::new(
+        0xfe80,
+        0,
+        0,
+        0,
+        u16::from_be_bytes([a, b]),
+        u16::from_be_bytes([c, 0xff]),
+        u16::from_be_bytes([0xfe, d]),
+        u16::from_be_bytes([e, f]),
+    );
+    SocketAddr::new(IpAddr::V6(ip), 0)
+}
@@ -0,0 +1,19 @@
+// ESP32 DevKitC, the original limb. The display and any PCA9685 are on the usual I2C pins, the servos along the
+// right-hand header
+use super::BoardProfile;
+
+pub const PROFILE: BoardProfile = BoardProfile {
+    name: "ESP32 DevKitC",
+    sda: 21,
+    scl: 22,
+    servo_pins: &[15, 16, 17, 18, 19],
+    gripper_pin: 23,
+    status_led_pin: 4,
+    estop_pin: 0,
+    button_pin: 0,
+    buzzer_pin: 0,
+    battery_pin: 0,
+    serial_tx_pin: 1, // UART0's own pins
+    serial_rx_pin: 3,
+    adc_pins: &[32, 33, 34, 35, 36, 37, 38, 39],
+};
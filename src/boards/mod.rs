@@ -0,0 +1,109 @@
+// Where things are wired on each board, one file per board picked by its board-* feature, the ESP32 devkit without
+// one. A pin set in cfg.toml takes precedence over the board's, so a one-off change needs no board of its own
+#[cfg(all(feature = "board-esp32-devkit", feature = "board-s3-custom"))]
+compile_error!("Only one board-* feature can be enabled");
+
+#[cfg(not(feature = "board-s3-custom"))]
+mod esp32_devkit;
+#[cfg(not(feature = "board-s3-custom"))]
+pub use esp32_devkit::PROFILE;
+
+#[cfg(feature = "board-s3-custom")]
+mod s3_custom;
+#[cfg(feature = "board-s3-custom")]
+pub use s3_custom::PROFILE;
+
+use crate::CONFIG;
+
+// Pins are GPIO numbers, 0 for something the board doesn't have
+pub struct BoardProfile {
+    pub name: &'static str,
+    #[cfg_attr(feature = "sim", allow(dead_code))] // The simulator has no bus
+    pub sda: i32,
+    #[cfg_attr(feature = "sim", allow(dead_code))]
+    pub scl: i32,
+    pub servo_pins: &'static [i32], // Each joint's LEDC channel in joint order, a joint past the end has none
+    pub gripper_pin: i32,
+    pub status_led_pin: u8,
+    pub estop_pin: u8,
+    pub button_pin: u8,
+    pub buzzer_pin: u8,
+    pub battery_pin: u8,
+    pub serial_tx_pin: u8,
+    pub serial_rx_pin: u8,
+    pub adc_pins: &'static [u8], // ADC1 GPIOs free for the battery, feedback and current sensors, ADC2 needs the radio
+}
+
+// The joint's LEDC pin, None for a joint the board has no pin for
+pub fn servo_pin(joint: usize) -> Option<i32> {
+    PROFILE.servo_pins.get(joint).copied()
+}
+
+pub fn is_adc_pin(pin: u8) -> bool {
+    PROFILE.adc_pins.contains(&pin)
+}
+
+#[cfg_attr(feature = "sim", allow(dead_code))] // The simulator has no LED
+pub fn status_led_pin() -> u8 {
+    configured_or(CONFIG.status_led_pin, PROFILE.status_led_pin)
+}
+
+#[cfg_attr(feature = "sim", allow(dead_code))] // Nor buttons
+pub fn estop_pin() -> u8 {
+    configured_or(CONFIG.estop_pin, PROFILE.estop_pin)
+}
+
+#[cfg_attr(feature = "sim", allow(dead_code))]
+pub fn button_pin() -> u8 {
+    configured_or(CONFIG.button_pin, PROFILE.button_pin)
+}
+
+#[cfg_attr(feature = "sim", allow(dead_code))] // Nor a buzzer
+pub fn buzzer_pin() -> u8 {
+    configured_or(CONFIG.buzzer_pin, PROFILE.buzzer_pin)
+}
+
+pub fn battery_pin() -> u8 {
+    configured_or(CONFIG.battery_pin, PROFILE.battery_pin)
+}
+
+#[cfg_attr(feature = "sim", allow(dead_code))] // Nor a UART
+pub fn serial_pins() -> (u8, u8) {
+    (
+        configured_or(CONFIG.serial_tx_pin, PROFILE.serial_tx_pin),
+        configured_or(CONFIG.serial_rx_pin, PROFILE.serial_rx_pin),
+    )
+}
+
+// The cfg.toml pin, or the board's while it is left at 0
+fn configured_or(configured: u8, board: u8) -> u8 {
+    match configured {
+        0 => board,
+        pin => pin,
+    }
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+    use crate::joints::JOINTS;
+
+    #[test]
+    fn a_pin_left_at_0_in_the_config_falls_back_to_the_boards() {
+        assert_eq!(configured_or(0, 18), 18);
+        assert_eq!(configured_or(25, 18), 25);
+        assert_eq!(configured_or(0, 0), 0);
+    }
+
+    #[test]
+    fn every_joint_has_a_pin_and_none_is_taken_twice() {
+        assert_eq!(servo_pin(JOINTS.len()), None);
+        let mut pins: Vec<i32> = (0..JOINTS.len()).map(|joint| servo_pin(joint).unwrap()).collect();
+        pins.extend([PROFILE.gripper_pin, PROFILE.sda, PROFILE.scl]);
+        pins.sort_unstable();
+        pins.dedup();
+        assert_eq!(pins.len(), JOINTS.len() + 3);
+        assert!(is_adc_pin(PROFILE.adc_pins[0]));
+        assert!(!is_adc_pin(PROFILE.sda as u8));
+    }
+}
@@ -0,0 +1,19 @@
+// The ESP32-S3 unit, with the display on GPIO8 and 9. Its ADC1 is GPIO1 to 10, less the two the bus takes, and its
+// flash pins are all above 26, clear of everything here. Build it as Cargo.toml's board-s3-custom says
+use super::BoardProfile;
+
+pub const PROFILE: BoardProfile = BoardProfile {
+    name: "ESP32-S3 custom",
+    sda: 8,
+    scl: 9,
+    servo_pins: &[11, 12, 13, 14, 15],
+    gripper_pin: 16,
+    status_led_pin: 17,
+    estop_pin: 0,
+    button_pin: 18, // An RTC GPIO, so it can wake the chip from deep sleep
+    buzzer_pin: 0,
+    battery_pin: 0,
+    serial_tx_pin: 43, // UART0's own pins
+    serial_rx_pin: 44,
+    adc_pins: &[1, 2, 3, 4, 5, 6, 7, 10],
+};
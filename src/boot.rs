@@ -0,0 +1,93 @@
+// Start-up checklist shown while the hardware comes up, a line per subsystem marked ok or crossed with its error
+use crate::backend::{Align, DisplayBackend};
+use crate::{wrap_text, DISPLAY_COLUMNS, VERSION_MAJ, VERSION_MIN};
+
+const ERROR_LINES: usize = 2; // Lines of a failed step's error shown under it
+const ERROR_INDENT: &str = "   "; // Lines the error up under the step's name
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Running,
+    Done,
+    Failed,
+}
+
+struct Step {
+    name: &'static str,
+    state: State,
+    detail: String, // Beside the name, or the error under it once failed
+}
+
+#[derive(Default)]
+pub struct BootChecklist {
+    steps: Vec<Step>,
+}
+
+impl BootChecklist {
+    pub fn new() -> BootChecklist {
+        BootChecklist::default()
+    }
+
+    // Adds the step as running, unless it is the one already running
+    pub fn start(&mut self, name: &'static str, display: &mut impl DisplayBackend) {
+        if self.running().is_some_and(|step| step.name == name) {
+            return;
+        }
+        self.steps.push(Step { name, state: State::Running, detail: String::new() });
+        self.draw(display);
+    }
+
+    // Replaces the running step's detail, like the attempt it is on
+    pub fn progress(&mut self, detail: &str, display: &mut impl DisplayBackend) {
+        self.finish(State::Running, detail, display);
+    }
+
+    pub fn done(&mut self, detail: &str, display: &mut impl DisplayBackend) {
+        self.finish(State::Done, detail, display);
+    }
+
+    // Crosses the running step with the error under it, the steps after it carry on below
+    pub fn fail(&mut self, error: &dyn std::fmt::Display, display: &mut impl DisplayBackend) {
+        self.finish(State::Failed, &error.to_string(), display);
+    }
+
+    fn running(&self) -> Option<&Step> {
+        self.steps.last().filter(|step| step.state == State::Running)
+    }
+
+    // Does nothing without a running step
+    fn finish(&mut self, state: State, detail: &str, display: &mut impl DisplayBackend) {
+        let Some(step) = self.steps.last_mut().filter(|step| step.state == State::Running) else {
+            return;
+        };
+        step.state = state;
+        step.detail = detail.to_string();
+        self.draw(display);
+    }
+
+    // The title stays on the first row, below it the latest lines that fit
+    fn draw(&self, display: &mut impl DisplayBackend) {
+        let mut lines: Vec<String> = Vec::new();
+        for step in &self.steps {
+            let mark = match step.state {
+                State::Running => "..",
+                State::Done => "ok",
+                State::Failed => " X",
+            };
+            match step.state {
+                State::Failed => {
+                    lines.push(format!("{} {}", mark, step.name));
+                    let error = wrap_text(&step.detail, DISPLAY_COLUMNS - ERROR_INDENT.len(), ERROR_LINES);
+                    lines.extend(error.lines().map(|line| format!("{}{}", ERROR_INDENT, line)));
+                }
+                _ => lines.push(wrap_text(&format!("{} {} {}", mark, step.name, step.detail), DISPLAY_COLUMNS, 1)),
+            }
+        }
+        let title = format!("Robotic Limb V{}.{}", VERSION_MAJ, VERSION_MIN);
+        let shown = display.rows().saturating_sub(1);
+        let rows: Vec<&str> = std::iter::once(title.as_str())
+            .chain(lines[lines.len().saturating_sub(shown)..].iter().map(String::as_str))
+            .collect();
+        display.draw_lines(&rows, Align::Left);
+    }
+}
@@ -0,0 +1,221 @@
+// Physical e-stop and user buttons, closing their pins to ground against pull-ups. The e-stop's interrupt only raises a
+// flag for the control loop to debounce and latch, the user button's level is just read each pass
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[cfg(not(feature = "sim"))]
+use esp_idf_hal::gpio::{AnyIOPin, Input, InterruptType, PinDriver, Pull};
+#[cfg(not(feature = "sim"))]
+use esp_idf_sys::EspError;
+#[cfg(not(feature = "sim"))]
+use log::error;
+
+const DEBOUNCE: Duration = Duration::from_millis(20); // The pin must still read pressed this long after the first edge
+const LONG_PRESS: Duration = Duration::from_secs(2);
+const VERY_LONG_PRESS: Duration = Duration::from_secs(5);
+
+pub struct EStopButton {
+    #[cfg(not(feature = "sim"))]
+    driver: Option<PinDriver<'static, AnyIOPin, Input>>, // None without a button
+    raised: Arc<AtomicBool>,    // Set from the interrupt
+    raised_at: Option<Instant>, // The first edge of a press that hasn't been confirmed yet
+}
+
+impl EStopButton {
+    // Stands in when no pin is configured, it is never pressed
+    pub fn none() -> EStopButton {
+        EStopButton {
+            #[cfg(not(feature = "sim"))]
+            driver: None,
+            raised: Arc::new(AtomicBool::new(false)),
+            raised_at: None,
+        }
+    }
+
+    // Input-only pins (34 to 39) have no pull-up and are refused
+    #[cfg(not(feature = "sim"))]
+    pub fn new(pin: i32) -> Result<EStopButton, EspError> {
+        // Safety: the button pin is only taken here and nothing else drives it
+        let mut driver = PinDriver::input(unsafe { AnyIOPin::new(pin) })?;
+        driver.set_pull(Pull::Up)?;
+        driver.set_interrupt_type(InterruptType::NegEdge)?;
+        let raised = Arc::new(AtomicBool::new(false));
+        let isr_raised = raised.clone();
+        // Safety: the callback only stores to an atomic, which is fine from the ISR
+        unsafe {
+            driver.subscribe(move || isr_raised.store(true, Ordering::SeqCst))?;
+        }
+        driver.enable_interrupt()?;
+        Ok(EStopButton { driver: Some(driver), raised, raised_at: None })
+    }
+
+    // Returns true once a press has lasted the debounce time, a bounce or glitch that is already released is ignored
+    pub fn poll(&mut self, now: Instant) -> bool {
+        if self.raised.swap(false, Ordering::SeqCst) {
+            self.raised_at.get_or_insert(now);
+            self.rearm();
+        }
+        match self.raised_at {
+            Some(raised_at) if now.duration_since(raised_at) >= DEBOUNCE => {
+                self.raised_at = None;
+                self.is_pressed()
+            }
+            _ => false,
+        }
+    }
+
+    #[cfg(not(feature = "sim"))]
+    pub fn is_pressed(&self) -> bool {
+        self.driver.as_ref().is_some_and(|driver| driver.is_low())
+    }
+
+    #[cfg(feature = "sim")]
+    pub fn is_pressed(&self) -> bool {
+        false
+    }
+
+    // The driver disables the interrupt each time it fires, it can only be enabled again from outside the ISR
+    #[cfg(not(feature = "sim"))]
+    fn rearm(&mut self) {
+        if let Some(driver) = self.driver.as_mut() {
+            match driver.enable_interrupt() {
+                Ok(_) => {},
+                Err(e) => error!("Failed to re-enable the e-stop button interrupt: {}", e),
+            }
+        }
+    }
+
+    #[cfg(feature = "sim")]
+    fn rearm(&mut self) {}
+}
+
+// How long the user button was held, known once it is released
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Press {
+    Short,    // Cycles the display pages
+    Long,     // LONG_PRESS or more, acknowledges an error banner
+    VeryLong, // VERY_LONG_PRESS or more, restarts into the setup portal
+}
+
+pub struct Button {
+    #[cfg(not(feature = "sim"))]
+    driver: Option<PinDriver<'static, AnyIOPin, Input>>, // None without a button
+    pressed: bool,               // As last debounced
+    changed_at: Option<Instant>, // The pin first read other than `pressed`, until it has for DEBOUNCE
+    pressed_at: Option<Instant>, // None for a press already held at boot, its release isn't a press of its own
+}
+
+impl Button {
+    // Stands in when no pin is configured, it is never pressed
+    pub fn none() -> Button {
+        Button {
+            #[cfg(not(feature = "sim"))]
+            driver: None,
+            pressed: false,
+            changed_at: None,
+            pressed_at: None,
+        }
+    }
+
+    // Input-only pins (34 to 39) have no pull-up and are refused
+    #[cfg(not(feature = "sim"))]
+    pub fn new(pin: i32) -> Result<Button, EspError> {
+        // Safety: the button pin is only taken here and nothing else drives it
+        let mut driver = PinDriver::input(unsafe { AnyIOPin::new(pin) })?;
+        driver.set_pull(Pull::Up)?;
+        let pressed = driver.is_low();
+        Ok(Button { driver: Some(driver), pressed, changed_at: None, pressed_at: None })
+    }
+
+    // Called every pass, returns the press once the button is released
+    pub fn poll(&mut self, now: Instant) -> Option<Press> {
+        let level = self.read();
+        self.update(level, now)
+    }
+
+    // A change has to hold for DEBOUNCE to count, and the press is timed from when it began
+    fn update(&mut self, level: bool, now: Instant) -> Option<Press> {
+        if level == self.pressed {
+            self.changed_at = None;
+            return None;
+        }
+        let changed_at = *self.changed_at.get_or_insert(now);
+        if now.duration_since(changed_at) < DEBOUNCE {
+            return None;
+        }
+        self.changed_at = None;
+        self.pressed = !self.pressed;
+        if self.pressed {
+            self.pressed_at = Some(changed_at);
+            return None;
+        }
+        let held = changed_at.duration_since(self.pressed_at.take()?);
+        Some(match held {
+            held if held >= VERY_LONG_PRESS => Press::VeryLong,
+            held if held >= LONG_PRESS => Press::Long,
+            _ => Press::Short,
+        })
+    }
+
+    #[cfg(not(feature = "sim"))]
+    fn read(&self) -> bool {
+        self.driver.as_ref().is_some_and(|driver| driver.is_low())
+    }
+
+    #[cfg(feature = "sim")]
+    fn read(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+
+    // Holds the button from `start` for `held`, reading the pin every 5 ms like the control loop
+    fn press(button: &mut Button, start: Instant, held: Duration) -> Option<Press> {
+        let step = Duration::from_millis(5);
+        let mut now = start;
+        while now < start + held {
+            assert_eq!(button.update(true, now), None, "a press reported before its release");
+            now += step;
+        }
+        let released = now;
+        while now < released + DEBOUNCE {
+            assert_eq!(button.update(false, now), None);
+            now += step;
+        }
+        button.update(false, now)
+    }
+
+    #[test]
+    fn presses_are_told_apart_by_how_long_they_were_held() {
+        let mut button = Button::none();
+        let start = Instant::now();
+        assert_eq!(press(&mut button, start, Duration::from_millis(200)), Some(Press::Short));
+        assert_eq!(press(&mut button, start + Duration::from_secs(1), LONG_PRESS), Some(Press::Long));
+        assert_eq!(press(&mut button, start + Duration::from_secs(5), VERY_LONG_PRESS), Some(Press::VeryLong));
+    }
+
+    #[test]
+    fn bounces_shorter_than_the_debounce_are_ignored() {
+        let mut button = Button::none();
+        let start = Instant::now();
+        for bounce in 0..5 {
+            let at = start + Duration::from_millis(bounce * 10);
+            assert_eq!(button.update(true, at), None);
+            assert_eq!(button.update(false, at + DEBOUNCE / 2), None);
+        }
+        assert!(!button.pressed);
+    }
+
+    #[test]
+    fn a_press_held_at_boot_is_not_reported() {
+        let mut button = Button { pressed: true, ..Button::none() };
+        let start = Instant::now();
+        button.update(false, start);
+        assert_eq!(button.update(false, start + DEBOUNCE), None);
+        assert_eq!(press(&mut button, start + Duration::from_secs(1), Duration::from_millis(100)), Some(Press::Short));
+    }
+}
@@ -0,0 +1,189 @@
+// Piezo buzzer on an LEDC channel and timer of its own, the timer's frequency is the pitch. A pattern is only started
+// here, the control loop moves it on each pass like the status LED, so a tone never holds up the servos
+use std::time::{Duration, Instant};
+
+#[cfg(not(feature = "sim"))]
+use esp_idf_hal::ledc::{LedcDriver, LedcTimerDriver};
+#[cfg(not(feature = "sim"))]
+use esp_idf_hal::units::Hertz;
+#[cfg(not(feature = "sim"))]
+use log::error;
+#[cfg(feature = "sim")]
+use log::info;
+
+// In order of urgency, a sound only cuts off one that is no more urgent than itself
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Sound {
+    Boot,
+    Connected,
+    LowBattery,
+    Failsafe,
+    EStop,
+}
+
+impl Sound {
+    // Tones in Hz and how long each is held in ms, 0 Hz is a rest. Each is played once, a new sound only needs its
+    // steps here
+    fn steps(self) -> &'static [(u16, u16)] {
+        match self {
+            Sound::Boot => &[(1000, 80), (0, 40), (1500, 80), (0, 40), (2000, 150)],
+            Sound::Connected => &[(2000, 60), (0, 40), (2600, 80)],
+            Sound::LowBattery => &[(700, 300), (0, 150), (500, 500)],
+            Sound::Failsafe => &[(900, 200), (0, 100), (900, 200), (0, 100), (900, 200)],
+            Sound::EStop => &[(3000, 100), (0, 50), (3000, 100), (0, 50), (3000, 100), (0, 50), (3000, 400)],
+        }
+    }
+}
+
+pub struct Buzzer {
+    #[cfg(not(feature = "sim"))]
+    output: Option<(LedcDriver<'static>, LedcTimerDriver<'static>)>, // None without a buzzer
+    muted: bool,
+    playing: Option<Sound>,
+    step: usize,
+    step_started: Instant,
+    tone: Option<u16>, // Last frequency written, 0 for silence, None until the first
+}
+
+impl Buzzer {
+    // Stands in when no pin is configured
+    pub fn none() -> Buzzer {
+        Buzzer {
+            #[cfg(not(feature = "sim"))]
+            output: None,
+            muted: false,
+            playing: None,
+            step: 0,
+            step_started: Instant::now(),
+            tone: None,
+        }
+    }
+
+    // The channel's duty is set to half for each tone, the timer is its own so changing pitch moves nothing else
+    #[cfg(not(feature = "sim"))]
+    pub fn new(driver: LedcDriver<'static>, timer: LedcTimerDriver<'static>) -> Buzzer {
+        Buzzer { output: Some((driver, timer)), ..Buzzer::none() }
+    }
+
+    // Starts the sound from its first step, unless something more urgent is playing. Muted, nothing starts
+    pub fn play(&mut self, sound: Sound, now: Instant) {
+        if self.muted || self.playing.is_some_and(|playing| playing > sound) {
+            return;
+        }
+        #[cfg(feature = "sim")]
+        info!("Buzzer: {:?}", sound);
+        self.playing = Some(sound);
+        self.step = 0;
+        self.step_started = now;
+    }
+
+    // Moves on to the next step once the current one has been held long enough, writing the tone when it changes
+    pub fn update(&mut self, now: Instant) {
+        if let Some(sound) = self.playing {
+            let steps = sound.steps();
+            let (_, hold_ms) = steps[self.step];
+            if now.duration_since(self.step_started) >= Duration::from_millis(hold_ms as u64) {
+                self.step += 1;
+                self.step_started = now;
+                if self.step == steps.len() {
+                    self.playing = None;
+                }
+            }
+        }
+        let hz = match (self.playing, self.muted) {
+            (Some(sound), false) => sound.steps()[self.step].0,
+            _ => 0,
+        };
+        if self.tone != Some(hz) {
+            self.write(hz);
+            self.tone = Some(hz);
+        }
+    }
+
+    // Muting silences a sound already playing on the next update
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+        if muted {
+            self.playing = None;
+        }
+    }
+
+    // What the host tests hear
+    #[cfg(all(test, feature = "sim"))]
+    pub fn playing(&self) -> Option<Sound> {
+        self.playing
+    }
+
+    #[cfg(not(feature = "sim"))]
+    fn write(&mut self, hz: u16) {
+        if let Some((driver, timer)) = self.output.as_mut() {
+            let result = match hz {
+                0 => driver.set_duty(0),
+                hz => timer.set_frequency(Hertz(hz as u32)).and_then(|_| driver.set_duty(driver.get_max_duty() / 2)),
+            };
+            match result {
+                Ok(_) => {},
+                Err(e) => error!("Failed to sound the buzzer: {}", e),
+            }
+        }
+    }
+
+    // Only the sound is logged, every tone would flood the log
+    #[cfg(feature = "sim")]
+    fn write(&mut self, _hz: u16) {}
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+
+    // Steps the sound through to its end, returning each tone it wrote
+    fn tones(buzzer: &mut Buzzer, start: Instant) -> Vec<u16> {
+        let mut now = start;
+        let mut tones = Vec::new();
+        while buzzer.playing.is_some() {
+            buzzer.update(now);
+            if tones.last() != buzzer.tone.as_ref() {
+                tones.extend(buzzer.tone);
+            }
+            now += Duration::from_millis(10);
+        }
+        tones
+    }
+
+    #[test]
+    fn a_sound_plays_its_steps_in_order_then_goes_quiet() {
+        let mut buzzer = Buzzer::none();
+        let start = Instant::now();
+        buzzer.play(Sound::Connected, start);
+        assert_eq!(tones(&mut buzzer, start), [2000, 0, 2600, 0]);
+    }
+
+    #[test]
+    fn only_a_sound_as_urgent_cuts_one_off() {
+        let mut buzzer = Buzzer::none();
+        let start = Instant::now();
+        buzzer.play(Sound::Failsafe, start);
+        buzzer.play(Sound::Connected, start);
+        assert_eq!(buzzer.playing, Some(Sound::Failsafe));
+        buzzer.update(start + Duration::from_millis(250));
+        buzzer.play(Sound::EStop, start + Duration::from_millis(250));
+        assert_eq!((buzzer.playing, buzzer.step), (Some(Sound::EStop), 0));
+        buzzer.play(Sound::EStop, start + Duration::from_millis(300));
+        assert_eq!(buzzer.step_started, start + Duration::from_millis(300));
+    }
+
+    #[test]
+    fn muting_silences_a_sound_already_playing() {
+        let mut buzzer = Buzzer::none();
+        let start = Instant::now();
+        buzzer.play(Sound::LowBattery, start);
+        buzzer.update(start);
+        assert_eq!(buzzer.tone, Some(700));
+        buzzer.set_muted(true);
+        buzzer.update(start + Duration::from_millis(10));
+        assert_eq!((buzzer.playing, buzzer.tone), (None, Some(0)));
+        buzzer.play(Sound::EStop, start + Duration::from_millis(20));
+        assert_eq!(buzzer.playing, None);
+    }
+}
@@ -0,0 +1,67 @@
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CalibrationError {
+    InvalidEndpoints { min_duty: u32, max_duty: u32 },
+}
+
+impl fmt::Display for CalibrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CalibrationError::InvalidEndpoints { min_duty, max_duty } => {
+                write!(f, "min duty {} must be below max duty {}", min_duty, max_duty)
+            }
+        }
+    }
+}
+
+// A servo being driven with raw duty counts while its endpoints are found
+pub struct CalibrationSession {
+    index: usize,
+    duty: u32,
+    min_duty: u32,
+    max_duty: u32,
+}
+
+impl CalibrationSession {
+    // Starts from the servo's current duty and endpoints, so only the endpoints that get captured change
+    pub fn new(index: usize, duty: u32, (min_duty, max_duty): (u32, u32)) -> CalibrationSession {
+        CalibrationSession {
+            index,
+            duty,
+            min_duty,
+            max_duty,
+        }
+    }
+
+    pub fn get_index(&self) -> usize {
+        self.index
+    }
+
+    pub fn set_duty(&mut self, duty: u32) {
+        self.duty = duty;
+    }
+
+    pub fn get_duty(&self) -> u32 {
+        self.duty
+    }
+
+    pub fn capture_min(&mut self) {
+        self.min_duty = self.duty;
+    }
+
+    pub fn capture_max(&mut self) {
+        self.max_duty = self.duty;
+    }
+
+    // The endpoints to apply on exit, refused if they would give an empty or inverted range
+    pub fn endpoints(&self) -> Result<(u32, u32), CalibrationError> {
+        if self.min_duty >= self.max_duty {
+            return Err(CalibrationError::InvalidEndpoints {
+                min_duty: self.min_duty,
+                max_duty: self.max_duty,
+            });
+        }
+        Ok((self.min_duty, self.max_duty))
+    }
+}
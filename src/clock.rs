@@ -0,0 +1,103 @@
+// Wall-clock time from SNTP for the timestamps in the status reply, heartbeats and recordings. Until the first sync
+// there is no time at all, rather than one counting up from 1970
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+#[cfg(not(feature = "sim"))]
+use std::thread;
+#[cfg(not(feature = "sim"))]
+use std::time::{Duration, Instant};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(not(feature = "sim"))]
+use esp_idf_svc::sntp::{EspSntp, SntpConf, SyncStatus};
+#[cfg(not(feature = "sim"))]
+use log::{error, info, warn};
+
+use crate::link::Link;
+#[cfg(not(feature = "sim"))]
+use crate::{tasks, CONFIG};
+
+#[cfg(not(feature = "sim"))]
+const LINK_POLL: Duration = Duration::from_secs(1); // How often the network is checked for before syncing
+#[cfg(not(feature = "sim"))]
+const SYNC_POLL: Duration = Duration::from_millis(100);
+#[cfg(not(feature = "sim"))]
+const SYNC_TIMEOUT: Duration = Duration::from_secs(15); // Longest a sync is waited on before it is tried again later
+#[cfg(not(feature = "sim"))]
+const RETRY: Duration = Duration::from_secs(60); // Between tries until the first sync, the resync interval after it
+
+// Shared by the clock task, which marks it synchronized, and everything reading the time
+#[derive(Clone)]
+pub struct Clock {
+    synced: Arc<AtomicBool>,
+}
+
+impl Clock {
+    // Milliseconds since the Unix epoch, None until the first sync
+    pub fn unix_ms(&self) -> Option<u64> {
+        if !self.synced.load(Ordering::Relaxed) {
+            return None;
+        }
+        SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|since| since.as_millis() as u64)
+    }
+}
+
+// Syncs on its own task once the link is up. An empty sntp_server leaves the time unsynchronized
+#[cfg(not(feature = "sim"))]
+pub fn start(link: Link) -> Clock {
+    let clock = Clock { synced: Arc::new(AtomicBool::new(false)) };
+    if CONFIG.sntp_server.is_empty() {
+        info!("No SNTP server set, timestamps stay unsynchronized");
+        return clock;
+    }
+    let task_clock = clock.clone();
+    tasks::spawn(&tasks::CLOCK_TASK, move || run(task_clock, link));
+    clock
+}
+
+// The host keeps its own time
+#[cfg(feature = "sim")]
+pub fn start(_link: Link) -> Clock {
+    Clock { synced: Arc::new(AtomicBool::new(true)) }
+}
+
+// Each sync starts the service afresh and stops it again once answered, so the interval is the config's rather than
+// whatever esp-idf was built with
+#[cfg(not(feature = "sim"))]
+fn run(clock: Clock, link: Link) {
+    let resync = Duration::from_secs(CONFIG.sntp_resync_s.max(60) as u64);
+    loop {
+        while !link.get().up {
+            thread::sleep(LINK_POLL);
+        }
+        let mut conf = SntpConf::default();
+        conf.servers[0] = CONFIG.sntp_server;
+        let sntp = match EspSntp::new(&conf) {
+            Ok(sntp) => sntp,
+            Err(e) => {
+                error!("Failed to start SNTP, retrying in {} s: {}", RETRY.as_secs(), e);
+                thread::sleep(RETRY);
+                continue;
+            }
+        };
+        let started = Instant::now();
+        while sntp.get_sync_status() != SyncStatus::Completed && started.elapsed() < SYNC_TIMEOUT {
+            thread::sleep(SYNC_POLL);
+        }
+        let synced = sntp.get_sync_status() == SyncStatus::Completed;
+        drop(sntp);
+        match (synced, clock.synced.load(Ordering::Relaxed)) {
+            (true, false) => {
+                info!("Time synchronized with {}", CONFIG.sntp_server);
+                clock.synced.store(true, Ordering::Relaxed);
+            }
+            (true, true) => {},
+            (false, false) => warn!("No answer from {}, retrying in {} s", CONFIG.sntp_server, RETRY.as_secs()),
+            (false, true) => warn!("No answer from {}, keeping the last sync", CONFIG.sntp_server),
+        }
+        thread::sleep(match clock.synced.load(Ordering::Relaxed) {
+            true => resync,
+            false => RETRY,
+        });
+    }
+}
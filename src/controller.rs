@@ -0,0 +1,3605 @@
+// Control task state, stepped by tick(), shown by render() and driven by the commands handle_packet() is given
+use std::net::SocketAddr;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info, warn};
+
+use crate::backend::{Align, DisplayBackend};
+use crate::battery::{Battery, BatteryConfig, Cutoff};
+use crate::button::{Button, EStopButton, Press};
+use crate::buzzer::{Buzzer, Sound};
+use crate::calibration::CalibrationSession;
+use crate::clock::Clock;
+use crate::current::CurrentMonitor;
+use crate::failsafe::{Failsafe, FailsafeAction};
+use crate::feedback::FeedbackCalibration;
+use crate::gripper::Gripper;
+use crate::heartbeat::{self, Subscription};
+use crate::led::{LedPattern, StatusLed};
+use crate::link::{Link, LinkStatus};
+use crate::loop_timing::{self, LoopTimer, Phase};
+use crate::mirror::{self, Mirror};
+use crate::history::{self, History};
+use crate::motion_queue::{Motion, MotionQueue, QueuedMove};
+use crate::network::{self, Command, Reply, ReplyTo};
+#[cfg(all(feature = "mqtt", not(feature = "sim")))]
+use crate::mqtt;
+use crate::ota::{UpdateState, Updater};
+use crate::pages::{DisplayConfig, DisplayStatus, Page, Pages};
+use crate::preset::{self, LastPose, Preset};
+use crate::protocol::{
+    self, BuzzerConfig, CalibrationCommand, ConfigCommand, ControlPacket, CurrentCommand, FailsafeConfig, GripperMode,
+    JointCurrent, JointStatus, MeasuredAngle, MoveTag, ReplyPacket, ReplyPayload, ServoConfig, ServoPosition, Status,
+    TeachCommand, Telemetry,
+};
+use crate::remote_log;
+use crate::self_test::SelfTest;
+use crate::servo::{self, Easing, Servo, Tenths};
+use crate::session::Session;
+use crate::settings::{Calibration, Settings};
+use crate::sleep::{self, WakeReason};
+use crate::stats::{self, Stats};
+use crate::tasks;
+use crate::thermal::{Level, Thermal};
+use crate::tick::Tick;
+use crate::trajectory::{self, Keyframe, Playback, Recording};
+use crate::watchdog::{self, ResetReason};
+use crate::{
+    format_volts, load_battery_config, load_buzzer_muted, load_display_config, load_mirror, load_speed_scale, restart,
+    wrap_text, Board, Shared, CONFIG, DISPLAY_COLUMNS, RECV_TIMEOUT, REBOOT_DELAY, SHUTDOWN_TIMEOUT, SLEEP_DELAY,
+    VERSION_MAJ, VERSION_MIN,
+};
+
+// A run of consecutive moves from one client: the newest, and the sequence and reply of each one it superseded
+type MoveRun = (Command, Vec<(u16, ReplyTo)>);
+
+pub struct Controller<D: DisplayBackend> {
+    servos: Vec<Servo>,
+    gripper: Option<Gripper>,
+    current: CurrentMonitor,
+    display: Pages<D>,
+    settings: Option<Settings>,
+    tick: Tick,
+    battery: Battery,
+    estop_button: EStopButton,
+    button: Button,
+    buzzer: Buzzer,
+    status_led: StatusLed,
+    updater: Updater,
+    link: Link,
+    stats: Stats,
+    clock: Clock,
+    replies: Sender<Reply>,
+    display_status: DisplayStatus,
+    failsafe: Failsafe,
+    control_state: ControlState,
+    reset_reason: ResetReason,
+    wake_reason: WakeReason,
+    last_crash: String,
+    calibration: Option<CalibrationSession>,
+    uploaded: Vec<Keyframe>, // Last uploaded trajectory, waiting to be stored
+    uploaded_at: Option<u64>, // Unix time a recording into the upload started, None for one sent by a client
+    playback: Option<Playback>,
+    motion_queue: MotionQueue,
+    recording: Option<Recording>, // Teach mode, finished into the uploaded trajectory
+    self_test: Option<SelfTest>,  // The last self-test, kept for its results once finished
+    link_status: LinkStatus,
+    session: Session,
+    subscription: Option<Subscription>,
+    cutoff: Cutoff,
+    battery_mv: Option<u16>, // Read each pass, None while the voltage isn't known
+    thermal: Thermal,
+    speed_scale: u8,         // Percent of full speed every servo moves at
+    mirror: Mirror,
+    paused: bool,
+    saved_pose: Vec<u16>,  // Last written to NVS, empty before the first save
+    pose_saved_at: Instant,
+    restored: u8,          // The RESTORED_* value for the pose kept through the last restart
+    loop_timer: LoopTimer,
+    history: History,
+    i2c_devices: Vec<u8>, // Addresses that answered the bus scan at boot
+}
+
+impl<D: DisplayBackend> Controller<D> {
+    pub fn new(
+        mut servos: Vec<Servo>,
+        display: D,
+        settings: Option<Settings>,
+        Board {
+            tick,
+            battery,
+            estop_button,
+            button,
+            mut buzzer,
+            status_led,
+            updater,
+            mut gripper,
+            history,
+            current,
+            i2c_devices,
+        }: Board,
+        Shared { link, stats, clock }: Shared,
+        replies: Sender<Reply>,
+    ) -> Controller<D> {
+        // Start-up leaves the address up, so rotation starts from the network page
+        let display_config = load_display_config(settings.as_ref());
+        let display = match CONFIG.display_page_s {
+            0 => Pages::new(display, Page::Servos, None, display_config),
+            page_s => Pages::new(display, Page::Network, Some(Duration::from_secs(page_s as u64)), display_config),
+        };
+        let display_status = DisplayStatus::new(&servos, link.get(), stats.clone());
+
+        let speed_scale = load_speed_scale(settings.as_ref());
+        for servo in servos.iter_mut() {
+            servo.set_poll_hz(tick.get_hz());
+            servo.set_speed_scale(speed_scale);
+        }
+        if let Some(gripper) = gripper.as_mut() {
+            gripper.set_poll_hz(tick.get_hz());
+            gripper.set_speed_scale(speed_scale);
+        }
+        let mirror_joints = mirror::parse_joints(CONFIG.mirror_joints, servos.len());
+        let mirror = Mirror::new(load_mirror(settings.as_ref()), mirror_joints);
+        let failsafe = Failsafe::new(servos.iter().map(|servo| servo.get_max_angle() / 2).collect());
+        // After a watchdog reset the servos stay off until a client deliberately clears the e-stop
+        let reset_reason = watchdog::reset_reason();
+        info!("Last reset: {:?}", reset_reason);
+        // A timed wake is for a scheduled move, the servos are left for it rather than ramped
+        let wake_reason = sleep::wake_reason();
+        if wake_reason != WakeReason::NotSlept {
+            info!("Woken from deep sleep by {:?}", wake_reason);
+        }
+        let cutoff = Cutoff::new(load_battery_config(settings.as_ref()));
+        buzzer.set_muted(load_buzzer_muted(settings.as_ref()));
+        let link_status = link.get();
+        let mut controller = Controller {
+            servos,
+            gripper,
+            current,
+            display,
+            settings,
+            tick,
+            battery,
+            estop_button,
+            button,
+            buzzer,
+            status_led,
+            updater,
+            link,
+            stats,
+            clock,
+            replies,
+            display_status,
+            failsafe,
+            control_state: ControlState::Running,
+            reset_reason,
+            wake_reason,
+            last_crash: String::new(),
+            calibration: None,
+            uploaded: Vec::new(),
+            uploaded_at: None,
+            playback: None,
+            motion_queue: MotionQueue::new(),
+            recording: None,
+            self_test: None,
+            link_status,
+            session: Session::new(Duration::from_secs(CONFIG.session_timeout_s as u64)),
+            subscription: None,
+            cutoff,
+            battery_mv: None,
+            thermal: Thermal::new(),
+            speed_scale,
+            mirror,
+            paused: false,
+            saved_pose: Vec::new(),
+            pose_saved_at: Instant::now(),
+            restored: protocol::RESTORED_NONE,
+            loop_timer: LoopTimer::new(CONFIG.loop_window_s, CONFIG.loop_slow_us),
+            history,
+            i2c_devices,
+        };
+        controller.start_up();
+        controller
+    }
+
+    // Latches anything left over from before the reset, the loop must not move the servos first
+    fn start_up(&mut self) {
+        // Started without the network, the banner otherwise only comes up once the link changes
+        if !self.link_status.up {
+            show_link_down(&mut self.display, &self.link, self.link_status);
+        }
+        if self.reset_reason.is_watchdog() {
+            error!("Control loop was reset by the watchdog, starting with the servos off");
+            stop_servos(&mut self.servos, self.gripper.as_mut());
+            self.control_state = ControlState::EStopped(EStopSource::Watchdog);
+            self.display.draw_banner("WATCHDOG", "Reset by watchdog\nServos off\nClear e-stop to resume");
+        }
+        // No edge is seen for a button already held down at boot
+        if self.estop_button.is_pressed() {
+            error!("E-stop button held at start-up");
+            let gripper = self.gripper.as_mut();
+            estop(&mut self.servos, gripper, &mut self.control_state, EStopSource::Button, &mut self.display);
+            self.buzzer.play(Sound::EStop, Instant::now());
+        }
+        // Kept by the panic hook, reported in telemetry until the next panic replaces it
+        self.last_crash = match self.settings.as_ref().map(Settings::load_crash) {
+            Some(Ok(last_crash)) => last_crash.unwrap_or_default(),
+            Some(Err(e)) => {
+                error!("Failed to read the last crash: {}", e);
+                String::new()
+            }
+            None => String::new(),
+        };
+        if self.reset_reason == ResetReason::Panic {
+            error!("Restarted after a panic: {}", self.last_crash);
+            self.display.draw_banner("CRASHED", &format!("Last crash:\n{}", wrap_text(&self.last_crash, DISPLAY_COLUMNS, 3)));
+        }
+        self.power_on();
+        self.buzzer.play(Sound::Boot, Instant::now());
+    }
+
+    // Where a clean shutdown stopped them, or else where shutdown parks them, is the best guess at where unmeasured
+    // joints are, the first move starts from there instead of snapping from 0. A pose kept through the restart is
+    // ramped to in place of the power-on pose. E-stopped at start-up, they stay off whatever the boot behaviour
+    fn power_on(&mut self) {
+        let last_pose = self.restore_pose();
+        for (index, servo) in self.servos.iter_mut().enumerate() {
+            let assumed = match last_pose {
+                Some(LastPose { clean: true, ref angles }) => angles.get(index).copied(),
+                _ => self.failsafe.get_safe_angle(index),
+            };
+            servo.assume_angle(assumed.unwrap_or(servo.get_max_angle() / 2));
+        }
+        match CONFIG.boot_behavior {
+            "limp" => info!("Servos limp until the first command"),
+            "ramp" if self.wake_reason == WakeReason::Timer => info!("Woken by the timer, servos limp until its move"),
+            "ramp" if self.control_state == ControlState::Running => {
+                let pose = match last_pose {
+                    Some(last_pose) => last_pose.angles,
+                    None => load_power_on_pose(self.settings.as_ref(), &self.servos),
+                };
+                info!("Ramping to the power-on pose over {} ms", CONFIG.boot_ramp_ms);
+                move_to_pose(&mut self.servos, &pose, CONFIG.boot_ramp_ms, Easing::Linear);
+            }
+            "ramp" => {}
+            behavior => warn!("Unknown boot behavior \"{}\", servos limp until the first command", behavior),
+        }
+    }
+
+    // Never returns, the control task runs for as long as the chip does
+    pub fn run(mut self, commands: Receiver<Command>) -> ! {
+        info!("Entering Loop");
+        watchdog::watch_current_task();
+        loop {
+            let started = loop_timing::now_us();
+            watchdog::feed();
+            self.tick();
+            let rendering = loop_timing::now_us();
+            self.render();
+            self.loop_timer.record(Phase::Display, rendering);
+            self.heartbeat();
+
+            // Waiting on the queue rather than the tick keeps command latency down, the timeout keeps ticks on time.
+            // Whatever else queued meanwhile is taken with it, at most a queue's worth so the next tick isn't held up
+            let waiting = loop_timing::now_us();
+            let first = match commands.recv_timeout(RECV_TIMEOUT) {
+                Ok(command) => Some(command),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => panic!("Network task stopped"), // Nothing left to receive commands
+            };
+            let dispatching = self.loop_timer.record(Phase::Recv, waiting);
+            if let Some(first) = first {
+                let batch: Vec<Command> =
+                    std::iter::once(first).chain(commands.try_iter().take(network::COMMAND_QUEUE_SIZE)).collect();
+                self.handle_batch(batch);
+                self.loop_timer.record(Phase::Dispatch, dispatching);
+            }
+            self.loop_timer.end_pass(started);
+        }
+    }
+
+    // Commands run in the order they arrived. A run of moves from one client collapses into its newest, which answers
+    // for the ones it superseded, and a command stopping motion drops the run so it can't move the servos afterwards
+    fn handle_batch(&mut self, batch: Vec<Command>) {
+        let mut run: Option<MoveRun> = None; // The newest move of the run and the moves it superseded
+        for command in batch {
+            if matches!(command.packet, ControlPacket::SetAngles(_)) {
+                match run.as_mut() {
+                    Some((newest, superseded)) if newest.addr == command.addr => {
+                        let stale = std::mem::replace(newest, command);
+                        superseded.push((stale.sequence, stale.reply_to));
+                    }
+                    _ => {
+                        if let Some(finished) = run.replace((command, Vec::new())) {
+                            self.apply_run(finished);
+                        }
+                    }
+                }
+                continue;
+            }
+            let stops = command.packet.stops_motion();
+            if !stops {
+                if let Some(finished) = run.take() {
+                    self.apply_run(finished);
+                }
+            }
+            let reply = self.handle_packet(command.addr, command.packet);
+            self.respond(command.addr, command.sequence, command.reply_to, reply);
+            if let Some(dropped) = run.take() {
+                self.drop_run(dropped);
+            }
+        }
+        if let Some(finished) = run {
+            self.apply_run(finished);
+        }
+    }
+
+    fn apply_run(&mut self, (Command { addr, sequence, packet, reply_to }, superseded): MoveRun) {
+        if !superseded.is_empty() {
+            debug!("{} queued moves from {} superseded by move {}", superseded.len(), addr, sequence);
+        }
+        let reply = self.handle_packet(addr, packet);
+        for (stale_sequence, stale_reply_to) in superseded {
+            self.respond(addr, stale_sequence, stale_reply_to, reply.clone());
+        }
+        self.respond(addr, sequence, reply_to, reply);
+    }
+
+    // Refuses every move of a run queued ahead of a stop, unless the stop was itself refused and changed nothing
+    fn drop_run(&mut self, (newest, superseded): MoveRun) {
+        if self.control_state == ControlState::Running && !self.paused && !self.is_busy() {
+            self.apply_run((newest, superseded));
+            return;
+        }
+        info!("{} queued moves from {} dropped by a stop", superseded.len() + 1, newest.addr);
+        let status = self.motion_refusal();
+        for (sequence, reply_to) in superseded.into_iter().chain([(newest.sequence, newest.reply_to)]) {
+            self.stats.count_rejected();
+            let reply = ReplyPacket::new(protocol::MOVE_COMMAND, status, positions(&self.servos));
+            self.respond(newest.addr, sequence, reply_to, reply);
+        }
+    }
+
+    fn respond(&self, addr: SocketAddr, sequence: u16, reply_to: ReplyTo, reply: ReplyPacket) {
+        match reply_to {
+            ReplyTo::Socket => send_reply(&self.replies, addr, sequence, reply),
+            ReplyTo::Channel(waiting) => {
+                let _ = waiting.try_send(reply); // Dropped if it has stopped waiting
+            }
+            ReplyTo::Discard => {},
+        }
+    }
+
+    // Why motion is refused in the current state
+    fn motion_refusal(&self) -> Status {
+        match self.control_state {
+            ControlState::EStopped(EStopSource::Button) => {
+                error!("Motion command rejected, the e-stop button latched");
+                Status::ButtonEStopped
+            }
+            ControlState::EStopped(_) => {
+                error!("Motion command rejected, e-stop is latched");
+                Status::EStopped
+            }
+            ControlState::ShuttingDown { .. } | ControlState::ShutDown => {
+                error!("Motion command rejected, the limb is shut down");
+                Status::ShutDown
+            }
+            ControlState::LowBattery { .. } => {
+                error!("Motion command rejected, the battery is low");
+                Status::LowBattery
+            }
+            ControlState::Overheated => {
+                error!("Motion command rejected, the chip is too hot");
+                Status::Overheated
+            }
+            ControlState::Updating { .. } => {
+                error!("Motion command rejected, the firmware is updating");
+                Status::Updating
+            }
+            ControlState::Running if self.calibration.is_some() => {
+                error!("Motion command rejected, a servo is being calibrated");
+                Status::Busy
+            }
+            ControlState::Running if self.is_self_testing() => {
+                error!("Command rejected, the servos are being self-tested");
+                Status::Busy
+            }
+            ControlState::Running => {
+                error!("Motion command rejected, motion is paused");
+                Status::Paused
+            }
+        }
+    }
+
+    // Parks the servos, the loop stops them and then powers down as asked once they are there
+    fn shut_down(&mut self, then: PowerDown) {
+        self.calibration = None;
+        self.set_paused(false);
+        self.abort_self_test();
+        // Servos already stopped, by an e-stop or a low battery, stay stopped
+        self.failsafe.park(&mut self.servos);
+        self.control_state = ControlState::ShuttingDown { then, started: Instant::now() };
+        let banner = match then {
+            PowerDown::Off => "SHUTDOWN",
+            PowerDown::Reboot => "REBOOT",
+            PowerDown::Sleep { .. } => "SLEEP",
+        };
+        self.display.draw_banner(banner, "Parking servos");
+    }
+
+    // A short press cycles the pages, a long one takes down an error banner once nothing still holds it up, and a very
+    // long one restarts into the setup portal
+    fn handle_press(&mut self, press: Press) {
+        match press {
+            Press::Short => {
+                if !self.display.next_page() {
+                    info!("Button pressed, hold it to acknowledge the banner");
+                }
+            }
+            // The states with banners of their own keep them, an e-stop is only cleared over the network
+            Press::Long => match self.control_state {
+                ControlState::Running if !self.paused && !self.is_self_testing() => {
+                    info!("Banner acknowledged from the button");
+                    self.display.release();
+                }
+                _ => info!("Banner kept, it goes once the limb is running again"),
+            },
+            Press::VeryLong => {
+                if matches!(
+                    self.control_state,
+                    ControlState::ShuttingDown { .. } | ControlState::ShutDown | ControlState::Updating { .. }
+                ) {
+                    warn!("Can't restart into setup while shutting down or updating");
+                    return;
+                }
+                let Some(settings) = self.settings.as_mut() else {
+                    error!("Can't restart into setup, NVS is unavailable");
+                    return;
+                };
+                match settings.request_setup() {
+                    Ok(_) => {
+                        warn!("Setup requested from the button, restarting into the setup portal");
+                        self.shut_down(PowerDown::Reboot);
+                    }
+                    Err(e) => error!("Failed to request setup: {}", e),
+                }
+            }
+        }
+    }
+
+    // Steps the servos on a raised tick and moves between the safety states, run before every command
+    pub fn tick(&mut self) {
+        // Checked before anything else can move the servos
+        if self.estop_button.poll(Instant::now()) {
+            error!("E-stop button pressed");
+            self.calibration = None;
+            self.set_paused(false);
+            self.abort_self_test();
+            self.cancel_moves();
+            if let Some(session) = self.playback.take() {
+                info!("Trajectory {} interrupted", session.get_slot());
+            }
+            let gripper = self.gripper.as_mut();
+            estop(&mut self.servos, gripper, &mut self.control_state, EStopSource::Button, &mut self.display);
+            self.buzzer.play(Sound::EStop, Instant::now());
+        }
+        if let Some(press) = self.button.poll(Instant::now()) {
+            self.handle_press(press);
+        }
+        if self.tick.take() {
+            let writing = loop_timing::now_us();
+            let was_moving = self.servos.iter().any(|servo| servo.is_moving());
+            for servo in self.servos.iter_mut() {
+                let was_ok = !servo.is_faulted();
+                // Servos hold their pose while a trajectory dwells on a frame
+                match servo.poll(self.playback.is_none()) {
+                    Ok(_) => {},
+                    // Only log the first failure so a dead channel doesn't flood the log every tick
+                    Err(e) if was_ok => error!("Failed to move {}: {}", servo.get_name(), e),
+                    Err(_) => {},
+                }
+            }
+            if let Some(gripper) = self.gripper.as_mut() {
+                let was_ok = !gripper.is_faulted();
+                match gripper.poll(self.playback.is_none(), Instant::now()) {
+                    Ok(_) => {},
+                    Err(e) if was_ok => error!("Failed to move the gripper: {}", e),
+                    Err(_) => {},
+                }
+            }
+            self.trip_overloads();
+            self.loop_timer.record(Phase::Servos, writing);
+            if was_moving
+                && !self.servos.iter().any(|servo| servo.is_moving())
+                && self.control_state == ControlState::Running
+                && self.playback.is_none()
+                && !self.is_self_testing()
+            {
+                info!("Servos reached their goal positions");
+                self.display.release();
+            }
+        }
+
+        if let Some(test) = self.self_test.as_mut().filter(|test| !test.is_finished()) {
+            if let Some(index) = test.step(&mut self.servos, Instant::now()) {
+                let name = self.servos[index].get_name();
+                info!("Self-testing {}", name);
+                self.display.draw_banner("SELF TEST", &format!("Testing: {}", name));
+            }
+            if test.is_finished() {
+                info!("Self-test finished with results {:?}", test.get_results());
+                self.display.release();
+            }
+        }
+
+        if let Some(session) = self.playback.as_mut() {
+            let moving = self.servos.iter().any(|servo| servo.is_moving());
+            match session.next_frame(Instant::now(), moving) {
+                Some(frame) => {
+                    move_to_pose(&mut self.servos, &frame.angles, frame.dwell_ms, Easing::Linear);
+                    self.display.draw_lines(
+                        &[
+                            &format!("Trajectory {}", session.get_slot()),
+                            &format!("frame {} of {}", session.get_frame() + 1, session.frame_count()),
+                        ],
+                        Align::Center,
+                    );
+                }
+                None if session.is_finished() => {
+                    info!("Trajectory {} finished", session.get_slot());
+                    self.playback = None;
+                    self.display.release();
+                }
+                None => {}
+            }
+        }
+        self.advance_queue();
+
+        // E-stopped servos are free to be posed by hand, so that's when feedback is the better record of the pose
+        if let Some(recording) = self.recording.as_mut() {
+            let now = Instant::now();
+            if recording.is_due(now) {
+                let estopped = matches!(self.control_state, ControlState::EStopped(_));
+                let mut angles = [0u16; protocol::SERVO_COUNT];
+                for (angle, servo) in angles.iter_mut().zip(self.servos.iter()) {
+                    *angle = match servo.get_measured_angle() {
+                        Some(measured) if estopped => measured,
+                        _ => servo.get_angle(),
+                    };
+                }
+                if !recording.sample(angles, now) {
+                    warn!("Recording full at {} frames, stopped", recording.frame_count());
+                    (self.uploaded, self.uploaded_at) =
+                        self.recording.take().map(Recording::into_frames).unwrap_or_default();
+                }
+            }
+        }
+
+        self.save_pose();
+
+        // No client can be heard while WiFi is down, so the servos are made safe without waiting for the timeout.
+        // Serial clients still can, they are left to the timeout
+        let current_link = self.link.get();
+        let unreachable = !current_link.up && !CONFIG.serial_commands;
+        if self.control_state == ControlState::Running
+            && (self.failsafe.check(Instant::now()) || (unreachable && self.failsafe.trigger()))
+        {
+            if self.calibration.take().is_some() {
+                warn!("Calibration abandoned by the failsafe");
+            }
+            // Parking can't wait on a pause, nor can the stops and e-stops below
+            self.set_paused(false);
+            self.abort_self_test();
+            self.cancel_moves();
+            if self.playback.take().is_some() {
+                warn!("Trajectory abandoned by the failsafe");
+            }
+            self.failsafe.apply(&mut self.servos);
+            self.display.draw_banner("FAILSAFE", &format!("No packets for\n{} ms", self.failsafe.get_timeout().as_millis()));
+            self.buzzer.play(Sound::Failsafe, Instant::now());
+        }
+        if let Some(owner) = self.session.check(Instant::now()) {
+            warn!("Session of {} expired", owner);
+            show_session(&mut self.display, &self.session);
+        }
+        if current_link != self.link_status {
+            if current_link.up {
+                info!("WiFi back up at {}", current_link.ip);
+                self.display.show(Page::Network);
+            } else {
+                show_link_down(&mut self.display, &self.link, current_link);
+            }
+            self.link_status = current_link;
+        }
+
+        // Shutdown, reboot and sleep stop the servos once they are parked
+        if let ControlState::ShuttingDown { then, started } = self.control_state {
+            if !self.servos.iter().any(|servo| servo.is_moving()) || started.elapsed() >= SHUTDOWN_TIMEOUT {
+                stop_servos(&mut self.servos, self.gripper.as_mut());
+                self.control_state = ControlState::ShutDown;
+                if CONFIG.pose_save_s != 0 {
+                    let angles = self.servos.iter().map(|servo| servo.get_angle()).collect();
+                    save_last_pose(self.settings.as_mut(), &LastPose { clean: true, angles });
+                }
+                match then {
+                    PowerDown::Off => {
+                        warn!("Servos stopped, safe to power off");
+                        self.display.draw_banner("SAFE", "Power off OK");
+                    }
+                    PowerDown::Reboot => {
+                        warn!("Servos stopped, rebooting");
+                        self.display.draw_banner("REBOOT", "Servos stopped\nRestarting...");
+                        thread::sleep(REBOOT_DELAY);
+                        restart();
+                    }
+                    PowerDown::Sleep { wake_s } => {
+                        warn!("Servos stopped, going to sleep");
+                        let wake = match wake_s {
+                            Some(wake_s) => format!("Waking in {} s", wake_s),
+                            None => "Press to wake".to_string(),
+                        };
+                        self.display.draw_banner("SLEEPING", &format!("Servos stopped\n{}", wake));
+                        thread::sleep(SLEEP_DELAY);
+                        // The display would otherwise stay lit on its own supply through the sleep. The display task
+                        // applies it at most MIN_DRAW_INTERVAL after the banner, waiting twice that covers the write
+                        self.display.set_display_on(false);
+                        thread::sleep(tasks::MIN_DRAW_INTERVAL * 2);
+                        sleep::deep_sleep(wake_s);
+                    }
+                }
+            }
+        }
+
+        // The servos stay parked through a firmware update and are stopped before restarting into it. An e-stop during
+        // the download leaves a finished image for the next restart
+        if let ControlState::Updating { estopped, shown } = self.control_state {
+            match self.updater.poll() {
+                UpdateState::Downloading { percent } if percent != shown => {
+                    let progress = match percent {
+                        Some(percent) => format!("Downloading {}%", percent),
+                        None => "Downloading".to_string(),
+                    };
+                    self.display.draw_banner("UPDATING", &format!("{}\nDon't power off", progress));
+                    self.control_state = ControlState::Updating { estopped, shown: percent };
+                }
+                UpdateState::Downloading { .. } | UpdateState::Idle => {}
+                UpdateState::Done => {
+                    stop_servos(&mut self.servos, self.gripper.as_mut());
+                    warn!("Firmware updated, restarting");
+                    self.display.draw_banner("UPDATED", "Servos stopped\nRestarting...");
+                    thread::sleep(REBOOT_DELAY);
+                    restart();
+                }
+                UpdateState::Failed(e) => {
+                    self.control_state = match estopped {
+                        Some(source) => ControlState::EStopped(source),
+                        None => ControlState::Running,
+                    };
+                    self.display.draw_banner("UPDATE FAILED", &wrap_text(&e, DISPLAY_COLUMNS, 3));
+                }
+            }
+        }
+
+        // A low battery parks and then stops the servos, motion waits until it has recovered past the hysteresis
+        self.battery_mv = self.battery.get_pin_mv().map(|pin_mv| self.cutoff.voltage_mv(pin_mv));
+        if let Some(voltage_mv) = self.battery_mv {
+            if self.cutoff.update(voltage_mv) {
+                match self.cutoff.is_low() {
+                    true => warn!("Battery at {} mV, below the {} mV cutoff", voltage_mv, self.cutoff.get_config().cutoff_mv),
+                    false => info!("Battery recovered to {} mV", voltage_mv),
+                }
+            }
+        }
+        match self.control_state {
+            ControlState::Running if self.cutoff.is_low() => {
+                if self.calibration.take().is_some() {
+                    warn!("Calibration abandoned, the battery is low");
+                }
+                self.set_paused(false);
+                self.abort_self_test();
+                self.cancel_moves();
+                if self.playback.take().is_some() {
+                    warn!("Trajectory abandoned, the battery is low");
+                }
+                self.failsafe.park(&mut self.servos);
+                self.control_state = ControlState::LowBattery { stopped: false, started: Instant::now() };
+                self.display.draw_banner("LOW BATT", &format!("{}\nParking servos", format_volts(self.battery_mv)));
+                self.buzzer.play(Sound::LowBattery, Instant::now());
+            }
+            ControlState::LowBattery { stopped: false, started }
+                if !self.servos.iter().any(|servo| servo.is_moving()) || started.elapsed() >= SHUTDOWN_TIMEOUT =>
+            {
+                stop_servos(&mut self.servos, self.gripper.as_mut());
+                for servo in self.servos.iter_mut() {
+                    servo.set_speed_override(None);
+                }
+                self.control_state = ControlState::LowBattery { stopped: true, started };
+                self.display.draw_banner("LOW BATT", &format!("{}\nServos stopped\nCharge the battery", format_volts(self.battery_mv)));
+            }
+            // Stopped servos stay off until the next command moves them
+            ControlState::LowBattery { stopped, .. } if !self.cutoff.is_low() => {
+                if !stopped {
+                    for servo in self.servos.iter_mut() {
+                        servo.set_speed_override(None);
+                    }
+                }
+                self.control_state = ControlState::Running;
+                self.display.release();
+            }
+            _ => {}
+        }
+
+        // A hot chip warns, a critical one parks the servos like the failsafe and motion waits until it has cooled
+        // past the hysteresis. They stay energized, holding the safe pose
+        let changed = self.thermal.update(Instant::now());
+        let celsius = self.thermal.get_celsius().unwrap_or(0);
+        match changed {
+            Some(Level::Critical) => {
+                warn!("Chip at {} C, past the {} C critical temperature", celsius, self.thermal.get_critical_c())
+            }
+            Some(Level::Warm) => warn!("Chip at {} C, past the {} C warning", celsius, self.thermal.get_warn_c()),
+            Some(Level::Normal) => info!("Chip cooled to {} C", celsius),
+            None => {}
+        }
+        let hot_banner = format!("Chip at {} C\nWarning at {} C", celsius, self.thermal.get_warn_c());
+        match self.control_state {
+            ControlState::Running if self.thermal.get_level() == Level::Critical => {
+                if self.calibration.take().is_some() {
+                    warn!("Calibration abandoned, the chip is too hot");
+                }
+                self.set_paused(false);
+                self.abort_self_test();
+                self.cancel_moves();
+                if self.playback.take().is_some() {
+                    warn!("Trajectory abandoned, the chip is too hot");
+                }
+                self.failsafe.park(&mut self.servos);
+                self.control_state = ControlState::Overheated;
+                self.display.draw_banner("OVERHEAT", &format!("Chip at {} C\nServos parked\nLet it cool", celsius));
+            }
+            ControlState::Overheated if self.thermal.get_level() != Level::Critical => {
+                for servo in self.servos.iter_mut() {
+                    servo.set_speed_override(None);
+                }
+                self.control_state = ControlState::Running;
+                match self.thermal.get_level() {
+                    Level::Warm => self.display.draw_banner("HOT", &hot_banner),
+                    _ => self.display.release(),
+                }
+            }
+            ControlState::Running if changed == Some(Level::Warm) => self.display.draw_banner("HOT", &hot_banner),
+            ControlState::Running if changed == Some(Level::Normal) => self.display.release(),
+            _ => {}
+        }
+    }
+
+    // The pose kept through the restart, re-saved as approximate straight away so a crash from here on isn't
+    // mistaken for a clean shutdown
+    fn restore_pose(&mut self) -> Option<LastPose> {
+        if CONFIG.pose_save_s == 0 {
+            return None;
+        }
+        let last_pose = match self.settings.as_ref().map(Settings::load_last_pose) {
+            Some(Ok(Some(pose))) if pose.angles.len() == self.servos.len() => pose,
+            Some(Ok(Some(pose))) => {
+                warn!("Last pose has {} angles for {} servos, ignoring it", pose.angles.len(), self.servos.len());
+                return None;
+            }
+            Some(Ok(None)) | None => return None,
+            Some(Err(e)) => {
+                warn!("Failed to read the last pose: {}", e);
+                return None;
+            }
+        };
+        info!(
+            "Restored the last pose {:?}, {}",
+            last_pose.angles,
+            if last_pose.clean { "stopped by a clean shutdown" } else { "approximate after a crash" }
+        );
+        self.restored = if last_pose.clean { protocol::RESTORED_TRUSTED } else { protocol::RESTORED_APPROXIMATE };
+        let approximate = LastPose { clean: false, angles: last_pose.angles.clone() };
+        if save_last_pose(self.settings.as_mut(), &approximate) {
+            self.saved_pose = approximate.angles;
+        }
+        Some(last_pose)
+    }
+
+    // Wear-limited: at most once per interval, and only once a joint has moved far enough from the saved pose
+    fn save_pose(&mut self) {
+        if CONFIG.pose_save_s == 0 || self.pose_saved_at.elapsed() < Duration::from_secs(CONFIG.pose_save_s as u64) {
+            return;
+        }
+        let angles: Vec<u16> = self.servos.iter().map(|servo| servo.get_angle()).collect();
+        let threshold = servo::from_degrees(CONFIG.pose_save_deg);
+        let moved = self.saved_pose.len() != angles.len()
+            || self.saved_pose.iter().zip(&angles).any(|(saved, angle)| saved.abs_diff(*angle) > threshold);
+        // A failed save waits out the interval too, rather than being retried every pass
+        if moved {
+            if save_last_pose(self.settings.as_mut(), &LastPose { clean: false, angles: angles.clone() }) {
+                self.saved_pose = angles;
+            }
+            self.pose_saved_at = Instant::now();
+        }
+    }
+
+    // Calibrating or self-testing, either of which holds off motion
+    fn is_busy(&self) -> bool {
+        self.calibration.is_some() || self.is_self_testing()
+    }
+
+    fn is_self_testing(&self) -> bool {
+        self.self_test.as_ref().is_some_and(|test| !test.is_finished())
+    }
+
+    // Anything that takes the servos away from the test leaves the joints it hasn't reached untested
+    fn abort_self_test(&mut self) {
+        if let Some(test) = self.self_test.as_mut().filter(|test| !test.is_finished()) {
+            warn!("Self-test abandoned");
+            test.abort();
+        }
+    }
+
+    // Reads the current sensors, stopping the joints on any that has been over the limit too long. Whatever was
+    // driving them goes with them
+    fn trip_overloads(&mut self) {
+        let mut stopped = Vec::new();
+        for index in self.current.sample(self.tick.get_hz()) {
+            let servo = &mut self.servos[index];
+            if servo.is_overloaded() {
+                continue;
+            }
+            error!("{} overloaded, stopped until the current command clears it", servo.get_name());
+            match servo.trip_overload() {
+                Ok(_) => {},
+                Err(e) => error!("Failed to stop {}: {}", servo.get_name(), e),
+            }
+            stopped.push(servo.get_name().to_string());
+        }
+        if stopped.is_empty() {
+            return;
+        }
+        self.abort_self_test();
+        self.cancel_moves();
+        if self.playback.take().is_some() {
+            warn!("Trajectory abandoned, a joint is overloaded");
+        }
+        self.display.draw_banner("OVERLOAD", &format!("{}\nServo stopped\nClear to resume", stopped.join(", ")));
+    }
+
+    // Reports every tagged move cancelled, for anything that takes the servos over from them
+    fn cancel_moves(&mut self) {
+        let cancelled = self.motion_queue.cancel();
+        if !cancelled.is_empty() {
+            info!("{} tagged moves cancelled", cancelled.len());
+        }
+        for (addr, id) in cancelled {
+            self.report_move(addr, id, Status::Cancelled);
+        }
+    }
+
+    // Tells the client that sent a tagged move how it ended. Only UDP clients have a port to be told on
+    fn report_move(&mut self, addr: SocketAddr, id: u16, status: Status) {
+        if !addr.is_ipv4() || addr.port() == 0 {
+            return;
+        }
+        let angles = self.servos.iter().map(|servo| servo.get_angle()).collect();
+        let payload = ReplyPayload::MoveDone { id, angles };
+        let sequence = self.motion_queue.next_sequence();
+        send_reply(&self.replies, addr, sequence, ReplyPacket::new(protocol::MOVE_DONE_COMMAND, status, payload));
+    }
+
+    // Once the servos stop with no trajectory playing, the executing move is done and the next queued one starts.
+    // A queued move that can't start is reported with why, and the one after it is tried
+    fn advance_queue(&mut self) {
+        if self.control_state != ControlState::Running
+            || self.paused
+            || self.calibration.is_some()
+            || self.is_self_testing()
+            || self.playback.is_some()
+            || self.servos.iter().any(|servo| servo.is_moving())
+        {
+            return;
+        }
+        if let Some((addr, id)) = self.motion_queue.finish() {
+            info!("Move {} done", id);
+            self.report_move(addr, id, Status::Ok);
+        }
+        while let Some(QueuedMove { addr, id, motion }) = self.motion_queue.next() {
+            info!("Starting queued move {}", id);
+            let status = match motion {
+                Motion::Pose { angles, duration_ms, easing } => {
+                    self.stats.count_applied();
+                    let status = move_to_pose(&mut self.servos, &angles, duration_ms, easing);
+                    self.display.release();
+                    status
+                }
+                Motion::Trajectory { slot } => self.play_trajectory(slot, false).0,
+            };
+            match status {
+                Status::Ok | Status::Clamped => {
+                    self.motion_queue.start(addr, id);
+                    return;
+                }
+                status => self.report_move(addr, id, status),
+            }
+        }
+    }
+
+    // Loads the slot from NVS and starts it, or the status saying why it can't be played
+    fn play_trajectory(&mut self, slot: u8, looping: bool) -> (Status, ReplyPayload) {
+        let loaded = match self.settings.as_ref() {
+            _ if slot >= trajectory::SLOT_COUNT => {
+                error!("Trajectory slot {} out of range", slot);
+                Err(Status::BadArgument)
+            }
+            Some(settings) => match settings.load_trajectory(slot) {
+                Ok(Some(stored)) => Ok(stored),
+                Ok(None) => {
+                    error!("Trajectory slot {} is empty", slot);
+                    Err(Status::BadArgument)
+                }
+                Err(e) => {
+                    error!("Failed to load trajectory {}: {}", slot, e);
+                    Err(Status::HardwareError)
+                }
+            },
+            None => {
+                error!("Can't load trajectory {}, NVS is unavailable", slot);
+                Err(Status::HardwareError)
+            }
+        };
+        match loaded {
+            Ok((frames, captured_ms)) => {
+                let looping_note = if looping { ", looping" } else { "" };
+                info!("Playing trajectory {} ({} frames{})", slot, frames.len(), looping_note);
+                let count = frames.len() as u8;
+                let mut playback = Playback::new(slot, frames, looping);
+                // Queued while paused, it starts on the resume
+                if self.paused {
+                    playback.pause(Instant::now());
+                }
+                self.playback = Some(playback);
+                (Status::Ok, ReplyPayload::Trajectory { slot, frames: count, captured_ms })
+            }
+            Err(status) => (status, ReplyPayload::Trajectory { slot, frames: 0, captured_ms: None }),
+        }
+    }
+
+    // Freezes or releases the servos, the gripper and any trajectory together
+    fn set_paused(&mut self, paused: bool) {
+        if paused == self.paused {
+            return;
+        }
+        self.paused = paused;
+        for servo in self.servos.iter_mut() {
+            servo.set_paused(paused);
+        }
+        if let Some(gripper) = self.gripper.as_mut() {
+            gripper.set_paused(paused);
+        }
+        if let Some(playback) = self.playback.as_mut() {
+            match paused {
+                true => playback.pause(Instant::now()),
+                false => playback.resume(Instant::now()),
+            }
+        }
+    }
+
+    // Status LED, buzzer and display, from the state tick() left
+    pub fn render(&mut self) {
+        let active = !self.paused
+            && (self.playback.is_some()
+                || self.servos.iter().any(|servo| servo.is_moving())
+                || self.gripper.as_ref().is_some_and(Gripper::is_moving));
+        let pattern = led_pattern(self.control_state, &self.failsafe, self.link_status.up, active);
+        self.status_led.set_pattern(pattern, Instant::now());
+        self.status_led.update(Instant::now());
+        self.buzzer.update(Instant::now());
+        self.display_status.set_joints(&self.servos);
+        self.display_status.battery_mv = self.battery_mv;
+        self.display_status.temperature_c = self.thermal.get_celsius();
+        self.display_status.gripper = self.gripper.as_ref().map(Gripper::status);
+        self.display_status.speed_scale = self.speed_scale;
+        self.display_status.paused = self.paused;
+        self.display_status.link = self.link_status;
+        self.display_status.owner = self.session.get_owner();
+        self.display_status.timing = self.loop_timer.timing();
+        self.display.tick(&self.display_status, Instant::now());
+    }
+
+    // Checked every pass rather than waited on, so a heartbeat never holds up a command
+    fn heartbeat(&mut self) {
+        if let Some(subscriber) = self.subscription.as_mut() {
+            if self.stats.get().heartbeat_failures >= CONFIG.heartbeat_max_failures {
+                warn!("Heartbeats to {} keep failing, dropping the subscription", subscriber.get_addr());
+                self.subscription = None;
+                self.stats.heartbeat_sent(true);
+            } else if let Some(heartbeat_sequence) = subscriber.poll(Instant::now()) {
+                let heartbeat = ReplyPayload::Heartbeat {
+                    flags: safety_flags(self.control_state, &self.failsafe, &self.cutoff, self.paused),
+                    rssi: stats::rssi().unwrap_or(0),
+                    angles: self.servos.iter().map(|servo| servo.get_angle()).collect(),
+                    moving: self.servos.iter().map(|servo| servo.is_moving()).collect(),
+                    time_ms: self.clock.unix_ms(),
+                };
+                let packet = ReplyPacket::new(protocol::HEARTBEAT_COMMAND, Status::Ok, heartbeat);
+                send_reply(&self.replies, subscriber.get_addr(), heartbeat_sequence, packet);
+            }
+        }
+    }
+
+    // Carries out one command from the network task, every command gets exactly one reply. The command is kept in the
+    // history with the status it was answered with
+    pub fn handle_packet(&mut self, from_addr: SocketAddr, control: ControlPacket) -> ReplyPacket {
+        let command = control.command();
+        let params = history::parameters(&control);
+        let reply = self.process(from_addr, control);
+        if let Some(params) = params {
+            self.history.record(from_addr, command, &params, reply.status);
+        }
+        reply
+    }
+
+    fn process(&mut self, from_addr: SocketAddr, control: ControlPacket) -> ReplyPacket {
+        let control = self.mirror.apply(control, &self.servos);
+        self.display.wake(Instant::now());
+
+        // Only the session owner may change anything, anyone can still look, pause and e-stop
+        self.session.packet_received(from_addr.ip(), Instant::now());
+        if !matches!(
+            control,
+            ControlPacket::Ping
+                | ControlPacket::Telemetry { .. }
+                | ControlPacket::Status
+                | ControlPacket::Subscribe { .. }
+                | ControlPacket::Gripper(None)
+                | ControlPacket::Teach(None)
+                | ControlPacket::SelfTest { start: false }
+                | ControlPacket::SpeedScale(None)
+                | ControlPacket::Mirror(None)
+                | ControlPacket::LoopTiming { reset: false }
+                | ControlPacket::History { .. }
+                | ControlPacket::Current(None)
+                | ControlPacket::Pause
+                | ControlPacket::EStop
+        )
+            && !self.session.allows(from_addr.ip())
+        {
+            error!("Command {} from {} rejected, the session is held by {}", control.command(), from_addr, self.session.owner_v4());
+            self.stats.count_rejected();
+            return ReplyPacket::new(control.command(), Status::Busy, ReplyPayload::Owner(self.session.owner_v4()));
+        }
+
+        // Only the client in control keeps the failsafe from firing, someone else looking on doesn't
+        if self.session.allows(from_addr.ip()) && self.failsafe.packet_received(Instant::now()) {
+            self.failsafe.release(&mut self.servos);
+            if self.control_state == ControlState::Running {
+                self.display.release();
+            }
+        }
+
+        // Motion is refused outside Running or while calibrating or self-testing, calibration too once shut down, low,
+        // overheated, updating or self-testing.
+        // While paused, moves are refused unless configured to wait for the resume
+        let moves = control.is_motion();
+        let shut_down = self.control_state.is_shutting_down()
+            || matches!(
+                self.control_state,
+                ControlState::LowBattery { .. } | ControlState::Overheated | ControlState::Updating { .. }
+            );
+        if ((moves || matches!(control, ControlPacket::Pause | ControlPacket::Resume))
+            && (self.control_state != ControlState::Running || self.is_busy()))
+            || (moves && self.paused && !CONFIG.pause_queue_moves)
+            || ((shut_down || self.is_self_testing()) && matches!(control, ControlPacket::Calibration { .. }))
+        {
+            self.stats.count_rejected();
+            let status = self.motion_refusal();
+            return ReplyPacket::new(control.command(), status, positions(&self.servos));
+        }
+
+        // Moves carry one angle per servo, the reply tells the client how many this arm has
+        match control {
+            ControlPacket::SetAngles(ref angles) | ControlPacket::Pose { ref angles, .. } if angles.len() != self.servos.len() => {
+                error!("Move has {} angles for {} servos", angles.len(), self.servos.len());
+                self.stats.count_malformed();
+                return ReplyPacket::new(control.command(), Status::BadLength, positions(&self.servos));
+            }
+            _ => {},
+        }
+
+        // A joint whose channel failed at boot keeps its index, so commands for it alone are refused rather than
+        // landing on another joint
+        if let Some(index) = control.joint_index() {
+            if let Some(error) = self.servos.get(index as usize).and_then(Servo::get_unavailable) {
+                error!("{} is unavailable: {}", self.servos[index as usize].get_name(), error);
+                return ReplyPacket::new(control.command(), Status::HardwareError, ReplyPayload::Index(index));
+            }
+        }
+
+        // A joint its current sensor stopped stays off until cleared, so moves driving it are refused. Moving every
+        // joint would drive it too, only the gripper still moves
+        if moves || matches!(control, ControlPacket::Calibration { .. }) {
+            let overloaded = match control.joint_index() {
+                Some(index) => {
+                    Some(index).filter(|&index| self.servos.get(index as usize).is_some_and(Servo::is_overloaded))
+                }
+                None if matches!(control, ControlPacket::Gripper(_)) => None,
+                None => self.servos.iter().position(Servo::is_overloaded).map(|index| index as u8),
+            };
+            if let Some(index) = overloaded {
+                let name = self.servos[index as usize].get_name();
+                error!("{} is overloaded, the current command must clear it first", name);
+                self.stats.count_rejected();
+                return ReplyPacket::new(control.command(), Status::Overloaded, ReplyPayload::Index(index));
+            }
+        }
+
+        // A looping trajectory never ends, so there would be nothing to report
+        if let ControlPacket::PlayTrajectory { slot, looping: true, tag: Some(_) } = control {
+            error!("Trajectory {} loops, it can't be tagged", slot);
+            let payload = ReplyPayload::Trajectory { slot, frames: 0, captured_ms: None };
+            return ReplyPacket::new(control.command(), Status::BadArgument, payload);
+        }
+        // A queued move waits behind the tagged moves ahead of it, taking nothing over until its turn
+        let queued = match control {
+            ControlPacket::Pose { ref angles, duration_ms, easing, tag: Some(MoveTag { id, queue: true }), .. } => {
+                Some((id, Motion::Pose { angles: angles.clone(), duration_ms, easing }))
+            }
+            ControlPacket::PlayTrajectory { slot, tag: Some(MoveTag { id, queue: true }), .. } => {
+                Some((id, Motion::Trajectory { slot }))
+            }
+            _ => None,
+        };
+        if let Some((id, motion)) = queued.filter(|_| !self.motion_queue.is_idle()) {
+            let status = match self.motion_queue.push(QueuedMove { addr: from_addr, id, motion }) {
+                true => {
+                    info!("Move {} queued, {} waiting", id, self.motion_queue.depth());
+                    Status::Ok
+                }
+                false => {
+                    error!("Move {} refused, the motion queue is full", id);
+                    self.stats.count_rejected();
+                    Status::Busy
+                }
+            };
+            let payload = match control {
+                ControlPacket::PlayTrajectory { slot, .. } => {
+                    ReplyPayload::Trajectory { slot, frames: 0, captured_ms: None }
+                }
+                _ => positions(&self.servos),
+            };
+            return ReplyPacket::new(control.command(), status, payload);
+        }
+
+        // Anything else that moves the servos takes over from a running trajectory and cancels the tagged moves
+        if matches!(
+            control,
+            ControlPacket::SetAngles(_)
+                | ControlPacket::Pose { .. }
+                | ControlPacket::MoveJoint { .. }
+                | ControlPacket::Jog { .. }
+                | ControlPacket::PlayTrajectory { .. }
+                | ControlPacket::RecallPreset { .. }
+                | ControlPacket::Calibration { .. }
+                | ControlPacket::SelfTest { start: true }
+                | ControlPacket::Shutdown { .. }
+                | ControlPacket::Sleep { .. }
+                | ControlPacket::Update { .. }
+                | ControlPacket::EStop
+        ) {
+            self.cancel_moves();
+            if let Some(session) = self.playback.take() {
+                info!("Trajectory {} interrupted", session.get_slot());
+            }
+        }
+        let tag = match control {
+            ControlPacket::Pose { tag, .. } | ControlPacket::PlayTrajectory { tag, .. } => tag,
+            _ => None,
+        };
+        if let Some(MoveTag { id, .. }) = tag {
+            self.motion_queue.start(from_addr, id);
+        }
+
+        let command = control.command();
+        let (status, payload) = self.dispatch(from_addr, control);
+        // A tagged move refused outright has its answer already
+        if tag.is_some() && !matches!(status, Status::Ok | Status::Clamped) {
+            self.motion_queue.finish();
+        }
+        ReplyPacket::new(command, status, payload)
+    }
+
+    // Routes each command to its handler
+    fn dispatch(&mut self, from_addr: SocketAddr, control: ControlPacket) -> (Status, ReplyPayload) {
+        match control {
+            ControlPacket::SetAngles(angles) => self.handle_move(&angles),
+            ControlPacket::Pose { angles, duration_ms, easing, .. } => self.handle_pose(&angles, duration_ms, easing),
+            ControlPacket::MoveJoint { index, angle, speed } => self.handle_move_joint(index, angle, speed),
+            ControlPacket::Jog { index, delta_tenths } => self.handle_jog(index, delta_tenths),
+            ControlPacket::Ping => self.handle_ping(from_addr),
+            ControlPacket::Telemetry { .. } => self.handle_telemetry(),
+            ControlPacket::Status => self.handle_status(),
+            ControlPacket::Claim => self.handle_claim(from_addr),
+            ControlPacket::Release => self.handle_release(from_addr),
+            ControlPacket::Subscribe { interval_ms: 0 } => self.handle_unsubscribe(),
+            ControlPacket::Subscribe { interval_ms } => self.handle_subscribe(from_addr, interval_ms),
+            ControlPacket::Config(ConfigCommand::Servo(config)) => self.handle_servo_config(config),
+            ControlPacket::Config(ConfigCommand::Failsafe(config)) => self.handle_failsafe_config(config),
+            ControlPacket::Config(ConfigCommand::Battery(config)) => self.handle_battery_config(config),
+            ControlPacket::Config(ConfigCommand::Display(config)) => self.handle_display_config(config),
+            ControlPacket::Config(ConfigCommand::Buzzer(config)) => self.handle_buzzer_config(config),
+            ControlPacket::Limits { index, min_limit, max_limit } => self.handle_limits(index, min_limit, max_limit),
+            ControlPacket::Calibration { index, command } => self.handle_calibration(index, command),
+            ControlPacket::UploadTrajectory(frames) => self.handle_upload_trajectory(frames),
+            ControlPacket::StoreTrajectory { slot } => self.handle_store_trajectory(slot),
+            ControlPacket::PlayTrajectory { slot, looping, .. } => self.handle_play_trajectory(slot, looping),
+            ControlPacket::SavePreset { slot, name } => self.handle_save_preset(slot, &name),
+            ControlPacket::RecallPreset { slot, duration_ms } => self.handle_recall_preset(slot, duration_ms),
+            ControlPacket::ListPresets => self.handle_list_presets(),
+            ControlPacket::ClearPreset { slot } => self.handle_clear_preset(slot),
+            ControlPacket::LogLevel(level) => self.handle_log_level(level),
+            ControlPacket::LogTarget { persist, level, target } => self.handle_log_target(persist, level, &target),
+            ControlPacket::Update { url } => self.handle_update(from_addr, url),
+            ControlPacket::Gripper(command) => self.handle_gripper(command),
+            ControlPacket::Teach(command) => self.handle_teach(command),
+            ControlPacket::SpeedScale(command) => self.handle_speed_scale(command),
+            ControlPacket::Mirror(command) => self.handle_mirror(command),
+            ControlPacket::LoopTiming { reset } => self.handle_loop_timing(reset),
+            ControlPacket::History { chunk } => self.handle_history(from_addr, chunk),
+            ControlPacket::Current(command) => self.handle_current(from_addr, command),
+            ControlPacket::Pause => self.handle_pause(from_addr),
+            ControlPacket::Resume => self.handle_resume(from_addr),
+            ControlPacket::SelfTest { start } => self.handle_self_test(start),
+            ControlPacket::Shutdown { reboot, confirm } => self.handle_shutdown(from_addr, reboot, confirm),
+            ControlPacket::Sleep { confirm, wake_s } => self.handle_sleep(from_addr, confirm, wake_s),
+            ControlPacket::EStop => self.handle_estop(from_addr),
+            ControlPacket::ClearEStop => self.handle_clear_estop(from_addr),
+        }
+    }
+
+    fn handle_move(&mut self, angles: &[u16]) -> (Status, ReplyPayload) {
+        if let Some(reply) = self.deduplicate(angles) {
+            return reply;
+        }
+        self.stats.count_applied();
+        let status = move_to_pose(&mut self.servos, angles, 0, Easing::Linear);
+        self.display.release();
+
+        (status, positions(&self.servos))
+    }
+
+    fn handle_pose(&mut self, angles: &[u16], duration_ms: u16, easing: Easing) -> (Status, ReplyPayload) {
+        if let Some(reply) = self.deduplicate(angles) {
+            return reply;
+        }
+        self.stats.count_applied();
+        info!("Moving to pose over {} ms, {:?}", duration_ms, easing);
+        let status = move_to_pose(&mut self.servos, angles, duration_ms, easing);
+        self.display.release();
+
+        (status, positions(&self.servos))
+    }
+
+    // A client streaming a pose that hasn't changed is still answered, without touching the servos or display
+    fn deduplicate(&mut self, angles: &[u16]) -> Option<(Status, ReplyPayload)> {
+        let status = unchanged_pose(&self.servos, angles)?;
+        self.stats.count_deduplicated();
+        Some((status, positions(&self.servos)))
+    }
+
+    fn handle_move_joint(&mut self, index: u8, angle: u16, speed: Option<u16>) -> (Status, ReplyPayload) {
+        match self.servos.get_mut(index as usize) {
+            Some(servo) => {
+                let result = match speed {
+                    Some(speed) => servo.set_angle_at(angle, speed),
+                    None => servo.set_angle(angle),
+                };
+                let status = match result {
+                    Ok(true) => Status::Clamped,
+                    Ok(false) => Status::Ok,
+                    Err(e) => {
+                        error!("Failed to set angle of {}: {}", servo.get_name(), e);
+                        Status::HardwareError
+                    }
+                };
+                let position = ServoPosition { angle: servo.get_angle(), status: servo.status() };
+                self.display.release();
+
+                (status, ReplyPayload::Joint { index, position })
+            }
+            None => {
+                error!("Servo index {} out of range", index);
+                (Status::BadArgument, ReplyPayload::Index(index))
+            }
+        }
+    }
+
+    // Added to the goal here rather than by the client, so jogs sent mid-move still add up
+    fn handle_jog(&mut self, index: u8, delta_tenths: i8) -> (Status, ReplyPayload) {
+        match self.servos.get_mut(index as usize) {
+            Some(servo) => {
+                let status = match servo.jog(delta_tenths) {
+                    Ok(true) => Status::Clamped,
+                    Ok(false) => Status::Ok,
+                    Err(e) => {
+                        error!("Failed to jog {}: {}", servo.get_name(), e);
+                        Status::HardwareError
+                    }
+                };
+                let goal_tenths = servo.get_goal();
+                debug!("Jogged {} by {} tenths to {}", servo.get_name(), delta_tenths, Tenths(goal_tenths));
+                let payload = ReplyPayload::Jog { index, goal_tenths, status: servo.status() };
+                self.display.release();
+
+                (status, payload)
+            }
+            None => {
+                error!("Servo index {} out of range", index);
+                (Status::BadArgument, ReplyPayload::Index(index))
+            }
+        }
+    }
+
+    fn handle_ping(&self, from_addr: SocketAddr) -> (Status, ReplyPayload) {
+        info!("Received Ping Signal");
+        if CONFIG.legacy_ping {
+            info!("Sending legacy ping reply back to {}", from_addr);
+            (Status::Ok, positions(&self.servos))
+        } else {
+            info!("Sending versioned ping reply back to {}", from_addr);
+            (
+                Status::Ok,
+                ReplyPayload::Ping {
+                    version_maj: VERSION_MAJ as u8,
+                    version_min: VERSION_MIN as u8,
+                    positions: servo_positions(&self.servos),
+                },
+            )
+        }
+    }
+
+    fn handle_telemetry(&self) -> (Status, ReplyPayload) {
+        info!("Received Telemetry Signal");
+        let counts = self.stats.get();
+        let telemetry = Telemetry {
+            version_maj: VERSION_MAJ as u8,
+            version_min: VERSION_MIN as u8,
+            uptime_s: self.stats.uptime_s(),
+            free_heap: stats::free_heap(),
+            rssi: stats::rssi().unwrap_or(0),
+            received: counts.received,
+            rejected: counts.rejected,
+            malformed: counts.malformed,
+            flags: safety_flags(self.control_state, &self.failsafe, &self.cutoff, self.paused),
+            auth_failures: counts.auth_failures,
+            reset_reason: self.reset_reason as u8,
+            battery_mv: self.battery_mv.unwrap_or(0),
+            last_crash: self.last_crash.clone(),
+            log_level: remote_log::console_levels().default as u8,
+            gripper: self.gripper.as_ref().map(Gripper::status),
+            joints: self
+                .servos
+                .iter()
+                .map(|servo| MeasuredAngle { angle: servo.get_angle(), measured: servo.get_measured_angle() })
+                .collect(),
+            applied: counts.applied,
+            deduplicated: counts.deduplicated,
+            currents: joint_currents(&self.servos, &self.current),
+        };
+        (Status::Ok, ReplyPayload::Telemetry(telemetry))
+    }
+
+    fn handle_status(&self) -> (Status, ReplyPayload) {
+        let joints = self
+            .servos
+            .iter()
+            .map(|servo| JointStatus { angle: servo.get_angle(), goal: servo.get_goal(), status: servo.status() })
+            .collect();
+        let payload = ReplyPayload::Status {
+            flags: safety_flags(self.control_state, &self.failsafe, &self.cutoff, self.paused),
+            rssi: stats::rssi().unwrap_or(0),
+            uptime_s: self.stats.uptime_s(),
+            battery_mv: self.battery_mv.unwrap_or(0),
+            joints,
+            restored: self.restored,
+            mirrored: self.mirror.is_enabled(),
+            time_ms: self.clock.unix_ms(),
+            timing: self.loop_timer.timing(),
+            queued: self.motion_queue.depth(),
+            executing: self.motion_queue.get_executing(),
+            unavailable: unavailable_joints(&self.servos),
+            i2c_devices: self.i2c_devices.clone(),
+            temperature_c: self.thermal.get_celsius(),
+            thermal: self.thermal.get_level().to_byte(),
+            wake_reason: self.wake_reason as u8,
+        };
+        (Status::Ok, payload)
+    }
+
+    fn handle_claim(&mut self, from_addr: SocketAddr) -> (Status, ReplyPayload) {
+        let renewed = self.session.get_owner().is_some();
+        self.session.claim(from_addr.ip(), Instant::now()); // Another client's claim was refused above
+        if !renewed {
+            info!("Session claimed by {}", from_addr);
+            show_session(&mut self.display, &self.session);
+            self.buzzer.play(Sound::Connected, Instant::now());
+        }
+        (Status::Ok, ReplyPayload::Owner(self.session.owner_v4()))
+    }
+
+    fn handle_release(&mut self, from_addr: SocketAddr) -> (Status, ReplyPayload) {
+        if self.session.release(from_addr.ip()) {
+            info!("Session released by {}", from_addr);
+            show_session(&mut self.display, &self.session);
+        }
+        (Status::Ok, ReplyPayload::Owner(self.session.owner_v4()))
+    }
+
+    fn handle_unsubscribe(&mut self) -> (Status, ReplyPayload) {
+        if let Some(subscriber) = self.subscription.take() {
+            info!("Heartbeats to {} stopped", subscriber.get_addr());
+        }
+        (Status::Ok, ReplyPayload::Empty)
+    }
+
+    fn handle_subscribe(&mut self, from_addr: SocketAddr, interval_ms: u16) -> (Status, ReplyPayload) {
+        let interval = Duration::from_millis(interval_ms as u64);
+        if interval < heartbeat::MIN_INTERVAL {
+            error!("Heartbeat interval {} ms rejected, the minimum is {} ms", interval_ms, heartbeat::MIN_INTERVAL.as_millis());
+            (Status::BadArgument, ReplyPayload::Empty)
+        } else {
+            info!("Sending heartbeats to {} every {} ms", from_addr, interval_ms);
+            self.subscription = Some(Subscription::new(from_addr, interval));
+            self.stats.heartbeat_sent(true); // Failures to a previous subscriber don't count against this one
+            (Status::Ok, ReplyPayload::Empty)
+        }
+    }
+
+    fn handle_servo_config(&mut self, config: ServoConfig) -> (Status, ReplyPayload) {
+        info!("Received Config Signal");
+        match self.servos.get_mut(config.index as usize) {
+            Some(servo) => {
+                servo.set_speed(config.speed);
+                servo.set_detach_timeout(config.detach_s);
+                if let Some(accel) = config.accel {
+                    servo.set_accel(accel);
+                }
+                self.failsafe.set_safe_angle(config.index as usize, config.safe_angle);
+                let status = match servo.set_trim(config.trim).and_then(|_| servo.set_reversed(config.reversed)) {
+                    Ok(_) => Status::Ok,
+                    Err(e) => {
+                        error!("Failed to apply config to {}: {}", servo.get_name(), e);
+                        Status::HardwareError
+                    }
+                };
+                info!(
+                    "{} configured: {} deg/s, {} deg/s², trim {}, reversed {}, safe angle {}, detach after {} s",
+                    servo.get_name(),
+                    servo.get_speed(),
+                    servo.get_accel(),
+                    servo.get_trim(),
+                    servo.is_reversed(),
+                    Tenths(config.safe_angle),
+                    servo.get_detach_timeout()
+                );
+                save_calibration(self.settings.as_mut(), config.index as usize, servo);
+                let applied = ServoConfig {
+                    index: config.index,
+                    speed: servo.get_speed(),
+                    trim: servo.get_trim(),
+                    reversed: servo.is_reversed(),
+                    safe_angle: self.failsafe.get_safe_angle(config.index as usize).unwrap_or(0),
+                    detach_s: servo.get_detach_timeout(),
+                    // Only a client that sent one knows to read it
+                    accel: config.accel.map(|_| servo.get_accel()),
+                };
+                (status, ReplyPayload::ServoConfig(applied))
+            }
+            None => {
+                error!("Servo index {} out of range", config.index);
+                (Status::BadArgument, ReplyPayload::Index(config.index))
+            }
+        }
+    }
+
+    fn handle_failsafe_config(&mut self, config: FailsafeConfig) -> (Status, ReplyPayload) {
+        info!("Received Config Signal");
+        match FailsafeAction::from_byte(config.action) {
+            Some(action) => {
+                self.failsafe.set_timeout(Duration::from_millis(config.timeout_ms as u64));
+                self.failsafe.set_action(action);
+                info!("Failsafe configured: timeout {} ms, action {:?}", config.timeout_ms, action);
+                // Echoes what the failsafe now runs with, like the servo config's reply
+                let applied = FailsafeConfig {
+                    timeout_ms: self.failsafe.get_timeout().as_millis().min(u16::MAX as u128) as u16,
+                    action: self.failsafe.get_action().to_byte(),
+                };
+                (Status::Ok, ReplyPayload::FailsafeConfig(applied))
+            }
+            None => {
+                error!("Invalid failsafe action {}", config.action);
+                (Status::BadArgument, ReplyPayload::Index(protocol::FAILSAFE_CONFIG_INDEX))
+            }
+        }
+    }
+
+    fn handle_battery_config(&mut self, config: BatteryConfig) -> (Status, ReplyPayload) {
+        info!("Received Config Signal");
+        if config.divider == 0 {
+            error!("Battery divider can't be 0");
+            (Status::BadArgument, ReplyPayload::Index(protocol::BATTERY_CONFIG_INDEX))
+        } else {
+            // The cutoff is checked against the new figures on the next pass
+            self.cutoff.set_config(config);
+            info!(
+                "Battery configured: divider {}, cutoff {} mV, hysteresis {} mV",
+                config.divider, config.cutoff_mv, config.hysteresis_mv
+            );
+            if let Some(settings) = self.settings.as_mut() {
+                match settings.save_battery(&config) {
+                    Ok(_) => info!("Battery config saved"),
+                    Err(e) => error!("Failed to save the battery config: {}", e),
+                }
+            }
+            (Status::Ok, ReplyPayload::BatteryConfig(config))
+        }
+    }
+
+    fn handle_display_config(&mut self, config: DisplayConfig) -> (Status, ReplyPayload) {
+        info!("Received Config Signal");
+        self.display.set_config(config);
+        info!(
+            "Display configured: brightness {}, dim after {} s, sleep after {} s, flipped {}",
+            config.brightness, config.dim_s, config.sleep_s, config.flipped
+        );
+        if let Some(settings) = self.settings.as_mut() {
+            match settings.save_display(&config) {
+                Ok(_) => info!("Display config saved"),
+                Err(e) => error!("Failed to save the display config: {}", e),
+            }
+        }
+        (Status::Ok, ReplyPayload::DisplayConfig(self.display.get_config()))
+    }
+
+    fn handle_buzzer_config(&mut self, config: BuzzerConfig) -> (Status, ReplyPayload) {
+        info!("Received Config Signal");
+        self.buzzer.set_muted(config.muted);
+        info!("Buzzer {}", if config.muted { "muted" } else { "unmuted" });
+        if let Some(settings) = self.settings.as_mut() {
+            match settings.save_buzzer_muted(config.muted) {
+                Ok(_) => info!("Buzzer setting saved"),
+                Err(e) => error!("Failed to save the buzzer setting: {}", e),
+            }
+        }
+        (Status::Ok, ReplyPayload::BuzzerConfig(config))
+    }
+
+    fn handle_limits(&mut self, index: u8, min_limit: u16, max_limit: u16) -> (Status, ReplyPayload) {
+        info!("Received Limits Signal");
+        match self.servos.get_mut(index as usize) {
+            Some(servo) => {
+                let was_clamped = servo.set_limits(min_limit, max_limit);
+                save_calibration(self.settings.as_mut(), index as usize, servo);
+                let (min_limit, max_limit) = servo.get_limits();
+                (
+                    if was_clamped { Status::Clamped } else { Status::Ok },
+                    ReplyPayload::Limits { index, min_limit, max_limit },
+                )
+            }
+            None => {
+                error!("Servo index {} out of range", index);
+                (Status::BadArgument, ReplyPayload::Index(index))
+            }
+        }
+    }
+
+    fn handle_calibration(&mut self, index: u8, command: CalibrationCommand) -> (Status, ReplyPayload) {
+        info!("Received Calibration Signal");
+        let mut finished = false;
+        let reply = match self.servos.get_mut(index as usize) {
+            None => {
+                error!("Servo index {} out of range", index);
+                (Status::BadArgument, ReplyPayload::Calibration { command, index, duty: 0 })
+            }
+            Some(servo) => match (command, self.calibration.as_mut()) {
+                (CalibrationCommand::Enter, Some(session)) => {
+                    error!("Servo {} is already being calibrated", session.get_index());
+                    (Status::Busy, ReplyPayload::Calibration { command, index, duty: servo.get_duty() as u16 })
+                }
+                (CalibrationCommand::Enter, None) => {
+                    // Hold the servo where it is, now outside of poll()'s control
+                    let session = CalibrationSession::new(index as usize, servo.get_duty(), servo.get_duty_endpoints());
+                    let duty = session.get_duty() as u16;
+                    match servo.set_duty(duty) {
+                        Ok(_) => {
+                            info!("Calibrating {}", servo.get_name());
+                            self.display.draw_lines(
+                                &[&format!("CAL: {}", servo.get_name()), &format!("duty={}", duty)],
+                                Align::Center,
+                            );
+                            self.calibration = Some(session);
+                            (Status::Ok, ReplyPayload::Calibration { command, index, duty })
+                        }
+                        Err(e) => {
+                            error!("Failed to start calibrating {}: {}", servo.get_name(), e);
+                            (Status::HardwareError, ReplyPayload::Calibration { command, index, duty })
+                        }
+                    }
+                }
+                // The feedback readings need no session, only the joint held at the angle being calibrated
+                (CalibrationCommand::FeedbackMin(reading), _) => {
+                    let (status, reading) =
+                        calibrate_feedback(servo, self.settings.as_mut(), index as usize, reading, false);
+                    (status, ReplyPayload::Calibration { command, index, duty: reading })
+                }
+                (CalibrationCommand::FeedbackMax(reading), _) => {
+                    let (status, reading) =
+                        calibrate_feedback(servo, self.settings.as_mut(), index as usize, reading, true);
+                    (status, ReplyPayload::Calibration { command, index, duty: reading })
+                }
+                (command, Some(session)) if session.get_index() == index as usize => {
+                    let status = match command {
+                        // Raw widths only within a session, like raw duties, so nothing else skips the limits
+                        CalibrationCommand::SetDuty(_) | CalibrationCommand::SetPulse(_) => {
+                            let (written, duty) = match command {
+                                CalibrationCommand::SetPulse(pulse_us) => {
+                                    (servo.set_pulse_us(pulse_us), servo.pulse_to_duty(pulse_us))
+                                }
+                                CalibrationCommand::SetDuty(duty) => (servo.set_duty(duty), duty as u32),
+                                _ => unreachable!(),
+                            };
+                            match written {
+                                Ok(_) => {
+                                    session.set_duty(duty);
+                                    let title = format!("CAL: {}", servo.get_name());
+                                    let shown = format!("duty={} {}us", duty, servo.duty_to_pulse_us(duty));
+                                    self.display.draw_lines(&[&title, &shown], Align::Center);
+                                    Status::Ok
+                                }
+                                Err(e) => {
+                                    error!("Failed to set duty of {}: {}", servo.get_name(), e);
+                                    Status::HardwareError
+                                }
+                            }
+                        }
+                        CalibrationCommand::CaptureMin => {
+                            session.capture_min();
+                            info!("{} min duty captured at {}", servo.get_name(), session.get_duty());
+                            Status::Ok
+                        }
+                        CalibrationCommand::CaptureMax => {
+                            session.capture_max();
+                            info!("{} max duty captured at {}", servo.get_name(), session.get_duty());
+                            Status::Ok
+                        }
+                        CalibrationCommand::Exit => match session.endpoints() {
+                            Ok((min_duty, max_duty)) => {
+                                servo.set_duty_endpoints(min_duty, max_duty);
+                                // Drive the servo back to its angle using the new endpoints
+                                servo.set_angle_logged(servo.get_angle());
+                                save_calibration(self.settings.as_mut(), index as usize, servo);
+                                finished = true;
+                                Status::Ok
+                            }
+                            Err(e) => {
+                                error!("Can't finish calibrating {}: {}", servo.get_name(), e);
+                                Status::BadArgument
+                            }
+                        },
+                        CalibrationCommand::Enter
+                        | CalibrationCommand::FeedbackMin(_)
+                        | CalibrationCommand::FeedbackMax(_) => unreachable!(),
+                    };
+                    (status, ReplyPayload::Calibration { command, index, duty: session.get_duty() as u16 })
+                }
+                (command, _) => {
+                    error!("Servo {} is not being calibrated", index);
+                    (Status::BadArgument, ReplyPayload::Calibration { command, index, duty: servo.get_duty() as u16 })
+                }
+            },
+        };
+        if finished {
+            self.calibration = None;
+            self.display.release();
+        }
+        reply
+    }
+
+    fn handle_upload_trajectory(&mut self, frames: Vec<Keyframe>) -> (Status, ReplyPayload) {
+        info!("Received Trajectory Upload Signal");
+        let count = frames.len() as u8;
+        // A client's upload wasn't captured here, there is no telling when it was
+        let reply = ReplyPayload::Trajectory { slot: protocol::UPLOAD_SLOT, frames: count, captured_ms: None };
+        if frames.is_empty() || frames.len() > trajectory::MAX_FRAMES {
+            error!("Trajectory of {} frames rejected, 1 to {} are allowed", frames.len(), trajectory::MAX_FRAMES);
+            (Status::BadArgument, reply)
+        } else {
+            info!("Trajectory of {} frames uploaded", count);
+            self.uploaded = frames;
+            self.uploaded_at = None;
+            (Status::Ok, reply)
+        }
+    }
+
+    fn handle_store_trajectory(&mut self, slot: u8) -> (Status, ReplyPayload) {
+        info!("Received Trajectory Store Signal");
+        let frames = self.uploaded.len() as u8;
+        let status = if slot >= trajectory::SLOT_COUNT {
+            error!("Trajectory slot {} out of range", slot);
+            Status::BadArgument
+        } else if self.uploaded.is_empty() {
+            error!("No trajectory uploaded to store in slot {}", slot);
+            Status::BadArgument
+        } else {
+            match self.settings.as_mut() {
+                Some(settings) => match settings.save_trajectory(slot, &self.uploaded, self.uploaded_at) {
+                    Ok(_) => {
+                        info!("Trajectory of {} frames stored in slot {}", frames, slot);
+                        Status::Ok
+                    }
+                    Err(e) => {
+                        error!("Failed to store trajectory {}: {}", slot, e);
+                        Status::HardwareError
+                    }
+                },
+                None => {
+                    error!("Can't store trajectory {}, NVS is unavailable", slot);
+                    Status::HardwareError
+                }
+            }
+        };
+        (status, ReplyPayload::Trajectory { slot, frames, captured_ms: self.uploaded_at })
+    }
+
+    fn handle_play_trajectory(&mut self, slot: u8, looping: bool) -> (Status, ReplyPayload) {
+        info!("Received Trajectory Play Signal");
+        self.play_trajectory(slot, looping)
+    }
+
+    fn handle_save_preset(&mut self, slot: u8, name: &str) -> (Status, ReplyPayload) {
+        info!("Received Pose Save Signal");
+        let status = if !preset::is_slot(slot) {
+            error!("Pose slot {} out of range", slot);
+            Status::BadArgument
+        } else if name.len() > preset::MAX_NAME_SIZE {
+            error!("Pose name is {} bytes, at most {} are allowed", name.len(), preset::MAX_NAME_SIZE);
+            Status::BadArgument
+        } else {
+            let pose = Preset {
+                name: name.to_string(),
+                angles: self.servos.iter().map(|servo| servo.get_angle()).collect(),
+            };
+            match self.settings.as_mut() {
+                Some(settings) => match settings.save_preset(slot, &pose) {
+                    Ok(_) => {
+                        info!("Pose {} \"{}\" saved", slot, name);
+                        Status::Ok
+                    }
+                    Err(e) => {
+                        error!("Failed to save pose {}: {}", slot, e);
+                        Status::HardwareError
+                    }
+                },
+                None => {
+                    error!("Can't save pose {}, NVS is unavailable", slot);
+                    Status::HardwareError
+                }
+            }
+        };
+        (status, ReplyPayload::Preset(slot))
+    }
+
+    fn handle_recall_preset(&mut self, slot: u8, duration_ms: u16) -> (Status, ReplyPayload) {
+        info!("Received Pose Recall Signal");
+        let loaded = match self.settings.as_ref() {
+            _ if !preset::is_slot(slot) => {
+                error!("Pose slot {} out of range", slot);
+                Err(Status::BadArgument)
+            }
+            Some(settings) => match settings.load_preset(slot) {
+                Ok(Some(pose)) if pose.angles.len() == self.servos.len() => Ok(pose),
+                Ok(Some(pose)) => {
+                    error!("Pose {} has {} angles for {} servos", slot, pose.angles.len(), self.servos.len());
+                    Err(Status::BadArgument)
+                }
+                Ok(None) => {
+                    error!("Pose slot {} is empty", slot);
+                    Err(Status::BadArgument)
+                }
+                Err(e) => {
+                    error!("Failed to load pose {}: {}", slot, e);
+                    Err(Status::HardwareError)
+                }
+            },
+            None => {
+                error!("Can't load pose {}, NVS is unavailable", slot);
+                Err(Status::HardwareError)
+            }
+        };
+        match loaded {
+            Ok(pose) => {
+                info!("Recalling pose {} over {} ms", slot, duration_ms);
+                self.display.draw_lines(&[&format!("Pose {}: {}", slot, pose.name)], Align::Center);
+                let status = move_to_pose(&mut self.servos, &pose.angles, duration_ms, Easing::Linear);
+                (status, ReplyPayload::Preset(slot))
+            }
+            Err(status) => (status, ReplyPayload::Preset(slot)),
+        }
+    }
+
+    fn handle_list_presets(&self) -> (Status, ReplyPayload) {
+        info!("Received Pose List Signal");
+        match self.settings.as_ref() {
+            Some(settings) => {
+                let mut presets = Vec::new();
+                let mut status = Status::Ok;
+                for slot in 0..preset::SLOT_COUNT {
+                    match settings.load_preset(slot) {
+                        Ok(Some(pose)) => presets.push((slot, pose.name)),
+                        Ok(None) => {},
+                        Err(e) => {
+                            error!("Failed to load pose {}: {}", slot, e);
+                            status = Status::HardwareError;
+                        }
+                    }
+                }
+                (status, ReplyPayload::Presets(presets))
+            }
+            None => {
+                error!("Can't list poses, NVS is unavailable");
+                (Status::HardwareError, ReplyPayload::Presets(Vec::new()))
+            }
+        }
+    }
+
+    fn handle_clear_preset(&mut self, slot: u8) -> (Status, ReplyPayload) {
+        info!("Received Pose Clear Signal");
+        let status = match self.settings.as_mut() {
+            _ if !preset::is_slot(slot) => {
+                error!("Pose slot {} out of range", slot);
+                Status::BadArgument
+            }
+            Some(settings) => match settings.clear_preset(slot) {
+                Ok(true) => {
+                    info!("Pose {} cleared", slot);
+                    Status::Ok
+                }
+                Ok(false) => Status::Ok, // Already empty, which is what was asked for
+                Err(e) => {
+                    error!("Failed to clear pose {}: {}", slot, e);
+                    Status::HardwareError
+                }
+            },
+            None => {
+                error!("Can't clear pose {}, NVS is unavailable", slot);
+                Status::HardwareError
+            }
+        };
+        (status, ReplyPayload::Preset(slot))
+    }
+
+    // Without a level only reports the current one
+    fn handle_log_level(&mut self, byte: Option<u8>) -> (Status, ReplyPayload) {
+        info!("Received Log Level Signal");
+        let status = match byte {
+            None => Status::Ok,
+            Some(byte) => match remote_log::level_from_byte(byte) {
+                Some(level) => {
+                    remote_log::set_forward_level(level);
+                    info!("Log forward level set to {}", level);
+                    Status::Ok
+                }
+                None => {
+                    error!("Log level {} out of range", byte);
+                    Status::BadArgument
+                }
+            },
+        };
+        let level = remote_log::forward_level() as u8;
+        (status, ReplyPayload::LogLevel { level, forwarding: remote_log::is_forwarding() })
+    }
+
+    fn handle_log_target(&mut self, persist: bool, level: u8, target: &str) -> (Status, ReplyPayload) {
+        info!("Received Log Target Signal");
+        let parsed = match level {
+            protocol::LOG_LEVEL_INHERIT => Ok(None),
+            level => remote_log::level_from_byte(level).map(Some).ok_or(()),
+        };
+        match parsed.and_then(|parsed| remote_log::set_console_level(target, parsed)) {
+            Ok(applied) => {
+                let name = if target.is_empty() { "default" } else { target };
+                info!("Console log level of {} set to {}", name, applied);
+                let status = if persist { save_log_levels(self.settings.as_mut()) } else { Status::Ok };
+                (status, ReplyPayload::TargetLevel { level: applied as u8, target: target.to_string() })
+            }
+            Err(_) => {
+                error!("Log level {} for {:?} refused, unknown or no room for the target", level, target);
+                let current = remote_log::console_levels().level_for(target) as u8;
+                (Status::BadArgument, ReplyPayload::TargetLevel { level: current, target: target.to_string() })
+            }
+        }
+    }
+
+    // Parks the servos and downloads the image, the tick restarts into it once written
+    fn handle_update(&mut self, from_addr: SocketAddr, url: String) -> (Status, ReplyPayload) {
+        warn!("Firmware update from {} requested by {}", url, from_addr);
+        let estopped = match self.control_state {
+            ControlState::Running => Ok(None),
+            ControlState::EStopped(source) => Ok(Some(source)),
+            ControlState::LowBattery { .. } => Err(Status::LowBattery),
+            ControlState::Overheated => Err(Status::Overheated),
+            ControlState::Updating { .. } => Err(Status::Updating),
+            ControlState::ShuttingDown { .. } | ControlState::ShutDown => Err(Status::ShutDown),
+        };
+        match estopped {
+            Ok(estopped) if self.updater.start(url) => {
+                self.calibration = None;
+                self.set_paused(false);
+                self.abort_self_test();
+                // E-stopped servos stay stopped
+                self.failsafe.park(&mut self.servos);
+                self.control_state = ControlState::Updating { estopped, shown: None };
+                self.display.draw_banner("UPDATING", "Parking servos\nDon't power off");
+                (Status::Ok, ReplyPayload::Empty)
+            }
+            Ok(_) => {
+                error!("Firmware update refused, the last one is waiting for a restart");
+                (Status::Busy, ReplyPayload::Empty)
+            }
+            Err(status) => {
+                error!("Firmware update refused in this state");
+                (status, ReplyPayload::Empty)
+            }
+        }
+    }
+
+    fn handle_gripper(&mut self, command: Option<(GripperMode, u8)>) -> (Status, ReplyPayload) {
+        info!("Received Gripper Signal");
+        let Some(gripper) = self.gripper.as_mut() else {
+            error!("Gripper command refused, no gripper is fitted");
+            return (Status::BadArgument, ReplyPayload::Empty);
+        };
+        let status = match command.map(|(mode, percent)| gripper.set(mode, percent)) {
+            None | Some(Ok(false)) => Status::Ok,
+            Some(Ok(true)) => Status::Clamped,
+            Some(Err(e)) => {
+                error!("Failed to move the gripper: {}", e);
+                Status::HardwareError
+            }
+        };
+        (status, ReplyPayload::Gripper(gripper.status()))
+    }
+
+    fn handle_teach(&mut self, command: Option<TeachCommand>) -> (Status, ReplyPayload) {
+        info!("Received Teach Signal");
+        match command {
+            Some(TeachCommand::Start) => {
+                info!("Recording every {} ms", CONFIG.teach_sample_ms);
+                let interval = Duration::from_millis(CONFIG.teach_sample_ms as u64);
+                self.recording = Some(Recording::new(interval, self.clock.unix_ms()));
+            }
+            Some(TeachCommand::Stop) => match self.recording.take() {
+                Some(recording) => {
+                    info!("Recording of {} frames uploaded", recording.frame_count());
+                    (self.uploaded, self.uploaded_at) = recording.into_frames();
+                }
+                None => info!("Not recording, the upload is left as it is"),
+            },
+            None => {}
+        }
+        // Stopped, the frames are the ones waiting to be stored
+        let frames = self.recording.as_ref().map_or(self.uploaded.len(), Recording::frame_count);
+        let payload = ReplyPayload::Recording {
+            recording: self.recording.is_some(),
+            frames: frames as u8,
+            remaining: (trajectory::MAX_FRAMES - frames) as u8,
+        };
+        (Status::Ok, payload)
+    }
+
+    fn handle_speed_scale(&mut self, command: Option<(u8, bool)>) -> (Status, ReplyPayload) {
+        info!("Received Speed Signal");
+        let status = match command {
+            None => Status::Ok,
+            Some((0, _)) => {
+                error!("Speed scale of 0% rejected, e-stop to halt the limb");
+                Status::BadArgument
+            }
+            Some((percent, persist)) => {
+                // Taken up by the next poll, moves in progress carry on at the new pace
+                self.speed_scale = percent.min(servo::FULL_SPEED);
+                info!("Speed scaled to {}%", self.speed_scale);
+                for servo in self.servos.iter_mut() {
+                    servo.set_speed_scale(self.speed_scale);
+                }
+                if let Some(gripper) = self.gripper.as_mut() {
+                    gripper.set_speed_scale(self.speed_scale);
+                }
+                let saved = match persist {
+                    true => save_speed_scale(self.settings.as_mut(), self.speed_scale),
+                    false => Status::Ok,
+                };
+                match saved {
+                    Status::Ok if percent > servo::FULL_SPEED => Status::Clamped,
+                    saved => saved,
+                }
+            }
+        };
+        (status, ReplyPayload::SpeedScale(self.speed_scale))
+    }
+
+    fn handle_mirror(&mut self, command: Option<(bool, bool)>) -> (Status, ReplyPayload) {
+        info!("Received Mirror Signal");
+        let status = match command {
+            None => Status::Ok,
+            // Taken up by the next move, the servos stay where the last one left them
+            Some((mirrored, persist)) => {
+                info!("Mirroring {}", if mirrored { "on" } else { "off" });
+                self.mirror.set_enabled(mirrored);
+                match persist {
+                    true => save_mirror(self.settings.as_mut(), mirrored),
+                    false => Status::Ok,
+                }
+            }
+        };
+        (status, ReplyPayload::Mirror(self.mirror.is_enabled()))
+    }
+
+    fn handle_loop_timing(&mut self, reset: bool) -> (Status, ReplyPayload) {
+        let timing = self.loop_timer.timing();
+        if reset {
+            info!("Loop timing reset after {} passes, {} slow", timing.passes, timing.slow);
+            self.loop_timer.reset();
+        }
+        (Status::Ok, ReplyPayload::LoopTiming(timing))
+    }
+
+    // Without a chunk the others follow the reply as packets of their own. A client without a UDP port gets the first
+    // and the count, to ask for the rest one at a time
+    fn handle_history(&mut self, from_addr: SocketAddr, chunk: Option<u8>) -> (Status, ReplyPayload) {
+        if let Some(chunk) = chunk {
+            return match self.history.chunk(chunk) {
+                Some(payload) => (Status::Ok, payload),
+                None => (Status::BadArgument, ReplyPayload::Empty),
+            };
+        }
+        if from_addr.is_ipv4() && from_addr.port() != 0 {
+            for chunk in 1..self.history.chunks() {
+                let Some(payload) = self.history.chunk(chunk) else {
+                    break;
+                };
+                let sequence = self.history.next_sequence();
+                let packet = ReplyPacket::new(protocol::HISTORY_COMMAND, Status::Ok, payload);
+                send_reply(&self.replies, from_addr, sequence, packet);
+            }
+        }
+        (Status::Ok, self.history.chunk(0).unwrap_or(ReplyPayload::Empty))
+    }
+
+    fn handle_current(&mut self, from_addr: SocketAddr, command: Option<CurrentCommand>) -> (Status, ReplyPayload) {
+        let status = match command {
+            None => Status::Ok,
+            Some(CurrentCommand::Clear { index: protocol::CURRENT_ALL_JOINTS }) => {
+                for servo in self.servos.iter_mut() {
+                    if servo.clear_overload() {
+                        info!("{} overload cleared by {}", servo.get_name(), from_addr);
+                    }
+                }
+                Status::Ok
+            }
+            Some(CurrentCommand::Clear { index }) => match self.servos.get_mut(index as usize) {
+                Some(servo) => {
+                    if servo.clear_overload() {
+                        info!("{} overload cleared by {}", servo.get_name(), from_addr);
+                    }
+                    Status::Ok
+                }
+                None => {
+                    error!("Invalid servo index {}", index);
+                    Status::BadArgument
+                }
+            },
+            Some(CurrentCommand::Calibrate { sensor, calibration }) if calibration.ma_per_v == 0 => {
+                error!("Current sensor {} can't be calibrated to 0 mA/V", sensor);
+                Status::BadArgument
+            }
+            Some(CurrentCommand::Calibrate { sensor, calibration }) => {
+                match self.current.set_calibration(sensor, calibration) {
+                    true => {
+                        if let Some(settings) = self.settings.as_mut() {
+                            match settings.save_current(sensor as usize, &calibration) {
+                                Ok(_) => info!("Current calibration {} saved", sensor),
+                                Err(e) => error!("Failed to save current calibration {}: {}", sensor, e),
+                            }
+                        }
+                        Status::Ok
+                    }
+                    false => {
+                        error!("There is no current sensor {}", sensor);
+                        Status::BadArgument
+                    }
+                }
+            }
+        };
+        // The banner goes once nothing is overloaded, unless something since has put up its own
+        if matches!(command, Some(CurrentCommand::Clear { .. }))
+            && self.control_state == ControlState::Running
+            && !self.servos.iter().any(Servo::is_overloaded)
+        {
+            self.display.release();
+        }
+        let payload = ReplyPayload::Current {
+            limit_ma: self.current.get_limit_ma(),
+            window_ms: self.current.get_window_ms(),
+            joints: joint_currents(&self.servos, &self.current),
+            sensors: self.current.readings(),
+        };
+        (status, payload)
+    }
+
+    fn handle_pause(&mut self, from_addr: SocketAddr) -> (Status, ReplyPayload) {
+        info!("Motion paused by {}", from_addr);
+        self.set_paused(true);
+        self.display.draw_banner("PAUSED", "Servos holding\nResume to continue");
+        (Status::Ok, positions(&self.servos))
+    }
+
+    fn handle_resume(&mut self, from_addr: SocketAddr) -> (Status, ReplyPayload) {
+        if self.paused {
+            info!("Motion resumed by {}", from_addr);
+            self.set_paused(false);
+            self.display.release();
+        }
+        (Status::Ok, positions(&self.servos))
+    }
+
+    fn handle_self_test(&mut self, start: bool) -> (Status, ReplyPayload) {
+        if start {
+            info!("Self-testing every servo {} degrees off where it stands", CONFIG.self_test_deg);
+            let dwell = Duration::from_millis(CONFIG.self_test_dwell_ms as u64);
+            self.self_test = Some(SelfTest::new(self.servos.len(), CONFIG.self_test_deg, dwell));
+        }
+        let payload = ReplyPayload::SelfTest {
+            running: self.is_self_testing(),
+            results: self.self_test.as_ref().map_or_else(Vec::new, |test| test.get_results().to_vec()),
+        };
+        (Status::Ok, payload)
+    }
+
+    fn handle_shutdown(&mut self, from_addr: SocketAddr, reboot: bool, confirm: u8) -> (Status, ReplyPayload) {
+        let name = if reboot { "Reboot" } else { "Shutdown" };
+        if confirm != protocol::CONFIRM_BYTE {
+            error!("{} from {} ignored, confirmation byte {:#04x} is wrong", name, from_addr, confirm);
+            (Status::BadArgument, ReplyPayload::Empty)
+        } else if self.control_state.is_shutting_down() {
+            error!("{} from {} refused, the limb is already shutting down", name, from_addr);
+            (Status::ShutDown, ReplyPayload::Empty)
+        } else {
+            warn!("{} requested by {}", name, from_addr);
+            self.shut_down(if reboot { PowerDown::Reboot } else { PowerDown::Off });
+            // Acknowledged before the servos are stopped or the chip restarts
+            (Status::Ok, ReplyPayload::Empty)
+        }
+    }
+
+    fn handle_sleep(&mut self, from_addr: SocketAddr, confirm: u8, wake_s: Option<u32>) -> (Status, ReplyPayload) {
+        if confirm != protocol::CONFIRM_BYTE {
+            error!("Sleep from {} ignored, confirmation byte {:#04x} is wrong", from_addr, confirm);
+            (Status::BadArgument, ReplyPayload::Empty)
+        } else if wake_s.is_none() && !sleep::button_wakes() {
+            error!("Sleep from {} refused, there is no timer and the button can't wake the chip", from_addr);
+            (Status::BadArgument, ReplyPayload::Empty)
+        } else if self.control_state.is_shutting_down() {
+            error!("Sleep from {} refused, the limb is already shutting down", from_addr);
+            (Status::ShutDown, ReplyPayload::Empty)
+        } else {
+            match wake_s {
+                Some(wake_s) => warn!("Sleep for {} s requested by {}", wake_s, from_addr),
+                None => warn!("Sleep until the button requested by {}", from_addr),
+            }
+            self.shut_down(PowerDown::Sleep { wake_s });
+            (Status::Ok, ReplyPayload::Empty)
+        }
+    }
+
+    fn handle_estop(&mut self, from_addr: SocketAddr) -> (Status, ReplyPayload) {
+        error!("E-stop received from {}", from_addr);
+        self.calibration = None;
+        self.set_paused(false);
+        self.abort_self_test();
+        let gripper = self.gripper.as_mut();
+        let status = estop(&mut self.servos, gripper, &mut self.control_state, EStopSource::Network, &mut self.display);
+        self.buzzer.play(Sound::EStop, Instant::now());
+        (status, ReplyPayload::Empty)
+    }
+
+    fn handle_clear_estop(&mut self, from_addr: SocketAddr) -> (Status, ReplyPayload) {
+        // Releasing the button leaves the latch set, it still takes this command to clear
+        if self.estop_button.is_pressed() {
+            error!("E-stop clear from {} refused, the button is still pressed", from_addr);
+            self.display.draw_banner("E-STOP", "Button still pressed\nRelease it to clear");
+            return (Status::ButtonEStopped, ReplyPayload::Empty);
+        }
+        let mut status = Status::Ok;
+        if matches!(self.control_state, ControlState::EStopped(_)) {
+            info!("E-stop cleared by {}", from_addr);
+            for servo in self.servos.iter_mut() {
+                match servo.reenable() {
+                    Ok(_) => {},
+                    Err(e) => {
+                        error!("Failed to re-enable {}: {}", servo.get_name(), e);
+                        status = Status::HardwareError;
+                    }
+                }
+            }
+            if let Some(gripper) = self.gripper.as_mut() {
+                match gripper.reenable() {
+                    Ok(_) => {},
+                    Err(e) => {
+                        error!("Failed to re-enable the gripper: {}", e);
+                        status = Status::HardwareError;
+                    }
+                }
+            }
+            self.control_state = ControlState::Running;
+            self.display.release();
+        }
+        (status, ReplyPayload::Empty)
+    }
+}
+
+// Latched by an e-stop packet or the button, only a clear e-stop packet returns to Running
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ControlState {
+    Running,
+    EStopped(EStopSource),
+    ShuttingDown { then: PowerDown, started: Instant }, // Parking before the servos are stopped
+    ShutDown,                                       // Servos stopped, nothing returns to Running
+    LowBattery { stopped: bool, started: Instant }, // Parking, then stopped, until the battery recovers
+    Overheated,                                     // Parked until the chip cools
+    // Parked while the firmware downloads, back to the e-stop it left or to Running if the download fails
+    Updating { estopped: Option<EStopSource>, shown: Option<u8> },
+}
+
+impl ControlState {
+    fn is_shutting_down(self) -> bool {
+        matches!(self, ControlState::ShuttingDown { .. } | ControlState::ShutDown)
+    }
+}
+
+// What a shutdown does once the servos are parked and stopped
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PowerDown {
+    Off,                           // Waits to be powered off
+    Reboot,
+    Sleep { wake_s: Option<u32> }, // Deep sleeps until the button, or the timer if set
+}
+
+// What latched the e-stop, reported so a client can tell someone pressed the button
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum EStopSource {
+    Network,
+    Button,
+    Watchdog, // Latched at start-up after a watchdog reset
+}
+
+// Stops every servo and the gripper and latches the e-stop, HardwareError if any of them couldn't be stopped
+fn estop(
+    servos: &mut [Servo],
+    gripper: Option<&mut Gripper>,
+    control_state: &mut ControlState,
+    source: EStopSource,
+    display: &mut impl DisplayBackend,
+) -> Status {
+    let status = stop_servos(servos, gripper);
+    // Once shut down only a power cycle brings the servos back, so there is nothing to latch
+    if *control_state != ControlState::ShutDown {
+        *control_state = ControlState::EStopped(source);
+        match source {
+            EStopSource::Button => display.draw_banner("E-STOP", "Button pressed\nRelease, then clear\nover the network"),
+            _ => display.draw_banner("E-STOP", "All servos stopped\nClear to resume"),
+        }
+    }
+    status
+}
+
+// De-energizes every servo and the gripper, HardwareError if any of them couldn't be stopped
+fn stop_servos(servos: &mut [Servo], gripper: Option<&mut Gripper>) -> Status {
+    let mut status = Status::Ok;
+    for servo in servos.iter_mut() {
+        match servo.stop() {
+            Ok(_) => {},
+            Err(e) => {
+                error!("Failed to stop {}: {}", servo.get_name(), e);
+                status = Status::HardwareError;
+            }
+        }
+    }
+    if let Some(gripper) = gripper {
+        match gripper.stop() {
+            Ok(_) => {},
+            Err(e) => {
+                error!("Failed to stop the gripper: {}", e);
+                status = Status::HardwareError;
+            }
+        }
+    }
+    status
+}
+
+// A limb that never joined shows why, one that dropped off shows how far rejoining has got
+fn show_link_down(display: &mut impl DisplayBackend, link: &Link, status: LinkStatus) {
+    match status.never_joined() {
+        true => display.draw_banner("WiFi failed", &link.get_failure()),
+        false => display.draw_banner("WiFi lost", &format!("reconnecting\n(attempt {})", status.attempt)),
+    }
+}
+
+// E-stop, failsafe and shutdown flags shared by the telemetry reply and heartbeats
+fn safety_flags(control_state: ControlState, failsafe: &Failsafe, cutoff: &Cutoff, paused: bool) -> u8 {
+    let mut flags = 0;
+    if let ControlState::EStopped(source) = control_state {
+        flags |= protocol::TELEMETRY_ESTOP_FLAG;
+        if source == EStopSource::Button {
+            flags |= protocol::TELEMETRY_BUTTON_ESTOP_FLAG;
+        }
+    }
+    if failsafe.is_triggered() {
+        flags |= protocol::TELEMETRY_FAILSAFE_FLAG;
+    }
+    if control_state.is_shutting_down() {
+        flags |= protocol::TELEMETRY_SHUTDOWN_FLAG;
+    }
+    if cutoff.is_low() {
+        flags |= protocol::TELEMETRY_LOW_BATTERY_FLAG;
+    }
+    if matches!(control_state, ControlState::Updating { .. }) {
+        flags |= protocol::TELEMETRY_UPDATING_FLAG;
+    }
+    if paused {
+        flags |= protocol::TELEMETRY_PAUSED_FLAG;
+    }
+    #[cfg(all(feature = "mqtt", not(feature = "sim")))]
+    if mqtt::is_connected() {
+        flags |= protocol::TELEMETRY_MQTT_FLAG;
+    }
+    flags
+}
+
+// The most urgent state shows, a new state only needs an arm here and a pattern in led.rs if none of them fit
+fn led_pattern(control_state: ControlState, failsafe: &Failsafe, link_up: bool, active: bool) -> LedPattern {
+    match control_state {
+        ControlState::EStopped(_) => LedPattern::Strobe,
+        ControlState::ShutDown => LedPattern::Off,
+        _ if !link_up => LedPattern::Solid, // Reconnecting, as while connecting at start-up
+        ControlState::LowBattery { .. } | ControlState::Overheated => LedPattern::DoubleBlink,
+        ControlState::Updating { .. } => LedPattern::FastBlink,
+        _ if failsafe.is_triggered() => LedPattern::DoubleBlink,
+        _ if active => LedPattern::FastBlink,
+        _ => LedPattern::SlowBlink,
+    }
+}
+
+// Stores a servo's calibration after a command changed it, a failure only costs the change at the next boot
+fn save_calibration(settings: Option<&mut Settings>, index: usize, servo: &Servo) {
+    if let Some(settings) = settings {
+        match settings.save_calibration(index, &Calibration::from_servo(servo)) {
+            Ok(_) => info!("{} calibration saved", servo.get_name()),
+            Err(e) => error!("Failed to save {} calibration: {}", servo.get_name(), e),
+        }
+    }
+}
+
+// Sets the feedback reading at 0 degrees, or at the max angle, and stores it. FEEDBACK_CAPTURE takes the joint's
+// current reading. Returns the status and the reading now calibrated
+fn calibrate_feedback(
+    servo: &mut Servo,
+    settings: Option<&mut Settings>,
+    index: usize,
+    reading: u16,
+    at_max: bool,
+) -> (Status, u16) {
+    let name = servo.get_name().to_string();
+    let Some(feedback) = servo.get_feedback_mut() else {
+        error!("{} has no feedback to calibrate", name);
+        return (Status::BadArgument, 0);
+    };
+    let reading = match reading {
+        protocol::FEEDBACK_CAPTURE => match feedback.get_reading() {
+            Some(reading) => reading,
+            None => {
+                error!("No {} feedback reading to capture yet", name);
+                return (Status::HardwareError, 0);
+            }
+        },
+        reading => reading,
+    };
+    let calibration = match at_max {
+        false => FeedbackCalibration { min_mv: reading, ..feedback.get_calibration() },
+        true => FeedbackCalibration { max_mv: reading, ..feedback.get_calibration() },
+    };
+    feedback.set_calibration(calibration);
+    info!("{} feedback calibrated from {} to {} mV", name, calibration.min_mv, calibration.max_mv);
+    if let Some(settings) = settings {
+        match settings.save_feedback(index, &calibration) {
+            Ok(_) => info!("{} feedback calibration saved", name),
+            Err(e) => error!("Failed to save {} feedback calibration: {}", name, e),
+        }
+    }
+    (Status::Ok, reading)
+}
+
+// Stores every console level so they outlast a restart
+fn save_log_levels(settings: Option<&mut Settings>) -> Status {
+    match settings.map(|settings| settings.save_log_levels(&remote_log::console_levels())) {
+        Some(Ok(_)) => {
+            info!("Log levels saved");
+            Status::Ok
+        }
+        Some(Err(e)) => {
+            error!("Failed to save the log levels: {}", e);
+            Status::HardwareError
+        }
+        None => {
+            error!("Can't save the log levels, NVS is unavailable");
+            Status::HardwareError
+        }
+    }
+}
+
+// The saved power-on pose, or mid-travel while none is saved or it no longer fits the joints
+fn load_power_on_pose(settings: Option<&Settings>, servos: &[Servo]) -> Vec<u16> {
+    match settings.map(|settings| settings.load_preset(protocol::POWER_ON_SLOT)) {
+        Some(Ok(Some(pose))) if pose.angles.len() == servos.len() => return pose.angles,
+        Some(Ok(Some(pose))) => {
+            warn!("Power-on pose has {} angles for {} servos, using mid-travel", pose.angles.len(), servos.len())
+        }
+        Some(Ok(None)) | None => {}
+        Some(Err(e)) => warn!("Failed to read the power-on pose, using mid-travel: {}", e),
+    }
+    servos.iter().map(|servo| servo.get_max_angle() / 2).collect()
+}
+
+// Returns false if the pose couldn't be saved
+fn save_last_pose(settings: Option<&mut Settings>, pose: &LastPose) -> bool {
+    match settings.map(|settings| settings.save_last_pose(pose)) {
+        Some(Ok(_)) => true,
+        Some(Err(e)) => {
+            error!("Failed to save the last pose: {}", e);
+            false
+        }
+        None => false,
+    }
+}
+
+fn save_speed_scale(settings: Option<&mut Settings>, percent: u8) -> Status {
+    match settings.map(|settings| settings.save_speed_scale(percent)) {
+        Some(Ok(_)) => {
+            info!("Speed scale saved");
+            Status::Ok
+        }
+        Some(Err(e)) => {
+            error!("Failed to save the speed scale: {}", e);
+            Status::HardwareError
+        }
+        None => {
+            error!("Can't save the speed scale, NVS is unavailable");
+            Status::HardwareError
+        }
+    }
+}
+
+fn save_mirror(settings: Option<&mut Settings>, mirrored: bool) -> Status {
+    match settings.map(|settings| settings.save_mirror(mirrored)) {
+        Some(Ok(_)) => {
+            info!("Mirror setting saved");
+            Status::Ok
+        }
+        Some(Err(e)) => {
+            error!("Failed to save the mirror setting: {}", e);
+            Status::HardwareError
+        }
+        None => {
+            error!("Can't save the mirror setting, NVS is unavailable");
+            Status::HardwareError
+        }
+    }
+}
+
+// Hands a reply to the network task to send, tagged with the sequence number of the packet it answers
+fn send_reply(replies: &Sender<Reply>, addr: SocketAddr, sequence: u16, packet: ReplyPacket) {
+    match replies.send(Reply { addr, sequence, packet }) {
+        Ok(_) => {},
+        Err(e) => error!("Network task stopped, reply to command {} dropped", e.0.packet.command),
+    }
+}
+
+// Sends every servo to its angle in the pose, all arriving together after duration_ms or at their own speeds if it is 0
+fn move_to_pose(servos: &mut [Servo], pose: &[u16], duration_ms: u16, easing: Easing) -> Status {
+    let mut status = Status::Ok;
+    for (servo, &angle) in servos.iter_mut().zip(pose.iter()) {
+        match servo.set_angle_timed(angle, duration_ms, easing) {
+            Ok(true) => if status == Status::Ok { status = Status::Clamped },
+            Ok(false) => {},
+            Err(e) => {
+                error!("Failed to set angle of {}: {}", servo.get_name(), e);
+                status = Status::HardwareError;
+            }
+        }
+    }
+    status
+}
+
+// The status a pose would be answered with, or None if it changes a goal or drives a stopped servo again
+fn unchanged_pose(servos: &[Servo], pose: &[u16]) -> Option<Status> {
+    let mut status = Status::Ok;
+    for (servo, &angle) in servos.iter().zip(pose) {
+        let (min_limit, max_limit) = servo.get_limits();
+        let (clamped, was_clamped) = servo::clamp_angle(angle, min_limit, max_limit);
+        if !servo.is_enabled() || servo.get_goal() != clamped {
+            return None;
+        }
+        if was_clamped {
+            status = Status::Clamped;
+        }
+    }
+    Some(status)
+}
+
+// Every servo's angle and status, as sent back for moves and legacy pings
+fn positions(servos: &[Servo]) -> ReplyPayload {
+    match CONFIG.legacy_byte_order {
+        true => ReplyPayload::LegacyPositions(servos.iter().map(Servo::get_angle).collect()),
+        false => ReplyPayload::Positions(servo_positions(servos)),
+    }
+}
+
+fn servo_positions(servos: &[Servo]) -> Vec<ServoPosition> {
+    servos
+        .iter()
+        .map(|servo| ServoPosition { angle: servo.get_angle(), status: servo.status() })
+        .collect()
+}
+
+// Each joint's sensor and its current, and whether the joint is overloaded
+fn joint_currents(servos: &[Servo], current: &CurrentMonitor) -> Vec<JointCurrent> {
+    servos
+        .iter()
+        .enumerate()
+        .map(|(index, servo)| {
+            let sensor = current.sensor_for(index);
+            JointCurrent {
+                sensor,
+                current_ma: sensor.and_then(|sensor| current.get_current_ma(sensor)),
+                overloaded: servo.is_overloaded(),
+            }
+        })
+        .collect()
+}
+
+// The index of each joint whose channel couldn't be set up at boot, and why
+fn unavailable_joints(servos: &[Servo]) -> Vec<(u8, String)> {
+    servos
+        .iter()
+        .enumerate()
+        .filter_map(|(index, servo)| servo.get_unavailable().map(|error| (index as u8, error.to_string())))
+        .collect()
+}
+
+// Shows who holds the session, or that nobody does
+fn show_session(display: &mut impl DisplayBackend, session: &Session) {
+    match session.get_owner() {
+        Some(owner) => display.draw_lines(&["Session owner:", &owner.to_string()], Align::Center),
+        None => display.draw_lines(&["Session released", "Open to all"], Align::Center),
+    }
+}
+
+// Run on the simulator's mock servos and display, without settings
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::atomic::{AtomicI32, Ordering};
+    use std::sync::{mpsc, Arc};
+
+    use super::*;
+    use crate::clock;
+    use crate::current::{self, CurrentCalibration, Source};
+    use crate::feedback::Feedback;
+    use crate::motion_queue;
+    use crate::sim::{self, MockDisplay, MockServo};
+
+    const CLIENT: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20)), 4210);
+    const OTHER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 21)), 4210);
+    const POSE: [u16; protocol::SERVO_COUNT] = [900, 450, 900, 1200, 600]; // Tenths, like every angle a packet holds
+    const LOW_BATTERY: BatteryConfig = BatteryConfig { divider: 1000, cutoff_mv: 6000, hysteresis_mv: 400 };
+    const SETTLE_TICKS: usize = 1000; // Long enough for any joint to cross its whole range while parking
+
+    struct Limb {
+        controller: Controller<MockDisplay>,
+        battery: Battery,
+        replies: Receiver<Reply>,
+    }
+
+    impl Limb {
+        fn new() -> Limb {
+            Limb::with_gripper(None)
+        }
+
+        fn with_gripper(gripper: Option<Gripper>) -> Limb {
+            let (servos, _) = sim::mock_servos();
+            let battery = Battery::default();
+            let board = Board {
+                tick: Tick::new(CONFIG.servo_tick_hz),
+                battery: battery.clone(),
+                estop_button: EStopButton::none(),
+                button: Button::none(),
+                buzzer: Buzzer::none(),
+                status_led: StatusLed::none(),
+                updater: Updater::default(),
+                gripper,
+                history: History::new(),
+                current: CurrentMonitor::new(),
+                i2c_devices: vec![0x3c, 0x40],
+            };
+            let link = Link::new(Ipv4Addr::LOCALHOST);
+            let shared = Shared { link: link.clone(), stats: Stats::new(), clock: clock::start(link) };
+            let (reply_sender, replies) = mpsc::channel();
+            let controller = Controller::new(servos, MockDisplay::default(), None, board, shared, reply_sender);
+            Limb { controller, battery, replies }
+        }
+
+        fn send(&mut self, control: ControlPacket) -> Status {
+            self.controller.handle_packet(CLIENT, control).status
+        }
+
+        fn send_from(&mut self, addr: SocketAddr, control: ControlPacket) -> ReplyPacket {
+            self.controller.handle_packet(addr, control)
+        }
+
+        fn tick(&mut self) {
+            self.controller.tick.raise();
+            self.controller.tick();
+        }
+
+        // Ticks until every servo has reached its goal
+        fn settle(&mut self) {
+            for _ in 0..SETTLE_TICKS {
+                self.tick();
+                if !self.controller.servos.iter().any(Servo::is_moving) {
+                    return;
+                }
+            }
+            panic!("Servos still moving after {} ticks", SETTLE_TICKS);
+        }
+
+        fn state(&self) -> ControlState {
+            self.controller.control_state
+        }
+
+        fn angles(&self) -> Vec<u16> {
+            self.controller.servos.iter().map(Servo::get_angle).collect()
+        }
+    }
+
+    fn set_angles() -> ControlPacket {
+        ControlPacket::SetAngles(POSE.to_vec())
+    }
+
+    fn shutdown() -> ControlPacket {
+        ControlPacket::Shutdown { reboot: false, confirm: protocol::CONFIRM_BYTE }
+    }
+
+    #[test]
+    fn estop_refuses_motion_until_cleared() {
+        let mut limb = Limb::new();
+        assert_eq!(limb.send(set_angles()), Status::Ok);
+        assert_eq!(limb.send(ControlPacket::EStop), Status::Ok);
+        assert!(limb.state() == ControlState::EStopped(EStopSource::Network));
+        assert!(limb.controller.servos.iter().all(|servo| !servo.is_enabled()));
+        assert_eq!(limb.send(set_angles()), Status::EStopped);
+        assert_eq!(limb.send(ControlPacket::MoveJoint { index: 0, angle: 0, speed: None }), Status::EStopped);
+        // Looking is still allowed
+        assert_eq!(limb.send(ControlPacket::Telemetry { tenths: false }), Status::Ok);
+
+        assert_eq!(limb.send(ControlPacket::ClearEStop), Status::Ok);
+        assert!(limb.state() == ControlState::Running);
+        assert_eq!(limb.send(set_angles()), Status::Ok);
+        limb.settle();
+        assert_eq!(limb.angles(), POSE);
+    }
+
+    #[test]
+    fn an_eased_pose_sets_off_gently_and_arrives_with_a_linear_one() {
+        let mut limbs = [Limb::new(), Limb::new()];
+        for (limb, easing) in limbs.iter_mut().zip([Easing::Linear, Easing::EaseIn]) {
+            limb.send(set_angles());
+            limb.settle();
+            let pose =
+                ControlPacket::Pose { angles: NEXT_POSE.to_vec(), duration_ms: 1000, easing, tag: None, tenths: false };
+            assert_eq!(limb.send(pose), Status::Ok);
+            for _ in 0..10 {
+                limb.tick();
+            }
+        }
+        let travelled = |limb: &Limb| POSE[0] - limb.angles()[0];
+        assert!(travelled(&limbs[1]) < travelled(&limbs[0]));
+        for limb in limbs.iter_mut() {
+            limb.settle();
+        }
+        assert_eq!(limbs[0].angles(), limbs[1].angles());
+        assert_eq!(limbs[1].angles(), NEXT_POSE);
+    }
+
+    #[test]
+    fn the_tenths_commands_are_replied_to_in_tenths() {
+        let mut limb = Limb::new();
+        let angles = vec![905, 451, 0, 1799, 600];
+        let pose = ControlPacket::Pose {
+            angles: angles.clone(),
+            duration_ms: 0,
+            easing: Easing::Linear,
+            tag: None,
+            tenths: true,
+        };
+        assert_eq!(limb.send_from(CLIENT, pose).command, protocol::POSE_TENTHS_COMMAND);
+        limb.settle();
+        assert_eq!(limb.angles(), angles);
+        let reply = limb.send_from(CLIENT, ControlPacket::Telemetry { tenths: true });
+        assert_eq!((reply.command, reply.status), (protocol::TELEMETRY_TENTHS_COMMAND, Status::Ok));
+        let ReplyPayload::Telemetry(telemetry) = reply.payload else { panic!("{:?} for telemetry", reply.payload) };
+        assert_eq!(telemetry.joints.iter().map(|joint| joint.angle).collect::<Vec<_>>(), angles);
+    }
+
+    const NEXT_POSE: [u16; protocol::SERVO_COUNT] = [600, 750, 600, 900, 900];
+
+    fn tagged(angles: [u16; protocol::SERVO_COUNT], id: u16, queue: bool) -> ControlPacket {
+        let tag = Some(MoveTag { id, queue });
+        ControlPacket::Pose { angles: angles.to_vec(), duration_ms: 200, easing: Easing::Linear, tag, tenths: false }
+    }
+
+    // The id and status of every move end reported since the last call
+    fn moves_done(limb: &Limb) -> Vec<(u16, Status)> {
+        limb.replies
+            .try_iter()
+            .filter(|reply| reply.packet.command == protocol::MOVE_DONE_COMMAND)
+            .map(|reply| match reply.packet.payload {
+                ReplyPayload::MoveDone { id, .. } => (id, reply.packet.status),
+                ref payload => panic!("{:?} in a move done packet", payload),
+            })
+            .collect()
+    }
+
+    fn queue_status(limb: &mut Limb) -> (u8, Option<u16>) {
+        match limb.send_from(CLIENT, ControlPacket::Status).payload {
+            ReplyPayload::Status { queued, executing, .. } => (queued, executing),
+            payload => panic!("{:?} for a status", payload),
+        }
+    }
+
+    #[test]
+    fn queued_moves_run_in_turn_and_each_reports_its_end() {
+        let mut limb = Limb::new();
+        limb.send(ControlPacket::SetAngles(NEXT_POSE.to_vec()));
+        limb.settle();
+        assert_eq!(limb.send(tagged(POSE, 1, true)), Status::Ok);
+        assert_eq!(limb.send(tagged(NEXT_POSE, 2, true)), Status::Ok);
+        assert_eq!(queue_status(&mut limb), (1, Some(1)));
+        // The next starts on the pass the first ends on
+        let mut done = vec![];
+        for _ in 0..SETTLE_TICKS {
+            limb.tick();
+            done = moves_done(&limb);
+            if !done.is_empty() {
+                break;
+            }
+        }
+        assert_eq!(done, [(1, Status::Ok)]);
+        assert_eq!(limb.angles(), POSE);
+        assert_eq!(queue_status(&mut limb), (0, Some(2)));
+        limb.settle();
+        assert_eq!(moves_done(&limb), [(2, Status::Ok)]);
+        assert_eq!(limb.angles(), NEXT_POSE);
+        assert_eq!(queue_status(&mut limb), (0, None));
+    }
+
+    #[test]
+    fn a_move_that_isnt_queued_cancels_every_tagged_one() {
+        let mut limb = Limb::new();
+        limb.send(tagged(POSE, 1, false));
+        limb.send(tagged(NEXT_POSE, 2, true));
+        limb.send(tagged(POSE, 3, true));
+        // Tagged without the queue flag, it takes over like any other move
+        assert_eq!(limb.send(tagged(NEXT_POSE, 4, false)), Status::Ok);
+        assert_eq!(moves_done(&limb), [(1, Status::Cancelled), (2, Status::Cancelled), (3, Status::Cancelled)]);
+        limb.send(set_angles());
+        assert_eq!(moves_done(&limb), [(4, Status::Cancelled)]);
+        assert_eq!(queue_status(&mut limb), (0, None));
+    }
+
+    #[test]
+    fn a_full_queue_refuses_more_and_a_refused_move_isnt_left_executing() {
+        let mut limb = Limb::new();
+        limb.send(tagged(POSE, 0, true));
+        for id in 1..=motion_queue::MAX_QUEUED as u16 {
+            assert_eq!(limb.send(tagged(NEXT_POSE, id, true)), Status::Ok);
+        }
+        assert_eq!(limb.send(tagged(POSE, 100, true)), Status::Busy);
+        assert_eq!(queue_status(&mut limb), (motion_queue::MAX_QUEUED as u8, Some(0)));
+
+        let mut limb = Limb::new();
+        // No NVS to play it from
+        let play = ControlPacket::PlayTrajectory { slot: 0, looping: false, tag: Some(MoveTag { id: 5, queue: true }) };
+        assert_eq!(limb.send(play), Status::HardwareError);
+        assert_eq!(queue_status(&mut limb), (0, None));
+        let tag = Some(MoveTag { id: 6, queue: false });
+        let looping = ControlPacket::PlayTrajectory { slot: 0, looping: true, tag };
+        assert_eq!(limb.send(looping), Status::BadArgument);
+        assert_eq!(queue_status(&mut limb), (0, None));
+    }
+
+    // The status and goal a jog of joint 1 replies with
+    fn jog(limb: &mut Limb, delta_tenths: i8) -> (Status, u16) {
+        match limb.send_from(CLIENT, ControlPacket::Jog { index: 1, delta_tenths }) {
+            ReplyPacket { status, payload: ReplyPayload::Jog { goal_tenths, .. }, .. } => (status, goal_tenths),
+            reply => panic!("{:?} for a jog", reply),
+        }
+    }
+
+    #[test]
+    fn jogs_add_up_in_tenths_and_stop_at_the_limits() {
+        let mut limb = Limb::new();
+        limb.send(set_angles());
+        limb.settle();
+        assert_eq!(jog(&mut limb, 5), (Status::Ok, 455));
+        assert_eq!(jog(&mut limb, 5), (Status::Ok, 460));
+        assert_eq!(jog(&mut limb, -25), (Status::Ok, 435));
+        let (min_limit, max_limit) = limb.controller.servos[1].get_limits();
+        let mut reply = (Status::Ok, 0);
+        for _ in 0..(max_limit / 127 + 1) {
+            reply = jog(&mut limb, 127);
+        }
+        assert_eq!(reply, (Status::Clamped, max_limit));
+        assert_eq!(limb.controller.servos[1].get_goal(), max_limit);
+        for _ in 0..(max_limit / 128 + 1) {
+            reply = jog(&mut limb, -128);
+        }
+        assert_eq!(reply, (Status::Clamped, min_limit));
+        // A goal of its own replaces the jogged one
+        limb.send(set_angles());
+        assert_eq!(limb.controller.servos[1].get_goal(), POSE[1]);
+        let reply = limb.send_from(CLIENT, ControlPacket::Jog { index: 9, delta_tenths: 1 });
+        assert_eq!((reply.status, reply.payload), (Status::BadArgument, ReplyPayload::Index(9)));
+    }
+
+    #[test]
+    fn a_joint_that_failed_at_boot_keeps_its_index_and_refuses_its_own_commands() {
+        let mut limb = Limb::new();
+        limb.controller.servos[1] = Servo::unavailable("Shoulder".to_string(), 180, "no timer 2".to_string());
+        let reply = limb.send_from(CLIENT, ControlPacket::MoveJoint { index: 1, angle: 900, speed: None });
+        assert_eq!((reply.status, reply.payload), (Status::HardwareError, ReplyPayload::Index(1)));
+        let reply = limb.send_from(CLIENT, ControlPacket::Jog { index: 1, delta_tenths: 5 });
+        assert_eq!((reply.status, reply.payload), (Status::HardwareError, ReplyPayload::Index(1)));
+        // The joint after it is still the one addressed
+        assert_eq!(limb.send(ControlPacket::MoveJoint { index: 2, angle: 300, speed: None }), Status::Ok);
+        limb.settle();
+        assert_eq!(limb.controller.servos[2].get_angle(), 300);
+        // A pose moves the rest and reports the one it couldn't
+        assert_eq!(limb.send(set_angles()), Status::HardwareError);
+        limb.settle();
+        let angles = limb.angles();
+        assert_eq!((angles[0], angles[2], angles[1]), (POSE[0], POSE[2], 0));
+        let ReplyPayload::Status { joints, unavailable, .. } = limb.send_from(CLIENT, ControlPacket::Status).payload
+        else {
+            panic!("not a status");
+        };
+        assert_eq!(joints[1].status, servo::STATUS_UNAVAILABLE);
+        assert_eq!(unavailable, [(1, "no timer 2".to_string())]);
+    }
+
+    #[test]
+    fn a_long_press_takes_down_a_banner_only_once_the_limb_is_running() {
+        let mut limb = Limb::new();
+        assert_eq!(limb.send(ControlPacket::Pause), Status::Ok);
+        limb.controller.display.draw_banner("PAUSED", "Resume over the network");
+        limb.controller.handle_press(Press::Long);
+        assert!(!limb.controller.display.next_page(), "the pause banner went while paused");
+        limb.controller.paused = false;
+        limb.controller.handle_press(Press::Long);
+        assert!(limb.controller.display.next_page());
+    }
+
+    #[test]
+    fn a_very_long_press_needs_nvs_to_restart_into_setup() {
+        let mut limb = Limb::new();
+        limb.controller.handle_press(Press::VeryLong);
+        assert!(!limb.controller.control_state.is_shutting_down());
+    }
+
+    #[test]
+    fn a_muted_buzzer_stays_quiet_through_an_estop() {
+        let mut limb = Limb::new();
+        assert_eq!(limb.controller.buzzer.playing(), Some(Sound::Boot));
+        let config = BuzzerConfig { muted: true };
+        let reply = limb.send_from(CLIENT, ControlPacket::Config(ConfigCommand::Buzzer(config)));
+        assert_eq!((reply.status, reply.payload), (Status::Ok, ReplyPayload::BuzzerConfig(config)));
+        assert_eq!(limb.controller.buzzer.playing(), None);
+        limb.send(ControlPacket::EStop);
+        assert_eq!(limb.controller.buzzer.playing(), None);
+        limb.send(ControlPacket::Config(ConfigCommand::Buzzer(BuzzerConfig { muted: false })));
+        limb.send(ControlPacket::EStop);
+        assert_eq!(limb.controller.buzzer.playing(), Some(Sound::EStop));
+    }
+
+    // An INA219 on a 0.1 ohm shunt for the joints, reading whatever the test stores in microvolts
+    fn add_sensor(limb: &mut Limb, joints: Vec<usize>) -> Arc<AtomicI32> {
+        let reading = Arc::new(AtomicI32::new(0));
+        let read = reading.clone();
+        let calibration = CurrentCalibration { zero_uv: 0, ma_per_v: 10_000 };
+        let reader: current::Reader = Box::new(move || Ok(read.load(Ordering::Relaxed)));
+        limb.controller.current.add(Source::Ina219(0x40), joints, reader, calibration);
+        reading
+    }
+
+    #[test]
+    fn an_overloaded_joint_is_latched_off_until_the_current_command_clears_it() {
+        let mut limb = Limb::new();
+        limb.send(set_angles());
+        limb.settle();
+        let reading = add_sensor(&mut limb, vec![1]);
+        reading.store(200_000, Ordering::Relaxed); // 2 A, over the 1.5 A default
+        for _ in 0..SETTLE_TICKS {
+            limb.tick();
+            if limb.controller.servos[1].is_overloaded() {
+                break;
+            }
+        }
+        assert!(limb.controller.servos[1].is_overloaded());
+        assert!(!limb.controller.servos[1].is_enabled());
+        assert!(!limb.controller.servos[0].is_overloaded());
+        reading.store(0, Ordering::Relaxed);
+        let reply = limb.send_from(CLIENT, ControlPacket::MoveJoint { index: 1, angle: 300, speed: None });
+        assert_eq!((reply.status, reply.payload), (Status::Overloaded, ReplyPayload::Index(1)));
+        assert_eq!(limb.send(set_angles()), Status::Overloaded);
+        // The joints off its sensor still move
+        assert_eq!(limb.send(ControlPacket::MoveJoint { index: 0, angle: 300, speed: None }), Status::Ok);
+        let reply = limb.send_from(CLIENT, ControlPacket::Current(Some(CurrentCommand::Clear { index: 1 })));
+        let ReplyPayload::Current { joints, .. } = reply.payload else {
+            panic!("{:?} for the current command", reply.payload);
+        };
+        assert_eq!(reply.status, Status::Ok);
+        assert_eq!((joints[1].sensor, joints[1].overloaded), (Some(0), false));
+        assert_eq!((joints[0].sensor, joints[0].current_ma), (None, None));
+        assert_eq!(limb.send(ControlPacket::MoveJoint { index: 1, angle: 300, speed: None }), Status::Ok);
+    }
+
+    #[test]
+    fn a_current_calibration_needs_a_sensor_and_a_scale() {
+        let mut limb = Limb::new();
+        add_sensor(&mut limb, vec![0, 1]);
+        let calibrate = |sensor, ma_per_v| {
+            let calibration = CurrentCalibration { zero_uv: 500, ma_per_v };
+            ControlPacket::Current(Some(CurrentCommand::Calibrate { sensor, calibration }))
+        };
+        assert_eq!(limb.send(calibrate(0, 0)), Status::BadArgument);
+        assert_eq!(limb.send(calibrate(1, 5000)), Status::BadArgument);
+        let reply = limb.send_from(CLIENT, calibrate(0, 5000));
+        let ReplyPayload::Current { sensors, .. } = reply.payload else {
+            panic!("{:?} for the current command", reply.payload);
+        };
+        assert_eq!(sensors[0].calibration, CurrentCalibration { zero_uv: 500, ma_per_v: 5000 });
+    }
+
+    #[test]
+    fn a_critical_chip_temperature_parks_the_servos_until_it_cools() {
+        let mut limb = Limb::new();
+        limb.send(set_angles());
+        limb.settle();
+        // The simulated chip sits at 45 C
+        limb.controller.thermal = Thermal::with_thresholds(30, 40);
+        limb.tick();
+        assert!(limb.state() == ControlState::Overheated);
+        assert_eq!(limb.send(set_angles()), Status::Overheated);
+        let ReplyPayload::Status { temperature_c, thermal, .. } = limb.send_from(CLIENT, ControlPacket::Status).payload
+        else {
+            panic!("not a status");
+        };
+        assert_eq!((temperature_c, thermal), (Some(45), protocol::THERMAL_CRITICAL));
+        // Parked at mid-travel and still holding it
+        limb.settle();
+        for servo in &limb.controller.servos {
+            assert_eq!(servo.get_angle(), servo.get_max_angle() / 2, "{}", servo.get_name());
+            assert!(servo.is_enabled(), "{}", servo.get_name());
+        }
+        limb.controller.thermal = Thermal::with_thresholds(0, 0);
+        limb.tick();
+        assert!(limb.state() == ControlState::Running);
+        assert_eq!(limb.send(set_angles()), Status::Ok);
+    }
+
+    #[test]
+    fn the_status_lists_the_devices_found_on_the_bus_at_boot() {
+        let mut limb = Limb::new();
+        let ReplyPayload::Status { i2c_devices, .. } = limb.send_from(CLIENT, ControlPacket::Status).payload else {
+            panic!("not a status");
+        };
+        assert_eq!(i2c_devices, [0x3c, 0x40]);
+    }
+
+    #[test]
+    fn answered_commands_are_kept_and_streamed_back_in_chunks() {
+        let mut limb = Limb::new();
+        limb.send(set_angles());
+        limb.send(ControlPacket::Status);
+        limb.send_from(OTHER, ControlPacket::Ping);
+        limb.send(ControlPacket::Jog { index: 9, delta_tenths: 5 });
+        for _ in 0..history::HISTORY_CHUNK {
+            limb.send(ControlPacket::Jog { index: 1, delta_tenths: 5 });
+        }
+        let reply = limb.send_from(CLIENT, ControlPacket::History { chunk: None });
+        let ReplyPayload::History { total, chunk: 0, chunks: 2, entries, .. } = reply.payload else {
+            panic!("{:?} for the history", reply.payload);
+        };
+        // The status poll isn't kept, a refused command is
+        assert_eq!(total as usize, 3 + history::HISTORY_CHUNK);
+        assert_eq!((entries[0].command, entries[0].params.to_vec()), (protocol::MOVE_COMMAND, POSE.to_vec()));
+        assert_eq!((entries[1].command, entries[1].ip), (protocol::PING_COMMAND, [192, 168, 1, 21]));
+        assert_eq!((entries[2].command, entries[2].status), (protocol::JOG_COMMAND, Status::BadArgument as u8));
+        let streamed: Vec<ReplyPayload> = limb
+            .replies
+            .try_iter()
+            .filter(|reply| reply.packet.command == protocol::HISTORY_COMMAND)
+            .map(|reply| reply.packet.payload)
+            .collect();
+        let [ReplyPayload::History { chunk: 1, ref entries, .. }] = streamed[..] else {
+            panic!("{:?} streamed", streamed);
+        };
+        assert_eq!(entries.len(), 3);
+        assert!(entries.iter().all(|entry| entry.command == protocol::JOG_COMMAND));
+        let reply = limb.send_from(CLIENT, ControlPacket::History { chunk: Some(2) });
+        assert_eq!(reply.status, Status::BadArgument);
+    }
+
+    #[test]
+    fn estop_stops_a_move_in_progress() {
+        let mut limb = Limb::new();
+        let pose = ControlPacket::Pose {
+            angles: POSE.to_vec(),
+            duration_ms: 2000,
+            easing: Easing::Linear,
+            tag: None,
+            tenths: false,
+        };
+        assert_eq!(limb.send(pose), Status::Ok);
+        limb.tick();
+        assert!(limb.controller.servos.iter().any(Servo::is_moving));
+        limb.send(ControlPacket::EStop);
+        let angles = limb.angles();
+        for _ in 0..10 {
+            limb.tick();
+        }
+        assert_eq!(limb.angles(), angles);
+    }
+
+    #[test]
+    fn shutdown_parks_then_stops_the_servos() {
+        let mut limb = Limb::new();
+        limb.send(set_angles());
+        limb.settle();
+        assert_eq!(limb.send(ControlPacket::Shutdown { reboot: false, confirm: 0 }), Status::BadArgument);
+        assert!(limb.state() == ControlState::Running);
+
+        assert_eq!(limb.send(shutdown()), Status::Ok);
+        assert!(matches!(limb.state(), ControlState::ShuttingDown { then: PowerDown::Off, .. }));
+        assert_eq!(limb.send(set_angles()), Status::ShutDown);
+        // Parked at mid-travel, the failsafe's safe pose
+        limb.settle();
+        limb.tick();
+        assert!(limb.state() == ControlState::ShutDown);
+        for servo in &limb.controller.servos {
+            assert_eq!(servo.get_angle(), servo.get_max_angle() / 2, "{}", servo.get_name());
+            assert!(!servo.is_enabled(), "{}", servo.get_name());
+        }
+    }
+
+    #[test]
+    fn shutdown_refuses_motion_and_another_shutdown() {
+        let mut limb = Limb::new();
+        limb.send(shutdown());
+        assert_eq!(limb.send(shutdown()), Status::ShutDown);
+        limb.settle();
+        limb.tick();
+        assert!(limb.state() == ControlState::ShutDown);
+        assert_eq!(limb.send(shutdown()), Status::ShutDown);
+        assert_eq!(limb.send(set_angles()), Status::ShutDown);
+        let calibrate = ControlPacket::Calibration { index: 0, command: CalibrationCommand::Enter };
+        assert_eq!(limb.send(calibrate), Status::ShutDown);
+        // Nothing returns it to running
+        assert_eq!(limb.send(ControlPacket::ClearEStop), Status::Ok);
+        assert!(limb.state() == ControlState::ShutDown);
+    }
+
+    #[test]
+    fn shutdown_leaves_estopped_servos_off() {
+        let mut limb = Limb::new();
+        limb.send(set_angles());
+        limb.settle();
+        limb.send(ControlPacket::EStop);
+        assert_eq!(limb.send(shutdown()), Status::Ok);
+        assert!(limb.controller.servos.iter().all(|servo| !servo.is_enabled()));
+        assert_eq!(limb.angles(), POSE);
+    }
+
+    // Only the request is checked, finishing the park would sleep the simulator, which exits
+    #[test]
+    fn sleep_needs_the_confirmation_and_a_way_to_wake() {
+        let mut limb = Limb::new();
+        limb.send(set_angles());
+        limb.settle();
+        let sleep = |confirm, wake_s| ControlPacket::Sleep { confirm, wake_s };
+        assert_eq!(limb.send(sleep(0, Some(60))), Status::BadArgument);
+        // The simulated button can't wake the chip, only a timer can
+        assert_eq!(limb.send(sleep(protocol::CONFIRM_BYTE, None)), Status::BadArgument);
+        assert!(limb.state() == ControlState::Running);
+
+        assert_eq!(limb.send(sleep(protocol::CONFIRM_BYTE, Some(60))), Status::Ok);
+        let sleeping = PowerDown::Sleep { wake_s: Some(60) };
+        assert!(matches!(limb.state(), ControlState::ShuttingDown { then, .. } if then == sleeping));
+        assert_eq!(limb.send(set_angles()), Status::ShutDown);
+        assert_eq!(limb.send(sleep(protocol::CONFIRM_BYTE, Some(60))), Status::ShutDown);
+    }
+
+    #[test]
+    fn low_battery_stops_the_servos_until_it_recovers() {
+        let mut limb = Limb::new();
+        assert_eq!(limb.send(ControlPacket::Config(ConfigCommand::Battery(LOW_BATTERY))), Status::Ok);
+        limb.battery.set_sampled_mv(7400);
+        limb.send(set_angles());
+        limb.tick();
+        assert!(limb.state() == ControlState::Running);
+
+        limb.battery.set_sampled_mv(5800);
+        limb.tick();
+        assert!(matches!(limb.state(), ControlState::LowBattery { stopped: false, .. }));
+        assert_eq!(limb.send(set_angles()), Status::LowBattery);
+        limb.settle();
+        limb.tick();
+        assert!(matches!(limb.state(), ControlState::LowBattery { stopped: true, .. }));
+        assert!(limb.controller.servos.iter().all(|servo| !servo.is_enabled()));
+
+        // Still within the hysteresis
+        limb.battery.set_sampled_mv(6200);
+        limb.tick();
+        assert!(matches!(limb.state(), ControlState::LowBattery { .. }));
+        assert_eq!(limb.send(set_angles()), Status::LowBattery);
+
+        limb.battery.set_sampled_mv(6400);
+        limb.tick();
+        assert!(limb.state() == ControlState::Running);
+        assert_eq!(limb.send(set_angles()), Status::Ok);
+    }
+
+    #[test]
+    fn estop_during_low_battery_is_kept_once_it_recovers() {
+        let mut limb = Limb::new();
+        limb.send(ControlPacket::Config(ConfigCommand::Battery(LOW_BATTERY)));
+        limb.battery.set_sampled_mv(5800);
+        limb.tick();
+        assert!(matches!(limb.state(), ControlState::LowBattery { .. }));
+        limb.send(ControlPacket::EStop);
+        assert!(limb.state() == ControlState::EStopped(EStopSource::Network));
+        limb.battery.set_sampled_mv(7400);
+        limb.tick();
+        assert!(limb.state() == ControlState::EStopped(EStopSource::Network));
+        assert_eq!(limb.send(set_angles()), Status::EStopped);
+    }
+
+    #[test]
+    fn update_is_refused_on_a_low_battery_and_once_shut_down() {
+        let mut limb = Limb::new();
+        let update = || ControlPacket::Update { url: "http://192.168.1.20/firmware.bin".to_string() };
+        limb.send(ControlPacket::Config(ConfigCommand::Battery(LOW_BATTERY)));
+        limb.battery.set_sampled_mv(5800);
+        limb.tick();
+        assert_eq!(limb.send(update()), Status::LowBattery);
+        limb.battery.set_sampled_mv(7400);
+        limb.tick();
+        limb.send(shutdown());
+        assert_eq!(limb.send(update()), Status::ShutDown);
+        assert!(matches!(limb.state(), ControlState::ShuttingDown { .. }));
+    }
+
+    #[test]
+    fn joints_start_off_at_the_safe_pose() {
+        let limb = Limb::new();
+        for servo in limb.controller.servos.iter() {
+            assert_eq!((servo.get_angle(), servo.get_goal()), (servo.get_max_angle() / 2, servo.get_max_angle() / 2));
+            assert!(!servo.is_enabled());
+        }
+    }
+
+    #[test]
+    fn the_power_on_pose_is_a_slot_of_its_own() {
+        let mut limb = Limb::new();
+        // Without settings there is nowhere to keep it, but the slot is taken
+        let reply = limb.send(ControlPacket::SavePreset { slot: protocol::POWER_ON_SLOT, name: "boot".into() });
+        assert_eq!(reply, Status::HardwareError);
+        let reply = limb.send(ControlPacket::SavePreset { slot: preset::SLOT_COUNT, name: "boot".into() });
+        assert_eq!(reply, Status::BadArgument);
+    }
+
+    #[test]
+    fn ping_replies_with_the_version_and_every_servo() {
+        let mut limb = Limb::new();
+        let reply = limb.send_from(CLIENT, ControlPacket::Ping);
+        assert_eq!((reply.command, reply.status), (protocol::PING_COMMAND, Status::Ok));
+        match reply.payload {
+            ReplyPayload::Ping { version_maj, version_min, positions } => {
+                assert_eq!((version_maj, version_min), (VERSION_MAJ as u8, VERSION_MIN as u8));
+                assert_eq!(positions.len(), protocol::SERVO_COUNT);
+            }
+            payload => panic!("Ping answered with {:?}", payload),
+        }
+    }
+
+    #[test]
+    fn move_replies_with_the_angles_it_reached() {
+        let mut limb = Limb::new();
+        let reply = limb.send_from(CLIENT, set_angles());
+        assert_eq!((reply.command, reply.status), (protocol::MOVE_COMMAND, Status::Ok));
+        limb.settle();
+        let reply = limb.send_from(CLIENT, set_angles());
+        let ReplyPayload::Positions(positions) = reply.payload else {
+            panic!("Move answered with {:?}", reply.payload);
+        };
+        assert!(positions.iter().map(|position| position.angle).eq(POSE));
+    }
+
+    #[test]
+    fn status_shows_each_joints_goal_then_the_angle_reached() {
+        let mut limb = Limb::new();
+        assert_eq!(limb.send(set_angles()), Status::Ok);
+        let reply = limb.send_from(CLIENT, ControlPacket::Status);
+        let ReplyPayload::Status { joints, .. } = reply.payload else {
+            panic!("Status answered with {:?}", reply.payload);
+        };
+        assert!(joints.iter().map(|joint| joint.goal).eq(POSE));
+        limb.settle();
+        let ReplyPayload::Status { joints, .. } = limb.send_from(CLIENT, ControlPacket::Status).payload else {
+            unreachable!();
+        };
+        assert!(joints.iter().map(|joint| joint.angle).eq(POSE));
+    }
+
+    #[test]
+    fn move_with_the_wrong_count_is_refused() {
+        let mut limb = Limb::new();
+        assert_eq!(limb.send(ControlPacket::SetAngles(vec![90])), Status::BadLength);
+        assert!(limb.controller.servos.iter().all(|servo| !servo.is_enabled()));
+        assert_eq!(limb.controller.stats.get().malformed, 1);
+    }
+
+    #[test]
+    fn move_past_the_limits_is_clamped() {
+        let mut limb = Limb::new();
+        let past: Vec<u16> = limb.controller.servos.iter().map(|servo| servo.get_max_angle() + 10).collect();
+        assert_eq!(limb.send(ControlPacket::SetAngles(past)), Status::Clamped);
+        for servo in &limb.controller.servos {
+            assert_eq!(servo.get_goal(), servo.get_max_angle(), "{}", servo.get_name());
+        }
+    }
+
+    #[test]
+    fn joint_out_of_range_is_refused() {
+        let mut limb = Limb::new();
+        let index = protocol::SERVO_COUNT as u8;
+        let reply = limb.send_from(CLIENT, ControlPacket::MoveJoint { index, angle: 90, speed: None });
+        assert_eq!((reply.status, reply.payload), (Status::BadArgument, ReplyPayload::Index(index)));
+    }
+
+    #[test]
+    fn repeated_pose_is_answered_without_moving() {
+        let mut limb = Limb::new();
+        assert_eq!(limb.send(set_angles()), Status::Ok);
+        assert_eq!(limb.send(set_angles()), Status::Ok);
+        let counts = limb.controller.stats.get();
+        assert_eq!((counts.applied, counts.deduplicated), (1, 1));
+        // Once stopped the same pose drives the servos again
+        assert_eq!(limb.send(ControlPacket::EStop), Status::Ok);
+        assert_eq!(limb.send(ControlPacket::ClearEStop), Status::Ok);
+        assert_eq!(limb.send(set_angles()), Status::Ok);
+        assert_eq!(limb.controller.stats.get().applied, 2);
+    }
+
+    #[test]
+    fn session_owner_alone_changes_anything() {
+        let mut limb = Limb::new();
+        let other = Ipv4Addr::new(192, 168, 1, 21);
+        assert_eq!(limb.send_from(OTHER, ControlPacket::Claim).payload, ReplyPayload::Owner(other));
+        let reply = limb.send_from(CLIENT, set_angles());
+        assert_eq!((reply.status, reply.payload), (Status::Busy, ReplyPayload::Owner(other)));
+        assert_eq!(limb.send(ControlPacket::Claim), Status::Busy);
+        // Anyone can still look, and stop the limb
+        assert_eq!(limb.send(ControlPacket::Telemetry { tenths: false }), Status::Ok);
+        assert_eq!(limb.send(ControlPacket::Status), Status::Ok);
+        assert_eq!(limb.send(ControlPacket::EStop), Status::Ok);
+
+        assert_eq!(limb.send_from(OTHER, ControlPacket::Release).status, Status::Ok);
+        assert_eq!(limb.send(ControlPacket::ClearEStop), Status::Ok);
+        assert_eq!(limb.send(set_angles()), Status::Ok);
+    }
+
+    #[test]
+    fn gripper_is_clamped_refused_while_estopped_and_read_by_anyone() {
+        let mut limb = Limb::new();
+        assert_eq!(limb.send(ControlPacket::Gripper(None)), Status::BadArgument);
+
+        let (driver, _) = MockServo::new();
+        let mut limb = Limb::with_gripper(Some(Gripper::new(driver, sim::FRAME_HZ)));
+        assert_eq!(limb.send(ControlPacket::Gripper(Some((GripperMode::Move, 50)))), Status::Ok);
+        let reply = limb.send_from(CLIENT, ControlPacket::Gripper(Some((GripperMode::Grip, 150))));
+        let ReplyPayload::Gripper(gripper) = reply.payload else {
+            panic!("Gripper answered with {:?}", reply.payload);
+        };
+        assert_eq!((reply.status, gripper.target, gripper.last), (Status::Clamped, 100, Some(GripperMode::Grip)));
+
+        assert_eq!(limb.send(ControlPacket::EStop), Status::Ok);
+        assert!(!limb.controller.gripper.as_ref().unwrap().is_moving());
+        assert_eq!(limb.send(ControlPacket::Gripper(Some((GripperMode::Move, 0)))), Status::EStopped);
+        assert_eq!(limb.send_from(OTHER, ControlPacket::Gripper(None)).status, Status::Ok);
+    }
+
+    #[test]
+    fn feedback_calibration_captures_the_current_reading() {
+        let mut limb = Limb::new();
+        let mut feedback = Feedback::new(Box::new(|| Ok(1234)), FeedbackCalibration::DEFAULT);
+        feedback.sample("Base");
+        limb.controller.servos[0].set_feedback(feedback);
+        let command = CalibrationCommand::FeedbackMin(protocol::FEEDBACK_CAPTURE);
+        let reply = limb.send_from(CLIENT, ControlPacket::Calibration { index: 0, command });
+        assert_eq!(reply.status, Status::Ok);
+        assert_eq!(reply.payload, ReplyPayload::Calibration { command, index: 0, duty: 1234 });
+        let calibration = limb.controller.servos[0].get_feedback_mut().unwrap().get_calibration();
+        assert_eq!(calibration, FeedbackCalibration { min_mv: 1234, ..FeedbackCalibration::DEFAULT });
+
+        let command = CalibrationCommand::FeedbackMax(3000);
+        assert_eq!(limb.send(ControlPacket::Calibration { index: 1, command }), Status::BadArgument);
+    }
+
+    #[test]
+    fn teach_records_the_pose_into_the_upload() {
+        let mut limb = Limb::new();
+        let reply = limb.send_from(CLIENT, ControlPacket::Teach(Some(TeachCommand::Start)));
+        assert_eq!(reply.payload, ReplyPayload::Recording { recording: true, frames: 0, remaining: 32 });
+        limb.tick();
+        let reply = limb.send_from(CLIENT, ControlPacket::Teach(Some(TeachCommand::Stop)));
+        assert_eq!(reply.payload, ReplyPayload::Recording { recording: false, frames: 1, remaining: 31 });
+        assert_eq!(limb.controller.uploaded[0].angles.to_vec(), limb.angles());
+        // The simulator's clock is always synced, a client's upload has no capture time
+        assert!(limb.controller.uploaded_at.is_some());
+        let frames = limb.controller.uploaded.clone();
+        assert_eq!(limb.send(ControlPacket::UploadTrajectory(frames)), Status::Ok);
+        assert_eq!(limb.controller.uploaded_at, None);
+    }
+
+    #[test]
+    fn speed_scale_is_clamped_and_never_zero() {
+        let mut limb = Limb::new();
+        let reply = limb.send_from(CLIENT, ControlPacket::SpeedScale(Some((150, false))));
+        assert_eq!((reply.status, reply.payload), (Status::Clamped, ReplyPayload::SpeedScale(servo::FULL_SPEED)));
+        assert_eq!(limb.send(ControlPacket::SpeedScale(Some((50, false)))), Status::Ok);
+        let reply = limb.send_from(CLIENT, ControlPacket::SpeedScale(Some((0, false))));
+        assert_eq!((reply.status, reply.payload), (Status::BadArgument, ReplyPayload::SpeedScale(50)));
+    }
+
+    #[test]
+    fn pause_holds_the_servos_until_resumed() {
+        let mut limb = Limb::new();
+        assert_eq!(limb.send(set_angles()), Status::Ok);
+        limb.tick();
+        // Anyone can pause, only the owner resumes
+        assert_eq!(limb.send_from(CLIENT, ControlPacket::Claim).status, Status::Ok);
+        assert_eq!(limb.send_from(OTHER, ControlPacket::Pause).status, Status::Ok);
+        assert_eq!(limb.send_from(OTHER, ControlPacket::Resume).status, Status::Busy);
+        let held = limb.angles();
+        for _ in 0..10 {
+            limb.tick();
+        }
+        assert_eq!(limb.angles(), held);
+        if !CONFIG.pause_queue_moves {
+            assert_eq!(limb.send(set_angles()), Status::Paused);
+        }
+
+        assert_eq!(limb.send(ControlPacket::Resume), Status::Ok);
+        limb.settle();
+        assert!(limb.angles().iter().eq(POSE.iter()));
+    }
+
+    #[test]
+    fn self_test_holds_off_moves_and_is_skipped_by_an_estop() {
+        let mut limb = Limb::new();
+        assert_eq!(limb.send(ControlPacket::SelfTest { start: true }), Status::Ok);
+        limb.tick();
+        assert_eq!(limb.send(set_angles()), Status::Busy);
+        assert_eq!(limb.send(ControlPacket::SelfTest { start: true }), Status::Busy);
+        let reply = limb.send_from(CLIENT, ControlPacket::SelfTest { start: false });
+        let ReplyPayload::SelfTest { running: true, results } = reply.payload else { panic!("{:?}", reply.payload) };
+        assert!(results.iter().all(|&result| result == protocol::SELF_TEST_PENDING));
+
+        assert_eq!(limb.send(ControlPacket::EStop), Status::Ok);
+        let reply = limb.send_from(CLIENT, ControlPacket::SelfTest { start: false });
+        let ReplyPayload::SelfTest { running: false, results } = reply.payload else { panic!("{:?}", reply.payload) };
+        assert!(results.iter().all(|&result| result == protocol::SELF_TEST_SKIPPED));
+    }
+
+    #[test]
+    fn mirroring_reflects_the_configured_joints_of_each_move() {
+        let mut limb = Limb::new();
+        limb.controller.mirror = Mirror::new(false, mirror::parse_joints("1", POSE.len()));
+        let reply = limb.send_from(CLIENT, ControlPacket::Mirror(Some((true, false))));
+        assert_eq!(reply.payload, ReplyPayload::Mirror(true));
+        assert_eq!(limb.send(set_angles()), Status::Ok);
+        limb.settle();
+        assert_eq!(limb.angles(), [900, 1350, 900, 1200, 600]);
+        let ReplyPayload::Status { mirrored: true, .. } = limb.send_from(CLIENT, ControlPacket::Status).payload else {
+            panic!("not mirrored");
+        };
+    }
+
+    #[test]
+    fn estop_ends_a_pause() {
+        let mut limb = Limb::new();
+        assert_eq!(limb.send(ControlPacket::Pause), Status::Ok);
+        assert_eq!(limb.send(ControlPacket::EStop), Status::Ok);
+        assert!(!limb.controller.paused);
+        assert_eq!(limb.send(ControlPacket::Pause), Status::EStopped);
+    }
+
+    #[test]
+    fn log_level_out_of_range_is_refused() {
+        let mut limb = Limb::new();
+        let level = remote_log::forward_level() as u8;
+        let reply = limb.send_from(CLIENT, ControlPacket::LogLevel(Some(9)));
+        assert_eq!(reply.status, Status::BadArgument);
+        assert_eq!(reply.payload, ReplyPayload::LogLevel { level, forwarding: remote_log::is_forwarding() });
+    }
+
+    #[test]
+    fn failsafe_config_replies_with_what_it_applied() {
+        let mut limb = Limb::new();
+        let config = FailsafeConfig { timeout_ms: 750, action: FailsafeAction::Park.to_byte() };
+        let reply = limb.send_from(CLIENT, ControlPacket::Config(ConfigCommand::Failsafe(config)));
+        assert_eq!((reply.status, reply.payload), (Status::Ok, ReplyPayload::FailsafeConfig(config)));
+        let bad = FailsafeConfig { action: 9, ..config };
+        let reply = limb.send_from(CLIENT, ControlPacket::Config(ConfigCommand::Failsafe(bad)));
+        let index = ReplyPayload::Index(protocol::FAILSAFE_CONFIG_INDEX);
+        assert_eq!((reply.status, reply.payload), (Status::BadArgument, index));
+    }
+
+    #[test]
+    fn servo_config_echoes_an_acceleration_only_to_a_client_that_sent_one() {
+        let mut limb = Limb::new();
+        let config =
+            ServoConfig { index: 1, speed: 90, trim: 0, reversed: false, safe_angle: 90, detach_s: 0, accel: None };
+        let accelerated = ServoConfig { accel: Some(400), ..config };
+        let reply = limb.send_from(CLIENT, ControlPacket::Config(ConfigCommand::Servo(accelerated)));
+        assert_eq!((reply.status, reply.payload), (Status::Ok, ReplyPayload::ServoConfig(accelerated)));
+        assert_eq!(limb.controller.servos[1].get_accel(), 400);
+        // An older client leaves it as it was and gets the reply it knows
+        let reply = limb.send_from(CLIENT, ControlPacket::Config(ConfigCommand::Servo(config)));
+        assert_eq!((reply.status, reply.payload), (Status::Ok, ReplyPayload::ServoConfig(config)));
+        assert_eq!(limb.controller.servos[1].get_accel(), 400);
+    }
+
+    // A full queue's worth, as much as the loop takes in one pass
+    const BURST: usize = network::COMMAND_QUEUE_SIZE + 1;
+
+    fn command(addr: SocketAddr, sequence: u16, packet: ControlPacket) -> Command {
+        Command { addr, sequence, packet, reply_to: ReplyTo::Socket }
+    }
+
+    // A move of every joint to `angle`
+    fn move_to(angle: u16) -> ControlPacket {
+        ControlPacket::SetAngles(vec![angle; protocol::SERVO_COUNT])
+    }
+
+    fn answered(limb: &Limb) -> Vec<(u16, u8, Status)> {
+        limb.replies.try_iter().map(|reply| (reply.sequence, reply.packet.command, reply.packet.status)).collect()
+    }
+
+    #[test]
+    fn burst_runs_in_order_and_applies_the_newest_of_consecutive_moves() {
+        let mut limb = Limb::new();
+        let mut batch: Vec<Command> = (0..BURST as u16).map(|n| command(CLIENT, n, move_to(10 + n * 10))).collect();
+        batch.insert(3, command(CLIENT, 100, ControlPacket::Ping));
+        limb.controller.handle_batch(batch);
+
+        // The three moves before the ping, then the rest
+        assert_eq!(limb.controller.stats.get().applied, 2);
+        let newest = 10 + (BURST as u16 - 1) * 10;
+        assert!(limb.controller.servos.iter().all(|servo| servo.get_goal() == newest));
+        let replies = answered(&limb);
+        let sequences: Vec<u16> = replies.iter().map(|&(sequence, _, _)| sequence).collect();
+        assert_eq!(sequences[..4], [0, 1, 2, 100]);
+        assert_eq!(sequences.len(), BURST + 1);
+        assert!(replies.iter().all(|&(_, _, status)| status == Status::Ok));
+    }
+
+    #[test]
+    fn burst_applies_moves_from_each_client_in_turn() {
+        let mut limb = Limb::new();
+        let batch = vec![
+            command(CLIENT, 0, move_to(30)),
+            command(CLIENT, 1, move_to(40)),
+            command(OTHER, 0, move_to(60)),
+            command(OTHER, 1, move_to(90)),
+        ];
+        limb.controller.handle_batch(batch);
+        assert_eq!(limb.controller.stats.get().applied, 2);
+        assert!(limb.controller.servos.iter().all(|servo| servo.get_goal() == 90));
+        assert_eq!(answered(&limb).len(), 4);
+    }
+
+    #[test]
+    fn estop_in_a_burst_drops_the_moves_before_it() {
+        let mut limb = Limb::new();
+        let batch = vec![
+            command(CLIENT, 0, move_to(30)),
+            command(CLIENT, 1, move_to(60)),
+            command(CLIENT, 2, ControlPacket::EStop),
+            command(CLIENT, 3, move_to(90)),
+        ];
+        limb.controller.handle_batch(batch);
+        assert_eq!(limb.controller.stats.get().applied, 0);
+        let mut replies = answered(&limb);
+        assert_eq!(replies.remove(0), (2, protocol::ESTOP_COMMAND, Status::Ok));
+        let mut refused: Vec<u16> = replies.iter().map(|&(sequence, _, _)| sequence).collect();
+        refused.sort_unstable();
+        assert_eq!(refused, [0, 1, 3]);
+        assert!(replies.iter().all(|&(_, _, status)| status == Status::EStopped), "{:?}", replies);
+        assert!(limb.controller.servos.iter().all(|servo| !servo.is_enabled()));
+    }
+
+    #[test]
+    fn move_then_estop_then_clear_leaves_the_servos_where_they_were() {
+        let mut limb = Limb::new();
+        assert_eq!(limb.send(set_angles()), Status::Ok);
+        limb.settle();
+        let batch = vec![
+            command(CLIENT, 0, move_to(30)),
+            command(CLIENT, 1, ControlPacket::EStop),
+            command(CLIENT, 2, ControlPacket::ClearEStop),
+        ];
+        limb.controller.handle_batch(batch);
+        let replies = answered(&limb);
+        assert!(replies.contains(&(0, protocol::MOVE_COMMAND, Status::EStopped)), "{:?}", replies);
+        assert!(limb.state() == ControlState::Running);
+        limb.settle();
+        assert!(limb.angles().iter().eq(POSE.iter()));
+    }
+
+    #[test]
+    fn pause_in_a_burst_drops_the_moves_before_it() {
+        let mut limb = Limb::new();
+        let batch = vec![command(CLIENT, 0, move_to(30)), command(CLIENT, 1, ControlPacket::Pause)];
+        limb.controller.handle_batch(batch);
+        assert_eq!(answered(&limb)[1], (0, protocol::MOVE_COMMAND, Status::Paused));
+        assert!(limb.controller.servos.iter().all(|servo| !servo.is_enabled()));
+    }
+
+    #[test]
+    fn a_refused_stop_still_applies_the_moves_before_it() {
+        let mut limb = Limb::new();
+        let batch = vec![
+            command(CLIENT, 0, move_to(30)),
+            // Ignored without the confirmation byte
+            command(CLIENT, 1, ControlPacket::Shutdown { reboot: false, confirm: 0 }),
+        ];
+        limb.controller.handle_batch(batch);
+        assert!(limb.state() == ControlState::Running);
+        assert_eq!(limb.controller.stats.get().applied, 1);
+        assert!(limb.controller.servos.iter().all(|servo| servo.get_goal() == 30));
+    }
+}
@@ -0,0 +1,115 @@
+// Panic hook for the hardware build, stops every servo output and keeps the message in NVS for the next boot to show
+use std::any::Any;
+use std::panic::{self, Location};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard, OnceLock, TryLockError};
+
+use esp_idf_hal::delay::FreeRtos;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_sys::{ledc_mode_t_LEDC_LOW_SPEED_MODE, ledc_stop};
+
+use crate::pca9685;
+use crate::settings::{self, Settings};
+use crate::shared_i2c::SharedI2c;
+
+const BUS_WAIT_MS: u32 = 10; // Time another task's transfer gets to finish before the PCA9685 is given up on
+
+// Outputs registered at start-up as they are created
+struct Outputs {
+    ledc_channels: u8,                // Channels 0 up to this drive servos, LEDC hands out the same low speed group
+    pca9685: Option<(SharedI2c, u8)>, // Bus and address, only when a joint is on the expander
+}
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+static PARTITION: OnceLock<EspDefaultNvsPartition> = OnceLock::new();
+static OUTPUTS: Mutex<Outputs> = Mutex::new(Outputs { ledc_channels: 0, pca9685: None });
+
+// Installed before anything else starts, so that a panic during start-up is kept too. Without NVS it still stops the
+// outputs but the message only reaches the serial console
+pub fn install(partition: Option<EspDefaultNvsPartition>) {
+    if let Some(partition) = partition {
+        let _ = PARTITION.set(partition);
+    }
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        PANICKING.store(true, Ordering::SeqCst);
+        stop_outputs();
+        default_hook(info); // Still prints the message and backtrace to the console
+        save_message(&describe(info.payload(), info.location()));
+    }));
+}
+
+// Set from the moment a panic starts, the backends refuse to drive outputs the hook has stopped. Other tasks keep
+// running until the chip restarts and would otherwise restart them
+pub fn in_progress() -> bool {
+    PANICKING.load(Ordering::SeqCst)
+}
+
+pub fn watch_ledc(channels: u8) {
+    lock_outputs().ledc_channels = channels;
+}
+
+pub fn watch_pca9685(bus: SharedI2c, address: u8) {
+    lock_outputs().pca9685 = Some((bus, address));
+}
+
+fn lock_outputs() -> MutexGuard<'static, Outputs> {
+    OUTPUTS.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn stop_outputs() {
+    // Only start-up takes the lock, if it panicked while holding it there are no servos to stop yet
+    let outputs = match OUTPUTS.try_lock() {
+        Ok(outputs) => outputs,
+        Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+        Err(TryLockError::WouldBlock) => return,
+    };
+    for channel in 0..outputs.ledc_channels {
+        // Safety: only writes the channel's registers, the backends stop touching them once PANICKING is set.
+        // An idle level of 0 sends no pulses, which leaves the servo unpowered
+        unsafe {
+            ledc_stop(ledc_mode_t_LEDC_LOW_SPEED_MODE, channel as u32, 0);
+        }
+    }
+    if let Some((bus, address)) = &outputs.pca9685 {
+        // The bus may be held by a transfer in another task, or by this one if it panicked mid-transfer
+        for _ in 0..BUS_WAIT_MS {
+            match pca9685::try_stop_all(bus, *address) {
+                Some(_) => break,
+                None => FreeRtos::delay_ms(1),
+            }
+        }
+    }
+}
+
+fn save_message(message: &str) {
+    let Some(partition) = PARTITION.get() else {
+        return;
+    };
+    // A handle of its own, the control task's Settings may be mid-write or held by the panicking task
+    match Settings::new(partition.clone()).and_then(|mut settings| settings.save_crash(message)) {
+        Ok(_) => {},
+        Err(e) => println!("Failed to keep the panic message: {}", e), // The logger may be what panicked
+    }
+}
+
+// "<message> at <file>:<line>", cut to fit the NVS blob
+fn describe(payload: &(dyn Any + Send), location: Option<&Location>) -> String {
+    let text = match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+        (Some(text), _) => text,
+        (None, Some(text)) => text.as_str(),
+        (None, None) => "unknown panic",
+    };
+    let mut message = match location {
+        Some(location) => format!("{} at {}:{}", text, location.file(), location.line()),
+        None => text.to_string(),
+    };
+    if message.len() > settings::MAX_CRASH_SIZE {
+        let mut end = settings::MAX_CRASH_SIZE;
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        message.truncate(end);
+    }
+    message
+}
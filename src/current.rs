@@ -0,0 +1,365 @@
+// Servo current from INA219s or ADC1 amplifiers, each covering one joint or the rail of several. One over the limit
+// for the whole window trips, the controller then latches its joints off until a current command clears them
+use std::fmt;
+
+use log::{error, info, warn};
+
+use crate::backend::DriverError;
+use crate::boards;
+use crate::protocol::SensorReading;
+use crate::settings::Settings;
+use crate::CONFIG;
+
+pub const CALIBRATION_SIZE: usize = 9;
+const CALIBRATION_VERSION: u8 = 1; // Bump when the stored current calibration layout changes
+const FILTER_WEIGHT: f32 = 0.25; // Of each new reading in the running average, enough to smooth out PWM ripple
+
+// Reads the sensor in microvolts, across the shunt for an INA219 or at the pin for an amplifier
+pub type Reader = Box<dyn FnMut() -> Result<i32, DriverError> + Send>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    Ina219(u8), // 7-bit address
+    Adc(u8),    // GPIO
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Ina219(address) => write!(f, "INA219 at {:#04x}", address),
+            Source::Adc(pin) => write!(f, "GPIO{}", pin),
+        }
+    }
+}
+
+// Maps a reading onto milliamps, set from the current command and kept in NVS
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CurrentCalibration {
+    pub zero_uv: i32,  // Reading with no current flowing, an amplifier's output usually sits above 0
+    pub ma_per_v: u32, // Current for each volt the reading rises above zero_uv, 10000 for a 0.1 ohm shunt
+}
+
+impl CurrentCalibration {
+    // Until the sensor is calibrated, the shunt or amplifier the config describes
+    pub fn default_for(source: Source) -> CurrentCalibration {
+        match source {
+            Source::Ina219(_) => CurrentCalibration {
+                zero_uv: 0,
+                ma_per_v: 1_000_000 / CONFIG.current_shunt_mohm.max(1) as u32,
+            },
+            Source::Adc(_) => CurrentCalibration {
+                zero_uv: CONFIG.current_adc_zero_mv as i32 * 1000,
+                ma_per_v: CONFIG.current_adc_ma_per_v,
+            },
+        }
+    }
+
+    // Layout: version, zero reading, milliamps per volt, all little-endian
+    pub fn to_bytes(self) -> [u8; CALIBRATION_SIZE] {
+        let mut bytes = [0u8; CALIBRATION_SIZE];
+        bytes[0] = CALIBRATION_VERSION;
+        bytes[1..5].copy_from_slice(&self.zero_uv.to_le_bytes());
+        bytes[5..9].copy_from_slice(&self.ma_per_v.to_le_bytes());
+        bytes
+    }
+
+    // Returns None for blobs of the wrong size or version, or without a scale
+    pub fn from_bytes(bytes: &[u8]) -> Option<CurrentCalibration> {
+        if bytes.len() != CALIBRATION_SIZE || bytes[0] != CALIBRATION_VERSION {
+            return None;
+        }
+        let calibration = CurrentCalibration {
+            zero_uv: i32::from_le_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]),
+            ma_per_v: u32::from_le_bytes([bytes[5], bytes[6], bytes[7], bytes[8]]),
+        };
+        match calibration.ma_per_v {
+            0 => None,
+            _ => Some(calibration),
+        }
+    }
+
+    // Current the other way reads as none, only its size matters for an overload
+    fn milliamps(self, reading_uv: f32) -> f32 {
+        ((reading_uv - self.zero_uv as f32) * self.ma_per_v as f32 / 1e6).max(0.0)
+    }
+}
+
+struct Sensor {
+    source: Source,
+    joints: Vec<usize>,
+    read: Reader,
+    calibration: CurrentCalibration,
+    average_uv: Option<f32>, // None until the first reading
+    failing: bool,           // Whether the last read failed, only the first failure is logged
+    over_polls: u32,         // Polls in a row the average has been over the limit
+}
+
+impl Sensor {
+    fn get_current_ma(&self) -> Option<u16> {
+        self.average_uv.map(|average_uv| self.calibration.milliamps(average_uv).round().min(u16::MAX as f32) as u16)
+    }
+}
+
+pub struct CurrentMonitor {
+    sensors: Vec<Sensor>,
+    limit_ma: u16, // 0 only monitors
+    window_ms: u16,
+}
+
+impl CurrentMonitor {
+    // Without sensors until they are added
+    pub fn new() -> CurrentMonitor {
+        CurrentMonitor { sensors: Vec::new(), limit_ma: CONFIG.current_limit_ma, window_ms: CONFIG.current_window_ms }
+    }
+
+    // Sensors are numbered in the order they are added
+    pub fn add(&mut self, source: Source, joints: Vec<usize>, read: Reader, calibration: CurrentCalibration) {
+        self.sensors.push(Sensor {
+            source,
+            joints,
+            read,
+            calibration,
+            average_uv: None,
+            failing: false,
+            over_polls: 0,
+        });
+    }
+
+    // Takes a reading from every sensor into its average, a failed one leaves the last average standing. Returns the
+    // joints on any sensor that has just been over the limit for the whole window
+    pub fn sample(&mut self, poll_hz: u32) -> Vec<usize> {
+        let window_polls = self.window_ms as u32 * poll_hz / 1000;
+        let mut tripped = Vec::new();
+        for sensor in self.sensors.iter_mut() {
+            match (sensor.read)() {
+                Ok(reading_uv) => {
+                    sensor.failing = false;
+                    let reading_uv = reading_uv as f32;
+                    sensor.average_uv = Some(match sensor.average_uv {
+                        Some(average_uv) => average_uv + (reading_uv - average_uv) * FILTER_WEIGHT,
+                        None => reading_uv,
+                    });
+                }
+                Err(e) if !sensor.failing => {
+                    error!("Failed to read the current sensor {}: {}", sensor.source, e);
+                    sensor.failing = true;
+                }
+                Err(_) => {},
+            }
+            let over =
+                self.limit_ma != 0 && sensor.get_current_ma().is_some_and(|current_ma| current_ma > self.limit_ma);
+            sensor.over_polls = if over { sensor.over_polls.saturating_add(1) } else { 0 };
+            // Counted afresh after a trip, the joints are stopped by then and only a fault drawing current regardless
+            // trips again
+            if sensor.over_polls > window_polls {
+                warn!(
+                    "{} over {} mA for {} ms, at {} mA",
+                    sensor.source,
+                    self.limit_ma,
+                    self.window_ms,
+                    sensor.get_current_ma().unwrap_or(0)
+                );
+                sensor.over_polls = 0;
+                tripped.extend_from_slice(&sensor.joints);
+            }
+        }
+        tripped
+    }
+
+    // The sensor a joint is on, None for a joint without one
+    pub fn sensor_for(&self, joint: usize) -> Option<u8> {
+        self.sensors.iter().position(|sensor| sensor.joints.contains(&joint)).map(|sensor| sensor as u8)
+    }
+
+    // The averaged current, None until the sensor's first reading or for a sensor there isn't
+    pub fn get_current_ma(&self, sensor: u8) -> Option<u16> {
+        self.sensors.get(sensor as usize).and_then(Sensor::get_current_ma)
+    }
+
+    // Every sensor's current and calibration, for the current reply
+    pub fn readings(&self) -> Vec<SensorReading> {
+        self.sensors
+            .iter()
+            .map(|sensor| SensorReading { current_ma: sensor.get_current_ma(), calibration: sensor.calibration })
+            .collect()
+    }
+
+    // False for a sensor there isn't
+    pub fn set_calibration(&mut self, sensor: u8, calibration: CurrentCalibration) -> bool {
+        match self.sensors.get_mut(sensor as usize) {
+            Some(sensor) => {
+                info!("{} calibrated: zero {} uV, {} mA/V", sensor.source, calibration.zero_uv, calibration.ma_per_v);
+                sensor.calibration = calibration;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn sensor_count(&self) -> u8 {
+        self.sensors.len() as u8
+    }
+
+    pub fn get_limit_ma(&self) -> u16 {
+        self.limit_ma
+    }
+
+    pub fn get_window_ms(&self) -> u16 {
+        self.window_ms
+    }
+}
+
+// One optional sensor per joint from the comma separated config, "ina219:<address>" or "adc:<pin>". Empty and
+// missing entries have no sensor, nor do ADC pins that aren't on ADC1 or are taken by the battery or feedback
+pub fn parse_sources(config: &str, joints: usize, taken_pins: &[u8]) -> Vec<Option<Source>> {
+    let mut entries = config.split(',').map(str::trim);
+    (0..joints)
+        .map(|joint| {
+            let entry = entries.next().unwrap_or("");
+            if entry.is_empty() {
+                return None;
+            }
+            let source = match entry.split_once(':') {
+                Some(("ina219", address)) => parse_address(address.trim()).map(Source::Ina219),
+                Some(("adc", pin)) => match pin.trim().parse::<u8>() {
+                    Ok(pin)
+                        if boards::is_adc_pin(pin) && pin != boards::battery_pin() && !taken_pins.contains(&pin) =>
+                    {
+                        Some(Source::Adc(pin))
+                    }
+                    _ => None,
+                },
+                _ => None,
+            };
+            if source.is_none() {
+                warn!(
+                    "Current sensor \"{}\" for joint {} isn't an INA219 or a free ADC1 pin, it has none",
+                    entry, joint
+                );
+            }
+            source
+        })
+        .collect()
+}
+
+// "0x40" or "64"
+fn parse_address(address: &str) -> Option<u8> {
+    match address.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => address.parse().ok(),
+    }
+}
+
+// Each sensor named and the joints on it, in the order of the first joint naming each
+pub fn group(sources: &[Option<Source>]) -> Vec<(Source, Vec<usize>)> {
+    let mut sensors: Vec<(Source, Vec<usize>)> = Vec::new();
+    for (joint, source) in sources.iter().enumerate() {
+        let Some(source) = *source else { continue };
+        match sensors.iter_mut().find(|(named, _)| *named == source) {
+            Some((_, joints)) => joints.push(joint),
+            None => sensors.push((source, vec![joint])),
+        }
+    }
+    sensors
+}
+
+// The stored calibration, or the default while there is none
+pub fn load_calibration(settings: Option<&Settings>, sensor: usize, source: Source) -> CurrentCalibration {
+    match settings.map(|settings| settings.load_current(sensor)) {
+        Some(Ok(Some(calibration))) => {
+            info!("Current calibration for {} loaded from NVS", source);
+            calibration
+        }
+        Some(Ok(None)) | None => CurrentCalibration::default_for(source),
+        Some(Err(e)) => {
+            warn!("Failed to read the current calibration for {}, using defaults: {}", source, e);
+            CurrentCalibration::default_for(source)
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::sim::SimError;
+
+    const SHUNT: CurrentCalibration = CurrentCalibration { zero_uv: 0, ma_per_v: 10_000 }; // 0.1 ohm
+    const POLL_HZ: u32 = 100;
+
+    // A sensor reading whatever the test last set, in microvolts, or failing for None
+    fn sensor(monitor: &mut CurrentMonitor, joints: Vec<usize>) -> Arc<Mutex<Option<i32>>> {
+        let reading = Arc::new(Mutex::new(Some(0)));
+        let read = reading.clone();
+        let reader: Reader = Box::new(move || read.lock().unwrap().ok_or(SimError(0x107)));
+        monitor.add(Source::Ina219(0x40), joints, reader, SHUNT);
+        reading
+    }
+
+    #[test]
+    fn calibration_round_trips_and_needs_a_scale() {
+        let calibration = CurrentCalibration { zero_uv: -2500, ma_per_v: 5000 };
+        assert_eq!(CurrentCalibration::from_bytes(&calibration.to_bytes()), Some(calibration));
+        assert_eq!(CurrentCalibration::from_bytes(&calibration.to_bytes()[..CALIBRATION_SIZE - 1]), None);
+        let unscaled = CurrentCalibration { ma_per_v: 0, ..calibration };
+        assert_eq!(CurrentCalibration::from_bytes(&unscaled.to_bytes()), None);
+    }
+
+    #[test]
+    fn a_sensor_only_trips_once_over_the_limit_for_the_whole_window() {
+        let mut monitor = CurrentMonitor { limit_ma: 1500, window_ms: 500, ..CurrentMonitor::new() };
+        let reading = sensor(&mut monitor, vec![1, 2]);
+        *reading.lock().unwrap() = Some(200_000); // 2 A
+        let window_polls = 500 * POLL_HZ / 1000;
+        for _ in 0..window_polls {
+            assert_eq!(monitor.sample(POLL_HZ), Vec::<usize>::new());
+        }
+        assert_eq!(monitor.sample(POLL_HZ), [1, 2]);
+        assert_eq!(monitor.get_current_ma(0), Some(2000));
+        // Counted afresh after the trip
+        assert_eq!(monitor.sample(POLL_HZ), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn a_dip_under_the_limit_restarts_the_window() {
+        let mut monitor = CurrentMonitor { limit_ma: 1500, window_ms: 100, ..CurrentMonitor::new() };
+        let reading = sensor(&mut monitor, vec![0]);
+        *reading.lock().unwrap() = Some(200_000);
+        for _ in 0..10 {
+            assert!(monitor.sample(POLL_HZ).is_empty());
+        }
+        *reading.lock().unwrap() = Some(0);
+        while monitor.get_current_ma(0) > Some(1500) {
+            monitor.sample(POLL_HZ);
+        }
+        *reading.lock().unwrap() = Some(200_000);
+        for _ in 0..10 {
+            assert!(monitor.sample(POLL_HZ).is_empty(), "the time under the limit was counted");
+        }
+    }
+
+    #[test]
+    fn a_failed_read_leaves_the_last_average_standing() {
+        let mut monitor = CurrentMonitor::new();
+        let reading = sensor(&mut monitor, vec![0]);
+        assert_eq!(monitor.get_current_ma(0), None);
+        *reading.lock().unwrap() = Some(50_000);
+        monitor.sample(POLL_HZ);
+        *reading.lock().unwrap() = None;
+        monitor.sample(POLL_HZ);
+        assert_eq!(monitor.get_current_ma(0), Some(500));
+        assert_eq!(monitor.readings()[0].current_ma, Some(500));
+    }
+
+    #[test]
+    fn sources_are_parsed_per_joint_and_grouped_by_sensor() {
+        let (free, taken) = (boards::PROFILE.adc_pins[2], boards::PROFILE.adc_pins[3]);
+        let config = format!("ina219:0x40, adc:{},, adc:12,ina219:64, ina219:nope", free);
+        let sources = parse_sources(&config, 7, &[taken]);
+        let ina219 = Some(Source::Ina219(0x40));
+        assert_eq!(sources, [ina219, Some(Source::Adc(free)), None, None, ina219, None, None]);
+        assert_eq!(parse_sources(&format!("adc:{}", taken), 1, &[taken]), [None]);
+        assert_eq!(group(&sources), [(Source::Ina219(0x40), vec![0, 4]), (Source::Adc(free), vec![1])]);
+    }
+}
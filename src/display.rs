@@ -1,61 +1,523 @@
+use std::mem;
+use std::time::{Duration, Instant};
+
 use embedded_graphics::draw_target::DrawTarget;
-use embedded_graphics::geometry::Point;
+use embedded_graphics::geometry::{OriginDimensions, Point, Size};
 use embedded_graphics::mono_font::{MonoTextStyle, MonoTextStyleBuilder};
 use embedded_graphics::pixelcolor::BinaryColor;
-use embedded_graphics::text::Text;
-use esp_idf_hal::i2c::{I2cDriver};
-use log::{error};
+use embedded_graphics::primitives::{Line, Primitive, PrimitiveStyle, Rectangle};
+use embedded_graphics::text::{Baseline, Text};
+use crate::backend::{Align, DisplayBackend, ServoBar};
+use crate::qr::QrCode;
+use crate::shared_i2c::SharedI2c;
+use log::{error, info, warn};
 use ssd1306::mode::{BufferedGraphicsMode, DisplayConfig};
-use ssd1306::prelude::{DisplaySize128x64, I2CInterface};
+use ssd1306::prelude::{Brightness, DisplayRotation, DisplaySize128x32, DisplaySize128x64, I2CInterface};
 use ssd1306::{Ssd1306};
-use embedded_graphics::Drawable;
-use embedded_graphics::mono_font::ascii::FONT_6X10;
+use embedded_graphics::{Drawable, Pixel};
+use embedded_graphics::mono_font::ascii::{FONT_10X20, FONT_6X10};
+
+type Panel<S> = Ssd1306<I2CInterface<SharedI2c>, S, BufferedGraphicsMode<S>>;
+pub type DisplayError = <Panel<DisplaySize128x64> as DrawTarget>::Error;
+
+const WIDTH: i32 = 128; // Every supported panel, only the height changes the layouts
+const BAR_LEFT: i32 = 26; // Room for a four letter name in the small font
+const BAR_WIDTH: i32 = WIDTH - BAR_LEFT;
+const MAX_ROW_HEIGHT: i32 = 12;
+const TALL_HEIGHT: i32 = 64; // Panels this tall get the large banner font
+const MAX_QR_SIDE: i32 = 64; // Lit square on the left the code is drawn into, the caption goes to its right
+
+// The panel sizes supported, picked at start-up. The buffer's type depends on the size, so the two are wrapped here
+// rather than making Display generic over it
+#[allow(clippy::large_enum_variant)] // Only the one panel is ever made, boxing it would save nothing
+pub enum Oled {
+    Size128x64(Panel<DisplaySize128x64>),
+    Size128x32(Panel<DisplaySize128x32>),
+}
+
+impl Oled {
+    // Any height other than 32 is taken to be a 128x64 panel
+    pub fn new(interface: I2CInterface<SharedI2c>, height: u8) -> Oled {
+        match height {
+            32 => Oled::Size128x32(
+                Ssd1306::new(interface, DisplaySize128x32, DisplayRotation::Rotate0).into_buffered_graphics_mode(),
+            ),
+            height => {
+                if height != 64 {
+                    warn!("No {} pixel high display, using 128x64", height);
+                }
+                Oled::Size128x64(
+                    Ssd1306::new(interface, DisplaySize128x64, DisplayRotation::Rotate0).into_buffered_graphics_mode(),
+                )
+            }
+        }
+    }
+
+    fn init(&mut self) -> Result<(), DisplayError> {
+        match self {
+            Oled::Size128x64(panel) => panel.init(),
+            Oled::Size128x32(panel) => panel.init(),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), DisplayError> {
+        match self {
+            Oled::Size128x64(panel) => panel.flush(),
+            Oled::Size128x32(panel) => panel.flush(),
+        }
+    }
+
+    fn set_brightness(&mut self, brightness: Brightness) -> Result<(), DisplayError> {
+        match self {
+            Oled::Size128x64(panel) => panel.set_brightness(brightness),
+            Oled::Size128x32(panel) => panel.set_brightness(brightness),
+        }
+    }
+
+    fn set_display_on(&mut self, on: bool) -> Result<(), DisplayError> {
+        match self {
+            Oled::Size128x64(panel) => panel.set_display_on(on),
+            Oled::Size128x32(panel) => panel.set_display_on(on),
+        }
+    }
+
+    // Only 0 and 180 degrees, the layouts are all landscape
+    fn set_flipped(&mut self, flipped: bool) -> Result<(), DisplayError> {
+        let rotation = if flipped { DisplayRotation::Rotate180 } else { DisplayRotation::Rotate0 };
+        match self {
+            Oled::Size128x64(panel) => panel.set_rotation(rotation),
+            Oled::Size128x32(panel) => panel.set_rotation(rotation),
+        }
+    }
+}
+
+impl DrawTarget for Oled {
+    type Color = BinaryColor;
+    type Error = DisplayError;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), DisplayError>
+    where
+        I: IntoIterator<Item = Pixel<BinaryColor>>,
+    {
+        match self {
+            Oled::Size128x64(panel) => panel.draw_iter(pixels),
+            Oled::Size128x32(panel) => panel.draw_iter(pixels),
+        }
+    }
+
+    fn clear(&mut self, color: BinaryColor) -> Result<(), DisplayError> {
+        match self {
+            Oled::Size128x64(panel) => panel.clear(color),
+            Oled::Size128x32(panel) => panel.clear(color),
+        }
+    }
+}
+
+impl OriginDimensions for Oled {
+    fn size(&self) -> Size {
+        match self {
+            Oled::Size128x64(panel) => panel.size(),
+            Oled::Size128x32(panel) => panel.size(),
+        }
+    }
+}
+const MAX_FAILURES: u32 = 3; // Draws failing in a row before the panel is taken to be gone
+const REINIT_INTERVAL: Duration = Duration::from_secs(5); // How often a missing panel is looked for again
+const WARN_INTERVAL: Duration = Duration::from_secs(60); // Between reminders that draws are being skipped
+
+// What was last drawn, so a repeat is skipped and a change only redraws what differs
+#[derive(PartialEq)]
+enum Screen {
+    Blank,
+    Text { x: i32, y: i32, text: String },
+    Lines { lines: Vec<String>, align: Align },
+    Banner { banner: String, text: String },
+    ServoBars(Vec<ServoBar>),
+    Qr { code: QrCode, caption: Vec<String> },
+}
 
 pub struct Display<'a>{
-    display: Ssd1306<I2CInterface<I2cDriver<'static>>, DisplaySize128x64, BufferedGraphicsMode<DisplaySize128x64>>,
+    display: Oled,
     text_style: MonoTextStyle<'a, BinaryColor>,
+    shown: Screen,
+    present: bool,              // Cleared when init fails or draws keep failing, drawing is skipped until a reinit works
+    failures: u32,              // Draws failed in a row
+    reinit_at: Instant,         // Next time a missing panel is initialised again
+    warned_at: Option<Instant>, // Last reminder that draws are being skipped
+    skipped: u32,               // Draws skipped since then
+    brightness: u8,             // Contrast, power and rotation as last set, sent again after a reinit
+    on: bool,
+    flipped: bool,
 }
 
 impl<'a> Display<'a>{
-    pub fn new(display: Ssd1306<I2CInterface<I2cDriver<'static>>, DisplaySize128x64, BufferedGraphicsMode<DisplaySize128x64>>) -> Display<'a> {
+    pub fn new(display: Oled) -> Display<'a> {
     Display{
             display,
             text_style: MonoTextStyleBuilder::new()
                 .font(&FONT_6X10)
                 .text_color(BinaryColor::On)
                 .build(),
+            shown: Screen::Blank,
+            present: false,
+            failures: 0,
+            reinit_at: Instant::now(),
+            warned_at: None,
+            skipped: 0,
+            brightness: 0x5F, // The driver's default
+            on: true,
+            flipped: false,
         }
     }
 
+    // Lines are a different height in the new style, so the next text is drawn in full
     pub fn set_text_style(&mut self, text_style: MonoTextStyle<'a, BinaryColor>) {
         self.text_style = text_style;
+        self.shown = Screen::Blank;
     }
 
-    pub fn draw_new_text(&mut self, x: i32, y: i32, text: &String){
-        match self.display.clear(BinaryColor::Off) {
-            Ok(_) => {},
-            Err(e) => {
-                error!("Error clearing display: {:?}", e);
+    // Without a panel the error is returned once and later draws are skipped, a panel plugged in later is picked up
+    // by the reinit the draws attempt
+    pub fn init(&mut self) -> Result<(), DisplayError> {
+        self.reinit_at = Instant::now() + REINIT_INTERVAL;
+        self.display.init()?;
+        self.display.set_brightness(contrast(self.brightness))?;
+        self.display.set_display_on(self.on)?;
+        self.display.set_flipped(self.flipped)?;
+        self.present = true;
+        self.failures = 0;
+        self.shown = Screen::Blank; // Whatever the panel shows after a power cycle, it isn't what was last drawn
+        Ok(())
+    }
+
+    // Text with its first baseline at y, as embedded-graphics lays it out
+    pub fn draw_new_text(&mut self, x: i32, y: i32, text: &str) -> Result<(), DisplayError> {
+        self.draw(Screen::Text { x, y, text: text.to_string() })
+    }
+
+    // One line per row from the top, spaced by the current font's height
+    pub fn draw_lines(&mut self, lines: &[&str], align: Align) -> Result<(), DisplayError> {
+        self.draw(Screen::Lines { lines: lines.iter().map(|line| line.to_string()).collect(), align })
+    }
+
+    // Draws a large banner line with smaller text underneath, used for alarms like the e-stop
+    pub fn draw_banner(&mut self, banner: &str, text: &str) -> Result<(), DisplayError> {
+        self.draw(Screen::Banner { banner: banner.to_string(), text: text.to_string() })
+    }
+
+    // One row per servo. Over the last bars only the rows that changed are redrawn, and the flush only sends
+    // what was drawn, so a moving arm doesn't flicker the whole screen
+    pub fn draw_servo_bars(&mut self, bars: &[ServoBar]) -> Result<(), DisplayError> {
+        self.draw(Screen::ServoBars(bars.to_vec()))
+    }
+
+    // The code on a lit square, the panel lights the light modules so a phone reads it as printed, with the caption
+    // beside it
+    pub fn draw_qr(&mut self, code: &QrCode, caption: &[&str]) -> Result<(), DisplayError> {
+        self.draw(Screen::Qr { code: code.clone(), caption: caption.iter().map(|line| line.to_string()).collect() })
+    }
+
+    // Kept while the panel is missing, it is set when the panel is found
+    pub fn set_brightness(&mut self, brightness: u8) -> Result<(), DisplayError> {
+        self.brightness = brightness;
+        match self.present {
+            true => self.display.set_brightness(contrast(brightness)),
+            false => Ok(()),
+        }
+    }
+
+    // Draws still go to the panel's memory while it is off and show once it is on again
+    pub fn set_display_on(&mut self, on: bool) -> Result<(), DisplayError> {
+        self.on = on;
+        match self.present {
+            true => self.display.set_display_on(on),
+            false => Ok(()),
+        }
+    }
+
+    // Turned 180 degrees for a panel mounted upside down. The panel maps its memory the other way round, so what is
+    // shown turns with it without a redraw
+    pub fn set_flipped(&mut self, flipped: bool) -> Result<(), DisplayError> {
+        self.flipped = flipped;
+        match self.present {
+            true => self.display.set_flipped(flipped),
+            false => Ok(()),
+        }
+    }
+
+    // Lines of the current font that fit down the panel
+    pub fn rows(&self) -> usize {
+        (self.height() / self.text_style.font.character_size.height as i32).max(1) as usize
+    }
+
+    fn height(&self) -> i32 {
+        self.display.size().height as i32
+    }
+
+    // Skips the draw while the panel is missing, and takes it to be missing once draws keep failing
+    fn draw(&mut self, screen: Screen) -> Result<(), DisplayError> {
+        if !self.present && !self.reinit() {
+            self.skipped = self.skipped.saturating_add(1);
+            if !self.warned_at.is_some_and(|warned_at| warned_at.elapsed() < WARN_INTERVAL) {
+                warn!("No display, {} draws skipped", self.skipped);
+                self.warned_at = Some(Instant::now());
+                self.skipped = 0;
             }
-        };
-        match Text::new(text.as_str(), Point::new(x, y), self.text_style)
-            .draw(&mut self.display) {
+            return Ok(());
+        }
+        let result = self.render(screen).and_then(|_| self.display.flush());
+        match result {
+            Ok(_) => self.failures = 0,
+            Err(_) => {
+                self.shown = Screen::Blank; // Unknown what made it to the panel
+                self.failures += 1;
+                if self.failures >= MAX_FAILURES {
+                    warn!("Display stopped responding, drawing paused");
+                    self.present = false;
+                    self.reinit_at = Instant::now() + REINIT_INTERVAL;
+                }
+            }
+        }
+        result
+    }
+
+    // Returns true once the panel answers again, only tried every REINIT_INTERVAL
+    fn reinit(&mut self) -> bool {
+        if Instant::now() < self.reinit_at {
+            return false;
+        }
+        match self.init() {
+            Ok(_) => {
+                info!("Display found, drawing resumed");
+                self.warned_at = None;
+                self.skipped = 0;
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    // Draws into the buffer over what is shown, leaving the flush to the caller
+    fn render(&mut self, screen: Screen) -> Result<(), DisplayError> {
+        let shown = mem::replace(&mut self.shown, Screen::Blank);
+        match (shown, &screen) {
+            (shown, screen) if shown == *screen => {},
+            // Over text at the same place only the lines that changed are redrawn
+            (Screen::Text { x: shown_x, y: shown_y, text: shown }, Screen::Text { x, y, text })
+                if shown_x == *x && shown_y == *y =>
+            {
+                let corner = Point::new(*x, *y - self.text_style.font.baseline as i32);
+                let shown: Vec<&str> = shown.split('\n').collect();
+                self.draw_rows(corner, Align::Left, &shown, &text.split('\n').collect::<Vec<&str>>())?
+            }
+            (_, Screen::Text { x, y, text }) => {
+                self.display.clear(BinaryColor::Off)?;
+                Text::new(text, Point::new(*x, *y), self.text_style).draw(&mut self.display)?;
+            }
+            (Screen::Lines { lines: shown, align: shown_align }, Screen::Lines { lines, align })
+                if shown_align == *align =>
+            {
+                let shown: Vec<&str> = shown.iter().map(String::as_str).collect();
+                self.draw_rows(Point::zero(), *align, &shown, &lines.iter().map(String::as_str).collect::<Vec<&str>>())?
+            }
+            (_, Screen::Lines { lines, align }) => {
+                self.display.clear(BinaryColor::Off)?;
+                self.draw_rows(Point::zero(), *align, &[], &lines.iter().map(String::as_str).collect::<Vec<&str>>())?
+            }
+            (_, Screen::Banner { banner, text }) => {
+                let banner_style = MonoTextStyleBuilder::new()
+                    .font(&FONT_10X20)
+                    .text_color(BinaryColor::On)
+                    .build();
+                self.display.clear(BinaryColor::Off)?;
+                if self.height() >= TALL_HEIGHT {
+                    Text::new(banner, Point::new(0, 16), banner_style).draw(&mut self.display)?;
+                    Text::new(text, Point::new(0, 32), self.text_style).draw(&mut self.display)?;
+                } else {
+                    // No room for the large font, the banner takes the first row
+                    let line_height = self.text_style.font.character_size.height as i32;
+                    Text::with_baseline(banner, Point::zero(), self.text_style, Baseline::Top).draw(&mut self.display)?;
+                    Text::with_baseline(text, Point::new(0, line_height), self.text_style, Baseline::Top)
+                        .draw(&mut self.display)?;
+                }
+            }
+            // Only the same servos in the same rows can be drawn over
+            (Screen::ServoBars(shown), Screen::ServoBars(bars))
+                if shown.len() == bars.len() && shown.iter().zip(bars).all(|(shown, bar)| shown.name == bar.name) =>
+            {
+                let row_height = bar_row_height(bars, self.height());
+                for (row, (shown, bar)) in shown.iter().zip(bars).enumerate() {
+                    if shown != bar {
+                        self.draw_bar(bar, row as i32 * row_height, row_height, true)?;
+                    }
+                }
+            }
+            (_, Screen::ServoBars(bars)) => {
+                self.display.clear(BinaryColor::Off)?;
+                let row_height = bar_row_height(bars, self.height());
+                for (row, bar) in bars.iter().enumerate() {
+                    self.draw_bar(bar, row as i32 * row_height, row_height, false)?;
+                }
+            }
+            (_, Screen::Qr { code, caption }) => {
+                self.display.clear(BinaryColor::Off)?;
+                let side = self.height().min(MAX_QR_SIDE);
+                let square = Rectangle::new(Point::zero(), Size::new(side as u32, side as u32));
+                self.display.fill_solid(&square, BinaryColor::On)?;
+                // Whole pixels per module, leaving at least a module of quiet zone on each side
+                let size = code.size() as i32;
+                let scale = (side / (size + 2)).max(1);
+                let offset = (side - size * scale) / 2;
+                for y in 0..size {
+                    for x in 0..size {
+                        if code.is_dark(x as usize, y as usize) {
+                            let module = Rectangle::new(
+                                Point::new(offset + x * scale, offset + y * scale),
+                                Size::new(scale as u32, scale as u32),
+                            );
+                            self.display.fill_solid(&module, BinaryColor::Off)?;
+                        }
+                    }
+                }
+                let caption: Vec<&str> = caption.iter().map(String::as_str).collect();
+                self.draw_rows(Point::new(side + 2, 0), Align::Left, &[], &caption)?
+            }
+            (_, Screen::Blank) => self.display.clear(BinaryColor::Off)?,
+        }
+        self.shown = screen;
+        Ok(())
+    }
+
+    // Draws lines from the top left corner, blanking and redrawing only the rows that differ from `shown`, including
+    // rows the new lines no longer reach
+    fn draw_rows(&mut self, corner: Point, align: Align, shown: &[&str], lines: &[&str]) -> Result<(), DisplayError> {
+        let font = self.text_style.font;
+        let line_height = font.character_size.height as i32;
+        let advance = (font.character_size.width + font.character_spacing) as i32;
+        for row in 0..shown.len().max(lines.len()) {
+            let line = lines.get(row);
+            if shown.get(row) == line {
+                continue;
+            }
+            let top = corner.y + row as i32 * line_height;
+            Rectangle::new(Point::new(corner.x, top), Size::new((WIDTH - corner.x).max(0) as u32, line_height as u32))
+                .into_styled(PrimitiveStyle::with_fill(BinaryColor::Off))
+                .draw(&mut self.display)?;
+            if let Some(line) = line {
+                let x = match align {
+                    Align::Left => corner.x,
+                    Align::Center => corner.x + ((WIDTH - corner.x - line.chars().count() as i32 * advance) / 2).max(0),
+                };
+                Text::with_baseline(line, Point::new(x, top), self.text_style, Baseline::Top).draw(&mut self.display)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Name, then an outlined bar filled up to the angle. The goal tick sticks out above and below the bar, and is
+    // cut out of the fill where the bar has passed it
+    fn draw_bar(&mut self, bar: &ServoBar, top: i32, height: i32, blank: bool)
+        -> Result<(), DisplayError> {
+        if blank {
+            Rectangle::new(Point::new(0, top), Size::new(WIDTH as u32, height as u32))
+                .into_styled(PrimitiveStyle::with_fill(BinaryColor::Off))
+                .draw(&mut self.display)?;
+        }
+        let name: String = bar.name.chars().take(4).collect();
+        Text::with_baseline(&name, Point::new(0, top + (height - 8) / 2), self.text_style, Baseline::Top)
+            .draw(&mut self.display)?;
+        Rectangle::new(Point::new(BAR_LEFT, top + 2), Size::new(BAR_WIDTH as u32, (height - 4).max(3) as u32))
+            .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
+            .draw(&mut self.display)?;
+        let inner = BAR_WIDTH - 2;
+        let max = bar.max.max(1) as i32;
+        let fill = inner * (bar.angle as i32).min(max) / max;
+        if fill > 0 {
+            Rectangle::new(Point::new(BAR_LEFT + 1, top + 3), Size::new(fill as u32, (height - 6).max(1) as u32))
+                .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+                .draw(&mut self.display)?;
+        }
+        let goal_x = BAR_LEFT + 1 + inner * (bar.goal as i32).min(max) / max;
+        Line::new(Point::new(goal_x, top), Point::new(goal_x, top + height - 2))
+            .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
+            .draw(&mut self.display)?;
+        if goal_x < BAR_LEFT + 1 + fill {
+            Line::new(Point::new(goal_x, top + 3), Point::new(goal_x, top + height - 4))
+                .into_styled(PrimitiveStyle::with_stroke(BinaryColor::Off, 1))
+                .draw(&mut self.display)?;
+        }
+        Ok(())
+    }
+}
+
+// The driver's presets use the shortest precharge only for the dimmest setting
+fn contrast(brightness: u8) -> Brightness {
+    Brightness::custom(if brightness == 0 { 1 } else { 2 }, brightness)
+}
+
+fn bar_row_height(bars: &[ServoBar], height: i32) -> i32 {
+    (height / bars.len().max(1) as i32).min(MAX_ROW_HEIGHT)
+}
+
+// The control loop has no use for a failed draw, it is logged here and the display stops being drawn to after a few
+impl DisplayBackend for Display<'_> {
+    fn draw_new_text(&mut self, x: i32, y: i32, text: &str) {
+        match Display::draw_new_text(self, x, y, text) {
             Ok(_) => {},
             Err(e) => error!("Error drawing text: {:?}", e),
-        };
-        match self.display.flush(){
+        }
+    }
+
+    fn draw_lines(&mut self, lines: &[&str], align: Align) {
+        match Display::draw_lines(self, lines, align) {
+            Ok(_) => {},
+            Err(e) => error!("Error drawing lines: {:?}", e),
+        }
+    }
+
+    fn draw_banner(&mut self, banner: &str, text: &str) {
+        match Display::draw_banner(self, banner, text) {
             Ok(_) => {},
-            Err(e) => error!("Error flushing display: {:?}", e),
-        };
+            Err(e) => error!("Error drawing banner: {:?}", e),
+        }
     }
 
-    pub fn init(&mut self){
-        match self.display.init() {
+    fn draw_servo_bars(&mut self, bars: &[ServoBar]) {
+        match Display::draw_servo_bars(self, bars) {
             Ok(_) => {},
-            Err(e) => {
-                error!("Error initializing display: {:?}", e);
-            }
+            Err(e) => error!("Error drawing servo bars: {:?}", e),
+        }
+    }
+
+    fn draw_qr(&mut self, code: &QrCode, caption: &[&str]) {
+        match Display::draw_qr(self, code, caption) {
+            Ok(_) => {},
+            Err(e) => error!("Error drawing QR code: {:?}", e),
+        }
+    }
+
+    fn set_brightness(&mut self, brightness: u8) {
+        match Display::set_brightness(self, brightness) {
+            Ok(_) => {},
+            Err(e) => error!("Error setting the display brightness: {:?}", e),
+        }
+    }
+
+    fn set_display_on(&mut self, on: bool) {
+        match Display::set_display_on(self, on) {
+            Ok(_) => {},
+            Err(e) => error!("Error turning the display {}: {:?}", if on { "on" } else { "off" }, e),
+        }
+    }
+
+    fn set_flipped(&mut self, flipped: bool) {
+        match Display::set_flipped(self, flipped) {
+            Ok(_) => {},
+            Err(e) => error!("Error rotating the display: {:?}", e),
         }
     }
-}
 
+    fn rows(&self) -> usize {
+        Display::rows(self)
+    }
+}
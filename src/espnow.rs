@@ -0,0 +1,197 @@
+// ESP-NOW peer link so two limbs can follow each other with no router. The leader broadcasts its goals as move frames,
+// the follower queues each one like an HTTP command. Both share the station's channel, see hardware.rs
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use esp_idf_svc::espnow::{EspNow, PeerInfo, BROADCAST};
+use log::{error, info, warn};
+
+use crate::auth::{self, Authenticator};
+use crate::network::{self, Command, ReplyTo, NO_ADDR};
+use crate::protocol::{self, ControlPacket, ReplyPacket, ReplyPayload};
+use crate::sequence;
+use crate::servo;
+use crate::tasks;
+use crate::CONFIG;
+
+const MAX_RATE_HZ: u16 = 100; // Poses sent by the leader per second at most, each one goes through the control task
+const FRAME_QUEUE_SIZE: usize = 4; // Frames the receive callback holds for the follower task, newer ones are dropped
+
+pub type Mac = [u8; 6];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Leader,
+    Follower,
+}
+
+// None leaves ESP-NOW off
+pub fn parse_role(config: &str) -> Option<Role> {
+    match config {
+        "" => None,
+        "leader" => Some(Role::Leader),
+        "follower" => Some(Role::Follower),
+        role => {
+            warn!("Unknown ESP-NOW role \"{}\", ESP-NOW is off", role);
+            None
+        }
+    }
+}
+
+// Starts the role's task, which owns the link from then on. The limb carries on without its peer if ESP-NOW can't be
+// started. The authenticator is the link's own, so the leader's nonces don't race a UDP client's
+pub fn start(role: Role, commands: SyncSender<Command>, authenticator: Option<Authenticator>) {
+    let espnow = match EspNow::take() {
+        Ok(espnow) => espnow,
+        Err(e) => {
+            error!("Failed to start ESP-NOW, running without the peer link: {}", e);
+            return;
+        }
+    };
+    match role {
+        Role::Leader => {
+            let peer = PeerInfo { peer_addr: BROADCAST, ..Default::default() };
+            match espnow.add_peer(peer) {
+                Ok(_) => {},
+                Err(e) => {
+                    error!("Failed to add the ESP-NOW broadcast peer, running without the peer link: {}", e);
+                    return;
+                }
+            }
+            info!("ESP-NOW leader broadcasting the pose at {} Hz", CONFIG.espnow_rate_hz.clamp(1, MAX_RATE_HZ));
+            tasks::spawn(&tasks::ESPNOW_TASK, move || leader_task(espnow, commands, authenticator));
+        }
+        Role::Follower => {
+            // The callback runs on the WiFi task, it only hands the frame over
+            let (sender, frames) = mpsc::sync_channel(FRAME_QUEUE_SIZE);
+            let registered = espnow.register_recv_cb(move |mac: &[u8], data: &[u8]| {
+                if let Ok(mac) = Mac::try_from(mac) {
+                    let _ = sender.try_send((mac, data.to_vec()));
+                }
+            });
+            match registered {
+                Ok(_) => {},
+                Err(e) => {
+                    error!("Failed to listen on ESP-NOW, running without the peer link: {}", e);
+                    return;
+                }
+            }
+            info!("ESP-NOW follower waiting for a leader");
+            let authenticator = authenticator.map(Mutex::new);
+            tasks::spawn(&tasks::ESPNOW_TASK, move || follower_task(espnow, frames, commands, authenticator));
+        }
+    }
+}
+
+// The pose comes from the control task like any status request, so it is the goals the servos are heading to
+fn leader_task(espnow: EspNow<'static>, commands: SyncSender<Command>, authenticator: Option<Authenticator>) {
+    let period = Duration::from_millis(1000 / CONFIG.espnow_rate_hz.clamp(1, MAX_RATE_HZ) as u64);
+    let mut sequence: u16 = 0;
+    let mut nonce: u32 = 0;
+    let mut failing = false; // Whether the last send failed, only the first failure is logged
+    loop {
+        thread::sleep(period);
+        let command = match network::request(&commands, NO_ADDR, ControlPacket::Status) {
+            Ok(ReplyPacket { payload: ReplyPayload::Status { flags, ref joints, .. }, .. }) => {
+                if flags & protocol::TELEMETRY_ESTOP_FLAG != 0 {
+                    vec![protocol::ESTOP_COMMAND]
+                } else {
+                    let mut command = vec![protocol::MOVE_COMMAND, joints.len() as u8];
+                    protocol::encode_angles(joints.iter().map(|joint| servo::to_degrees(joint.goal)), &mut command);
+                    command
+                }
+            }
+            Ok(_) => continue,
+            Err(e) => {
+                warn!("No pose to send the follower: {:?}", e);
+                continue;
+            }
+        };
+
+        sequence = sequence.wrapping_add(1);
+        let mut frame = sequence.to_be_bytes().to_vec();
+        frame.extend_from_slice(&command);
+        if let Some(authenticator) = authenticator.as_ref().filter(|_| auth::is_protected(command[0])) {
+            nonce = nonce.wrapping_add(1);
+            authenticator.sign(&mut frame, nonce);
+        }
+        if CONFIG.packet_crc {
+            protocol::append_crc(&mut frame);
+        }
+        match espnow.send(BROADCAST, &frame) {
+            Ok(_) => failing = false,
+            Err(e) if !failing => {
+                error!("Failed to send the pose over ESP-NOW: {}", e);
+                failing = true;
+            }
+            Err(_) => {},
+        }
+    }
+}
+
+// Follows the first leader heard until it goes quiet, other peers are ignored meanwhile. A leader that comes back
+// has restarted its sequence and nonces, so both are forgotten when it is lost
+fn follower_task(
+    espnow: EspNow<'static>,
+    frames: Receiver<(Mac, Vec<u8>)>,
+    commands: SyncSender<Command>,
+    authenticator: Option<Mutex<Authenticator>>,
+) {
+    let _espnow = espnow; // Dropping it would stop the callback
+    let timeout = Duration::from_millis(CONFIG.espnow_timeout_ms.max(1) as u64);
+    let mut leader: Option<Mac> = None;
+    let mut last_sequence: Option<u16> = None;
+    loop {
+        let (mac, frame) = match frames.recv_timeout(timeout) {
+            Ok(received) => received,
+            Err(RecvTimeoutError::Timeout) => {
+                if let Some(lost) = leader.take() {
+                    warn!("ESP-NOW leader {} lost, the failsafe takes over once it times out", format_mac(&lost));
+                    last_sequence = None;
+                    if let Some(authenticator) = authenticator.as_ref() {
+                        lock(authenticator).forget_nonce();
+                    }
+                }
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => return, // Only once the callback is gone
+        };
+        if leader.is_some_and(|leader| leader != mac) {
+            continue;
+        }
+        let (sequence, control) = match network::decode_frame(&frame, authenticator.as_ref()) {
+            Ok(decoded) => decoded,
+            Err(refused) => {
+                warn!("Refused ESP-NOW frame from {}: {:?}", format_mac(&mac), refused.error);
+                continue;
+            }
+        };
+        // Only the leader's own frames are taken, whatever else a peer might broadcast
+        if !matches!(control, ControlPacket::SetAngles(_) | ControlPacket::EStop) {
+            continue;
+        }
+        if last_sequence.is_some_and(|last| !sequence::is_newer(sequence, last)) {
+            continue;
+        }
+        last_sequence = Some(sequence);
+        if leader.is_none() {
+            info!("Following ESP-NOW leader {}", format_mac(&mac));
+            leader = Some(mac);
+        }
+        match commands.try_send(Command { addr: NO_ADDR, sequence, packet: control, reply_to: ReplyTo::Discard }) {
+            Ok(_) => {},
+            Err(TrySendError::Full(_)) => {}, // The next pose follows shortly
+            Err(TrySendError::Disconnected(_)) => panic!("Control task stopped"),
+        }
+    }
+}
+
+fn format_mac(mac: &Mac) -> String {
+    mac.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(":")
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
@@ -0,0 +1,183 @@
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+
+use crate::servo::Servo;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+const PARK_SPEED: u16 = 20; // Degrees per second used when moving to the safe pose
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FailsafeAction {
+    Park, // Move every servo to the safe pose at low speed
+    Stop, // De-energize every servo
+}
+
+impl FailsafeAction {
+    pub fn from_byte(byte: u8) -> Option<FailsafeAction> {
+        match byte {
+            0 => Some(FailsafeAction::Park),
+            1 => Some(FailsafeAction::Stop),
+            _ => None,
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            FailsafeAction::Park => 0,
+            FailsafeAction::Stop => 1,
+        }
+    }
+}
+
+// Tracks when the last valid packet arrived and parks the servos once packets stop arriving
+pub struct Failsafe {
+    timeout: Duration, // A zero timeout disables the failsafe
+    action: FailsafeAction,
+    safe_pose: Vec<u16>,
+    last_packet: Option<Instant>, // None until the first packet arrives, so the failsafe only arms once a client connects
+    triggered: bool,
+}
+
+impl Failsafe {
+    pub fn new(safe_pose: Vec<u16>) -> Failsafe {
+        Failsafe {
+            timeout: DEFAULT_TIMEOUT,
+            action: FailsafeAction::Stop,
+            safe_pose,
+            last_packet: None,
+            triggered: false,
+        }
+    }
+
+    // Records a valid packet, returns true if this clears a triggered failsafe
+    pub fn packet_received(&mut self, now: Instant) -> bool {
+        self.last_packet = Some(now);
+        let was_triggered = self.triggered;
+        self.triggered = false;
+        was_triggered
+    }
+
+    // Returns true when the timeout has just elapsed, so the failsafe is only applied once
+    pub fn check(&mut self, now: Instant) -> bool {
+        if self.triggered || self.timeout.is_zero() {
+            return false;
+        }
+        match self.last_packet {
+            Some(last_packet) if now.duration_since(last_packet) >= self.timeout => {
+                self.triggered = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Triggers the failsafe without waiting for the timeout, even when it is disabled. Returns true if it wasn't
+    // already triggered, it clears on the next packet as usual
+    pub fn trigger(&mut self) -> bool {
+        let was_triggered = self.triggered;
+        self.triggered = true;
+        !was_triggered
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.triggered
+    }
+
+    // Parks or stops the servos according to the configured action
+    pub fn apply(&self, servos: &mut [Servo]) {
+        warn!("Failsafe triggered, no packets for {} ms", self.timeout.as_millis());
+        match self.action {
+            FailsafeAction::Park => self.park(servos),
+            FailsafeAction::Stop => {
+                for servo in servos.iter_mut() {
+                    match servo.stop() {
+                        Ok(_) => {},
+                        Err(e) => error!("Failed to stop {}: {}", servo.get_name(), e),
+                    }
+                }
+            }
+        }
+    }
+
+    // Sends every servo to its safe angle at the parking speed. Stopped servos and ones never driven are left off,
+    // parking would energize them again
+    pub fn park(&self, servos: &mut [Servo]) {
+        for (servo, &angle) in servos.iter_mut().zip(self.safe_pose.iter()) {
+            if !servo.is_enabled() {
+                continue;
+            }
+            servo.set_speed_override(Some(PARK_SPEED));
+            servo.set_angle_logged(angle);
+        }
+    }
+
+    // Undoes the parking speed once packets resume, stopped servos are re-enabled by their next command
+    pub fn release(&self, servos: &mut [Servo]) {
+        info!("Packets resumed, failsafe cleared");
+        for servo in servos.iter_mut() {
+            servo.set_speed_override(None);
+        }
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn get_timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn set_action(&mut self, action: FailsafeAction) {
+        self.action = action;
+    }
+
+    pub fn get_action(&self) -> FailsafeAction {
+        self.action
+    }
+
+    pub fn set_safe_angle(&mut self, index: usize, angle: u16) {
+        if let Some(safe_angle) = self.safe_pose.get_mut(index) {
+            *safe_angle = angle;
+        }
+    }
+
+    pub fn get_safe_angle(&self, index: usize) -> Option<u16> {
+        self.safe_pose.get(index).copied()
+    }
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+    use crate::sim;
+
+    #[test]
+    fn failsafe_triggers_once_after_the_timeout() {
+        let mut failsafe = Failsafe::new(vec![90; 5]);
+        let start = Instant::now();
+        assert!(!failsafe.check(start + DEFAULT_TIMEOUT), "armed before the first packet");
+        failsafe.packet_received(start);
+        assert!(!failsafe.check(start + DEFAULT_TIMEOUT - Duration::from_millis(1)));
+        assert!(failsafe.check(start + DEFAULT_TIMEOUT));
+        assert!(!failsafe.check(start + DEFAULT_TIMEOUT * 2));
+        assert!(failsafe.packet_received(start + DEFAULT_TIMEOUT * 2));
+    }
+
+    #[test]
+    fn park_leaves_stopped_servos_off() {
+        let (mut servos, _) = sim::mock_servos();
+        let failsafe = Failsafe::new(vec![45; servos.len()]);
+        servos[0].set_angle(90).unwrap();
+        servos[2].set_angle(90).unwrap();
+        servos[2].poll(false).unwrap();
+        servos[2].stop().unwrap();
+        failsafe.park(&mut servos);
+        assert_eq!(servos[0].get_goal(), 45);
+        assert!(servos[0].is_enabled());
+        // Never driven, then stopped by an e-stop
+        assert!(!servos[1].is_enabled());
+        assert!(!servos[2].is_enabled());
+        assert_eq!(servos[2].get_goal(), servos[2].get_angle());
+    }
+}
@@ -0,0 +1,248 @@
+// Measured joint angles from servos with their potentiometer wiper on an ADC pin, averaged every poll and flagged as
+// stalled while they stay off the commanded angle for longer than the stall time
+use log::{error, info, warn};
+
+use crate::backend::DriverError;
+use crate::boards;
+use crate::servo;
+use crate::settings::Settings;
+use crate::CONFIG;
+
+pub const CALIBRATION_SIZE: usize = 5;
+const CALIBRATION_VERSION: u8 = 1; // Bump when the stored feedback calibration layout changes
+const FILTER_WEIGHT: f32 = 0.25; // Of each new reading in the running average, enough to smooth out ADC noise
+const FULL_SCALE_MV: u16 = 3100; // Top of the ADC's range at 11 dB attenuation
+
+// Reads the wiper in millivolts
+pub type Reader = Box<dyn FnMut() -> Result<u16, DriverError> + Send>;
+
+// Pin readings at either end of the joint's travel, set from the calibration command and kept in NVS. Either can be
+// the higher, for a pot turning the other way to the servo
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FeedbackCalibration {
+    pub min_mv: u16, // At 0 degrees
+    pub max_mv: u16, // At the joint's max angle
+}
+
+impl FeedbackCalibration {
+    // Until the joint is calibrated the whole ADC range maps onto its travel
+    pub const DEFAULT: FeedbackCalibration = FeedbackCalibration { min_mv: 0, max_mv: FULL_SCALE_MV };
+
+    // Layout: version, reading at 0 degrees, reading at the max angle, all little-endian
+    pub fn to_bytes(self) -> [u8; CALIBRATION_SIZE] {
+        let mut bytes = [0u8; CALIBRATION_SIZE];
+        bytes[0] = CALIBRATION_VERSION;
+        bytes[1..3].copy_from_slice(&self.min_mv.to_le_bytes());
+        bytes[3..5].copy_from_slice(&self.max_mv.to_le_bytes());
+        bytes
+    }
+
+    // Returns None for blobs of the wrong size or version
+    pub fn from_bytes(bytes: &[u8]) -> Option<FeedbackCalibration> {
+        if bytes.len() != CALIBRATION_SIZE || bytes[0] != CALIBRATION_VERSION {
+            return None;
+        }
+        Some(FeedbackCalibration {
+            min_mv: u16::from_le_bytes([bytes[1], bytes[2]]),
+            max_mv: u16::from_le_bytes([bytes[3], bytes[4]]),
+        })
+    }
+}
+
+pub struct Feedback {
+    read: Reader,
+    calibration: FeedbackCalibration,
+    average_mv: Option<f32>, // None until the first reading
+    failing: bool,           // Whether the last read failed, only the first failure is logged
+    tolerance: u16,          // Tenths of a degree
+    stall_ms: u16,           // 0 never flags a stall
+    off_polls: u32,          // Polls in a row the measured angle has been off the driven one
+    stalled: bool,
+}
+
+impl Feedback {
+    pub fn new(read: Reader, calibration: FeedbackCalibration) -> Feedback {
+        Feedback {
+            read,
+            calibration,
+            average_mv: None,
+            failing: false,
+            tolerance: servo::from_degrees(CONFIG.feedback_tolerance_deg),
+            stall_ms: CONFIG.feedback_stall_ms,
+            off_polls: 0,
+            stalled: false,
+        }
+    }
+
+    // Takes a reading into the average, a failed one leaves the last average standing
+    pub fn sample(&mut self, name: &str) {
+        match (self.read)() {
+            Ok(reading_mv) => {
+                self.failing = false;
+                let reading_mv = reading_mv as f32;
+                self.average_mv = Some(match self.average_mv {
+                    Some(average_mv) => average_mv + (reading_mv - average_mv) * FILTER_WEIGHT,
+                    None => reading_mv,
+                });
+            }
+            Err(e) if !self.failing => {
+                error!("Failed to read {} feedback: {}", name, e);
+                self.failing = true;
+            }
+            Err(_) => {},
+        }
+    }
+
+    // Counts the polls spent off `driven`, the angle the servo is being driven to, or None while it isn't driven and
+    // so free to be moved by hand. Returns the new stalled state when it changes
+    pub fn track(&mut self, driven: Option<u16>, max_angle: u16, poll_hz: u32) -> Option<bool> {
+        let off = match (driven, self.get_angle(max_angle)) {
+            (Some(driven), Some(measured)) => driven.abs_diff(measured) > self.tolerance,
+            _ => false,
+        };
+        self.off_polls = if off { self.off_polls.saturating_add(1) } else { 0 };
+        let stalled = self.stall_ms != 0 && self.off_polls > self.stall_ms as u32 * poll_hz / 1000;
+        if stalled == self.stalled {
+            return None;
+        }
+        self.stalled = stalled;
+        Some(stalled)
+    }
+
+    // The averaged reading, None until the first
+    pub fn get_reading(&self) -> Option<u16> {
+        self.average_mv.map(|average_mv| average_mv.round() as u16)
+    }
+
+    // The averaged reading as an angle, clamped to the joint's travel
+    pub fn get_angle(&self, max_angle: u16) -> Option<u16> {
+        let (min_mv, max_mv) = (self.calibration.min_mv as f32, self.calibration.max_mv as f32);
+        if min_mv == max_mv {
+            return None;
+        }
+        self.average_mv
+            .map(|average_mv| ((average_mv - min_mv) / (max_mv - min_mv) * max_angle as f32).round())
+            .map(|angle| angle.clamp(0.0, max_angle as f32) as u16)
+    }
+
+    pub fn get_calibration(&self) -> FeedbackCalibration {
+        self.calibration
+    }
+
+    pub fn set_calibration(&mut self, calibration: FeedbackCalibration) {
+        self.calibration = calibration;
+    }
+
+    pub fn is_stalled(&self) -> bool {
+        self.stalled
+    }
+}
+
+// One optional ADC1 pin per joint from the comma separated config. Empty and missing entries have no feedback, nor
+// do pins that aren't on ADC1 or are already taken by the battery or another joint
+pub fn parse_pins(config: &str, joints: usize) -> Vec<Option<u8>> {
+    let mut entries = config.split(',').map(str::trim);
+    let mut pins = Vec::with_capacity(joints);
+    for joint in 0..joints {
+        let pin = match entries.next() {
+            Some("") | None => None,
+            Some(entry) => match entry.parse::<u8>() {
+                Ok(pin) if boards::is_adc_pin(pin) && pin != boards::battery_pin() && !pins.contains(&Some(pin)) => {
+                    Some(pin)
+                }
+                _ => {
+                    warn!("Feedback pin \"{}\" for joint {} isn't a free ADC1 pin, it has no feedback", entry, joint);
+                    None
+                }
+            },
+        };
+        pins.push(pin);
+    }
+    pins
+}
+
+// The stored calibration, or the default while there is none
+pub fn load_calibration(settings: Option<&Settings>, index: usize, name: &str) -> FeedbackCalibration {
+    match settings.map(|settings| settings.load_feedback(index)) {
+        Some(Ok(Some(calibration))) => {
+            info!("{} feedback calibration loaded from NVS", name);
+            calibration
+        }
+        Some(Ok(None)) | None => FeedbackCalibration::DEFAULT,
+        Some(Err(e)) => {
+            warn!("Failed to read {} feedback calibration, using defaults: {}", name, e);
+            FeedbackCalibration::DEFAULT
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feedback(calibration: FeedbackCalibration, reading_mv: u16) -> Feedback {
+        let mut feedback = Feedback::new(Box::new(move || Ok(reading_mv)), calibration);
+        feedback.sample("Base");
+        feedback
+    }
+
+    #[test]
+    fn readings_map_onto_the_travel_either_way_round() {
+        let calibration = FeedbackCalibration { min_mv: 500, max_mv: 2500 };
+        assert_eq!(feedback(calibration, 1500).get_angle(180), Some(90));
+        assert_eq!(feedback(calibration, 100).get_angle(180), Some(0));
+        let reversed = FeedbackCalibration { min_mv: 2500, max_mv: 500 };
+        assert_eq!(feedback(reversed, 2000).get_angle(180), Some(45));
+        // Both ends at one reading can't be mapped
+        assert_eq!(feedback(FeedbackCalibration { min_mv: 900, max_mv: 900 }, 900).get_angle(180), None);
+    }
+
+    #[test]
+    fn readings_are_averaged() {
+        let mut readings = [1000, 2000].into_iter();
+        let mut feedback =
+            Feedback::new(Box::new(move || Ok(readings.next().unwrap())), FeedbackCalibration::DEFAULT);
+        assert_eq!(feedback.get_reading(), None);
+        feedback.sample("Base");
+        assert_eq!(feedback.get_reading(), Some(1000));
+        feedback.sample("Base");
+        assert_eq!(feedback.get_reading(), Some(1250));
+    }
+
+    #[test]
+    fn stall_is_flagged_after_the_stall_time_then_clears() {
+        let mut feedback = feedback(FeedbackCalibration { min_mv: 0, max_mv: 1800 }, 900);
+        feedback.tolerance = 50;
+        feedback.stall_ms = 100;
+        // 10 polls at 100 Hz, in tenths
+        for _ in 0..10 {
+            assert_eq!(feedback.track(Some(1200), 1800, 100), None);
+        }
+        assert_eq!(feedback.track(Some(1200), 1800, 100), Some(true));
+        assert!(feedback.is_stalled());
+        assert_eq!(feedback.track(Some(920), 1800, 100), Some(false));
+        // Moved by hand while it isn't driven
+        for _ in 0..20 {
+            assert_eq!(feedback.track(None, 1800, 100), None);
+        }
+    }
+
+    #[test]
+    fn calibration_round_trips_and_refuses_bad_blobs() {
+        let calibration = FeedbackCalibration { min_mv: 2800, max_mv: 300 };
+        let bytes = calibration.to_bytes();
+        assert_eq!(FeedbackCalibration::from_bytes(&bytes), Some(calibration));
+        assert_eq!(FeedbackCalibration::from_bytes(&bytes[..4]), None);
+        let mut newer = bytes;
+        newer[0] = CALIBRATION_VERSION + 1;
+        assert_eq!(FeedbackCalibration::from_bytes(&newer), None);
+    }
+
+    #[test]
+    fn pins_must_be_free_adc1_pins() {
+        // 12 is on ADC2 on the devkit, and not an ADC pin at all on the S3
+        let (a, b) = (boards::PROFILE.adc_pins[2], boards::PROFILE.adc_pins[3]);
+        let config = format!("{}, ,12,{},{}", a, a, b);
+        assert_eq!(parse_pins(&config, 6), vec![Some(a), None, None, None, Some(b), None]);
+    }
+}
@@ -0,0 +1,221 @@
+// Optional gripper on a servo of its own, outside the joint table, moved straight to a percent closed or stepped there
+// with a dwell at each step so it stalls gently on what it grips. The failsafe and parking leave it where it is
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::backend::ServoBackend;
+use crate::joints::GRIPPER;
+use crate::protocol::{GripperMode, GripperStatus};
+use crate::servo::{self, Servo, ServoError};
+use crate::CONFIG;
+
+pub struct Gripper {
+    servo: Servo,
+    open_angle: u16, // Tenths of a degree, like the servo's angles
+    closed_angle: u16,
+    step: u16,
+    dwell: Duration,
+    target: u8,                // Percent closed last asked for
+    last: Option<GripperMode>, // None until the first command
+    grip: Option<Grip>,
+}
+
+// A grip in progress, heading for the target's angle
+struct Grip {
+    goal: u16,
+    dwell_until: Option<Instant>, // Set once the servo comes to rest at a step
+}
+
+impl Gripper {
+    // `frame_hz` is the frame rate of the driver, the endpoints are GRIPPER's pulse widths
+    pub fn new(driver: impl ServoBackend + Send + 'static, frame_hz: u32) -> Gripper {
+        let mut servo = Servo::with_pulses(
+            GRIPPER.name.to_string(),
+            driver,
+            frame_hz,
+            GRIPPER.min_pulse_us,
+            GRIPPER.max_pulse_us,
+            GRIPPER.max_angle,
+        );
+        GRIPPER.default_calibration(frame_hz).apply(&mut servo);
+        Gripper {
+            servo,
+            open_angle: servo::from_degrees(CONFIG.gripper_open_angle.min(GRIPPER.max_angle)),
+            closed_angle: servo::from_degrees(CONFIG.gripper_closed_angle.min(GRIPPER.max_angle)),
+            step: servo::from_degrees(CONFIG.gripper_step_deg.max(1)),
+            dwell: Duration::from_millis(CONFIG.gripper_dwell_ms as u64),
+            target: 0,
+            last: None,
+            grip: None,
+        }
+    }
+
+    pub fn set_poll_hz(&mut self, poll_hz: u32) {
+        self.servo.set_poll_hz(poll_hz);
+    }
+
+    pub fn set_speed_scale(&mut self, percent: u8) {
+        self.servo.set_speed_scale(percent);
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.servo.set_paused(paused);
+    }
+
+    /// Opens or closes to `percent`, either straight there or stepping for a grip.
+    /// Returns Ok(true) if the percentage had to be clamped to 100.
+    pub fn set(&mut self, mode: GripperMode, percent: u8) -> Result<bool, ServoError> {
+        let was_clamped = percent > 100;
+        self.target = percent.min(100);
+        self.last = Some(mode);
+        let goal = self.angle_for(self.target);
+        info!("Gripper {:?} to {}% closed", mode, self.target);
+        match mode {
+            GripperMode::Move => {
+                self.grip = None;
+                self.servo.set_angle(goal)?;
+            }
+            // The servo holds where it is until the first step, taken from the next poll
+            GripperMode::Grip => {
+                self.grip = Some(Grip { goal, dwell_until: None });
+                self.servo.set_angle(self.servo.get_angle())?;
+            }
+        }
+        Ok(was_clamped)
+    }
+
+    /// Steps the servo, and a grip once the servo is at rest and the last step's dwell is up.
+    /// Must be called at the rate given to `set_poll_hz()`, like `Servo::poll()`.
+    pub fn poll(&mut self, allow_detach: bool, now: Instant) -> Result<(), ServoError> {
+        self.servo.poll(allow_detach)?;
+        let Some(grip) = self.grip.as_mut() else {
+            return Ok(());
+        };
+        if self.servo.is_moving() {
+            return Ok(());
+        }
+        if self.servo.get_angle() == grip.goal {
+            info!("Grip reached {}% closed", self.target);
+            self.grip = None;
+            return Ok(());
+        }
+        match grip.dwell_until {
+            None => grip.dwell_until = Some(now + self.dwell),
+            Some(until) if now >= until => {
+                let angle = self.servo.get_angle();
+                let step = if grip.goal > angle {
+                    (angle + self.step).min(grip.goal)
+                } else {
+                    angle.saturating_sub(self.step).max(grip.goal)
+                };
+                grip.dwell_until = None;
+                self.servo.set_angle(step)?;
+            }
+            Some(_) => {}
+        }
+        Ok(())
+    }
+
+    /// De-energizes the gripper, a grip in progress is abandoned where it is
+    pub fn stop(&mut self) -> Result<(), ServoError> {
+        self.grip = None;
+        self.servo.stop()
+    }
+
+    pub fn reenable(&mut self) -> Result<(), ServoError> {
+        self.servo.reenable()
+    }
+
+    pub fn status(&self) -> GripperStatus {
+        GripperStatus { percent: self.percent_for(self.servo.get_angle()), target: self.target, last: self.last }
+    }
+
+    pub fn is_faulted(&self) -> bool {
+        self.servo.is_faulted()
+    }
+
+    pub fn is_moving(&self) -> bool {
+        self.servo.is_moving() || self.grip.is_some()
+    }
+
+    // Closed can be below open for a servo mounted the other way round
+    fn angle_for(&self, percent: u8) -> u16 {
+        let (open, closed) = (self.open_angle as i32, self.closed_angle as i32);
+        (open + (closed - open) * percent as i32 / 100) as u16
+    }
+
+    fn percent_for(&self, angle: u16) -> u8 {
+        let (open, closed) = (self.open_angle as i32, self.closed_angle as i32);
+        if open == closed {
+            return 0;
+        }
+        ((angle as i32 - open) * 100 / (closed - open)).clamp(0, 100) as u8
+    }
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+    use crate::sim::{self, MockServo};
+
+    const POLL: Duration = Duration::from_millis(10);
+
+    fn gripper() -> Gripper {
+        let (driver, _) = MockServo::new();
+        let mut gripper = Gripper::new(driver, sim::FRAME_HZ);
+        gripper.set_poll_hz(100);
+        gripper
+    }
+
+    // Polls until the gripper is at rest, returning the time it got there
+    fn settle(gripper: &mut Gripper, mut now: Instant) -> Instant {
+        for _ in 0..100_000 {
+            gripper.poll(false, now).unwrap();
+            if !gripper.is_moving() {
+                return now;
+            }
+            now += POLL;
+        }
+        panic!("Gripper never came to rest");
+    }
+
+    #[test]
+    fn percent_past_100_is_clamped() {
+        let mut gripper = gripper();
+        assert!(gripper.set(GripperMode::Move, 150).unwrap());
+        assert!(!gripper.set(GripperMode::Move, 100).unwrap());
+        settle(&mut gripper, Instant::now());
+        let status = gripper.status();
+        assert_eq!((status.percent, status.target, status.last), (100, 100, Some(GripperMode::Move)));
+    }
+
+    #[test]
+    fn grip_dwells_at_every_step() {
+        let mut gripper = gripper();
+        gripper.set(GripperMode::Move, 0).unwrap();
+        let start = settle(&mut gripper, Instant::now());
+        gripper.set(GripperMode::Grip, 100).unwrap();
+        let end = settle(&mut gripper, start);
+        assert_eq!(gripper.status().percent, 100);
+        let travel = gripper.open_angle.abs_diff(gripper.closed_angle);
+        let steps = (travel / gripper.step) as u32;
+        assert!(end - start >= gripper.dwell * steps, "gripped in {:?}", end - start);
+    }
+
+    #[test]
+    fn stop_abandons_a_grip_where_it_is() {
+        let mut gripper = gripper();
+        gripper.set(GripperMode::Move, 0).unwrap();
+        let mut now = settle(&mut gripper, Instant::now());
+        gripper.set(GripperMode::Grip, 100).unwrap();
+        for _ in 0..50 {
+            gripper.poll(false, now).unwrap();
+            now += POLL;
+        }
+        gripper.stop().unwrap();
+        assert!(!gripper.is_moving());
+        let percent = gripper.status().percent;
+        assert!(percent > 0 && percent < 100, "stopped at {}%", percent);
+    }
+}
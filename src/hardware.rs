@@ -0,0 +1,1180 @@
+// Hardware start-up: brings up the display, WiFi, NVS and the servo outputs, then hands over to the control loop
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use embedded_graphics::mono_font::iso_8859_16::FONT_5X8;
+use embedded_graphics::mono_font::MonoTextStyleBuilder;
+use embedded_graphics::pixelcolor::BinaryColor;
+use log::{error, info, warn};
+use thiserror::Error;
+
+// ESP IDF related imports
+use esp_idf_hal::adc::config::Config as AdcConfig;
+use esp_idf_hal::adc::{attenuation, AdcChannelDriver, AdcDriver, ADC1};
+use esp_idf_hal::delay::FreeRtos;
+use esp_idf_hal::gpio::{ADCPin, AnyIOPin, AnyOutputPin};
+#[cfg(not(esp32s3))]
+use esp_idf_hal::gpio::{Gpio32, Gpio33, Gpio34, Gpio35, Gpio36, Gpio37, Gpio38, Gpio39};
+#[cfg(esp32s3)]
+use esp_idf_hal::gpio::{Gpio1, Gpio10, Gpio2, Gpio3, Gpio4, Gpio5, Gpio6, Gpio7, Gpio8, Gpio9};
+use esp_idf_hal::i2c::{I2cConfig, I2cDriver};
+use esp_idf_hal::ledc::{config, LedcDriver, LedcTimer, LedcTimerDriver, Resolution, TIMER0, TIMER1, TIMER2, CHANNEL0, CHANNEL1, CHANNEL2, CHANNEL3, CHANNEL4, CHANNEL5, CHANNEL6, CHANNEL7};
+use esp_idf_hal::modem::Modem;
+use esp_idf_hal::peripheral::Peripheral;
+use esp_idf_hal::peripherals::Peripherals;
+use esp_idf_hal::timer::{config as HalTimerConfig, TimerDriver, TIMER00};
+use esp_idf_hal::uart::{UartDriver, UART0, UART1, UART2};
+use esp_idf_hal::units::{FromValueType, Hertz};
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::log::EspLogger;
+use esp_idf_svc::mdns::EspMdns;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::wifi::EspWifi;
+
+use esp_idf_sys::{EspError, ESP_ERR_INVALID_ARG};
+use ssd1306::I2CDisplayInterface;
+
+use crate::backend::{LoggingMock, ServoBackend};
+use crate::boards::{self, PROFILE};
+use crate::battery::{self, Battery};
+use crate::boot::BootChecklist;
+use crate::button::{Button, EStopButton};
+use crate::buzzer::Buzzer;
+use crate::crash;
+use crate::current::{self, CurrentMonitor, Source};
+use crate::display::{Display, Oled};
+use crate::feedback::{self, Feedback, Reader};
+use crate::gripper::Gripper;
+use crate::history::History;
+use crate::ina219;
+use crate::pca9685::{self, Pca9685Channel};
+use crate::serial;
+use crate::remote_log::{self, Console, RemoteLog};
+use crate::joints::{JointConfig, GRIPPER, JOINTS};
+use crate::led::{LedPattern, StatusLed};
+use crate::link::Link;
+use crate::ota::{self, Updater};
+use crate::servo::Servo;
+use crate::settings::{Calibration, Settings, WifiCredentials};
+use crate::shared_i2c::{self, SharedI2c};
+use crate::tick::Tick;
+use crate::wifi_setup::WifiStage;
+use crate::{tasks, wifi_setup, CONFIG, RECV_TIMEOUT};
+
+const SOCKET_RETRY_MS: u32 = 2000;
+const LED_POLL_MS: u32 = 10; // Nothing else updates the LED until the control loop runs
+const CONSOLE_LEVEL: log::LevelFilter = log::LevelFilter::Info; // CONFIG_LOG_DEFAULT_LEVEL, EspLogger filters on it too
+const LEDC_CLOCK_HZ: u32 = 80_000_000; // APB clock the LEDC timers divide down
+const MIN_RESOLUTION_BITS: u8 = 8;
+const MAX_RESOLUTION_BITS: u8 = 16; // Duties go out to clients as u16
+const PCA9685_HZ: u32 = 50;
+const BUZZER_HZ: u32 = 2000; // Where the buzzer's timer starts, each tone sets its own
+const SSD1306_ADDRESS: u8 = 0x3C;
+const SSD1306_ALTERNATE_ADDRESS: u8 = 0x3D; // With the panel's address jumper moved
+const I2C_DEFAULT_HZ: u32 = 1_000_000;
+const I2C_MIN_HZ: u32 = 10_000;
+const I2C_MAX_HZ: u32 = 1_000_000; // The fastest the ESP32's controller runs
+const ADS1115_ADDRESSES: std::ops::RangeInclusive<u8> = 0x48..=0x4B; // Picked by where its ADDR pin is tied
+const MOCK_HZ: u32 = 50; // Mock outputs run like timer 0's defaults, so their duties read the same
+const MOCK_BITS: u8 = 12;
+// Pulse widths a servo is driven between at most, wider than any hobby servo takes
+const MIN_PULSE_US: f32 = 250.0;
+const MAX_PULSE_US: f32 = 2750.0;
+
+static LOGGER: RemoteLog<EspLogger> = RemoteLog::new(EspLogger);
+
+// The battery and feedback pins all read through the one ADC1 driver
+type SharedAdc = Arc<Mutex<AdcDriver<'static, ADC1>>>;
+
+// Sets esp-idf's level for the tag too, which covers its own components like wifi as well as what EspLogger prints
+impl Console for EspLogger {
+    fn apply_level(&self, target: Option<&str>, level: log::LevelFilter) {
+        match self.set_target_level(target.unwrap_or("*"), level) {
+            Ok(_) => {},
+            Err(e) => error!("Failed to set the {} log level: {}", target.unwrap_or("default"), e),
+        }
+    }
+}
+
+// Why start-up stopped, shown on the checklist while the LED flashes the fault pattern
+#[derive(Debug, Error)]
+pub enum AppError {
+    #[error("peripherals unavailable: {0}")]
+    Peripherals(EspError),
+    #[error("event loop unavailable: {0}")]
+    EventLoop(EspError),
+    #[error("I2C driver failed: {0}")]
+    I2c(EspError),
+    #[error("WiFi driver failed: {0}")]
+    WifiDriver(anyhow::Error),
+    #[error("LEDC timer failed: {0}")]
+    LedcTimer(EspError),
+    #[error("tick timer failed: {0}")]
+    Timer(EspError),
+}
+
+// What start() brings up for the control loop. The drivers are only held, dropping them would stop the servo
+// outputs, the tick and the advertising
+struct Started {
+    socket: Option<UdpSocket>, // None when starting offline
+    servos: Vec<Servo>,
+    gripper: Option<Gripper>,
+    current: CurrentMonitor,
+    settings: Option<Settings>,
+    link: Link,
+    tick: Tick,
+    battery: Battery,
+    estop_button: EStopButton,
+    button: Button,
+    buzzer: Buzzer,
+    _ledc_timers: [Option<ServoTimer>; 2], // The second only if a joint is on it
+    _timer: TimerDriver<'static>,
+    _mdns: Option<EspMdns>,
+    _wifi: Option<Box<EspWifi<'static>>>, // Only while running on ESP-NOW alone, the reconnect task has it otherwise
+    serial: Option<UartDriver<'static>>, // None unless serial commands are on
+    i2c_devices: Vec<u8>,
+}
+
+// Peripherals start() takes over once the display is up
+struct Parts {
+    modem: Modem,
+    ledc_timers: (TIMER0, TIMER1, TIMER2), // The third is the buzzer's
+    adc: ADC1,
+    timer: TIMER00,
+    bus: SharedI2c,
+    i2c_devices: Vec<u8>, // Addresses that answered the bus scan
+    system_loop: EspSystemEventLoop,
+    uarts: (UART0, UART1, UART2), // For serial commands, whichever one the config names
+    partition: Option<EspDefaultNvsPartition>, // Where the setup portal keeps credentials, None without NVS
+}
+
+// Nothing here returns, a failure that can't be retried halts with the LED flashing rather than panicking, so a
+// limb without a display still shows it is stuck
+pub fn main() -> ! {
+    // It is necessary to call this function once. Otherwise some patches to the runtime
+    // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
+    esp_idf_hal::sys::link_patches();
+
+    remote_log::install(&LOGGER, CONSOLE_LEVEL);
+    // Initialize NVS, servo calibration is stored there. Without it the compiled defaults are used
+    let partition = EspDefaultNvsPartition::take();
+    crash::install(partition.as_ref().ok().cloned());
+    let portal_partition = partition.as_ref().ok().cloned();
+    let nvs = partition.and_then(Settings::new);
+    match &nvs {
+        Ok(_) => info!("NVS Flash initialized"),
+        Err(e) => error!("NVS Flash initialization failed: {}", e),
+    }
+    info!("Pins for the {}", PROFILE.name);
+
+    // Solid until the control loop takes it over, connecting to WiFi can take a while. It needs no peripherals
+    // taken, so it can flash any failure after this
+    let mut status_led = match boards::status_led_pin() {
+        0 => StatusLed::none(),
+        pin => match StatusLed::new(pin as i32) {
+            Ok(status_led) => status_led,
+            Err(e) => {
+                error!("Failed to set up the status LED on GPIO{}: {}", pin, e);
+                StatusLed::none()
+            }
+        },
+    };
+    status_led.set_pattern(LedPattern::Solid, Instant::now());
+    status_led.update(Instant::now());
+
+    // get peripherals
+    let peripherals: Peripherals = match Peripherals::take() {
+        Ok(peripherals) => peripherals,
+        Err(e) => halt(AppError::Peripherals(e), None, status_led),
+    };
+
+    // get system event loop
+    let system_loop = match EspSystemEventLoop::take() {
+        Ok(sloop) => sloop,
+        Err(e) => halt(AppError::EventLoop(e), None, status_led),
+    };
+
+    // Set up pins for i2c, and i2c port
+    let i2c = peripherals.i2c0;
+    // Safety: the bus pins are only taken here, and no board puts anything else on them
+    let sda = unsafe { AnyIOPin::new(PROFILE.sda) };
+    let scl = unsafe { AnyIOPin::new(PROFILE.scl) };
+
+    // Set up the i2c driver
+    let config = I2cConfig::new().baudrate(i2c_hz().Hz());
+
+    let driver = match I2cDriver::new(i2c, sda, scl, &config) {
+        Ok(driver) => driver,
+        Err(e) => halt(AppError::I2c(e), None, status_led), // The display is on the bus, there is nowhere to show it
+    };
+
+    // The display and any PCA9685 share the bus. What answers on it is logged before anything uses it, so a display
+    // that doesn't come up can be told apart from one at the other address or a bus with nothing on it
+    let bus = SharedI2c::new(driver);
+    let i2c_devices = bus.scan();
+    info!("I2C bus scan found {}", describe_devices(&i2c_devices));
+    let display_address = display_address(&i2c_devices);
+    let interface = I2CDisplayInterface::new_custom_address(bus.clone(), display_address);
+
+    let mut display = Display::new(Oled::new(interface, CONFIG.display_height));
+    // Stored settings are applied now so start-up is shown the right way up, the control loop takes them over
+    let display_config = crate::load_display_config(nvs.as_ref().ok());
+    match display.set_flipped(display_config.flipped).and_then(|_| display.set_brightness(display_config.brightness)) {
+        Ok(_) => {},
+        Err(e) => error!("Error setting up the display: {:?}", e),
+    }
+
+    let display_result = display.init();
+    match &display_result {
+        Ok(_) => info!("Display initialized at {:#04x}", display_address),
+        Err(e) => warn!("No display found, carrying on without it: {:?}, {}", e, display_hint(&i2c_devices)),
+    }
+    display.set_text_style(
+        MonoTextStyleBuilder::new()
+            .font(&FONT_5X8)
+            .text_color(BinaryColor::On)
+            .build(),
+    );
+
+    // NVS came up before there was anything to show it on
+    let mut boot = BootChecklist::new();
+    boot.start("NVS", &mut display);
+    match &nvs {
+        Ok(_) => boot.done("", &mut display),
+        Err(e) => boot.fail(e, &mut display),
+    }
+    boot.start("Display", &mut display);
+    match display_result {
+        Ok(_) => boot.done(&format!("128x{} at {:#04x}", CONFIG.display_height, display_address), &mut display),
+        Err(e) => boot.fail(&format!("{:?}", e), &mut display), // Drawn for the log's sake, there is no panel
+    }
+
+    let parts = Parts {
+        modem: peripherals.modem,
+        ledc_timers: (peripherals.ledc.timer0, peripherals.ledc.timer1, peripherals.ledc.timer2),
+        adc: peripherals.adc1,
+        timer: peripherals.timer00,
+        bus,
+        i2c_devices,
+        system_loop,
+        uarts: (peripherals.uart0, peripherals.uart1, peripherals.uart2),
+        partition: nvs.is_ok().then_some(portal_partition).flatten(),
+    };
+    match start(parts, nvs.ok(), &mut boot, &mut display, &mut status_led) {
+        // The fields left in started are never dropped, run doesn't return
+        Ok(started) => crate::run(
+            started.socket,
+            started.servos,
+            display,
+            started.settings,
+            started.link,
+            crate::Board {
+                tick: started.tick,
+                battery: started.battery,
+                estop_button: started.estop_button,
+                button: started.button,
+                buzzer: started.buzzer,
+                status_led,
+                updater: Updater::default(),
+                gripper: started.gripper,
+                history: History::take(),
+                current: started.current,
+                i2c_devices: started.i2c_devices,
+            },
+            started.serial,
+        ),
+        Err(e) => halt(e, Some((&mut boot, &mut display)), status_led),
+    }
+}
+
+// Brings up everything after the display. A WiFi failure leaves the limb running offline while it is retried, the
+// socket is retried until it binds, anything else that fails stops start-up
+fn start(
+    parts: Parts,
+    mut settings: Option<Settings>,
+    boot: &mut BootChecklist,
+    display: &mut Display,
+    status_led: &mut StatusLed,
+) -> Result<Started, AppError> {
+    let Parts {
+        modem,
+        ledc_timers: (ledc_timer0, ledc_timer1, ledc_timer2),
+        adc,
+        timer,
+        bus,
+        i2c_devices,
+        system_loop,
+        uarts: (uart0, uart1, uart2),
+        partition,
+    } = parts;
+
+    // Opened first, it is what still works should WiFi not
+    let serial = serial::open(uart0, uart1, uart2);
+    // Settings of its own for the setup portal, used only if WiFi can't be joined
+    let portal = match partition.map(Settings::new) {
+        Some(Ok(portal)) => Some(portal),
+        Some(Err(e)) => {
+            error!("Failed to open NVS for the setup portal: {}", e);
+            None
+        }
+        None => None,
+    };
+
+    // Connect to WiFi, credentials from the setup portal take precedence over the compiled ones
+    info!("Socket initialize");
+    boot.start("WiFi scan", display);
+    let mut _wifi = wifi_setup::new_wifi(modem, system_loop.clone()).map_err(AppError::WifiDriver)?;
+    let stored = match settings.as_ref().map(Settings::load_wifi) {
+        Some(Ok(stored)) => stored,
+        Some(Err(e)) => {
+            error!("Failed to read WiFi credentials: {}", e);
+            None
+        }
+        None => None,
+    };
+    // The single SSID fields count as the first compiled network, the list follows them
+    let mut networks: Vec<WifiCredentials> = stored.into_iter().collect();
+    networks.extend(parse_networks(&format!("{}:{};{}", CONFIG.wifi_ssid, CONFIG.wifi_psk, CONFIG.wifi_networks)));
+    let access_point = match CONFIG.wifi_ap {
+        true if !CONFIG.wifi_ap_psk.is_empty() && !(8..=64).contains(&CONFIG.wifi_ap_psk.len()) => {
+            error!("Access point password must be 8 to 64 characters, running station only");
+            None
+        }
+        true => Some(wifi_setup::AccessPoint {
+            ssid: CONFIG.wifi_ap_ssid,
+            password: CONFIG.wifi_ap_psk,
+            channel: Some(CONFIG.wifi_ap_channel).filter(|&channel| channel != 0),
+        }),
+        false => None,
+    };
+    // A very long press of the button asked for the setup portal, which then runs until the next restart whatever
+    // networks are known. The portal needs NVS too, to keep what it is given
+    let setup = match settings.as_mut().map(Settings::take_setup_request) {
+        Some(Ok(requested)) => requested && portal.is_some(),
+        Some(Err(e)) => {
+            error!("Failed to read the setup request: {}", e);
+            false
+        }
+        None => false,
+    };
+    let mut radio_only = false;
+    let mut failure: Option<String> = None; // Why WiFi couldn't be joined, when start-up carries on without it
+    let joined = match setup {
+        true => Err(anyhow::anyhow!("Setup requested")),
+        false => wifi_setup::wifi(&networks, &mut _wifi, system_loop.clone(), 6, access_point, &mut |stage| {
+            show_wifi_stage(boot, stage, display)
+        }),
+    };
+    let ip_string = match joined.and_then(|_| Ok(_wifi.sta_netif().get_ip_info()?.ip)) {
+        Ok(ip) => {
+            if !CONFIG.espnow_role.is_empty() {
+                info!("ESP-NOW shares the access point's channel, the peer limb must be on it too");
+            }
+            ip
+        }
+        // A limb linked to its peer still works without a network, so it isn't held up waiting for one
+        Err(e) if !CONFIG.espnow_role.is_empty() && !setup => {
+            boot.fail(&e, display);
+            let channel = CONFIG.espnow_channel;
+            match wifi_setup::radio_only(&mut _wifi, system_loop.clone(), channel) {
+                Ok(_) => warn!("Failed to join WiFi, running on ESP-NOW alone on channel {}: {}", channel, e),
+                Err(e) => error!("Failed to set channel {} for ESP-NOW: {}", channel, e),
+            }
+            radio_only = true;
+            Ipv4Addr::UNSPECIFIED
+        }
+        // Everything else starts without the network, the reconnect task keeps trying to join it with the setup
+        // portal beside it. Without NVS there is nowhere to keep what the portal would collect, so there is none
+        Err(e) => {
+            boot.fail(&e, display);
+            warn!("Failed to join WiFi, starting without the network and retrying in the background: {}", e);
+            let network = networks.first().filter(|_| !setup); // One it could join would close the portal
+            match wifi_setup::offline(&mut _wifi, system_loop.clone(), network, portal.is_some()) {
+                Ok(_) => {},
+                Err(e) => error!("Failed to set up WiFi for retrying: {}", e),
+            }
+            failure = Some(wifi_setup::failure_text(&e, portal.is_some()));
+            Ipv4Addr::UNSPECIFIED
+        }
+    };
+    let offline = failure.is_some();
+    match offline {
+        true => boot.done("offline", display),
+        false => boot.done(&ip_string.to_string(), display),
+    }
+
+    // The short read timeout keeps the loop running so ticks are served while no packets arrive
+    // Nothing works without it, so a failed bind is shown and retried rather than given up on. Offline, the network
+    // task binds it once the network is joined
+    boot.start("Socket", display);
+    let socket = match offline {
+        true => {
+            boot.done("once joined", display);
+            None
+        }
+        false => loop {
+            match wifi_setup::init_socket(CONFIG.udp_port, Some(RECV_TIMEOUT)) {
+                Ok(socket) => {
+                    info!("Socket initialized");
+                    boot.done(&format!("port {}", CONFIG.udp_port), display);
+                    break Some(socket);
+                }
+                Err(e) => {
+                    error!("Failed to bind socket, retrying: {}", e);
+                    boot.progress(&format!("retrying, {}", e), display);
+                    wait_flashing(status_led, SOCKET_RETRY_MS);
+                }
+            }
+        },
+    };
+    // The limb can be reached to update it again, so a freshly flashed image is kept rather than rolled back. Offline
+    // that waits for the reconnect task to join
+    if !offline {
+        ota::mark_valid();
+    }
+    let mac = match _wifi.sta_netif().get_mac() {
+        Ok(mac) => mac,
+        Err(e) => {
+            error!("Failed to read the MAC address: {}", e);
+            [0; 6]
+        }
+    };
+    let link = Link::new(ip_string);
+    if let Some(failure) = failure {
+        link.set_failed(1, failure);
+    }
+
+    // Set up the servo drivers
+    boot.start("Servos", display);
+    // Each joint drives either its own LEDC channel and GPIO or a channel on the PCA9685
+    let backends = parse_backends(CONFIG.servo_backends, JOINTS.len());
+    let gripper_backend = match CONFIG.gripper_backend.trim() {
+        "" => None,
+        entry => {
+            let backend = parse_backend(entry);
+            if backend.is_none() {
+                warn!("Unknown gripper backend \"{}\", running without the gripper", entry);
+            }
+            backend
+        }
+    };
+    // LEDC joints run from one of two timers, the second is only started if one of them names it
+    let on_timer1 = JOINTS
+        .iter()
+        .zip(backends.iter())
+        .chain(gripper_backend.iter().map(|backend| (&GRIPPER, backend)))
+        .any(|(joint, backend)| joint.timer == 1 && *backend == BackendSelection::Ledc);
+    let timer0 = servo_timer(ledc_timer0, 0, CONFIG.servo_timer0_hz, CONFIG.servo_timer0_bits)
+        .map_err(AppError::LedcTimer)?;
+    let timer1 = match on_timer1 {
+        true => Some(
+            servo_timer(ledc_timer1, 1, CONFIG.servo_timer1_hz, CONFIG.servo_timer1_bits)
+                .map_err(AppError::LedcTimer)?,
+        ),
+        false => None,
+    };
+    let timers = [Some(timer0), timer1];
+    let pca9685_found = i2c_devices.contains(&CONFIG.pca9685_address);
+    match backends.iter().chain(gripper_backend.iter()).any(|backend| matches!(backend, BackendSelection::Pca9685(_))) {
+        true => {
+            if !pca9685_found {
+                error!("Nothing answered at the PCA9685's address {:#04x}", CONFIG.pca9685_address);
+            }
+            match pca9685::init(&bus, CONFIG.pca9685_address, PCA9685_HZ) {
+                Ok(_) => {},
+                Err(e) => error!("PCA9685 failed to initialise, its servos will report faults: {}", e),
+            }
+            crash::watch_pca9685(bus.clone(), CONFIG.pca9685_address);
+        }
+        false if pca9685_found => {
+            info!("A PCA9685 answered at {:#04x}, servo_backends can put joints on it", CONFIG.pca9685_address)
+        }
+        false => {},
+    }
+
+    let feedback_pins = feedback::parse_pins(CONFIG.feedback_pins, JOINTS.len());
+    let taken_pins: Vec<u8> = feedback_pins.iter().flatten().copied().collect();
+    let current_sources = current::parse_sources(CONFIG.current_sensors, JOINTS.len(), &taken_pins);
+    let adc_current = current_sources.iter().any(|source| matches!(source, Some(Source::Adc(_))));
+    let adc: Option<SharedAdc> = match boards::battery_pin() != 0 || !taken_pins.is_empty() || adc_current {
+        true => match AdcDriver::new(adc, &AdcConfig::new().calibration(true)) {
+            Ok(driver) => Some(Arc::new(Mutex::new(driver))),
+            Err(e) => {
+                error!("Failed to set up ADC1, there is no battery monitoring, joint feedback or ADC current: {}", e);
+                None
+            }
+        },
+        false => None,
+    };
+
+    let mut servos: Vec<Servo> = Vec::with_capacity(JOINTS.len());
+    let mut ledc_channel = 0; // LEDC channels are handed out in joint order, skipping joints on the PCA9685
+    for ((joint, backend), feedback_pin) in JOINTS.iter().zip(backends).zip(feedback_pins) {
+        let index = servos.len();
+        match backend {
+            BackendSelection::Ledc => {
+                create_and_add_servo(joint, ledc_channel, &timers, &mut servos, settings.as_ref());
+                ledc_channel += 1;
+            }
+            BackendSelection::Pca9685(channel) => {
+                create_and_add_pca_servo(joint, channel, &bus, CONFIG.pca9685_address, &mut servos, settings.as_ref())
+            }
+            BackendSelection::Mock => {
+                add_servo(joint, LoggingMock::new(joint.name, MOCK_BITS), MOCK_HZ, &mut servos, settings.as_ref())
+            }
+        }
+        // A joint that failed to create has nothing to attach its feedback to
+        if let (Some(pin), Some(adc), None) = (feedback_pin, adc.as_ref(), servos[index].get_unavailable()) {
+            attach_feedback(&mut servos[index], index, pin, adc, settings.as_ref());
+        }
+    }
+    // The gripper comes after the joints, so its LEDC channel follows theirs
+    let gripper = match gripper_backend {
+        Some(BackendSelection::Ledc) => match servo_timer_for(&timers, &GRIPPER) {
+            Some(timer) if valid_pulses(&GRIPPER, &GRIPPER.default_calibration(timer.hz), timer.hz) => {
+                match ledc_channel_driver(ledc_channel, &timer.driver, PROFILE.gripper_pin) {
+                    Ok(driver) => {
+                        ledc_channel += 1;
+                        Some(Gripper::new(driver, timer.hz))
+                    }
+                    Err(e) => {
+                        error!("Failed to create the gripper on GPIO{}: {}", PROFILE.gripper_pin, e);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        },
+        Some(BackendSelection::Pca9685(channel)) => {
+            let driver = Pca9685Channel::new(bus.clone(), CONFIG.pca9685_address, channel);
+            match valid_pulses(&GRIPPER, &GRIPPER.default_calibration(PCA9685_HZ), PCA9685_HZ) {
+                true => Some(Gripper::new(driver, PCA9685_HZ)),
+                false => None,
+            }
+        }
+        Some(BackendSelection::Mock) => match valid_pulses(&GRIPPER, &GRIPPER.default_calibration(MOCK_HZ), MOCK_HZ) {
+            true => Some(Gripper::new(LoggingMock::new(GRIPPER.name, MOCK_BITS), MOCK_HZ)),
+            false => None,
+        },
+        None => None,
+    };
+    // After the gripper, so a buzzer doesn't move the servo channels when it is added
+    let buzzer = match boards::buzzer_pin() {
+        0 => Buzzer::none(),
+        pin => match start_buzzer(ledc_timer2, ledc_channel, pin as i32) {
+            Ok(buzzer) => {
+                info!("Buzzer on GPIO{}", pin);
+                ledc_channel += 1;
+                buzzer
+            }
+            Err(e) => {
+                error!("Failed to set up the buzzer on GPIO{}: {}", pin, e);
+                Buzzer::none()
+            }
+        },
+    };
+    // The buzzer's channel is stopped too, a panic mid-tone would otherwise leave it sounding
+    crash::watch_ledc(ledc_channel as u8);
+    let current = current_monitor(&current_sources, adc.as_ref(), &bus, &i2c_devices, settings.as_ref());
+    // Servos that failed are already logged and keep their slots, the rest still run
+    let failed: Vec<String> = servos
+        .iter()
+        .filter_map(|servo| servo.get_unavailable().map(|error| format!("{} {}", servo.get_name(), error)))
+        .collect();
+    match failed.is_empty() {
+        true => boot.done(&servos.len().to_string(), display),
+        false => boot.fail(&failed.join(", "), display),
+    }
+
+    //let mut resistor = PinDriver::input(peripherals.pins.gpio2)?;
+
+    // The battery is sampled by a task of its own, without it the voltage is never known and there is no cutoff
+    let battery = Battery::default();
+    let battery_pin = boards::battery_pin();
+    if let (Some(adc), true) = (adc.as_ref(), battery_pin != 0) {
+        match adc_pin_reader(adc, battery_pin) {
+            Ok(read_mv) => {
+                info!("Battery monitored on GPIO{}", battery_pin);
+                let sample_battery = battery.clone();
+                tasks::spawn(&tasks::BATTERY_TASK, move || {
+                    battery::sample_task(read_mv, sample_battery, CONFIG.battery_window as usize)
+                });
+            }
+            Err(e) => error!("Failed to set up the battery ADC on GPIO{}: {}", battery_pin, e),
+        }
+    }
+
+    // Timer setup
+    boot.start("Tick timer", display);
+    let mut timer = TimerDriver::new(timer, &HalTimerConfig::Config::new().auto_reload(true))
+        .map_err(AppError::Timer)?;
+
+    // The timer only raises the tick, the servos are polled from the control loop
+    let tick = Tick::new(CONFIG.servo_tick_hz);
+    let alarm_ticks = timer.tick_hz() / tick.get_hz() as u64;
+
+    match timer.set_alarm(alarm_ticks){
+        Ok(_) => {},
+        Err(e) => error!("Failed to set alarm: {}", e),
+    };
+
+    let timer_tick = tick.clone();
+    unsafe {
+        match timer.subscribe(move || {
+            timer_tick.raise();
+        }){
+            Ok(_) => {},
+            Err(e) => error!("Failed to subscribe to timer: {}", e),
+        };
+    }
+
+    timer
+        .enable_interrupt()
+        .and_then(|_| timer.enable_alarm(true))
+        .and_then(|_| timer.enable(true))
+        .map_err(AppError::Timer)?;
+    info!("Servo tick every {} ms", tick.get_period().as_millis());
+    boot.done(&format!("{} Hz", tick.get_hz()), display);
+
+    // Registered once the servos exist, so the advertised names and count match what was actually created. Offline
+    // it is left to the reconnect task, there is nothing to advertise until the network is joined
+    boot.start("mDNS", display);
+    let servo_names: Vec<String> = servos.iter().map(|servo| servo.get_name().to_string()).collect();
+    let advertise = move || {
+        let servo_names: Vec<&str> = servo_names.iter().map(String::as_str).collect();
+        match wifi_setup::init_mdns(CONFIG.udp_port, CONFIG.mdns_hostname, CONFIG.mdns_instance, mac, &servo_names) {
+            Ok(mdns) => {
+                info!("mDNS initialized");
+                Ok(mdns)
+            }
+            Err(e) => {
+                error!("mDNS initialization failed, clients will need the IP address: {}", e);
+                Err(e)
+            }
+        }
+    };
+    let (_mdns, on_joined) = match offline {
+        true => {
+            boot.done("once joined", display);
+            (None, Some(advertise))
+        }
+        false => match advertise() {
+            Ok(mdns) => {
+                boot.done("", display);
+                (Some(mdns), None)
+            }
+            Err(e) => {
+                boot.fail(&e, display);
+                (None, None)
+            }
+        },
+    };
+
+    // The driver moves to its own task, which rejoins the network if the connection drops, or joins it for the
+    // first time when starting offline. Without a network it stays started for ESP-NOW, rejoining would take the
+    // radio off the peer's channel
+    let _wifi = match radio_only {
+        true => Some(_wifi),
+        false => {
+            let offline = on_joined.map(|advertise| wifi_setup::Offline {
+                portal,
+                on_joined: Box::new(move || advertise().ok()),
+            });
+            let reconnect_link = link.clone();
+            tasks::spawn(&tasks::WIFI_TASK, move || {
+                wifi_setup::reconnect_task(*_wifi, system_loop, reconnect_link, offline)
+            });
+            None
+        }
+    };
+
+    // Latches the e-stop from the control loop, and while held keeps it from being cleared
+    let estop_button = match boards::estop_pin() {
+        0 => EStopButton::none(),
+        pin => match EStopButton::new(pin as i32) {
+            Ok(button) => {
+                info!("E-stop button on GPIO{}", pin);
+                button
+            }
+            Err(e) => {
+                error!("Failed to set up the e-stop button on GPIO{}: {}", pin, e);
+                EStopButton::none()
+            }
+        },
+    };
+    let button = match boards::button_pin() {
+        0 => Button::none(),
+        pin => match Button::new(pin as i32) {
+            Ok(button) => {
+                info!("Button on GPIO{}", pin);
+                button
+            }
+            Err(e) => {
+                error!("Failed to set up the button on GPIO{}: {}", pin, e);
+                Button::none()
+            }
+        },
+    };
+
+    Ok(Started {
+        socket,
+        servos,
+        gripper,
+        current,
+        settings,
+        link,
+        tick,
+        battery,
+        estop_button,
+        button,
+        buzzer,
+        _ledc_timers: timers,
+        _timer: timer,
+        _mdns,
+        _wifi,
+        serial,
+        i2c_devices,
+    })
+}
+
+// The configured bus speed, or the default for one the controller can't run at
+fn i2c_hz() -> u32 {
+    match CONFIG.i2c_baud_hz {
+        hz if (I2C_MIN_HZ..=I2C_MAX_HZ).contains(&hz) => hz,
+        hz => {
+            warn!("The I2C bus can't run at {} Hz, using {} Hz", hz, I2C_DEFAULT_HZ);
+            I2C_DEFAULT_HZ
+        }
+    }
+}
+
+// The configured display address, otherwise whichever of the two an SSD1306 can have answered the scan. Neither
+// answering leaves the usual one, for the init to report the failure
+fn display_address(found: &[u8]) -> u8 {
+    match CONFIG.display_address {
+        0 => {}
+        address if shared_i2c::is_valid_address(address) => {
+            if !found.contains(&address) {
+                warn!("Nothing answered at the display's address {:#04x}", address);
+            }
+            return address;
+        }
+        address => warn!("{:#04x} isn't a 7-bit I2C address, finding the display by the bus scan", address),
+    }
+    match (found.contains(&SSD1306_ADDRESS), found.contains(&SSD1306_ALTERNATE_ADDRESS)) {
+        (false, true) => SSD1306_ALTERNATE_ADDRESS,
+        _ => SSD1306_ADDRESS,
+    }
+}
+
+// What a scanned address is likely to be, by the addresses the parts this firmware knows can be set to
+fn known_device(address: u8) -> Option<&'static str> {
+    match address {
+        SSD1306_ADDRESS | SSD1306_ALTERNATE_ADDRESS => Some("SSD1306"),
+        address if address == CONFIG.pca9685_address => Some("PCA9685"),
+        address if ADS1115_ADDRESSES.contains(&address) => Some("ADS1115"),
+        _ => None,
+    }
+}
+
+// Each address with what it is likely to be, for the log
+fn describe_devices(addresses: &[u8]) -> String {
+    if addresses.is_empty() {
+        return "nothing".to_string();
+    }
+    let described: Vec<String> = addresses
+        .iter()
+        .map(|&address| match known_device(address) {
+            Some(name) => format!("{:#04x} ({})", address, name),
+            None => format!("{:#04x}", address),
+        })
+        .collect();
+    described.join(", ")
+}
+
+// Where to look for a display that didn't initialise, by what the scan found
+fn display_hint(addresses: &[u8]) -> &'static str {
+    match addresses {
+        [] => "nothing answered on the bus, check the wiring and pull-ups",
+        _ if addresses.contains(&SSD1306_ADDRESS) || addresses.contains(&SSD1306_ALTERNATE_ADDRESS) => {
+            "the panel answered but didn't initialise"
+        }
+        _ => "nothing answered at either display address",
+    }
+}
+
+// Shows why start-up stopped and flashes the fault pattern until the limb is reset, there is nothing left to try
+fn halt(error: AppError, shown: Option<(&mut BootChecklist, &mut Display)>, mut status_led: StatusLed) -> ! {
+    error!("Start-up failed: {}", error);
+    if let Some((boot, display)) = shown {
+        boot.fail(&error, display);
+    }
+    status_led.set_pattern(LedPattern::Fault, Instant::now());
+    loop {
+        status_led.update(Instant::now());
+        FreeRtos::delay_ms(LED_POLL_MS);
+    }
+}
+
+// Flashes the fault pattern while a failed stage waits to be retried, then goes back to solid
+fn wait_flashing(status_led: &mut StatusLed, ms: u32) {
+    let until = Instant::now() + Duration::from_millis(ms as u64);
+    status_led.set_pattern(LedPattern::Fault, Instant::now());
+    while Instant::now() < until {
+        status_led.update(Instant::now());
+        FreeRtos::delay_ms(LED_POLL_MS);
+    }
+    status_led.set_pattern(LedPattern::Solid, Instant::now());
+    status_led.update(Instant::now());
+}
+
+// Scanning and DHCP are steps of their own, every network tried gets a join step with its attempts
+fn show_wifi_stage(boot: &mut BootChecklist, stage: WifiStage, display: &mut Display) {
+    match stage {
+        WifiStage::Scanning => boot.start("WiFi scan", display),
+        WifiStage::Scanned { seen } => boot.done(&format!("{} in range", seen), display),
+        WifiStage::Connecting { ssid, attempt, max_attempts } => {
+            boot.start("WiFi join", display);
+            boot.progress(&format!("{} {}/{}", ssid, attempt, max_attempts), display);
+        }
+        WifiStage::Dhcp { ssid } => {
+            boot.done(ssid, display);
+            boot.start("DHCP", display);
+        }
+        WifiStage::GaveUp { ssid, error } => boot.fail(&format!("{}: {}", ssid, error), display),
+    }
+}
+
+// A servo timer once started, its frame rate is what the duty ranges of the joints on it are checked against
+struct ServoTimer {
+    driver: LedcTimerDriver<'static>,
+    hz: u32,
+}
+
+// Starts one of the servo timers. A resolution finer than the frame rate allows is lowered, the timer divides the
+// 80 MHz clock and each step needs a tick of it, so 14 bits works up to about 4.8 kHz and 16 bits up to 1.2 kHz
+fn servo_timer<T: LedcTimer>(
+    timer: impl Peripheral<P = T> + 'static,
+    index: u8,
+    hz: u32,
+    bits: u8,
+) -> Result<ServoTimer, EspError> {
+    let hz = hz.max(1);
+    let finest = (LEDC_CLOCK_HZ / hz).max(1).ilog2().min(MAX_RESOLUTION_BITS as u32) as u8;
+    if finest < MIN_RESOLUTION_BITS {
+        error!("LEDC timer {} can't run at {} Hz with {} bits or more", index, hz, MIN_RESOLUTION_BITS);
+        return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>());
+    }
+    let resolution_bits = bits.clamp(MIN_RESOLUTION_BITS, finest);
+    if resolution_bits != bits {
+        warn!("LEDC timer {} can't have {} bits at {} Hz, using {}", index, bits, hz, resolution_bits);
+    }
+    let resolution = match resolution_bits {
+        8 => Resolution::Bits8,
+        9 => Resolution::Bits9,
+        10 => Resolution::Bits10,
+        11 => Resolution::Bits11,
+        12 => Resolution::Bits12,
+        13 => Resolution::Bits13,
+        14 => Resolution::Bits14,
+        15 => Resolution::Bits15,
+        _ => Resolution::Bits16,
+    };
+    let driver = LedcTimerDriver::new(timer, &config::TimerConfig::new().resolution(resolution).frequency(Hertz(hz)))?;
+    info!("LEDC timer {} at {} Hz, {} bits", index, hz, resolution_bits);
+    Ok(ServoTimer { driver, hz })
+}
+
+// None, logged, for a joint naming a timer there isn't
+fn servo_timer_for<'a>(timers: &'a [Option<ServoTimer>], joint: &JointConfig) -> Option<&'a ServoTimer> {
+    let timer = timers.get(joint.timer as usize).and_then(Option::as_ref);
+    if timer.is_none() {
+        error!("{} is on LEDC timer {}, there are only timers 0 and 1", joint.name, joint.timer);
+    }
+    timer
+}
+
+// Whether a duty range drives pulses a servo can take at the frame rate. One outside them was most likely calibrated
+// at another rate, and could drive the servo into its end stops
+fn valid_pulses(joint: &JointConfig, calibration: &Calibration, hz: u32) -> bool {
+    let frame_us = 1e6 / hz as f32;
+    let (min_us, max_us) = (calibration.min_duty * frame_us, calibration.max_duty * frame_us);
+    if min_us < MIN_PULSE_US || max_us > MAX_PULSE_US {
+        error!(
+            "{} would get {:.0} to {:.0} us pulses at {} Hz, outside {:.0} to {:.0} us, a duty range for another rate?",
+            joint.name, min_us, max_us, hz, MIN_PULSE_US, MAX_PULSE_US
+        );
+        return false;
+    }
+    true
+}
+
+fn create_and_add_servo(
+    joint: &JointConfig,
+    channel: usize,
+    timers: &[Option<ServoTimer>],
+    servos: &mut Vec<Servo>,
+    settings: Option<&Settings>,
+) {
+    let Some(timer) = servo_timer_for(timers, joint) else {
+        servos.push(Servo::unavailable(joint.name.to_string(), joint.max_angle, format!("no timer {}", joint.timer)));
+        return;
+    };
+    let Some(pin) = boards::servo_pin(servos.len()) else {
+        error!("The {} has no pin for servo {}", PROFILE.name, joint.name);
+        servos.push(Servo::unavailable(joint.name.to_string(), joint.max_angle, "no pin on this board".to_string()));
+        return;
+    };
+    match ledc_channel_driver(channel, &timer.driver, pin) {
+        Ok(driver) => add_servo(joint, driver, timer.hz, servos, settings),
+        Err(e) => {
+            error!("Failed to create servo {} on GPIO{}: {}", joint.name, pin, e);
+            servos.push(Servo::unavailable(joint.name.to_string(), joint.max_angle, e.to_string()));
+        }
+    }
+}
+
+// 10 bits is plenty for a square wave, only the pitch ever changes
+fn start_buzzer(timer: TIMER2, channel: usize, pin: i32) -> Result<Buzzer, EspError> {
+    let timer = LedcTimerDriver::new(
+        timer,
+        &config::TimerConfig::new().resolution(Resolution::Bits10).frequency(Hertz(BUZZER_HZ)),
+    )?;
+    let driver = ledc_channel_driver(channel, &timer, pin)?;
+    Ok(Buzzer::new(driver, timer))
+}
+
+// Channels are distinct peripheral types, so the joint's is picked by number
+fn ledc_channel_driver(channel: usize, ledc_driver: &LedcTimerDriver<'static>, pin: i32) -> Result<LedcDriver<'static>, EspError> {
+    // Safety: the channels and joint pins are only taken here, once each, and nothing else uses them
+    unsafe {
+        let pin = AnyOutputPin::new(pin);
+        match channel {
+            0 => LedcDriver::new(CHANNEL0::new(), ledc_driver, pin),
+            1 => LedcDriver::new(CHANNEL1::new(), ledc_driver, pin),
+            2 => LedcDriver::new(CHANNEL2::new(), ledc_driver, pin),
+            3 => LedcDriver::new(CHANNEL3::new(), ledc_driver, pin),
+            4 => LedcDriver::new(CHANNEL4::new(), ledc_driver, pin),
+            5 => LedcDriver::new(CHANNEL5::new(), ledc_driver, pin),
+            6 => LedcDriver::new(CHANNEL6::new(), ledc_driver, pin),
+            7 => LedcDriver::new(CHANNEL7::new(), ledc_driver, pin),
+            _ => Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>()), // Out of LEDC channels, the rest need the PCA9685
+        }
+    }
+}
+
+// Reads an ADC1 pin in calibrated millivolts. Pins are distinct types, so like the LEDC channels it is picked by
+// number, only ADC1 pins work alongside WiFi. The chip's ADC1 pins, a board may leave some of them out
+fn adc_pin_reader(adc: &SharedAdc, pin: u8) -> Result<Reader, EspError> {
+    let adc = adc.clone();
+    // Safety: each battery, feedback and current sensor pin is only taken here, once, and none of the board's ADC1
+    // pins drive a joint
+    unsafe {
+        match pin {
+            _ if !boards::is_adc_pin(pin) => Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>()),
+            #[cfg(not(esp32s3))]
+            32 => adc_reader(adc, Gpio32::new()),
+            #[cfg(not(esp32s3))]
+            33 => adc_reader(adc, Gpio33::new()),
+            #[cfg(not(esp32s3))]
+            34 => adc_reader(adc, Gpio34::new()),
+            #[cfg(not(esp32s3))]
+            35 => adc_reader(adc, Gpio35::new()),
+            #[cfg(not(esp32s3))]
+            36 => adc_reader(adc, Gpio36::new()),
+            #[cfg(not(esp32s3))]
+            37 => adc_reader(adc, Gpio37::new()),
+            #[cfg(not(esp32s3))]
+            38 => adc_reader(adc, Gpio38::new()),
+            #[cfg(not(esp32s3))]
+            39 => adc_reader(adc, Gpio39::new()),
+            #[cfg(esp32s3)]
+            1 => adc_reader(adc, Gpio1::new()),
+            #[cfg(esp32s3)]
+            2 => adc_reader(adc, Gpio2::new()),
+            #[cfg(esp32s3)]
+            3 => adc_reader(adc, Gpio3::new()),
+            #[cfg(esp32s3)]
+            4 => adc_reader(adc, Gpio4::new()),
+            #[cfg(esp32s3)]
+            5 => adc_reader(adc, Gpio5::new()),
+            #[cfg(esp32s3)]
+            6 => adc_reader(adc, Gpio6::new()),
+            #[cfg(esp32s3)]
+            7 => adc_reader(adc, Gpio7::new()),
+            #[cfg(esp32s3)]
+            8 => adc_reader(adc, Gpio8::new()),
+            #[cfg(esp32s3)]
+            9 => adc_reader(adc, Gpio9::new()),
+            #[cfg(esp32s3)]
+            10 => adc_reader(adc, Gpio10::new()),
+            _ => Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>()),
+        }
+    }
+}
+
+// 11 dB attenuation reads up to about 3.1 V at the pin
+fn adc_reader<T: ADCPin<Adc = ADC1> + Send + 'static>(adc: SharedAdc, pin: T) -> Result<Reader, EspError> {
+    let mut channel: AdcChannelDriver<'static, { attenuation::DB_11 }, T> = AdcChannelDriver::new(pin)?;
+    Ok(Box::new(move || adc.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).read(&mut channel)))
+}
+
+// Starts reading each current sensor the config names, with the calibration stored for it. An ADC pin that can't be
+// set up leaves its joints unwatched, an INA219 that doesn't answer stays in and reports its failed reads
+fn current_monitor(
+    sources: &[Option<Source>],
+    adc: Option<&SharedAdc>,
+    bus: &SharedI2c,
+    found: &[u8],
+    settings: Option<&Settings>,
+) -> CurrentMonitor {
+    let mut monitor = CurrentMonitor::new();
+    for (source, joints) in current::group(sources) {
+        let read: current::Reader = match source {
+            Source::Ina219(address) if !shared_i2c::is_valid_address(address) => {
+                error!("{} is on a reserved I2C address, its joints have no current sensing", source);
+                continue;
+            }
+            Source::Ina219(address) => {
+                if !found.contains(&address) {
+                    warn!("{} didn't answer the bus scan", source);
+                }
+                match ina219::init(bus, address) {
+                    Ok(_) => {},
+                    Err(e) => error!("{} failed to initialise, its readings will fail: {}", source, e),
+                }
+                ina219::reader(bus.clone(), address)
+            }
+            Source::Adc(pin) => match adc.map(|adc| adc_pin_reader(adc, pin)) {
+                Some(Ok(mut read_mv)) => Box::new(move || read_mv().map(|reading_mv| reading_mv as i32 * 1000)),
+                Some(Err(e)) => {
+                    error!("Failed to set up the current sensor on GPIO{}: {}", pin, e);
+                    continue;
+                }
+                None => continue, // ADC1 failed to start, that is logged already
+            },
+        };
+        let names: Vec<&str> = joints.iter().map(|&joint| JOINTS[joint].name).collect();
+        info!("Current of {} read from {}", names.join(", "), source);
+        let calibration = current::load_calibration(settings, monitor.sensor_count() as usize, source);
+        monitor.add(source, joints, read, calibration);
+    }
+    monitor
+}
+
+// Reads the joint's potentiometer wiper, mapped through the feedback calibration stored for it
+fn attach_feedback(servo: &mut Servo, index: usize, pin: u8, adc: &SharedAdc, settings: Option<&Settings>) {
+    let calibration = feedback::load_calibration(settings, index, servo.get_name());
+    match adc_pin_reader(adc, pin) {
+        Ok(read) => {
+            info!("{} feedback on GPIO{}", servo.get_name(), pin);
+            servo.set_feedback(Feedback::new(read, calibration));
+        }
+        Err(e) => error!("Failed to set up {} feedback on GPIO{}: {}", servo.get_name(), pin, e),
+    }
+}
+
+// The chip runs at the 50 Hz and 12 bits timer 0 defaults to, so the same duty fractions apply
+fn create_and_add_pca_servo(
+    joint: &JointConfig,
+    channel: u8,
+    bus: &SharedI2c,
+    address: u8,
+    servos: &mut Vec<Servo>,
+    settings: Option<&Settings>,
+) {
+    let driver = Pca9685Channel::new(bus.clone(), address, channel);
+    add_servo(joint, driver, PCA9685_HZ, servos, settings);
+}
+
+// `hz` is the frame rate of the driver, a servo whose duty range doesn't suit it is left unavailable
+fn add_servo(
+    joint: &JointConfig,
+    driver: impl ServoBackend + Send + 'static,
+    hz: u32,
+    servos: &mut Vec<Servo>,
+    settings: Option<&Settings>,
+) {
+    let defaults = joint.default_calibration(hz);
+    let calibration = match settings {
+        Some(settings) => settings.load_calibration(servos.len(), joint.name, defaults),
+        None => defaults,
+    };
+    if !valid_pulses(joint, &calibration, hz) {
+        servos.push(Servo::unavailable(joint.name.to_string(), joint.max_angle, "bad duty range".to_string()));
+        return;
+    }
+    let mut servo = Servo::new(
+        joint.name.to_string(),
+        driver,
+        hz,
+        calibration.min_duty,
+        calibration.max_duty,
+        joint.max_angle,
+    );
+    calibration.apply(&mut servo);
+    let (min_duty, max_duty) = servo.get_duty_endpoints();
+    if max_duty - min_duty < joint.max_angle as u32 {
+        let steps = max_duty - min_duty;
+        warn!("{} has only {} duty steps for {} degrees, give its timer more bits", joint.name, steps, joint.max_angle);
+    }
+    servos.push(servo);
+}
+
+// Used by the reboot command once the servos are stopped
+pub fn restart() -> ! {
+    esp_idf_hal::reset::restart();
+    unreachable!("Restart returned");
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum BackendSelection {
+    Ledc,
+    Pca9685(u8), // Channel on the expander
+    Mock,        // Nothing attached, the duties are only logged
+}
+
+// One entry per joint from the comma separated config, missing or unreadable entries fall back to LEDC
+fn parse_backends(config: &str, joints: usize) -> Vec<BackendSelection> {
+    let mut entries = config.split(',').map(str::trim);
+    (0..joints)
+        .map(|joint| match entries.next() {
+            Some("") | None => BackendSelection::Ledc,
+            Some(entry) => parse_backend(entry).unwrap_or_else(|| {
+                warn!("Unknown servo backend \"{}\" for joint {}, using LEDC", entry, joint);
+                BackendSelection::Ledc
+            }),
+        })
+        .collect()
+}
+
+// "ledc", "pca9685:<channel>" or "mock", None for anything else
+fn parse_backend(entry: &str) -> Option<BackendSelection> {
+    match entry {
+        "ledc" => Some(BackendSelection::Ledc),
+        "mock" => Some(BackendSelection::Mock),
+        _ => match entry.strip_prefix("pca9685:").and_then(|channel| channel.parse::<u8>().ok()) {
+            Some(channel) if channel < pca9685::CHANNEL_COUNT => Some(BackendSelection::Pca9685(channel)),
+            _ => None,
+        },
+    }
+}
+
+// SSID:password pairs separated by ';', the password runs to the end of its entry so only the SSID can't hold a ':'.
+// Entries with an empty SSID are skipped, a missing password means an open network
+fn parse_networks(config: &str) -> Vec<WifiCredentials> {
+    config
+        .split(';')
+        .filter_map(|entry| {
+            let (ssid, psk) = entry.split_once(':').unwrap_or((entry, ""));
+            if ssid.is_empty() {
+                return None;
+            }
+            Some(WifiCredentials {
+                ssid: ssid.to_string(),
+                psk: psk.to_string(),
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,34 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+pub const MIN_INTERVAL: Duration = Duration::from_millis(20); // One servo tick at the default rate
+
+// A client that asked for heartbeats, only the latest subscriber is kept
+pub struct Subscription {
+    addr: SocketAddr,
+    interval: Duration,
+    last_sent: Option<Instant>, // None until the first heartbeat, which goes out straight away
+    sequence: u16,              // Heartbeats carry their own count in the sequence field
+}
+
+impl Subscription {
+    pub fn new(addr: SocketAddr, interval: Duration) -> Subscription {
+        Subscription { addr, interval, last_sent: None, sequence: 0 }
+    }
+
+    // Returns the sequence number for the next heartbeat if one is due, without waiting
+    pub fn poll(&mut self, now: Instant) -> Option<u16> {
+        match self.last_sent {
+            Some(last_sent) if now.duration_since(last_sent) < self.interval => None,
+            _ => {
+                self.last_sent = Some(now);
+                self.sequence = self.sequence.wrapping_add(1);
+                Some(self.sequence)
+            }
+        }
+    }
+
+    pub fn get_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
@@ -0,0 +1,283 @@
+// The last HISTORY_SIZE commands dispatched, kept in RTC memory on the chip so a panic, watchdog reset or reboot
+// leaves them to be read back. Telemetry and status polls aren't kept, they would push everything else out
+use std::net::SocketAddr;
+#[cfg(not(feature = "sim"))]
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::info;
+
+use crate::loop_timing;
+use crate::protocol::{
+    self, CalibrationCommand, ConfigCommand, ControlPacket, CurrentCommand, GripperMode, HistoryEntry, ReplyPayload,
+    Status, HISTORY_PARAMS,
+};
+
+pub const HISTORY_SIZE: usize = 128;
+pub const HISTORY_CHUNK: usize = 16; // Entries in each history reply, small enough for one datagram
+const MAGIC: u32 = 0x4C48_4953; // Marks a ring written by this firmware, rather than whatever power-on left there
+
+const EMPTY: HistoryEntry = HistoryEntry {
+    uptime_ms: 0,
+    boot: 0,
+    ip: [0; 4],
+    port: 0,
+    command: 0,
+    status: 0,
+    count: 0,
+    params: [0; HISTORY_PARAMS],
+};
+
+struct Ring {
+    magic: u32,
+    boot: u8,
+    head: usize, // Where the next entry goes
+    len: usize,
+    entries: [HistoryEntry; HISTORY_SIZE],
+}
+
+// Left alone by the startup code, only power-on clears it
+#[cfg(not(feature = "sim"))]
+#[link_section = ".rtc_noinit"]
+static mut RETAINED: MaybeUninit<Ring> = MaybeUninit::uninit();
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+pub struct History {
+    ring: &'static mut Ring,
+    sequence: u16, // Streamed chunks carry their own count in the sequence field, like heartbeats
+}
+
+impl History {
+    // The ring kept through the last restart, or an empty one after power-on. Only the controller holds it
+    pub fn take() -> History {
+        assert!(!TAKEN.swap(true, Ordering::Relaxed), "Command history taken twice");
+        History::load(retained())
+    }
+
+    // A ring of its own, so each test's controller starts with an empty history
+    #[cfg(all(test, feature = "sim"))]
+    pub fn new() -> History {
+        History::load(unwritten())
+    }
+
+    fn load(ring: &'static mut Ring) -> History {
+        match ring.magic == MAGIC && ring.head < HISTORY_SIZE && ring.len <= HISTORY_SIZE {
+            true => {
+                ring.boot = ring.boot.wrapping_add(1);
+                info!("Kept {} commands of history through the restart", ring.len);
+            }
+            false => {
+                ring.magic = MAGIC;
+                ring.boot = 0;
+                ring.head = 0;
+                ring.len = 0;
+                ring.entries = [EMPTY; HISTORY_SIZE];
+            }
+        }
+        History { ring, sequence: 0 }
+    }
+
+    // Keeps a command once it has been answered, with the status it was answered with
+    pub fn record(&mut self, addr: SocketAddr, command: u8, params: &[u16], status: Status) {
+        let (ip, port) = match addr {
+            SocketAddr::V4(addr) => (addr.ip().octets(), addr.port()),
+            SocketAddr::V6(_) => ([0; 4], 0), // A BLE phone
+        };
+        let count = params.len().min(HISTORY_PARAMS);
+        let mut entry = HistoryEntry {
+            uptime_ms: (loop_timing::now_us() / 1000) as u32,
+            boot: self.ring.boot,
+            ip,
+            port,
+            command,
+            status: status as u8,
+            count: count as u8,
+            ..EMPTY
+        };
+        entry.params[..count].copy_from_slice(&params[..count]);
+        self.ring.entries[self.ring.head] = entry;
+        self.ring.head = (self.ring.head + 1) % HISTORY_SIZE;
+        self.ring.len = (self.ring.len + 1).min(HISTORY_SIZE);
+    }
+
+    // An empty history still has a chunk, to say so
+    pub fn chunks(&self) -> u8 {
+        match self.ring.len {
+            0 => 1,
+            len => ((len - 1) / HISTORY_CHUNK + 1) as u8,
+        }
+    }
+
+    // The chunk'th HISTORY_CHUNK entries counting from the oldest, None past the last chunk
+    pub fn chunk(&self, chunk: u8) -> Option<ReplyPayload> {
+        if chunk >= self.chunks() {
+            return None;
+        }
+        let oldest = (self.ring.head + HISTORY_SIZE - self.ring.len) % HISTORY_SIZE;
+        let first = chunk as usize * HISTORY_CHUNK;
+        let entries = (first..self.ring.len.min(first + HISTORY_CHUNK))
+            .map(|n| self.ring.entries[(oldest + n) % HISTORY_SIZE])
+            .collect();
+        Some(ReplyPayload::History {
+            boot: self.ring.boot,
+            total: self.ring.len as u8,
+            chunk,
+            chunks: self.chunks(),
+            entries,
+        })
+    }
+
+    pub fn next_sequence(&mut self) -> u16 {
+        self.sequence = self.sequence.wrapping_add(1);
+        self.sequence
+    }
+}
+
+// What is kept of a command besides its byte: the angles of a move, otherwise the index, slot or values it was sent
+// with. None for the polls that aren't kept
+pub fn parameters(control: &ControlPacket) -> Option<Vec<u16>> {
+    let params = match *control {
+        ControlPacket::Telemetry { .. } | ControlPacket::Status | ControlPacket::History { .. } => return None,
+        ControlPacket::SetAngles(ref angles) | ControlPacket::Pose { ref angles, .. } => angles.clone(),
+        ControlPacket::MoveJoint { index, angle, speed } => vec![index as u16, angle, speed.unwrap_or(0)],
+        ControlPacket::Jog { index, delta_tenths } => vec![index as u16, delta_tenths as i16 as u16],
+        ControlPacket::Config(ConfigCommand::Servo(config)) => vec![
+            config.index as u16,
+            config.speed,
+            config.trim as u16,
+            config.reversed as u16,
+            config.safe_angle,
+        ],
+        ControlPacket::Config(ConfigCommand::Failsafe(_)) => vec![protocol::FAILSAFE_CONFIG_INDEX as u16],
+        ControlPacket::Config(ConfigCommand::Battery(_)) => vec![protocol::BATTERY_CONFIG_INDEX as u16],
+        ControlPacket::Config(ConfigCommand::Display(_)) => vec![protocol::DISPLAY_CONFIG_INDEX as u16],
+        ControlPacket::Config(ConfigCommand::Buzzer(config)) => {
+            vec![protocol::BUZZER_CONFIG_INDEX as u16, config.muted as u16]
+        }
+        ControlPacket::Limits { index, min_limit, max_limit } => vec![index as u16, min_limit, max_limit],
+        ControlPacket::Calibration { index, command } => {
+            let value = match command {
+                CalibrationCommand::SetDuty(value)
+                | CalibrationCommand::FeedbackMin(value)
+                | CalibrationCommand::FeedbackMax(value)
+                | CalibrationCommand::SetPulse(value) => value,
+                _ => 0,
+            };
+            vec![index as u16, command.to_byte() as u16, value]
+        }
+        ControlPacket::UploadTrajectory(ref frames) => vec![frames.len() as u16],
+        ControlPacket::StoreTrajectory { slot } | ControlPacket::ClearPreset { slot } => vec![slot as u16],
+        ControlPacket::SavePreset { slot, .. } => vec![slot as u16],
+        ControlPacket::PlayTrajectory { slot, looping, .. } => vec![slot as u16, looping as u16],
+        ControlPacket::RecallPreset { slot, duration_ms } => vec![slot as u16, duration_ms],
+        ControlPacket::Subscribe { interval_ms } => vec![interval_ms],
+        ControlPacket::Gripper(Some((mode, percent))) => vec![GripperMode::to_byte(mode) as u16, percent as u16],
+        ControlPacket::SpeedScale(Some((percent, _))) => vec![percent as u16],
+        ControlPacket::Mirror(Some((mirrored, _))) => vec![mirrored as u16],
+        ControlPacket::LogLevel(Some(level)) => vec![level as u16],
+        ControlPacket::Current(Some(CurrentCommand::Clear { index })) => {
+            vec![protocol::CURRENT_CLEAR as u16, index as u16]
+        }
+        ControlPacket::Current(Some(CurrentCommand::Calibrate { sensor, .. })) => {
+            vec![protocol::CURRENT_CALIBRATE as u16, sensor as u16]
+        }
+        _ => Vec::new(),
+    };
+    Some(params)
+}
+
+// Only ever borrowed once, TAKEN sees to that. Every field is a plain integer, so whatever a restart or power-on left
+// there reads as some Ring, and the magic tells a kept one from noise
+#[cfg(not(feature = "sim"))]
+fn retained() -> &'static mut Ring {
+    unsafe { &mut *std::ptr::addr_of_mut!(RETAINED).cast::<Ring>() }
+}
+
+// The host has no RTC memory, the history starts empty each run
+#[cfg(feature = "sim")]
+fn retained() -> &'static mut Ring {
+    unwritten()
+}
+
+#[cfg(feature = "sim")]
+fn unwritten() -> &'static mut Ring {
+    Box::leak(Box::new(Ring { magic: 0, boot: 0, head: 0, len: 0, entries: [EMPTY; HISTORY_SIZE] }))
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+
+    const CLIENT: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20)), 4210);
+
+    // Every entry read back chunk by chunk
+    fn entries(history: &History) -> Vec<HistoryEntry> {
+        let mut entries = Vec::new();
+        for chunk in 0..history.chunks() {
+            let Some(ReplyPayload::History { entries: read, .. }) = history.chunk(chunk) else {
+                panic!("no chunk {}", chunk);
+            };
+            entries.extend(read);
+        }
+        entries
+    }
+
+    #[test]
+    fn the_ring_keeps_the_newest_commands_oldest_first() {
+        let mut history = History::new();
+        assert_eq!(history.chunks(), 1);
+        assert!(entries(&history).is_empty());
+        for n in 0..HISTORY_SIZE as u16 + 3 {
+            history.record(CLIENT, protocol::MOVE_COMMAND, &[n], Status::Ok);
+        }
+        let entries = entries(&history);
+        assert_eq!(entries.len(), HISTORY_SIZE);
+        assert_eq!(entries[0].params[0], 3);
+        assert_eq!(entries[HISTORY_SIZE - 1].params[0], HISTORY_SIZE as u16 + 2);
+        assert_eq!((entries[0].ip, entries[0].port), ([192, 168, 1, 20], 4210));
+        assert_eq!(history.chunks() as usize, HISTORY_SIZE / HISTORY_CHUNK);
+        assert_eq!(history.chunk(history.chunks()), None);
+    }
+
+    #[test]
+    fn parameters_past_the_entry_are_dropped() {
+        let mut history = History::new();
+        history.record(CLIENT, protocol::MOVE_COMMAND, &[1; HISTORY_PARAMS + 2], Status::BadLength);
+        let entry = entries(&history)[0];
+        assert_eq!((entry.count as usize, entry.status), (HISTORY_PARAMS, Status::BadLength as u8));
+    }
+
+    #[test]
+    fn a_kept_ring_counts_the_restart_and_noise_is_cleared() {
+        let mut history = History::new();
+        history.record(CLIENT, protocol::JOG_COMMAND, &[1, 5], Status::Clamped);
+        let history = History::load(history.ring);
+        let Some(ReplyPayload::History { boot: 1, total: 1, entries, .. }) = history.chunk(0) else {
+            panic!("not kept");
+        };
+        assert_eq!(entries[0].boot, 0);
+        history.ring.head = HISTORY_SIZE;
+        let history = History::load(history.ring);
+        let empty = ReplyPayload::History { boot: 0, total: 0, chunk: 0, chunks: 1, entries: vec![] };
+        assert_eq!(history.chunk(0), Some(empty));
+    }
+
+    #[test]
+    #[should_panic(expected = "taken twice")]
+    fn the_retained_ring_has_one_holder() {
+        let _history = History::take();
+        History::take();
+    }
+
+    #[test]
+    fn polls_are_left_out() {
+        assert_eq!(parameters(&ControlPacket::Status), None);
+        assert_eq!(parameters(&ControlPacket::History { chunk: None }), None);
+        let joint = ControlPacket::MoveJoint { index: 2, angle: 905, speed: Some(30) };
+        assert_eq!(parameters(&joint), Some(vec![2, 905, 30]));
+        assert_eq!(parameters(&ControlPacket::Jog { index: 1, delta_tenths: -10 }), Some(vec![1, 0xFFF6]));
+        assert_eq!(parameters(&ControlPacket::EStop), Some(vec![]));
+    }
+}
@@ -0,0 +1,284 @@
+// HTTP endpoint beside the UDP protocol, each request goes through the command queue exactly as a packet would
+//   GET /status     every joint with the address, RSSI, uptime, battery, e-stop and time
+//   GET /servo/<n>  one joint
+//   POST /pose      a JSON array of angles, one per joint, to a tenth of a degree
+//   /ws             the WebSocket control channel, see ws.rs
+use std::net::Ipv4Addr;
+use std::sync::mpsc::SyncSender;
+use std::sync::{Arc, Mutex};
+
+use embedded_svc::http::server::{HandlerResult, Request};
+use embedded_svc::http::Method;
+use embedded_svc::io::Write;
+use esp_idf_svc::http::server::{Configuration as HttpConfiguration, EspHttpConnection, EspHttpServer};
+use log::{error, info};
+
+use crate::auth::Authenticator;
+use crate::joints::JOINTS;
+use crate::link::Link;
+use crate::network::{self, Command, RequestError};
+use crate::protocol::{self, ControlPacket, JointStatus, ReplyPacket, ReplyPayload, Status};
+use crate::servo::{self, Tenths};
+use crate::sleep::WakeReason;
+use crate::ws;
+
+const MAX_BODY_SIZE: usize = 128; // A pose of five angles with generous spacing
+
+// None if the server couldn't be started, the limb still works over UDP. Poses are refused when commands need
+// authentication, there is no tag to check on a request
+pub fn start(
+    port: u16,
+    commands: SyncSender<Command>,
+    link: Link,
+    authenticator: Option<Arc<Mutex<Authenticator>>>,
+) -> Option<EspHttpServer<'static>> {
+    let authenticated = authenticator.is_some();
+    let mut server = match EspHttpServer::new(&HttpConfiguration {
+        http_port: port,
+        uri_match_wildcard: true,
+        ..Default::default()
+    }) {
+        Ok(server) => server,
+        Err(e) => {
+            error!("Failed to start the HTTP server: {}", e);
+            return None;
+        }
+    };
+    let status_commands = commands.clone();
+    let servo_commands = commands.clone();
+    let ws_commands = commands.clone();
+    let registered = server
+        .fn_handler("/status", Method::Get, move |request| {
+            let (code, body) = match command(&status_commands, ControlPacket::Status) {
+                Ok(reply) => match status_json(&reply.payload, link.get().ip) {
+                    Some(body) => (200, body),
+                    None => (500, error_json("unexpected reply")),
+                },
+                Err(failure) => failure,
+            };
+            respond(request, code, &body)
+        })
+        .and_then(|server| {
+            server.fn_handler("/servo/*", Method::Get, move |request| {
+                let path = request.uri().trim_start_matches("/servo/");
+                let index = path.split('?').next().and_then(|index| index.parse::<usize>().ok());
+                let (code, body) = match (index, command(&servo_commands, ControlPacket::Status)) {
+                    (_, Err(failure)) => failure,
+                    (Some(index), Ok(ReplyPacket { payload: ReplyPayload::Status { joints, .. }, .. })) => {
+                        match joints.get(index) {
+                            Some(joint) => (200, joint_json(index, joint)),
+                            None => (404, error_json("no such servo")),
+                        }
+                    }
+                    (None, Ok(_)) => (404, error_json("servo index must be a number")),
+                    (Some(_), Ok(_)) => (500, error_json("unexpected reply")),
+                };
+                respond(request, code, &body)
+            })
+        })
+        .and_then(|server| {
+            server.fn_handler("/pose", Method::Post, move |mut request| {
+                if authenticated {
+                    return respond(request, 403, &error_json("commands need an authenticated UDP packet"));
+                }
+                let mut body = [0u8; MAX_BODY_SIZE];
+                let mut len = 0;
+                while len < body.len() {
+                    match request.read(&mut body[len..])? {
+                        0 => break,
+                        read => len += read,
+                    }
+                }
+                let angles = std::str::from_utf8(&body[..len]).ok().and_then(parse_angles);
+                let (code, body) = match angles {
+                    None => (400, error_json("expected a JSON array of angles")),
+                    Some(angles) => match command(&commands, ControlPacket::SetAngles(angles)) {
+                        Ok(reply) => (http_status(reply.status), pose_json(&reply)),
+                        Err(failure) => failure,
+                    },
+                };
+                respond(request, code, &body)
+            })
+        })
+        .and_then(|server| ws::register(server, ws_commands, authenticator));
+    match registered {
+        Ok(_) => {
+            info!("HTTP server listening on port {}", port);
+            Some(server)
+        }
+        Err(e) => {
+            error!("Failed to register the HTTP handlers: {}", e);
+            None
+        }
+    }
+}
+
+// The control task's answer, or the HTTP status and body to reply with instead
+fn command(commands: &SyncSender<Command>, packet: ControlPacket) -> Result<ReplyPacket, (u16, String)> {
+    network::request(commands, network::NO_ADDR, packet).map_err(|e| match e {
+        RequestError::Full => (503, error_json("command queue full")),
+        RequestError::Stopped => (503, error_json("control task stopped")),
+        RequestError::TimedOut => (504, error_json("no reply from the control task")),
+    })
+}
+
+// Every joint with the address, RSSI, uptime, battery, chip temperature, flags, wake reason, time, loop timing and
+// motion queue, and the joints whose channels couldn't be set up, None for another reply. Also what MQTT publishes
+pub fn status_json(payload: &ReplyPayload, ip: Ipv4Addr) -> Option<String> {
+    let ReplyPayload::Status {
+        flags,
+        rssi,
+        uptime_s,
+        battery_mv,
+        ref joints,
+        restored,
+        mirrored,
+        time_ms,
+        timing,
+        queued,
+        executing,
+        ref unavailable,
+        ref i2c_devices,
+        temperature_c,
+        thermal,
+        wake_reason,
+    } = *payload
+    else {
+        return None;
+    };
+    let restored = match restored {
+        protocol::RESTORED_TRUSTED => "trusted",
+        protocol::RESTORED_APPROXIMATE => "approximate",
+        _ => "none",
+    };
+    let joints: Vec<String> = joints.iter().enumerate().map(|(index, joint)| joint_json(index, joint)).collect();
+    // Null until the clock is synchronized
+    let time_ms = time_ms.map_or_else(|| "null".to_string(), |time_ms| time_ms.to_string());
+    // Means and maxima in the order of protocol::LOOP_PHASES
+    let means: Vec<String> = timing.phases.iter().map(|phase| phase.mean_us.to_string()).collect();
+    let maxima: Vec<String> = timing.phases.iter().map(|phase| phase.max_us.to_string()).collect();
+    let executing = executing.map_or_else(|| "null".to_string(), |id| id.to_string());
+    let temperature_c = temperature_c.map_or_else(|| "null".to_string(), |celsius| celsius.to_string());
+    let thermal = match thermal {
+        protocol::THERMAL_CRITICAL => "critical",
+        protocol::THERMAL_WARM => "warm",
+        _ => "normal",
+    };
+    let wake_reason = match wake_reason {
+        reason if reason == WakeReason::Button as u8 => "button",
+        reason if reason == WakeReason::Timer as u8 => "timer",
+        reason if reason == WakeReason::Other as u8 => "other",
+        _ => "none",
+    };
+    let unavailable: Vec<String> = unavailable
+        .iter()
+        .map(|(index, error)| format!("{{\"index\":{},\"error\":\"{}\"}}", index, error))
+        .collect();
+    let i2c_devices: Vec<String> = i2c_devices.iter().map(|address| address.to_string()).collect();
+    Some(format!(
+        concat!(
+            "{{\"ip\":\"{}\",\"rssi\":{},\"uptime_s\":{},\"battery_mv\":{},\"temperature_c\":{},\"thermal\":\"{}\",",
+            "\"estop\":{},\"low_battery\":{},\"paused\":{},\"mqtt\":{},\"restored_pose\":\"{}\",\"mirrored\":{},",
+            "\"wake\":\"{}\",",
+            "\"time_ms\":{},\"loop\":{{\"passes\":{},\"slow\":{},\"mean_us\":[{}],\"max_us\":[{}]}},",
+            "\"queue\":{{\"depth\":{},\"executing\":{}}},\"joints\":[{}],\"unavailable\":[{}],\"i2c\":[{}]}}"
+        ),
+        ip,
+        rssi,
+        uptime_s,
+        battery_mv,
+        temperature_c,
+        thermal,
+        flags & protocol::TELEMETRY_ESTOP_FLAG != 0,
+        flags & protocol::TELEMETRY_LOW_BATTERY_FLAG != 0,
+        flags & protocol::TELEMETRY_PAUSED_FLAG != 0,
+        flags & protocol::TELEMETRY_MQTT_FLAG != 0,
+        restored,
+        mirrored,
+        wake_reason,
+        time_ms,
+        timing.passes,
+        timing.slow,
+        means.join(","),
+        maxima.join(","),
+        queued,
+        executing,
+        joints.join(","),
+        unavailable.join(","),
+        i2c_devices.join(",")
+    ))
+}
+
+fn respond(request: Request<&mut EspHttpConnection>, code: u16, body: &str) -> HandlerResult {
+    request
+        .into_response(code, None, &[("Content-Type", "application/json")])?
+        .write_all(body.as_bytes())?;
+    Ok(())
+}
+
+// Applied, even if clamped, is a success. Refusals while the limb can't move are conflicts with its state
+fn http_status(status: Status) -> u16 {
+    match status {
+        Status::Ok | Status::Clamped => 200,
+        Status::BadLength | Status::BadCommand | Status::BadArgument | Status::BadCrc => 400,
+        Status::Unauthorized => 403,
+        Status::Throttled => 429,
+        Status::EStopped
+        | Status::ButtonEStopped
+        | Status::Busy
+        | Status::ShutDown
+        | Status::LowBattery
+        | Status::Updating
+        | Status::Paused
+        | Status::Cancelled
+        | Status::Overloaded
+        | Status::Overheated => 409,
+        Status::HardwareError => 500,
+    }
+}
+
+fn joint_json(index: usize, joint: &JointStatus) -> String {
+    format!(
+        "{{\"index\":{},\"name\":\"{}\",\"angle\":{},\"goal\":{},\"status\":{}}}",
+        index,
+        JOINTS.get(index).map_or("", |config| config.name),
+        Tenths(joint.angle),
+        Tenths(joint.goal),
+        joint.status
+    )
+}
+
+// The status name and the angles the servos were left heading to
+fn pose_json(reply: &ReplyPacket) -> String {
+    let angles: Vec<String> = match reply.payload {
+        ReplyPayload::Positions(ref positions) => {
+            positions.iter().map(|position| Tenths(position.angle).to_string()).collect()
+        }
+        ReplyPayload::LegacyPositions(ref angles) => angles.iter().map(|&angle| Tenths(angle).to_string()).collect(),
+        _ => Vec::new(),
+    };
+    format!("{{\"status\":\"{:?}\",\"angles\":[{}]}}", reply.status, angles.join(","))
+}
+
+fn error_json(message: &str) -> String {
+    format!("{{\"error\":\"{}\"}}", message)
+}
+
+// "[90, 45.5, 120]" as tenths, whitespace anywhere. Only numbers with at most one decimal that fit an angle, the
+// control task checks the count
+fn parse_angles(body: &str) -> Option<Vec<u16>> {
+    let inner = body.trim().strip_prefix('[')?.strip_suffix(']')?.trim();
+    if inner.is_empty() {
+        return Some(Vec::new());
+    }
+    inner.split(',').map(|angle| parse_tenths(angle.trim())).collect()
+}
+
+fn parse_tenths(angle: &str) -> Option<u16> {
+    let (whole, tenth) = match angle.split_once('.') {
+        Some((whole, tenth)) if tenth.len() == 1 => (whole, tenth.parse::<u16>().ok()?),
+        Some(_) => return None,
+        None => (angle, 0),
+    };
+    whole.parse::<u16>().ok()?.checked_mul(servo::TENTHS)?.checked_add(tenth)
+}
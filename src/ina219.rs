@@ -0,0 +1,32 @@
+// INA219 current monitor on the shared I2C bus, only its shunt voltage is read. The bus voltage and the chip's own
+// current calculation are left alone, the current calibration maps the shunt reading onto milliamps
+use log::info;
+
+use crate::backend::DriverError;
+use crate::current::Reader;
+use crate::shared_i2c::SharedI2c;
+
+// Registers
+const CONFIGURATION: u8 = 0x00;
+const SHUNT_VOLTAGE: u8 = 0x01;
+
+// The power-on configuration: 32 V bus range, +-320 mV shunt range, 12 bit samples, both converted continuously
+const DEFAULT_CONFIGURATION: u16 = 0x399F;
+const SHUNT_LSB_UV: i32 = 10;
+
+// Puts the chip back in its power-on configuration, in case something on the bus changed it
+pub fn init(bus: &SharedI2c, address: u8) -> Result<(), DriverError> {
+    let [high, low] = DEFAULT_CONFIGURATION.to_be_bytes();
+    bus.write(address, &[CONFIGURATION, high, low])?;
+    info!("INA219 at {:#04x} sampling continuously", address);
+    Ok(())
+}
+
+// The signed shunt voltage in microvolts
+pub fn reader(bus: SharedI2c, address: u8) -> Reader {
+    Box::new(move || {
+        let mut bytes = [0u8; 2];
+        bus.write_read(address, &[SHUNT_VOLTAGE], &mut bytes)?;
+        Ok(i16::from_be_bytes(bytes) as i32 * SHUNT_LSB_UV)
+    })
+}
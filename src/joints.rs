@@ -0,0 +1,68 @@
+// The arm's joints in protocol order, add or remove entries to build for a different arm.
+// Move and pose commands carry one angle per joint and keyframes are sized from the table.
+// Which GPIO drives each joint's LEDC channel is the board's, see boards.
+use crate::settings::Calibration;
+use crate::{MIUZEI_MINI_MAX_PULSE_US, MIUZEI_MINI_MIN_PULSE_US};
+
+pub struct JointConfig {
+    pub name: &'static str,
+    pub timer: u8,         // LEDC timer the channel runs from, 0 or 1, see servo_timer0_hz. The PCA9685 is 50 Hz
+    pub min_pulse_us: u16, // Pulse width at 0 degrees, until calibrated
+    pub max_pulse_us: u16, // Pulse width at max_angle
+    pub max_angle: u16,
+}
+
+impl JointConfig {
+    // Used until the joint has a calibration stored in NVS, whose duties are fractions of the `frame_hz` frame
+    pub fn default_calibration(&self, frame_hz: u32) -> Calibration {
+        let frame_us = 1e6 / frame_hz.max(1) as f32;
+        Calibration::new(self.min_pulse_us as f32 / frame_us, self.max_pulse_us as f32 / frame_us, self.max_angle)
+    }
+}
+
+pub const JOINTS: &[JointConfig] = &[
+    JointConfig {
+        name: "Top",
+        timer: 0,
+        min_pulse_us: MIUZEI_MINI_MIN_PULSE_US,
+        max_pulse_us: MIUZEI_MINI_MAX_PULSE_US,
+        max_angle: 180,
+    },
+    JointConfig {
+        name: "Shoulder",
+        timer: 0,
+        min_pulse_us: MIUZEI_MINI_MIN_PULSE_US,
+        max_pulse_us: MIUZEI_MINI_MAX_PULSE_US,
+        max_angle: 180,
+    },
+    JointConfig {
+        name: "Upper Arm",
+        timer: 0,
+        min_pulse_us: MIUZEI_MINI_MIN_PULSE_US,
+        max_pulse_us: MIUZEI_MINI_MAX_PULSE_US,
+        max_angle: 180,
+    },
+    JointConfig {
+        name: "Elbow",
+        timer: 0,
+        min_pulse_us: MIUZEI_MINI_MIN_PULSE_US,
+        max_pulse_us: MIUZEI_MINI_MAX_PULSE_US,
+        max_angle: 180,
+    },
+    JointConfig {
+        name: "Lower Arm",
+        timer: 0,
+        min_pulse_us: MIUZEI_MINI_MIN_PULSE_US,
+        max_pulse_us: MIUZEI_MINI_MAX_PULSE_US,
+        max_angle: 180,
+    },
+];
+
+// Not a joint, the optional gripper's servo when gripper_backend is set. Its LEDC channel is the next after the joints
+pub const GRIPPER: JointConfig = JointConfig {
+    name: "Gripper",
+    timer: 0,
+    min_pulse_us: MIUZEI_MINI_MIN_PULSE_US,
+    max_pulse_us: MIUZEI_MINI_MAX_PULSE_US,
+    max_angle: 180,
+};
@@ -0,0 +1,113 @@
+// Status LED, blinking a pattern for the state the limb is in. The control loop picks the pattern and updates the
+// LED every pass, so it needs no timer of its own
+use std::time::{Duration, Instant};
+
+#[cfg(not(feature = "sim"))]
+use esp_idf_hal::gpio::{AnyOutputPin, Output, PinDriver};
+#[cfg(not(feature = "sim"))]
+use esp_idf_sys::EspError;
+#[cfg(not(feature = "sim"))]
+use log::error;
+#[cfg(feature = "sim")]
+use log::info;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LedPattern {
+    Off,
+    Solid,
+    SlowBlink,
+    FastBlink,
+    DoubleBlink,
+    Strobe,
+    #[cfg_attr(feature = "sim", allow(dead_code))] // Only start-up on hardware can fail
+    Fault,
+}
+
+impl LedPattern {
+    // Levels and how long each is held in ms, repeated for as long as the pattern is shown.
+    // A new pattern only needs its steps here
+    fn steps(self) -> &'static [(bool, u16)] {
+        match self {
+            LedPattern::Off => &[(false, 1000)],
+            LedPattern::Solid => &[(true, 1000)],
+            LedPattern::SlowBlink => &[(true, 500), (false, 500)],
+            LedPattern::FastBlink => &[(true, 100), (false, 100)],
+            LedPattern::DoubleBlink => &[(true, 100), (false, 150), (true, 100), (false, 650)],
+            LedPattern::Strobe => &[(true, 30), (false, 50)],
+            LedPattern::Fault => &[(true, 100), (false, 100), (true, 100), (false, 100), (true, 100), (false, 1000)],
+        }
+    }
+}
+
+pub struct StatusLed {
+    #[cfg(not(feature = "sim"))]
+    driver: Option<PinDriver<'static, AnyOutputPin, Output>>, // None without an LED
+    pattern: LedPattern,
+    step: usize,
+    step_started: Instant,
+    level: Option<bool>, // Last level written, None until the first
+}
+
+impl StatusLed {
+    // Stands in when no pin is configured
+    pub fn none() -> StatusLed {
+        StatusLed {
+            #[cfg(not(feature = "sim"))]
+            driver: None,
+            pattern: LedPattern::Off,
+            step: 0,
+            step_started: Instant::now(),
+            level: None,
+        }
+    }
+
+    #[cfg(not(feature = "sim"))]
+    pub fn new(pin: i32) -> Result<StatusLed, EspError> {
+        // Safety: the LED pin is only taken here and nothing else drives it
+        let driver = PinDriver::output(unsafe { AnyOutputPin::new(pin) })?;
+        Ok(StatusLed { driver: Some(driver), ..StatusLed::none() })
+    }
+
+    // Starts the pattern from its first step, unless it is already showing
+    pub fn set_pattern(&mut self, pattern: LedPattern, now: Instant) {
+        if pattern == self.pattern {
+            return;
+        }
+        #[cfg(feature = "sim")]
+        info!("Status LED: {:?}", pattern);
+        self.pattern = pattern;
+        self.step = 0;
+        self.step_started = now;
+        self.level = None;
+    }
+
+    // Moves on to the next step once the current one has been held long enough, writing the level when it changes
+    pub fn update(&mut self, now: Instant) {
+        let steps = self.pattern.steps();
+        let (_, hold_ms) = steps[self.step];
+        if now.duration_since(self.step_started) >= Duration::from_millis(hold_ms as u64) {
+            self.step = (self.step + 1) % steps.len();
+            self.step_started = now;
+        }
+        let (level, _) = steps[self.step];
+        if self.level != Some(level) {
+            self.write(level);
+            self.level = Some(level);
+        }
+    }
+
+    #[cfg(not(feature = "sim"))]
+    fn write(&mut self, level: bool) {
+        if let Some(driver) = self.driver.as_mut() {
+            let result = if level { driver.set_high() } else { driver.set_low() };
+            match result {
+                Ok(_) => {},
+                Err(e) => error!("Failed to set the status LED: {}", e),
+            }
+        }
+    }
+
+    // Only the pattern is logged, every level would flood the log
+    #[cfg(feature = "sim")]
+    fn write(&mut self, _level: bool) {}
+}
@@ -0,0 +1,78 @@
+// WiFi link state, written by the reconnect task and watched by the control and network tasks
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LinkStatus {
+    pub up: bool,
+    pub attempt: u32, // Reconnect attempts since the link dropped, 0 while it is up
+    pub ip: Ipv4Addr,
+    pub connections: u32, // Bumped each time the link comes back, so the socket can be re-bound
+}
+
+impl LinkStatus {
+    // Started without the network and still waiting on it, the address is only set by joining
+    pub fn never_joined(&self) -> bool {
+        !self.up && self.ip.is_unspecified()
+    }
+}
+
+#[derive(Clone)]
+pub struct Link {
+    status: Arc<Mutex<LinkStatus>>,
+    failure: Arc<Mutex<String>>, // Why joining last failed while the network was never joined, for the display
+}
+
+impl Link {
+    // The link starts up, start-up marks it down when WiFi couldn't be joined
+    pub fn new(ip: Ipv4Addr) -> Link {
+        Link {
+            status: Arc::new(Mutex::new(LinkStatus { up: true, attempt: 0, ip, connections: 0 })),
+            failure: Arc::new(Mutex::new(String::new())),
+        }
+    }
+
+    pub fn get(&self) -> LinkStatus {
+        *self.status.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn get_failure(&self) -> String {
+        self.failure.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+    }
+
+    // Set before the attempt is, so whoever sees the attempt change finds the failure that goes with it
+    #[cfg(not(feature = "sim"))]
+    pub fn set_failed(&self, attempt: u32, failure: String) {
+        *self.failure.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = failure;
+        self.set_down(attempt);
+    }
+
+    #[cfg(not(feature = "sim"))]
+    pub fn set_down(&self, attempt: u32) {
+        let mut status = self.status.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        status.up = false;
+        status.attempt = attempt;
+    }
+
+    #[cfg(not(feature = "sim"))]
+    pub fn set_up(&self, ip: Ipv4Addr) {
+        let mut status = self.status.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        status.up = true;
+        status.attempt = 0;
+        status.ip = ip;
+        status.connections = status.connections.wrapping_add(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_a_link_down_without_an_address_never_joined() {
+        let status = LinkStatus { up: false, attempt: 3, ip: Ipv4Addr::UNSPECIFIED, connections: 0 };
+        assert!(status.never_joined());
+        assert!(!LinkStatus { up: true, ..status }.never_joined());
+        assert!(!LinkStatus { ip: Ipv4Addr::new(192, 168, 1, 20), ..status }.never_joined());
+    }
+}
@@ -0,0 +1,148 @@
+// Control loop timing, to tell which part of a pass holds up the servos. Maxima are of the last full window and the
+// one in progress, so a spike stays visible for at least loop_window_s
+#[cfg(feature = "sim")]
+use std::sync::OnceLock;
+#[cfg(feature = "sim")]
+use std::time::Instant;
+
+use crate::protocol::{LoopTiming, PhaseTiming, LOOP_PHASES};
+
+// The parts of a pass that are timed, besides the pass as a whole
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    Recv,     // Waiting on the command queue
+    Dispatch, // Carrying out the commands taken
+    Servos,   // Stepping and writing the servos on a tick
+    Display,  // Rendering the LED and display, the display task does the flush
+}
+
+impl Phase {
+    // The pass as a whole comes first in LoopTiming
+    fn index(self) -> usize {
+        match self {
+            Phase::Recv => 1,
+            Phase::Dispatch => 2,
+            Phase::Servos => 3,
+            Phase::Display => 4,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Counter {
+    total_us: u64,
+    max_us: u32,      // In the window in progress
+    last_max_us: u32, // In the last full window
+}
+
+impl Counter {
+    fn add(&mut self, us: u32) {
+        self.total_us += us as u64;
+        self.max_us = self.max_us.max(us);
+    }
+}
+
+pub struct LoopTimer {
+    window_us: u64,
+    slow_us: u32,
+    window_start: u64,
+    passes: u32,
+    slow: u32,
+    counters: [Counter; LOOP_PHASES],
+}
+
+impl LoopTimer {
+    pub fn new(window_s: u32, slow_us: u32) -> LoopTimer {
+        LoopTimer {
+            window_us: window_s.max(1) as u64 * 1_000_000,
+            slow_us,
+            window_start: now_us(),
+            passes: 0,
+            slow: 0,
+            counters: [Counter::default(); LOOP_PHASES],
+        }
+    }
+
+    // Adds the time since `started`, a reading from now_us(). Returns now, for timing the next phase from
+    pub fn record(&mut self, phase: Phase, started: u64) -> u64 {
+        let now = now_us();
+        self.counters[phase.index()].add(now.saturating_sub(started) as u32);
+        now
+    }
+
+    // Ends a pass begun at `started`, moving the window on if it is over
+    pub fn end_pass(&mut self, started: u64) {
+        let now = now_us();
+        let pass_us = now.saturating_sub(started) as u32;
+        self.counters[0].add(pass_us);
+        self.passes = self.passes.wrapping_add(1);
+        if pass_us > self.slow_us {
+            self.slow = self.slow.wrapping_add(1);
+        }
+        if now.saturating_sub(self.window_start) >= self.window_us {
+            self.window_start = now;
+            for counter in self.counters.iter_mut() {
+                counter.last_max_us = counter.max_us;
+                counter.max_us = 0;
+            }
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = LoopTimer::new((self.window_us / 1_000_000) as u32, self.slow_us);
+    }
+
+    pub fn timing(&self) -> LoopTiming {
+        let mut phases = [PhaseTiming::default(); LOOP_PHASES];
+        for (phase, counter) in phases.iter_mut().zip(self.counters.iter()) {
+            *phase = PhaseTiming {
+                mean_us: match self.passes {
+                    0 => 0,
+                    passes => (counter.total_us / passes as u64).min(u32::MAX as u64) as u32,
+                },
+                max_us: counter.max_us.max(counter.last_max_us),
+            };
+        }
+        LoopTiming { passes: self.passes, slow: self.slow, phases }
+    }
+}
+
+// Microseconds since boot
+#[cfg(not(feature = "sim"))]
+pub fn now_us() -> u64 {
+    unsafe { esp_idf_sys::esp_timer_get_time() as u64 }
+}
+
+// Microseconds since the first reading
+#[cfg(feature = "sim")]
+pub fn now_us() -> u64 {
+    static STARTED: OnceLock<Instant> = OnceLock::new();
+    STARTED.get_or_init(Instant::now).elapsed().as_micros() as u64
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn passes_and_phases_are_timed_and_slow_ones_counted() {
+        let mut timer = LoopTimer::new(10, 1000);
+        let started = now_us();
+        thread::sleep(Duration::from_millis(2));
+        let dispatched = timer.record(Phase::Dispatch, started);
+        timer.end_pass(started);
+        timer.end_pass(now_us());
+        let timing = timer.timing();
+        assert_eq!((timing.passes, timing.slow), (2, 1));
+        assert!(timing.phases[0].max_us >= 2000);
+        assert!(timing.phases[Phase::Dispatch.index()].max_us >= 2000);
+        assert!(dispatched >= started + 2000);
+        assert_eq!(timing.phases[Phase::Recv.index()], PhaseTiming::default());
+
+        timer.reset();
+        assert_eq!(timer.timing(), LoopTiming::default());
+    }
+}
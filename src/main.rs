@@ -1,42 +1,113 @@
 #![feature(let_chains)]
 
 // Modules
+mod auth;
+mod backend;
+mod battery;
+#[cfg(all(feature = "ble", not(feature = "sim")))]
+mod ble;
+mod boards;
+#[cfg(not(feature = "sim"))]
+mod boot;
+mod button;
+mod buzzer;
+mod calibration;
+mod clock;
+mod controller;
+#[cfg(not(feature = "sim"))]
+mod crash;
+mod current;
+#[cfg(not(feature = "sim"))]
 mod display;
+#[cfg(not(feature = "sim"))]
+mod espnow;
+mod failsafe;
+mod feedback;
+mod gripper;
+#[cfg(not(feature = "sim"))]
+mod hardware;
+mod heartbeat;
+mod history;
+#[cfg(not(feature = "sim"))]
+mod http_api;
+#[cfg(not(feature = "sim"))]
+mod ina219;
+mod led;
+mod joints;
+mod link;
+mod loop_timing;
+mod mirror;
+mod motion_queue;
+#[cfg(all(feature = "mqtt", not(feature = "sim")))]
+mod mqtt;
+mod network;
+mod ota;
+mod pages;
+#[cfg(not(feature = "sim"))]
+mod pca9685;
+mod preset;
+mod protocol;
+#[cfg(not(feature = "sim"))]
+mod provisioning;
+mod qr;
+mod rate_limit;
+mod remote_log;
+mod self_test;
+mod sequence;
+#[cfg(not(feature = "sim"))]
+mod serial;
 mod servo;
+mod session;
+mod settings;
+mod sleep;
+#[cfg(not(feature = "sim"))]
+mod shared_i2c;
+#[cfg(feature = "sim")]
+mod sim;
+mod stats;
+mod tasks;
+mod thermal;
+mod tick;
+mod trajectory;
+mod watchdog;
 mod wifi_setup;
+#[cfg(not(feature = "sim"))]
+mod ws;
 
 // Standard library imports
-use std::borrow::Borrow;
-use std::io;
-use std::net::UdpSocket;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
 
 // Third-party imports
-use anyhow::Result;
-use embedded_graphics::mono_font::ascii::FONT_6X10;
-use embedded_graphics::mono_font::iso_8859_16::FONT_5X8;
-use embedded_graphics::mono_font::MonoTextStyleBuilder;
-use embedded_graphics::pixelcolor::BinaryColor;
-use log::{error, info};
-
-// ESP IDF related imports
-use esp_idf_hal::gpio::{OutputPin, PinDriver};
-use esp_idf_hal::i2c::{I2cConfig, I2cDriver};
-use esp_idf_hal::ledc::{config, LedcChannel, LedcDriver, LedcTimerDriver};
-use esp_idf_hal::peripheral::Peripheral;
-use esp_idf_hal::peripherals::Peripherals;
-use esp_idf_hal::timer::{config as HalTimerConfig, TimerDriver};
-use esp_idf_hal::units::FromValueType;
-use esp_idf_svc::eventloop::EspSystemEventLoop;
-use esp_idf_sys::nvs_flash_init;
+use log::{info, warn};
+#[cfg(not(feature = "sim"))]
+use esp_idf_hal::uart::UartDriver;
 
 // Custom Imports
-use crate::display::Display;
+use crate::auth::Authenticator;
+use crate::backend::DisplayBackend;
+use crate::battery::{Battery, BatteryConfig};
+use crate::button::{Button, EStopButton};
+use crate::buzzer::Buzzer;
+use crate::clock::Clock;
+use crate::controller::Controller;
+use crate::current::CurrentMonitor;
+use crate::gripper::Gripper;
+use crate::history::History;
+use crate::led::StatusLed;
+use crate::link::Link;
+use crate::ota::Updater;
+use crate::pages::DisplayConfig;
+use crate::settings::Settings;
+use crate::stats::Stats;
+use crate::tasks::DisplayChannel;
+use crate::tick::Tick;
 use servo::Servo;
-
-#[allow(unused_imports)]
-use esp_idf_sys as _;
-use ssd1306::prelude::{DisplayRotation, DisplaySize128x64};
-use ssd1306::{I2CDisplayInterface, Ssd1306};
+#[cfg(not(feature = "sim"))]
+use hardware::restart;
+#[cfg(feature = "sim")]
+use sim::restart;
 
 #[toml_cfg::toml_config]
 pub struct Config {
@@ -44,367 +115,535 @@ pub struct Config {
     wifi_ssid: &'static str,
     #[default("")]
     wifi_psk: &'static str,
+    // More networks to try, "ssid:password;ssid:password". The strongest in range is joined first
+    #[default("")]
+    wifi_networks: &'static str,
+    // Also broadcast an access point of the limb's own (mixed mode), off leaves the radio to the station
+    #[default(false)]
+    wifi_ap: bool,
+    #[default("limbcontroller")]
+    wifi_ap_ssid: &'static str,
+    // Empty for an open access point, otherwise 8 to 64 characters
+    #[default("")]
+    wifi_ap_psk: &'static str,
+    // 0 follows the channel the station's network was found on
+    #[default(0)]
+    wifi_ap_channel: u8,
+    // UDP port the control socket binds and mDNS advertises, must not be 0
+    #[default(8080)]
+    udp_port: u16,
+    // mDNS names, empty for defaults ending in the last three bytes of the MAC
+    #[default("")]
+    mdns_hostname: &'static str,
+    #[default("")]
+    mdns_instance: &'static str,
+    // Set to false for clients that don't send or expect the trailing CRC-8
+    #[default(true)]
+    packet_crc: bool,
+    // Pre-shared key for authenticating commands, empty leaves them open. A key stored in NVS takes precedence
+    #[default("")]
+    auth_key: &'static str,
+    // Answer pings with the bare positions, for clients that predate the versioned ping reply
+    #[default(false)]
+    legacy_ping: bool,
+    // Reply to moves and legacy pings with bare little-endian angles as protocol 1 did, for clients that decode them
+    // that way. Every other field is big-endian regardless
+    #[default(false)]
+    legacy_byte_order: bool,
+    // Seconds without a packet from the client holding the session before its claim lapses, 0 never lapses
+    #[default(30)]
+    session_timeout_s: u16,
+    // Motion commands a client may send per second once its burst is used up, 0 never throttles. Faster ones are
+    // refused as throttled
+    #[default(200)]
+    rate_limit_hz: u16,
+    // Motion commands a client may send at once before the rate limit applies
+    #[default(50)]
+    rate_limit_burst: u16,
+    // Datagrams read back to back before the network task sleeps a moment, so a flood can't starve the other tasks
+    #[default(32)]
+    udp_burst_max: u16,
+    // Heartbeats in a row the socket can fail to send before the subscription is dropped
+    #[default(3)]
+    heartbeat_max_failures: u32,
+    // Rate the servos are stepped towards their goals at
+    #[default(50)]
+    servo_tick_hz: u32,
+    // Output for each joint in order, "ledc" for its GPIO, "pca9685:<channel>" for a channel on the expander or "mock"
+    // for one that drives nothing and logs its duties, to bring up a bare board. Joints past the end of the list use
+    // LEDC
+    #[default("ledc,ledc,ledc,ledc,ledc")]
+    servo_backends: &'static str,
+    // Frame rate and resolution, 8 to 16 bits, of LEDC timer 0, which the joints with timer 0 in joints.rs run from.
+    // A resolution finer than the rate allows is lowered
+    #[default(50)]
+    servo_timer0_hz: u32,
+    #[default(12)]
+    servo_timer0_bits: u8,
+    // The same for timer 1, only started if a joint is on it. Digital servos can take 333 Hz, analog ones want 50
+    #[default(333)]
+    servo_timer1_hz: u32,
+    #[default(14)]
+    servo_timer1_bits: u8,
+    // I2C address of the PCA9685, only used when a joint is on it
+    #[default(0x40)]
+    pca9685_address: u8,
+    // ADC1 GPIO (32 to 39 on the ESP32) reading each joint's potentiometer wiper, in joint order like servo_backends.
+    // Empty entries and joints past the end of the list have no feedback
+    #[default("")]
+    feedback_pins: &'static str,
+    // Degrees the measured angle may be off the driven one, and for how many ms, before the joint is reported stalled.
+    // 0 ms never reports a stall
+    #[default(10)]
+    feedback_tolerance_deg: u16,
+    #[default(1000)]
+    feedback_stall_ms: u16,
+    // Current sensor of each joint, in joint order like servo_backends: "ina219:<address>" for an INA219 on the I2C
+    // bus or "adc:<pin>" for a current sense amplifier on an ADC1 GPIO. Joints naming the same sensor share it, as the
+    // servos on one power rail do. Empty entries and joints past the end of the list have none
+    #[default("")]
+    current_sensors: &'static str,
+    // Milliamps a sensor may read, and for how many ms, before every joint on it is stopped and latched off until a
+    // current command clears it. 0 mA only monitors
+    #[default(1500)]
+    current_limit_ma: u16,
+    #[default(500)]
+    current_window_ms: u16,
+    // Shunt resistance of the INA219 boards, in milliohms, for sensors the current command hasn't calibrated
+    #[default(100)]
+    current_shunt_mohm: u16,
+    // Amplifier output at no current and milliamps per volt it rises by, for uncalibrated ADC sensors
+    #[default(0)]
+    current_adc_zero_mv: u16,
+    #[default(1000)]
+    current_adc_ma_per_v: u32,
+    // Output of the optional gripper, "ledc" for the next LEDC channel after the joints, "pca9685:<channel>" or "mock".
+    // Empty without a gripper
+    #[default("")]
+    gripper_backend: &'static str,
+    // Gripper servo angles at 0 and 100 percent closed, closed is below open for a servo mounted the other way round
+    #[default(30)]
+    gripper_open_angle: u16,
+    #[default(150)]
+    gripper_closed_angle: u16,
+    // Degrees per step and milliseconds of dwell at each step of a force-limited grip
+    #[default(2)]
+    gripper_step_deg: u16,
+    #[default(100)]
+    gripper_dwell_ms: u16,
+    // Interval teach mode samples the pose at, a recording fills the trajectory limit after that many changed samples
+    #[default(250)]
+    teach_sample_ms: u16,
+    // Degrees the self-test moves each servo off where it stands, and how long it holds each end of the move
+    #[default(5)]
+    self_test_deg: u16,
+    #[default(500)]
+    self_test_dwell_ms: u16,
+    // Percent of full speed every move runs at until a speed command keeps another in NVS, 1 to 100
+    #[default(100)]
+    speed_scale: u8,
+    // Mirror moves onto this limb until a mirror command keeps another setting in NVS, for the second of a mirrored
+    // pair. The joints reflected are the comma separated indices in mirror_joints
+    #[default(false)]
+    mirror: bool,
+    #[default("")]
+    mirror_joints: &'static str,
+    // Whether moves received while paused set the goals the limb heads for once resumed, otherwise they are refused
+    #[default(false)]
+    pause_queue_moves: bool,
+    // What the joints do at boot: "limp" stays off until the first command, "ramp" eases them to the power-on pose.
+    // Either way they are taken to start from their measured angle, or from the safe pose without feedback
+    #[default("limp")]
+    boot_behavior: &'static str,
+    #[default(3000)]
+    boot_ramp_ms: u16,
+    // Seconds between saves of the pose kept through a restart, 0 never keeps or restores it. A save is skipped until
+    // a joint has moved more than pose_save_deg from the last one, sparing the flash
+    #[default(30)]
+    pose_save_s: u16,
+    #[default(5)]
+    pose_save_deg: u16,
+    // ADC1 GPIO (32 to 39 on the ESP32) reading the battery through a divider, 0 for the board's, which may be none
+    #[default(0)]
+    battery_pin: u8,
+    // Battery millivolts per volt at the pin. This and the cutoff can be changed by the config command, which stores them
+    #[default(3000)]
+    battery_divider: u16,
+    // Below this the servos are parked and stopped, 0 only monitors. 6600 keeps a 2S LiPo above 3.3 V a cell
+    #[default(6600)]
+    battery_cutoff_mv: u16,
+    // How far above the cutoff the battery must recover before motion is allowed again
+    #[default(400)]
+    battery_hysteresis_mv: u16,
+    // Readings averaged, one is taken every 100 ms
+    #[default(16)]
+    battery_window: u8,
+    // Chip temperatures in degrees C past which a banner warns, and past which the servos are parked and motion is
+    // refused, 0 disables either. Each clears once the chip has cooled by the hysteresis. The sensor is on the die, so
+    // it reads well above the enclosure air
+    #[default(70)]
+    temperature_warn_c: u8,
+    #[default(85)]
+    temperature_critical_c: u8,
+    #[default(5)]
+    temperature_hysteresis_c: u8,
+    // GPIO of an e-stop button closing to ground, 0 for the board's. It needs an internal pull-up, so not 34 to 39 on
+    // the ESP32
+    #[default(0)]
+    estop_pin: u8,
+    // GPIO of a button closing to ground, 0 for the board's. A press cycles the display pages, holding it 2 s takes
+    // down an error banner and 5 s restarts into the setup portal. Like the e-stop's, not 34 to 39 on the ESP32
+    #[default(0)]
+    button_pin: u8,
+    // GPIO of a piezo buzzer sounding boot, client connects, the failsafe, e-stops and a low battery, 0 for the
+    // board's. It takes the LEDC channel after the servos and gripper, and LEDC timer 2
+    #[default(0)]
+    buzzer_pin: u8,
+    // Keep the buzzer quiet until a buzzer config command keeps another setting in NVS
+    #[default(false)]
+    buzzer_muted: bool,
+    // GPIO of the status LED, 0 for the board's
+    #[default(0)]
+    status_led_pin: u8,
+    // Seconds each display page is shown before the next, 0 keeps the servo page
+    #[default(5)]
+    display_page_s: u8,
+    // Put in front of ip:port in the pairing QR code, empty for the bare address
+    #[default("limb://")]
+    pairing_scheme: &'static str,
+    // Display contrast from 0 to 255. This and the idle timers can be changed by the config command, which stores them
+    #[default(95)]
+    display_brightness: u8,
+    // Seconds without a packet before the display dims, 0 never dims
+    #[default(60)]
+    display_dim_s: u16,
+    // Seconds without a packet before the display turns off until the next packet or alarm, 0 never sleeps
+    #[default(600)]
+    display_sleep_s: u16,
+    // Pixel height of the 128 wide panel, 64 or 32
+    #[default(64)]
+    display_height: u8,
+    // I2C address of the display, 0 picks 0x3C or 0x3D by which one answers the bus scan at boot
+    #[default(0)]
+    display_address: u8,
+    // Speed of the I2C bus the display and the PCA9685 share, 10000 to 1000000 Hz. Clone panels and long cables may
+    // need 400000 or 100000
+    #[default(1000000)]
+    i2c_baud_hz: u32,
+    // Turns the display 180 degrees for a panel mounted upside down, the config command can change it too
+    #[default(false)]
+    display_flipped: bool,
+    // "ip:port" of a UDP collector that log records are forwarded to, empty keeps them on the console
+    #[default("")]
+    log_collector: &'static str,
+    // Most verbose level forwarded, from "off" to "trace". The log level command changes it until the next restart
+    #[default("info")]
+    log_forward_level: &'static str,
+    // Port of the HTTP status and control endpoint and the /ws control channel, 0 leaves them off
+    #[default(80)]
+    http_port: u16,
+    // Broker URL like "mqtt://192.168.1.10:1883", empty leaves MQTT off. Only used when built with the mqtt feature
+    #[default("")]
+    mqtt_broker: &'static str,
+    // Empty for esp-mqtt's default, which ends in the chip's MAC
+    #[default("")]
+    mqtt_client_id: &'static str,
+    // Empty to connect without credentials
+    #[default("")]
+    mqtt_username: &'static str,
+    #[default("")]
+    mqtt_password: &'static str,
+    // Prefix of the status, online, command and reply topics
+    #[default("lamhshaorga")]
+    mqtt_topic: &'static str,
+    // Seconds between status publishes when nothing has changed
+    #[default(5)]
+    mqtt_interval_s: u16,
+    // Take control frames published on <mqtt_topic>/command, authenticated like UDP packets when a key is set
+    #[default(false)]
+    mqtt_commands: bool,
+    // "leader" broadcasts this limb's pose over ESP-NOW, "follower" takes it from the first leader heard, empty is off
+    #[default("")]
+    espnow_role: &'static str,
+    // Poses the leader broadcasts per second
+    #[default(20)]
+    espnow_rate_hz: u16,
+    // Radio channel when there is no access point to join, joined limbs use the access point's
+    #[default(1)]
+    espnow_channel: u8,
+    // Silence after which the follower stops following its leader and waits for any leader again
+    #[default(500)]
+    espnow_timeout_ms: u32,
+    // Name the BLE control service advertises under, empty for the mDNS instance name. Only used when built with the
+    // ble feature
+    #[default("")]
+    ble_name: &'static str,
+    // Take framed commands on a UART, see serial.rs. Also how to reach a limb that couldn't join WiFi
+    #[default(false)]
+    serial_commands: bool,
+    // 0 is the USB port, shared with the console
+    #[default(0)]
+    serial_uart: u8,
+    #[default(115200)]
+    serial_baud: u32,
+    // 0 for the board's, UART0's own pins
+    #[default(0)]
+    serial_tx_pin: u8,
+    #[default(0)]
+    serial_rx_pin: u8,
+    // Where the time for timestamps comes from once the network is up, empty leaves them unsynchronized
+    #[default("pool.ntp.org")]
+    sntp_server: &'static str,
+    // Between syncs once the time is known, at least 60
+    #[default(3600)]
+    sntp_resync_s: u32,
+    // Seconds the loop timing maxima cover, a spike is reported for at least this long
+    #[default(10)]
+    loop_window_s: u32,
+    // A control loop pass taking longer than this counts as slow, a tick at the default rate
+    #[default(20000)]
+    loop_slow_us: u32,
 }
 
 // Set a constant CONTROL_SIGNAL_SIZE
 const VERSION_MIN: u32 = 6;
 const VERSION_MAJ: u32 = 0;
-const MAX_CONTROL_SIGNAL_SIZE: usize = 11;
-
-// VALUES FOR SERVOS
-const HOBBY_FANS_MIN_DUTY: f32 = 0.0275;
-const HOBBY_FANS_MAX_DUTY: f32 = 0.125;
-
-const MIUZEI_MIN_DUTY: f32 = 0.018;
-const MIUZEI_MAX_DUTY: f32 = 0.11;
-
-const MIUZEI_MINI_MIN_DUTY: f32 = 0.024;
-const MIUZEI_MINI_MAX_DUTY: f32 = 0.11;
+const RECV_TIMEOUT: Duration = Duration::from_millis(5); // Longest a raised tick or queued reply waits on an idle task
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10); // Longest the servos get to park before they are stopped anyway
+const REBOOT_DELAY: Duration = Duration::from_millis(200); // Lets the network and display tasks flush before a restart
+const SLEEP_DELAY: Duration = Duration::from_secs(2); // Leaves the sleep banner up before the display goes off
+const DISPLAY_COLUMNS: usize = 25; // Characters of the small font across the display
+#[cfg(not(feature = "sim"))]
+const LINK_POLL: Duration = Duration::from_millis(500); // How often the HTTP server checks for the network
+
+// VALUES FOR SERVOS, pulse widths in microseconds
+const MIUZEI_MINI_MIN_PULSE_US: u16 = 480;
+const MIUZEI_MINI_MAX_PULSE_US: u16 = 2200;
 
 // Control bytes
 
-fn main() -> Result<()> {
-    // It is necessary to call this function once. Otherwise some patches to the runtime
-    // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
-    esp_idf_hal::sys::link_patches();
-
-    esp_idf_svc::log::EspLogger::initialize_default();
-    // Initialize NVS, unsure if we will need this in future
-    unsafe {
-        match nvs_flash_init() {
-            0 => info!("NVS Flash initialized"),
-            error_code => error!(
-                "NVS Flash initialization failed with error code: {}",
-                error_code
-            ),
-        }
-    }
+#[cfg(not(feature = "sim"))]
+fn main() {
+    hardware::main()
+}
 
-    // get peripherals
-    let peripherals: Peripherals = match Peripherals::take() {
-        Ok(peripherals) => peripherals,
-        Err(e) => {
-            panic!("Failed to take peripherals: {:?}", e);
-        }
-    };
+#[cfg(feature = "sim")]
+fn main() {
+    sim::main()
+}
 
-    // get system event loop
-    let system_loop = match EspSystemEventLoop::take() {
-        Ok(sloop) => sloop,
-        Err(e) => {
-            panic!("Failed to take system event loop: {:?}", e);
+// Starts the tasks shared by the hardware and simulator builds, it never returns. The socket is None when the hardware
+// started offline, the network task binds it once the network is joined
+fn run(
+    socket: Option<UdpSocket>,
+    servos: Vec<Servo>,
+    display: impl DisplayBackend + Send + 'static,
+    settings: Option<Settings>,
+    link: Link,
+    board: Board,
+    #[cfg(not(feature = "sim"))] serial: Option<UartDriver<'static>>,
+) -> ! {
+    let (command_sender, commands) = mpsc::sync_channel(network::COMMAND_QUEUE_SIZE);
+    let (reply_sender, replies) = mpsc::channel();
+    let (display_sender, display_updates) = mpsc::channel();
+    let display_rows = display.rows();
+    // Stored console levels replace the compiled default, otherwise it is back after every restart
+    match settings.as_ref().map(Settings::load_log_levels) {
+        Some(Ok(Some(levels))) => {
+            remote_log::set_console_levels(levels);
+            info!("Log levels loaded from NVS");
         }
-    };
-
-    // Set up pins for i2c, and i2c port
-    let i2c = peripherals.i2c0;
-    let sda = peripherals.pins.gpio21;
-    let scl = peripherals.pins.gpio22;
-
-    // Set up the i2c driver
-    let config = I2cConfig::new().baudrate(1.MHz().into());
-
-    let driver = match I2cDriver::new(i2c, sda, scl, &config) {
-        Ok(driver) => driver,
-        Err(e) => {
-            panic!("Failed to initialize I2C driver: {:?}", e);
+        Some(Ok(None)) | None => {},
+        Some(Err(e)) => warn!("Failed to read the log levels, using defaults: {}", e),
+    }
+    remote_log::start(CONFIG.log_collector, CONFIG.log_forward_level);
+
+    tasks::spawn(&tasks::DISPLAY_TASK, move || tasks::display_task(display, display_updates));
+    let clock = clock::start(link.clone());
+    let shared = Shared { link, stats: Stats::new(), clock };
+    let network_shared = shared.clone();
+    // Shared by every transport that takes commands, so a nonce used on one is spent on all of them
+    let authenticator =
+        Authenticator::from_settings(settings.as_ref()).map(|authenticator| Arc::new(Mutex::new(authenticator)));
+    match authenticator {
+        Some(_) => info!("Commands require authentication"),
+        None => warn!("No authentication key set, any client can command the limb"),
+    }
+    #[cfg(not(feature = "sim"))]
+    let (http_commands, http_link, http_authenticator) =
+        (command_sender.clone(), shared.link.clone(), authenticator.clone());
+    #[cfg(all(feature = "mqtt", not(feature = "sim")))]
+    mqtt::start(command_sender.clone(), shared.link.clone(), authenticator.clone());
+    #[cfg(all(feature = "ble", not(feature = "sim")))]
+    ble::start(command_sender.clone(), authenticator.is_some());
+    #[cfg(not(feature = "sim"))]
+    if let Some(serial) = serial {
+        serial::start(serial, command_sender.clone(), authenticator.clone());
+    }
+    // Its own authenticator, the leader numbers its nonces apart from any UDP client
+    #[cfg(not(feature = "sim"))]
+    if let Some(role) = espnow::parse_role(CONFIG.espnow_role) {
+        espnow::start(role, command_sender.clone(), Authenticator::from_settings(settings.as_ref()));
+    }
+    #[cfg(feature = "sim")]
+    if !CONFIG.espnow_role.is_empty() {
+        warn!("The simulator has no radio, espnow_role is ignored");
+    }
+    tasks::spawn(&tasks::NETWORK_TASK, move || {
+        network::run(socket, network_shared.link, network_shared.stats, authenticator, command_sender, replies)
+    });
+    let control_task = tasks::spawn(&tasks::CONTROL_TASK, move || {
+        let display = DisplayChannel::new(display_sender, display_rows);
+        Controller::new(servos, display, settings, board, shared, reply_sender).run(commands)
+    });
+
+    // Kept for as long as run() blocks below, dropping it stops the server. Started offline, the setup portal has
+    // port 80 until the network is joined, so the server waits for that
+    #[cfg(not(feature = "sim"))]
+    let _http_server = match CONFIG.http_port {
+        0 => None,
+        port => {
+            while http_link.get().never_joined() {
+                std::thread::sleep(LINK_POLL);
+            }
+            http_api::start(port, http_commands, http_link, http_authenticator)
         }
     };
 
-    let interface = I2CDisplayInterface::new(driver);
-
-    let mut display = Display::new(
-        Ssd1306::new(interface, DisplaySize128x64, DisplayRotation::Rotate0)
-            .into_buffered_graphics_mode(),
-    );
-
-    let mut to_oled: String = "Starting...".to_string();
-
-    display.init();
-    display.set_text_style(
-        MonoTextStyleBuilder::new()
-            .font(&FONT_6X10)
-            .text_color(BinaryColor::On)
-            .build(),
-    );
-    display.draw_new_text(0, 7, &to_oled);
-
-    // Connect to WiFi
-    info!("Socket initialize");
-    let _wifi = wifi_setup::wifi(
-        CONFIG.wifi_ssid,
-        CONFIG.wifi_psk,
-        peripherals.modem,
-        system_loop,
-        6,
-    )?;
-
-    let socket = wifi_setup::init_socket(None);
-    info!("Socket initialized");
-
-    let _mdns = wifi_setup::init_mdns();
-    info!("mDNS initialized");
-
-    let ip_string = _wifi.sta_netif().get_ip_info()?.ip;
-
-    to_oled = format!(
-        "Robotic Limb V{}.{}\nIP Address: \n{}",
-        VERSION_MAJ, VERSION_MIN, ip_string
-    )
-    .parse()?;
-
-    display.draw_new_text(0, 7, &to_oled);
-    drop(to_oled);
-
-    // Set up the servo drivers
-    let ledc_driver = match LedcTimerDriver::new(
-        peripherals.ledc.timer0,
-        &config::TimerConfig::new()
-            .resolution(esp_idf_hal::ledc::Resolution::Bits12)
-            .frequency(50.Hz().into()),
-    ) {
-        Ok(driver) => driver,
-        Err(e) => panic!("LEDc Timer driver failed to initialise: {}", e), // Serious issue if ledc driver cannot be initialised
-    };
-
-    let mut servos: Vec<Servo> = Vec::with_capacity(5);
-
-    create_and_add_servo(
-        "Top",
-        peripherals.ledc.channel0,
-        &ledc_driver,
-        peripherals.pins.gpio15,
-        &mut servos,
-        MIUZEI_MINI_MIN_DUTY,
-        MIUZEI_MINI_MAX_DUTY,
-        180,
-    );
-    create_and_add_servo(
-        "Shoulder",
-        peripherals.ledc.channel1,
-        &ledc_driver,
-        peripherals.pins.gpio16,
-        &mut servos,
-        MIUZEI_MINI_MIN_DUTY,
-        MIUZEI_MINI_MAX_DUTY,
-        180,
-    );
-    create_and_add_servo(
-        "Upper Arm",
-        peripherals.ledc.channel2,
-        &ledc_driver,
-        peripherals.pins.gpio17,
-        &mut servos,
-        MIUZEI_MINI_MIN_DUTY,
-        MIUZEI_MINI_MAX_DUTY,
-        180,
-    );
-    create_and_add_servo(
-        "Elbow",
-        peripherals.ledc.channel3,
-        &ledc_driver,
-        peripherals.pins.gpio18,
-        &mut servos,
-        MIUZEI_MINI_MIN_DUTY,
-        MIUZEI_MINI_MAX_DUTY,
-        180,
-    );
-    create_and_add_servo(
-        "Lower Arm",
-        peripherals.ledc.channel4,
-        &ledc_driver,
-        peripherals.pins.gpio19,
-        &mut servos,
-        MIUZEI_MINI_MIN_DUTY,
-        MIUZEI_MINI_MAX_DUTY,
-        180,
-    );
+    // The main task only waits, a panic in any task aborts and restarts the chip
+    let _ = control_task.join();
+    panic!("Control task stopped");
+}
 
-    let mut led = PinDriver::output(peripherals.pins.gpio4)?;
+// State the control task shares with the network and WiFi tasks
+#[derive(Clone)]
+struct Shared {
+    link: Link,
+    stats: Stats,
+    clock: Clock,
+}
 
-    //let mut resistor = PinDriver::input(peripherals.pins.gpio2)?;
+// Everything on the board the control loop uses besides the servos and display, set up by the hardware or simulator
+// start-up
+struct Board {
+    tick: Tick,
+    battery: Battery,
+    estop_button: EStopButton,
+    button: Button,
+    buzzer: Buzzer,
+    status_led: StatusLed,
+    updater: Updater,
+    gripper: Option<Gripper>, // None unless gripper_backend is set
+    history: History,
+    current: CurrentMonitor,  // Without sensors unless current_sensors names some
+    i2c_devices: Vec<u8>,     // Addresses that answered the bus scan at boot
+}
 
-    // Timer setup
-    let mut timer = match TimerDriver::new(
-        peripherals.timer00,
-        &HalTimerConfig::Config::new().auto_reload(true),
-    ){
-        Ok(timer) => timer,
-        Err(e) => panic!("Failed to initialize timer: {}", e),
+// Stored settings take precedence over the compiled ones
+fn load_battery_config(settings: Option<&Settings>) -> BatteryConfig {
+    let compiled = BatteryConfig {
+        divider: CONFIG.battery_divider,
+        cutoff_mv: CONFIG.battery_cutoff_mv,
+        hysteresis_mv: CONFIG.battery_hysteresis_mv,
     };
+    match settings.map(Settings::load_battery) {
+        Some(Ok(Some(config))) => {
+            info!("Battery config loaded from NVS");
+            config
+        }
+        Some(Ok(None)) | None => compiled,
+        Some(Err(e)) => {
+            warn!("Failed to read the battery config, using defaults: {}", e);
+            compiled
+        }
+    }
+}
 
-    let mut alarm_time_us: u64 = 1_000_000; // Set for 1 second (in microseconds)
-
-    match timer.set_alarm(alarm_time_us){
-        Ok(_) => {},
-        Err(e) => error!("Failed to set alarm: {}", e),
+// Stored settings take precedence over the compiled ones
+fn load_display_config(settings: Option<&Settings>) -> DisplayConfig {
+    let compiled = DisplayConfig {
+        brightness: CONFIG.display_brightness,
+        dim_s: CONFIG.display_dim_s,
+        sleep_s: CONFIG.display_sleep_s,
+        flipped: CONFIG.display_flipped,
     };
-
-    unsafe {
-        match timer.subscribe(move || {
-            led.toggle().unwrap();
-        }){
-            Ok(_) => {},
-            Err(e) => error!("Failed to subscribe to timer: {}", e),
-        };
+    match settings.map(Settings::load_display) {
+        Some(Ok(Some(config))) => {
+            info!("Display config loaded from NVS");
+            config
+        }
+        Some(Ok(None)) | None => compiled,
+        Some(Err(e)) => {
+            warn!("Failed to read the display config, using defaults: {}", e);
+            compiled
+        }
     }
+}
 
-    timer.enable_interrupt()?;
-    timer.enable_alarm(true)?;
-    timer.enable(false)?;
-
-    let mut from_addr: std::net::SocketAddr;
-    let mut ctrl_vec: Vec<u8> = Vec::with_capacity(MAX_CONTROL_SIGNAL_SIZE);
-    ctrl_vec = vec![0; MAX_CONTROL_SIGNAL_SIZE];
-
-    display.set_text_style(
-        MonoTextStyleBuilder::new()
-            .font(&FONT_5X8)
-            .text_color(BinaryColor::On)
-            .build(),
-    );
-
-    let calc_string = format!(
-        // Create a stub string to calculate the size of the servo string
-        "Servo Positions:\n{}0\n{}0\n{}0\n{}0\n{}0",
-        servos[0].to_string(),
-        servos[1].to_string(),
-        servos[2].to_string(),
-        servos[3].to_string(),
-        servos[4].to_string()
-    );
-
-    let mut servo_string = String::with_capacity(calc_string.len()); // Allocate the space for the loop string, small performance boost
-    drop(calc_string); // Drop the stub string
-
-
-
-
-    info!("Entering Loop");
-    loop {
-        match recv_data(&socket, &mut ctrl_vec) {
-            Ok(Some((received_data, src_addr))) => {
-                if received_data.is_empty() {
-                    continue;
-                }
-                from_addr = src_addr;
-            }
-            Ok(None) => {
-                info!("Received None");
-                continue;
-            }
-            Err(e) => {
-                error!("Failed to receive data: {}", e);
-                continue;
-            }
+fn load_speed_scale(settings: Option<&Settings>) -> u8 {
+    let compiled = CONFIG.speed_scale.clamp(1, servo::FULL_SPEED);
+    match settings.map(Settings::load_speed_scale) {
+        Some(Ok(Some(percent))) => {
+            info!("Speed scale of {}% loaded from NVS", percent);
+            percent
         }
-        // Read pin
-
-            match ctrl_vec[0] {
-                0 => {
-                    servos[0].set_angle(u16::from_be_bytes([ctrl_vec[1], ctrl_vec[2]]));
-                    servos[1].set_angle(u16::from_be_bytes([ctrl_vec[3], ctrl_vec[4]]));
-                    servos[2].set_angle(u16::from_be_bytes([ctrl_vec[5], ctrl_vec[6]]));
-                    servos[3].set_angle(u16::from_be_bytes([ctrl_vec[7], ctrl_vec[8]]));
-                    servos[4].set_angle(u16::from_be_bytes([ctrl_vec[9], ctrl_vec[10]]));
-
-                    servo_string.clear();
-                    // Append the static part of the display string
-                    servo_string.push_str("Servo Positions:\n");
-                    servo_string.push_str(&*format!(
-                        "{}\n{}\n{}\n{}\n{}",
-                        servos[0].to_string(),
-                        servos[1].to_string(),
-                        servos[2].to_string(),
-                        servos[3].to_string(),
-                        servos[4].to_string()
-                    ));
-
-                    display.draw_new_text(0, 7, &servo_string);
-
-                    ctrl_vec.clear();
-                    for servo in &servos {
-                        ctrl_vec.push(servo.get_angle() as u8);
-                        ctrl_vec.push((servo.get_angle() >> 8) as u8);
-                    }
-                    match socket.send_to(&ctrl_vec, from_addr){
-                        Ok(_) => {},
-                        Err(e) => error!("Failed to send servo positions: {}", e),
-                    }
-                    ctrl_vec.push(0); // Required to make ctrl vec = 11
-                    // TIMER TEST
-                    //timer.counter()?;
-                    //timer.enable(true)?;
-                }
-                1 => {
-                    info!("Received Ping Signal");
-                    info!("Sending back to {}", from_addr);
-                    let mut ping_vec: Vec<u8> = Vec::new();
-
-                    for servo in &servos {
-                        ping_vec.push(servo.get_angle() as u8);
-                        ping_vec.push((servo.get_angle() >> 8) as u8);
-                    }
-
-                    match socket.send_to(&ping_vec, from_addr){
-                        Ok(_) => {},
-                        Err(e) => error!("Failed to send servo positions: {}", e),
-                    }
-                    drop(ping_vec);
-                }
-                2 => {
-                    info!("Received Config Signal");
-
-                }
-                _ => {
-                    error!("Not a valid command");
-                }
-            }
+        Some(Ok(None)) | None => compiled,
+        Some(Err(e)) => {
+            warn!("Failed to read the speed scale, using the default: {}", e);
+            compiled
         }
+    }
 }
 
-// Function to receive data from UDP packet and return it along with the source address
-fn recv_data(
-    socket: &UdpSocket,
-    buf: &mut Vec<u8>,
-) -> Result<Option<(Vec<u8>, std::net::SocketAddr)>> {
-    match socket.recv_from(buf) {
-        Ok((size, src_addr)) => {
-            Ok(Some((buf.to_vec(), src_addr)))
-        }
-        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-            // WouldBlock is the error kind for a read timeout
-            Ok(None)
+fn load_mirror(settings: Option<&Settings>) -> bool {
+    match settings.map(Settings::load_mirror) {
+        Some(Ok(Some(mirrored))) => {
+            info!("Mirroring {} from NVS", if mirrored { "on" } else { "off" });
+            mirrored
         }
-        Err(_) => {
-            // Handle other errors by setting all byte values to 0, effectively halting the system.
-            buf.iter_mut().for_each(|byte| *byte = 0);
-            Ok(Some((buf.to_vec(), "0.0.0.0:8080".parse().unwrap())))
+        Some(Ok(None)) | None => CONFIG.mirror,
+        Some(Err(e)) => {
+            warn!("Failed to read the mirror setting, using the default: {}", e);
+            CONFIG.mirror
         }
     }
 }
 
-fn create_and_add_servo<'d, C: LedcChannel, B: Borrow<LedcTimerDriver<'static>>>(
-    name: &str,
-    channel: impl Peripheral<P = C> + 'static,
-    ledc_driver: B,
-    pin: impl Peripheral<P = impl OutputPin> + 'static,
-    servos: &mut Vec<Servo>,
-    min_duty: f32,
-    max_duty: f32,
-    max_angle_degrees: u16,
-) {
-    match LedcDriver::new(channel, ledc_driver, pin) {
-        Ok(driver) => {
-            let servo = Servo::new(
-                name.to_string(),
-                driver,
-                min_duty,
-                max_duty,
-                max_angle_degrees,
-            );
-            servos.push(servo);
+fn load_buzzer_muted(settings: Option<&Settings>) -> bool {
+    match settings.map(Settings::load_buzzer_muted) {
+        Some(Ok(Some(muted))) => {
+            info!("Buzzer {} from NVS", if muted { "muted" } else { "unmuted" });
+            muted
+        }
+        Some(Ok(None)) | None => CONFIG.buzzer_muted,
+        Some(Err(e)) => {
+            warn!("Failed to read the buzzer setting, using the default: {}", e);
+            CONFIG.buzzer_muted
         }
-        Err(e) => error!("Failed to create servo {}: {}", name, e),
     }
 }
+
+// "7.42 V", or a placeholder before the first reading
+fn format_volts(voltage_mv: Option<u16>) -> String {
+    match voltage_mv {
+        Some(voltage_mv) => format!("{}.{:02} V", voltage_mv / 1000, voltage_mv % 1000 / 10),
+        None => "-.-- V".to_string(),
+    }
+}
+
+// Version and address page shown once WiFi is up
+fn status_page(ip: Ipv4Addr) -> String {
+    format!("Robotic Limb V{}.{}\nIP Address: \n{}:{}", VERSION_MAJ, VERSION_MIN, ip, CONFIG.udp_port)
+}
+
+// Breaks text into lines of at most `width` characters, anything past `lines` lines is dropped
+fn wrap_text(text: &str, width: usize, lines: usize) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    chars
+        .chunks(width)
+        .take(lines)
+        .map(|line| line.iter().collect::<String>())
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
@@ -0,0 +1,126 @@
+// Mirror mode for a limb built as the mirror image of another, reflecting the configured joints of each move across
+// their travel before it's clamped, ahead of each servo's own reversal and trim
+use log::warn;
+
+use crate::protocol::ControlPacket;
+use crate::servo::Servo;
+
+pub struct Mirror {
+    enabled: bool,
+    joints: Vec<bool>, // Whether each joint is reflected while enabled
+}
+
+impl Mirror {
+    pub fn new(enabled: bool, joints: Vec<bool>) -> Mirror {
+        Mirror { enabled, joints }
+    }
+
+    // The command as this limb carries it out, moves are reflected and everything else is left alone
+    pub fn apply(&self, control: ControlPacket, servos: &[Servo]) -> ControlPacket {
+        if !self.enabled {
+            return control;
+        }
+        match control {
+            ControlPacket::SetAngles(angles) => ControlPacket::SetAngles(self.reflect_pose(&angles, servos)),
+            ControlPacket::Pose { angles, duration_ms, easing, tag, tenths } => {
+                ControlPacket::Pose { angles: self.reflect_pose(&angles, servos), duration_ms, easing, tag, tenths }
+            }
+            // An index out of range is left for the range check to refuse
+            ControlPacket::MoveJoint { index, angle, speed } => {
+                let angle = match servos.get(index as usize) {
+                    Some(servo) => self.reflect(index as usize, angle, servo),
+                    None => angle,
+                };
+                ControlPacket::MoveJoint { index, angle, speed }
+            }
+            // A reflected joint jogs the other way
+            ControlPacket::Jog { index, delta_tenths } => match self.joints.get(index as usize) {
+                Some(true) => ControlPacket::Jog { index, delta_tenths: delta_tenths.saturating_neg() },
+                _ => ControlPacket::Jog { index, delta_tenths },
+            },
+            control => control,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    fn reflect_pose(&self, angles: &[u16], servos: &[Servo]) -> Vec<u16> {
+        // A count that doesn't match the servos is left for the length check to refuse
+        angles
+            .iter()
+            .enumerate()
+            .map(|(index, &angle)| match servos.get(index) {
+                Some(servo) => self.reflect(index, angle, servo),
+                None => angle,
+            })
+            .collect()
+    }
+
+    // An angle past the end of travel reflects to 0
+    fn reflect(&self, index: usize, angle: u16, servo: &Servo) -> u16 {
+        match self.joints.get(index) {
+            Some(true) => servo.get_max_angle().saturating_sub(angle),
+            _ => angle,
+        }
+    }
+}
+
+// The joints named by index in the comma separated config. Indices that aren't joints are warned about and ignored
+pub fn parse_joints(config: &str, joints: usize) -> Vec<bool> {
+    let mut mirrored = vec![false; joints];
+    for entry in config.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        match entry.parse::<usize>() {
+            Ok(index) if index < joints => mirrored[index] = true,
+            _ => warn!("Mirror joint \"{}\" isn't a joint index, ignored", entry),
+        }
+    }
+    mirrored
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+    use crate::servo::Easing;
+    use crate::sim;
+
+    #[test]
+    fn only_the_configured_joints_are_reflected_while_enabled() {
+        let (servos, _) = sim::mock_servos();
+        let mut mirror = Mirror::new(false, parse_joints("0, 2", servos.len()));
+        let pose = vec![300; servos.len()];
+        let unmirrored = mirror.apply(ControlPacket::SetAngles(pose.clone()), &servos);
+        assert_eq!(unmirrored, ControlPacket::SetAngles(pose.clone()));
+
+        mirror.set_enabled(true);
+        let eased =
+            ControlPacket::Pose { angles: pose, duration_ms: 400, easing: Easing::EaseOut, tag: None, tenths: true };
+        let ControlPacket::Pose { angles, duration_ms: 400, easing: Easing::EaseOut, tag: None, tenths: true } =
+            mirror.apply(eased, &servos)
+        else {
+            panic!("not a pose");
+        };
+        assert_eq!(angles[..3], [1500, 300, 1500]);
+        assert!(angles[3..].iter().all(|&angle| angle == 300));
+        // Past the end of travel, and a joint that isn't there
+        let far = ControlPacket::MoveJoint { index: 0, angle: 2000, speed: None };
+        assert_eq!(mirror.apply(far, &servos), ControlPacket::MoveJoint { index: 0, angle: 0, speed: None });
+        let missing = ControlPacket::MoveJoint { index: 40, angle: 300, speed: None };
+        assert_eq!(mirror.apply(missing.clone(), &servos), missing);
+        // Reflected joints jog the other way
+        let jog = |index| ControlPacket::Jog { index, delta_tenths: -128 };
+        assert_eq!(mirror.apply(jog(0), &servos), ControlPacket::Jog { index: 0, delta_tenths: 127 });
+        assert_eq!(mirror.apply(jog(1), &servos), jog(1));
+    }
+
+    #[test]
+    fn joints_that_arent_indices_are_ignored() {
+        assert_eq!(parse_joints("", 3), [false; 3]);
+        assert_eq!(parse_joints("2,,x, 7 ,0", 3), [true, false, true]);
+    }
+}
@@ -0,0 +1,126 @@
+// Tagged moves waiting their turn behind the executing one, each reporting its end to the client that sent it. One
+// tagged with QUEUE_FLAG waits behind the others, any other move cancels them all
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+
+use crate::servo::Easing;
+
+pub const MAX_QUEUED: usize = 8; // Moves waiting behind the executing one, a fuller queue refuses more as busy
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Motion {
+    Pose { angles: Vec<u16>, duration_ms: u16, easing: Easing },
+    Trajectory { slot: u8 }, // Never looping, a looping trajectory would hold up the queue for good
+}
+
+// A move and the client its end is reported to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueuedMove {
+    pub addr: SocketAddr,
+    pub id: u16,
+    pub motion: Motion,
+}
+
+pub struct MotionQueue {
+    pending: VecDeque<QueuedMove>,
+    executing: Option<(SocketAddr, u16)>,
+    sequence: u16, // Completions carry their own count in the sequence field, like heartbeats
+}
+
+impl MotionQueue {
+    pub fn new() -> MotionQueue {
+        MotionQueue { pending: VecDeque::with_capacity(MAX_QUEUED), executing: None, sequence: 0 }
+    }
+
+    // Nothing tagged is running or waiting, so a queued move can start straight away
+    pub fn is_idle(&self) -> bool {
+        self.executing.is_none() && self.pending.is_empty()
+    }
+
+    // False if the queue is full, the move is dropped
+    pub fn push(&mut self, queued: QueuedMove) -> bool {
+        if self.pending.len() >= MAX_QUEUED {
+            return false;
+        }
+        self.pending.push_back(queued);
+        true
+    }
+
+    pub fn start(&mut self, addr: SocketAddr, id: u16) {
+        self.executing = Some((addr, id));
+    }
+
+    // The move that was executing, now over
+    pub fn finish(&mut self) -> Option<(SocketAddr, u16)> {
+        self.executing.take()
+    }
+
+    pub fn next(&mut self) -> Option<QueuedMove> {
+        self.pending.pop_front()
+    }
+
+    // Every tagged move, the executing one first, for their cancellations to be reported
+    pub fn cancel(&mut self) -> Vec<(SocketAddr, u16)> {
+        let mut cancelled: Vec<(SocketAddr, u16)> = self.executing.take().into_iter().collect();
+        cancelled.extend(self.pending.drain(..).map(|queued| (queued.addr, queued.id)));
+        cancelled
+    }
+
+    pub fn depth(&self) -> u8 {
+        self.pending.len() as u8
+    }
+
+    pub fn get_executing(&self) -> Option<u16> {
+        self.executing.map(|(_, id)| id)
+    }
+
+    pub fn next_sequence(&mut self) -> u16 {
+        self.sequence = self.sequence.wrapping_add(1);
+        self.sequence
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    const CLIENT: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20)), 4210);
+
+    fn queued(id: u16) -> QueuedMove {
+        QueuedMove { addr: CLIENT, id, motion: Motion::Trajectory { slot: 0 } }
+    }
+
+    #[test]
+    fn moves_come_out_in_the_order_they_went_in_until_the_queue_is_full() {
+        let mut queue = MotionQueue::new();
+        assert!(queue.is_idle());
+        queue.start(CLIENT, 0);
+        assert!(!queue.is_idle());
+        for id in 1..=MAX_QUEUED as u16 {
+            assert!(queue.push(queued(id)));
+        }
+        assert!(!queue.push(queued(100)));
+        assert_eq!((queue.depth(), queue.get_executing()), (MAX_QUEUED as u8, Some(0)));
+        assert_eq!(queue.finish(), Some((CLIENT, 0)));
+        assert_eq!(queue.next().map(|next| next.id), Some(1));
+        assert_eq!(queue.next().map(|next| next.id), Some(2));
+    }
+
+    #[test]
+    fn cancel_returns_the_executing_move_first_and_empties_the_queue() {
+        let mut queue = MotionQueue::new();
+        queue.start(CLIENT, 4);
+        queue.push(queued(5));
+        queue.push(queued(6));
+        assert_eq!(queue.cancel(), [(CLIENT, 4), (CLIENT, 5), (CLIENT, 6)]);
+        assert!(queue.is_idle());
+        assert_eq!(queue.cancel(), []);
+    }
+
+    #[test]
+    fn completions_count_their_own_sequence() {
+        let mut queue = MotionQueue::new();
+        assert_eq!((queue.next_sequence(), queue.next_sequence()), (1, 2));
+    }
+}
@@ -0,0 +1,220 @@
+// MQTT link for home automation, publishing retained status on <topic>/status and taking command frames on
+// <topic>/command, answered on <topic>/reply, through the command queue like UDP packets
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use embedded_svc::mqtt::client::{Details, Event, Message, QoS};
+use esp_idf_svc::mqtt::client::{EspMqttClient, LwtConfiguration, MqttClientConfiguration};
+use esp_idf_sys::EspError;
+use log::{error, info, warn};
+
+use crate::auth::Authenticator;
+use crate::http_api;
+use crate::link::Link;
+use crate::network::{self, Command, NO_ADDR};
+use crate::protocol::{ControlPacket, JointStatus, ReplyPacket, ReplyPayload, Status};
+use crate::sequence::SequenceTracker;
+use crate::{tasks, CONFIG};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500); // How often the status is checked for changes
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10); // For the broker to accept a new connection
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+
+// What the client's callback passes on, a message is copied out of the client's buffer
+enum MqttEvent {
+    Connected,
+    Disconnected,
+    Received(Vec<u8>),
+}
+
+struct Topics {
+    status: String,
+    online: String,
+    command: String,
+    reply: String,
+}
+
+// Everything the status change check goes by, the uptime and RSSI move on their own
+type StatusState = (u8, u16, Vec<JointStatus>);
+
+pub fn is_connected() -> bool {
+    CONNECTED.load(Ordering::Relaxed)
+}
+
+// Starts the MQTT task for the broker in the config, an empty broker leaves MQTT off
+pub fn start(commands: SyncSender<Command>, link: Link, authenticator: Option<Arc<Mutex<Authenticator>>>) {
+    if CONFIG.mqtt_broker.is_empty() {
+        return;
+    }
+    tasks::spawn(&tasks::MQTT_TASK, move || run(commands, link, authenticator));
+}
+
+fn run(commands: SyncSender<Command>, link: Link, authenticator: Option<Arc<Mutex<Authenticator>>>) {
+    let topics = Topics {
+        status: format!("{}/status", CONFIG.mqtt_topic),
+        online: format!("{}/online", CONFIG.mqtt_topic),
+        command: format!("{}/command", CONFIG.mqtt_topic),
+        reply: format!("{}/reply", CONFIG.mqtt_topic),
+    };
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        match connect(&topics) {
+            Ok((mut client, events)) => {
+                if serve(&mut client, &events, &topics, &commands, &link, authenticator.as_deref()) {
+                    backoff = MIN_BACKOFF;
+                }
+                CONNECTED.store(false, Ordering::Relaxed);
+            }
+            Err(e) => error!("Failed to create the MQTT client: {}", e),
+        }
+        warn!("MQTT broker {} lost, reconnecting in {} s", CONFIG.mqtt_broker, backoff.as_secs());
+        thread::sleep(backoff);
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+fn connect(topics: &Topics) -> Result<(EspMqttClient<'static>, Receiver<MqttEvent>), EspError> {
+    let (sender, events) = mpsc::channel();
+    let conf = MqttClientConfiguration {
+        client_id: Some(CONFIG.mqtt_client_id).filter(|id| !id.is_empty()),
+        username: Some(CONFIG.mqtt_username).filter(|username| !username.is_empty()),
+        password: Some(CONFIG.mqtt_password).filter(|password| !password.is_empty()),
+        lwt: Some(LwtConfiguration { topic: &topics.online, payload: b"0", qos: QoS::AtLeastOnce, retain: true }),
+        ..Default::default()
+    };
+    let client = EspMqttClient::new(CONFIG.mqtt_broker, &conf, move |event| {
+        let event = match event {
+            Ok(Event::Connected(_)) => MqttEvent::Connected,
+            Ok(Event::Disconnected) => MqttEvent::Disconnected,
+            Ok(Event::Received(message)) if matches!(message.details(), Details::Complete) => {
+                MqttEvent::Received(message.data().to_vec())
+            }
+            Ok(Event::Received(_)) => {
+                warn!("Ignoring an MQTT message split across chunks, frames are much smaller");
+                return;
+            }
+            Ok(_) => return,
+            Err(e) => {
+                warn!("MQTT client error: {}", e);
+                return;
+            }
+        };
+        let _ = sender.send(event); // Only fails once the task has dropped this connection
+    })?;
+    Ok((client, events))
+}
+
+// Serves one connection until it drops, true if the broker accepted it
+fn serve(
+    client: &mut EspMqttClient<'static>,
+    events: &Receiver<MqttEvent>,
+    topics: &Topics,
+    commands: &SyncSender<Command>,
+    link: &Link,
+    authenticator: Option<&Mutex<Authenticator>>,
+) -> bool {
+    match events.recv_timeout(CONNECT_TIMEOUT) {
+        Ok(MqttEvent::Connected) => {},
+        _ => {
+            error!("MQTT broker {} didn't accept the connection", CONFIG.mqtt_broker);
+            return false;
+        }
+    }
+    CONNECTED.store(true, Ordering::Relaxed);
+    info!("Connected to MQTT broker {}", CONFIG.mqtt_broker);
+    publish(client, &topics.online, true, b"1");
+    if CONFIG.mqtt_commands {
+        match client.subscribe(&topics.command, QoS::AtMostOnce) {
+            Ok(_) => info!("Taking commands on {}", topics.command),
+            Err(e) => error!("Failed to subscribe to {}: {}", topics.command, e),
+        }
+    }
+
+    let interval = Duration::from_secs(CONFIG.mqtt_interval_s.max(1) as u64);
+    let mut sequences = SequenceTracker::new();
+    let mut last_poll: Option<Instant> = None;
+    let mut published: Option<(Instant, StatusState)> = None;
+    loop {
+        let wait = last_poll.map_or(Duration::ZERO, |at| POLL_INTERVAL.saturating_sub(at.elapsed()));
+        match events.recv_timeout(wait) {
+            Ok(MqttEvent::Received(frame)) if CONFIG.mqtt_commands => {
+                if let Some((sequence, reply)) = handle_frame(&frame, commands, authenticator, &mut sequences) {
+                    publish(client, &topics.reply, false, &network::encode_reply(sequence, &reply));
+                }
+            }
+            Ok(MqttEvent::Received(_)) | Ok(MqttEvent::Connected) | Err(RecvTimeoutError::Timeout) => {},
+            Ok(MqttEvent::Disconnected) | Err(RecvTimeoutError::Disconnected) => return true,
+        }
+        if last_poll.is_some_and(|at| at.elapsed() < POLL_INTERVAL) {
+            continue;
+        }
+        last_poll = Some(Instant::now());
+        let reply = match network::request(commands, NO_ADDR, ControlPacket::Status) {
+            Ok(reply) => reply,
+            Err(e) => {
+                warn!("No status to publish: {:?}", e);
+                continue;
+            }
+        };
+        let ReplyPayload::Status { flags, battery_mv, ref joints, .. } = reply.payload else {
+            continue;
+        };
+        let state = (flags, battery_mv, joints.clone());
+        let due = match published {
+            Some((at, ref last)) => at.elapsed() >= interval || *last != state,
+            None => true,
+        };
+        if due {
+            if let Some(json) = http_api::status_json(&reply.payload, link.get().ip) {
+                publish(client, &topics.status, true, json.as_bytes());
+            }
+            published = Some((Instant::now(), state));
+        }
+    }
+}
+
+// Takes a frame from the command topic the way the network task takes a datagram, returning the answer and the
+// sequence it goes back under. None for a stale frame, which is dropped unanswered like a stale datagram
+fn handle_frame(
+    frame: &[u8],
+    commands: &SyncSender<Command>,
+    authenticator: Option<&Mutex<Authenticator>>,
+    sequences: &mut SequenceTracker,
+) -> Option<(u16, ReplyPacket)> {
+    let (sequence, control) = match network::decode_frame(frame, authenticator) {
+        Ok(decoded) => decoded,
+        Err(refused) => {
+            warn!("Refused MQTT command {}: {:?}", refused.command, refused.error);
+            return Some((refused.sequence, ReplyPacket::new(refused.command, refused.status(), ReplyPayload::Empty)));
+        }
+    };
+    // Every MQTT client shares one sequence, as they all come from the broker
+    if control == ControlPacket::Ping {
+        sequences.reset(NO_ADDR);
+    }
+    let command = control.command();
+    if !sequences.accept(NO_ADDR, sequence) {
+        warn!("Discarding stale MQTT command {}", sequence);
+        return None;
+    }
+    match network::request(commands, NO_ADDR, control) {
+        Ok(reply) => Some((sequence, reply)),
+        Err(e) => {
+            warn!("MQTT command {} got no answer: {:?}", command, e);
+            Some((sequence, ReplyPacket::new(command, Status::Busy, ReplyPayload::Empty)))
+        }
+    }
+}
+
+// A failed publish is only logged, the next status goes out anyway
+fn publish(client: &mut EspMqttClient<'static>, topic: &str, retain: bool, payload: &[u8]) {
+    if let Err(e) = client.publish(topic, QoS::AtMostOnce, retain, payload) {
+        warn!("Failed to publish to {}: {}", topic, e);
+    }
+}
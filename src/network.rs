@@ -0,0 +1,371 @@
+// Network task: receives and validates packets, hands commands to the control task and sends its replies back
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use log::{error, info, warn};
+
+use crate::auth::{self, AuthError, Authenticator, LogLimiter};
+use crate::link::Link;
+use crate::protocol::{self, ControlPacket, DecodeError, ReplyPacket, ReplyPayload, Status};
+use crate::rate_limit::RateLimiter;
+use crate::sequence::SequenceTracker;
+use crate::stats::Stats;
+use crate::{wifi_setup, CONFIG, RECV_TIMEOUT};
+
+pub const COMMAND_QUEUE_SIZE: usize = 8; // Commands waiting on the control task before new ones are refused as busy
+const MAX_SOCKET_ERRORS: u32 = 5; // Consecutive receive errors before the socket is re-bound
+const REBIND_DELAY: Duration = Duration::from_secs(1);
+const AUTH_LOG_INTERVAL: Duration = Duration::from_secs(5); // Keeps a flood of forged packets from flooding the log too
+const THROTTLE_LOG_INTERVAL: Duration = Duration::from_secs(5);
+const FLOOD_YIELD: Duration = Duration::from_millis(10); // Lets the other tasks run after a burst of datagrams
+#[cfg_attr(feature = "sim", allow(dead_code))]
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1); // Longest a request waits on the control task
+// Commands from HTTP and MQTT carry no client address the session could go by. The unspecified one never holds it,
+// so their motion commands are refused while a UDP client does
+#[cfg_attr(feature = "sim", allow(dead_code))]
+pub const NO_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+
+// A decoded packet on its way to the control task
+pub struct Command {
+    pub addr: SocketAddr,
+    pub sequence: u16,
+    pub packet: ControlPacket,
+    pub reply_to: ReplyTo,
+}
+
+// Where the control task sends its answer
+pub enum ReplyTo {
+    Socket, // Back through this task to the packet's address
+    #[cfg_attr(feature = "sim", allow(dead_code))] // The simulator has no HTTP server or MQTT client
+    Channel(SyncSender<ReplyPacket>), // Straight to the HTTP handler or MQTT task waiting on it
+    #[cfg_attr(feature = "sim", allow(dead_code))] // Nor ESP-NOW
+    Discard, // Nobody waits on it, the ESP-NOW follower's moves
+}
+
+// Why a frame was refused
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameError {
+    BadCrc,
+    Unauthorized(AuthError),
+    NoHeader,
+    Invalid(DecodeError),
+}
+
+// A refused frame, answered under its sequence and command as far as they could be read
+#[derive(Clone, Copy, Debug)]
+pub struct Refused {
+    pub sequence: u16,
+    pub command: u8,
+    pub error: FrameError,
+}
+
+impl Refused {
+    pub fn status(&self) -> Status {
+        match self.error {
+            FrameError::BadCrc => Status::BadCrc,
+            FrameError::Unauthorized(_) => Status::Unauthorized,
+            FrameError::NoHeader => Status::BadLength,
+            FrameError::Invalid(e) => e.status(),
+        }
+    }
+}
+
+// Why a request got no answer from the control task
+#[cfg_attr(feature = "sim", allow(dead_code))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RequestError {
+    Full,    // The command queue is full
+    Stopped, // The control task has stopped
+    TimedOut,
+}
+
+// The control task's answer to a command
+pub struct Reply {
+    pub addr: SocketAddr,
+    pub sequence: u16,
+    pub packet: ReplyPacket,
+}
+
+// Started offline there is no socket until the network is first joined
+pub fn run(
+    socket: Option<UdpSocket>,
+    link: Link,
+    stats: Stats,
+    authenticator: Option<Arc<Mutex<Authenticator>>>,
+    commands: SyncSender<Command>,
+    replies: Receiver<Reply>,
+) {
+    // One byte larger than the largest packet so oversized datagrams can be detected
+    let mut recv_buf = [0u8; protocol::HEADER_SIZE + protocol::MAX_COMMAND_SIZE + auth::AUTH_SIZE + protocol::CRC_SIZE + 1];
+    let mut auth_log = LogLimiter::new(AUTH_LOG_INTERVAL);
+    let mut throttle_log = LogLimiter::new(THROTTLE_LOG_INTERVAL);
+    let mut socket_errors: u32 = 0;
+    let mut sequences = SequenceTracker::new();
+    let mut limiter = RateLimiter::new(CONFIG.rate_limit_hz, CONFIG.rate_limit_burst);
+    let mut drained: u16 = 0; // Datagrams read since the socket last timed out or the task last yielded
+    let mut held: Option<Command> = None; // A move that found the queue full, queued as soon as there is room
+
+    info!("Network task running");
+    let mut socket = match socket {
+        Some(socket) => socket,
+        None => {
+            // Nothing is answered through the socket before there is one, anything else has nowhere to go
+            while !link.get().up {
+                replies.try_iter().for_each(drop);
+                thread::sleep(REBIND_DELAY);
+            }
+            info!("WiFi joined, binding socket");
+            rebind_socket()
+        }
+    };
+    let mut connections = link.get().connections;
+    loop {
+        // A fresh socket after WiFi comes back, the old one may be bound to the dropped interface's state
+        let status = link.get();
+        if status.connections != connections {
+            info!("WiFi reconnected, re-binding socket");
+            drop(socket);
+            socket = rebind_socket();
+            connections = status.connections;
+        }
+
+        // Replies are sent between reads, the read timeout bounds how long one waits
+        for reply in replies.try_iter() {
+            let sent = send_reply(&socket, reply.addr, reply.sequence, &reply.packet);
+            if reply.packet.command == protocol::HEARTBEAT_COMMAND {
+                stats.heartbeat_sent(sent);
+            }
+        }
+        if let Some(command) = held.take() {
+            match commands.try_send(command) {
+                Ok(_) => {},
+                Err(TrySendError::Full(command)) => held = Some(command),
+                Err(TrySendError::Disconnected(_)) => panic!("Control task stopped"),
+            }
+        }
+
+        let packet: &[u8];
+        let from_addr: SocketAddr;
+        match recv_data(&socket, &mut recv_buf) {
+            Ok(Some((data, src_addr))) => {
+                socket_errors = 0;
+                drained += 1;
+                if drained >= CONFIG.udp_burst_max.max(1) {
+                    drained = 0;
+                    thread::sleep(FLOOD_YIELD);
+                }
+                stats.count_received();
+                packet = data;
+                from_addr = src_addr;
+            }
+            Ok(None) => {
+                // Read timed out, go back around to send any replies
+                socket_errors = 0;
+                drained = 0;
+                continue;
+            }
+            Err(e) => {
+                socket_errors += 1;
+                error!("Failed to receive data ({} consecutive errors): {}", socket_errors, e);
+                if socket_errors >= MAX_SOCKET_ERRORS {
+                    error!("Too many socket errors, re-binding socket");
+                    drop(socket);
+                    socket = rebind_socket();
+                    socket_errors = 0;
+                }
+                continue;
+            }
+        }
+        let (sequence, control) = match decode_frame(packet, authenticator.as_deref()) {
+            Ok(decoded) => decoded,
+            Err(refused) => {
+                match refused.error {
+                    FrameError::BadCrc => {
+                        error!("CRC mismatch on packet from {}", from_addr);
+                        stats.count_malformed();
+                    }
+                    FrameError::Unauthorized(e) => {
+                        stats.count_auth_failure();
+                        if let Some(suppressed) = auth_log.allow(Instant::now()) {
+                            error!(
+                                "Unauthenticated command {} from {} ({:?}, {} more since the last)",
+                                refused.command, from_addr, e, suppressed
+                            );
+                        }
+                    }
+                    FrameError::NoHeader => {
+                        error!("Packet from {} too short for a header", from_addr);
+                        stats.count_malformed();
+                    }
+                    FrameError::Invalid(e) => {
+                        error!("Invalid packet from {}: {:?}", from_addr, e);
+                        stats.count_malformed();
+                    }
+                }
+                let reply = ReplyPacket::new(refused.command, refused.status(), ReplyPayload::Empty);
+                send_reply(&socket, from_addr, refused.sequence, &reply);
+                continue;
+            }
+        };
+
+        // A ping starts a fresh sequence, so a restarted client isn't locked out by its old numbers
+        if control == ControlPacket::Ping {
+            sequences.reset(from_addr);
+        }
+        if !sequences.accept(from_addr, sequence) {
+            warn!("Discarding stale packet {} from {}", sequence, from_addr);
+            stats.count_rejected();
+            continue;
+        }
+
+        // A client streaming moves faster than the limit has the excess refused here, before it reaches the queue
+        if control.is_motion() && !limiter.allow(from_addr, Instant::now()) {
+            stats.count_throttled();
+            if let Some(suppressed) = throttle_log.allow(Instant::now()) {
+                warn!("Throttling moves from {} ({} more since the last)", from_addr, suppressed);
+            }
+            let reply = ReplyPacket::new(control.command(), Status::Throttled, ReplyPayload::Empty);
+            send_reply(&socket, from_addr, sequence, &reply);
+            continue;
+        }
+
+        match commands.try_send(Command { addr: from_addr, sequence, packet: control, reply_to: ReplyTo::Socket }) {
+            Ok(_) => {},
+            // The newest move waits for room rather than being refused, so a burst ends on its last pose. The held
+            // move it replaces is refused instead
+            Err(TrySendError::Full(command))
+                if matches!(command.packet, ControlPacket::SetAngles(_))
+                    && !held.as_ref().is_some_and(|held| held.addr != command.addr) =>
+            {
+                if let Some(stale) = held.replace(command) {
+                    stats.count_rejected();
+                    let reply = ReplyPacket::new(stale.packet.command(), Status::Busy, ReplyPayload::Empty);
+                    send_reply(&socket, stale.addr, stale.sequence, &reply);
+                }
+            }
+            Err(TrySendError::Full(command)) => {
+                error!("Command queue full, refusing command from {}", from_addr);
+                stats.count_rejected();
+                send_reply(&socket, from_addr, sequence, &ReplyPacket::new(command.packet.command(), Status::Busy, ReplyPayload::Empty));
+            }
+            Err(TrySendError::Disconnected(_)) => panic!("Control task stopped"), // Nothing left to carry out commands
+        }
+    }
+}
+
+// Checks a frame's CRC when enabled and its tag when the command is protected, then decodes it. Every transport
+// goes through here, so a frame means the same however it arrives. The sequence is left to the caller to check
+pub fn decode_frame(
+    mut frame: &[u8],
+    authenticator: Option<&Mutex<Authenticator>>,
+) -> Result<(u16, ControlPacket), Refused> {
+    // Corrupted frames are refused before anything in them is trusted
+    if CONFIG.packet_crc {
+        match protocol::verify_crc(frame) {
+            Some(data) => frame = data,
+            None => {
+                let (sequence, command) = match protocol::split_header(frame) {
+                    Some((sequence, rest)) => (sequence, rest.first().copied().unwrap_or(0)),
+                    None => (0, 0),
+                };
+                return Err(Refused { sequence, command, error: FrameError::BadCrc });
+            }
+        }
+    }
+
+    // Protected commands are checked before anything else in them is trusted
+    let command = protocol::split_header(frame).and_then(|(_, rest)| rest.first().copied());
+    if let (Some(authenticator), Some(command)) = (authenticator, command) {
+        if auth::is_protected(command) {
+            let verified = authenticator.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).verify(frame);
+            match verified {
+                Ok(data) => frame = data,
+                Err(e) => {
+                    let sequence = protocol::split_header(frame).map_or(0, |(sequence, _)| sequence);
+                    return Err(Refused { sequence, command, error: FrameError::Unauthorized(e) });
+                }
+            }
+        }
+    }
+
+    // Every frame starts with a sequence number, the command byte and its payload follow
+    let (sequence, ctrl_vec) =
+        protocol::split_header(frame).ok_or(Refused { sequence: 0, command: 0, error: FrameError::NoHeader })?;
+    let command = ctrl_vec.first().copied().unwrap_or(0);
+    ControlPacket::decode(ctrl_vec)
+        .map(|control| (sequence, control))
+        .map_err(|e| Refused { sequence, command, error: FrameError::Invalid(e) })
+}
+
+// Queues a command from `addr` for the control task as this task would for a packet and waits for the answer. For
+// the transports that aren't answered through the socket
+#[cfg_attr(feature = "sim", allow(dead_code))]
+pub fn request(
+    commands: &SyncSender<Command>,
+    addr: SocketAddr,
+    packet: ControlPacket,
+) -> Result<ReplyPacket, RequestError> {
+    let (sender, reply) = mpsc::sync_channel(1);
+    match commands.try_send(Command { addr, sequence: 0, packet, reply_to: ReplyTo::Channel(sender) }) {
+        Ok(_) => {},
+        Err(TrySendError::Full(_)) => return Err(RequestError::Full),
+        Err(TrySendError::Disconnected(_)) => return Err(RequestError::Stopped),
+    }
+    reply.recv_timeout(REQUEST_TIMEOUT).map_err(|_| RequestError::TimedOut)
+}
+
+// Nothing can be received without a socket, so this keeps trying until the bind succeeds
+fn rebind_socket() -> UdpSocket {
+    loop {
+        match wifi_setup::init_socket(CONFIG.udp_port, Some(RECV_TIMEOUT)) {
+            Ok(socket) => return socket,
+            Err(e) => {
+                error!("Failed to re-bind socket, retrying in {} ms: {}", REBIND_DELAY.as_millis(), e);
+                thread::sleep(REBIND_DELAY);
+            }
+        }
+    }
+}
+
+// Function to receive data from UDP packet into buf and return the received part along with the source address
+fn recv_data<'a>(
+    socket: &UdpSocket,
+    buf: &'a mut [u8],
+) -> Result<Option<(&'a [u8], SocketAddr)>> {
+    match socket.recv_from(buf) {
+        Ok((size, src_addr)) => {
+            Ok(Some((&buf[..size], src_addr)))
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+            // WouldBlock (or TimedOut on some platforms) is the error kind for a read timeout
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+// A reply tagged with the sequence number of the packet it answers, followed by its CRC when enabled. A protocol 1
+// reply is neither
+pub fn encode_reply(sequence: u16, reply: &ReplyPacket) -> Vec<u8> {
+    let mut frame = reply.encode(sequence);
+    if CONFIG.packet_crc && !reply.is_legacy() {
+        protocol::append_crc(&mut frame);
+    }
+    frame
+}
+
+// Returns false if the socket refused the reply
+fn send_reply(socket: &UdpSocket, addr: SocketAddr, sequence: u16, reply: &ReplyPacket) -> bool {
+    match socket.send_to(&encode_reply(sequence, reply), addr) {
+        Ok(_) => true,
+        Err(e) => {
+            error!("Failed to send reply to command {}: {}", reply.command, e);
+            false
+        }
+    }
+}
@@ -0,0 +1,182 @@
+// Firmware updates downloaded into the spare OTA partition by a task of their own, rolled back unless marked valid
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "sim")]
+use std::thread;
+#[cfg(feature = "sim")]
+use std::time::Duration;
+
+#[cfg(not(feature = "sim"))]
+use embedded_svc::http::Method;
+#[cfg(not(feature = "sim"))]
+use embedded_svc::io::Write;
+#[cfg(not(feature = "sim"))]
+use esp_idf_svc::http::client::{Configuration as HttpConfig, EspHttpConnection};
+#[cfg(not(feature = "sim"))]
+use esp_idf_svc::ota::{EspOta, EspOtaUpdate};
+#[cfg(not(feature = "sim"))]
+use esp_idf_sys::EspError;
+use log::{error, info};
+#[cfg(not(feature = "sim"))]
+use thiserror::Error;
+
+use crate::tasks;
+
+pub const MAX_URL_SIZE: usize = 200; // Bytes of the URL in an update command
+#[cfg(not(feature = "sim"))]
+const CHUNK_SIZE: usize = 1024; // Read from the connection and written to flash at a time
+#[cfg(not(feature = "sim"))]
+const HTTP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum UpdateState {
+    #[default]
+    Idle,
+    Downloading { percent: Option<u8> }, // None without a Content-Length to go by
+    Failed(String),
+    Done, // Written and set as the boot partition, waiting for the restart
+}
+
+#[cfg(not(feature = "sim"))]
+#[derive(Debug, Error)]
+enum OtaError {
+    #[error("HTTP request failed: {0}")]
+    Http(EspError),
+    #[error("server answered {0}")]
+    Status(u16),
+    #[error("image ended after {0} of {1} bytes")]
+    Truncated(usize, usize),
+    #[error("flash write failed: {0}")]
+    Flash(EspError),
+}
+
+// Shared by the control loop and the download task
+#[derive(Clone, Default)]
+pub struct Updater {
+    state: Arc<Mutex<UpdateState>>,
+}
+
+impl Updater {
+    // Starts downloading the image in its own task, false while an update is already running
+    pub fn start(&self, url: String) -> bool {
+        {
+            let mut state = self.lock();
+            if matches!(*state, UpdateState::Downloading { .. } | UpdateState::Done) {
+                return false;
+            }
+            *state = UpdateState::Downloading { percent: None };
+        }
+        let updater = self.clone();
+        tasks::spawn(&tasks::OTA_TASK, move || {
+            info!("Downloading firmware from {}", url);
+            let state = match download(&url, &updater) {
+                Ok(_) => {
+                    info!("Firmware written, it boots at the next restart");
+                    UpdateState::Done
+                }
+                Err(e) => {
+                    error!("Firmware update failed: {}", e);
+                    UpdateState::Failed(e.to_string())
+                }
+            };
+            *updater.lock() = state;
+        });
+        true
+    }
+
+    // The current state, a failure is only reported once and then goes back to idle
+    pub fn poll(&self) -> UpdateState {
+        let mut state = self.lock();
+        let current = state.clone();
+        if matches!(current, UpdateState::Failed(_)) {
+            *state = UpdateState::Idle;
+        }
+        current
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, UpdateState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn set_percent(&self, percent: u8) {
+        *self.lock() = UpdateState::Downloading { percent: Some(percent) };
+    }
+}
+
+// Marks the running image as good so the bootloader won't roll it back, called once the limb can be reached
+#[cfg(not(feature = "sim"))]
+pub fn mark_valid() {
+    match EspOta::new().and_then(|mut ota| ota.mark_running_slot_valid()) {
+        Ok(_) => info!("Running firmware marked valid"),
+        Err(e) => error!("Failed to mark the running firmware valid: {}", e),
+    }
+}
+
+#[cfg(not(feature = "sim"))]
+fn download(url: &str, updater: &Updater) -> Result<(), OtaError> {
+    let mut connection = EspHttpConnection::new(&HttpConfig {
+        timeout: Some(HTTP_TIMEOUT),
+        crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach), // For https URLs
+        ..Default::default()
+    })
+    .map_err(OtaError::Http)?;
+    connection.initiate_request(Method::Get, url, &[]).map_err(OtaError::Http)?;
+    connection.initiate_response().map_err(OtaError::Http)?;
+    match connection.status() {
+        200 => {}
+        status => return Err(OtaError::Status(status)),
+    }
+    let total = connection.header("Content-Length").and_then(|length| length.parse::<usize>().ok());
+
+    let mut ota = EspOta::new().map_err(OtaError::Flash)?;
+    let mut update = ota.initiate_update().map_err(OtaError::Flash)?;
+    match copy_image(&mut connection, &mut update, total, updater) {
+        Ok(_) => update.complete().map_err(OtaError::Flash), // Checks the image and makes it the boot partition
+        Err(e) => {
+            if let Err(abort_error) = update.abort() {
+                error!("Failed to abort the update: {}", abort_error);
+            }
+            Err(e)
+        }
+    }
+}
+
+#[cfg(not(feature = "sim"))]
+fn copy_image(
+    connection: &mut EspHttpConnection,
+    update: &mut EspOtaUpdate,
+    total: Option<usize>,
+    updater: &Updater,
+) -> Result<(), OtaError> {
+    let mut buf = [0u8; CHUNK_SIZE];
+    let mut written = 0;
+    let mut shown = None;
+    loop {
+        let read = connection.read(&mut buf).map_err(OtaError::Http)?;
+        if read == 0 {
+            break;
+        }
+        update.write_all(&buf[..read]).map_err(|e| OtaError::Flash(e.0))?;
+        written += read;
+        if let Some(total) = total {
+            let percent = (written * 100 / total.max(1)).min(100) as u8;
+            if shown != Some(percent) {
+                shown = Some(percent);
+                updater.set_percent(percent);
+            }
+        }
+    }
+    match total {
+        Some(total) if written < total => Err(OtaError::Truncated(written, total)),
+        _ => Ok(()),
+    }
+}
+
+// There is no flash to write, the download only runs through its progress so the display and refusals can be tried
+#[cfg(feature = "sim")]
+fn download(_url: &str, updater: &Updater) -> Result<(), String> {
+    for percent in (0..=100).step_by(10) {
+        updater.set_percent(percent);
+        thread::sleep(Duration::from_millis(200));
+    }
+    Ok(())
+}
@@ -0,0 +1,570 @@
+// Display pages rotated by the control loop, each rendered from DisplayStatus and only sent to the display task when
+// its text changed. Without packets the display dims and then sleeps, the next packet or banner wakes it
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::backend::{Align, DisplayBackend, ServoBar};
+use crate::link::LinkStatus;
+use crate::protocol::{GripperMode, GripperStatus, LoopTiming};
+use crate::qr::QrCode;
+use crate::servo::{self, Servo, Tenths};
+use crate::stats::{self, Stats};
+use crate::{wrap_text, CONFIG};
+
+const REFRESH: Duration = Duration::from_millis(250); // Render rate limit, a moving arm would otherwise flood the bus
+const QR_CAPTION_COLUMNS: usize = 10; // Small font characters beside the code
+const MESSAGE_HOLD: Duration = Duration::from_secs(5); // How long text drawn over the pages stays up
+pub const CONFIG_SIZE: usize = 7;
+const CONFIG_VERSION: u8 = 2; // Bump when the stored display config layout changes
+const CONFIG_V1_SIZE: usize = 6; // No flipped flag, still loaded
+
+// Brightness and idle timers, set from the config command and kept in NVS
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DisplayConfig {
+    pub brightness: u8, // Contrast while awake, a quarter of it once dimmed
+    pub dim_s: u16,     // Seconds without a packet before dimming, 0 never dims
+    pub sleep_s: u16,   // Seconds without a packet before turning off, 0 never sleeps
+    pub flipped: bool,  // Turned 180 degrees for a panel mounted upside down
+}
+
+impl DisplayConfig {
+    // Layout: version, brightness, dim timeout, sleep timeout, flipped (u8), all little-endian
+    pub fn to_bytes(self) -> [u8; CONFIG_SIZE] {
+        let mut bytes = [0u8; CONFIG_SIZE];
+        bytes[0] = CONFIG_VERSION;
+        bytes[1] = self.brightness;
+        bytes[2..4].copy_from_slice(&self.dim_s.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.sleep_s.to_le_bytes());
+        bytes[6] = self.flipped as u8;
+        bytes
+    }
+
+    // Returns None for blobs of the wrong size or version
+    pub fn from_bytes(bytes: &[u8]) -> Option<DisplayConfig> {
+        match (bytes.first(), bytes.len()) {
+            (Some(&CONFIG_VERSION), CONFIG_SIZE) | (Some(1), CONFIG_V1_SIZE) => {}
+            _ => return None,
+        }
+        Some(DisplayConfig {
+            brightness: bytes[1],
+            dim_s: u16::from_le_bytes([bytes[2], bytes[3]]),
+            sleep_s: u16::from_le_bytes([bytes[4], bytes[5]]),
+            flipped: bytes.get(6).is_some_and(|&flipped| flipped != 0),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Page {
+    Servos,    // Current and goal angles, the gripper and the battery
+    ServoBars, // The same angles as bars, with the goal marked
+    Network,   // Address, signal strength and session owner
+    Pairing,   // The address as a QR code for a phone to scan
+    Stats,     // Uptime and packet counters
+    Timing,    // Control loop passes, each phase's mean and max
+}
+
+// A rendered page, compared with the last one sent to skip redraws
+#[derive(PartialEq)]
+enum Frame {
+    Lines(Vec<String>),
+    ServoBars(Vec<ServoBar>),
+    Qr { code: QrCode, caption: Vec<String> },
+}
+
+impl Page {
+    // Order of the rotation, a new page goes here and in render
+    const ROTATION: [Page; 6] =
+        [Page::Servos, Page::ServoBars, Page::Network, Page::Pairing, Page::Stats, Page::Timing];
+
+    fn next(self) -> Page {
+        let index = Page::ROTATION.iter().position(|&page| page == self).unwrap_or(0);
+        Page::ROTATION[(index + 1) % Page::ROTATION.len()]
+    }
+
+    // Laid out for `rows` lines, longer pages are split into screens by Pages
+    fn render(self, status: &DisplayStatus, pairing: &mut PairingCode, rows: usize) -> Frame {
+        let lines = match self {
+            // The header heads every screen, and says so while paused
+            Page::Servos => {
+                let mut lines = Vec::new();
+                let header = if status.paused { "PAUSED      now  goal" } else { "Servo       now  goal" };
+                for joints in status.joints.chunks(rows.saturating_sub(1).max(1)) {
+                    lines.push(header.to_string());
+                    lines.extend(joints.iter().map(|joint| {
+                        format!("{:<10.9}{:>5}{:>6}", joint.name, Tenths(joint.angle), Tenths(joint.goal))
+                    }));
+                }
+                // Percent closed now and as commanded, the label tells a force-limited grip from a plain move
+                if let Some(gripper) = status.gripper {
+                    let label = if gripper.last == Some(GripperMode::Grip) { "Grip limit" } else { "Gripper" };
+                    lines.push(format!("{:<12.11}{:>3}%{:>4}%", label, gripper.percent, gripper.target));
+                }
+                if status.battery_mv.is_some() {
+                    lines.push(format!("Battery {}", crate::format_volts(status.battery_mv)));
+                }
+                // Only while slowed, so a limb crawling on purpose isn't mistaken for a fault
+                if status.speed_scale < servo::FULL_SPEED {
+                    lines.push(format!("Speed {}%", status.speed_scale));
+                }
+                lines
+            }
+            Page::ServoBars => {
+                let bars = status
+                    .joints
+                    .iter()
+                    .map(|joint| ServoBar {
+                        name: joint.name.clone(),
+                        angle: joint.angle,
+                        goal: joint.goal,
+                        max: joint.max,
+                    })
+                    .collect();
+                return Frame::ServoBars(bars);
+            }
+            // The network page stands in while WiFi is down or if the address doesn't fit in a code
+            Page::Pairing => {
+                let address = format!("{}:{}", status.link.ip, CONFIG.udp_port);
+                let code = match status.link.up {
+                    true => pairing.get(&format!("{}{}", CONFIG.pairing_scheme, address)),
+                    false => None,
+                };
+                return match code {
+                    // The address comes first on a display without room for both
+                    Some(code) => {
+                        let address: Vec<String> =
+                            wrap_text(&address, QR_CAPTION_COLUMNS, 3).lines().map(str::to_string).collect();
+                        let mut caption = Vec::new();
+                        if rows >= address.len() + 3 {
+                            caption.extend(["Scan to".to_string(), "pair".to_string(), String::new()]);
+                        }
+                        caption.extend(address);
+                        Frame::Qr { code: code.clone(), caption }
+                    }
+                    None => Page::Network.render(status, pairing, rows),
+                };
+            }
+            Page::Network => {
+                let mut lines: Vec<String> = match status.link.up {
+                    true => crate::status_page(status.link.ip).lines().map(str::to_string).collect(),
+                    false => vec!["WiFi down".to_string(), format!("reconnecting (attempt {})", status.link.attempt)],
+                };
+                lines.push(match stats::rssi() {
+                    Some(rssi) => format!("RSSI {} dBm", rssi),
+                    None => "RSSI --".to_string(),
+                });
+                lines.push(match status.owner {
+                    Some(owner) => format!("Owner: {}", owner),
+                    None => "Owner: open to all".to_string(),
+                });
+                lines
+            }
+            Page::Stats => {
+                let uptime_s = status.stats.uptime_s();
+                let counts = status.stats.get();
+                vec![
+                    format!("Uptime {}:{:02}:{:02}", uptime_s / 3600, uptime_s / 60 % 60, uptime_s % 60),
+                    format!("Packets {}", counts.received),
+                    format!("Rejected {}", counts.rejected),
+                    format!("Throttled {}", counts.throttled),
+                    format!("Moves {} dup {}", counts.applied, counts.deduplicated),
+                    format!("Malformed {}", counts.malformed),
+                    format!("Auth fails {}", counts.auth_failures),
+                    format!("Free heap {} B", stats::free_heap()),
+                    match status.temperature_c {
+                        Some(celsius) => format!("Chip {} C", celsius),
+                        None => "Chip -- C".to_string(),
+                    },
+                ]
+            }
+            // Microseconds, the whole pass first
+            Page::Timing => {
+                let timing = status.timing;
+                let mut lines = vec![
+                    format!("Loop {} slow {}", timing.passes, timing.slow),
+                    format!("{:<9}{:>6}{:>6}", "us", "mean", "max"),
+                ];
+                let names = ["Pass", "Recv", "Dispatch", "Servos", "Display"];
+                for (name, phase) in names.iter().zip(timing.phases.iter()) {
+                    lines.push(format!("{:<9}{:>6}{:>6}", name, phase.mean_us, phase.max_us));
+                }
+                lines
+            }
+        };
+        Frame::Lines(lines)
+    }
+}
+
+// Encoded again only when the address changes, None while it doesn't fit
+#[derive(Default)]
+struct PairingCode {
+    payload: String,
+    code: Option<QrCode>,
+}
+
+impl PairingCode {
+    fn get(&mut self, payload: &str) -> Option<&QrCode> {
+        if payload != self.payload || self.payload.is_empty() {
+            self.code = QrCode::encode(payload.as_bytes());
+            if self.code.is_none() {
+                warn!("{} is too long for a pairing code", payload);
+            }
+            self.payload = payload.to_string();
+        }
+        self.code.as_ref()
+    }
+}
+
+struct JointReading {
+    name: String,
+    angle: u16,
+    goal: u16,
+    max: u16,
+}
+
+// What the pages show, kept up to date by the control loop. The counters are read from the shared Stats as a page is
+// rendered
+pub struct DisplayStatus {
+    joints: Vec<JointReading>,
+    pub battery_mv: Option<u16>,
+    pub temperature_c: Option<i8>,
+    pub gripper: Option<GripperStatus>,
+    pub speed_scale: u8,
+    pub paused: bool,
+    pub link: LinkStatus,
+    pub owner: Option<IpAddr>,
+    pub timing: LoopTiming,
+    stats: Stats,
+}
+
+impl DisplayStatus {
+    pub fn new(servos: &[Servo], link: LinkStatus, stats: Stats) -> DisplayStatus {
+        let joints = servos
+            .iter()
+            .map(|servo| JointReading {
+                name: servo.get_name().to_string(),
+                angle: servo.get_angle(),
+                goal: servo.get_goal(),
+                max: servo.get_max_angle(),
+            })
+            .collect();
+        DisplayStatus {
+            joints,
+            battery_mv: None,
+            temperature_c: None,
+            gripper: None,
+            speed_scale: servo::FULL_SPEED,
+            paused: false,
+            link,
+            owner: None,
+            timing: LoopTiming::default(),
+            stats,
+        }
+    }
+
+    pub fn set_joints(&mut self, servos: &[Servo]) {
+        for (joint, servo) in self.joints.iter_mut().zip(servos) {
+            joint.angle = servo.get_angle();
+            joint.goal = servo.get_goal();
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Power {
+    Awake,
+    Dimmed,
+    Asleep,
+}
+
+// What is on the display besides the current page
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Hold {
+    Pages,
+    Message(Instant), // Until then
+    Banner,           // Until released
+}
+
+pub struct Pages<D: DisplayBackend> {
+    display: D,
+    page: Page,
+    interval: Option<Duration>, // None keeps the first page
+    rotate_at: Instant,
+    refresh_at: Instant,
+    hold: Hold,
+    shown: Option<Frame>, // Last page sent, None once something else is drawn so the next page always goes
+    pairing: PairingCode,
+    screen: usize,  // Of the current page
+    screens: usize, // The current page took when last rendered
+    config: DisplayConfig,
+    active_at: Instant,   // Last packet or banner, the idle timers count from here
+    power: Option<Power>, // As last set on the display, None until it has been
+}
+
+impl<D: DisplayBackend> Pages<D> {
+    pub fn new(display: D, page: Page, interval: Option<Duration>, config: DisplayConfig) -> Pages<D> {
+        let now = Instant::now();
+        Pages {
+            display,
+            page,
+            interval,
+            rotate_at: now + interval.unwrap_or_default(),
+            refresh_at: now,
+            hold: Hold::Pages,
+            shown: None,
+            pairing: PairingCode::default(),
+            screen: 0,
+            screens: 1,
+            config,
+            active_at: now,
+            power: None,
+        }
+    }
+
+    // Restarts the idle timers, a dimmed or sleeping display comes back on the next tick
+    pub fn wake(&mut self, now: Instant) {
+        self.active_at = now;
+    }
+
+    pub fn get_config(&self) -> DisplayConfig {
+        self.config
+    }
+
+    // Takes effect on the next tick
+    pub fn set_config(&mut self, config: DisplayConfig) {
+        self.config = config;
+        self.power = None;
+    }
+
+    // Goes back to the pages after a banner or message, the page carries on where it was
+    pub fn release(&mut self) {
+        if self.hold != Hold::Pages {
+            self.resume(Instant::now());
+        }
+    }
+
+    // Releases anything held and jumps to the page
+    pub fn show(&mut self, page: Page) {
+        self.page = page;
+        self.screen = 0;
+        self.resume(Instant::now());
+    }
+
+    // Steps to the next screen or page straight away, the rotation carries on from it. Returns false while a banner is
+    // held, which only its release takes down
+    pub fn next_page(&mut self) -> bool {
+        if self.hold == Hold::Banner {
+            return false;
+        }
+        self.active_at = Instant::now();
+        self.screen += 1;
+        if self.screen >= self.screens {
+            self.page = self.page.next();
+            self.screen = 0;
+        }
+        self.resume(Instant::now());
+        true
+    }
+
+    // Called every pass of the control loop, rotates and redraws the page when due
+    pub fn tick(&mut self, status: &DisplayStatus, now: Instant) {
+        let power = self.power_at(now);
+        if self.power.is_none() {
+            self.display.set_flipped(self.config.flipped);
+        }
+        if self.power != Some(power) {
+            self.set_power(power);
+        }
+        if power == Power::Asleep {
+            return; // Nothing to see, the page is drawn once it wakes
+        }
+        match self.hold {
+            Hold::Pages => {},
+            Hold::Message(until) if now >= until => self.resume(now),
+            Hold::Message(_) | Hold::Banner => return,
+        }
+        match self.interval {
+            Some(interval) if now >= self.rotate_at => {
+                self.screen += 1;
+                if self.screen >= self.screens {
+                    self.page = self.page.next();
+                    self.screen = 0;
+                }
+                self.rotate_at = now + interval;
+                self.refresh_at = now;
+            }
+            _ => {},
+        }
+        if now < self.refresh_at {
+            return;
+        }
+        self.refresh_at = now + REFRESH;
+        let rows = self.display.rows().max(1);
+        let frame = match self.page.render(status, &mut self.pairing, rows) {
+            Frame::Lines(lines) => {
+                self.screens = lines.chunks(rows).len().max(1);
+                self.screen = self.screen.min(self.screens - 1); // The page may have shrunk since
+                Frame::Lines(lines.chunks(rows).nth(self.screen).map(<[String]>::to_vec).unwrap_or_default())
+            }
+            frame => {
+                self.screens = 1;
+                self.screen = 0;
+                frame
+            }
+        };
+        if self.shown.as_ref() != Some(&frame) {
+            match &frame {
+                Frame::Lines(lines) => {
+                    self.display.draw_lines(&lines.iter().map(String::as_str).collect::<Vec<&str>>(), Align::Left)
+                }
+                Frame::ServoBars(bars) => self.display.draw_servo_bars(bars),
+                Frame::Qr { code, caption } => {
+                    self.display.draw_qr(code, &caption.iter().map(String::as_str).collect::<Vec<&str>>())
+                }
+            }
+            self.shown = Some(frame);
+        }
+    }
+
+    fn power_at(&self, now: Instant) -> Power {
+        let idle = now.duration_since(self.active_at);
+        let idle_for = |s: u16| s != 0 && idle >= Duration::from_secs(s as u64);
+        if idle_for(self.config.sleep_s) {
+            Power::Asleep
+        } else if idle_for(self.config.dim_s) {
+            Power::Dimmed
+        } else {
+            Power::Awake
+        }
+    }
+
+    fn set_power(&mut self, power: Power) {
+        match power {
+            Power::Awake => self.display.set_brightness(self.config.brightness),
+            Power::Dimmed => self.display.set_brightness(self.config.brightness / 4),
+            Power::Asleep => {},
+        }
+        match (self.power, power) {
+            (_, Power::Asleep) => self.display.set_display_on(false),
+            (Some(Power::Asleep) | None, _) => self.display.set_display_on(true),
+            _ => {},
+        }
+        self.power = Some(power);
+    }
+
+    // The page gets its full interval again and is redrawn on the next tick
+    fn resume(&mut self, now: Instant) {
+        self.hold = Hold::Pages;
+        self.rotate_at = now + self.interval.unwrap_or_default();
+        self.refresh_at = now;
+        self.shown = None;
+    }
+}
+
+impl<D: DisplayBackend> DisplayBackend for Pages<D> {
+    fn draw_new_text(&mut self, x: i32, y: i32, text: &str) {
+        self.hold = Hold::Message(Instant::now() + MESSAGE_HOLD);
+        self.shown = None;
+        self.display.draw_new_text(x, y, text);
+    }
+
+    fn draw_lines(&mut self, lines: &[&str], align: Align) {
+        self.hold = Hold::Message(Instant::now() + MESSAGE_HOLD);
+        self.shown = None;
+        self.display.draw_lines(lines, align);
+    }
+
+    // Alarms like the e-stop button wake the display
+    fn draw_banner(&mut self, banner: &str, text: &str) {
+        self.wake(Instant::now());
+        self.hold = Hold::Banner;
+        self.shown = None;
+        self.display.draw_banner(banner, text);
+    }
+
+    // Only the pages draw bars, so this doesn't hold the rotation
+    fn draw_servo_bars(&mut self, bars: &[ServoBar]) {
+        self.shown = None;
+        self.display.draw_servo_bars(bars);
+    }
+
+    // Nor the pairing code
+    fn draw_qr(&mut self, code: &QrCode, caption: &[&str]) {
+        self.shown = None;
+        self.display.draw_qr(code, caption);
+    }
+
+    // The idle timers set these again on their next change
+    fn set_brightness(&mut self, brightness: u8) {
+        self.display.set_brightness(brightness);
+    }
+
+    fn set_display_on(&mut self, on: bool) {
+        self.display.set_display_on(on);
+    }
+
+    fn set_flipped(&mut self, flipped: bool) {
+        self.display.set_flipped(flipped);
+    }
+
+    fn rows(&self) -> usize {
+        self.display.rows()
+    }
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+    use crate::sim::MockDisplay;
+
+    const CONFIG: DisplayConfig = DisplayConfig { brightness: 200, dim_s: 30, sleep_s: 300, flipped: false };
+
+    #[test]
+    fn config_round_trips() {
+        assert_eq!(DisplayConfig::from_bytes(&CONFIG.to_bytes()), Some(CONFIG));
+        assert_eq!(DisplayConfig::from_bytes(&CONFIG.to_bytes()[..CONFIG_SIZE - 1]), None);
+    }
+
+    #[test]
+    fn version_one_configs_load_unflipped() {
+        let flipped = DisplayConfig { flipped: true, ..CONFIG };
+        let mut bytes = flipped.to_bytes()[..CONFIG_V1_SIZE].to_vec();
+        bytes[0] = 1;
+        assert_eq!(DisplayConfig::from_bytes(&bytes), Some(CONFIG));
+    }
+
+    #[test]
+    fn display_dims_then_sleeps_until_woken() {
+        let mut pages = Pages::new(MockDisplay::default(), Page::Servos, None, CONFIG);
+        let start = pages.active_at;
+        assert_eq!(pages.power_at(start + Duration::from_secs(29)), Power::Awake);
+        assert_eq!(pages.power_at(start + Duration::from_secs(30)), Power::Dimmed);
+        assert_eq!(pages.power_at(start + Duration::from_secs(300)), Power::Asleep);
+        pages.wake(start + Duration::from_secs(300));
+        assert_eq!(pages.power_at(start + Duration::from_secs(301)), Power::Awake);
+    }
+
+    #[test]
+    fn next_page_steps_through_the_screens_then_pages_unless_a_banner_is_held() {
+        let mut pages = Pages::new(MockDisplay::default(), Page::Servos, None, CONFIG);
+        pages.screens = 2;
+        assert!(pages.next_page());
+        assert_eq!((pages.page, pages.screen), (Page::Servos, 1));
+        assert!(pages.next_page());
+        assert_eq!((pages.page, pages.screen), (Page::Servos.next(), 0));
+        pages.draw_banner("E-STOP", "Button pressed");
+        assert!(!pages.next_page());
+        assert_eq!(pages.page, Page::Servos.next());
+        pages.release();
+        assert!(pages.next_page());
+    }
+
+    #[test]
+    fn zero_timers_never_dim_or_sleep() {
+        let config = DisplayConfig { dim_s: 0, sleep_s: 0, ..CONFIG };
+        let pages = Pages::new(MockDisplay::default(), Page::Servos, None, config);
+        assert_eq!(pages.power_at(pages.active_at + Duration::from_secs(u16::MAX as u64)), Power::Awake);
+    }
+}
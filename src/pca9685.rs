@@ -0,0 +1,109 @@
+// PCA9685 16 channel PWM expander on the shared I2C bus, each channel can drive a servo
+use esp_idf_hal::delay::FreeRtos;
+use esp_idf_sys::{EspError, ESP_ERR_INVALID_ARG, ESP_ERR_INVALID_STATE};
+use log::info;
+
+use crate::backend::{DriverError, ServoBackend};
+use crate::crash;
+use crate::shared_i2c::SharedI2c;
+
+pub const CHANNEL_COUNT: u8 = 16;
+const MAX_DUTY: u32 = 4095; // 12 bit counter, so a 50 Hz frame is split into 4096 steps like the LEDC timer
+const OSCILLATOR_HZ: u32 = 25_000_000; // Internal oscillator
+
+// Registers
+const MODE1: u8 = 0x00;
+const LED0_ON_L: u8 = 0x06; // Each channel has ON_L, ON_H, OFF_L and OFF_H from here
+const ALL_LED_OFF_H: u8 = 0xFD;
+const PRESCALE: u8 = 0xFE;
+
+const MODE1_SLEEP: u8 = 0x10;
+const MODE1_AUTO_INCREMENT: u8 = 0x20;
+const FULL_OFF: u8 = 0x10; // Bit in OFF_H that holds a channel low
+
+// Prescaler for a PWM frequency, round(oscillator / (4096 * hz)) - 1 from the datasheet
+pub fn prescale(hz: u32) -> u8 {
+    let hz = hz.max(1);
+    let divider = (OSCILLATOR_HZ + 2048 * hz) / (4096 * hz);
+    divider.saturating_sub(1).clamp(3, 255) as u8 // The chip ignores values below 3
+}
+
+// Sets the PWM frequency and register auto-increment, every channel starts off
+pub fn init(bus: &SharedI2c, address: u8, hz: u32) -> Result<(), DriverError> {
+    bus.write(address, &[MODE1, MODE1_SLEEP])?; // The prescaler can only be written while asleep
+    bus.write(address, &[PRESCALE, prescale(hz)])?;
+    bus.write(address, &[ALL_LED_OFF_H, FULL_OFF])?;
+    bus.write(address, &[MODE1, MODE1_AUTO_INCREMENT])?;
+    FreeRtos::delay_ms(1); // The oscillator takes 500 us to start after waking
+    info!("PCA9685 at {:#04x} running at {} Hz", address, hz);
+    Ok(())
+}
+
+// Holds every channel low, None if the bus is busy
+pub fn try_stop_all(bus: &SharedI2c, address: u8) -> Option<Result<(), DriverError>> {
+    bus.try_write(address, &[ALL_LED_OFF_H, FULL_OFF])
+}
+
+pub struct Pca9685Channel {
+    bus: SharedI2c,
+    address: u8,
+    channel: u8,
+    duty: u32,
+    enabled: bool,
+}
+
+impl Pca9685Channel {
+    // The chip must have been set up with init() first
+    pub fn new(bus: SharedI2c, address: u8, channel: u8) -> Pca9685Channel {
+        Pca9685Channel {
+            bus,
+            address,
+            channel,
+            duty: 0,
+            enabled: true,
+        }
+    }
+
+    // The output goes high at the start of the frame and low after `duty` steps
+    fn write_channel(&self, off_l: u8, off_h: u8) -> Result<(), DriverError> {
+        let register = LED0_ON_L + 4 * self.channel;
+        self.bus.write(self.address, &[register, 0, 0, off_l, off_h])
+    }
+}
+
+impl ServoBackend for Pca9685Channel {
+    fn set_duty(&mut self, duty: u32) -> Result<(), DriverError> {
+        if duty > MAX_DUTY {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_ARG>()); // Same as LedcDriver
+        }
+        self.duty = duty;
+        if !self.enabled {
+            return Ok(());
+        }
+        if crash::in_progress() {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>()); // The panic hook switched every channel off
+        }
+        self.write_channel(duty as u8, (duty >> 8) as u8)
+    }
+
+    fn get_duty(&self) -> u32 {
+        self.duty
+    }
+
+    fn get_max_duty(&self) -> u32 {
+        MAX_DUTY
+    }
+
+    fn disable(&mut self) -> Result<(), DriverError> {
+        self.enabled = false;
+        self.write_channel(0, FULL_OFF)
+    }
+
+    fn enable(&mut self) -> Result<(), DriverError> {
+        if crash::in_progress() {
+            return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>());
+        }
+        self.enabled = true;
+        self.write_channel(self.duty as u8, (self.duty >> 8) as u8)
+    }
+}
@@ -0,0 +1,132 @@
+use crate::protocol::{POWER_ON_SLOT, SERVO_COUNT};
+use crate::servo;
+
+pub const SLOT_COUNT: u8 = 8; // Poses that can be stored in NVS
+pub const MAX_NAME_SIZE: usize = 16; // Bytes, fits on one line of the display
+pub const MAX_BLOB_SIZE: usize = 3 + MAX_NAME_SIZE + SERVO_COUNT * 2;
+pub const LAST_POSE_SIZE: usize = 3 + SERVO_COUNT * 2;
+const BLOB_VERSION: u8 = 2; // Bump when the stored pose layout changes
+const LAST_POSE_VERSION: u8 = 2; // Bump when the stored last pose layout changes
+const DEGREES_VERSION: u8 = 1; // Both layouts held whole degrees before they held tenths, and are still loaded
+
+// The stored slots and the power-on pose, which is saved, recalled and cleared like any other but isn't listed
+pub fn is_slot(slot: u8) -> bool {
+    slot < SLOT_COUNT || slot == POWER_ON_SLOT
+}
+
+// A pose saved from the servos' angles, recalled by slot
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Preset {
+    pub name: String, // May be empty
+    pub angles: Vec<u16>,
+}
+
+impl Preset {
+    // Layout: version, name length, name, angle count, then the angles, little-endian like the other blobs
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(3 + self.name.len() + self.angles.len() * 2);
+        bytes.push(BLOB_VERSION);
+        bytes.push(self.name.len() as u8);
+        bytes.extend_from_slice(self.name.as_bytes());
+        bytes.push(self.angles.len() as u8);
+        for angle in &self.angles {
+            bytes.extend_from_slice(&angle.to_le_bytes());
+        }
+        bytes
+    }
+
+    // Returns None for blobs of the wrong version or with lengths that don't match their size
+    pub fn from_bytes(bytes: &[u8]) -> Option<Preset> {
+        let (&version, rest) = bytes.split_first()?;
+        let (&name_len, rest) = rest.split_first()?;
+        let degrees = match version {
+            BLOB_VERSION => false,
+            DEGREES_VERSION => true,
+            _ => return None,
+        };
+        if name_len as usize > MAX_NAME_SIZE || rest.len() <= name_len as usize {
+            return None;
+        }
+        let (name, rest) = rest.split_at(name_len as usize);
+        let (&count, angles) = rest.split_first()?;
+        if angles.len() != count as usize * 2 {
+            return None;
+        }
+        Some(Preset { name: String::from_utf8(name.to_vec()).ok()?, angles: read_angles(angles, degrees) })
+    }
+}
+
+// The pose kept through a restart. It is only clean when written as the servos were stopped by a shutdown, a crash or
+// brown-out leaves the last periodic save, which may be a few degrees and seconds behind
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LastPose {
+    pub clean: bool,
+    pub angles: Vec<u16>,
+}
+
+impl LastPose {
+    // Layout: version, clean flag, angle count, then the angles, little-endian like the other blobs
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(3 + self.angles.len() * 2);
+        bytes.push(LAST_POSE_VERSION);
+        bytes.push(self.clean as u8);
+        bytes.push(self.angles.len() as u8);
+        for angle in &self.angles {
+            bytes.extend_from_slice(&angle.to_le_bytes());
+        }
+        bytes
+    }
+
+    // Returns None for blobs of the wrong version or with a count that doesn't match their size
+    pub fn from_bytes(bytes: &[u8]) -> Option<LastPose> {
+        let (&version, rest) = bytes.split_first()?;
+        let (&clean, rest) = rest.split_first()?;
+        let (&count, angles) = rest.split_first()?;
+        let degrees = match version {
+            LAST_POSE_VERSION => false,
+            DEGREES_VERSION => true,
+            _ => return None,
+        };
+        if angles.len() != count as usize * 2 {
+            return None;
+        }
+        Some(LastPose { clean: clean != 0, angles: read_angles(angles, degrees) })
+    }
+}
+
+// Little-endian angles in tenths, or in whole degrees from an older blob
+fn read_angles(bytes: &[u8], degrees: bool) -> Vec<u16> {
+    let angles = bytes.chunks_exact(2).map(|angle| u16::from_le_bytes([angle[0], angle[1]]));
+    match degrees {
+        true => angles.map(servo::from_degrees).collect(),
+        false => angles.collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn last_pose_round_trips_and_refuses_bad_blobs() {
+        let pose = LastPose { clean: true, angles: vec![905, 450, 1800, 0, 1200] };
+        let bytes = pose.to_bytes();
+        assert_eq!(bytes.len(), LAST_POSE_SIZE);
+        assert_eq!(LastPose::from_bytes(&bytes), Some(pose));
+        assert_eq!(LastPose::from_bytes(&bytes[..bytes.len() - 1]), None);
+        assert_eq!(LastPose::from_bytes(&[]), None);
+        let mut newer = bytes;
+        newer[0] = LAST_POSE_VERSION + 1;
+        assert_eq!(LastPose::from_bytes(&newer), None);
+    }
+
+    #[test]
+    fn blobs_in_whole_degrees_still_load_as_tenths() {
+        let mut last = LastPose { clean: false, angles: vec![90, 45] }.to_bytes();
+        last[0] = DEGREES_VERSION;
+        assert_eq!(LastPose::from_bytes(&last), Some(LastPose { clean: false, angles: vec![900, 450] }));
+        let mut preset = Preset { name: "home".into(), angles: vec![90, 180] }.to_bytes();
+        preset[0] = DEGREES_VERSION;
+        assert_eq!(Preset::from_bytes(&preset), Some(Preset { name: "home".into(), angles: vec![900, 1800] }));
+    }
+}
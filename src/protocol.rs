@@ -0,0 +1,2184 @@
+// Wire format shared with the desktop client, all multi-byte values are big-endian both ways. Angle lists go through
+// encode_angles and decode_angles so moves and their replies can't disagree again. Protocol 1 replied to moves and
+// pings with bare little-endian angles, legacy_byte_order in the config keeps that for clients that expect it.
+// Angles are whole degrees both ways, except in POSE_TENTHS_COMMAND and TELEMETRY_TENTHS_COMMAND and their replies,
+// which carry tenths of a degree. Whole degrees are scaled up to the tenths the limb works in as they are decoded
+// Packet: sequence (u16), command byte, payload, CRC-8 (when enabled)
+// Reply:  sequence (u16), echoed command byte, status, payload, CRC-8 (when enabled)
+
+use std::net::Ipv4Addr;
+
+use crate::battery::BatteryConfig;
+use crate::current::CurrentCalibration;
+use crate::joints::JOINTS;
+use crate::pages::DisplayConfig;
+use crate::ota;
+use crate::preset;
+use crate::remote_log;
+use crate::servo::{self, Easing};
+use crate::settings;
+use crate::trajectory::{self, Keyframe};
+
+pub const PROTOCOL_VERSION: u8 = 2; // Advertised over mDNS, bump when a change breaks existing clients
+pub const SERVO_COUNT: usize = JOINTS.len();
+pub const HEADER_SIZE: usize = 2; // u16 sequence number in front of the command byte
+pub const CRC_SIZE: usize = 1; // CRC-8 trailing every packet and reply when checksums are enabled
+const TAG_SIZE: usize = 3; // Flags and move id trailing a tagged pose or trajectory play
+pub const MAX_COMMAND_SIZE: usize = 2 + trajectory::MAX_FRAMES * trajectory::FRAME_SIZE; // A full trajectory upload is the largest
+
+pub const MOVE_COMMAND: u8 = 0; // Angle count then one angle per servo
+pub const PING_COMMAND: u8 = 1;
+pub const CONFIG_COMMAND: u8 = 2;
+pub const LIMITS_COMMAND: u8 = 3;
+pub const CALIBRATION_COMMAND: u8 = 4;
+pub const POSE_COMMAND: u8 = 5; // Move payload, a duration every servo arrives together in, an optional easing and tag
+pub const TRAJECTORY_UPLOAD_COMMAND: u8 = 6; // Frame count then that many keyframes, held in RAM until stored
+pub const TRAJECTORY_STORE_COMMAND: u8 = 7;
+pub const TRAJECTORY_PLAY_COMMAND: u8 = 8; // Slot and whether to loop, optionally followed by a move tag
+pub const TELEMETRY_COMMAND: u8 = 9; // Health check that never moves the servos
+pub const CLAIM_COMMAND: u8 = 10; // Takes the session, other clients can then only ping, query telemetry and e-stop
+pub const RELEASE_COMMAND: u8 = 11;
+pub const SUBSCRIBE_COMMAND: u8 = 12; // Heartbeat interval in ms, 0 unsubscribes
+pub const HEARTBEAT_COMMAND: u8 = 13; // Only ever sent by the limb, to the subscribed client
+pub const SHUTDOWN_COMMAND: u8 = 14; // Parks and stops the servos, then refuses motion until power is cycled
+pub const REBOOT_COMMAND: u8 = 15; // Parks and stops the servos, then restarts
+pub const JOINT_COMMAND: u8 = 16; // Servo index and angle, optionally followed by a speed for this move
+pub const PRESET_SAVE_COMMAND: u8 = 17; // Slot then an optional name filling the rest, stores the current angles
+pub const PRESET_RECALL_COMMAND: u8 = 18; // Slot and the duration every servo arrives together in
+pub const PRESET_LIST_COMMAND: u8 = 19;
+pub const PRESET_CLEAR_COMMAND: u8 = 20;
+pub const LOG_LEVEL_COMMAND: u8 = 21; // Level forwarded to the log collector, 0 off to 5 trace, none only reads it
+pub const LOG_TARGET_COMMAND: u8 = 22; // Flags, console level, then a target filling the rest, none sets the default
+pub const OTA_COMMAND: u8 = 23; // Firmware image URL filling the payload, parks the servos, downloads it and restarts
+pub const STATUS_COMMAND: u8 = 24; // Every joint's angle and goal with the safety flags, never moves the servos
+pub const GRIPPER_COMMAND: u8 = 25; // Mode then percent closed, none only reads the gripper
+pub const TEACH_COMMAND: u8 = 26; // Starts or stops recording into the upload, which is stored and played as uploaded
+pub const SPEED_COMMAND: u8 = 27; // Flags then the percent of full speed every move runs at, none only reads it
+pub const PAUSE_COMMAND: u8 = 28; // Freezes the servos and any trajectory where they are, still energized
+pub const RESUME_COMMAND: u8 = 29;
+pub const SELF_TEST_COMMAND: u8 = 30; // Wiggles each servo in turn to check its wiring, none only reads the results
+pub const MIRROR_COMMAND: u8 = 31; // Flags then whether moves are mirrored onto this limb, none only reads it
+pub const LOOP_TIMING_COMMAND: u8 = 32; // Control loop timing, LOOP_TIMING_RESET starts the counters afresh
+pub const MOVE_DONE_COMMAND: u8 = 33; // Only ever sent by the limb, to the client whose tagged move ended
+pub const JOG_COMMAND: u8 = 34; // Servo index and a signed step in tenths of a degree, added to the joint's goal
+pub const POSE_TENTHS_COMMAND: u8 = 35; // Pose payload with angles in tenths of a degree, replied with tenths
+pub const TELEMETRY_TENTHS_COMMAND: u8 = 36; // Telemetry with the joints' angles in tenths of a degree
+pub const HISTORY_COMMAND: u8 = 37; // Chunk of the command history to read, none streams every chunk
+pub const CURRENT_COMMAND: u8 = 38; // Clears an overloaded joint or calibrates a current sensor, none only reads them
+pub const SLEEP_COMMAND: u8 = 39; // Confirmation byte and an optional wake timer in s, parks the servos and sleeps
+pub const CLEAR_ESTOP_COMMAND: u8 = 0xFE;
+pub const ESTOP_COMMAND: u8 = 0xFF; // De-energizes every servo and latches until CLEAR_ESTOP_COMMAND
+
+pub const FAILSAFE_CONFIG_INDEX: u8 = 0xFF; // Config command index addressing the failsafe instead of a servo
+pub const BATTERY_CONFIG_INDEX: u8 = 0xFE; // Config command index addressing the battery monitor
+pub const DISPLAY_CONFIG_INDEX: u8 = 0xFD; // Config command index addressing the display brightness and idle timers
+pub const BUZZER_CONFIG_INDEX: u8 = 0xFC; // Config command index addressing the buzzer's mute
+pub const UPLOAD_SLOT: u8 = 0xFF; // Trajectory reply slot meaning the uploaded frames that aren't stored yet
+pub const POWER_ON_SLOT: u8 = 0xFF; // Pose slot addressing the power-on pose, mid-travel until one is saved
+pub const LOG_PERSIST_FLAG: u8 = 0x01; // Log target command flag storing every console level in NVS
+pub const SPEED_PERSIST_FLAG: u8 = 0x01; // Speed command flag storing the scale in NVS
+pub const MIRROR_PERSIST_FLAG: u8 = 0x01; // Mirror command flag storing the setting in NVS
+pub const QUEUE_FLAG: u8 = 0x01; // Move tag flag appending the move to the motion queue rather than cancelling it
+pub const MOVE_ID_NONE: u16 = 0xFFFF; // Executing move id in the status reply while no tagged move runs, never a tag's
+pub const LOG_LEVEL_INHERIT: u8 = 0xFF; // Log target command level dropping the target's own, it follows the default
+pub const CONFIRM_BYTE: u8 = 0xA5; // Opens shutdown, reboot and sleep payloads, so a corrupt packet can't trigger them
+pub const GRIPPER_MOVE: u8 = 0; // Gripper command mode driving straight to the percentage
+pub const GRIPPER_GRIP: u8 = 1; // Gripper command mode stepping there with a dwell, to stall gently on an object
+pub const FEEDBACK_CAPTURE: u16 = 0xFFFF; // Calibration command feedback reading taking the joint's current one
+pub const FEEDBACK_NONE: u16 = 0xFFFF; // Measured angle in telemetry for a joint without feedback
+pub const CURRENT_NONE: u16 = 0xFFFF; // Current of a joint without a sensor, or before the sensor's first reading
+pub const SENSOR_NONE: u8 = 0xFF; // Current reply sensor of a joint without one
+pub const CURRENT_CLEAR: u8 = 0; // Current command clearing a joint's overload, or every joint's for CURRENT_ALL_JOINTS
+pub const CURRENT_CALIBRATE: u8 = 1; // Current command setting a sensor's zero and scale, kept in NVS
+pub const CURRENT_ALL_JOINTS: u8 = 0xFF;
+pub const TEACH_START: u8 = 0; // Teach command starting a fresh recording, none only reads it
+pub const TEACH_STOP: u8 = 1; // Teach command finishing the recording into the uploaded trajectory
+pub const SELF_TEST_START: u8 = 0; // Self-test command starting a fresh test of every servo
+pub const LOOP_TIMING_RESET: u8 = 0; // Loop timing command resetting the counters once they are replied with
+pub const LOOP_PHASES: usize = 5; // The whole pass, then waiting, dispatching, the servos and the display
+// Parameters kept with each history entry, enough for a pose or a servo's config
+pub const HISTORY_PARAMS: usize = if SERVO_COUNT > 5 { SERVO_COUNT } else { 5 };
+pub const SELF_TEST_PENDING: u8 = 0; // Self-test result for a servo not tested yet
+pub const SELF_TEST_PASSED: u8 = 1;
+pub const SELF_TEST_WRITE_FAILED: u8 = 2; // Driving the servo errored
+pub const SELF_TEST_NOT_MOVED: u8 = 3; // The servo's feedback didn't follow it
+pub const SELF_TEST_SKIPPED: u8 = 4; // Left untested by an e-stop
+pub const GRIPPER_NONE: u8 = 0xFF; // Gripper fields in telemetry without a gripper, and the mode before any command
+pub const RESTORED_NONE: u8 = 0; // Status reply pose byte when no pose was restored at boot
+pub const RESTORED_APPROXIMATE: u8 = 1; // Restored from the last periodic save, after a crash or a brown-out
+pub const RESTORED_TRUSTED: u8 = 2; // Restored from where a clean shutdown stopped the servos
+pub const TEMPERATURE_NONE: i8 = i8::MIN; // Status reply chip temperature before the sensor's first reading
+pub const THERMAL_NORMAL: u8 = 0; // Status reply thermal byte below the warning
+pub const THERMAL_WARM: u8 = 1; // Past the warning and not yet cooled past its hysteresis
+pub const THERMAL_CRITICAL: u8 = 2; // Past the critical temperature, the servos are parked
+pub const PING_MAGIC: [u8; 2] = *b"LM"; // Opens a ping reply, so clients can tell it from the legacy bare positions
+pub const TELEMETRY_ESTOP_FLAG: u8 = 0x01;
+pub const TELEMETRY_FAILSAFE_FLAG: u8 = 0x02;
+pub const TELEMETRY_SHUTDOWN_FLAG: u8 = 0x04; // Set from a shutdown or reboot command until power is cycled
+pub const TELEMETRY_LOW_BATTERY_FLAG: u8 = 0x08; // Below the cutoff and not yet recovered past its hysteresis
+pub const TELEMETRY_BUTTON_ESTOP_FLAG: u8 = 0x10; // Alongside TELEMETRY_ESTOP_FLAG when the button latched it
+pub const TELEMETRY_UPDATING_FLAG: u8 = 0x20; // A firmware update is downloading
+#[cfg_attr(feature = "sim", allow(dead_code))] // Read by the HTTP status, which the simulator lacks
+pub const TELEMETRY_MQTT_FLAG: u8 = 0x40; // Connected to the MQTT broker, only built with the mqtt feature
+pub const TELEMETRY_PAUSED_FLAG: u8 = 0x80; // Motion paused until a resume command
+
+// The last crash goes out behind a single length byte
+const _: () = assert!(settings::MAX_CRASH_SIZE <= u8::MAX as usize);
+
+// CRC-8 with polynomial 0x07 and a zero initial value (CRC-8/SMBUS)
+pub fn crc8(data: &[u8]) -> u8 {
+    let mut crc: u8 = 0;
+    for &byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+// Checks the trailing CRC of a packet, returning the packet without it if it matches
+pub fn verify_crc(packet: &[u8]) -> Option<&[u8]> {
+    let (&crc, data) = packet.split_last()?;
+    if crc8(data) == crc {
+        Some(data)
+    } else {
+        None
+    }
+}
+
+// Appends the CRC of everything already in the frame
+pub fn append_crc(frame: &mut Vec<u8>) {
+    let crc = crc8(frame);
+    frame.push(crc);
+}
+
+// Splits a packet into its sequence number and the command bytes that follow it
+pub fn split_header(packet: &[u8]) -> Option<(u16, &[u8])> {
+    if packet.len() < HEADER_SIZE {
+        return None;
+    }
+    let (header, rest) = packet.split_at(HEADER_SIZE);
+    Some((u16::from_be_bytes([header[0], header[1]]), rest))
+}
+
+// Second byte of every reply, after the echoed command byte
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Ok = 0,
+    Clamped = 1,         // Applied, but at least one value was limited to the allowed range
+    BadLength = 2,       // Packet length doesn't match its command
+    BadCommand = 3,      // Unknown command or sub-command
+    EStopped = 4,        // Motion refused while the e-stop is latched
+    HardwareError = 5,   // A servo driver write failed
+    BadCrc = 6,          // Packet failed its CRC check
+    BadArgument = 7,     // Servo index out of range or a value that can't be applied
+    Busy = 8,            // Refused while a servo is being calibrated, the queue is full or another client holds the session
+    Unauthorized = 9,    // Missing or wrong authentication tag, or a replayed nonce
+    ShutDown = 10,       // Motion refused after a shutdown command, until power is cycled
+    LowBattery = 11,     // Motion refused until the battery recovers
+    ButtonEStopped = 12, // Motion refused while the e-stop button's latch is set, or clearing it while the button is held
+    Updating = 13,       // Motion refused while a firmware update downloads
+    Paused = 14,         // Motion refused while paused, unless moves are configured to wait for the resume
+    Throttled = 15,      // Motion refused while the client sends faster than the rate limit
+    Cancelled = 16,      // A tagged move ended early, by a move that wasn't queued, a stop or an e-stop
+    Overloaded = 17,     // Motion refused for a joint its current sensor stopped, until the current command clears it
+    Overheated = 18,     // Motion refused past the critical chip temperature, until the chip has cooled
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    BadLength,
+    BadCommand,
+}
+
+impl DecodeError {
+    pub fn status(self) -> Status {
+        match self {
+            DecodeError::BadLength => Status::BadLength,
+            DecodeError::BadCommand => Status::BadCommand,
+        }
+    }
+}
+
+// Trails a pose or trajectory play, so its end is reported to the client with a MOVE_DONE_COMMAND packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MoveTag {
+    pub id: u16,
+    pub queue: bool, // Waits for the tagged moves ahead of it instead of cancelling them
+}
+
+// Per-servo parameters carried by the config command
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ServoConfig {
+    pub index: u8,
+    pub speed: u16,
+    pub trim: i16,
+    pub reversed: bool,
+    pub safe_angle: u16,
+    pub detach_s: u16, // Seconds at rest before the servo's output is turned off, 0 never detaches
+    // Degrees per second squared, 0 is unlimited. Left off by older clients, kept as it is and left out of the reply
+    pub accel: Option<u16>,
+}
+
+// Failsafe parameters carried by the config command
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FailsafeConfig {
+    pub timeout_ms: u16, // 0 disables the failsafe
+    pub action: u8,
+}
+
+// Buzzer parameters carried by the config command
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BuzzerConfig {
+    pub muted: bool,
+}
+
+// Config commands are addressed by their first payload byte, a servo index or one of the *_CONFIG_INDEX values
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigCommand {
+    Servo(ServoConfig),
+    Failsafe(FailsafeConfig),
+    Battery(BatteryConfig),
+    Display(DisplayConfig),
+    Buzzer(BuzzerConfig),
+}
+
+// Sub-commands of the calibration command
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CalibrationCommand {
+    Enter,            // Put a servo into raw duty mode
+    SetDuty(u16),     // Write a raw duty count
+    CaptureMin,       // Use the current duty as the 0 degree endpoint
+    CaptureMax,       // Use the current duty as the max angle endpoint
+    Exit,             // Apply the captured endpoints and leave calibration
+    FeedbackMin(u16), // Set the feedback reading at 0 degrees, FEEDBACK_CAPTURE takes the current one
+    FeedbackMax(u16), // Set the feedback reading at the max angle
+    SetPulse(u16),    // Write a raw pulse width in microseconds, replied with the duty count it came to
+}
+
+impl CalibrationCommand {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            CalibrationCommand::Enter => 0,
+            CalibrationCommand::SetDuty(_) => 1,
+            CalibrationCommand::CaptureMin => 2,
+            CalibrationCommand::CaptureMax => 3,
+            CalibrationCommand::Exit => 4,
+            CalibrationCommand::FeedbackMin(_) => 5,
+            CalibrationCommand::FeedbackMax(_) => 6,
+            CalibrationCommand::SetPulse(_) => 7,
+        }
+    }
+}
+
+// How the gripper command gets to its percentage
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GripperMode {
+    Move,
+    Grip,
+}
+
+impl GripperMode {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            GripperMode::Move => GRIPPER_MOVE,
+            GripperMode::Grip => GRIPPER_GRIP,
+        }
+    }
+}
+
+// Sub-commands of the current command
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CurrentCommand {
+    Clear { index: u8 }, // A joint, or CURRENT_ALL_JOINTS
+    Calibrate { sensor: u8, calibration: CurrentCalibration },
+}
+
+// Sub-commands of the teach command
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TeachCommand {
+    Start,
+    Stop,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ControlPacket {
+    SetAngles(Vec<u16>),
+    // A duration of 0 moves each servo at its own speed. `tenths` is set for POSE_TENTHS_COMMAND, to be replied to in
+    // tenths, the angles are in tenths either way
+    Pose { angles: Vec<u16>, duration_ms: u16, easing: Easing, tag: Option<MoveTag>, tenths: bool },
+    MoveJoint { index: u8, angle: u16, speed: Option<u16> }, // No speed moves at the servo's configured speed
+    Jog { index: u8, delta_tenths: i8 },
+    Ping,
+    Config(ConfigCommand),
+    Limits { index: u8, min_limit: u16, max_limit: u16 },
+    Calibration { index: u8, command: CalibrationCommand },
+    UploadTrajectory(Vec<Keyframe>),
+    StoreTrajectory { slot: u8 },
+    PlayTrajectory { slot: u8, looping: bool, tag: Option<MoveTag> },
+    Telemetry { tenths: bool }, // Set for TELEMETRY_TENTHS_COMMAND
+    Status,
+    Claim,
+    Release,
+    Subscribe { interval_ms: u16 },
+    Shutdown { reboot: bool, confirm: u8 },
+    Sleep { confirm: u8, wake_s: Option<u32> }, // None, or a timer of 0, sleeps until the button
+    SavePreset { slot: u8, name: String },
+    RecallPreset { slot: u8, duration_ms: u16 },
+    ListPresets,
+    ClearPreset { slot: u8 },
+    LogLevel(Option<u8>), // None reads the level without changing it
+    LogTarget { persist: bool, level: u8, target: String }, // An empty target is the default level
+    Update { url: String },
+    Gripper(Option<(GripperMode, u8)>), // Mode and percent closed, None reads the gripper without moving it
+    Teach(Option<TeachCommand>), // None reads the recording without changing it
+    SpeedScale(Option<(u8, bool)>), // Percent and whether to keep it in NVS, None reads the scale
+    Pause,
+    Resume,
+    SelfTest { start: bool }, // Not starting only reads the results
+    Mirror(Option<(bool, bool)>), // Whether to mirror and whether to keep it in NVS, None reads the setting
+    LoopTiming { reset: bool }, // Not resetting only reads the counters
+    History { chunk: Option<u8> }, // None streams every chunk to a UDP client
+    Current(Option<CurrentCommand>), // None reads the sensors without changing anything
+    EStop,
+    ClearEStop,
+}
+
+impl ControlPacket {
+    // Decodes the command byte and payload of a packet whose header and CRC have been stripped
+    pub fn decode(bytes: &[u8]) -> Result<ControlPacket, DecodeError> {
+        let (&command, payload) = bytes.split_first().ok_or(DecodeError::BadLength)?;
+        let length = match command {
+            // Counts are checked against the servos by the control loop, an empty payload fails the length check
+            MOVE_COMMAND => 1 + payload.first().map_or(0, |&count| count as usize * 2),
+            // The easing byte after the duration is optional, older clients' poses are linear. A tag may follow it
+            POSE_COMMAND | POSE_TENTHS_COMMAND => {
+                let length = 1 + payload.first().map_or(0, |&count| count as usize * 2) + 2;
+                match payload.len() {
+                    len if len == length + 1 || len == length + 1 + TAG_SIZE => len,
+                    _ => length,
+                }
+            }
+            PING_COMMAND | TELEMETRY_COMMAND | CLAIM_COMMAND | RELEASE_COMMAND | ESTOP_COMMAND | CLEAR_ESTOP_COMMAND => 0,
+            STATUS_COMMAND | TELEMETRY_TENTHS_COMMAND => 0,
+            CONFIG_COMMAND if payload.len() == 12 && payload[0] < BUZZER_CONFIG_INDEX => 12,
+            CONFIG_COMMAND => 10,
+            JOINT_COMMAND if payload.len() == 5 => 5,
+            JOINT_COMMAND => 3,
+            JOG_COMMAND => 2,
+            GRIPPER_COMMAND if payload.is_empty() => 0,
+            GRIPPER_COMMAND => 2,
+            TEACH_COMMAND => payload.len().min(1),
+            PAUSE_COMMAND | RESUME_COMMAND => 0,
+            SELF_TEST_COMMAND => payload.len().min(1),
+            LOOP_TIMING_COMMAND | HISTORY_COMMAND => payload.len().min(1),
+            CURRENT_COMMAND if payload.is_empty() => 0,
+            CURRENT_COMMAND if payload[0] == CURRENT_CALIBRATE => 10,
+            CURRENT_COMMAND => 2,
+            MIRROR_COMMAND if payload.is_empty() => 0,
+            MIRROR_COMMAND => 2,
+            SPEED_COMMAND if payload.is_empty() => 0,
+            SPEED_COMMAND => 2,
+            LIMITS_COMMAND => 5,
+            CALIBRATION_COMMAND => 4,
+            // The frame count comes first, an empty payload falls through to the length check
+            TRAJECTORY_UPLOAD_COMMAND => 1 + payload.first().map_or(0, |&count| count as usize * trajectory::FRAME_SIZE),
+            TRAJECTORY_STORE_COMMAND | SHUTDOWN_COMMAND | REBOOT_COMMAND | PRESET_CLEAR_COMMAND => 1,
+            SLEEP_COMMAND if payload.len() == 5 => 5,
+            SLEEP_COMMAND => 1,
+            // The name is optional, anything up to its limit is taken
+            PRESET_SAVE_COMMAND => payload.len().clamp(1, 1 + preset::MAX_NAME_SIZE),
+            PRESET_RECALL_COMMAND => 3,
+            PRESET_LIST_COMMAND => 0,
+            LOG_LEVEL_COMMAND => payload.len().min(1),
+            LOG_TARGET_COMMAND => payload.len().clamp(2, 2 + remote_log::MAX_TARGET_SIZE),
+            OTA_COMMAND => payload.len().clamp(1, ota::MAX_URL_SIZE),
+            TRAJECTORY_PLAY_COMMAND if payload.len() == 2 + TAG_SIZE => 2 + TAG_SIZE,
+            TRAJECTORY_PLAY_COMMAND | SUBSCRIBE_COMMAND => 2,
+            _ => return Err(DecodeError::BadCommand),
+        };
+        if payload.len() != length {
+            return Err(DecodeError::BadLength);
+        }
+        let u16_at = |i: usize| u16::from_be_bytes([payload[i], payload[i + 1]]);
+
+        Ok(match command {
+            MOVE_COMMAND => ControlPacket::SetAngles(decode_angles(&payload[1..1 + payload[0] as usize * 2])),
+            POSE_COMMAND | POSE_TENTHS_COMMAND => ControlPacket::Pose {
+                angles: match command {
+                    POSE_TENTHS_COMMAND => decode_tenths(&payload[1..1 + payload[0] as usize * 2]),
+                    _ => decode_angles(&payload[1..1 + payload[0] as usize * 2]),
+                },
+                duration_ms: u16_at(1 + payload[0] as usize * 2),
+                easing: match payload.get(3 + payload[0] as usize * 2) {
+                    None => Easing::Linear,
+                    Some(&easing) => Easing::from_byte(easing).ok_or(DecodeError::BadCommand)?,
+                },
+                tag: decode_tag(payload.get(4 + payload[0] as usize * 2..).unwrap_or(&[]))?,
+                tenths: command == POSE_TENTHS_COMMAND,
+            },
+            JOINT_COMMAND => ControlPacket::MoveJoint {
+                index: payload[0],
+                angle: servo::from_degrees(u16_at(1)),
+                speed: if payload.len() == 5 { Some(u16_at(3)) } else { None },
+            },
+            JOG_COMMAND => ControlPacket::Jog { index: payload[0], delta_tenths: payload[1] as i8 },
+            PING_COMMAND => ControlPacket::Ping,
+            CONFIG_COMMAND => ControlPacket::Config(match payload[0] {
+                FAILSAFE_CONFIG_INDEX => ConfigCommand::Failsafe(FailsafeConfig {
+                    timeout_ms: u16_at(1),
+                    action: payload[3],
+                }),
+                BATTERY_CONFIG_INDEX => ConfigCommand::Battery(BatteryConfig {
+                    divider: u16_at(1),
+                    cutoff_mv: u16_at(3),
+                    hysteresis_mv: u16_at(5),
+                }),
+                DISPLAY_CONFIG_INDEX => ConfigCommand::Display(DisplayConfig {
+                    brightness: payload[1],
+                    dim_s: u16_at(2),
+                    sleep_s: u16_at(4),
+                    flipped: payload[6] != 0,
+                }),
+                BUZZER_CONFIG_INDEX => ConfigCommand::Buzzer(BuzzerConfig { muted: payload[1] != 0 }),
+                index => ConfigCommand::Servo(ServoConfig {
+                    index,
+                    speed: u16_at(1),
+                    trim: i16::from_be_bytes([payload[3], payload[4]]),
+                    reversed: payload[5] != 0,
+                    safe_angle: servo::from_degrees(u16_at(6)),
+                    detach_s: u16_at(8),
+                    accel: (payload.len() == 12).then(|| u16_at(10)),
+                }),
+            }),
+            LIMITS_COMMAND => ControlPacket::Limits {
+                index: payload[0],
+                min_limit: servo::from_degrees(u16_at(1)),
+                max_limit: servo::from_degrees(u16_at(3)),
+            },
+            CALIBRATION_COMMAND => ControlPacket::Calibration {
+                index: payload[1],
+                command: match payload[0] {
+                    0 => CalibrationCommand::Enter,
+                    1 => CalibrationCommand::SetDuty(u16_at(2)),
+                    2 => CalibrationCommand::CaptureMin,
+                    3 => CalibrationCommand::CaptureMax,
+                    4 => CalibrationCommand::Exit,
+                    5 => CalibrationCommand::FeedbackMin(u16_at(2)),
+                    6 => CalibrationCommand::FeedbackMax(u16_at(2)),
+                    7 => CalibrationCommand::SetPulse(u16_at(2)),
+                    _ => return Err(DecodeError::BadCommand),
+                },
+            },
+            TRAJECTORY_UPLOAD_COMMAND => ControlPacket::UploadTrajectory(
+                payload[1..].chunks_exact(trajectory::FRAME_SIZE).map(Keyframe::from_be_bytes).collect(),
+            ),
+            TRAJECTORY_STORE_COMMAND => ControlPacket::StoreTrajectory { slot: payload[0] },
+            TRAJECTORY_PLAY_COMMAND => ControlPacket::PlayTrajectory {
+                slot: payload[0],
+                looping: payload[1] != 0,
+                tag: decode_tag(&payload[2..])?,
+            },
+            TELEMETRY_COMMAND | TELEMETRY_TENTHS_COMMAND => {
+                ControlPacket::Telemetry { tenths: command == TELEMETRY_TENTHS_COMMAND }
+            }
+            STATUS_COMMAND => ControlPacket::Status,
+            CLAIM_COMMAND => ControlPacket::Claim,
+            RELEASE_COMMAND => ControlPacket::Release,
+            SUBSCRIBE_COMMAND => ControlPacket::Subscribe { interval_ms: u16_at(0) },
+            PRESET_SAVE_COMMAND => ControlPacket::SavePreset {
+                slot: payload[0],
+                name: String::from_utf8_lossy(&payload[1..]).into_owned(),
+            },
+            PRESET_RECALL_COMMAND => ControlPacket::RecallPreset { slot: payload[0], duration_ms: u16_at(1) },
+            PRESET_LIST_COMMAND => ControlPacket::ListPresets,
+            PRESET_CLEAR_COMMAND => ControlPacket::ClearPreset { slot: payload[0] },
+            LOG_LEVEL_COMMAND => ControlPacket::LogLevel(payload.first().copied()),
+            OTA_COMMAND => ControlPacket::Update { url: String::from_utf8_lossy(payload).into_owned() },
+            TEACH_COMMAND => ControlPacket::Teach(match payload.first() {
+                None => None,
+                Some(&TEACH_START) => Some(TeachCommand::Start),
+                Some(&TEACH_STOP) => Some(TeachCommand::Stop),
+                Some(_) => return Err(DecodeError::BadCommand),
+            }),
+            PAUSE_COMMAND => ControlPacket::Pause,
+            RESUME_COMMAND => ControlPacket::Resume,
+            MIRROR_COMMAND => ControlPacket::Mirror(
+                payload.first().map(|&flags| (payload[1] != 0, flags & MIRROR_PERSIST_FLAG != 0)),
+            ),
+            SELF_TEST_COMMAND => ControlPacket::SelfTest {
+                start: match payload.first() {
+                    None => false,
+                    Some(&SELF_TEST_START) => true,
+                    Some(_) => return Err(DecodeError::BadCommand),
+                },
+            },
+            LOOP_TIMING_COMMAND => ControlPacket::LoopTiming {
+                reset: match payload.first() {
+                    None => false,
+                    Some(&LOOP_TIMING_RESET) => true,
+                    Some(_) => return Err(DecodeError::BadCommand),
+                },
+            },
+            HISTORY_COMMAND => ControlPacket::History { chunk: payload.first().copied() },
+            CURRENT_COMMAND => ControlPacket::Current(match payload.first() {
+                None => None,
+                Some(&CURRENT_CLEAR) => Some(CurrentCommand::Clear { index: payload[1] }),
+                Some(&CURRENT_CALIBRATE) => Some(CurrentCommand::Calibrate {
+                    sensor: payload[1],
+                    calibration: CurrentCalibration {
+                        zero_uv: i32::from_be_bytes([payload[2], payload[3], payload[4], payload[5]]),
+                        ma_per_v: u32::from_be_bytes([payload[6], payload[7], payload[8], payload[9]]),
+                    },
+                }),
+                Some(_) => return Err(DecodeError::BadCommand),
+            }),
+            SPEED_COMMAND => ControlPacket::SpeedScale(
+                payload.first().map(|&flags| (payload[1], flags & SPEED_PERSIST_FLAG != 0)),
+            ),
+            GRIPPER_COMMAND => ControlPacket::Gripper(match payload.first() {
+                None => None,
+                Some(&GRIPPER_MOVE) => Some((GripperMode::Move, payload[1])),
+                Some(&GRIPPER_GRIP) => Some((GripperMode::Grip, payload[1])),
+                Some(_) => return Err(DecodeError::BadCommand),
+            }),
+            LOG_TARGET_COMMAND => ControlPacket::LogTarget {
+                persist: payload[0] & LOG_PERSIST_FLAG != 0,
+                level: payload[1],
+                target: String::from_utf8_lossy(&payload[2..]).into_owned(),
+            },
+            SHUTDOWN_COMMAND | REBOOT_COMMAND => ControlPacket::Shutdown {
+                reboot: command == REBOOT_COMMAND,
+                confirm: payload[0],
+            },
+            SLEEP_COMMAND => ControlPacket::Sleep {
+                confirm: payload[0],
+                wake_s: match payload {
+                    &[_, a, b, c, d] => Some(u32::from_be_bytes([a, b, c, d])).filter(|&wake_s| wake_s != 0),
+                    _ => None,
+                },
+            },
+            ESTOP_COMMAND => ControlPacket::EStop,
+            _ => ControlPacket::ClearEStop,
+        })
+    }
+
+    // Commands that drive the servos, refused while the limb can't move and rate limited per client
+    pub fn is_motion(&self) -> bool {
+        matches!(
+            self,
+            ControlPacket::SetAngles(_)
+                | ControlPacket::Pose { .. }
+                | ControlPacket::MoveJoint { .. }
+                | ControlPacket::Jog { .. }
+                | ControlPacket::PlayTrajectory { .. }
+                | ControlPacket::RecallPreset { .. }
+                | ControlPacket::Gripper(Some(_))
+                | ControlPacket::SelfTest { start: true }
+        )
+    }
+
+    // Commands that halt or freeze the servos, moves queued ahead of one are dropped rather than applied after it
+    pub fn stops_motion(&self) -> bool {
+        matches!(
+            self,
+            ControlPacket::EStop | ControlPacket::Shutdown { .. } | ControlPacket::Pause | ControlPacket::Update { .. }
+        )
+    }
+
+    // The servo a command addresses alone, None for the commands addressing every joint or none
+    pub fn joint_index(&self) -> Option<u8> {
+        match *self {
+            ControlPacket::MoveJoint { index, .. }
+            | ControlPacket::Jog { index, .. }
+            | ControlPacket::Limits { index, .. }
+            | ControlPacket::Calibration { index, .. }
+            | ControlPacket::Config(ConfigCommand::Servo(ServoConfig { index, .. })) => Some(index),
+            _ => None,
+        }
+    }
+
+    pub fn command(&self) -> u8 {
+        match self {
+            ControlPacket::SetAngles(_) => MOVE_COMMAND,
+            ControlPacket::Pose { tenths: false, .. } => POSE_COMMAND,
+            ControlPacket::Pose { tenths: true, .. } => POSE_TENTHS_COMMAND,
+            ControlPacket::MoveJoint { .. } => JOINT_COMMAND,
+            ControlPacket::Jog { .. } => JOG_COMMAND,
+            ControlPacket::Ping => PING_COMMAND,
+            ControlPacket::Config(_) => CONFIG_COMMAND,
+            ControlPacket::Limits { .. } => LIMITS_COMMAND,
+            ControlPacket::Calibration { .. } => CALIBRATION_COMMAND,
+            ControlPacket::UploadTrajectory(_) => TRAJECTORY_UPLOAD_COMMAND,
+            ControlPacket::StoreTrajectory { .. } => TRAJECTORY_STORE_COMMAND,
+            ControlPacket::PlayTrajectory { .. } => TRAJECTORY_PLAY_COMMAND,
+            ControlPacket::Telemetry { tenths: false } => TELEMETRY_COMMAND,
+            ControlPacket::Telemetry { tenths: true } => TELEMETRY_TENTHS_COMMAND,
+            ControlPacket::Status => STATUS_COMMAND,
+            ControlPacket::Claim => CLAIM_COMMAND,
+            ControlPacket::Release => RELEASE_COMMAND,
+            ControlPacket::Subscribe { .. } => SUBSCRIBE_COMMAND,
+            ControlPacket::SavePreset { .. } => PRESET_SAVE_COMMAND,
+            ControlPacket::RecallPreset { .. } => PRESET_RECALL_COMMAND,
+            ControlPacket::ListPresets => PRESET_LIST_COMMAND,
+            ControlPacket::ClearPreset { .. } => PRESET_CLEAR_COMMAND,
+            ControlPacket::LogLevel(_) => LOG_LEVEL_COMMAND,
+            ControlPacket::LogTarget { .. } => LOG_TARGET_COMMAND,
+            ControlPacket::Update { .. } => OTA_COMMAND,
+            ControlPacket::Gripper(_) => GRIPPER_COMMAND,
+            ControlPacket::Teach(_) => TEACH_COMMAND,
+            ControlPacket::SpeedScale(_) => SPEED_COMMAND,
+            ControlPacket::Pause => PAUSE_COMMAND,
+            ControlPacket::Resume => RESUME_COMMAND,
+            ControlPacket::SelfTest { .. } => SELF_TEST_COMMAND,
+            ControlPacket::Mirror(_) => MIRROR_COMMAND,
+            ControlPacket::LoopTiming { .. } => LOOP_TIMING_COMMAND,
+            ControlPacket::History { .. } => HISTORY_COMMAND,
+            ControlPacket::Current(_) => CURRENT_COMMAND,
+            ControlPacket::Shutdown { reboot: false, .. } => SHUTDOWN_COMMAND,
+            ControlPacket::Shutdown { reboot: true, .. } => REBOOT_COMMAND,
+            ControlPacket::Sleep { .. } => SLEEP_COMMAND,
+            ControlPacket::EStop => ESTOP_COMMAND,
+            ControlPacket::ClearEStop => CLEAR_ESTOP_COMMAND,
+        }
+    }
+}
+
+// A servo's angle and status byte as reported in position replies
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ServoPosition {
+    pub angle: u16,
+    pub status: u8,
+}
+
+// A joint in the status reply
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct JointStatus {
+    pub angle: u16,
+    pub goal: u16,
+    pub status: u8,
+}
+
+// A joint's commanded and measured angles in telemetry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MeasuredAngle {
+    pub angle: u16,
+    pub measured: Option<u16>, // None without feedback
+}
+
+// The gripper in its reply and in telemetry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GripperStatus {
+    pub percent: u8,               // Closed, from where the servo is now
+    pub target: u8,                // Closed, as last commanded
+    pub last: Option<GripperMode>, // None until the first command
+}
+
+// A phase of the control loop, over every pass since the counters were reset
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PhaseTiming {
+    pub mean_us: u32,
+    pub max_us: u32, // Over the last full window and the one in progress
+}
+
+// Carried by the status reply and the loop timing reply
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LoopTiming {
+    pub passes: u32,
+    pub slow: u32, // Passes longer than loop_slow_us
+    pub phases: [PhaseTiming; LOOP_PHASES],
+}
+
+// A joint's current in telemetry and the current reply
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct JointCurrent {
+    pub sensor: Option<u8>,      // None without a sensor
+    pub current_ma: Option<u16>, // Of the whole sensor, shared with the rest of the joints on its rail
+    pub overloaded: bool,
+}
+
+// A current sensor in the current reply
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SensorReading {
+    pub current_ma: Option<u16>, // None before the first reading
+    pub calibration: CurrentCalibration,
+}
+
+// A dispatched command as the history keeps it. Angles among the parameters are in tenths, as the limb holds them
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub uptime_ms: u32, // Since the start it was dispatched in
+    pub boot: u8,       // Counts the restarts the history was kept through, wrapping
+    pub ip: [u8; 4],    // Unspecified for a command from BLE, serial, MQTT or a WebSocket
+    pub port: u16,
+    pub command: u8,
+    pub status: u8,
+    pub count: u8, // Parameters in use, from the front
+    pub params: [u16; HISTORY_PARAMS],
+}
+
+// Health figures carried by the telemetry reply
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Telemetry {
+    pub version_maj: u8,
+    pub version_min: u8,
+    pub uptime_s: u32,
+    pub free_heap: u32,
+    pub rssi: i8, // dBm, 0 while the station isn't connected
+    pub received: u32,
+    pub rejected: u32,
+    pub malformed: u32,
+    pub flags: u8, // The TELEMETRY_*_FLAG bits
+    pub auth_failures: u32,
+    pub reset_reason: u8, // Why the chip last started, see watchdog::ResetReason
+    pub battery_mv: u16,  // 0 without battery monitoring
+    pub last_crash: String, // Panic message kept from before a restart, empty if there never was one
+    pub log_level: u8,      // Default console level, 0 off to 5 trace
+    pub gripper: Option<GripperStatus>, // None without a gripper
+    pub joints: Vec<MeasuredAngle>,
+    pub applied: u32,      // Moves and poses that set new goals
+    pub deduplicated: u32, // Moves and poses that repeated the goals already set
+    pub currents: Vec<JointCurrent>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReplyPayload {
+    Empty,
+    Positions(Vec<ServoPosition>),
+    LegacyPositions(Vec<u16>), // Every angle, for clients of protocol 1
+    Joint { index: u8, position: ServoPosition },
+    Jog { index: u8, goal_tenths: u16, status: u8 }, // The goal the jog left in tenths of a degree, and the status
+    Ping { version_maj: u8, version_min: u8, positions: Vec<ServoPosition> },
+    ServoConfig(ServoConfig),
+    FailsafeConfig(FailsafeConfig),
+    BatteryConfig(BatteryConfig),
+    DisplayConfig(DisplayConfig),
+    BuzzerConfig(BuzzerConfig),
+    Limits { index: u8, min_limit: u16, max_limit: u16 },
+    Calibration { command: CalibrationCommand, index: u8, duty: u16 },
+    Trajectory { slot: u8, frames: u8, captured_ms: Option<u64> }, // Unix time the frames were recorded at, if known
+    Preset(u8), // The pose slot the command addressed
+    Presets(Vec<(u8, String)>), // Every stored pose's slot and name
+    Telemetry(Telemetry),
+    // Fields as in telemetry, with one of the RESTORED_* values for the pose kept through the last restart and the Unix
+    // time in ms, None until the clock is synchronized
+    Status {
+        flags: u8,
+        rssi: i8,
+        uptime_s: u32,
+        battery_mv: u16,
+        joints: Vec<JointStatus>,
+        restored: u8,
+        mirrored: bool,
+        time_ms: Option<u64>,
+        timing: LoopTiming,
+        queued: u8,             // Moves waiting in the motion queue
+        executing: Option<u16>, // The tagged move running, if any
+        unavailable: Vec<(u8, String)>, // Each joint whose channel couldn't be set up at boot, and why
+        i2c_devices: Vec<u8>,           // Addresses that answered the bus scan at boot
+        temperature_c: Option<i8>,      // Chip temperature, None until the sensor's first reading
+        thermal: u8,                    // One of the THERMAL_* values
+        wake_reason: u8,                // Why the chip last woke from deep sleep, see sleep::WakeReason
+    },
+    Owner(Ipv4Addr), // The client holding the session, unspecified while unclaimed
+    // Flags, RSSI and time as in the status reply
+    Heartbeat { flags: u8, rssi: i8, angles: Vec<u16>, moving: Vec<bool>, time_ms: Option<u64> },
+    Index(u8), // The servo or *_CONFIG_INDEX that a refused command addressed
+    LogLevel { level: u8, forwarding: bool }, // The level byte in effect, and whether a collector is set
+    TargetLevel { level: u8, target: String }, // The console level the target now logs at
+    Gripper(GripperStatus),
+    Recording { recording: bool, frames: u8, remaining: u8 }, // The frames taken so far and the room left for more
+    SpeedScale(u8), // The percent of full speed in effect
+    SelfTest { running: bool, results: Vec<u8> }, // A SELF_TEST_* value per servo
+    Mirror(bool), // Whether moves are mirrored
+    LoopTiming(LoopTiming), // As they were before any reset
+    MoveDone { id: u16, angles: Vec<u16> }, // The tagged move that ended and the angles it left the servos at
+    // The start the history is now in, the entries it holds, then the chunk read, oldest first, out of how many
+    History { boot: u8, total: u8, chunk: u8, chunks: u8, entries: Vec<HistoryEntry> },
+    Current { limit_ma: u16, window_ms: u16, joints: Vec<JointCurrent>, sensors: Vec<SensorReading> },
+}
+
+impl ReplyPayload {
+    // Angles go out in whole degrees, or as the tenths they're held in when `tenths` is set
+    fn encode(&self, tenths: bool, frame: &mut Vec<u8>) {
+        let angle = |angle: u16| match tenths {
+            true => angle,
+            false => servo::to_degrees(angle),
+        };
+        match self {
+            ReplyPayload::Empty => {}
+            ReplyPayload::Positions(positions) => encode_positions(positions, angle, frame),
+            ReplyPayload::LegacyPositions(angles) => encode_legacy_angles(angles, frame),
+            ReplyPayload::Joint { index, position } => {
+                frame.push(*index);
+                frame.extend_from_slice(&angle(position.angle).to_be_bytes());
+                frame.push(position.status);
+            }
+            // Always in tenths, like the step
+            ReplyPayload::Jog { index, goal_tenths, status } => {
+                frame.push(*index);
+                frame.extend_from_slice(&goal_tenths.to_be_bytes());
+                frame.push(*status);
+            }
+            ReplyPayload::Ping { version_maj, version_min, positions } => {
+                frame.extend_from_slice(&PING_MAGIC);
+                frame.push(*version_maj);
+                frame.push(*version_min);
+                encode_positions(positions, angle, frame);
+            }
+            ReplyPayload::ServoConfig(config) => {
+                frame.push(config.index);
+                frame.extend_from_slice(&config.speed.to_be_bytes());
+                frame.extend_from_slice(&config.trim.to_be_bytes());
+                frame.push(config.reversed as u8);
+                frame.extend_from_slice(&angle(config.safe_angle).to_be_bytes());
+                frame.extend_from_slice(&config.detach_s.to_be_bytes());
+                if let Some(accel) = config.accel {
+                    frame.extend_from_slice(&accel.to_be_bytes());
+                }
+            }
+            ReplyPayload::FailsafeConfig(config) => {
+                frame.push(FAILSAFE_CONFIG_INDEX);
+                frame.extend_from_slice(&config.timeout_ms.to_be_bytes());
+                frame.push(config.action);
+            }
+            ReplyPayload::BatteryConfig(config) => {
+                frame.push(BATTERY_CONFIG_INDEX);
+                frame.extend_from_slice(&config.divider.to_be_bytes());
+                frame.extend_from_slice(&config.cutoff_mv.to_be_bytes());
+                frame.extend_from_slice(&config.hysteresis_mv.to_be_bytes());
+            }
+            ReplyPayload::DisplayConfig(config) => {
+                frame.push(DISPLAY_CONFIG_INDEX);
+                frame.push(config.brightness);
+                frame.extend_from_slice(&config.dim_s.to_be_bytes());
+                frame.extend_from_slice(&config.sleep_s.to_be_bytes());
+                frame.push(config.flipped as u8);
+            }
+            ReplyPayload::BuzzerConfig(config) => {
+                frame.push(BUZZER_CONFIG_INDEX);
+                frame.push(config.muted as u8);
+            }
+            ReplyPayload::Limits { index, min_limit, max_limit } => {
+                frame.push(*index);
+                frame.extend_from_slice(&angle(*min_limit).to_be_bytes());
+                frame.extend_from_slice(&angle(*max_limit).to_be_bytes());
+            }
+            ReplyPayload::Calibration { command, index, duty } => {
+                frame.push(command.to_byte());
+                frame.push(*index);
+                frame.extend_from_slice(&duty.to_be_bytes());
+            }
+            ReplyPayload::Trajectory { slot, frames, captured_ms } => {
+                frame.push(*slot);
+                frame.push(*frames);
+                encode_time(*captured_ms, frame);
+            }
+            ReplyPayload::Telemetry(telemetry) => {
+                // Versions first, so a client can stop reading at firmware it doesn't understand
+                frame.push(telemetry.version_maj);
+                frame.push(telemetry.version_min);
+                frame.push(PROTOCOL_VERSION);
+                frame.extend_from_slice(&telemetry.uptime_s.to_be_bytes());
+                frame.extend_from_slice(&telemetry.free_heap.to_be_bytes());
+                frame.push(telemetry.rssi as u8);
+                frame.extend_from_slice(&telemetry.received.to_be_bytes());
+                frame.extend_from_slice(&telemetry.rejected.to_be_bytes());
+                frame.extend_from_slice(&telemetry.malformed.to_be_bytes());
+                frame.push(telemetry.flags);
+                frame.extend_from_slice(&telemetry.auth_failures.to_be_bytes());
+                frame.push(telemetry.reset_reason);
+                frame.extend_from_slice(&telemetry.battery_mv.to_be_bytes());
+                // Length prefixed like the pose names
+                frame.push(telemetry.last_crash.len() as u8);
+                frame.extend_from_slice(telemetry.last_crash.as_bytes());
+                frame.push(telemetry.log_level);
+                match telemetry.gripper {
+                    Some(ref gripper) => encode_gripper(gripper, frame),
+                    None => frame.extend_from_slice(&[GRIPPER_NONE; 3]),
+                }
+                // The joint count, then each joint's commanded and measured angle
+                frame.push(telemetry.joints.len() as u8);
+                for joint in &telemetry.joints {
+                    frame.extend_from_slice(&angle(joint.angle).to_be_bytes());
+                    frame.extend_from_slice(&joint.measured.map(angle).unwrap_or(FEEDBACK_NONE).to_be_bytes());
+                }
+                frame.extend_from_slice(&telemetry.applied.to_be_bytes());
+                frame.extend_from_slice(&telemetry.deduplicated.to_be_bytes());
+                // The joint count again, then each joint's current and whether it is overloaded
+                frame.push(telemetry.currents.len() as u8);
+                for joint in &telemetry.currents {
+                    frame.extend_from_slice(&joint.current_ma.unwrap_or(CURRENT_NONE).to_be_bytes());
+                    frame.push(joint.overloaded as u8);
+                }
+            }
+            ReplyPayload::Status {
+                flags,
+                rssi,
+                uptime_s,
+                battery_mv,
+                joints,
+                restored,
+                mirrored,
+                time_ms,
+                timing,
+                queued,
+                executing,
+                unavailable,
+                i2c_devices,
+                temperature_c,
+                thermal,
+                wake_reason,
+            } => {
+                // Flags, RSSI, uptime, battery, the joint count, each joint's angle, goal and status byte, then the
+                // restored pose, whether moves are mirrored, the time, the loop timing, the queue depth, the
+                // executing move, the count of unavailable joints with each one's index and error, the count of I2C
+                // addresses with each one, then the chip temperature, thermal level and wake reason
+                frame.push(*flags);
+                frame.push(*rssi as u8);
+                frame.extend_from_slice(&uptime_s.to_be_bytes());
+                frame.extend_from_slice(&battery_mv.to_be_bytes());
+                frame.push(joints.len() as u8);
+                for joint in joints {
+                    frame.extend_from_slice(&angle(joint.angle).to_be_bytes());
+                    frame.extend_from_slice(&angle(joint.goal).to_be_bytes());
+                    frame.push(joint.status);
+                }
+                frame.push(*restored);
+                frame.push(*mirrored as u8);
+                encode_time(*time_ms, frame);
+                encode_loop_timing(timing, frame);
+                frame.push(*queued);
+                frame.extend_from_slice(&executing.unwrap_or(MOVE_ID_NONE).to_be_bytes());
+                // Length prefixed like the pose names
+                frame.push(unavailable.len() as u8);
+                for (index, error) in unavailable {
+                    frame.push(*index);
+                    frame.push(error.len() as u8);
+                    frame.extend_from_slice(error.as_bytes());
+                }
+                frame.push(i2c_devices.len() as u8);
+                frame.extend_from_slice(i2c_devices);
+                frame.push(temperature_c.unwrap_or(TEMPERATURE_NONE) as u8);
+                frame.push(*thermal);
+                frame.push(*wake_reason);
+            }
+            ReplyPayload::Owner(ip) => frame.extend_from_slice(&ip.octets()),
+            ReplyPayload::Heartbeat { flags, rssi, angles, moving, time_ms } => {
+                // Flags, RSSI, the servo count, every angle, a moving byte per servo, then the time
+                frame.push(*flags);
+                frame.push(*rssi as u8);
+                frame.push(angles.len() as u8);
+                encode_angles(angles.iter().copied().map(angle), frame);
+                frame.extend(moving.iter().map(|&moving| moving as u8));
+                encode_time(*time_ms, frame);
+            }
+            ReplyPayload::Preset(slot) => frame.push(*slot),
+            ReplyPayload::Presets(presets) => {
+                // The pose count, then each slot, name length and name
+                frame.push(presets.len() as u8);
+                for (slot, name) in presets {
+                    frame.push(*slot);
+                    frame.push(name.len() as u8);
+                    frame.extend_from_slice(name.as_bytes());
+                }
+            }
+            ReplyPayload::Index(index) => frame.push(*index),
+            ReplyPayload::LogLevel { level, forwarding } => {
+                frame.push(*level);
+                frame.push(*forwarding as u8);
+            }
+            ReplyPayload::TargetLevel { level, target } => {
+                // Length prefixed like the pose names
+                frame.push(*level);
+                frame.push(target.len() as u8);
+                frame.extend_from_slice(target.as_bytes());
+            }
+            ReplyPayload::Gripper(gripper) => encode_gripper(gripper, frame),
+            ReplyPayload::SpeedScale(percent) => frame.push(*percent),
+            ReplyPayload::Mirror(mirrored) => frame.push(*mirrored as u8),
+            ReplyPayload::LoopTiming(timing) => encode_loop_timing(timing, frame),
+            ReplyPayload::MoveDone { id, angles } => {
+                // The move id, the servo count, then every angle
+                frame.extend_from_slice(&id.to_be_bytes());
+                frame.push(angles.len() as u8);
+                encode_angles(angles.iter().copied().map(angle), frame);
+            }
+            ReplyPayload::History { boot, total, chunk, chunks, entries } => {
+                frame.extend_from_slice(&[*boot, *total, *chunk, *chunks, entries.len() as u8]);
+                for entry in entries {
+                    encode_history_entry(entry, frame);
+                }
+            }
+            ReplyPayload::Current { limit_ma, window_ms, joints, sensors } => {
+                frame.extend_from_slice(&limit_ma.to_be_bytes());
+                frame.extend_from_slice(&window_ms.to_be_bytes());
+                // The joint count, then each joint's sensor, current and whether it is overloaded
+                frame.push(joints.len() as u8);
+                for joint in joints {
+                    frame.push(joint.sensor.unwrap_or(SENSOR_NONE));
+                    frame.extend_from_slice(&joint.current_ma.unwrap_or(CURRENT_NONE).to_be_bytes());
+                    frame.push(joint.overloaded as u8);
+                }
+                // The sensor count, then each sensor's current and calibration
+                frame.push(sensors.len() as u8);
+                for sensor in sensors {
+                    frame.extend_from_slice(&sensor.current_ma.unwrap_or(CURRENT_NONE).to_be_bytes());
+                    frame.extend_from_slice(&sensor.calibration.zero_uv.to_be_bytes());
+                    frame.extend_from_slice(&sensor.calibration.ma_per_v.to_be_bytes());
+                }
+            }
+            ReplyPayload::SelfTest { running, results } => {
+                // Whether the test is still running, the servo count, then each servo's result
+                frame.push(*running as u8);
+                frame.push(results.len() as u8);
+                frame.extend_from_slice(results);
+            }
+            ReplyPayload::Recording { recording, frames, remaining } => {
+                frame.push(*recording as u8);
+                frame.push(*frames);
+                frame.push(*remaining);
+            }
+        }
+    }
+}
+
+// Whether the time is known, then the Unix time in ms, 0 rather than 1970 when it isn't
+fn encode_time(time_ms: Option<u64>, frame: &mut Vec<u8>) {
+    frame.push(time_ms.is_some() as u8);
+    frame.extend_from_slice(&time_ms.unwrap_or(0).to_be_bytes());
+}
+
+// Passes, slow passes, the phase count, then each phase's mean and max in microseconds, the whole pass first
+fn encode_loop_timing(timing: &LoopTiming, frame: &mut Vec<u8>) {
+    frame.extend_from_slice(&timing.passes.to_be_bytes());
+    frame.extend_from_slice(&timing.slow.to_be_bytes());
+    frame.push(timing.phases.len() as u8);
+    for phase in &timing.phases {
+        frame.extend_from_slice(&phase.mean_us.to_be_bytes());
+        frame.extend_from_slice(&phase.max_us.to_be_bytes());
+    }
+}
+
+// Uptime in ms, the start, the sender's address and port, the command, its status, then the parameter count and each
+// parameter
+fn encode_history_entry(entry: &HistoryEntry, frame: &mut Vec<u8>) {
+    frame.extend_from_slice(&entry.uptime_ms.to_be_bytes());
+    frame.push(entry.boot);
+    frame.extend_from_slice(&entry.ip);
+    frame.extend_from_slice(&entry.port.to_be_bytes());
+    frame.extend_from_slice(&[entry.command, entry.status]);
+    let params = &entry.params[..(entry.count as usize).min(HISTORY_PARAMS)];
+    frame.push(params.len() as u8);
+    encode_angles(params.iter().copied(), frame);
+}
+
+// Percent closed now, the target, then the last mode
+fn encode_gripper(gripper: &GripperStatus, frame: &mut Vec<u8>) {
+    frame.push(gripper.percent);
+    frame.push(gripper.target);
+    frame.push(gripper.last.map_or(GRIPPER_NONE, GripperMode::to_byte));
+}
+
+// Every angle in turn, without a count
+pub fn encode_angles(angles: impl IntoIterator<Item = u16>, frame: &mut Vec<u8>) {
+    for angle in angles {
+        frame.extend_from_slice(&angle.to_be_bytes());
+    }
+}
+
+// Every whole angle in `bytes` as tenths, a trailing odd byte is ignored
+pub fn decode_angles(bytes: &[u8]) -> Vec<u16> {
+    decode_tenths(bytes).into_iter().map(servo::from_degrees).collect()
+}
+
+// Every angle in `bytes` already in tenths, a trailing odd byte is ignored
+fn decode_tenths(bytes: &[u8]) -> Vec<u16> {
+    bytes.chunks_exact(2).map(|angle| u16::from_be_bytes([angle[0], angle[1]])).collect()
+}
+
+// Flags then the move id, or nothing for an untagged move
+fn decode_tag(bytes: &[u8]) -> Result<Option<MoveTag>, DecodeError> {
+    match *bytes {
+        [] => Ok(None),
+        [flags, high, low] if flags & !QUEUE_FLAG == 0 && u16::from_be_bytes([high, low]) != MOVE_ID_NONE => {
+            Ok(Some(MoveTag { id: u16::from_be_bytes([high, low]), queue: flags & QUEUE_FLAG != 0 }))
+        }
+        _ => Err(DecodeError::BadCommand),
+    }
+}
+
+// Whole degrees, low byte first with no count or status, as protocol 1 sent them
+fn encode_legacy_angles(angles: &[u16], frame: &mut Vec<u8>) {
+    for &angle in angles {
+        frame.extend_from_slice(&servo::to_degrees(angle).to_le_bytes());
+    }
+}
+
+// The servo count, every angle as `angle` gives it, then every status byte
+fn encode_positions(positions: &[ServoPosition], angle: impl Fn(u16) -> u16, frame: &mut Vec<u8>) {
+    frame.push(positions.len() as u8);
+    encode_angles(positions.iter().map(|position| angle(position.angle)), frame);
+    frame.extend(positions.iter().map(|position| position.status));
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplyPacket {
+    pub command: u8,
+    pub status: Status,
+    pub payload: ReplyPayload,
+}
+
+impl ReplyPacket {
+    pub fn new(command: u8, status: Status, payload: ReplyPayload) -> ReplyPacket {
+        ReplyPacket { command, status, payload }
+    }
+
+    // Encodes the reply behind the sequence number of the packet it answers, the CRC is left to the caller. Legacy
+    // positions go out bare, as protocol 1 sent every reply
+    pub fn encode(&self, sequence: u16) -> Vec<u8> {
+        if let ReplyPayload::LegacyPositions(angles) = &self.payload {
+            let mut frame = Vec::with_capacity(angles.len() * 2);
+            encode_legacy_angles(angles, &mut frame);
+            return frame;
+        }
+        let mut frame = Vec::with_capacity(HEADER_SIZE + 3 + SERVO_COUNT * 3);
+        frame.extend_from_slice(&sequence.to_be_bytes());
+        frame.push(self.command);
+        frame.push(self.status as u8);
+        let tenths = matches!(self.command, POSE_TENTHS_COMMAND | TELEMETRY_TENTHS_COMMAND);
+        self.payload.encode(tenths, &mut frame);
+        frame
+    }
+
+    // Whether the reply is a protocol 1 one, which has no header and no CRC
+    pub fn is_legacy(&self) -> bool {
+        matches!(self.payload, ReplyPayload::LegacyPositions(_))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEGREES: [u16; SERVO_COUNT] = [90, 45, 0, 180, 10];
+    const TENTHS: [u16; SERVO_COUNT] = [900, 450, 0, 1800, 100]; // DEGREES as they're decoded
+
+    fn be(values: &[u16]) -> Vec<u8> {
+        values.iter().flat_map(|value| value.to_be_bytes()).collect()
+    }
+
+    fn frame(command: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![command];
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn keyframe() -> Vec<u8> {
+        let mut bytes = be(&DEGREES);
+        bytes.extend_from_slice(&250u16.to_be_bytes());
+        bytes
+    }
+
+    // A frame of every command and form, and what it decodes to
+    fn exact_frames() -> Vec<(Vec<u8>, ControlPacket)> {
+        let mut upload = vec![1];
+        upload.extend(keyframe());
+        vec![
+            (frame(MOVE_COMMAND, &[&[5u8][..], &be(&DEGREES)].concat()), ControlPacket::SetAngles(TENTHS.to_vec())),
+            (frame(JOINT_COMMAND, &[2, 0, 90]), ControlPacket::MoveJoint { index: 2, angle: 900, speed: None }),
+            (
+                frame(JOINT_COMMAND, &[2, 0, 90, 0, 60]),
+                ControlPacket::MoveJoint { index: 2, angle: 900, speed: Some(60) },
+            ),
+            (frame(JOG_COMMAND, &[2, 0xF6]), ControlPacket::Jog { index: 2, delta_tenths: -10 }),
+            (frame(PING_COMMAND, &[]), ControlPacket::Ping),
+            (frame(STATUS_COMMAND, &[]), ControlPacket::Status),
+            (frame(PAUSE_COMMAND, &[]), ControlPacket::Pause),
+            (frame(RESUME_COMMAND, &[]), ControlPacket::Resume),
+            (frame(GRIPPER_COMMAND, &[]), ControlPacket::Gripper(None)),
+            (frame(GRIPPER_COMMAND, &[GRIPPER_MOVE, 40]), ControlPacket::Gripper(Some((GripperMode::Move, 40)))),
+            (frame(GRIPPER_COMMAND, &[GRIPPER_GRIP, 80]), ControlPacket::Gripper(Some((GripperMode::Grip, 80)))),
+            (frame(SPEED_COMMAND, &[]), ControlPacket::SpeedScale(None)),
+            (frame(SPEED_COMMAND, &[SPEED_PERSIST_FLAG, 50]), ControlPacket::SpeedScale(Some((50, true)))),
+            (frame(MIRROR_COMMAND, &[]), ControlPacket::Mirror(None)),
+            (frame(MIRROR_COMMAND, &[0, 1]), ControlPacket::Mirror(Some((true, false)))),
+            (frame(MIRROR_COMMAND, &[MIRROR_PERSIST_FLAG, 0]), ControlPacket::Mirror(Some((false, true)))),
+            (
+                frame(CONFIG_COMMAND, &[3, 0, 120, 0xFF, 0xF6, 1, 0, 90, 0, 30]),
+                ControlPacket::Config(ConfigCommand::Servo(ServoConfig {
+                    index: 3,
+                    speed: 120,
+                    trim: -10,
+                    reversed: true,
+                    safe_angle: 900,
+                    detach_s: 30,
+                    accel: None,
+                })),
+            ),
+            (
+                frame(CONFIG_COMMAND, &[FAILSAFE_CONFIG_INDEX, 0x01, 0xF4, 2, 0, 0, 0, 0, 0, 0]),
+                ControlPacket::Config(ConfigCommand::Failsafe(FailsafeConfig { timeout_ms: 500, action: 2 })),
+            ),
+            (
+                frame(CONFIG_COMMAND, &[BATTERY_CONFIG_INDEX, 0x0B, 0xB8, 0x1B, 0x58, 0, 200, 0, 0, 0]),
+                ControlPacket::Config(ConfigCommand::Battery(BatteryConfig {
+                    divider: 3000,
+                    cutoff_mv: 7000,
+                    hysteresis_mv: 200,
+                })),
+            ),
+            (
+                frame(CONFIG_COMMAND, &[DISPLAY_CONFIG_INDEX, 200, 0, 30, 0x01, 0x2C, 1, 0, 0, 0]),
+                ControlPacket::Config(ConfigCommand::Display(DisplayConfig {
+                    brightness: 200,
+                    dim_s: 30,
+                    sleep_s: 300,
+                    flipped: true,
+                })),
+            ),
+            (
+                frame(CONFIG_COMMAND, &[BUZZER_CONFIG_INDEX, 1, 0, 0, 0, 0, 0, 0, 0, 0]),
+                ControlPacket::Config(ConfigCommand::Buzzer(BuzzerConfig { muted: true })),
+            ),
+            (
+                frame(LIMITS_COMMAND, &[4, 0, 10, 0, 170]),
+                ControlPacket::Limits { index: 4, min_limit: 100, max_limit: 1700 },
+            ),
+            (
+                frame(CALIBRATION_COMMAND, &[0, 1, 0, 0]),
+                ControlPacket::Calibration { index: 1, command: CalibrationCommand::Enter },
+            ),
+            (
+                frame(CALIBRATION_COMMAND, &[1, 1, 0x01, 0x2C]),
+                ControlPacket::Calibration { index: 1, command: CalibrationCommand::SetDuty(300) },
+            ),
+            (
+                frame(CALIBRATION_COMMAND, &[2, 1, 0, 0]),
+                ControlPacket::Calibration { index: 1, command: CalibrationCommand::CaptureMin },
+            ),
+            (
+                frame(CALIBRATION_COMMAND, &[3, 1, 0, 0]),
+                ControlPacket::Calibration { index: 1, command: CalibrationCommand::CaptureMax },
+            ),
+            (
+                frame(CALIBRATION_COMMAND, &[4, 1, 0, 0]),
+                ControlPacket::Calibration { index: 1, command: CalibrationCommand::Exit },
+            ),
+            (
+                frame(CALIBRATION_COMMAND, &[5, 1, 0xFF, 0xFF]),
+                ControlPacket::Calibration { index: 1, command: CalibrationCommand::FeedbackMin(FEEDBACK_CAPTURE) },
+            ),
+            (
+                frame(CALIBRATION_COMMAND, &[6, 1, 0x0F, 0xA0]),
+                ControlPacket::Calibration { index: 1, command: CalibrationCommand::FeedbackMax(4000) },
+            ),
+            (
+                frame(CALIBRATION_COMMAND, &[7, 1, 0x05, 0xDC]),
+                ControlPacket::Calibration { index: 1, command: CalibrationCommand::SetPulse(1500) },
+            ),
+            (
+                frame(TRAJECTORY_UPLOAD_COMMAND, &upload),
+                ControlPacket::UploadTrajectory(vec![Keyframe { angles: TENTHS, dwell_ms: 250 }]),
+            ),
+            (frame(TRAJECTORY_STORE_COMMAND, &[2]), ControlPacket::StoreTrajectory { slot: 2 }),
+            (
+                frame(TRAJECTORY_PLAY_COMMAND, &[2, 1]),
+                ControlPacket::PlayTrajectory { slot: 2, looping: true, tag: None },
+            ),
+            (frame(TELEMETRY_COMMAND, &[]), ControlPacket::Telemetry { tenths: false }),
+            (frame(TELEMETRY_TENTHS_COMMAND, &[]), ControlPacket::Telemetry { tenths: true }),
+            (frame(CLAIM_COMMAND, &[]), ControlPacket::Claim),
+            (frame(RELEASE_COMMAND, &[]), ControlPacket::Release),
+            (frame(SUBSCRIBE_COMMAND, &[0x03, 0xE8]), ControlPacket::Subscribe { interval_ms: 1000 }),
+            (
+                frame(SHUTDOWN_COMMAND, &[CONFIRM_BYTE]),
+                ControlPacket::Shutdown { reboot: false, confirm: CONFIRM_BYTE },
+            ),
+            (frame(REBOOT_COMMAND, &[CONFIRM_BYTE]), ControlPacket::Shutdown { reboot: true, confirm: CONFIRM_BYTE }),
+            (
+                frame(PRESET_RECALL_COMMAND, &[3, 0x07, 0xD0]),
+                ControlPacket::RecallPreset { slot: 3, duration_ms: 2000 },
+            ),
+            (frame(PRESET_LIST_COMMAND, &[]), ControlPacket::ListPresets),
+            (frame(PRESET_CLEAR_COMMAND, &[3]), ControlPacket::ClearPreset { slot: 3 }),
+            (frame(ESTOP_COMMAND, &[]), ControlPacket::EStop),
+            (frame(CLEAR_ESTOP_COMMAND, &[]), ControlPacket::ClearEStop),
+        ]
+    }
+
+    #[test]
+    fn decode_reads_every_command() {
+        for (bytes, packet) in exact_frames() {
+            assert_eq!(ControlPacket::decode(&bytes), Ok(packet), "{bytes:?}");
+        }
+    }
+
+    #[test]
+    fn decode_refuses_truncated_frames() {
+        for (bytes, _) in exact_frames() {
+            assert_eq!(ControlPacket::decode(&bytes[..bytes.len() - 1]), Err(DecodeError::BadLength), "{bytes:?}");
+        }
+    }
+
+    #[test]
+    fn decode_refuses_oversized_frames() {
+        for (mut bytes, _) in exact_frames() {
+            bytes.push(0);
+            assert_eq!(ControlPacket::decode(&bytes), Err(DecodeError::BadLength), "{bytes:?}");
+        }
+    }
+
+    #[test]
+    fn decode_echoes_the_command_of_every_packet() {
+        for (bytes, packet) in exact_frames() {
+            assert_eq!(packet.command(), bytes[0]);
+        }
+    }
+
+    #[test]
+    fn decode_takes_a_move_of_any_count() {
+        assert_eq!(ControlPacket::decode(&[MOVE_COMMAND, 0]), Ok(ControlPacket::SetAngles(vec![])));
+        assert_eq!(ControlPacket::decode(&[MOVE_COMMAND, 1, 0, 45]), Ok(ControlPacket::SetAngles(vec![450])));
+        assert_eq!(ControlPacket::decode(&[MOVE_COMMAND]), Err(DecodeError::BadLength));
+        assert_eq!(ControlPacket::decode(&[MOVE_COMMAND, 2, 0, 45]), Err(DecodeError::BadLength));
+    }
+
+    #[test]
+    fn decode_takes_strings_up_to_their_limit() {
+        let name = [b'a'; preset::MAX_NAME_SIZE];
+        let saved = |name: &[u8]| ControlPacket::SavePreset {
+            slot: 1,
+            name: String::from_utf8(name.to_vec()).unwrap(),
+        };
+        assert_eq!(ControlPacket::decode(&[PRESET_SAVE_COMMAND, 1]), Ok(saved(b"")));
+        assert_eq!(ControlPacket::decode(&frame(PRESET_SAVE_COMMAND, &[&[1], &name[..]].concat())), Ok(saved(&name)));
+        let long = frame(PRESET_SAVE_COMMAND, &[&[1], &name[..], b"a"].concat());
+        assert_eq!(ControlPacket::decode(&long), Err(DecodeError::BadLength));
+        assert_eq!(ControlPacket::decode(&[PRESET_SAVE_COMMAND]), Err(DecodeError::BadLength));
+
+        let target = [b'a'; remote_log::MAX_TARGET_SIZE];
+        let set = |target: &[u8]| ControlPacket::LogTarget {
+            persist: true,
+            level: 4,
+            target: String::from_utf8(target.to_vec()).unwrap(),
+        };
+        assert_eq!(ControlPacket::decode(&[LOG_TARGET_COMMAND, LOG_PERSIST_FLAG, 4]), Ok(set(b"")));
+        let full = frame(LOG_TARGET_COMMAND, &[&[LOG_PERSIST_FLAG, 4], &target[..]].concat());
+        assert_eq!(ControlPacket::decode(&full), Ok(set(&target)));
+        let long = frame(LOG_TARGET_COMMAND, &[&[LOG_PERSIST_FLAG, 4], &target[..], b"a"].concat());
+        assert_eq!(ControlPacket::decode(&long), Err(DecodeError::BadLength));
+        assert_eq!(ControlPacket::decode(&[LOG_TARGET_COMMAND, 0]), Err(DecodeError::BadLength));
+
+        let url = [b'a'; ota::MAX_URL_SIZE];
+        let update = ControlPacket::Update { url: String::from_utf8(url.to_vec()).unwrap() };
+        assert_eq!(ControlPacket::decode(&frame(OTA_COMMAND, &url)), Ok(update));
+        assert_eq!(ControlPacket::decode(&frame(OTA_COMMAND, &[&url[..], b"a"].concat())), Err(DecodeError::BadLength));
+        assert_eq!(ControlPacket::decode(&[OTA_COMMAND]), Err(DecodeError::BadLength));
+    }
+
+    #[test]
+    fn decode_takes_a_log_level_or_none() {
+        assert_eq!(ControlPacket::decode(&[LOG_LEVEL_COMMAND]), Ok(ControlPacket::LogLevel(None)));
+        assert_eq!(ControlPacket::decode(&[LOG_LEVEL_COMMAND, 4]), Ok(ControlPacket::LogLevel(Some(4))));
+        assert_eq!(ControlPacket::decode(&[LOG_LEVEL_COMMAND, 4, 0]), Err(DecodeError::BadLength));
+    }
+
+    #[test]
+    fn only_commands_that_drive_the_servos_are_motion() {
+        assert!(ControlPacket::SetAngles(TENTHS.to_vec()).is_motion());
+        assert!(ControlPacket::Gripper(Some((GripperMode::Move, 40))).is_motion());
+        assert!(!ControlPacket::Gripper(None).is_motion());
+        assert!(!ControlPacket::Ping.is_motion());
+        assert!(!ControlPacket::EStop.is_motion());
+    }
+
+    #[test]
+    fn decode_takes_a_teach_command_or_none() {
+        assert_eq!(ControlPacket::decode(&[TEACH_COMMAND]), Ok(ControlPacket::Teach(None)));
+        let start = ControlPacket::decode(&[TEACH_COMMAND, TEACH_START]);
+        assert_eq!(start, Ok(ControlPacket::Teach(Some(TeachCommand::Start))));
+        let stop = ControlPacket::decode(&[TEACH_COMMAND, TEACH_STOP]);
+        assert_eq!(stop, Ok(ControlPacket::Teach(Some(TeachCommand::Stop))));
+        assert_eq!(ControlPacket::decode(&[TEACH_COMMAND, 2]), Err(DecodeError::BadCommand));
+        assert_eq!(ControlPacket::decode(&[TEACH_COMMAND, TEACH_STOP, 0]), Err(DecodeError::BadLength));
+    }
+
+    #[test]
+    fn decode_takes_a_self_test_start_or_a_read() {
+        assert_eq!(ControlPacket::decode(&[SELF_TEST_COMMAND]), Ok(ControlPacket::SelfTest { start: false }));
+        let start = ControlPacket::decode(&[SELF_TEST_COMMAND, SELF_TEST_START]);
+        assert_eq!(start, Ok(ControlPacket::SelfTest { start: true }));
+        assert_eq!(ControlPacket::decode(&[SELF_TEST_COMMAND, 1]), Err(DecodeError::BadCommand));
+        assert_eq!(ControlPacket::decode(&[SELF_TEST_COMMAND, SELF_TEST_START, 0]), Err(DecodeError::BadLength));
+    }
+
+    #[test]
+    fn decode_takes_a_loop_timing_reset_or_a_read() {
+        assert_eq!(ControlPacket::decode(&[LOOP_TIMING_COMMAND]), Ok(ControlPacket::LoopTiming { reset: false }));
+        let reset = ControlPacket::decode(&[LOOP_TIMING_COMMAND, LOOP_TIMING_RESET]);
+        assert_eq!(reset, Ok(ControlPacket::LoopTiming { reset: true }));
+        assert_eq!(ControlPacket::decode(&[LOOP_TIMING_COMMAND, 1]), Err(DecodeError::BadCommand));
+        assert_eq!(ControlPacket::decode(&[LOOP_TIMING_COMMAND, 0, 0]), Err(DecodeError::BadLength));
+    }
+
+    #[test]
+    fn decode_takes_a_history_chunk_or_none() {
+        assert_eq!(ControlPacket::decode(&[HISTORY_COMMAND]), Ok(ControlPacket::History { chunk: None }));
+        assert_eq!(ControlPacket::decode(&[HISTORY_COMMAND, 3]), Ok(ControlPacket::History { chunk: Some(3) }));
+        assert_eq!(ControlPacket::decode(&[HISTORY_COMMAND, 3, 0]), Err(DecodeError::BadLength));
+    }
+
+    #[test]
+    fn decode_takes_a_current_clear_or_calibration_or_none() {
+        assert_eq!(ControlPacket::decode(&[CURRENT_COMMAND]), Ok(ControlPacket::Current(None)));
+        let clear = ControlPacket::decode(&[CURRENT_COMMAND, CURRENT_CLEAR, CURRENT_ALL_JOINTS]);
+        assert_eq!(clear, Ok(ControlPacket::Current(Some(CurrentCommand::Clear { index: CURRENT_ALL_JOINTS }))));
+        let bytes = [CURRENT_COMMAND, CURRENT_CALIBRATE, 1, 0xFF, 0xFF, 0xFC, 0x18, 0x00, 0x00, 0x27, 0x10];
+        let calibration = CurrentCalibration { zero_uv: -1000, ma_per_v: 10_000 };
+        let calibrate = ControlPacket::Current(Some(CurrentCommand::Calibrate { sensor: 1, calibration }));
+        assert_eq!(ControlPacket::decode(&bytes), Ok(calibrate));
+        assert_eq!(ControlPacket::decode(&bytes[..10]), Err(DecodeError::BadLength));
+        assert_eq!(ControlPacket::decode(&[CURRENT_COMMAND, CURRENT_CLEAR]), Err(DecodeError::BadLength));
+        assert_eq!(ControlPacket::decode(&[CURRENT_COMMAND, 2, 0]), Err(DecodeError::BadCommand));
+    }
+
+    #[test]
+    fn decode_takes_a_sleep_with_or_without_a_wake_timer() {
+        let sleep = |wake_s| Ok(ControlPacket::Sleep { confirm: CONFIRM_BYTE, wake_s });
+        assert_eq!(ControlPacket::decode(&[SLEEP_COMMAND, CONFIRM_BYTE]), sleep(None));
+        assert_eq!(ControlPacket::decode(&[SLEEP_COMMAND, CONFIRM_BYTE, 0, 0, 0x0E, 0x10]), sleep(Some(3600)));
+        // A timer of 0 sleeps until the button, as no timer does
+        assert_eq!(ControlPacket::decode(&[SLEEP_COMMAND, CONFIRM_BYTE, 0, 0, 0, 0]), sleep(None));
+        assert_eq!(ControlPacket::decode(&[SLEEP_COMMAND]), Err(DecodeError::BadLength));
+        assert_eq!(ControlPacket::decode(&[SLEEP_COMMAND, CONFIRM_BYTE, 0, 0]), Err(DecodeError::BadLength));
+    }
+
+    #[test]
+    fn decode_takes_a_pose_with_or_without_an_easing() {
+        let pose = [&[5u8][..], &be(&DEGREES), &[0x05, 0xDC]].concat();
+        let posed = |easing| ControlPacket::Pose {
+            angles: TENTHS.to_vec(),
+            duration_ms: 1500,
+            easing,
+            tag: None,
+            tenths: false,
+        };
+        assert_eq!(ControlPacket::decode(&frame(POSE_COMMAND, &pose)), Ok(posed(Easing::Linear)));
+        assert_eq!(ControlPacket::decode(&frame(POSE_COMMAND, &[&pose[..], &[4]].concat())), Ok(posed(Easing::Cubic)));
+        let tenths = ControlPacket::Pose {
+            angles: vec![905, 1],
+            duration_ms: 0,
+            easing: Easing::Linear,
+            tag: None,
+            tenths: true,
+        };
+        assert_eq!(ControlPacket::decode(&frame(POSE_TENTHS_COMMAND, &[2, 0x03, 0x89, 0, 1, 0, 0])), Ok(tenths));
+        assert_eq!(ControlPacket::decode(&frame(POSE_COMMAND, &pose[..pose.len() - 1])), Err(DecodeError::BadLength));
+        assert_eq!(
+            ControlPacket::decode(&frame(POSE_COMMAND, &[&pose[..], &[5]].concat())),
+            Err(DecodeError::BadCommand)
+        );
+        assert_eq!(
+            ControlPacket::decode(&frame(POSE_COMMAND, &[&pose[..], &[4, 0]].concat())),
+            Err(DecodeError::BadLength)
+        );
+    }
+
+    #[test]
+    fn decode_takes_a_tag_after_an_eased_pose_or_a_trajectory_play() {
+        let pose = [&[5u8][..], &be(&DEGREES), &[0x05, 0xDC, 2]].concat();
+        let tag = Some(MoveTag { id: 0x0102, queue: true });
+        let tagged = ControlPacket::Pose {
+            angles: TENTHS.to_vec(),
+            duration_ms: 1500,
+            easing: Easing::EaseOut,
+            tag,
+            tenths: false,
+        };
+        let bytes = [&pose[..], &[QUEUE_FLAG, 1, 2]].concat();
+        assert_eq!(ControlPacket::decode(&frame(POSE_COMMAND, &bytes)), Ok(tagged));
+        let tag = Some(MoveTag { id: 7, queue: false });
+        let play = ControlPacket::PlayTrajectory { slot: 2, looping: false, tag };
+        assert_eq!(ControlPacket::decode(&frame(TRAJECTORY_PLAY_COMMAND, &[2, 0, 0, 0, 7])), Ok(play));
+        // Unknown flags and the id the status reply keeps for none are refused, a part of a tag is the wrong length
+        let flagged = [2, 0, 2, 0, 7];
+        assert_eq!(ControlPacket::decode(&frame(TRAJECTORY_PLAY_COMMAND, &flagged)), Err(DecodeError::BadCommand));
+        let none = [2, 0, 0, 0xFF, 0xFF];
+        assert_eq!(ControlPacket::decode(&frame(TRAJECTORY_PLAY_COMMAND, &none)), Err(DecodeError::BadCommand));
+        assert_eq!(ControlPacket::decode(&frame(TRAJECTORY_PLAY_COMMAND, &[2, 0, 0, 7])), Err(DecodeError::BadLength));
+        assert_eq!(ControlPacket::decode(&frame(POSE_COMMAND, &bytes[..bytes.len() - 1])), Err(DecodeError::BadLength));
+    }
+
+    #[test]
+    fn decode_takes_a_servo_config_with_or_without_an_acceleration() {
+        let bytes = [3, 0, 120, 0xFF, 0xF6, 1, 0, 90, 0, 30, 0x01, 0x90];
+        let Ok(ControlPacket::Config(ConfigCommand::Servo(config))) =
+            ControlPacket::decode(&frame(CONFIG_COMMAND, &bytes))
+        else {
+            panic!("not a servo config");
+        };
+        assert_eq!((config.detach_s, config.accel), (30, Some(400)));
+        let Ok(ControlPacket::Config(ConfigCommand::Servo(config))) =
+            ControlPacket::decode(&frame(CONFIG_COMMAND, &bytes[..10]))
+        else {
+            panic!("not a servo config");
+        };
+        assert_eq!(config.accel, None);
+        assert_eq!(ControlPacket::decode(&frame(CONFIG_COMMAND, &bytes[..11])), Err(DecodeError::BadLength));
+        // Only a servo's config has one
+        for index in [DISPLAY_CONFIG_INDEX, BUZZER_CONFIG_INDEX] {
+            let config = [&[index][..], &bytes[1..]].concat();
+            assert_eq!(ControlPacket::decode(&frame(CONFIG_COMMAND, &config)), Err(DecodeError::BadLength));
+        }
+    }
+
+    #[test]
+    fn decode_refuses_unknown_commands_and_sub_commands() {
+        assert_eq!(ControlPacket::decode(&[0x80]), Err(DecodeError::BadCommand));
+        assert_eq!(ControlPacket::decode(&[0x81, 1, 2]), Err(DecodeError::BadCommand));
+        assert_eq!(ControlPacket::decode(&[CALIBRATION_COMMAND, 8, 1, 0, 0]), Err(DecodeError::BadCommand));
+        assert_eq!(ControlPacket::decode(&[GRIPPER_COMMAND, 2, 40]), Err(DecodeError::BadCommand));
+        // Only ever sent by the limb
+        assert_eq!(ControlPacket::decode(&[MOVE_DONE_COMMAND, 0, 1]), Err(DecodeError::BadCommand));
+    }
+
+    // Reads a reply payload back the way a client would, for the round trips below
+    struct Reader<'a> {
+        bytes: &'a [u8],
+        tenths: bool, // Angles come in tenths, as in replies to the tenths commands
+    }
+
+    impl<'a> Reader<'a> {
+        fn take(&mut self, count: usize) -> &'a [u8] {
+            let (taken, rest) = self.bytes.split_at(count);
+            self.bytes = rest;
+            taken
+        }
+
+        fn u8(&mut self) -> u8 {
+            self.take(1)[0]
+        }
+
+        fn u16(&mut self) -> u16 {
+            u16::from_be_bytes(self.take(2).try_into().unwrap())
+        }
+
+        // An angle as tenths, scaling whole degrees up unless the reply carries tenths
+        fn angle(&mut self) -> u16 {
+            let angle = self.u16();
+            self.scale(angle)
+        }
+
+        fn scale(&self, angle: u16) -> u16 {
+            match self.tenths {
+                true => angle,
+                false => servo::from_degrees(angle),
+            }
+        }
+
+        fn u32(&mut self) -> u32 {
+            u32::from_be_bytes(self.take(4).try_into().unwrap())
+        }
+
+        fn time(&mut self) -> Option<u64> {
+            let known = self.u8() != 0;
+            let time_ms = u64::from_be_bytes(self.take(8).try_into().unwrap());
+            known.then_some(time_ms)
+        }
+
+        fn loop_timing(&mut self) -> LoopTiming {
+            let (passes, slow) = (self.u32(), self.u32());
+            assert_eq!(self.u8() as usize, LOOP_PHASES);
+            let mut phases = [PhaseTiming::default(); LOOP_PHASES];
+            for phase in phases.iter_mut() {
+                *phase = PhaseTiming { mean_us: self.u32(), max_us: self.u32() };
+            }
+            LoopTiming { passes, slow, phases }
+        }
+
+        fn string(&mut self) -> String {
+            let length = self.u8() as usize;
+            String::from_utf8(self.take(length).to_vec()).unwrap()
+        }
+
+        fn positions(&mut self) -> Vec<ServoPosition> {
+            let count = self.u8();
+            let angles: Vec<u16> = (0..count).map(|_| self.angle()).collect();
+            angles.into_iter().map(|angle| ServoPosition { angle, status: self.u8() }).collect()
+        }
+
+        fn joint_current(&mut self, sensor: Option<u8>) -> JointCurrent {
+            JointCurrent {
+                sensor,
+                current_ma: Some(self.u16()).filter(|&current_ma| current_ma != CURRENT_NONE),
+                overloaded: self.u8() != 0,
+            }
+        }
+
+        fn history_entry(&mut self) -> HistoryEntry {
+            let mut entry = HistoryEntry {
+                uptime_ms: self.u32(),
+                boot: self.u8(),
+                ip: self.take(4).try_into().unwrap(),
+                port: self.u16(),
+                command: self.u8(),
+                status: self.u8(),
+                count: self.u8(),
+                params: [0; HISTORY_PARAMS],
+            };
+            for param in entry.params[..entry.count as usize].iter_mut() {
+                *param = self.u16();
+            }
+            entry
+        }
+
+        fn gripper(&mut self) -> GripperStatus {
+            GripperStatus {
+                percent: self.u8(),
+                target: self.u8(),
+                last: match self.u8() {
+                    GRIPPER_MOVE => Some(GripperMode::Move),
+                    GRIPPER_GRIP => Some(GripperMode::Grip),
+                    _ => None,
+                },
+            }
+        }
+    }
+
+    // The payload `reader` holds, of the same kind as `sent`
+    fn read_payload(sent: &ReplyPayload, reader: &mut Reader) -> ReplyPayload {
+        match sent {
+            ReplyPayload::Empty => ReplyPayload::Empty,
+            ReplyPayload::Positions(_) => ReplyPayload::Positions(reader.positions()),
+            ReplyPayload::LegacyPositions(_) => unreachable!("sent bare, without a header"),
+            ReplyPayload::Joint { .. } => ReplyPayload::Joint {
+                index: reader.u8(),
+                position: ServoPosition { angle: reader.angle(), status: reader.u8() },
+            },
+            ReplyPayload::Jog { .. } => {
+                ReplyPayload::Jog { index: reader.u8(), goal_tenths: reader.u16(), status: reader.u8() }
+            }
+            ReplyPayload::Ping { .. } => {
+                assert_eq!(reader.take(2), PING_MAGIC);
+                ReplyPayload::Ping { version_maj: reader.u8(), version_min: reader.u8(), positions: reader.positions() }
+            }
+            ReplyPayload::ServoConfig(sent) => ReplyPayload::ServoConfig(ServoConfig {
+                index: reader.u8(),
+                speed: reader.u16(),
+                trim: reader.u16() as i16,
+                reversed: reader.u8() != 0,
+                safe_angle: reader.angle(),
+                detach_s: reader.u16(),
+                accel: sent.accel.map(|_| reader.u16()),
+            }),
+            ReplyPayload::FailsafeConfig(_) => {
+                assert_eq!(reader.u8(), FAILSAFE_CONFIG_INDEX);
+                ReplyPayload::FailsafeConfig(FailsafeConfig { timeout_ms: reader.u16(), action: reader.u8() })
+            }
+            ReplyPayload::BatteryConfig(_) => {
+                assert_eq!(reader.u8(), BATTERY_CONFIG_INDEX);
+                ReplyPayload::BatteryConfig(BatteryConfig {
+                    divider: reader.u16(),
+                    cutoff_mv: reader.u16(),
+                    hysteresis_mv: reader.u16(),
+                })
+            }
+            ReplyPayload::DisplayConfig(_) => {
+                assert_eq!(reader.u8(), DISPLAY_CONFIG_INDEX);
+                ReplyPayload::DisplayConfig(DisplayConfig {
+                    brightness: reader.u8(),
+                    dim_s: reader.u16(),
+                    sleep_s: reader.u16(),
+                    flipped: reader.u8() != 0,
+                })
+            }
+            ReplyPayload::BuzzerConfig(_) => {
+                assert_eq!(reader.u8(), BUZZER_CONFIG_INDEX);
+                ReplyPayload::BuzzerConfig(BuzzerConfig { muted: reader.u8() != 0 })
+            }
+            ReplyPayload::Limits { .. } => {
+                ReplyPayload::Limits { index: reader.u8(), min_limit: reader.angle(), max_limit: reader.angle() }
+            }
+            // Only the sub-command's byte goes back, not its argument
+            ReplyPayload::Calibration { .. } => ReplyPayload::Calibration {
+                command: match reader.u8() {
+                    0 => CalibrationCommand::Enter,
+                    2 => CalibrationCommand::CaptureMin,
+                    3 => CalibrationCommand::CaptureMax,
+                    4 => CalibrationCommand::Exit,
+                    byte => panic!("sub-command {byte} carries an argument"),
+                },
+                index: reader.u8(),
+                duty: reader.u16(),
+            },
+            ReplyPayload::Trajectory { .. } => {
+                ReplyPayload::Trajectory { slot: reader.u8(), frames: reader.u8(), captured_ms: reader.time() }
+            }
+            ReplyPayload::Preset(_) => ReplyPayload::Preset(reader.u8()),
+            ReplyPayload::Presets(_) => {
+                let count = reader.u8();
+                ReplyPayload::Presets((0..count).map(|_| (reader.u8(), reader.string())).collect())
+            }
+            ReplyPayload::Telemetry(_) => {
+                let (version_maj, version_min) = (reader.u8(), reader.u8());
+                assert_eq!(reader.u8(), PROTOCOL_VERSION);
+                ReplyPayload::Telemetry(Telemetry {
+                    version_maj,
+                    version_min,
+                    uptime_s: reader.u32(),
+                    free_heap: reader.u32(),
+                    rssi: reader.u8() as i8,
+                    received: reader.u32(),
+                    rejected: reader.u32(),
+                    malformed: reader.u32(),
+                    flags: reader.u8(),
+                    auth_failures: reader.u32(),
+                    reset_reason: reader.u8(),
+                    battery_mv: reader.u16(),
+                    last_crash: reader.string(),
+                    log_level: reader.u8(),
+                    gripper: Some(reader.gripper()).filter(|gripper| gripper.percent != GRIPPER_NONE),
+                    joints: {
+                        let count = reader.u8();
+                        (0..count)
+                            .map(|_| MeasuredAngle {
+                                angle: reader.angle(),
+                                measured: Some(reader.u16())
+                                    .filter(|&measured| measured != FEEDBACK_NONE)
+                                    .map(|measured| reader.scale(measured)),
+                            })
+                            .collect()
+                    },
+                    applied: reader.u32(),
+                    deduplicated: reader.u32(),
+                    // Only the current reply says which sensor each joint is on
+                    currents: {
+                        let count = reader.u8();
+                        (0..count).map(|_| reader.joint_current(None)).collect()
+                    },
+                })
+            }
+            ReplyPayload::Owner(_) => {
+                let octets: [u8; 4] = reader.take(4).try_into().unwrap();
+                ReplyPayload::Owner(Ipv4Addr::from(octets))
+            }
+            ReplyPayload::Heartbeat { .. } => {
+                let (flags, rssi, count) = (reader.u8(), reader.u8() as i8, reader.u8());
+                ReplyPayload::Heartbeat {
+                    flags,
+                    rssi,
+                    angles: (0..count).map(|_| reader.angle()).collect(),
+                    moving: (0..count).map(|_| reader.u8() != 0).collect(),
+                    time_ms: reader.time(),
+                }
+            }
+            ReplyPayload::Index(_) => ReplyPayload::Index(reader.u8()),
+            ReplyPayload::LogLevel { .. } => {
+                ReplyPayload::LogLevel { level: reader.u8(), forwarding: reader.u8() != 0 }
+            }
+            ReplyPayload::TargetLevel { .. } => {
+                ReplyPayload::TargetLevel { level: reader.u8(), target: reader.string() }
+            }
+            ReplyPayload::Gripper(_) => ReplyPayload::Gripper(reader.gripper()),
+            ReplyPayload::SpeedScale(_) => ReplyPayload::SpeedScale(reader.u8()),
+            ReplyPayload::Mirror(_) => ReplyPayload::Mirror(reader.u8() != 0),
+            ReplyPayload::LoopTiming(_) => ReplyPayload::LoopTiming(reader.loop_timing()),
+            ReplyPayload::MoveDone { .. } => {
+                let (id, count) = (reader.u16(), reader.u8());
+                ReplyPayload::MoveDone { id, angles: (0..count).map(|_| reader.angle()).collect() }
+            }
+            ReplyPayload::History { .. } => {
+                let (boot, total, chunk, chunks) = (reader.u8(), reader.u8(), reader.u8(), reader.u8());
+                let count = reader.u8();
+                let entries = (0..count).map(|_| reader.history_entry()).collect();
+                ReplyPayload::History { boot, total, chunk, chunks, entries }
+            }
+            ReplyPayload::Current { .. } => {
+                let (limit_ma, window_ms) = (reader.u16(), reader.u16());
+                let count = reader.u8();
+                let joints = (0..count)
+                    .map(|_| {
+                        let sensor = Some(reader.u8()).filter(|&sensor| sensor != SENSOR_NONE);
+                        reader.joint_current(sensor)
+                    })
+                    .collect();
+                let count = reader.u8();
+                let sensors = (0..count)
+                    .map(|_| SensorReading {
+                        current_ma: Some(reader.u16()).filter(|&current_ma| current_ma != CURRENT_NONE),
+                        calibration: CurrentCalibration {
+                            zero_uv: reader.u32() as i32,
+                            ma_per_v: reader.u32(),
+                        },
+                    })
+                    .collect();
+                ReplyPayload::Current { limit_ma, window_ms, joints, sensors }
+            }
+            ReplyPayload::SelfTest { .. } => ReplyPayload::SelfTest {
+                running: reader.u8() != 0,
+                results: {
+                    let count = reader.u8() as usize;
+                    reader.take(count).to_vec()
+                },
+            },
+            ReplyPayload::Recording { .. } => {
+                ReplyPayload::Recording { recording: reader.u8() != 0, frames: reader.u8(), remaining: reader.u8() }
+            }
+            ReplyPayload::Status { .. } => ReplyPayload::Status {
+                flags: reader.u8(),
+                rssi: reader.u8() as i8,
+                uptime_s: reader.u32(),
+                battery_mv: reader.u16(),
+                joints: {
+                    let count = reader.u8();
+                    (0..count)
+                        .map(|_| JointStatus { angle: reader.angle(), goal: reader.angle(), status: reader.u8() })
+                        .collect()
+                },
+                restored: reader.u8(),
+                mirrored: reader.u8() != 0,
+                time_ms: reader.time(),
+                timing: reader.loop_timing(),
+                queued: reader.u8(),
+                executing: Some(reader.u16()).filter(|&id| id != MOVE_ID_NONE),
+                unavailable: {
+                    let count = reader.u8();
+                    (0..count).map(|_| (reader.u8(), reader.string())).collect()
+                },
+                i2c_devices: {
+                    let count = reader.u8() as usize;
+                    reader.take(count).to_vec()
+                },
+                temperature_c: Some(reader.u8() as i8).filter(|&celsius| celsius != TEMPERATURE_NONE),
+                thermal: reader.u8(),
+                wake_reason: reader.u8(),
+            },
+        }
+    }
+
+    // One of every payload
+    fn sample_replies() -> Vec<ReplyPayload> {
+        let positions: Vec<ServoPosition> =
+            TENTHS.iter().map(|&angle| ServoPosition { angle, status: Status::Clamped as u8 }).collect();
+        let gripper = GripperStatus { percent: 40, target: 60, last: Some(GripperMode::Grip) };
+        let mut timing = LoopTiming { passes: 70_000, slow: 3, ..LoopTiming::default() };
+        timing.phases[0] = PhaseTiming { mean_us: 950, max_us: 12_000 };
+        timing.phases[LOOP_PHASES - 1] = PhaseTiming { mean_us: 4, max_us: 80 };
+        vec![
+            ReplyPayload::Empty,
+            ReplyPayload::Positions(positions.clone()),
+            ReplyPayload::Joint { index: 2, position: positions[0] },
+            ReplyPayload::Jog { index: 1, goal_tenths: 1205, status: Status::Clamped as u8 },
+            ReplyPayload::Ping { version_maj: 0, version_min: 6, positions },
+            ReplyPayload::ServoConfig(ServoConfig {
+                index: 3,
+                speed: 120,
+                trim: -10,
+                reversed: true,
+                safe_angle: 900,
+                detach_s: 30,
+                accel: None,
+            }),
+            ReplyPayload::ServoConfig(ServoConfig {
+                index: 3,
+                speed: 120,
+                trim: -10,
+                reversed: true,
+                safe_angle: 900,
+                detach_s: 30,
+                accel: Some(400),
+            }),
+            ReplyPayload::FailsafeConfig(FailsafeConfig { timeout_ms: 500, action: 2 }),
+            ReplyPayload::BatteryConfig(BatteryConfig { divider: 3000, cutoff_mv: 7000, hysteresis_mv: 200 }),
+            ReplyPayload::DisplayConfig(DisplayConfig { brightness: 200, dim_s: 30, sleep_s: 300, flipped: true }),
+            ReplyPayload::BuzzerConfig(BuzzerConfig { muted: true }),
+            ReplyPayload::Limits { index: 4, min_limit: 100, max_limit: 1700 },
+            ReplyPayload::Calibration { command: CalibrationCommand::CaptureMax, index: 1, duty: 410 },
+            ReplyPayload::Trajectory { slot: UPLOAD_SLOT, frames: 12, captured_ms: None },
+            ReplyPayload::Trajectory { slot: 2, frames: 12, captured_ms: Some(1_760_000_000_123) },
+            ReplyPayload::Preset(3),
+            ReplyPayload::Presets(vec![(0, "home".into()), (3, String::new())]),
+            ReplyPayload::Telemetry(Telemetry {
+                version_maj: 0,
+                version_min: 6,
+                uptime_s: 3600,
+                free_heap: 120_000,
+                rssi: -61,
+                received: 500,
+                rejected: 2,
+                malformed: 1,
+                flags: TELEMETRY_FAILSAFE_FLAG,
+                auth_failures: 4,
+                reset_reason: 3,
+                last_crash: "panicked at servo.rs".into(),
+                battery_mv: 7400,
+                log_level: 4,
+                gripper: Some(gripper),
+                joints: vec![
+                    MeasuredAngle { angle: 900, measured: Some(880) },
+                    MeasuredAngle { angle: 0, measured: None },
+                ],
+                applied: 480,
+                deduplicated: 17,
+                currents: vec![
+                    JointCurrent { sensor: None, current_ma: Some(1250), overloaded: true },
+                    JointCurrent { sensor: None, current_ma: None, overloaded: false },
+                ],
+            }),
+            ReplyPayload::Owner(Ipv4Addr::new(192, 168, 1, 20)),
+            ReplyPayload::Heartbeat {
+                flags: 0,
+                rssi: -50,
+                angles: TENTHS.to_vec(),
+                moving: vec![true, false, false, true, false],
+                time_ms: None,
+            },
+            ReplyPayload::Index(DISPLAY_CONFIG_INDEX),
+            ReplyPayload::LogLevel { level: 4, forwarding: true },
+            ReplyPayload::TargetLevel { level: 5, target: "lamhshaorga_v2::servo".to_string() },
+            ReplyPayload::Status {
+                flags: TELEMETRY_FAILSAFE_FLAG,
+                rssi: -61,
+                uptime_s: 3600,
+                battery_mv: 7400,
+                joints: vec![
+                    JointStatus { angle: 900, goal: 1200, status: Status::Ok as u8 },
+                    JointStatus { angle: 100, goal: 100, status: Status::Clamped as u8 },
+                ],
+                restored: RESTORED_APPROXIMATE,
+                mirrored: true,
+                time_ms: Some(1_760_000_000_123),
+                timing,
+                queued: 2,
+                executing: Some(41),
+                unavailable: vec![(1, "no timer 2".into())],
+                i2c_devices: vec![0x3c, 0x40],
+                temperature_c: Some(-5),
+                thermal: THERMAL_WARM,
+                wake_reason: 2,
+            },
+            ReplyPayload::Status {
+                flags: 0,
+                rssi: -61,
+                uptime_s: 3600,
+                battery_mv: 7400,
+                joints: vec![],
+                restored: RESTORED_APPROXIMATE,
+                mirrored: false,
+                time_ms: None,
+                timing,
+                queued: 0,
+                executing: None,
+                unavailable: vec![],
+                i2c_devices: vec![],
+                temperature_c: None,
+                thermal: THERMAL_NORMAL,
+                wake_reason: 0,
+            },
+            ReplyPayload::Gripper(GripperStatus { last: None, ..gripper }),
+            ReplyPayload::Recording { recording: true, frames: 5, remaining: 27 },
+            ReplyPayload::SpeedScale(75),
+            ReplyPayload::Mirror(true),
+            ReplyPayload::LoopTiming(timing),
+            ReplyPayload::MoveDone { id: 41, angles: TENTHS.to_vec() },
+            ReplyPayload::History { boot: 2, total: 0, chunk: 0, chunks: 1, entries: vec![] },
+            ReplyPayload::Current {
+                limit_ma: 1500,
+                window_ms: 500,
+                joints: vec![
+                    JointCurrent { sensor: Some(0), current_ma: Some(1620), overloaded: true },
+                    JointCurrent { sensor: Some(0), current_ma: Some(1620), overloaded: false },
+                    JointCurrent { sensor: None, current_ma: None, overloaded: false },
+                ],
+                sensors: vec![
+                    SensorReading {
+                        current_ma: Some(1620),
+                        calibration: CurrentCalibration { zero_uv: 0, ma_per_v: 10_000 },
+                    },
+                    SensorReading {
+                        current_ma: None,
+                        calibration: CurrentCalibration { zero_uv: -2500, ma_per_v: 5000 },
+                    },
+                ],
+            },
+            ReplyPayload::History {
+                boot: 2,
+                total: 40,
+                chunk: 2,
+                chunks: 3,
+                entries: vec![
+                    HistoryEntry {
+                        uptime_ms: 61_000,
+                        boot: 1,
+                        ip: [192, 168, 1, 20],
+                        port: 4210,
+                        command: JOG_COMMAND,
+                        status: Status::Clamped as u8,
+                        count: 2,
+                        params: [1, 0xFFF6, 0, 0, 0],
+                    },
+                    HistoryEntry {
+                        uptime_ms: 62_500,
+                        boot: 2,
+                        ip: [0; 4],
+                        port: 0,
+                        command: POSE_TENTHS_COMMAND,
+                        status: Status::Ok as u8,
+                        count: SERVO_COUNT as u8,
+                        params: TENTHS,
+                    },
+                ],
+            },
+            ReplyPayload::SelfTest {
+                running: true,
+                results: vec![SELF_TEST_PASSED, SELF_TEST_NOT_MOVED, SELF_TEST_PENDING],
+            },
+        ]
+    }
+
+    #[test]
+    fn every_reply_round_trips() {
+        for command in [CONFIG_COMMAND, POSE_TENTHS_COMMAND, TELEMETRY_TENTHS_COMMAND] {
+            for payload in sample_replies() {
+                let reply = ReplyPacket::new(command, Status::Ok, payload);
+                let bytes = reply.encode(0x1234);
+                assert_eq!(&bytes[..4], &[0x12, 0x34, command, Status::Ok as u8]);
+                let mut reader = Reader { bytes: &bytes[4..], tenths: command != CONFIG_COMMAND };
+                assert_eq!(read_payload(&reply.payload, &mut reader), reply.payload);
+                assert!(reader.bytes.is_empty(), "{:?} left {:?}", reply.payload, reader.bytes);
+            }
+        }
+    }
+
+    #[test]
+    fn replies_round_angles_to_whole_degrees_unless_asked_for_tenths() {
+        let position = ServoPosition { angle: 905, status: Status::Ok as u8 };
+        let joint = ReplyPayload::Joint { index: 2, position };
+        let reply = ReplyPacket::new(JOINT_COMMAND, Status::Ok, joint.clone());
+        assert_eq!(reply.encode(1)[4..], [2, 0, 91, 0]);
+        let reply = ReplyPacket::new(POSE_TENTHS_COMMAND, Status::Ok, joint);
+        assert_eq!(reply.encode(1)[4..], [2, 0x03, 0x89, 0]);
+        let done = ReplyPayload::MoveDone { id: 7, angles: vec![904, 1799] };
+        assert_eq!(ReplyPacket::new(MOVE_DONE_COMMAND, Status::Ok, done).encode(1)[4..], [0, 7, 2, 0, 90, 0, 180]);
+    }
+
+    #[test]
+    fn reply_opens_with_the_sequence_command_and_status() {
+        let reply = ReplyPacket::new(LIMITS_COMMAND, Status::BadArgument, ReplyPayload::Index(7));
+        assert_eq!(reply.encode(0xABCD), [0xAB, 0xCD, LIMITS_COMMAND, Status::BadArgument as u8, 7]);
+        let reply = ReplyPacket::new(ESTOP_COMMAND, Status::Ok, ReplyPayload::Empty);
+        assert_eq!(reply.encode(1), [0, 1, ESTOP_COMMAND, 0]);
+    }
+
+    // Sequence, command bytes and CRC, as a client sends them
+    fn datagram(bytes: &[u8]) -> Vec<u8> {
+        let mut datagram = vec![0x12, 0x34];
+        datagram.extend_from_slice(bytes);
+        append_crc(&mut datagram);
+        datagram
+    }
+
+    // Stripped and decoded the way the network task takes a datagram in
+    fn receive(datagram: &[u8]) -> Option<Result<ControlPacket, DecodeError>> {
+        let (_, bytes) = split_header(verify_crc(datagram)?)?;
+        Some(ControlPacket::decode(bytes))
+    }
+
+    #[test]
+    fn empty_datagrams_are_refused() {
+        assert_eq!(verify_crc(&[]), None);
+        assert_eq!(split_header(&[]), None);
+        assert_eq!(split_header(&[0x12]), None);
+        assert_eq!(ControlPacket::decode(&[]), Err(DecodeError::BadLength));
+        // A lone CRC, then a header with no command after it
+        assert_eq!(receive(&datagram(&[])[2..]), None);
+        assert_eq!(receive(&datagram(&[])), Some(Err(DecodeError::BadLength)));
+    }
+
+    #[test]
+    fn truncated_and_oversized_datagrams_are_refused() {
+        let bytes = frame(MOVE_COMMAND, &[&[5u8][..], &be(&DEGREES)].concat());
+        assert_eq!(receive(&datagram(&bytes)), Some(Ok(ControlPacket::SetAngles(TENTHS.to_vec()))));
+        for length in 0..bytes.len() {
+            assert_eq!(receive(&datagram(&bytes[..length])), Some(Err(DecodeError::BadLength)), "{length}");
+        }
+        let oversized = [&bytes[..], &[0; MAX_COMMAND_SIZE]].concat();
+        assert_eq!(receive(&datagram(&oversized)), Some(Err(DecodeError::BadLength)));
+        assert_eq!(DecodeError::BadLength.status(), Status::BadLength);
+    }
+
+    #[test]
+    fn decode_never_panics_on_any_length() {
+        let variable = [
+            PRESET_SAVE_COMMAND,
+            LOG_LEVEL_COMMAND,
+            LOG_TARGET_COMMAND,
+            OTA_COMMAND,
+            TEACH_COMMAND,
+            SELF_TEST_COMMAND,
+            LOOP_TIMING_COMMAND,
+            POSE_COMMAND,
+            HISTORY_COMMAND,
+            CURRENT_COMMAND,
+            SLEEP_COMMAND,
+        ];
+        let commands: Vec<u8> = exact_frames().into_iter().map(|(bytes, _)| bytes[0]).chain(variable).collect();
+        for command in commands {
+            for fill in [0x00, 0x01, 0xFF] {
+                for length in 0..=MAX_COMMAND_SIZE {
+                    let _ = ControlPacket::decode(&frame(command, &vec![fill; length]));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn crc8_matches_the_smbus_check_value() {
+        assert_eq!(crc8(b"123456789"), 0xF4);
+        assert_eq!(crc8(&[]), 0);
+    }
+
+    #[test]
+    fn verify_crc_strips_a_matching_crc() {
+        let mut frame = vec![0x12, 0x34, PING_COMMAND];
+        append_crc(&mut frame);
+        assert_eq!(verify_crc(&frame), Some(&[0x12, 0x34, PING_COMMAND][..]));
+    }
+
+    #[test]
+    fn verify_crc_rejects_any_flipped_bit() {
+        let mut packet = datagram(&frame(LIMITS_COMMAND, &[2, 0, 90, 0, 60]));
+        for bit in 0..packet.len() * 8 {
+            packet[bit / 8] ^= 1 << (bit % 8);
+            assert_eq!(verify_crc(&packet), None, "bit {bit}");
+            packet[bit / 8] ^= 1 << (bit % 8);
+        }
+        assert!(verify_crc(&packet).is_some());
+    }
+
+    #[test]
+    fn angles_round_trip_through_encode_and_decode() {
+        let mut bytes = Vec::new();
+        encode_angles(TENTHS.map(servo::to_degrees), &mut bytes);
+        assert_eq!(bytes, be(&DEGREES));
+        assert_eq!(decode_angles(&bytes), TENTHS);
+        assert_eq!(decode_tenths(&be(&[905, 1799])), [905, 1799]);
+    }
+
+    #[test]
+    fn decode_angles_ignores_a_trailing_odd_byte() {
+        assert_eq!(decode_angles(&[0, 90, 0]), [900]);
+        assert_eq!(decode_tenths(&[0x03, 0x89, 0]), [905]);
+        assert!(decode_angles(&[]).is_empty());
+    }
+
+    #[test]
+    fn legacy_positions_are_sent_bare() {
+        let reply = ReplyPacket::new(MOVE_COMMAND, Status::Ok, ReplyPayload::LegacyPositions(vec![900, 3000]));
+        assert!(reply.is_legacy());
+        assert_eq!(reply.encode(0x1234), [90, 0, 44, 1]);
+        assert!(!ReplyPacket::new(MOVE_COMMAND, Status::Ok, ReplyPayload::Empty).is_legacy());
+    }
+}
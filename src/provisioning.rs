@@ -0,0 +1,124 @@
+// Setup portal: an access point beside the station serving a form for the network credentials, until the station
+// joins. The HTTP API only comes up after that, so the two never want port 80 at once
+use std::sync::mpsc::{self, Receiver};
+
+use embedded_svc::http::Method;
+use embedded_svc::io::Write;
+use esp_idf_hal::delay::FreeRtos;
+use esp_idf_svc::http::server::{Configuration as HttpConfiguration, EspHttpServer};
+use esp_idf_sys::EspError;
+use log::{error, info, warn};
+
+use crate::auth;
+use crate::settings::{Settings, WifiCredentials, MAX_PSK_SIZE, MAX_SSID_SIZE};
+
+const MAX_FORM_SIZE: usize = 768; // Every field fully percent-encoded fits with room to spare
+
+const FORM_PAGE: &str = "<!DOCTYPE html><html><head><meta name=\"viewport\" content=\"width=device-width\">\
+<title>Limb setup</title></head><body><h1>Limb setup</h1><form method=\"post\" action=\"/\">\
+<p><label>Network <input name=\"ssid\" maxlength=\"32\" required></label></p>\
+<p><label>Password <input name=\"psk\" type=\"password\" maxlength=\"64\"></label></p>\
+<p><label>Control key <input name=\"key\" type=\"password\" maxlength=\"64\"></label> (optional)</p>\
+<p><button type=\"submit\">Save and restart</button></p></form></body></html>";
+const SAVED_PAGE: &str = "<!DOCTYPE html><html><body><h1>Saved</h1><p>The limb is restarting and will join the network.</p></body></html>";
+const INVALID_PAGE: &str = "<!DOCTYPE html><html><body><h1>Invalid</h1><p>The network name must be 1 to 32 bytes and \
+the password empty or 8 to 64 bytes, the control key at most 64. <a href=\"/\">Try again</a></p></body></html>";
+
+// Serves the form on port 80 for as long as the server is held. Submissions come back on the receiver to be saved,
+// the handlers run on the server's task
+pub fn serve() -> Result<(EspHttpServer<'static>, Receiver<Submission>), EspError> {
+    let (sender, submissions) = mpsc::sync_channel::<Submission>(1);
+    let mut server = EspHttpServer::new(&HttpConfiguration::default()).map_err(|e| e.0)?;
+    server
+        .fn_handler("/", Method::Get, |request| {
+            request.into_ok_response()?.write_all(FORM_PAGE.as_bytes())?;
+            Ok(())
+        })?
+        .fn_handler("/", Method::Post, move |mut request| {
+            let mut body = [0u8; MAX_FORM_SIZE];
+            let mut len = 0;
+            while len < body.len() {
+                match request.read(&mut body[len..])? {
+                    0 => break,
+                    read => len += read,
+                }
+            }
+            match parse_form(&body[..len]) {
+                Some(submission) => {
+                    request.into_ok_response()?.write_all(SAVED_PAGE.as_bytes())?;
+                    let _ = sender.try_send(submission); // A second submission while the first is saved is dropped
+                }
+                None => {
+                    warn!("Setup portal rejected a submission");
+                    request.into_status_response(400)?.write_all(INVALID_PAGE.as_bytes())?;
+                }
+            }
+            Ok(())
+        })?;
+    Ok((server, submissions))
+}
+
+// Stores the credentials and restarts to join with them. Only returns if they couldn't be stored
+pub fn save(settings: &mut Settings, submission: Submission) {
+    let Submission { credentials, key } = submission;
+    // An empty key field keeps whatever key is stored
+    let saved = if key.is_empty() {
+        settings.save_wifi(&credentials)
+    } else {
+        settings.save_auth_key(key.as_bytes()).and_then(|_| settings.save_wifi(&credentials))
+    };
+    match saved {
+        Ok(_) => {
+            info!("WiFi credentials for {} saved, restarting", credentials.ssid);
+            FreeRtos::delay_ms(1000); // Lets the confirmation page reach the browser
+            esp_idf_hal::reset::restart();
+            unreachable!("Restart returned");
+        }
+        Err(e) => error!("Failed to save WiFi credentials: {}", e),
+    }
+}
+
+pub struct Submission {
+    credentials: WifiCredentials,
+    key: String, // Command authentication key, empty when the field was left blank
+}
+
+// Reads the URL-encoded ssid, psk and key fields, None if the ssid is missing or any is out of bounds
+fn parse_form(body: &[u8]) -> Option<Submission> {
+    let body = std::str::from_utf8(body).ok()?;
+    let mut ssid = None;
+    let mut psk = None;
+    let mut key = None;
+    for field in body.split('&') {
+        let (name, value) = field.split_once('=')?;
+        match name {
+            "ssid" => ssid = Some(url_decode(value)?),
+            "psk" => psk = Some(url_decode(value)?),
+            "key" => key = Some(url_decode(value)?),
+            _ => {}
+        }
+    }
+    let (ssid, psk, key) = (ssid?, psk.unwrap_or_default(), key.unwrap_or_default());
+    let psk_valid = psk.is_empty() || (8..=MAX_PSK_SIZE).contains(&psk.len()); // WPA2 needs at least 8
+    if ssid.is_empty() || ssid.len() > MAX_SSID_SIZE || !psk_valid || key.len() > auth::MAX_KEY_SIZE {
+        return None;
+    }
+    Some(Submission { credentials: WifiCredentials { ssid, psk }, key })
+}
+
+// Form encoding: '+' for spaces and %XX for everything else outside the unreserved set
+fn url_decode(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut input = value.bytes();
+    while let Some(byte) = input.next() {
+        bytes.push(match byte {
+            b'+' => b' ',
+            b'%' => {
+                let hex = [input.next()?, input.next()?];
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            byte => byte,
+        });
+    }
+    String::from_utf8(bytes).ok()
+}
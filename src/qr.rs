@@ -0,0 +1,331 @@
+// Minimal QR code encoder for the pairing page, byte mode at level L in versions 1 to 3 (up to 53 bytes)
+const VERSIONS: [(usize, usize); 3] = [(19, 7), (34, 10), (55, 15)]; // Data and error correction codewords
+const FORMAT_LEVEL_L: u32 = 0b01;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QrCode {
+    size: usize,
+    modules: Vec<bool>, // Row by row, true is dark
+}
+
+impl QrCode {
+    // The smallest version that fits, None if the data is too long for version 3
+    pub fn encode(data: &[u8]) -> Option<QrCode> {
+        let (version, &(data_codewords, ec_codewords)) = VERSIONS
+            .iter()
+            .enumerate()
+            .find(|(_, (data_codewords, _))| data.len() + 2 <= *data_codewords)?;
+        let mut codewords = data_codewords_for(data, data_codewords);
+        let ec = reed_solomon_remainder(&codewords, &reed_solomon_divisor(ec_codewords));
+        codewords.extend(ec);
+
+        let mut matrix = Matrix::new(version + 1);
+        matrix.draw_function_patterns();
+        matrix.draw_codewords(&codewords);
+        // Any mask scans, the one scoring the fewest penalty points is easiest on the reader
+        let mask = (0..8)
+            .min_by_key(|&mask| {
+                let mut masked = matrix.clone();
+                masked.apply_mask(mask);
+                masked.draw_format_bits(mask);
+                masked.penalty()
+            })
+            .unwrap_or(0);
+        matrix.apply_mask(mask);
+        matrix.draw_format_bits(mask);
+        Some(QrCode { size: matrix.size, modules: matrix.modules })
+    }
+
+    // Modules across, without the quiet zone
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    #[cfg_attr(feature = "sim", allow(dead_code))] // The simulator only logs the size
+    pub fn is_dark(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.size + x]
+    }
+}
+
+// Byte mode header, the data, then the terminator and padding up to the version's capacity
+fn data_codewords_for(data: &[u8], capacity: usize) -> Vec<u8> {
+    let mut bits: Vec<bool> = Vec::with_capacity(capacity * 8);
+    let mut push = |value: u32, count: u32| {
+        for i in (0..count).rev() {
+            bits.push((value >> i) & 1 != 0);
+        }
+    };
+    push(0b0100, 4);
+    push(data.len() as u32, 8);
+    for &byte in data {
+        push(byte as u32, 8);
+    }
+    let terminator = (capacity * 8 - bits.len()).min(4);
+    bits.extend((0..terminator).map(|_| false));
+    let partial = (8 - bits.len() % 8) % 8;
+    bits.extend((0..partial).map(|_| false));
+    let mut codewords: Vec<u8> = bits
+        .chunks(8)
+        .map(|byte| byte.iter().fold(0u8, |value, &bit| (value << 1) | bit as u8))
+        .collect();
+    for pad in [0xEC, 0x11].into_iter().cycle() {
+        if codewords.len() >= capacity {
+            break;
+        }
+        codewords.push(pad);
+    }
+    codewords
+}
+
+// Product in GF(2^8) modulo the QR polynomial x^8 + x^4 + x^3 + x^2 + 1
+fn gf_multiply(x: u8, y: u8) -> u8 {
+    let mut z: u16 = 0;
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x11D);
+        z ^= ((y >> i) & 1) as u16 * x as u16;
+    }
+    z as u8
+}
+
+// Generator polynomial coefficients, highest power first with the leading 1 left out
+fn reed_solomon_divisor(degree: usize) -> Vec<u8> {
+    let mut divisor = vec![0u8; degree];
+    divisor[degree - 1] = 1;
+    let mut root = 1u8;
+    for _ in 0..degree {
+        for j in 0..degree {
+            divisor[j] = gf_multiply(divisor[j], root);
+            if j + 1 < degree {
+                divisor[j] ^= divisor[j + 1];
+            }
+        }
+        root = gf_multiply(root, 0x02);
+    }
+    divisor
+}
+
+fn reed_solomon_remainder(data: &[u8], divisor: &[u8]) -> Vec<u8> {
+    let mut remainder = vec![0u8; divisor.len()];
+    for &byte in data {
+        let factor = byte ^ remainder.remove(0);
+        remainder.push(0);
+        for (value, &coefficient) in remainder.iter_mut().zip(divisor) {
+            *value ^= gf_multiply(coefficient, factor);
+        }
+    }
+    remainder
+}
+
+#[derive(Clone)]
+struct Matrix {
+    size: usize,
+    modules: Vec<bool>,
+    function: Vec<bool>, // Finder, timing, alignment and format modules, left alone by the data and mask
+}
+
+impl Matrix {
+    fn new(version: usize) -> Matrix {
+        let size = 17 + 4 * version;
+        Matrix { size, modules: vec![false; size * size], function: vec![false; size * size] }
+    }
+
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        self.modules[y * self.size + x] = dark;
+        self.function[y * self.size + x] = true;
+    }
+
+    fn draw_function_patterns(&mut self) {
+        for i in 0..self.size {
+            self.set_function(6, i, i % 2 == 0);
+            self.set_function(i, 6, i % 2 == 0);
+        }
+        let far = self.size - 4;
+        for (x, y) in [(3, 3), (far, 3), (3, far)] {
+            self.draw_finder(x, y);
+        }
+        // Versions 2 and 3 only have the one alignment pattern clear of the finders
+        if self.size > 21 {
+            let center = self.size - 7;
+            for dy in 0..5 {
+                for dx in 0..5 {
+                    let distance = (dx as i32 - 2).abs().max((dy as i32 - 2).abs());
+                    self.set_function(center + dx - 2, center + dy - 2, distance != 1);
+                }
+            }
+        }
+        self.draw_format_bits(0); // Reserves the format areas, redrawn once the mask is picked
+    }
+
+    // The 7x7 finder with its light separator, clipped at the edges
+    fn draw_finder(&mut self, center_x: usize, center_y: usize) {
+        for dy in -4i32..=4 {
+            for dx in -4i32..=4 {
+                let (x, y) = (center_x as i32 + dx, center_y as i32 + dy);
+                if (0..self.size as i32).contains(&x) && (0..self.size as i32).contains(&y) {
+                    let distance = dx.abs().max(dy.abs());
+                    self.set_function(x as usize, y as usize, distance != 2 && distance != 4);
+                }
+            }
+        }
+    }
+
+    // Level and mask with their BCH check bits, once beside the top left finder and split across the other two
+    fn draw_format_bits(&mut self, mask: u32) {
+        let data = (FORMAT_LEVEL_L << 3) | mask;
+        let mut remainder = data;
+        for _ in 0..10 {
+            remainder = (remainder << 1) ^ ((remainder >> 9) * 0x537);
+        }
+        let bits = ((data << 10) | remainder) ^ 0x5412;
+        let bit = |i: usize| (bits >> i) & 1 != 0;
+        for i in 0..6 {
+            self.set_function(8, i, bit(i));
+        }
+        self.set_function(8, 7, bit(6));
+        self.set_function(8, 8, bit(7));
+        self.set_function(7, 8, bit(8));
+        for i in 9..15 {
+            self.set_function(14 - i, 8, bit(i));
+        }
+        for i in 0..8 {
+            self.set_function(self.size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set_function(8, self.size - 15 + i, bit(i));
+        }
+        self.set_function(8, self.size - 8, true); // Always dark
+    }
+
+    // Zigzags up and down two columns at a time from the bottom right, skipping the vertical timing column.
+    // Remainder bits past the codewords stay light
+    fn draw_codewords(&mut self, codewords: &[u8]) {
+        let total_bits = codewords.len() * 8;
+        let mut bit = 0;
+        let mut right = self.size as i32 - 1;
+        while right >= 1 {
+            if right == 6 {
+                right = 5;
+            }
+            let upward = (right + 1) & 2 == 0;
+            for vertical in 0..self.size {
+                for column in 0..2 {
+                    let x = (right - column) as usize;
+                    let y = if upward { self.size - 1 - vertical } else { vertical };
+                    if !self.function[y * self.size + x] && bit < total_bits {
+                        self.modules[y * self.size + x] = (codewords[bit / 8] >> (7 - bit % 8)) & 1 != 0;
+                        bit += 1;
+                    }
+                }
+            }
+            right -= 2;
+        }
+    }
+
+    fn apply_mask(&mut self, mask: u32) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                let invert = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+                if invert && !self.function[y * self.size + x] {
+                    self.modules[y * self.size + x] ^= true;
+                }
+            }
+        }
+    }
+
+    // The standard's four penalty rules: long runs, 2x2 blocks, finder-like patterns and dark/light imbalance
+    fn penalty(&self) -> u32 {
+        let dark = |x: usize, y: usize| self.modules[y * self.size + x];
+        let mut penalty: u32 = 0;
+        for transpose in [false, true] {
+            for a in 0..self.size {
+                let line: Vec<bool> =
+                    (0..self.size).map(|b| if transpose { dark(a, b) } else { dark(b, a) }).collect();
+                let mut run = 1;
+                for b in 1..=self.size {
+                    if b < self.size && line[b] == line[b - 1] {
+                        run += 1;
+                        continue;
+                    }
+                    if run >= 5 {
+                        penalty += run as u32 - 2;
+                    }
+                    run = 1;
+                }
+                for window in line.windows(11) {
+                    const FINDER_BEFORE: [bool; 11] =
+                        [true, false, true, true, true, false, true, false, false, false, false];
+                    const FINDER_AFTER: [bool; 11] =
+                        [false, false, false, false, true, false, true, true, true, false, true];
+                    if window == FINDER_BEFORE || window == FINDER_AFTER {
+                        penalty += 40;
+                    }
+                }
+            }
+        }
+        for y in 0..self.size - 1 {
+            for x in 0..self.size - 1 {
+                let color = dark(x, y);
+                if color == dark(x + 1, y) && color == dark(x, y + 1) && color == dark(x + 1, y + 1) {
+                    penalty += 3;
+                }
+            }
+        }
+        let total = (self.size * self.size) as u32;
+        let dark_count = self.modules.iter().filter(|&&module| module).count() as u32;
+        let deviation = (dark_count * 20).abs_diff(total * 10);
+        penalty + deviation.saturating_sub(1) / total * 10 // Every full 5% off an even split
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_picks_the_smallest_version_that_fits() {
+        assert_eq!(QrCode::encode(&[b'a'; 17]).unwrap().size(), 21);
+        assert_eq!(QrCode::encode(&[b'a'; 18]).unwrap().size(), 25);
+        assert_eq!(QrCode::encode(&[b'a'; 53]).unwrap().size(), 29);
+        assert_eq!(QrCode::encode(&[b'a'; 54]), None);
+    }
+
+    #[test]
+    fn data_codewords_are_headed_terminated_and_padded() {
+        assert_eq!(data_codewords_for(b"hi", 8), vec![0x40, 0x26, 0x86, 0x90, 0xEC, 0x11, 0xEC, 0x11]);
+    }
+
+    // The worked 1-M "HELLO WORLD" example from the QR specification's tutorials
+    #[test]
+    fn error_correction_matches_the_reference_example() {
+        let data = [32, 91, 11, 120, 209, 114, 220, 77, 67, 64, 236, 17, 236, 17, 236, 17];
+        assert_eq!(
+            reed_solomon_remainder(&data, &reed_solomon_divisor(10)),
+            vec![196, 35, 39, 119, 235, 215, 231, 226, 93, 23]
+        );
+    }
+
+    #[test]
+    fn finders_and_timing_are_drawn() {
+        let code = QrCode::encode(b"192.168.4.1").unwrap();
+        let last = code.size() - 1;
+        for (x, y) in [(0, 0), (last, 0), (0, last)] {
+            assert!(code.is_dark(x, y));
+        }
+        assert!(code.is_dark(3, 3));
+        assert!(!code.is_dark(1, 1));
+        assert!(!code.is_dark(7, 7), "separator");
+        for i in 8..code.size() - 8 {
+            assert_eq!(code.is_dark(i, 6), i % 2 == 0);
+            assert_eq!(code.is_dark(6, i), i % 2 == 0);
+        }
+    }
+}
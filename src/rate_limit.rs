@@ -0,0 +1,94 @@
+// Per-client token buckets for motion commands, refilled at the configured rate so a flooding client is throttled
+// without holding up any other
+use std::net::SocketAddr;
+use std::time::Instant;
+
+const MAX_CLIENTS: usize = 8; // Clients tracked at once, the oldest is forgotten past this
+
+struct Bucket {
+    tokens: f32,
+    refilled: Instant,
+}
+
+pub struct RateLimiter {
+    rate_hz: f32, // 0 never throttles
+    burst: f32,
+    clients: Vec<(SocketAddr, Bucket)>,
+}
+
+impl RateLimiter {
+    pub fn new(rate_hz: u16, burst: u16) -> RateLimiter {
+        RateLimiter {
+            rate_hz: rate_hz as f32,
+            burst: burst.max(1) as f32,
+            clients: Vec::with_capacity(MAX_CLIENTS),
+        }
+    }
+
+    // Takes a token from the client's bucket, false if it's empty. A new client starts with a full one
+    pub fn allow(&mut self, addr: SocketAddr, now: Instant) -> bool {
+        if self.rate_hz == 0.0 {
+            return true;
+        }
+        let bucket = match self.clients.iter().position(|(client, _)| *client == addr) {
+            Some(index) => &mut self.clients[index].1,
+            None => {
+                if self.clients.len() >= MAX_CLIENTS {
+                    self.clients.remove(0);
+                }
+                self.clients.push((addr, Bucket { tokens: self.burst, refilled: now }));
+                &mut self.clients.last_mut().expect("client was just added").1
+            }
+        };
+        let elapsed_s = now.saturating_duration_since(bucket.refilled).as_secs_f32();
+        bucket.tokens = (bucket.tokens + elapsed_s * self.rate_hz).min(self.burst);
+        bucket.refilled = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn client(port: u16) -> SocketAddr {
+        SocketAddr::from(([192, 168, 1, 20], port))
+    }
+
+    #[test]
+    fn a_burst_is_allowed_then_the_rate() {
+        let mut limiter = RateLimiter::new(100, 5);
+        let start = Instant::now();
+        assert!((0..5).all(|_| limiter.allow(client(1), start)));
+        assert!(!limiter.allow(client(1), start));
+        // Another client has a bucket of its own
+        assert!(limiter.allow(client(2), start));
+        assert!(limiter.allow(client(1), start + Duration::from_millis(10)));
+        assert!(!limiter.allow(client(1), start + Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn a_zero_rate_never_throttles() {
+        let mut limiter = RateLimiter::new(0, 1);
+        let now = Instant::now();
+        assert!((0..100).all(|_| limiter.allow(client(1), now)));
+    }
+
+    #[test]
+    fn the_oldest_client_is_forgotten_past_the_limit() {
+        let mut limiter = RateLimiter::new(1, 1);
+        let now = Instant::now();
+        assert!(limiter.allow(client(0), now));
+        assert!(!limiter.allow(client(0), now));
+        for port in 1..=MAX_CLIENTS as u16 {
+            assert!(limiter.allow(client(port), now));
+        }
+        // Back with a full bucket
+        assert!(limiter.allow(client(0), now));
+    }
+}
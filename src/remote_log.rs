@@ -0,0 +1,333 @@
+// Tees log records to the console and to a UDP collector, dropping them rather than waiting while its queue is full.
+// The console has a default level and per-target ones, each covering the target's submodules
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+use log::{error, info, warn, LevelFilter, Log, Metadata, Record};
+
+use crate::tasks;
+
+const QUEUE_SIZE: usize = 32; // Records waiting for the log task, more are dropped
+const MAX_RECORD_SIZE: usize = 512; // Bytes of a datagram, longer messages are cut short
+pub const MAX_TARGET_SIZE: usize = 32; // Bytes of a target given its own console level
+pub const MAX_TARGETS: usize = 8;
+pub const MAX_LEVELS_SIZE: usize = 3 + MAX_TARGETS * (2 + MAX_TARGET_SIZE);
+const LEVELS_VERSION: u8 = 1; // Bump when the stored levels layout changes
+
+// Index is the level byte of the log level command
+const LEVELS: [LevelFilter; 6] = [
+    LevelFilter::Off,
+    LevelFilter::Error,
+    LevelFilter::Warn,
+    LevelFilter::Info,
+    LevelFilter::Debug,
+    LevelFilter::Trace,
+];
+
+static CONSOLE_LEVELS: Mutex<LogLevels> = Mutex::new(LogLevels { default: LevelFilter::Info, targets: Vec::new() });
+static CONSOLE: OnceLock<&'static (dyn Console + Sync)> = OnceLock::new();
+static FORWARD_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Info as usize);
+static RECORDS: OnceLock<SyncSender<String>> = OnceLock::new(); // Set once the log task is running
+static DROPPED: AtomicBool = AtomicBool::new(false); // A record was lost since the last one sent
+
+// A logger whose own filtering, if it has any, is kept in line with the console levels set here
+pub trait Console: Log {
+    fn apply_level(&self, target: Option<&str>, level: LevelFilter); // None for the default
+}
+
+// Console levels, stored in NVS when a log target command asks for it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogLevels {
+    pub default: LevelFilter,
+    pub targets: Vec<(String, LevelFilter)>,
+}
+
+impl LogLevels {
+    // The level of the longest target that is the record's or one of its parent modules, otherwise the default
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .filter(|(prefix, _)| {
+                target.strip_prefix(prefix.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.default, |&(_, level)| level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.targets.iter().map(|&(_, level)| level).fold(self.default, Ord::max)
+    }
+
+    // Layout: version, default level, target count, then each target's level, length and name
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![LEVELS_VERSION, self.default as u8, self.targets.len() as u8];
+        for (target, level) in &self.targets {
+            bytes.push(*level as u8);
+            bytes.push(target.len() as u8);
+            bytes.extend_from_slice(target.as_bytes());
+        }
+        bytes
+    }
+
+    // Returns None for blobs of the wrong version, unknown levels or lengths that don't match their size
+    pub fn from_bytes(bytes: &[u8]) -> Option<LogLevels> {
+        let (&version, rest) = bytes.split_first()?;
+        let (&default, rest) = rest.split_first()?;
+        let (&count, mut rest) = rest.split_first()?;
+        if version != LEVELS_VERSION || count as usize > MAX_TARGETS {
+            return None;
+        }
+        let mut targets = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let (&level, after) = rest.split_first()?;
+            let (&len, after) = after.split_first()?;
+            if len as usize > MAX_TARGET_SIZE || after.len() < len as usize {
+                return None;
+            }
+            let (target, after) = after.split_at(len as usize);
+            targets.push((String::from_utf8(target.to_vec()).ok()?, level_from_byte(level)?));
+            rest = after;
+        }
+        if !rest.is_empty() {
+            return None;
+        }
+        Some(LogLevels { default: level_from_byte(default)?, targets })
+    }
+}
+
+pub struct RemoteLog<L: Console + Sync + 'static> {
+    console: L,
+}
+
+impl<L: Console + Sync + 'static> RemoteLog<L> {
+    pub const fn new(console: L) -> RemoteLog<L> {
+        RemoteLog { console }
+    }
+}
+
+impl<L: Console + Sync + 'static> Log for RemoteLog<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        console_enabled(metadata) || (RECORDS.get().is_some() && metadata.level() <= forward_level())
+    }
+
+    fn log(&self, record: &Record) {
+        if console_enabled(record.metadata()) && self.console.enabled(record.metadata()) {
+            self.console.log(record);
+        }
+        // Nothing in here may log, the record would come straight back
+        if let Some(records) = RECORDS.get() {
+            if record.level() <= forward_level() {
+                let mut line = format!("{} {}: {}", record.level(), record.target(), record.args());
+                truncate(&mut line, MAX_RECORD_SIZE);
+                if records.try_send(line).is_err() {
+                    DROPPED.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    fn flush(&self) {
+        self.console.flush();
+    }
+}
+
+// Installs the logger, records at or below `console_level` go to the console until levels are set
+pub fn install<L: Console + Sync + 'static>(logger: &'static RemoteLog<L>, console_level: LevelFilter) {
+    console_levels_mut().default = console_level;
+    let _ = CONSOLE.set(&logger.console);
+    match log::set_logger(logger) {
+        Ok(_) => update_max_level(),
+        Err(e) => eprintln!("Failed to set logger: {}", e),
+    }
+}
+
+// Starts forwarding to the "ip:port" collector from the config at the given level, an empty address leaves
+// forwarding off
+pub fn start(collector: &str, level: &str) {
+    if collector.is_empty() {
+        return;
+    }
+    let collector: SocketAddr = match collector.parse() {
+        Ok(collector) => collector,
+        Err(e) => {
+            error!("Log collector {:?} is not an ip:port, not forwarding logs: {}", collector, e);
+            return;
+        }
+    };
+    let level = match level.parse::<LevelFilter>() {
+        Ok(level) => level,
+        Err(_) => {
+            warn!("Unknown log forward level {:?}, forwarding info", level);
+            LevelFilter::Info
+        }
+    };
+    let socket = match UdpSocket::bind("0.0.0.0:0") {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("Failed to bind the log socket, not forwarding logs: {}", e);
+            return;
+        }
+    };
+    let (sender, records) = mpsc::sync_channel(QUEUE_SIZE);
+    FORWARD_LEVEL.store(level as usize, Ordering::Relaxed);
+    tasks::spawn(&tasks::LOG_TASK, move || log_task(socket, collector, records));
+    match RECORDS.set(sender) {
+        Ok(_) => {
+            update_max_level();
+            info!("Forwarding {} logs to {}", level, collector);
+        }
+        Err(_) => error!("Log forwarding already started"),
+    }
+}
+
+// Level byte of the log level command, None past trace
+pub fn level_from_byte(byte: u8) -> Option<LevelFilter> {
+    LEVELS.get(byte as usize).copied()
+}
+
+pub fn forward_level() -> LevelFilter {
+    LEVELS[FORWARD_LEVEL.load(Ordering::Relaxed)]
+}
+
+// Kept until the next restart, which goes back to the configured level
+pub fn set_forward_level(level: LevelFilter) {
+    FORWARD_LEVEL.store(level as usize, Ordering::Relaxed);
+    update_max_level();
+}
+
+pub fn is_forwarding() -> bool {
+    RECORDS.get().is_some()
+}
+
+pub fn console_levels() -> LogLevels {
+    console_levels_mut().clone()
+}
+
+// Sets the console level of a target, or the default for an empty one. None drops a target's own level so it follows
+// the default again. Err if the target is too long or a new one won't fit
+pub fn set_console_level(target: &str, level: Option<LevelFilter>) -> Result<LevelFilter, ()> {
+    if target.len() > MAX_TARGET_SIZE {
+        return Err(());
+    }
+    let mut levels = console_levels_mut();
+    let existing = levels.targets.iter().position(|(name, _)| name == target);
+    match (target.is_empty(), level, existing) {
+        (true, Some(level), _) => levels.default = level,
+        (true, None, _) => return Err(()),
+        (false, Some(level), Some(index)) => levels.targets[index].1 = level,
+        (false, Some(_), None) if levels.targets.len() >= MAX_TARGETS => return Err(()),
+        (false, Some(level), None) => levels.targets.push((target.to_string(), level)),
+        (false, None, Some(index)) => {
+            levels.targets.remove(index);
+        }
+        (false, None, None) => {},
+    }
+    let applied = if target.is_empty() { levels.default } else { levels.level_for(target) };
+    drop(levels);
+    if let Some(console) = CONSOLE.get() {
+        console.apply_level(Some(target).filter(|target| !target.is_empty()), applied);
+    }
+    update_max_level();
+    Ok(applied)
+}
+
+// Replaces every console level, like the ones stored in NVS
+pub fn set_console_levels(levels: LogLevels) {
+    if let Some(console) = CONSOLE.get() {
+        console.apply_level(None, levels.default);
+        for (target, level) in &levels.targets {
+            console.apply_level(Some(target), *level);
+        }
+    }
+    *console_levels_mut() = levels;
+    update_max_level();
+}
+
+// Nothing may log while this is held, the logger takes it too
+fn console_levels_mut() -> MutexGuard<'static, LogLevels> {
+    CONSOLE_LEVELS.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn console_enabled(metadata: &Metadata) -> bool {
+    metadata.level() <= console_levels_mut().level_for(metadata.target())
+}
+
+// The log macros skip records above the max level before they reach the logger, so it has to cover both outputs
+fn update_max_level() {
+    let console = console_levels_mut().max_level();
+    let forward = if is_forwarding() { forward_level() } else { LevelFilter::Off };
+    log::set_max_level(console.max(forward));
+}
+
+// Cuts at a character boundary at or below `size` bytes
+fn truncate(line: &mut String, size: usize) {
+    if line.len() > size {
+        let mut end = size;
+        while !line.is_char_boundary(end) {
+            end -= 1;
+        }
+        line.truncate(end);
+    }
+}
+
+// Failures only reach the console, a forwarded one would be sent to the collector that just failed. Each streak of
+// failures is reported once
+fn log_task(socket: UdpSocket, collector: SocketAddr, records: Receiver<String>) {
+    let mut failing = false;
+    for record in records {
+        if DROPPED.swap(false, Ordering::Relaxed) {
+            let _ = socket.send_to(b"WARN remote_log: records dropped, the queue was full", collector);
+        }
+        match socket.send_to(record.as_bytes(), collector) {
+            Ok(_) => failing = false,
+            Err(e) if !failing => {
+                failing = true;
+                eprintln!("Failed to send a log record to {}: {}", collector, e);
+            }
+            Err(_) => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn levels() -> LogLevels {
+        LogLevels {
+            default: LevelFilter::Warn,
+            targets: vec![
+                ("app::servo".to_string(), LevelFilter::Debug),
+                ("app::servo::pwm".to_string(), LevelFilter::Off),
+            ],
+        }
+    }
+
+    #[test]
+    fn the_longest_matching_target_sets_the_level() {
+        let levels = levels();
+        assert_eq!(levels.level_for("app::servo"), LevelFilter::Debug);
+        assert_eq!(levels.level_for("app::servo::tick"), LevelFilter::Debug);
+        assert_eq!(levels.level_for("app::servo::pwm"), LevelFilter::Off);
+        // A target only covers its submodules, not every name it prefixes
+        assert_eq!(levels.level_for("app::servos"), LevelFilter::Warn);
+        assert_eq!(levels.level_for("app"), LevelFilter::Warn);
+        assert_eq!(levels.max_level(), LevelFilter::Debug);
+    }
+
+    #[test]
+    fn levels_round_trip_and_refuse_bad_blobs() {
+        let bytes = levels().to_bytes();
+        assert_eq!(LogLevels::from_bytes(&bytes), Some(levels()));
+        assert_eq!(LogLevels::from_bytes(&bytes[..bytes.len() - 1]), None);
+        assert_eq!(LogLevels::from_bytes(&[&bytes[..], &[0]].concat()), None);
+        let mut unknown = bytes.clone();
+        unknown[1] = LEVELS.len() as u8;
+        assert_eq!(LogLevels::from_bytes(&unknown), None);
+        let mut version = bytes;
+        version[0] = LEVELS_VERSION + 1;
+        assert_eq!(LogLevels::from_bytes(&version), None);
+    }
+}
@@ -0,0 +1,192 @@
+// Wiring check moving one servo at a time a few degrees off where it stands and back. A servo fails if a write to it
+// errors or its feedback didn't follow it out
+use std::time::{Duration, Instant};
+
+use log::error;
+
+use crate::protocol;
+use crate::servo::{self, Servo, ServoError};
+
+pub struct SelfTest {
+    offset: u16, // Tenths of a degree
+    dwell: Duration,
+    joint: usize, // Under test, the servo count once every joint is done
+    step: Step,
+    results: Vec<u8>, // A SELF_TEST_* value per joint
+}
+
+enum Step {
+    Start,
+    Out { origin: u16, measured: Option<u16>, until: Option<Instant> }, // Measured before moving, None without feedback
+    Back { moved: bool, until: Option<Instant> },
+}
+
+impl SelfTest {
+    pub fn new(servo_count: usize, offset_deg: u16, dwell: Duration) -> SelfTest {
+        SelfTest {
+            offset: servo::from_degrees(offset_deg.max(1)),
+            dwell,
+            joint: 0,
+            step: Step::Start,
+            results: vec![protocol::SELF_TEST_PENDING; servo_count],
+        }
+    }
+
+    // Takes the test as far as it can go without waiting, called every pass of the control loop. Returns the joint
+    // when its test starts
+    pub fn step(&mut self, servos: &mut [Servo], now: Instant) -> Option<usize> {
+        let servo = servos.get_mut(self.joint)?;
+        match self.step {
+            Step::Start => {
+                // Outwards, unless that would leave the joint's travel
+                let origin = servo.get_angle();
+                let (min_limit, max_limit) = servo.get_limits();
+                let target = match origin.checked_add(self.offset) {
+                    Some(target) if target <= max_limit => target,
+                    _ => origin.saturating_sub(self.offset).max(min_limit),
+                };
+                let measured = servo.get_measured_angle();
+                match servo.set_angle(target) {
+                    Ok(_) => self.step = Step::Out { origin, measured, until: None },
+                    Err(e) => self.fail(servo.get_name(), e),
+                }
+                Some(self.joint)
+            }
+            _ if servo.is_moving() => None,
+            Step::Out { until: None, .. } | Step::Back { until: None, .. } => {
+                self.hold(now);
+                None
+            }
+            Step::Out { origin, measured, until: Some(until) } if now >= until => {
+                let moved = match (measured, servo.get_measured_angle()) {
+                    (Some(before), Some(after)) => before.abs_diff(after) >= (self.offset / 2).max(1),
+                    _ => true, // Nothing to tell otherwise without feedback
+                };
+                match servo.set_angle(origin) {
+                    Ok(_) => self.step = Step::Back { moved, until: None },
+                    Err(e) => self.fail(servo.get_name(), e),
+                }
+                None
+            }
+            Step::Back { moved, until: Some(until) } if now >= until => {
+                self.results[self.joint] = match moved {
+                    true => protocol::SELF_TEST_PASSED,
+                    false => {
+                        error!("{} didn't move in the self-test", servo.get_name());
+                        protocol::SELF_TEST_NOT_MOVED
+                    }
+                };
+                self.next();
+                None
+            }
+            Step::Out { .. } | Step::Back { .. } => None,
+        }
+    }
+
+    // Leaves every joint not yet passed or failed as skipped
+    pub fn abort(&mut self) {
+        for result in self.results.iter_mut().filter(|result| **result == protocol::SELF_TEST_PENDING) {
+            *result = protocol::SELF_TEST_SKIPPED;
+        }
+        self.joint = self.results.len();
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.joint >= self.results.len()
+    }
+
+    pub fn get_results(&self) -> &[u8] {
+        &self.results
+    }
+
+    // Starts the dwell once the servo has come to rest
+    fn hold(&mut self, now: Instant) {
+        match &mut self.step {
+            Step::Out { until, .. } | Step::Back { until, .. } => *until = Some(now + self.dwell),
+            Step::Start => {}
+        }
+    }
+
+    fn fail(&mut self, name: &str, e: ServoError) {
+        error!("Failed to move {} in the self-test: {}", name, e);
+        self.results[self.joint] = protocol::SELF_TEST_WRITE_FAILED;
+        self.next();
+    }
+
+    fn next(&mut self) {
+        self.joint += 1;
+        self.step = Step::Start;
+    }
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+    use crate::feedback::{Feedback, FeedbackCalibration};
+    use crate::sim;
+
+    const DWELL: Duration = Duration::from_millis(500);
+
+    // Instant servos resting at 90, so each step of the test takes one pass
+    fn servos() -> Vec<Servo> {
+        let (mut servos, _) = sim::mock_servos();
+        for servo in servos.iter_mut() {
+            servo.set_speed(0);
+            servo.set_angle(900).unwrap();
+            servo.poll(false).unwrap();
+        }
+        servos
+    }
+
+    // Steps the test and polls the servos a dwell apart until it finishes, returning each joint's goal on the way out
+    fn run(test: &mut SelfTest, servos: &mut [Servo]) -> Vec<u16> {
+        let mut now = Instant::now();
+        let mut goals = Vec::new();
+        while !test.is_finished() {
+            if let Some(index) = test.step(servos, now) {
+                goals.push(servos[index].get_goal());
+            }
+            for servo in servos.iter_mut() {
+                servo.poll(false).unwrap();
+            }
+            now += DWELL;
+        }
+        goals
+    }
+
+    #[test]
+    fn each_joint_is_moved_out_and_back_in_turn() {
+        let mut servos = servos();
+        servos[1].set_limits(0, 920);
+        let mut test = SelfTest::new(servos.len(), 5, DWELL);
+        let goals = run(&mut test, &mut servos);
+        // Outwards unless that leaves the joint's travel
+        assert_eq!(goals[..2], [950, 850]);
+        assert!(goals[2..].iter().all(|&goal| goal == 950));
+        assert!(servos.iter().all(|servo| servo.get_angle() == 900));
+        assert!(test.get_results().iter().all(|&result| result == protocol::SELF_TEST_PASSED));
+    }
+
+    #[test]
+    fn a_joint_whose_feedback_stays_put_hasnt_moved() {
+        let mut servos = servos();
+        let calibration = FeedbackCalibration { min_mv: 0, max_mv: 1800 };
+        servos[0].set_feedback(Feedback::new(Box::new(|| Ok(900)), calibration));
+        servos[0].poll(false).unwrap();
+        let mut test = SelfTest::new(servos.len(), 5, DWELL);
+        run(&mut test, &mut servos);
+        assert_eq!(test.get_results()[0], protocol::SELF_TEST_NOT_MOVED);
+        assert_eq!(test.get_results()[1], protocol::SELF_TEST_PASSED);
+    }
+
+    #[test]
+    fn abort_skips_every_joint_not_yet_tested() {
+        let mut servos = servos();
+        let mut test = SelfTest::new(servos.len(), 5, DWELL);
+        assert_eq!(test.step(&mut servos, Instant::now()), Some(0));
+        test.abort();
+        assert!(test.is_finished());
+        assert_eq!(test.step(&mut servos, Instant::now()), None);
+        assert!(test.get_results().iter().all(|&result| result == protocol::SELF_TEST_SKIPPED));
+    }
+}
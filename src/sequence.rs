@@ -0,0 +1,46 @@
+use std::net::SocketAddr;
+
+const MAX_CLIENTS: usize = 8; // Clients tracked at once, the oldest is forgotten past this
+
+// True if `sequence` comes after `last`, treating anything up to half the range ahead as newer so the counter can wrap
+pub fn is_newer(sequence: u16, last: u16) -> bool {
+    let ahead = sequence.wrapping_sub(last);
+    ahead != 0 && ahead < 0x8000
+}
+
+// Remembers the last accepted sequence number of each client
+pub struct SequenceTracker {
+    clients: Vec<(SocketAddr, u16)>,
+}
+
+impl SequenceTracker {
+    pub fn new() -> SequenceTracker {
+        SequenceTracker {
+            clients: Vec::with_capacity(MAX_CLIENTS),
+        }
+    }
+
+    // Returns false for duplicates and packets older than the last one accepted from the same client
+    pub fn accept(&mut self, addr: SocketAddr, sequence: u16) -> bool {
+        match self.clients.iter_mut().find(|(client, _)| *client == addr) {
+            Some((_, last)) => {
+                if !is_newer(sequence, *last) {
+                    return false;
+                }
+                *last = sequence;
+            }
+            None => {
+                if self.clients.len() >= MAX_CLIENTS {
+                    self.clients.remove(0);
+                }
+                self.clients.push((addr, sequence));
+            }
+        }
+        true
+    }
+
+    // Forgets a client, so its next packet is accepted whatever its sequence number
+    pub fn reset(&mut self, addr: SocketAddr) {
+        self.clients.retain(|(client, _)| *client != addr);
+    }
+}
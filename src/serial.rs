@@ -0,0 +1,191 @@
+// Serial command link for bench testing over the USB cable, carrying UDP's frames each behind a start byte and its
+// big-endian length. The start byte is never ASCII, so the console's log lines sharing UART0 can be skipped
+use std::sync::mpsc::SyncSender;
+use std::sync::{Arc, Mutex};
+
+use esp_idf_hal::delay::TickType;
+use esp_idf_hal::gpio::AnyIOPin;
+use esp_idf_hal::uart::config::Config as UartConfig;
+use esp_idf_hal::uart::{UartDriver, UART0, UART1, UART2};
+use esp_idf_hal::units::Hertz;
+use esp_idf_sys::EspError;
+use log::{error, info, warn};
+
+use crate::auth::{self, Authenticator};
+use crate::boards;
+use crate::network::{self, Command, NO_ADDR};
+use crate::protocol::{self, ControlPacket, ReplyPacket, ReplyPayload, Status};
+use crate::sequence::SequenceTracker;
+use crate::{tasks, CONFIG};
+
+pub const START_BYTE: u8 = 0xA5;
+const MAX_FRAME_SIZE: usize = protocol::HEADER_SIZE + protocol::MAX_COMMAND_SIZE + auth::AUTH_SIZE + protocol::CRC_SIZE;
+const READ_TIMEOUT_MS: u64 = 100;
+
+// Installs the driver on the configured UART and pins, None if serial commands are off or it couldn't be installed
+pub fn open(uart0: UART0, uart1: UART1, uart2: UART2) -> Option<UartDriver<'static>> {
+    if !CONFIG.serial_commands {
+        return None;
+    }
+    let config = UartConfig::default().baudrate(Hertz(CONFIG.serial_baud));
+    // The pins are whatever the config names, or the board's, like the e-stop button's
+    let (tx_pin, rx_pin) = boards::serial_pins();
+    let tx = unsafe { AnyIOPin::new(tx_pin as i32) };
+    let rx = unsafe { AnyIOPin::new(rx_pin as i32) };
+    let driver: Result<UartDriver<'static>, EspError> = match CONFIG.serial_uart {
+        0 => UartDriver::new(uart0, tx, rx, Option::<AnyIOPin>::None, Option::<AnyIOPin>::None, &config),
+        1 => UartDriver::new(uart1, tx, rx, Option::<AnyIOPin>::None, Option::<AnyIOPin>::None, &config),
+        2 => UartDriver::new(uart2, tx, rx, Option::<AnyIOPin>::None, Option::<AnyIOPin>::None, &config),
+        uart => {
+            error!("There is no UART{}, serial commands are off", uart);
+            return None;
+        }
+    };
+    match driver {
+        Ok(driver) => {
+            info!("Serial commands on UART{} at {} baud", CONFIG.serial_uart, CONFIG.serial_baud);
+            Some(driver)
+        }
+        Err(e) => {
+            error!("Failed to open UART{}, serial commands are off: {}", CONFIG.serial_uart, e);
+            None
+        }
+    }
+}
+
+pub fn start(
+    driver: UartDriver<'static>,
+    commands: SyncSender<Command>,
+    authenticator: Option<Arc<Mutex<Authenticator>>>,
+) {
+    tasks::spawn(&tasks::SERIAL_TASK, move || run(driver, commands, authenticator.as_deref()));
+}
+
+fn run(driver: UartDriver<'static>, commands: SyncSender<Command>, authenticator: Option<&Mutex<Authenticator>>) {
+    let mut deframer = Deframer::new();
+    let mut sequences = SequenceTracker::new();
+    let mut buf = [0u8; MAX_FRAME_SIZE];
+    loop {
+        // The first byte is waited on, then whatever else has come in is taken without waiting
+        let read = match driver.read(&mut buf[..1], TickType::new_millis(READ_TIMEOUT_MS).ticks()) {
+            Ok(0) => {
+                deframer.reset();
+                continue;
+            }
+            Ok(_) => {
+                let available = driver.remaining_read().unwrap_or(0).min(buf.len() - 1);
+                1 + driver.read(&mut buf[1..1 + available], 0).unwrap_or(0)
+            }
+            Err(e) => {
+                warn!("Failed to read the serial port: {}", e);
+                deframer.reset();
+                continue;
+            }
+        };
+        for &byte in &buf[..read] {
+            let Some(frame) = deframer.push(byte) else {
+                continue;
+            };
+            let Some((sequence, reply)) = handle_frame(frame, &commands, authenticator, &mut sequences) else {
+                continue;
+            };
+            let reply = network::encode_reply(sequence, &reply);
+            let mut framed = Vec::with_capacity(3 + reply.len());
+            framed.push(START_BYTE);
+            framed.extend_from_slice(&(reply.len() as u16).to_be_bytes());
+            framed.extend_from_slice(&reply);
+            // Written in one go, so a log line can't land in the middle of it
+            match driver.write(&framed) {
+                Ok(_) => {},
+                Err(e) => warn!("Failed to write a serial reply: {}", e),
+            }
+        }
+    }
+}
+
+// Takes a frame the way the network task takes a datagram, returning the answer and the sequence it goes back under.
+// None for a stale frame, which is dropped unanswered like a stale datagram
+fn handle_frame(
+    frame: &[u8],
+    commands: &SyncSender<Command>,
+    authenticator: Option<&Mutex<Authenticator>>,
+    sequences: &mut SequenceTracker,
+) -> Option<(u16, ReplyPacket)> {
+    let (sequence, control) = match network::decode_frame(frame, authenticator) {
+        Ok(decoded) => decoded,
+        Err(refused) => {
+            warn!("Refused serial command {}: {:?}", refused.command, refused.error);
+            return Some((refused.sequence, ReplyPacket::new(refused.command, refused.status(), ReplyPayload::Empty)));
+        }
+    };
+    if control == ControlPacket::Ping {
+        sequences.reset(NO_ADDR);
+    }
+    let command = control.command();
+    if !sequences.accept(NO_ADDR, sequence) {
+        warn!("Discarding stale serial command {}", sequence);
+        return None;
+    }
+    // Heartbeats go to a UDP port, which the serial client doesn't have
+    if matches!(control, ControlPacket::Subscribe { .. }) {
+        return Some((sequence, ReplyPacket::new(command, Status::BadCommand, ReplyPayload::Empty)));
+    }
+    match network::request(commands, NO_ADDR, control) {
+        Ok(reply) => Some((sequence, reply)),
+        Err(e) => {
+            warn!("Serial command {} got no answer: {:?}", command, e);
+            Some((sequence, ReplyPacket::new(command, Status::Busy, ReplyPayload::Empty)))
+        }
+    }
+}
+
+// Picks frames out of the byte stream, anything before a start byte is skipped
+struct Deframer {
+    header: Vec<u8>, // The start byte and as much of the length as has come in, empty while looking for a start byte
+    frame: Vec<u8>,
+}
+
+impl Deframer {
+    fn new() -> Deframer {
+        Deframer { header: Vec::with_capacity(3), frame: Vec::with_capacity(MAX_FRAME_SIZE) }
+    }
+
+    fn reset(&mut self) {
+        self.header.clear();
+        self.frame.clear();
+    }
+
+    // The frame once its last byte is in
+    fn push(&mut self, byte: u8) -> Option<&[u8]> {
+        match self.header.len() {
+            0 if byte == START_BYTE => {
+                self.frame.clear();
+                self.header.push(byte);
+            }
+            0 => {},
+            1 | 2 => {
+                self.header.push(byte);
+                // A length no frame can have means that wasn't a start byte after all
+                let len = self.len();
+                if self.header.len() == 3 && (len == 0 || len > MAX_FRAME_SIZE) {
+                    self.reset();
+                }
+            }
+            _ => {
+                self.frame.push(byte);
+                if self.frame.len() == self.len() {
+                    self.header.clear();
+                    return Some(&self.frame);
+                }
+            }
+        }
+        None
+    }
+
+    fn len(&self) -> usize {
+        match self.header[..] {
+            [_, high, low] => u16::from_be_bytes([high, low]) as usize,
+            _ => 0,
+        }
+    }
+}
@@ -1,102 +1,1088 @@
-use esp_idf_hal::ledc::LedcDriver;
-use log::{error, info};
+use std::fmt;
+
+use log::{error, info, warn};
+
+use crate::backend::{DriverError, ServoBackend};
+use crate::feedback::Feedback;
+
+pub const POLL_HZ: u32 = 50; // Default rate poll() is called at, see set_poll_hz()
+pub const STATUS_OK: u8 = 0; // Status byte reported for a servo with no driver fault
+pub const STATUS_DETACHED: u8 = 3; // Status byte reported for a healthy servo whose output is off after idling
+pub const STATUS_STALLED: u8 = 4; // Status byte reported while the measured angle isn't tracking the driven one
+pub const STATUS_UNAVAILABLE: u8 = 5; // Status byte reported for a joint whose channel couldn't be set up at boot
+pub const STATUS_OVERLOADED: u8 = 6; // Status byte reported for a servo its current sensor stopped, until cleared
+pub const FULL_SPEED: u8 = 100; // Speed scale percent moving at the configured speeds
+pub const TENTHS: u16 = 10; // Angles are held in tenths of a degree, speeds and trims stay in whole degrees
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServoError {
+    Driver(DriverError), // The backend rejected a write
+    Faulted(DriverError), // The channel's last write failed, so a new goal may never be reached
+    Unavailable, // The channel couldn't be set up at boot, there is nothing to drive
+    Overloaded,  // Stopped for drawing too much current, nothing drives it until the fault is cleared
+}
+
+impl ServoError {
+    // Status byte reported to the client for this error
+    pub fn status_byte(&self) -> u8 {
+        match self {
+            ServoError::Driver(_) => 1,
+            ServoError::Faulted(_) => 2,
+            ServoError::Unavailable => STATUS_UNAVAILABLE,
+            ServoError::Overloaded => STATUS_OVERLOADED,
+        }
+    }
+}
+
+impl fmt::Display for ServoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServoError::Driver(e) => write!(f, "driver error: {}", e),
+            ServoError::Faulted(e) => write!(f, "channel faulted on its last write: {}", e),
+            ServoError::Unavailable => write!(f, "channel unavailable since boot"),
+            ServoError::Overloaded => write!(f, "stopped by an overload until it is cleared"),
+        }
+    }
+}
+
+impl std::error::Error for ServoError {}
+
+// A move interpolated from `start` to the goal over a fixed number of polls at full speed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TimedMove {
+    start: u16,
+    polls: u32,
+    elapsed: u32, // In hundredths of a poll, each poll adds the speed scale
+    easing: Easing,
+}
+
+// How a timed move's progress follows its time, carried by the pose command
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Easing {
+    #[default]
+    Linear,    // Constant speed, starting and stopping abruptly
+    EaseIn,    // Quadratic, setting off gently and arriving at speed
+    EaseOut,   // Quadratic, setting off at speed and arriving gently
+    EaseInOut, // Quadratic both ways
+    Cubic,     // Cubic both ways, gentler still at the ends and quicker in the middle
+}
+
+impl Easing {
+    pub fn from_byte(byte: u8) -> Option<Easing> {
+        match byte {
+            0 => Some(Easing::Linear),
+            1 => Some(Easing::EaseIn),
+            2 => Some(Easing::EaseOut),
+            3 => Some(Easing::EaseInOut),
+            4 => Some(Easing::Cubic),
+            _ => None,
+        }
+    }
+
+    /// Fraction of the travel covered `t` of the way through the move. Every curve runs from 0 to 1 without going
+    /// back, `t` outside 0 to 1 is clamped
+    pub fn apply(self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Easing::Linear => t,
+            Easing::EaseIn => t * t,
+            Easing::EaseOut => t * (2.0 - t),
+            Easing::EaseInOut if t < 0.5 => 2.0 * t * t,
+            Easing::EaseInOut => 1.0 - 2.0 * (1.0 - t) * (1.0 - t),
+            Easing::Cubic if t < 0.5 => 4.0 * t * t * t,
+            Easing::Cubic => 1.0 - 4.0 * (1.0 - t) * (1.0 - t) * (1.0 - t),
+        }
+    }
+}
+
+// Speed and fractional position of an acceleration limited move, carried between polls
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Ramp {
+    velocity: f32, // Tenths of a degree per second, negative while the angle is falling
+    offset: f32,   // Tenths the position is past the angle, within half a tenth either way
+}
 
 pub struct Servo {
     name: String,
-    driver: LedcDriver<'static>,
-    angle: u16,
+    driver: Box<dyn ServoBackend + Send>,
+    angle: u16, // Tenths of a degree, like every angle the servo takes and returns
     goal: u16,
-    deg_s: u16,
+    deg_s: u16, // Degrees per second, 0 moves instantly
+    accel: u16, // Degrees per second squared speed moves ramp up and down at, 0 starts and stops at full speed
+    ramp: Ramp,
+    speed_override: Option<u16>, // Temporarily replaces deg_s, e.g. while parking in failsafe
+    speed_scale: u8, // Percent of full speed, see set_speed_scale()
+    move_speed: Option<u16>, // Replaces deg_s until the goal is reached, see set_angle_at()
+    step_remainder: u32, // Fractional step carried between polls, in 1/poll_hz tenths
+    poll_hz: u32,
+    frame_hz: u32, // PWM frame rate of the driver, for converting pulse widths to duty counts
+    timed_move: Option<TimedMove>, // Replaces the speed until the goal is reached, see set_angle_timed()
     min_angle_duty: u32,
     duty_interval: u32,
-    max_angle_degrees: u16,
+    max_angle: u16,
+    min_limit: u16,
+    max_limit: u16,
+    trim: i16, // Whole degrees
+    reversed: bool,
+    enabled: bool, // Whether poll() should drive the servo, cleared by stop()
+    paused: bool, // Held energized where it is, the move carries on once resumed
+    energized: bool, // Whether a duty for the current angle has been written since the last stop
+    detach_s: u16, // Seconds at rest before the output is turned off, 0 never detaches
+    idle_polls: u32, // Polls the servo has been at rest for
+    detached: bool,
+    fault: Option<DriverError>, // Error from the last driver write, cleared by the next successful one
+    feedback: Option<Feedback>, // The measured angle, for servos with their potentiometer on an ADC pin
+    unavailable: Option<String>, // Why the channel couldn't be set up, for a joint standing in for a missing one
+    overloaded: bool, // Stopped by its current sensor, new goals and duties are refused until it is cleared
+}
+
+// Drives nothing, for a joint whose channel couldn't be set up
+#[cfg_attr(feature = "sim", allow(dead_code))] // The simulator's channels can't fail
+struct NoChannel;
+
+impl ServoBackend for NoChannel {
+    fn set_duty(&mut self, _duty: u32) -> Result<(), DriverError> {
+        Ok(())
+    }
+
+    fn get_duty(&self) -> u32 {
+        0
+    }
+
+    fn get_max_duty(&self) -> u32 {
+        0
+    }
+
+    fn disable(&mut self) -> Result<(), DriverError> {
+        Ok(())
+    }
+
+    fn enable(&mut self) -> Result<(), DriverError> {
+        Ok(())
+    }
 }
 
 impl Servo {
 
-    pub fn new(name: String, mut driver: LedcDriver<'static>, min_percent: f32, max_percent: f32, max_angle_degrees: u16) -> Servo {
-        match driver.set_duty(0) {
-            Ok(_) => info!("{} initialised", name),
-            Err(e) => error!("{} not initialised: {}", name, e),
-        }
-        let max_duty = driver.get_max_duty() as f32;
-        let min_angle_duty = (max_duty * min_percent).round() as u32;
-        let max_angle_duty = (max_duty * max_percent).round() as u32;
+    /// Endpoints as fractions of the `frame_hz` frame, the way calibrations are stored
+    pub fn new(
+        name: String,
+        driver: impl ServoBackend + Send + 'static,
+        frame_hz: u32,
+        min_percent: f32,
+        max_percent: f32,
+        max_angle_degrees: u16,
+    ) -> Servo {
+        let frame_us = 1e6 / frame_hz.max(1) as f32;
+        let (min_pulse_us, max_pulse_us) = (min_percent * frame_us, max_percent * frame_us);
+        Servo::create(name, Box::new(driver), frame_hz, min_pulse_us, max_pulse_us, max_angle_degrees).initialise()
+    }
+
+    /// Endpoints as pulse widths in microseconds, converted to duty counts from the frame rate and the driver's max
+    /// duty, so they hold whatever the timer runs at
+    pub fn with_pulses(
+        name: String,
+        driver: impl ServoBackend + Send + 'static,
+        frame_hz: u32,
+        min_pulse_us: u16,
+        max_pulse_us: u16,
+        max_angle_degrees: u16,
+    ) -> Servo {
+        let (min_pulse_us, max_pulse_us) = (min_pulse_us as f32, max_pulse_us as f32);
+        Servo::create(name, Box::new(driver), frame_hz, min_pulse_us, max_pulse_us, max_angle_degrees).initialise()
+    }
+
+    /// Stands in for a joint whose channel couldn't be set up, so the joints after it keep their indices.
+    /// It never drives anything, and moves and duties written to it fail with `ServoError::Unavailable`.
+    #[cfg_attr(feature = "sim", allow(dead_code))]
+    pub fn unavailable(name: String, max_angle_degrees: u16, error: String) -> Servo {
+        let mut servo = Servo::create(name, Box::new(NoChannel), 1, 0.0, 0.0, max_angle_degrees);
+        servo.unavailable = Some(error);
+        servo
+    }
+
+    fn create(
+        name: String,
+        driver: Box<dyn ServoBackend + Send>,
+        frame_hz: u32,
+        min_pulse_us: f32,
+        max_pulse_us: f32,
+        max_angle_degrees: u16,
+    ) -> Servo {
+        let frame_hz = frame_hz.max(1);
+        let counts_per_us = driver.get_max_duty() as f32 * frame_hz as f32 / 1e6;
+        let min_angle_duty = (min_pulse_us * counts_per_us).round() as u32;
+        let max_angle_duty = (max_pulse_us * counts_per_us).round() as u32;
         Servo {
             name,
             driver,
             angle: 0,
             goal: 0,
-            deg_s: 2,
+            deg_s: 100,
+            accel: 0,
+            ramp: Ramp::default(),
+            speed_override: None,
+            move_speed: None,
+            step_remainder: 0,
+            poll_hz: POLL_HZ,
+            frame_hz,
+            speed_scale: FULL_SPEED,
+            timed_move: None,
             min_angle_duty,
             duty_interval: max_angle_duty - min_angle_duty,
-            max_angle_degrees,
+            max_angle: from_degrees(max_angle_degrees),
+            min_limit: 0,
+            max_limit: from_degrees(max_angle_degrees),
+            trim: 0,
+            reversed: false,
+            enabled: false,
+            paused: false,
+            energized: false,
+            detach_s: 0,
+            idle_polls: 0,
+            detached: false,
+            fault: None,
+            feedback: None,
+            unavailable: None,
+            overloaded: false,
         }
     }
 
-    pub fn set_angle(&mut self, goal: u16){
-        self.goal = goal;
-        self.angle = goal;
-        let duty = self.get_servo_duty(goal);
-        match self.driver.set_duty(duty) {
+    fn initialise(mut self) -> Servo {
+        match self.driver.set_duty(0) {
+            Ok(_) => info!("{} initialised", self.name),
+            Err(e) => error!("{} not initialised: {}", self.name, e),
+        }
+        self
+    }
+
+    /// Sets the goal angle, the servo is moved towards it by `poll()`.
+    /// A stopped servo is driven again from its next poll.
+    /// The goal is always stored, but an error is returned if the channel is currently faulted.
+    /// Returns Ok(true) if the goal had to be clamped into the limits.
+    pub fn set_angle(&mut self, goal: u16) -> Result<bool, ServoError> {
+        if self.unavailable.is_some() {
+            return Err(ServoError::Unavailable);
+        }
+        if self.overloaded {
+            return Err(ServoError::Overloaded);
+        }
+        let was_clamped = self.set_goal(goal);
+        self.enabled = true;
+        if self.detached {
+            self.attach()?;
+        }
+        match self.fault {
+            Some(e) => Err(ServoError::Faulted(e)),
+            None => Ok(was_clamped),
+        }
+    }
+
+    /// Sets the goal like `set_angle()`, but the servo travels there in `duration_ms` whatever its speed,
+    /// so servos given the same duration arrive together. A duration of 0 moves at the servo's speed, uneased.
+    pub fn set_angle_timed(&mut self, goal: u16, duration_ms: u16, easing: Easing) -> Result<bool, ServoError> {
+        let result = self.set_angle(goal);
+        let polls = duration_ms as u32 * self.poll_hz / 1000;
+        self.timed_move = if polls > 0 && self.goal != self.angle {
+            Some(TimedMove { start: self.angle, polls, elapsed: 0, easing })
+        } else {
+            None
+        };
+        result
+    }
+
+    /// Sets the goal like `set_angle()`, travelling at `deg_s` for this move only
+    pub fn set_angle_at(&mut self, goal: u16, deg_s: u16) -> Result<bool, ServoError> {
+        let result = self.set_angle(goal);
+        self.timed_move = None;
+        self.move_speed = Some(deg_s);
+        result
+    }
+
+    /// Moves the goal by `delta` from wherever it is now, even mid-move, like `set_angle()`.
+    /// Returns Ok(true) if the goal had to be clamped into the limits.
+    pub fn jog(&mut self, delta: i8) -> Result<bool, ServoError> {
+        let goal = self.goal as i32 + delta as i32;
+        self.set_angle(goal.max(0) as u16).map(|clamped| clamped || goal < 0)
+    }
+
+    /// Takes the measured angle, or `fallback` without feedback, as where a servo that hasn't been driven yet is,
+    /// so its first move starts from there rather than from 0. The servo stays off until then.
+    pub fn assume_angle(&mut self, fallback: u16) {
+        if self.energized {
+            return;
+        }
+        if let Some(feedback) = self.feedback.as_mut() {
+            feedback.sample(&self.name);
+        }
+        let (angle, _) = clamp_angle(self.get_measured_angle().unwrap_or(fallback), self.min_limit, self.max_limit);
+        self.angle = angle;
+        self.goal = angle;
+    }
+
+    /// `set_angle()` for callers that have nowhere to report a failure
+    pub fn set_angle_logged(&mut self, goal: u16) {
+        match self.set_angle(goal) {
             Ok(_) => {},
-            Err(e) => error!("Failed to change duty of {}: {}", self.name, e),
+            Err(e) => error!("Failed to set angle of {}: {}", self.name, e),
+        }
+    }
+
+    fn set_goal(&mut self, goal: u16) -> bool {
+        let (clamped, was_clamped) = clamp_angle(goal, self.min_limit, self.max_limit);
+        if was_clamped {
+            warn!("{} angle {}\u{b0} out of range, clamped to {}\u{b0}", self.name, Tenths(goal), Tenths(clamped));
+        }
+        if clamped != self.goal {
+            self.timed_move = None;
+            self.move_speed = None;
         }
+        self.goal = clamped;
+        was_clamped
+    }
+
+    /// Sets the software travel limits, the current goal is re-clamped into the new window.
+    /// Returns true if the limits themselves had to be clamped to the servo's travel.
+    pub fn set_limits(&mut self, min_limit: u16, max_limit: u16) -> bool {
+        let (max_limit, max_clamped) = clamp_angle(max_limit, 0, self.max_angle);
+        let (min_limit, min_clamped) = clamp_angle(min_limit, 0, max_limit);
+        info!("{} limits set to {}..{}", self.name, Tenths(min_limit), Tenths(max_limit));
+        self.min_limit = min_limit;
+        self.max_limit = max_limit;
+        self.set_goal(self.goal);
+        max_clamped || min_clamped
+    }
+
+    pub fn get_limits(&self) -> (u16, u16) {
+        (self.min_limit, self.max_limit)
+    }
+
+    pub fn set_speed(&mut self, deg_s: u16) {
+        self.deg_s = deg_s;
+    }
+
+    pub fn get_speed(&self) -> u16 {
+        self.deg_s
+    }
+
+    /// Limits how quickly moves at a speed get up to it and slow down to the goal, 0 doesn't. Timed moves keep their
+    /// linear pace, so servos given the same duration still arrive together
+    pub fn set_accel(&mut self, deg_s2: u16) {
+        self.accel = deg_s2;
+    }
+
+    pub fn get_accel(&self) -> u16 {
+        self.accel
+    }
+
+    pub fn set_speed_override(&mut self, deg_s: Option<u16>) {
+        self.speed_override = deg_s;
+    }
+
+    /// Scales the speed and the pace of timed moves to `percent` of full speed, from the next poll on so a move in
+    /// progress carries on from where it is. Servos that move instantly still do, and a speed override isn't scaled
+    /// so parking is as quick as ever. Clamped to 1 to 100.
+    pub fn set_speed_scale(&mut self, percent: u8) {
+        self.speed_scale = percent.clamp(1, FULL_SPEED);
+    }
+
+    /// Freezes the servo at its current angle, still energized, without dropping the goal or the progress of a
+    /// timed move. Goals set while paused are headed for once resumed.
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    /// Sets the rate `poll()` is called at, so speeds stay in degrees per second
+    pub fn set_poll_hz(&mut self, poll_hz: u32) {
+        self.poll_hz = poll_hz.max(1);
+        self.step_remainder = 0;
+        self.ramp = Ramp::default();
+    }
+
+    /// Sets how long the servo may sit at rest before its output is turned off, 0 keeps it attached.
+    /// Joints that hold up a load must keep this at 0.
+    pub fn set_detach_timeout(&mut self, detach_s: u16) {
+        self.detach_s = detach_s;
+        self.idle_polls = 0;
+    }
+
+    pub fn get_detach_timeout(&self) -> u16 {
+        self.detach_s
+    }
+
+    fn attach(&mut self) -> Result<(), ServoError> {
+        info!("{} re-attached", self.name);
+        self.detached = false;
+        self.idle_polls = 0;
+        let result = self.driver.enable();
+        self.record(result)
+    }
+
+    pub fn set_trim(&mut self, trim: i16) -> Result<(), ServoError> {
+        self.trim = trim;
+        self.refresh_duty()
+    }
+
+    pub fn get_trim(&self) -> i16 {
+        self.trim
+    }
+
+    pub fn set_reversed(&mut self, reversed: bool) -> Result<(), ServoError> {
+        self.reversed = reversed;
+        self.refresh_duty()
+    }
+
+    pub fn is_reversed(&self) -> bool {
+        self.reversed
+    }
+
+    /// Duty range as fractions of the driver's max duty, the same units `new()` takes
+    pub fn get_duty_range(&self) -> (f32, f32) {
+        let max_duty = self.driver.get_max_duty() as f32;
+        (
+            self.min_angle_duty as f32 / max_duty,
+            (self.min_angle_duty + self.duty_interval) as f32 / max_duty,
+        )
     }
 
     fn get_servo_duty(&self, angle: u16) -> u32 {
-        let percentage = angle as f32 / self.max_angle_degrees as f32;
+        let physical = physical_angle(angle, self.max_angle, self.trim, self.reversed);
+        angle_to_duty(physical, self.max_angle, self.min_angle_duty, self.duty_interval)
+    }
 
-        (self.duty_interval as f32 * percentage).round() as u32 + self.min_angle_duty
+    // Rewrites the duty for the current angle, unless the servo is not being driven
+    fn refresh_duty(&mut self) -> Result<(), ServoError> {
+        if !self.energized {
+            return Ok(());
+        }
+        let duty = self.get_servo_duty(self.angle);
+        self.write_duty(duty)
     }
 
-    pub fn set_duty(&mut self, duty: u16) {
-        match self.driver.set_duty(duty as u32) {
-            Ok(_) => {},
-            Err(e) => error!("Failed to change duty of {}: {}", self.name, e),
+    // Writes a duty to the driver, recording the outcome as the channel's fault state
+    fn write_duty(&mut self, duty: u32) -> Result<(), ServoError> {
+        let result = self.driver.set_duty(duty);
+        self.record(result)
+    }
+
+    fn record(&mut self, result: Result<(), DriverError>) -> Result<(), ServoError> {
+        match result {
+            Ok(_) => {
+                self.fault = None;
+                Ok(())
+            }
+            Err(e) => {
+                self.fault = Some(e);
+                Err(ServoError::Driver(e))
+            }
         }
     }
 
-    pub fn stop(&mut self) {
-        match self.driver.disable() {
-            Ok(_) => {},
-            Err(e) => error!("Failed to stop {}: {}", self.name, e),
-        }
-    }
-
-    pub fn poll(&mut self) {
-        // if self.angle != self.goal {
-        //     let mut new_angle = self.angle;
-        //     if self.angle < self.goal {
-        //         new_angle += self.deg_s;
-        //         if new_angle > self.goal {
-        //             new_angle = self.goal;
-        //         }
-        //     } else {
-        //         new_angle -= self.deg_s;
-        //         if new_angle < self.goal {
-        //             new_angle = self.goal;
-        //         }
-        //     }
-        //     self.angle = new_angle;
-        //     let duty = self.get_servo_duty(self.angle);
-        //     match self.driver.set_duty(duty) {
-        //         Ok(_) => {},
-        //         Err(e) => error!("Failed to change duty of {}: {}", self.name, e),
-        //     }
-        // }
-        // TODO: Make it not a stub
+    /// Writes a raw duty count, bypassing the angle. The servo is not driven by `poll()` again
+    /// until its next `set_angle()`
+    pub fn set_duty(&mut self, duty: u16) -> Result<(), ServoError> {
+        if self.unavailable.is_some() {
+            return Err(ServoError::Unavailable);
+        }
+        if self.overloaded {
+            return Err(ServoError::Overloaded);
+        }
+        self.enabled = false;
+        self.energized = false;
+        if self.detached {
+            self.attach()?;
+        }
+        self.write_duty(duty as u32)
+    }
+
+    /// Writes a raw pulse width, like `set_duty()`. Widths longer than the frame hold the output high
+    pub fn set_pulse_us(&mut self, pulse_us: u16) -> Result<(), ServoError> {
+        let duty = self.pulse_to_duty(pulse_us);
+        self.set_duty(duty.min(u16::MAX as u32) as u16)
+    }
+
+    /// Duty count for a pulse width at the driver's frame rate and resolution
+    pub fn pulse_to_duty(&self, pulse_us: u16) -> u32 {
+        let max_duty = self.driver.get_max_duty() as u64;
+        let duty = (pulse_us as u64 * self.frame_hz as u64 * max_duty + 500_000) / 1_000_000;
+        duty.min(max_duty) as u32
+    }
+
+    /// Pulse width, rounded to the microsecond, a duty count drives
+    pub fn duty_to_pulse_us(&self, duty: u32) -> u16 {
+        let counts = self.frame_hz as u64 * self.driver.get_max_duty().max(1) as u64;
+        ((duty as u64 * 1_000_000 + counts / 2) / counts).min(u16::MAX as u64) as u16
+    }
+
+    /// Duty currently held by the driver, 0 if the servo was never driven
+    pub fn get_duty(&self) -> u32 {
+        self.driver.get_duty()
+    }
+
+    /// Duty counts at 0 degrees and at the max angle
+    pub fn get_duty_endpoints(&self) -> (u32, u32) {
+        (self.min_angle_duty, self.min_angle_duty + self.duty_interval)
+    }
+
+    /// Replaces the duty counts at 0 degrees and at the max angle, `min_duty` must be below `max_duty`
+    pub fn set_duty_endpoints(&mut self, min_duty: u32, max_duty: u32) {
+        info!("{} duty range set to {}..{}", self.name, min_duty, max_duty);
+        self.min_angle_duty = min_duty;
+        self.duty_interval = max_duty.saturating_sub(min_duty);
+    }
+
+    /// De-energizes the servo, any move in progress is abandoned where it is
+    pub fn stop(&mut self) -> Result<(), ServoError> {
+        self.enabled = false;
+        self.energized = false;
+        self.goal = self.angle;
+        self.timed_move = None;
+        self.ramp = Ramp::default();
+        self.detached = false;
+        let result = self.driver.disable();
+        self.record(result)
+    }
+
+    /// Re-energizes a stopped servo at the angle it was stopped at, servos never driven or overloaded stay off
+    pub fn reenable(&mut self) -> Result<(), ServoError> {
+        if self.driver.get_duty() == 0 || self.overloaded {
+            return Ok(());
+        }
+        let result = self.driver.enable();
+        self.record(result)?;
+        self.enabled = true;
+        self.energized = true;
+        Ok(())
+    }
+
+    /// Steps the angle towards the goal at `deg_s` degrees per second, a `deg_s` of 0 moves instantly.
+    /// Must be called at the rate given to `set_poll_hz()` for the servo speed to be consistent.
+    /// A servo at rest detaches once its detach timeout is up, unless `allow_detach` is false.
+    pub fn poll(&mut self, allow_detach: bool) -> Result<(), ServoError> {
+        if let Some(feedback) = self.feedback.as_mut() {
+            feedback.sample(&self.name);
+            // A stopped or detached servo is free to be moved by hand, only a driven one can stall
+            let driven = (self.enabled && self.energized && !self.detached).then_some(self.angle);
+            match feedback.track(driven, self.max_angle, self.poll_hz) {
+                Some(true) => warn!(
+                    "{} stalled, measured {}° while driven to {}°",
+                    self.name,
+                    Tenths(feedback.get_angle(self.max_angle).unwrap_or(0)),
+                    Tenths(self.angle)
+                ),
+                Some(false) => info!("{} tracking again", self.name),
+                None => {},
+            }
+        }
+        if !self.enabled || self.detached {
+            return Ok(());
+        }
+        if self.paused {
+            // Frozen where it is, so a resumed move ramps up again from rest
+            self.idle_polls = 0;
+            self.ramp = Ramp::default();
+            return Ok(());
+        }
+        if self.angle == self.goal && self.energized {
+            self.step_remainder = 0;
+            self.ramp = Ramp::default();
+            self.timed_move = None;
+            self.move_speed = None;
+            if !allow_detach || self.detach_s == 0 {
+                self.idle_polls = 0;
+                return Ok(());
+            }
+            self.idle_polls += 1;
+            if self.idle_polls >= self.detach_s as u32 * self.poll_hz {
+                info!("{} at rest for {} s, detaching", self.name, self.detach_s);
+                self.detached = true;
+                self.energized = false;
+                let result = self.driver.disable();
+                return self.record(result);
+            }
+            return Ok(());
+        }
+        self.idle_polls = 0;
+        self.angle = match self.timed_move.as_mut() {
+            Some(timed) => {
+                timed.elapsed += self.speed_scale as u32;
+                interpolate(timed.start, self.goal, timed.elapsed, timed.polls * FULL_SPEED as u32, timed.easing)
+            }
+            None => {
+                let deg_s = match self.speed_override {
+                    Some(deg_s) => deg_s,
+                    None => scale_speed(self.move_speed.unwrap_or(self.deg_s), self.speed_scale),
+                };
+                match self.accel {
+                    0 => step_towards(self.angle, self.goal, deg_s, self.poll_hz, &mut self.step_remainder),
+                    accel => ramp_towards(self.angle, self.goal, deg_s, accel, self.poll_hz, &mut self.ramp),
+                }
+            }
+        };
+        let duty = self.get_servo_duty(self.angle);
+        self.write_duty(duty)?;
+        self.energized = true;
+        Ok(())
     }
 
+    /// Status byte for replies, a missing channel takes precedence over an overload, an overload over a driver fault,
+    /// a fault over a stall and a stall over being detached
+    pub fn status(&self) -> u8 {
+        if self.unavailable.is_some() {
+            return STATUS_UNAVAILABLE;
+        }
+        if self.overloaded {
+            return STATUS_OVERLOADED;
+        }
+        match self.fault {
+            Some(e) => ServoError::Driver(e).status_byte(),
+            None if self.feedback.as_ref().is_some_and(Feedback::is_stalled) => STATUS_STALLED,
+            None if self.detached => STATUS_DETACHED,
+            None => STATUS_OK,
+        }
+    }
+
+    pub fn is_faulted(&self) -> bool {
+        self.fault.is_some()
+    }
+
+    /// Whether `poll()` drives the servo, false once stopped or while calibrating
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn is_moving(&self) -> bool {
+        self.angle != self.goal
+    }
+
+    /// The logical joint angle, before reversal and trim are applied
     pub fn get_angle(&self) -> u16 {
         self.angle
     }
 
+    pub fn get_goal(&self) -> u16 {
+        self.goal
+    }
+
+    pub fn get_max_angle(&self) -> u16 {
+        self.max_angle
+    }
+
     pub fn get_name(&self) -> &str {
         &self.name
     }
 
-    pub fn to_string(&self) -> String {
-        format!("{}: {}°", self.name, self.angle)
+    /// Why the channel couldn't be set up at boot, None for a joint that has one
+    pub fn get_unavailable(&self) -> Option<&str> {
+        self.unavailable.as_deref()
+    }
+
+    /// Stops the servo and latches it off, for a current sensor that saw it draw too much for too long
+    pub fn trip_overload(&mut self) -> Result<(), ServoError> {
+        self.overloaded = true;
+        self.stop()
+    }
+
+    /// Lets the servo be driven again, it stays off until the next command moves it. False if it wasn't overloaded
+    pub fn clear_overload(&mut self) -> bool {
+        std::mem::replace(&mut self.overloaded, false)
+    }
+
+    pub fn is_overloaded(&self) -> bool {
+        self.overloaded
+    }
+
+    pub fn set_feedback(&mut self, feedback: Feedback) {
+        self.feedback = Some(feedback);
+    }
+
+    pub fn get_feedback_mut(&mut self) -> Option<&mut Feedback> {
+        self.feedback.as_mut()
+    }
+
+    /// The angle read back from the potentiometer, None without feedback or before its first reading
+    pub fn get_measured_angle(&self) -> Option<u16> {
+        self.feedback.as_ref().and_then(|feedback| feedback.get_angle(self.max_angle))
+    }
+}
+
+// "<name>: <angle>°", the display's FONT_5X8 is ISO-8859-16 so it has the degree sign
+impl fmt::Display for Servo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}\u{b0}", self.name, Tenths(self.angle))
+    }
+}
+
+// An angle in tenths shown in degrees to one decimal place, "90.5"
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tenths(pub u16);
+
+impl fmt::Display for Tenths {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let degrees = format!("{}.{}", self.0 / TENTHS, self.0 % TENTHS);
+        f.pad(&degrees)
+    }
+}
+
+// Whole degrees as tenths, for the commands, config and stored layouts that carry them
+pub fn from_degrees(degrees: u16) -> u16 {
+    degrees.saturating_mul(TENTHS)
+}
+
+// Tenths rounded to the nearest whole degree, for the replies that carry them
+pub fn to_degrees(tenths: u16) -> u16 {
+    ((tenths as u32 + TENTHS as u32 / 2) / TENTHS as u32) as u16
+}
+
+// Clamps an angle to [min_angle, max_angle], also returning whether clamping occurred
+pub fn clamp_angle(angle: u16, min_angle: u16, max_angle: u16) -> (u16, bool) {
+    if angle > max_angle {
+        (max_angle, true)
+    } else if angle < min_angle {
+        (min_angle, true)
+    } else {
+        (angle, false)
+    }
+}
+
+// Moves an angle in tenths towards the goal by one poll's worth of travel at deg_s degrees per second when polled at
+// poll_hz, carrying the fractional part of the step over in `remainder`
+pub fn step_towards(angle: u16, goal: u16, deg_s: u16, poll_hz: u32, remainder: &mut u32) -> u16 {
+    if deg_s == 0 {
+        *remainder = 0;
+        return goal;
+    }
+    *remainder += deg_s as u32 * TENTHS as u32;
+    let step = (*remainder / poll_hz).min(u16::MAX as u32) as u16;
+    *remainder %= poll_hz;
+
+    if angle < goal {
+        angle.saturating_add(step).min(goal)
+    } else {
+        angle.saturating_sub(step).max(goal)
+    }
+}
+
+// Moves an angle towards the goal by one poll of a trapezoidal profile: speeding up at `accel` degrees per second
+// squared, cruising at deg_s and slowing down at `accel` to stop on the goal. The speed is worked out afresh each poll
+// from the distance left, so a goal changed mid-move is followed from the current speed. A goal moved behind the
+// servo has it slow down first, one moved closer than it can stop in has it slow down faster rather than overshoot
+pub fn ramp_towards(angle: u16, goal: u16, deg_s: u16, accel: u16, poll_hz: u32, ramp: &mut Ramp) -> u16 {
+    if deg_s == 0 {
+        *ramp = Ramp::default();
+        return goal;
+    }
+    let dt = 1.0 / poll_hz.max(1) as f32;
+    // Worked in tenths, like the angles
+    let (deg_s, accel) = (deg_s as f32 * TENTHS as f32, accel as f32 * TENTHS as f32);
+    let position = angle as f32 + ramp.offset;
+    let distance = goal as f32 - position;
+    let direction = if distance < 0.0 { -1.0 } else { 1.0 };
+    // Fastest the servo can go and still stop on the goal
+    let stopping = (2.0 * accel * distance.abs()).sqrt();
+    let speed = (ramp.velocity * direction + accel * dt).min(deg_s).min(stopping);
+    let step = speed * dt;
+    if step >= distance.abs() {
+        *ramp = Ramp::default();
+        return goal;
+    }
+    let position = position + step * direction;
+    let angle = position.round().max(0.0) as u16;
+    *ramp = Ramp { velocity: speed * direction, offset: position - angle as f32 };
+    angle
+}
+
+// `percent` of a speed, never scaled down to 0 since that would move instantly
+pub fn scale_speed(deg_s: u16, percent: u8) -> u16 {
+    if deg_s == 0 {
+        return 0;
+    }
+    (deg_s as u32 * percent as u32 / FULL_SPEED as u32).max(1) as u16
+}
+
+// The angle `elapsed` polls into a move from start to goal lasting `polls` polls, reaching the goal on the last one
+pub fn interpolate(start: u16, goal: u16, elapsed: u32, polls: u32, easing: Easing) -> u16 {
+    if elapsed >= polls {
+        return goal;
+    }
+    let start = start as i64;
+    let travel = goal as i64 - start;
+    match easing {
+        // Kept in integers, so uneased moves step exactly as they always have
+        Easing::Linear => (start + travel * elapsed as i64 / polls as i64) as u16,
+        easing => {
+            let progress = easing.apply(elapsed as f32 / polls as f32);
+            (start as f32 + travel as f32 * progress).round() as u16
+        }
+    }
+}
+
+// Converts a logical joint angle to the physical servo angle by applying reversal and then the trim in whole degrees
+pub fn physical_angle(angle: u16, max_angle: u16, trim: i16, reversed: bool) -> u16 {
+    let angle = if reversed {
+        max_angle.saturating_sub(angle)
+    } else {
+        angle
+    };
+    (angle as i32 + trim as i32 * TENTHS as i32).clamp(0, max_angle as i32) as u16
+}
+
+// Maps an angle onto the duty range of a servo, angles past the max are clamped
+pub fn angle_to_duty(angle: u16, max_angle: u16, min_angle_duty: u32, duty_interval: u32) -> u32 {
+    if max_angle == 0 {
+        return min_angle_duty;
+    }
+    let (angle, _) = clamp_angle(angle, 0, max_angle);
+    let percentage = angle as f32 / max_angle as f32;
+
+    (duty_interval as f32 * percentage).round() as u32 + min_angle_duty
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_ANGLE: u16 = 180;
+    const MIN_DUTY: u32 = 100;
+    const DUTY_INTERVAL: u32 = 400;
+
+    #[test]
+    fn clamp_angle_keeps_angles_in_range() {
+        assert_eq!(clamp_angle(0, 0, MAX_ANGLE), (0, false));
+        assert_eq!(clamp_angle(MAX_ANGLE, 0, MAX_ANGLE), (MAX_ANGLE, false));
+    }
+
+    #[test]
+    fn clamp_angle_limits_angles_past_the_max() {
+        assert_eq!(clamp_angle(MAX_ANGLE + 1, 0, MAX_ANGLE), (MAX_ANGLE, true));
+        assert_eq!(clamp_angle(u16::MAX, 0, MAX_ANGLE), (MAX_ANGLE, true));
+    }
+
+    #[test]
+    fn clamp_angle_limits_angles_below_the_min() {
+        assert_eq!(clamp_angle(0, 10, MAX_ANGLE), (10, true));
+    }
+
+    #[cfg(feature = "sim")]
+    #[test]
+    fn pulse_widths_convert_to_duty_counts_at_the_frame_rate() {
+        let (driver, _) = crate::sim::MockServo::new();
+        // 4095 counts across a 20 ms frame
+        let servo = Servo::with_pulses("Base".to_string(), driver, 50, 500, 2500, MAX_ANGLE);
+        assert_eq!(servo.pulse_to_duty(1500), 307);
+        assert_eq!(servo.duty_to_pulse_us(307), 1499);
+        assert_eq!(servo.get_servo_duty(0), 102);
+        assert_eq!(servo.get_servo_duty(from_degrees(MAX_ANGLE)), 512);
+        // Longer than the frame holds the output high
+        assert_eq!(servo.pulse_to_duty(u16::MAX), 4095);
+    }
+
+    #[test]
+    fn angle_to_duty_spans_the_duty_range() {
+        assert_eq!(angle_to_duty(0, MAX_ANGLE, MIN_DUTY, DUTY_INTERVAL), MIN_DUTY);
+        assert_eq!(angle_to_duty(MAX_ANGLE / 2, MAX_ANGLE, MIN_DUTY, DUTY_INTERVAL), MIN_DUTY + DUTY_INTERVAL / 2);
+        assert_eq!(angle_to_duty(MAX_ANGLE, MAX_ANGLE, MIN_DUTY, DUTY_INTERVAL), MIN_DUTY + DUTY_INTERVAL);
+    }
+
+    #[test]
+    fn angle_to_duty_never_drives_past_the_max() {
+        let max_duty = MIN_DUTY + DUTY_INTERVAL;
+        assert_eq!(angle_to_duty(MAX_ANGLE + 1, MAX_ANGLE, MIN_DUTY, DUTY_INTERVAL), max_duty);
+        assert_eq!(angle_to_duty(u16::MAX, MAX_ANGLE, MIN_DUTY, DUTY_INTERVAL), max_duty);
+    }
+
+    #[test]
+    fn interpolate_reaches_the_goal_on_the_last_poll() {
+        assert_eq!(interpolate(0, 180, 0, 50, Easing::Linear), 0);
+        assert_eq!(interpolate(0, 180, 25, 50, Easing::Linear), 90);
+        assert_eq!(interpolate(180, 0, 25, 50, Easing::Linear), 90);
+        assert_eq!(interpolate(0, 180, 50, 50, Easing::Linear), 180);
+        assert_eq!(interpolate(0, 180, 60, 50, Easing::Linear), 180);
+    }
+
+    #[test]
+    fn scale_speed_never_reaches_an_instant_move() {
+        assert_eq!(scale_speed(120, FULL_SPEED), 120);
+        assert_eq!(scale_speed(120, 50), 60);
+        assert_eq!(scale_speed(120, 0), 1);
+        assert_eq!(scale_speed(0, 50), 0);
+    }
+
+    const DEG_S: u16 = 60;
+    const ACCEL: u16 = 100;
+
+    // Every angle a ramped move from `start` passes through on the way to `goal`, the goal last
+    fn ramp(start: u16, goal: u16) -> Vec<u16> {
+        let mut ramp = Ramp::default();
+        let mut angles = vec![start];
+        while *angles.last().unwrap() != goal {
+            assert!(angles.len() < 1000, "never reached {} from {}", goal, start);
+            angles.push(ramp_towards(*angles.last().unwrap(), goal, DEG_S, ACCEL, POLL_HZ, &mut ramp));
+        }
+        angles
+    }
+
+    // The step between polls may grow by a poll's worth of acceleration at most, and never past the cruising speed.
+    // Either is allowed a tenth for the rounding to whole tenths
+    fn assert_profile(angles: &[u16]) {
+        let max_step = (DEG_S * TENTHS) as f32 / POLL_HZ as f32;
+        let max_change = (ACCEL * TENTHS) as f32 / (POLL_HZ * POLL_HZ) as f32;
+        let steps: Vec<f32> = angles.windows(2).map(|pair| pair[1] as f32 - pair[0] as f32).collect();
+        for step in &steps {
+            assert!(step.abs() <= max_step + 1.0, "step of {} past the cruising speed", step);
+        }
+        for pair in steps.windows(2) {
+            assert!((pair[1] - pair[0]).abs() <= max_change + 2.0, "speed changed from {} to {}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn ramp_reaches_the_goal_of_a_short_move_without_overshoot() {
+        let angles = ramp(900, 920);
+        assert!(angles.iter().all(|&angle| (900..=920).contains(&angle)));
+        assert!(angles.windows(2).all(|pair| pair[1] >= pair[0]));
+        assert_profile(&angles);
+    }
+
+    #[test]
+    fn ramp_reaches_the_goal_of_a_long_move_without_overshoot() {
+        let angles = ramp(1800, 0);
+        assert!(angles.windows(2).all(|pair| pair[1] <= pair[0]));
+        assert_profile(&angles);
+        // Long enough to reach the cruising speed, so it takes longer than the distance at that speed alone
+        let cruise_polls = (1800.0 / ((DEG_S * TENTHS) as f32 / POLL_HZ as f32)) as usize;
+        assert!(angles.len() > cruise_polls);
+    }
+
+    #[test]
+    fn ramp_follows_a_goal_changed_mid_move() {
+        let mut ramp = Ramp::default();
+        let mut angle = 0;
+        for _ in 0..20 {
+            angle = ramp_towards(angle, 1800, DEG_S, ACCEL, POLL_HZ, &mut ramp);
+        }
+        assert!(angle > 0 && angle < 300);
+        // Brought closer, still ahead of where it can stop, so it slows down onto it without passing it
+        let mut angles = vec![angle];
+        while angle != 300 {
+            assert!(angles.len() < 1000, "never reached the new goal");
+            angle = ramp_towards(angle, 300, DEG_S, ACCEL, POLL_HZ, &mut ramp);
+            assert!(angle <= 300, "passed the new goal at {}", angle);
+            angles.push(angle);
+        }
+        assert_profile(&angles);
+    }
+
+    #[test]
+    fn ramp_without_a_speed_limit_jumps_to_the_goal() {
+        assert_eq!(ramp_towards(0, 1800, 0, ACCEL, POLL_HZ, &mut Ramp::default()), 1800);
+    }
+
+    #[test]
+    fn step_towards_covers_the_speed_in_tenths() {
+        let (mut angle, mut remainder) = (0, 0);
+        let step = DEG_S * TENTHS / POLL_HZ as u16;
+        for poll in 1..=3 {
+            angle = step_towards(angle, 1800, DEG_S, POLL_HZ, &mut remainder);
+            assert_eq!(angle, step * poll);
+        }
+    }
+
+    #[test]
+    fn degrees_scale_to_tenths_and_round_back() {
+        assert_eq!(from_degrees(90), 900);
+        assert_eq!(from_degrees(u16::MAX), u16::MAX);
+        assert_eq!(to_degrees(900), 90);
+        assert_eq!(to_degrees(904), 90);
+        assert_eq!(to_degrees(905), 91);
+        assert_eq!(to_degrees(u16::MAX), 6554);
+    }
+
+    #[test]
+    fn tenths_show_one_decimal_place() {
+        assert_eq!(Tenths(905).to_string(), "90.5");
+        assert_eq!(Tenths(0).to_string(), "0.0");
+        assert_eq!(format!("{:>6}", Tenths(1800)), " 180.0");
+    }
+
+    #[test]
+    fn an_unavailable_joint_refuses_every_move() {
+        let mut servo = Servo::unavailable("Elbow".to_string(), MAX_ANGLE, "no timer 2".to_string());
+        assert_eq!(servo.set_angle(900), Err(ServoError::Unavailable));
+        assert_eq!(servo.set_angle_timed(900, 500, Easing::Linear), Err(ServoError::Unavailable));
+        assert_eq!(servo.set_duty(300), Err(ServoError::Unavailable));
+        assert!(!servo.is_enabled());
+        assert_eq!(servo.status(), STATUS_UNAVAILABLE);
+        assert_eq!(servo.get_unavailable(), Some("no timer 2"));
+    }
+
+    #[cfg(feature = "sim")]
+    #[test]
+    fn an_overloaded_servo_stays_off_until_cleared() {
+        let (driver, duties) = crate::sim::MockServo::new();
+        let mut servo = Servo::with_pulses("Elbow".to_string(), driver, 50, 500, 2500, MAX_ANGLE);
+        servo.set_angle(900).unwrap();
+        servo.poll(false).unwrap();
+        servo.trip_overload().unwrap();
+        assert_eq!(servo.status(), STATUS_OVERLOADED);
+        assert_eq!(servo.set_angle(450), Err(ServoError::Overloaded));
+        assert_eq!(servo.set_duty(300), Err(ServoError::Overloaded));
+        let written = duties.lock().unwrap().len();
+        servo.reenable().unwrap();
+        assert_eq!(duties.lock().unwrap().len(), written, "re-enabled while overloaded");
+        assert!(servo.clear_overload());
+        assert!(!servo.clear_overload());
+        assert!(!servo.is_enabled(), "driven again before a command moved it");
+        assert_eq!(servo.set_angle(450), Ok(false));
+    }
+
+    #[test]
+    fn trim_stays_in_whole_degrees() {
+        assert_eq!(physical_angle(900, 1800, 5, false), 950);
+        assert_eq!(physical_angle(900, 1800, -5, true), 850);
+        assert_eq!(physical_angle(1795, 1800, 5, false), 1800);
+    }
+
+    const EASINGS: [Easing; 5] = [Easing::Linear, Easing::EaseIn, Easing::EaseOut, Easing::EaseInOut, Easing::Cubic];
+
+    #[test]
+    fn every_easing_starts_at_0_and_ends_at_1() {
+        for easing in EASINGS {
+            assert_eq!(easing.apply(0.0), 0.0, "{:?}", easing);
+            assert_eq!(easing.apply(1.0), 1.0, "{:?}", easing);
+        }
+    }
+
+    #[test]
+    fn every_easing_clamps_outside_the_move() {
+        for easing in EASINGS {
+            assert_eq!(easing.apply(-0.5), 0.0, "{:?}", easing);
+            assert_eq!(easing.apply(1.5), 1.0, "{:?}", easing);
+        }
+    }
+
+    #[test]
+    fn every_easing_never_goes_back() {
+        for easing in EASINGS {
+            let mut last = easing.apply(0.0);
+            for step in 1..=1000 {
+                let progress = easing.apply(step as f32 / 1000.0);
+                assert!(progress >= last, "{:?} went back at {}", easing, step);
+                last = progress;
+            }
+        }
+    }
+
+    #[test]
+    fn eased_moves_arrive_on_the_goal() {
+        for easing in EASINGS {
+            assert_eq!(interpolate(0, 180, 0, 50, easing), 0, "{:?}", easing);
+            assert_eq!(interpolate(0, 180, 50, 50, easing), 180, "{:?}", easing);
+        }
+        // Gentler than linear a tenth of the way in, quicker in the middle
+        assert_eq!(interpolate(0, 180, 5, 50, Easing::Linear), 18);
+        assert_eq!(interpolate(0, 180, 5, 50, Easing::EaseIn), 2);
+        assert_eq!(interpolate(0, 180, 5, 50, Easing::Cubic), 1);
+        assert_eq!(interpolate(0, 180, 25, 50, Easing::EaseInOut), 90);
     }
 }
@@ -0,0 +1,174 @@
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
+
+// The client that claimed the limb, commands that change anything are only taken from it until it releases the claim
+// or goes quiet for the timeout. Unclaimed, every client is allowed.
+pub struct Session {
+    timeout: Duration, // A zero timeout never expires the claim
+    owner: Option<(IpAddr, Instant)>, // The owner and its last packet
+}
+
+impl Session {
+    pub fn new(timeout: Duration) -> Session {
+        Session { timeout, owner: None }
+    }
+
+    // Claims the limb for a client, or renews its claim. Returns false if another client holds it
+    pub fn claim(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if !self.allows(ip) {
+            return false;
+        }
+        self.owner = Some((ip, now));
+        true
+    }
+
+    // Returns true if the client held the claim
+    pub fn release(&mut self, ip: IpAddr) -> bool {
+        match self.owner {
+            Some((owner, _)) if owner == ip => {
+                self.owner = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        match self.owner {
+            Some((owner, _)) => owner == ip,
+            None => true,
+        }
+    }
+
+    // Records a packet, keeping the claim alive if it came from the owner
+    pub fn packet_received(&mut self, ip: IpAddr, now: Instant) {
+        if let Some((owner, last_packet)) = self.owner.as_mut() {
+            if *owner == ip {
+                *last_packet = now;
+            }
+        }
+    }
+
+    // Drops the claim once the owner has been idle for the timeout, returning the owner it expired
+    pub fn check(&mut self, now: Instant) -> Option<IpAddr> {
+        match self.owner {
+            Some((owner, last_packet)) if !self.timeout.is_zero() && now.duration_since(last_packet) >= self.timeout => {
+                self.owner = None;
+                Some(owner)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn get_owner(&self) -> Option<IpAddr> {
+        self.owner.map(|(owner, _)| owner)
+    }
+
+    // The owner as sent in replies, unspecified while unclaimed
+    pub fn owner_v4(&self) -> Ipv4Addr {
+        match self.get_owner() {
+            Some(IpAddr::V4(ip)) => ip,
+            Some(IpAddr::V6(ip)) => ip.to_ipv4_mapped().unwrap_or(Ipv4Addr::UNSPECIFIED),
+            None => Ipv4Addr::UNSPECIFIED,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    const OWNER: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20));
+    const OTHER: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 21));
+
+    #[test]
+    fn unclaimed_allows_every_client() {
+        let session = Session::new(TIMEOUT);
+        assert!(session.allows(OWNER) && session.allows(OTHER));
+        assert_eq!(session.get_owner(), None);
+        assert_eq!(session.owner_v4(), Ipv4Addr::UNSPECIFIED);
+    }
+
+    #[test]
+    fn claim_only_allows_the_owner() {
+        let mut session = Session::new(TIMEOUT);
+        assert!(session.claim(OWNER, Instant::now()));
+        assert!(session.allows(OWNER));
+        assert!(!session.allows(OTHER));
+        assert!(!session.claim(OTHER, Instant::now()));
+        assert_eq!(session.owner_v4(), Ipv4Addr::new(192, 168, 1, 20));
+    }
+
+    #[test]
+    fn only_the_owner_releases_the_claim() {
+        let mut session = Session::new(TIMEOUT);
+        session.claim(OWNER, Instant::now());
+        assert!(!session.release(OTHER));
+        assert_eq!(session.get_owner(), Some(OWNER));
+        assert!(session.release(OWNER));
+        assert!(!session.release(OWNER));
+        assert_eq!(session.get_owner(), None);
+    }
+
+    #[test]
+    fn claim_expires_after_the_timeout() {
+        let mut session = Session::new(TIMEOUT);
+        let start = Instant::now();
+        session.claim(OWNER, start);
+        assert_eq!(session.check(start + TIMEOUT - Duration::from_millis(1)), None);
+        assert_eq!(session.check(start + TIMEOUT), Some(OWNER));
+        assert_eq!(session.check(start + TIMEOUT), None);
+        assert!(session.allows(OTHER));
+    }
+
+    #[test]
+    fn owner_packets_keep_the_claim_alive() {
+        let mut session = Session::new(TIMEOUT);
+        let start = Instant::now();
+        session.claim(OWNER, start);
+        session.packet_received(OWNER, start + Duration::from_secs(4));
+        session.packet_received(OTHER, start + Duration::from_secs(8));
+        assert_eq!(session.check(start + Duration::from_secs(8)), None);
+        assert_eq!(session.check(start + Duration::from_secs(9)), Some(OWNER));
+    }
+
+    #[test]
+    fn claiming_again_renews_the_claim() {
+        let mut session = Session::new(TIMEOUT);
+        let start = Instant::now();
+        session.claim(OWNER, start);
+        assert!(session.claim(OWNER, start + Duration::from_secs(4)));
+        assert_eq!(session.check(start + TIMEOUT), None);
+    }
+
+    #[test]
+    fn zero_timeout_never_expires() {
+        let mut session = Session::new(Duration::ZERO);
+        let start = Instant::now();
+        session.claim(OWNER, start);
+        assert_eq!(session.check(start + Duration::from_secs(3600)), None);
+        assert_eq!(session.get_owner(), Some(OWNER));
+    }
+
+    #[test]
+    fn another_client_takes_over_once_released_or_expired() {
+        let mut session = Session::new(TIMEOUT);
+        let start = Instant::now();
+        session.claim(OWNER, start);
+        session.release(OWNER);
+        assert!(session.claim(OTHER, start));
+        assert!(!session.allows(OWNER));
+
+        assert_eq!(session.check(start + TIMEOUT), Some(OTHER));
+        assert!(session.claim(OWNER, start + TIMEOUT));
+        assert_eq!(session.get_owner(), Some(OWNER));
+    }
+
+    #[test]
+    fn owner_v4_unmaps_an_ipv6_owner() {
+        let mut session = Session::new(TIMEOUT);
+        session.claim(IpAddr::V6(Ipv4Addr::new(10, 0, 0, 5).to_ipv6_mapped()), Instant::now());
+        assert_eq!(session.owner_v4(), Ipv4Addr::new(10, 0, 0, 5));
+    }
+}
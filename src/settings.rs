@@ -0,0 +1,573 @@
+#[cfg(feature = "sim")]
+use std::collections::HashMap;
+
+#[cfg(not(feature = "sim"))]
+use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+use log::{info, warn};
+
+use crate::auth;
+use crate::battery::{self, BatteryConfig};
+use crate::backend::DriverError;
+use crate::current::{self, CurrentCalibration};
+use crate::feedback::{self, FeedbackCalibration};
+use crate::pages::{self, DisplayConfig};
+use crate::preset::{self, LastPose, Preset};
+use crate::remote_log::{self, LogLevels};
+use crate::servo::{self, Servo};
+use crate::trajectory::{self, Keyframe, Timed};
+
+#[cfg(not(feature = "sim"))]
+const NAMESPACE: &str = "limb";
+const CALIBRATION_VERSION: u8 = 5; // Bump when the calibration blob layout changes
+const CALIBRATION_SIZE: usize = 22;
+// Older layouts are still loaded, fields they lack take their defaults. Up to version 4 the limits were whole degrees
+const CALIBRATION_V1_SIZE: usize = 17; // No reversed flag
+const CALIBRATION_V2_SIZE: usize = 18; // No detach timeout
+const CALIBRATION_V3_SIZE: usize = 20; // No acceleration limit
+#[cfg(not(feature = "sim"))]
+const WIFI_KEY: &str = "wifi";
+const AUTH_KEY: &str = "auth";
+const CRASH_KEY: &str = "crash";
+const BATTERY_KEY: &str = "battery";
+const DISPLAY_KEY: &str = "display";
+const LOG_LEVELS_KEY: &str = "loglevels";
+const SPEED_KEY: &str = "speed";
+const LAST_POSE_KEY: &str = "lastpose";
+const MIRROR_KEY: &str = "mirror";
+const SETUP_KEY: &str = "setup";
+const BUZZER_KEY: &str = "buzzer";
+#[cfg(not(feature = "sim"))]
+pub const MAX_SSID_SIZE: usize = 32; // 802.11 limits
+#[cfg(not(feature = "sim"))]
+pub const MAX_PSK_SIZE: usize = 64;
+pub const MAX_CRASH_SIZE: usize = 96; // Bytes kept of a panic message and its location
+
+// Per-servo calibration, duties are fractions of the LEDC max duty so they survive a resolution change
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Calibration {
+    pub min_duty: f32,
+    pub max_duty: f32,
+    pub trim: i16,
+    pub reversed: bool,
+    pub min_limit: u16, // Tenths of a degree
+    pub max_limit: u16,
+    pub speed: u16,
+    pub detach_s: u16,
+    pub accel: u16, // Degrees per second squared, 0 is unlimited
+}
+
+impl Calibration {
+    // Compiled defaults, limits cover the full travel and speed matches Servo::new
+    pub fn new(min_duty: f32, max_duty: f32, max_angle_degrees: u16) -> Calibration {
+        Calibration {
+            min_duty,
+            max_duty,
+            trim: 0,
+            reversed: false,
+            min_limit: 0,
+            max_limit: servo::from_degrees(max_angle_degrees),
+            speed: 100,
+            detach_s: 0,
+            accel: 0,
+        }
+    }
+
+    pub fn from_servo(servo: &Servo) -> Calibration {
+        let (min_duty, max_duty) = servo.get_duty_range();
+        let (min_limit, max_limit) = servo.get_limits();
+        Calibration {
+            min_duty,
+            max_duty,
+            trim: servo.get_trim(),
+            reversed: servo.is_reversed(),
+            min_limit,
+            max_limit,
+            speed: servo.get_speed(),
+            detach_s: servo.get_detach_timeout(),
+            accel: servo.get_accel(),
+        }
+    }
+
+    // Applies everything except the duty range, which is fixed when the servo is created
+    pub fn apply(&self, servo: &mut Servo) {
+        servo.set_speed(self.speed);
+        servo.set_detach_timeout(self.detach_s);
+        servo.set_accel(self.accel);
+        servo.set_limits(self.min_limit, self.max_limit);
+        // The servo is not energized yet at boot, so this cannot touch the driver
+        let _ = servo.set_trim(self.trim);
+        let _ = servo.set_reversed(self.reversed);
+    }
+
+    // Layout: version, min duty (f32), max duty (f32), trim (i16), min limit (u16), max limit (u16), speed (u16), reversed (u8),
+    // detach timeout (u16), acceleration (u16), all little-endian
+    pub fn to_bytes(self) -> [u8; CALIBRATION_SIZE] {
+        let mut bytes = [0u8; CALIBRATION_SIZE];
+        bytes[0] = CALIBRATION_VERSION;
+        bytes[1..5].copy_from_slice(&self.min_duty.to_le_bytes());
+        bytes[5..9].copy_from_slice(&self.max_duty.to_le_bytes());
+        bytes[9..11].copy_from_slice(&self.trim.to_le_bytes());
+        bytes[11..13].copy_from_slice(&self.min_limit.to_le_bytes());
+        bytes[13..15].copy_from_slice(&self.max_limit.to_le_bytes());
+        bytes[15..17].copy_from_slice(&self.speed.to_le_bytes());
+        bytes[17] = self.reversed as u8;
+        bytes[18..20].copy_from_slice(&self.detach_s.to_le_bytes());
+        bytes[20..22].copy_from_slice(&self.accel.to_le_bytes());
+        bytes
+    }
+
+    // Returns None for blobs of the wrong size or version, or with a duty range that can't be right
+    pub fn from_bytes(bytes: &[u8]) -> Option<Calibration> {
+        match (bytes.first(), bytes.len()) {
+            (Some(&CALIBRATION_VERSION), CALIBRATION_SIZE)
+            | (Some(4), CALIBRATION_SIZE)
+            | (Some(1), CALIBRATION_V1_SIZE)
+            | (Some(2), CALIBRATION_V2_SIZE)
+            | (Some(3), CALIBRATION_V3_SIZE) => {}
+            _ => return None,
+        }
+        let f32_at = |i: usize| f32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        let limit_at = |i: usize| match bytes[0] {
+            CALIBRATION_VERSION => u16_at(i),
+            _ => servo::from_degrees(u16_at(i)),
+        };
+        let calibration = Calibration {
+            min_duty: f32_at(1),
+            max_duty: f32_at(5),
+            trim: i16::from_le_bytes([bytes[9], bytes[10]]),
+            reversed: bytes.get(17).is_some_and(|&reversed| reversed != 0),
+            min_limit: limit_at(11),
+            max_limit: limit_at(13),
+            speed: u16_at(15),
+            detach_s: if bytes.len() >= CALIBRATION_V3_SIZE { u16_at(18) } else { 0 },
+            accel: if bytes.len() >= CALIBRATION_SIZE { u16_at(20) } else { 0 },
+        };
+        let valid_duty = |duty: f32| duty.is_finite() && (0.0..=1.0).contains(&duty);
+        if !valid_duty(calibration.min_duty)
+            || !valid_duty(calibration.max_duty)
+            || calibration.min_duty >= calibration.max_duty
+            || calibration.min_limit > calibration.max_limit
+        {
+            return None;
+        }
+        Some(calibration)
+    }
+}
+
+// Network credentials entered through the setup portal, they take precedence over the compiled ones
+#[cfg(not(feature = "sim"))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WifiCredentials {
+    pub ssid: String,
+    pub psk: String,
+}
+
+#[cfg(not(feature = "sim"))]
+impl WifiCredentials {
+    // Layout: SSID length, SSID, then the password filling the rest
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(1 + self.ssid.len() + self.psk.len());
+        bytes.push(self.ssid.len() as u8);
+        bytes.extend_from_slice(self.ssid.as_bytes());
+        bytes.extend_from_slice(self.psk.as_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<WifiCredentials> {
+        let (&ssid_len, rest) = bytes.split_first()?;
+        if ssid_len == 0 || ssid_len as usize > rest.len() {
+            return None;
+        }
+        let (ssid, psk) = rest.split_at(ssid_len as usize);
+        Some(WifiCredentials {
+            ssid: String::from_utf8(ssid.to_vec()).ok()?,
+            psk: String::from_utf8(psk.to_vec()).ok()?,
+        })
+    }
+}
+
+// Settings kept in the NVS partition across reboots
+pub struct Settings {
+    #[cfg(not(feature = "sim"))]
+    nvs: EspDefaultNvs,
+    #[cfg(feature = "sim")]
+    blobs: HashMap<String, Vec<u8>>, // The simulator only keeps settings until it exits
+}
+
+impl Settings {
+    #[cfg(not(feature = "sim"))]
+    pub fn new(partition: EspDefaultNvsPartition) -> Result<Settings, DriverError> {
+        Ok(Settings {
+            nvs: EspDefaultNvs::new(partition, NAMESPACE, true)?,
+        })
+    }
+
+    #[cfg(feature = "sim")]
+    pub fn new() -> Settings {
+        Settings { blobs: HashMap::new() }
+    }
+
+    #[cfg(not(feature = "sim"))]
+    fn get_blob<'a>(&self, key: &str, buf: &'a mut [u8]) -> Result<Option<&'a [u8]>, DriverError> {
+        self.nvs.get_blob(key, buf)
+    }
+
+    #[cfg(feature = "sim")]
+    fn get_blob<'a>(&self, key: &str, buf: &'a mut [u8]) -> Result<Option<&'a [u8]>, DriverError> {
+        Ok(self.blobs.get(key).map(|blob| {
+            let len = blob.len().min(buf.len());
+            buf[..len].copy_from_slice(&blob[..len]);
+            &buf[..len]
+        }))
+    }
+
+    #[cfg(not(feature = "sim"))]
+    fn set_blob(&mut self, key: &str, blob: &[u8]) -> Result<(), DriverError> {
+        self.nvs.set_blob(key, blob)
+    }
+
+    #[cfg(feature = "sim")]
+    fn set_blob(&mut self, key: &str, blob: &[u8]) -> Result<(), DriverError> {
+        self.blobs.insert(key.to_string(), blob.to_vec());
+        Ok(())
+    }
+
+    // Returns true if there was a blob to remove
+    #[cfg(not(feature = "sim"))]
+    fn remove_blob(&mut self, key: &str) -> Result<bool, DriverError> {
+        self.nvs.remove(key)
+    }
+
+    #[cfg(feature = "sim")]
+    fn remove_blob(&mut self, key: &str) -> Result<bool, DriverError> {
+        Ok(self.blobs.remove(key).is_some())
+    }
+
+    // Loads a servo's stored calibration, falling back to the defaults if it is missing or corrupt
+    pub fn load_calibration(&self, index: usize, name: &str, defaults: Calibration) -> Calibration {
+        let mut buf = [0u8; CALIBRATION_SIZE];
+        match self.get_blob(&calibration_key(index), &mut buf) {
+            Ok(Some(bytes)) => match Calibration::from_bytes(bytes) {
+                Some(calibration) => {
+                    info!("{} calibration loaded from NVS", name);
+                    calibration
+                }
+                None => {
+                    warn!("{} has a corrupt stored calibration, using defaults", name);
+                    defaults
+                }
+            },
+            Ok(None) => {
+                info!("{} has no stored calibration, using defaults", name);
+                defaults
+            }
+            Err(e) => {
+                warn!("Failed to read {} calibration, using defaults: {}", name, e);
+                defaults
+            }
+        }
+    }
+
+    pub fn save_calibration(&mut self, index: usize, calibration: &Calibration) -> Result<(), DriverError> {
+        self.set_blob(&calibration_key(index), &calibration.to_bytes())
+    }
+
+    // A joint's feedback readings at either end of its travel, None until the calibration command has set them.
+    // A corrupt one is reported and treated as missing
+    pub fn load_feedback(&self, index: usize) -> Result<Option<FeedbackCalibration>, DriverError> {
+        let mut buf = [0u8; feedback::CALIBRATION_SIZE];
+        Ok(match self.get_blob(&feedback_key(index), &mut buf)? {
+            Some(bytes) => {
+                let calibration = FeedbackCalibration::from_bytes(bytes);
+                if calibration.is_none() {
+                    warn!("Feedback calibration {} in NVS is corrupt", index);
+                }
+                calibration
+            }
+            None => None,
+        })
+    }
+
+    pub fn save_feedback(&mut self, index: usize, calibration: &FeedbackCalibration) -> Result<(), DriverError> {
+        self.set_blob(&feedback_key(index), &calibration.to_bytes())
+    }
+
+    // A current sensor's zero and scale, None until the current command has set them. Sensors are numbered in the
+    // order of the first joint on each. A corrupt one is reported and treated as missing
+    pub fn load_current(&self, sensor: usize) -> Result<Option<CurrentCalibration>, DriverError> {
+        let mut buf = [0u8; current::CALIBRATION_SIZE];
+        Ok(match self.get_blob(&current_key(sensor), &mut buf)? {
+            Some(bytes) => {
+                let calibration = CurrentCalibration::from_bytes(bytes);
+                if calibration.is_none() {
+                    warn!("Current calibration {} in NVS is corrupt", sensor);
+                }
+                calibration
+            }
+            None => None,
+        })
+    }
+
+    pub fn save_current(&mut self, sensor: usize, calibration: &CurrentCalibration) -> Result<(), DriverError> {
+        self.set_blob(&current_key(sensor), &calibration.to_bytes())
+    }
+
+    // Loads a stored trajectory and its capture time, None if the slot is empty. A corrupt one is reported and treated
+    // as empty
+    pub fn load_trajectory(&self, slot: u8) -> Result<Option<Timed>, DriverError> {
+        let mut buf = [0u8; trajectory::MAX_BLOB_SIZE];
+        Ok(match self.get_blob(&trajectory_key(slot), &mut buf)? {
+            Some(bytes) => {
+                let frames = trajectory::from_bytes(bytes);
+                if frames.is_none() {
+                    warn!("Trajectory {} in NVS is corrupt", slot);
+                }
+                frames
+            }
+            None => None,
+        })
+    }
+
+    pub fn save_trajectory(
+        &mut self,
+        slot: u8,
+        frames: &[Keyframe],
+        captured_ms: Option<u64>,
+    ) -> Result<(), DriverError> {
+        self.set_blob(&trajectory_key(slot), &trajectory::to_bytes(frames, captured_ms))
+    }
+
+    // Loads a stored pose, None if the slot is empty. A corrupt one is reported and treated as empty
+    pub fn load_preset(&self, slot: u8) -> Result<Option<Preset>, DriverError> {
+        let mut buf = [0u8; preset::MAX_BLOB_SIZE];
+        Ok(match self.get_blob(&preset_key(slot), &mut buf)? {
+            Some(bytes) => {
+                let preset = Preset::from_bytes(bytes);
+                if preset.is_none() {
+                    warn!("Pose {} in NVS is corrupt", slot);
+                }
+                preset
+            }
+            None => None,
+        })
+    }
+
+    pub fn save_preset(&mut self, slot: u8, preset: &Preset) -> Result<(), DriverError> {
+        self.set_blob(&preset_key(slot), &preset.to_bytes())
+    }
+
+    // Returns false if the slot was already empty
+    pub fn clear_preset(&mut self, slot: u8) -> Result<bool, DriverError> {
+        self.remove_blob(&preset_key(slot))
+    }
+
+    // None until the setup portal has been used. Corrupt credentials are reported and treated as missing
+    #[cfg(not(feature = "sim"))]
+    pub fn load_wifi(&self) -> Result<Option<WifiCredentials>, DriverError> {
+        let mut buf = [0u8; 1 + MAX_SSID_SIZE + MAX_PSK_SIZE];
+        Ok(match self.get_blob(WIFI_KEY, &mut buf)? {
+            Some(bytes) => {
+                let credentials = WifiCredentials::from_bytes(bytes);
+                if credentials.is_none() {
+                    warn!("WiFi credentials in NVS are corrupt");
+                }
+                credentials
+            }
+            None => None,
+        })
+    }
+
+    #[cfg(not(feature = "sim"))]
+    pub fn save_wifi(&mut self, credentials: &WifiCredentials) -> Result<(), DriverError> {
+        self.set_blob(WIFI_KEY, &credentials.to_bytes())
+    }
+
+    // The packet authentication key, None until one has been stored
+    pub fn load_auth_key(&self) -> Result<Option<Vec<u8>>, DriverError> {
+        let mut buf = [0u8; auth::MAX_KEY_SIZE];
+        Ok(self.get_blob(AUTH_KEY, &mut buf)?.filter(|key| !key.is_empty()).map(|key| key.to_vec()))
+    }
+
+    #[cfg(not(feature = "sim"))]
+    pub fn save_auth_key(&mut self, key: &[u8]) -> Result<(), DriverError> {
+        self.set_blob(AUTH_KEY, key)
+    }
+
+    // The battery divider and cutoff, None until the config command has set them.
+    // A corrupt one is reported and treated as missing
+    pub fn load_battery(&self) -> Result<Option<BatteryConfig>, DriverError> {
+        let mut buf = [0u8; battery::CONFIG_SIZE];
+        Ok(match self.get_blob(BATTERY_KEY, &mut buf)? {
+            Some(bytes) => {
+                let config = BatteryConfig::from_bytes(bytes);
+                if config.is_none() {
+                    warn!("Battery config in NVS is corrupt");
+                }
+                config
+            }
+            None => None,
+        })
+    }
+
+    pub fn save_battery(&mut self, config: &BatteryConfig) -> Result<(), DriverError> {
+        self.set_blob(BATTERY_KEY, &config.to_bytes())
+    }
+
+    // The brightness and idle timers, None until the config command has set them.
+    // A corrupt one is reported and treated as missing
+    pub fn load_display(&self) -> Result<Option<DisplayConfig>, DriverError> {
+        let mut buf = [0u8; pages::CONFIG_SIZE];
+        Ok(match self.get_blob(DISPLAY_KEY, &mut buf)? {
+            Some(bytes) => {
+                let config = DisplayConfig::from_bytes(bytes);
+                if config.is_none() {
+                    warn!("Display config in NVS is corrupt");
+                }
+                config
+            }
+            None => None,
+        })
+    }
+
+    pub fn save_display(&mut self, config: &DisplayConfig) -> Result<(), DriverError> {
+        self.set_blob(DISPLAY_KEY, &config.to_bytes())
+    }
+
+    // Console log levels, None unless a log target command asked for them to be kept.
+    // A corrupt blob is reported and treated as missing
+    pub fn load_log_levels(&self) -> Result<Option<LogLevels>, DriverError> {
+        let mut buf = [0u8; remote_log::MAX_LEVELS_SIZE];
+        Ok(match self.get_blob(LOG_LEVELS_KEY, &mut buf)? {
+            Some(bytes) => {
+                let levels = LogLevels::from_bytes(bytes);
+                if levels.is_none() {
+                    warn!("Log levels in NVS are corrupt");
+                }
+                levels
+            }
+            None => None,
+        })
+    }
+
+    pub fn save_log_levels(&mut self, levels: &LogLevels) -> Result<(), DriverError> {
+        self.set_blob(LOG_LEVELS_KEY, &levels.to_bytes())
+    }
+
+    // The pose saved as the limb moved, None until the first save. A corrupt one is reported and treated as missing
+    pub fn load_last_pose(&self) -> Result<Option<LastPose>, DriverError> {
+        let mut buf = [0u8; preset::LAST_POSE_SIZE];
+        Ok(match self.get_blob(LAST_POSE_KEY, &mut buf)? {
+            Some(bytes) => {
+                let pose = LastPose::from_bytes(bytes);
+                if pose.is_none() {
+                    warn!("Last pose in NVS is corrupt");
+                }
+                pose
+            }
+            None => None,
+        })
+    }
+
+    pub fn save_last_pose(&mut self, pose: &LastPose) -> Result<(), DriverError> {
+        self.set_blob(LAST_POSE_KEY, &pose.to_bytes())
+    }
+
+    // The speed scale percent, None unless a speed command asked for it to be kept.
+    // A corrupt one is reported and treated as missing
+    pub fn load_speed_scale(&self) -> Result<Option<u8>, DriverError> {
+        let mut buf = [0u8; 1];
+        Ok(match self.get_blob(SPEED_KEY, &mut buf)? {
+            Some(&[percent]) if (1..=servo::FULL_SPEED).contains(&percent) => Some(percent),
+            Some(_) => {
+                warn!("Speed scale in NVS is corrupt");
+                None
+            }
+            None => None,
+        })
+    }
+
+    pub fn save_speed_scale(&mut self, percent: u8) -> Result<(), DriverError> {
+        self.set_blob(SPEED_KEY, &[percent])
+    }
+
+    // Whether moves are mirrored, None unless a mirror command asked for it to be kept.
+    // A corrupt one is reported and treated as missing
+    pub fn load_mirror(&self) -> Result<Option<bool>, DriverError> {
+        let mut buf = [0u8; 1];
+        Ok(match self.get_blob(MIRROR_KEY, &mut buf)? {
+            Some(&[mirrored]) if mirrored <= 1 => Some(mirrored == 1),
+            Some(_) => {
+                warn!("Mirror setting in NVS is corrupt");
+                None
+            }
+            None => None,
+        })
+    }
+
+    pub fn save_mirror(&mut self, mirrored: bool) -> Result<(), DriverError> {
+        self.set_blob(MIRROR_KEY, &[mirrored as u8])
+    }
+
+    // Whether the buzzer is muted, None until a buzzer config command has set it. A corrupt one is reported and
+    // treated as missing
+    pub fn load_buzzer_muted(&self) -> Result<Option<bool>, DriverError> {
+        let mut buf = [0u8; 1];
+        Ok(match self.get_blob(BUZZER_KEY, &mut buf)? {
+            Some(&[muted]) if muted <= 1 => Some(muted == 1),
+            Some(_) => {
+                warn!("Buzzer setting in NVS is corrupt");
+                None
+            }
+            None => None,
+        })
+    }
+
+    pub fn save_buzzer_muted(&mut self, muted: bool) -> Result<(), DriverError> {
+        self.set_blob(BUZZER_KEY, &[muted as u8])
+    }
+
+    // Has the next start bring up the setup portal rather than join a network
+    pub fn request_setup(&mut self) -> Result<(), DriverError> {
+        self.set_blob(SETUP_KEY, &[1])
+    }
+
+    // Whether setup was requested, the request is cleared so only the start after it sees it
+    #[cfg(not(feature = "sim"))]
+    pub fn take_setup_request(&mut self) -> Result<bool, DriverError> {
+        self.remove_blob(SETUP_KEY)
+    }
+
+    // The message of the last panic, kept until the next one replaces it
+    pub fn load_crash(&self) -> Result<Option<String>, DriverError> {
+        let mut buf = [0u8; MAX_CRASH_SIZE];
+        Ok(self.get_blob(CRASH_KEY, &mut buf)?.map(|message| String::from_utf8_lossy(message).into_owned()))
+    }
+
+    // Called from the panic hook
+    #[cfg(not(feature = "sim"))]
+    pub fn save_crash(&mut self, message: &str) -> Result<(), DriverError> {
+        self.set_blob(CRASH_KEY, message.as_bytes())
+    }
+}
+
+// NVS keys are limited to 15 characters, so servos are keyed by index rather than name
+fn calibration_key(index: usize) -> String {
+    format!("servo{}", index)
+}
+
+fn feedback_key(index: usize) -> String {
+    format!("feedback{}", index)
+}
+
+fn current_key(sensor: usize) -> String {
+    format!("current{}", sensor)
+}
+
+fn trajectory_key(slot: u8) -> String {
+    format!("traj{}", slot)
+}
+
+fn preset_key(slot: u8) -> String {
+    format!("pose{}", slot)
+}
@@ -0,0 +1,70 @@
+// I2C bus shared by the SSD1306 in the display task and the PCA9685 servos and INA219 sensors in the control task
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+
+use esp_idf_hal::delay::{TickType, BLOCK};
+use esp_idf_hal::i2c::I2cDriver;
+use esp_idf_sys::EspError;
+
+// 7-bit addresses a scan probes, those either side are reserved
+const FIRST_ADDRESS: u8 = 0x08;
+const LAST_ADDRESS: u8 = 0x77;
+const PROBE_TIMEOUT_MS: u64 = 10; // Long enough for a device holding the clock low to answer at all
+
+// Outside the reserved addresses, so it can be probed or talked to
+pub fn is_valid_address(address: u8) -> bool {
+    (FIRST_ADDRESS..=LAST_ADDRESS).contains(&address)
+}
+
+// Clones all use the same driver, each transfer holds the bus until it completes
+#[derive(Clone)]
+pub struct SharedI2c {
+    driver: Arc<Mutex<I2cDriver<'static>>>,
+}
+
+impl SharedI2c {
+    pub fn new(driver: I2cDriver<'static>) -> SharedI2c {
+        SharedI2c {
+            driver: Arc::new(Mutex::new(driver)),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, I2cDriver<'static>> {
+        // A transfer interrupted by a panic leaves nothing half done in the driver itself
+        self.driver.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn write(&self, address: u8, bytes: &[u8]) -> Result<(), EspError> {
+        self.lock().write(address, bytes, BLOCK)
+    }
+
+    // Writes `bytes`, typically a register, then reads `buffer` back without releasing the bus in between
+    pub fn write_read(&self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), EspError> {
+        self.lock().write_read(address, bytes, buffer, BLOCK)
+    }
+
+    // Every address acknowledging a zero-length write, lowest first. The bus is held for the whole scan
+    pub fn scan(&self) -> Vec<u8> {
+        let mut driver = self.lock();
+        let timeout = TickType::new_millis(PROBE_TIMEOUT_MS).ticks();
+        (FIRST_ADDRESS..=LAST_ADDRESS).filter(|&address| driver.write(address, &[], timeout).is_ok()).collect()
+    }
+
+    // None rather than waiting if another transfer holds the bus, for the panic hook
+    pub fn try_write(&self, address: u8, bytes: &[u8]) -> Option<Result<(), EspError>> {
+        let mut driver = match self.driver.try_lock() {
+            Ok(driver) => driver,
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+            Err(TryLockError::WouldBlock) => return None,
+        };
+        Some(driver.write(address, bytes, BLOCK))
+    }
+}
+
+// Lets the SSD1306 interface use the shared bus in place of the driver
+impl embedded_hal_0_2::blocking::i2c::Write for SharedI2c {
+    type Error = EspError;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        SharedI2c::write(self, address, bytes)
+    }
+}
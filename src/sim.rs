@@ -0,0 +1,399 @@
+// Host simulation: runs the control loop as a normal binary with in-memory servos and display.
+// cargo run --no-default-features --features sim --target x86_64-unknown-linux-gnu
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::{info, LevelFilter, Log, Metadata, Record};
+
+use crate::backend::{Align, DisplayBackend, DriverError, ServoBackend, ServoBar};
+use crate::battery::Battery;
+use crate::boards::{self, PROFILE};
+use crate::button::{Button, EStopButton};
+use crate::buzzer::Buzzer;
+use crate::current::{self, CurrentCalibration, CurrentMonitor};
+use crate::feedback::{self, Feedback, FeedbackCalibration, Reader};
+use crate::gripper::Gripper;
+use crate::history::History;
+use crate::joints::JOINTS;
+use crate::led::StatusLed;
+use crate::link::Link;
+use crate::ota::Updater;
+use crate::protocol;
+use crate::qr::QrCode;
+use crate::remote_log::{self, Console, RemoteLog};
+use crate::servo::{Servo, Tenths};
+use crate::settings::Settings;
+use crate::tick::Tick;
+use crate::{wifi_setup, CONFIG, RECV_TIMEOUT, VERSION_MAJ, VERSION_MIN};
+
+const MAX_DUTY: u32 = 4095; // Matches the 12 bit LEDC timer used on hardware
+pub const FRAME_HZ: u32 = 50; // And its frame rate, whatever timer the joint names
+const IDLE_MA: u32 = 80; // Drawn by a simulated servo holding its angle
+const MOVING_MA: u32 = 600; // And by one whose duty changed since the last reading
+
+// Stands in for the esp-idf error code on hardware
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SimError(pub i32);
+
+impl fmt::Display for SimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "simulated error {}", self.0)
+    }
+}
+
+// Every duty a mock servo has output, shared so a test can read it while the servo is owned by the loop
+pub type DutyHistory = Arc<Mutex<Vec<u32>>>;
+
+pub struct MockServo {
+    duty: u32,
+    enabled: bool,
+    history: DutyHistory,
+}
+
+impl MockServo {
+    pub fn new() -> (MockServo, DutyHistory) {
+        let history = DutyHistory::default();
+        (
+            MockServo {
+                duty: 0,
+                enabled: true,
+                history: history.clone(),
+            },
+            history,
+        )
+    }
+
+    fn record(&self, duty: u32) {
+        self.history.lock().unwrap().push(duty);
+    }
+}
+
+impl ServoBackend for MockServo {
+    fn set_duty(&mut self, duty: u32) -> Result<(), DriverError> {
+        if duty > MAX_DUTY {
+            return Err(SimError(0x102)); // ESP_ERR_INVALID_ARG, like LedcDriver
+        }
+        self.duty = duty;
+        if self.enabled {
+            self.record(duty);
+        }
+        Ok(())
+    }
+
+    fn get_duty(&self) -> u32 {
+        self.duty
+    }
+
+    fn get_max_duty(&self) -> u32 {
+        MAX_DUTY
+    }
+
+    fn disable(&mut self) -> Result<(), DriverError> {
+        self.enabled = false;
+        self.record(0);
+        Ok(())
+    }
+
+    fn enable(&mut self) -> Result<(), DriverError> {
+        self.enabled = true;
+        self.record(self.duty);
+        Ok(())
+    }
+}
+
+// Every joint on a mock servo at its default calibration, for the host tests
+#[cfg(test)]
+pub fn mock_servos() -> (Vec<Servo>, Vec<DutyHistory>) {
+    JOINTS
+        .iter()
+        .map(|joint| {
+            let calibration = joint.default_calibration(FRAME_HZ);
+            let (driver, history) = MockServo::new();
+            let mut servo = Servo::new(
+                joint.name.to_string(),
+                driver,
+                FRAME_HZ,
+                calibration.min_duty,
+                calibration.max_duty,
+                joint.max_angle,
+            );
+            calibration.apply(&mut servo);
+            (servo, history)
+        })
+        .unzip()
+}
+
+// Keeps everything that would have been drawn on the OLED
+#[derive(Default)]
+pub struct MockDisplay {
+    pub history: Vec<String>,
+}
+
+impl DisplayBackend for MockDisplay {
+    fn draw_new_text(&mut self, _x: i32, _y: i32, text: &str) {
+        info!("Display: {}", text.replace('\n', " | "));
+        self.history.push(text.to_string());
+    }
+
+    fn draw_lines(&mut self, lines: &[&str], _align: Align) {
+        info!("Display: {}", lines.join(" | "));
+        self.history.push(lines.join("\n"));
+    }
+
+    fn draw_banner(&mut self, banner: &str, text: &str) {
+        let text = format!("{}\n{}", banner, text);
+        info!("Display: {}", text.replace('\n', " | "));
+        self.history.push(text);
+    }
+
+    // Bars are logged as their angles, the goal and the angle of a full bar
+    fn draw_servo_bars(&mut self, bars: &[ServoBar]) {
+        let text = bars
+            .iter()
+            .map(|bar| format!("{} {}->{}/{}", bar.name, Tenths(bar.angle), Tenths(bar.goal), Tenths(bar.max)))
+            .collect::<Vec<String>>()
+            .join("\n");
+        info!("Display: bars {}", text.replace('\n', " | "));
+        self.history.push(text);
+    }
+
+    // The code itself is only logged by its size
+    fn draw_qr(&mut self, code: &QrCode, caption: &[&str]) {
+        let text = format!("QR {}x{}\n{}", code.size(), code.size(), caption.join("\n"));
+        info!("Display: {}", text.replace('\n', " | "));
+        self.history.push(text);
+    }
+
+    fn set_brightness(&mut self, brightness: u8) {
+        info!("Display: brightness {}", brightness);
+    }
+
+    fn set_display_on(&mut self, on: bool) {
+        info!("Display: {}", if on { "on" } else { "off" });
+    }
+
+    fn set_flipped(&mut self, flipped: bool) {
+        info!("Display: rotated {}", if flipped { 180 } else { 0 });
+    }
+
+    // As many lines of the 5x8 font as the configured panel holds
+    fn rows(&self) -> usize {
+        CONFIG.display_height as usize / 8
+    }
+}
+
+const CONSOLE_LEVEL: LevelFilter = LevelFilter::Info;
+
+// Prints log records to stdout, the EspLogger equivalent for the host. The levels are left to RemoteLog
+struct StdoutLogger;
+
+impl Log for StdoutLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        println!("{} ({}) {}", record.level(), record.target(), record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+impl Console for StdoutLogger {
+    fn apply_level(&self, _target: Option<&str>, _level: LevelFilter) {}
+}
+
+static LOGGER: RemoteLog<StdoutLogger> = RemoteLog::new(StdoutLogger);
+
+// There is no chip to reset, so the reboot command ends the simulator
+pub fn restart() -> ! {
+    info!("Simulated restart, exiting");
+    std::process::exit(0);
+}
+
+// A pot that follows the servo exactly, the last duty output mapped across the uncalibrated feedback range
+fn mock_feedback(history: DutyHistory, min_percent: f32, max_percent: f32) -> Reader {
+    let min_duty = (MAX_DUTY as f32 * min_percent).round() as u32;
+    let max_duty = (MAX_DUTY as f32 * max_percent).round() as u32;
+    Box::new(move || {
+        let duty = history.lock().unwrap().last().copied().unwrap_or(min_duty).clamp(min_duty, max_duty);
+        let span = max_duty.saturating_sub(min_duty).max(1);
+        let reading = (duty - min_duty) * FeedbackCalibration::DEFAULT.max_mv as u32 / span;
+        Ok(reading as u16)
+    })
+}
+
+// A sensor reading the joints on it draw, more while their duties are changing, turned back through the sensor's
+// calibration into the reading that gives
+fn mock_current(histories: Vec<DutyHistory>, calibration: CurrentCalibration) -> current::Reader {
+    let mut last: Vec<Option<u32>> = vec![None; histories.len()];
+    Box::new(move || {
+        let mut current_ma = 0;
+        for (history, last) in histories.iter().zip(last.iter_mut()) {
+            let duty = history.lock().unwrap().last().copied();
+            current_ma += match duty {
+                None | Some(0) => 0,
+                duty if duty == *last => IDLE_MA,
+                _ => MOVING_MA,
+            };
+            *last = duty;
+        }
+        Ok(calibration.zero_uv + (current_ma as u64 * 1_000_000 / calibration.ma_per_v.max(1) as u64) as i32)
+    })
+}
+
+pub fn main() {
+    remote_log::install(&LOGGER, CONSOLE_LEVEL);
+    info!("Starting simulator v{}.{}, protocol {}", VERSION_MAJ, VERSION_MIN, protocol::PROTOCOL_VERSION);
+
+    let settings = Settings::new();
+    let feedback_pins = feedback::parse_pins(CONFIG.feedback_pins, JOINTS.len());
+    let mut histories = Vec::with_capacity(JOINTS.len());
+    let servos: Vec<Servo> = JOINTS
+        .iter()
+        .zip(feedback_pins)
+        .enumerate()
+        .map(|(index, (joint, feedback_pin))| {
+            let calibration = settings.load_calibration(index, joint.name, joint.default_calibration(FRAME_HZ));
+            let (driver, history) = MockServo::new();
+            histories.push(history.clone());
+            match boards::servo_pin(index) {
+                Some(pin) => info!("{} simulated in place of GPIO{} on LEDC timer {}", joint.name, pin, joint.timer),
+                None => info!("{} simulated without a pin on the {}", joint.name, PROFILE.name),
+            }
+            let mut servo = Servo::new(
+                joint.name.to_string(),
+                driver,
+                FRAME_HZ,
+                calibration.min_duty,
+                calibration.max_duty,
+                joint.max_angle,
+            );
+            calibration.apply(&mut servo);
+            if let Some(pin) = feedback_pin {
+                info!("{} feedback simulated in place of GPIO{}", joint.name, pin);
+                let read = mock_feedback(history, calibration.min_duty, calibration.max_duty);
+                servo.set_feedback(Feedback::new(read, feedback::load_calibration(Some(&settings), index, joint.name)));
+            }
+            servo
+        })
+        .collect();
+
+    let taken_pins: Vec<u8> = feedback::parse_pins(CONFIG.feedback_pins, JOINTS.len()).into_iter().flatten().collect();
+    let mut current = CurrentMonitor::new();
+    for (source, joints) in current::group(&current::parse_sources(CONFIG.current_sensors, JOINTS.len(), &taken_pins)) {
+        info!("Current sensor simulated in place of {}", source);
+        // Readings are made with the calibration the sensor starts with, a new one changes what they come to
+        let calibration = current::load_calibration(Some(&settings), current.sensor_count() as usize, source);
+        let read = mock_current(joints.iter().map(|&joint| histories[joint].clone()).collect(), calibration);
+        current.add(source, joints, read, calibration);
+    }
+
+    let gripper = match CONFIG.gripper_backend {
+        "" => None,
+        _ => {
+            let (driver, _) = MockServo::new();
+            info!("Gripper simulated in place of GPIO{}", PROFILE.gripper_pin);
+            Some(Gripper::new(driver, FRAME_HZ))
+        }
+    };
+
+    // A sleeping thread stands in for the hardware timer
+    let tick = Tick::new(CONFIG.servo_tick_hz);
+    let timer_tick = tick.clone();
+    thread::spawn(move || loop {
+        thread::sleep(timer_tick.get_period());
+        timer_tick.raise();
+    });
+
+    let socket = match wifi_setup::init_socket(CONFIG.udp_port, Some(RECV_TIMEOUT)) {
+        Ok(socket) => socket,
+        Err(e) => panic!("Unable to bind socket: {}", e), // Probably another simulator already running
+    };
+    // No ADC to read or button to press, the battery voltage is never known. The LED and buzzer only log
+    let board = crate::Board {
+        tick,
+        battery: Battery::default(),
+        estop_button: EStopButton::none(),
+        button: Button::none(),
+        buzzer: Buzzer::none(),
+        status_led: StatusLed::none(),
+        updater: Updater::default(),
+        gripper,
+        history: History::take(),
+        current,
+        i2c_devices: Vec::new(), // There is no bus
+    };
+    crate::run(Some(socket), servos, MockDisplay::default(), Some(settings), Link::new(Ipv4Addr::LOCALHOST), board)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::servo::{self, Easing, POLL_HZ};
+
+    // A servo at 0 sent to 90 degrees at 90 degrees per second, with every duty it output on the way
+    fn speed_move() -> (Servo, Vec<u32>) {
+        let (mut servos, histories) = mock_servos();
+        let mut servo = servos.swap_remove(0);
+        servo.set_speed(90);
+        servo.set_angle(900).unwrap();
+        for _ in 0..POLL_HZ {
+            servo.poll(false).unwrap();
+        }
+        let duties = histories[0].lock().unwrap().clone();
+        (servo, duties)
+    }
+
+    // The same move timed to take a second instead
+    fn timed_move() -> (Servo, Vec<u32>) {
+        let (mut servos, histories) = mock_servos();
+        let mut servo = servos.swap_remove(0);
+        servo.set_speed(10);
+        servo.set_angle_timed(900, 1000, Easing::Linear).unwrap();
+        for _ in 0..POLL_HZ {
+            servo.poll(false).unwrap();
+        }
+        let duties = histories[0].lock().unwrap().clone();
+        (servo, duties)
+    }
+
+    fn duty_at(servo: &Servo, angle: u16) -> u32 {
+        let (min_duty, max_duty) = servo.get_duty_endpoints();
+        servo::angle_to_duty(angle, servo.get_max_angle(), min_duty, max_duty - min_duty)
+    }
+
+    #[test]
+    fn speed_move_outputs_a_duty_every_poll_and_arrives_on_time() {
+        let (servo, duties) = speed_move();
+        // The zero written as the servo was set up, then one per poll
+        assert_eq!(duties.len(), 1 + POLL_HZ as usize);
+        assert_eq!(duties[0], 0);
+        assert_eq!(duties.last(), Some(&duty_at(&servo, 900)));
+        assert_eq!(servo.get_angle(), 900);
+        assert!(!servo.is_moving());
+        assert!(duties[1..].windows(2).all(|pair| pair[0] <= pair[1]), "{duties:?}");
+    }
+
+    #[test]
+    fn speed_move_steps_evenly() {
+        let (servo, duties) = speed_move();
+        let (start, end) = (duty_at(&servo, 0), duty_at(&servo, 900));
+        let halfway = duties[POLL_HZ as usize / 2];
+        assert!(halfway.abs_diff((start + end) / 2) <= 2, "{halfway} between {start} and {end}");
+    }
+
+    #[test]
+    fn timed_move_arrives_on_time_whatever_the_speed() {
+        let (servo, duties) = timed_move();
+        assert_eq!(duties.len(), 1 + POLL_HZ as usize);
+        assert_eq!(duties.last(), Some(&duty_at(&servo, 900)));
+        assert_eq!(servo.get_angle(), 900);
+        assert!(!servo.is_moving());
+        let (start, end) = (duty_at(&servo, 0), duty_at(&servo, 900));
+        let halfway = duties[POLL_HZ as usize / 2];
+        assert!(halfway.abs_diff((start + end) / 2) <= 2, "{halfway} between {start} and {end}");
+    }
+}
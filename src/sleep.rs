@@ -0,0 +1,82 @@
+// Deep sleep between sessions, for a limb on a battery. The chip sleeps until the page button is pressed or the sleep
+// command's timer runs out, waking is a fresh boot and the wake reason tells the two apart
+#[cfg(not(feature = "sim"))]
+use log::error;
+#[cfg(feature = "sim")]
+use log::info;
+
+#[cfg(not(feature = "sim"))]
+use crate::boards;
+
+// Why the chip last woke from deep sleep, as reported in the status reply
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "sim", allow(dead_code))] // The simulator never sleeps
+pub enum WakeReason {
+    NotSlept = 0, // Started some other way, see watchdog::ResetReason
+    Button = 1,
+    Timer = 2,
+    Other = 3,
+}
+
+#[cfg(not(feature = "sim"))]
+pub fn wake_reason() -> WakeReason {
+    #[allow(non_upper_case_globals)]
+    match unsafe { esp_idf_sys::esp_sleep_get_wakeup_cause() } {
+        esp_idf_sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_UNDEFINED => WakeReason::NotSlept,
+        esp_idf_sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_EXT0 => WakeReason::Button,
+        esp_idf_sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_TIMER => WakeReason::Timer,
+        _ => WakeReason::Other,
+    }
+}
+
+#[cfg(feature = "sim")]
+pub fn wake_reason() -> WakeReason {
+    WakeReason::NotSlept
+}
+
+// Only an RTC GPIO can wake the chip, and the button must close to ground on one
+#[cfg(not(feature = "sim"))]
+pub fn button_wakes() -> bool {
+    let pin = boards::button_pin();
+    pin != 0 && unsafe { esp_idf_sys::rtc_gpio_is_valid_gpio(pin as i32) }
+}
+
+// The simulated button has no pin, only a timer wakes the simulator
+#[cfg(feature = "sim")]
+pub fn button_wakes() -> bool {
+    false
+}
+
+// Arms the button and the timer, if any, and sleeps. A wake source that can't be armed is logged and left out, the
+// chip still sleeps on whatever else wakes it
+#[cfg(not(feature = "sim"))]
+pub fn deep_sleep(wake_s: Option<u32>) -> ! {
+    if button_wakes() {
+        let pin = boards::button_pin() as i32;
+        // The digital pull-up is off in deep sleep, the RTC one holds the pin high until the button closes
+        let armed = esp_idf_sys::esp!(unsafe { esp_idf_sys::rtc_gpio_pullup_en(pin) })
+            .and_then(|_| esp_idf_sys::esp!(unsafe { esp_idf_sys::rtc_gpio_pulldown_dis(pin) }))
+            .and_then(|_| esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_sleep_enable_ext0_wakeup(pin, 0) }));
+        match armed {
+            Ok(_) => {},
+            Err(e) => error!("Failed to arm the button to wake the chip: {}", e),
+        }
+    }
+    if let Some(wake_s) = wake_s {
+        match esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_sleep_enable_timer_wakeup(wake_s as u64 * 1_000_000) }) {
+            Ok(_) => {},
+            Err(e) => error!("Failed to arm the wake timer: {}", e),
+        }
+    }
+    unsafe { esp_idf_sys::esp_deep_sleep_start() }
+}
+
+// Exits, as a restart does, the next run stands in for the wake
+#[cfg(feature = "sim")]
+pub fn deep_sleep(wake_s: Option<u32>) -> ! {
+    match wake_s {
+        Some(wake_s) => info!("Simulated deep sleep for {} s, exiting", wake_s),
+        None => info!("Simulated deep sleep until the button, exiting"),
+    }
+    std::process::exit(0);
+}
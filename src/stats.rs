@@ -0,0 +1,99 @@
+// Packet counters and system readings reported by the telemetry command
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PacketCounts {
+    pub received: u32,           // Every datagram read from the socket
+    pub rejected: u32,           // Well formed but refused: stale, queued too deep, or motion while e-stopped or calibrating
+    pub malformed: u32,          // Failed the CRC, too short, or didn't decode
+    pub auth_failures: u32,      // Protected commands without a valid tag and fresh nonce
+    pub heartbeat_failures: u32, // Consecutive heartbeats the socket failed to send, reset by the next one sent
+    pub applied: u32,            // Moves and poses that set new goals
+    pub deduplicated: u32,       // Moves and poses answered without touching the servos, their goals were unchanged
+    pub throttled: u32,          // Motion commands refused for coming faster than the client's rate limit
+}
+
+// Shared by the network task, which counts packets, and the control task, which reports them
+#[derive(Clone)]
+pub struct Stats {
+    started: Instant,
+    counts: Arc<Mutex<PacketCounts>>,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats { started: Instant::now(), counts: Arc::new(Mutex::new(PacketCounts::default())) }
+    }
+
+    pub fn count_received(&self) {
+        self.update(|counts| counts.received = counts.received.wrapping_add(1));
+    }
+
+    pub fn count_rejected(&self) {
+        self.update(|counts| counts.rejected = counts.rejected.wrapping_add(1));
+    }
+
+    pub fn count_malformed(&self) {
+        self.update(|counts| counts.malformed = counts.malformed.wrapping_add(1));
+    }
+
+    pub fn count_auth_failure(&self) {
+        self.update(|counts| counts.auth_failures = counts.auth_failures.wrapping_add(1));
+    }
+
+    pub fn count_throttled(&self) {
+        self.update(|counts| counts.throttled = counts.throttled.wrapping_add(1));
+    }
+
+    pub fn count_applied(&self) {
+        self.update(|counts| counts.applied = counts.applied.wrapping_add(1));
+    }
+
+    pub fn count_deduplicated(&self) {
+        self.update(|counts| counts.deduplicated = counts.deduplicated.wrapping_add(1));
+    }
+
+    pub fn heartbeat_sent(&self, sent: bool) {
+        self.update(|counts| counts.heartbeat_failures = if sent { 0 } else { counts.heartbeat_failures.saturating_add(1) });
+    }
+
+    pub fn get(&self) -> PacketCounts {
+        *self.counts.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Seconds since the tasks were started
+    pub fn uptime_s(&self) -> u32 {
+        self.started.elapsed().as_secs() as u32
+    }
+
+    fn update(&self, f: impl FnOnce(&mut PacketCounts)) {
+        f(&mut self.counts.lock().unwrap_or_else(|poisoned| poisoned.into_inner()));
+    }
+}
+
+#[cfg(not(feature = "sim"))]
+pub fn free_heap() -> u32 {
+    unsafe { esp_idf_sys::esp_get_free_heap_size() }
+}
+
+// The simulator has no heap figure to report
+#[cfg(feature = "sim")]
+pub fn free_heap() -> u32 {
+    0
+}
+
+// Signal strength of the joined network in dBm, None while the station isn't connected
+#[cfg(not(feature = "sim"))]
+pub fn rssi() -> Option<i8> {
+    let mut info = esp_idf_sys::wifi_ap_record_t::default();
+    match esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_wifi_sta_get_ap_info(&mut info) }) {
+        Ok(_) => Some(info.rssi),
+        Err(_) => None,
+    }
+}
+
+#[cfg(feature = "sim")]
+pub fn rssi() -> Option<i8> {
+    None
+}
@@ -0,0 +1,274 @@
+// Task layout: the control task owns the servos, the network task the socket and the display task the OLED
+use std::iter;
+use std::sync::mpsc::{Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+#[cfg(not(feature = "sim"))]
+use esp_idf_hal::task::thread::ThreadSpawnConfiguration;
+use log::{error, info};
+
+use crate::backend::{Align, DisplayBackend, ServoBar};
+use crate::qr::QrCode;
+
+pub const MIN_DRAW_INTERVAL: Duration = Duration::from_millis(100); // At most 10 Hz, each flush holds the I2C bus
+
+// FreeRTOS task parameters, the names need a trailing nul for esp-idf.
+// Priorities sit above the main task (1) and below the WiFi and lwIP tasks (18 and up).
+pub struct TaskConfig {
+    pub name: &'static str,
+    pub stack_size: usize, // Bytes
+    pub priority: u8,
+}
+
+// Highest of ours so ticks are served on time, the big command match, its formatting and the trajectory blob buffer need the stack
+pub const CONTROL_TASK: TaskConfig = TaskConfig {
+    name: "control\0",
+    stack_size: 12 * 1024,
+    priority: 5,
+};
+
+// Mostly blocked in recv, holds the receive buffer and reply frames, and the SHA-256 rounds when authenticating
+pub const NETWORK_TASK: TaskConfig = TaskConfig {
+    name: "network\0",
+    stack_size: 8 * 1024,
+    priority: 4,
+};
+
+// Blocked on disconnect events, then in the driver's connect call while rejoining
+#[cfg(not(feature = "sim"))]
+pub const WIFI_TASK: TaskConfig = TaskConfig {
+    name: "wifi\0",
+    stack_size: 4 * 1024,
+    priority: 3,
+};
+
+// Sleeps between SNTP syncs and polls the one in progress, a late sync only delays the time being known
+#[cfg(not(feature = "sim"))]
+pub const CLOCK_TASK: TaskConfig = TaskConfig {
+    name: "clock\0",
+    stack_size: 4 * 1024,
+    priority: 2,
+};
+
+// Sleeps between ADC readings, a late one only delays the average
+#[cfg(not(feature = "sim"))]
+pub const BATTERY_TASK: TaskConfig = TaskConfig {
+    name: "battery\0",
+    stack_size: 3 * 1024,
+    priority: 2,
+};
+
+// Only sends the records queued to it, formatting happens in the task that logged. A late record is still worth having
+pub const LOG_TASK: TaskConfig = TaskConfig {
+    name: "log\0",
+    stack_size: 3 * 1024,
+    priority: 2,
+};
+
+// Blocked on the connection most of the time, the TLS handshake needs the stack. Below the network task so packets are
+// still answered during a download
+pub const OTA_TASK: TaskConfig = TaskConfig {
+    name: "ota\0",
+    stack_size: 10 * 1024,
+    priority: 2,
+};
+
+// Waits on broker events and the control task's replies, the client's own task does the socket work. Publishing late
+// only delays a status nobody is waiting on
+#[cfg(all(feature = "mqtt", not(feature = "sim")))]
+pub const MQTT_TASK: TaskConfig = TaskConfig {
+    name: "mqtt\0",
+    stack_size: 6 * 1024,
+    priority: 2,
+};
+
+// Sleeps between telemetry pushes to the WebSocket clients, the replies to their commands are sent by the HTTP server
+#[cfg(not(feature = "sim"))]
+pub const WS_TASK: TaskConfig = TaskConfig {
+    name: "ws\0",
+    stack_size: 4 * 1024,
+    priority: 2,
+};
+
+// Waits on the next frame or the next broadcast, the follower checks tags like the network task does. Level with the
+// network task, a late pose reaches the follower as late as a late packet would
+#[cfg(not(feature = "sim"))]
+pub const ESPNOW_TASK: TaskConfig = TaskConfig {
+    name: "espnow\0",
+    stack_size: 6 * 1024,
+    priority: 4,
+};
+
+// Waits on the NimBLE callbacks and the control task's replies, the host runs its own task. A late telemetry notify
+// only delays a status nobody is waiting on, but a phone's poses should go out as promptly as the WebSocket's
+#[cfg(all(feature = "ble", not(feature = "sim")))]
+pub const BLE_TASK: TaskConfig = TaskConfig {
+    name: "ble\0",
+    stack_size: 6 * 1024,
+    priority: 3,
+};
+
+// Blocked reading the UART, then waiting on the control task like the network task. Holds a frame buffer the size of
+// the receive buffer
+#[cfg(not(feature = "sim"))]
+pub const SERIAL_TASK: TaskConfig = TaskConfig {
+    name: "serial\0",
+    stack_size: 6 * 1024,
+    priority: 4,
+};
+
+// Lowest so a slow flush is preempted by the others, the display and its 1 KiB frame buffer live on this stack
+pub const DISPLAY_TASK: TaskConfig = TaskConfig {
+    name: "display\0",
+    stack_size: 6 * 1024,
+    priority: 2,
+};
+
+pub fn spawn<F>(config: &TaskConfig, task: F) -> JoinHandle<()>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let name = config.name.trim_end_matches('\0');
+    #[cfg(not(feature = "sim"))]
+    let stack_size = config.stack_size;
+    // Unoptimised host builds need far bigger frames than the device
+    #[cfg(feature = "sim")]
+    let stack_size = config.stack_size * 6;
+    // Applies to the next thread spawned from this one, which picks up the priority from it
+    #[cfg(not(feature = "sim"))]
+    match (ThreadSpawnConfiguration {
+        name: Some(config.name.as_bytes()),
+        stack_size,
+        priority: config.priority,
+        ..Default::default()
+    })
+    .set()
+    {
+        Ok(_) => {},
+        Err(e) => error!("Failed to configure {} task: {}", name, e),
+    }
+
+    info!("Starting {} task, priority {}, {} byte stack", name, config.priority, stack_size);
+    match thread::Builder::new()
+        .name(name.to_string())
+        .stack_size(stack_size)
+        .spawn(task)
+    {
+        Ok(handle) => handle,
+        Err(e) => panic!("Failed to spawn {} task: {}", name, e), // Nothing works without all three tasks
+    }
+}
+
+pub enum DisplayUpdate {
+    Text { x: i32, y: i32, text: String },
+    Lines { lines: Vec<String>, align: Align },
+    Banner { banner: String, text: String },
+    ServoBars(Vec<ServoBar>),
+    Qr { code: QrCode, caption: Vec<String> },
+    Brightness(u8),
+    Power(bool),
+    Flip(bool),
+}
+
+// Stands in for the display in the control task, queuing each draw for the display task
+pub struct DisplayChannel {
+    updates: Sender<DisplayUpdate>,
+    rows: usize, // Asked of the display before it moved to its task
+}
+
+impl DisplayChannel {
+    pub fn new(updates: Sender<DisplayUpdate>, rows: usize) -> DisplayChannel {
+        DisplayChannel { updates, rows }
+    }
+
+    fn send(&self, update: DisplayUpdate) {
+        match self.updates.send(update) {
+            Ok(_) => {},
+            Err(_) => error!("Display task stopped, update dropped"),
+        }
+    }
+}
+
+impl DisplayBackend for DisplayChannel {
+    fn draw_new_text(&mut self, x: i32, y: i32, text: &str) {
+        self.send(DisplayUpdate::Text { x, y, text: text.to_string() });
+    }
+
+    fn draw_lines(&mut self, lines: &[&str], align: Align) {
+        self.send(DisplayUpdate::Lines {
+            lines: lines.iter().map(|line| line.to_string()).collect(),
+            align,
+        });
+    }
+
+    fn draw_banner(&mut self, banner: &str, text: &str) {
+        self.send(DisplayUpdate::Banner {
+            banner: banner.to_string(),
+            text: text.to_string(),
+        });
+    }
+
+    fn draw_servo_bars(&mut self, bars: &[ServoBar]) {
+        self.send(DisplayUpdate::ServoBars(bars.to_vec()));
+    }
+
+    fn draw_qr(&mut self, code: &QrCode, caption: &[&str]) {
+        self.send(DisplayUpdate::Qr {
+            code: code.clone(),
+            caption: caption.iter().map(|line| line.to_string()).collect(),
+        });
+    }
+
+    fn set_brightness(&mut self, brightness: u8) {
+        self.send(DisplayUpdate::Brightness(brightness));
+    }
+
+    fn set_display_on(&mut self, on: bool) {
+        self.send(DisplayUpdate::Power(on));
+    }
+
+    fn set_flipped(&mut self, flipped: bool) {
+        self.send(DisplayUpdate::Flip(flipped));
+    }
+
+    fn rows(&self) -> usize {
+        self.rows
+    }
+}
+
+// Draws at most every MIN_DRAW_INTERVAL, only the newest draw queued meanwhile is drawn. Brightness, power and rotation
+// changes are all applied, in order
+pub fn display_task(mut display: impl DisplayBackend, updates: Receiver<DisplayUpdate>) {
+    let mut drawn_at: Option<Instant> = None;
+    while let Ok(update) = updates.recv() {
+        if let Some(elapsed) = drawn_at.map(|drawn_at| drawn_at.elapsed()) {
+            if elapsed < MIN_DRAW_INTERVAL {
+                thread::sleep(MIN_DRAW_INTERVAL - elapsed);
+            }
+        }
+        drawn_at = Some(Instant::now());
+        // Any draw queued behind a slow flush or the wait is already out of date
+        let mut draw = None;
+        for update in iter::once(update).chain(updates.try_iter()) {
+            match update {
+                DisplayUpdate::Brightness(brightness) => display.set_brightness(brightness),
+                DisplayUpdate::Power(on) => display.set_display_on(on),
+                DisplayUpdate::Flip(flipped) => display.set_flipped(flipped),
+                update => draw = Some(update),
+            }
+        }
+        match draw {
+            Some(DisplayUpdate::Text { x, y, text }) => display.draw_new_text(x, y, &text),
+            Some(DisplayUpdate::Lines { lines, align }) => {
+                display.draw_lines(&lines.iter().map(String::as_str).collect::<Vec<&str>>(), align)
+            }
+            Some(DisplayUpdate::Banner { banner, text }) => display.draw_banner(&banner, &text),
+            Some(DisplayUpdate::ServoBars(bars)) => display.draw_servo_bars(&bars),
+            Some(DisplayUpdate::Qr { code, caption }) => {
+                display.draw_qr(&code, &caption.iter().map(String::as_str).collect::<Vec<&str>>())
+            }
+            Some(DisplayUpdate::Brightness(_) | DisplayUpdate::Power(_) | DisplayUpdate::Flip(_)) | None => {},
+        }
+    }
+}
@@ -0,0 +1,192 @@
+// Chip temperature from the ESP32's on-die sensor, so it reads above the enclosure air. Past the warning the controller
+// warns, past the critical temperature it parks the servos, and either clears only once cooled by the hysteresis
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::protocol;
+use crate::CONFIG;
+
+const READ_INTERVAL: Duration = Duration::from_secs(1);
+#[cfg(feature = "sim")]
+const SIM_CELSIUS: i8 = 45; // A warm enclosure, well short of the default warning
+
+#[cfg(all(not(feature = "sim"), not(esp32s3)))]
+extern "C" {
+    // In the ESP32's RTC library, spelt so there. Fahrenheit, 128 when the sensor gave no reading
+    fn temprature_sens_read() -> u8;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Normal,
+    Warm,     // Past the warning
+    Critical, // Past the critical temperature, the servos are parked
+}
+
+impl Level {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Level::Normal => protocol::THERMAL_NORMAL,
+            Level::Warm => protocol::THERMAL_WARM,
+            Level::Critical => protocol::THERMAL_CRITICAL,
+        }
+    }
+}
+
+pub struct Thermal {
+    warn_c: u8,     // 0 never warns
+    critical_c: u8, // 0 never parks
+    hysteresis_c: u8,
+    celsius: Option<i8>, // None until the first reading
+    level: Level,
+    read_at: Instant,
+    failing: bool, // Whether the last read failed, only the first failure is logged
+}
+
+impl Thermal {
+    pub fn new() -> Thermal {
+        Thermal {
+            warn_c: CONFIG.temperature_warn_c,
+            critical_c: CONFIG.temperature_critical_c,
+            hysteresis_c: CONFIG.temperature_hysteresis_c,
+            celsius: None,
+            level: Level::Normal,
+            read_at: Instant::now(),
+            failing: false,
+        }
+    }
+
+    // Reads the sensor once it is due, a failed read leaves the last reading and level standing. Returns the level
+    // when it has just changed
+    pub fn update(&mut self, now: Instant) -> Option<Level> {
+        if now < self.read_at {
+            return None;
+        }
+        self.read_at = now + READ_INTERVAL;
+        match read_celsius() {
+            Some(celsius) => {
+                self.failing = false;
+                self.record(celsius)
+            }
+            None => {
+                if !self.failing {
+                    warn!("The chip temperature sensor gave no reading");
+                    self.failing = true;
+                }
+                None
+            }
+        }
+    }
+
+    // Moves the level on for a reading, returning it when it has just changed
+    fn record(&mut self, celsius: i8) -> Option<Level> {
+        self.celsius = Some(celsius);
+        // Held while above the threshold less the hysteresis, reached only by going past the threshold itself
+        let past = |threshold: u8, held: bool| {
+            let hysteresis_c = if held { self.hysteresis_c as i16 } else { 0 };
+            threshold != 0 && celsius as i16 > threshold as i16 - hysteresis_c
+        };
+        let level = match () {
+            _ if past(self.critical_c, self.level == Level::Critical) => Level::Critical,
+            _ if past(self.warn_c, self.level >= Level::Warm) => Level::Warm,
+            _ => Level::Normal,
+        };
+        let changed = level != self.level;
+        self.level = level;
+        changed.then_some(level)
+    }
+
+    // Thresholds the simulated chip's steady temperature can be put past
+    #[cfg(all(test, feature = "sim"))]
+    pub fn with_thresholds(warn_c: u8, critical_c: u8) -> Thermal {
+        Thermal { warn_c, critical_c, ..Thermal::new() }
+    }
+
+    pub fn get_celsius(&self) -> Option<i8> {
+        self.celsius
+    }
+
+    pub fn get_level(&self) -> Level {
+        self.level
+    }
+
+    pub fn get_warn_c(&self) -> u8 {
+        self.warn_c
+    }
+
+    pub fn get_critical_c(&self) -> u8 {
+        self.critical_c
+    }
+}
+
+#[cfg(all(not(feature = "sim"), not(esp32s3)))]
+fn read_celsius() -> Option<i8> {
+    match unsafe { temprature_sens_read() } {
+        128 => None,
+        fahrenheit => Some(((fahrenheit as i16 - 32) * 5 / 9) as i8),
+    }
+}
+
+// The S3 has no such function, its sensor is a driver installed on the first read. Only the control loop reads it
+#[cfg(all(not(feature = "sim"), esp32s3))]
+fn read_celsius() -> Option<i8> {
+    use std::sync::atomic::{AtomicPtr, Ordering};
+
+    static SENSOR: AtomicPtr<esp_idf_sys::temperature_sensor_obj_t> = AtomicPtr::new(std::ptr::null_mut());
+    let mut sensor = SENSOR.load(Ordering::Relaxed);
+    if sensor.is_null() {
+        // The range it is most accurate over, from the enclosure warming up to past the critical temperature
+        let config = esp_idf_sys::temperature_sensor_config_t { range_min: 20, range_max: 100, ..Default::default() };
+        esp_idf_sys::esp!(unsafe { esp_idf_sys::temperature_sensor_install(&config, &mut sensor) }).ok()?;
+        esp_idf_sys::esp!(unsafe { esp_idf_sys::temperature_sensor_enable(sensor) }).ok()?;
+        SENSOR.store(sensor, Ordering::Relaxed);
+    }
+    let mut celsius: f32 = 0.0;
+    esp_idf_sys::esp!(unsafe { esp_idf_sys::temperature_sensor_get_celsius(sensor, &mut celsius) }).ok()?;
+    Some(celsius.round() as i8)
+}
+
+// The host has no die sensor, the simulated chip sits at a steady temperature
+#[cfg(feature = "sim")]
+fn read_celsius() -> Option<i8> {
+    Some(SIM_CELSIUS)
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_are_reached_past_each_threshold_and_left_past_the_hysteresis() {
+        let mut thermal = Thermal { hysteresis_c: 5, ..Thermal::with_thresholds(70, 85) };
+        assert_eq!(thermal.record(70), None);
+        assert_eq!(thermal.record(71), Some(Level::Warm));
+        assert_eq!(thermal.record(86), Some(Level::Critical));
+        assert_eq!(thermal.record(81), None, "left critical inside the hysteresis");
+        assert_eq!(thermal.record(80), Some(Level::Warm));
+        assert_eq!(thermal.record(84), None, "critical again short of the threshold");
+        assert_eq!(thermal.record(66), None);
+        assert_eq!(thermal.record(65), Some(Level::Normal));
+        assert_eq!(thermal.get_celsius(), Some(65));
+    }
+
+    #[test]
+    fn a_zero_threshold_never_trips() {
+        let mut thermal = Thermal::with_thresholds(0, 0);
+        assert_eq!(thermal.record(i8::MAX), None);
+        assert_eq!(thermal.get_level(), Level::Normal);
+    }
+
+    #[test]
+    fn the_sensor_is_only_read_once_due() {
+        let mut thermal = Thermal::with_thresholds(SIM_CELSIUS as u8 - 1, 0);
+        let start = Instant::now();
+        assert_eq!(thermal.update(start), Some(Level::Warm));
+        thermal.celsius = None;
+        assert_eq!(thermal.update(start + READ_INTERVAL / 2), None);
+        assert_eq!(thermal.get_celsius(), None);
+        thermal.update(start + READ_INTERVAL);
+        assert_eq!(thermal.get_celsius(), Some(SIM_CELSIUS));
+    }
+}
@@ -0,0 +1,51 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::warn;
+
+use crate::servo;
+
+pub const MAX_TICK_HZ: u32 = 1000; // Faster than this and the loop spends its time polling servos
+
+// Periodic servo update flag, raised by the hardware timer ISR (or a thread in the simulator) and taken by the control loop.
+// Clones share the same flag, so one can be moved into the ISR.
+#[derive(Clone)]
+pub struct Tick {
+    hz: u32,
+    pending: Arc<AtomicBool>,
+}
+
+impl Tick {
+    // Rates of 0 or above MAX_TICK_HZ fall back to servo::POLL_HZ
+    pub fn new(hz: u32) -> Tick {
+        let hz = if hz == 0 || hz > MAX_TICK_HZ {
+            warn!("Servo tick of {} Hz is out of range, using {} Hz", hz, servo::POLL_HZ);
+            servo::POLL_HZ
+        } else {
+            hz
+        };
+        Tick {
+            hz,
+            pending: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn get_hz(&self) -> u32 {
+        self.hz
+    }
+
+    pub fn get_period(&self) -> Duration {
+        Duration::from_micros(1_000_000 / self.hz as u64)
+    }
+
+    // Only touches the atomic, so it is safe to call from an ISR
+    pub fn raise(&self) {
+        self.pending.store(true, Ordering::Release);
+    }
+
+    // True once for each raised tick, ticks raised before the last one was taken are merged into it
+    pub fn take(&self) -> bool {
+        self.pending.swap(false, Ordering::AcqRel)
+    }
+}
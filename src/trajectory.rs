@@ -0,0 +1,283 @@
+use std::time::{Duration, Instant};
+
+use crate::protocol::SERVO_COUNT;
+use crate::servo;
+
+pub const MAX_FRAMES: usize = 32;
+pub const SLOT_COUNT: u8 = 8; // Trajectories that can be stored in NVS
+pub const FRAME_SIZE: usize = SERVO_COUNT * 2 + 2; // Encoded size of a keyframe, on the wire and in NVS
+pub const MAX_BLOB_SIZE: usize = 2 + MAX_FRAMES * FRAME_SIZE + 9;
+const BLOB_VERSION: u8 = 3; // Bump when the stored trajectory layout changes
+const DEGREES_BLOB_VERSION: u8 = 2; // Stored before angles were in tenths, still loaded
+const UNTIMED_BLOB_VERSION: u8 = 1; // Stored before trajectories carried their capture time either, still loaded
+
+// Frames with the Unix time in ms they were captured at, None if that isn't known
+pub type Timed = (Vec<Keyframe>, Option<u64>);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Keyframe {
+    pub angles: [u16; SERVO_COUNT], // Tenths of a degree
+    pub dwell_ms: u16, // Time spent on this frame, the joints are interpolated to arrive at its end
+}
+
+impl Keyframe {
+    // Angles in whole degrees then dwell time, big-endian to match the rest of the wire format
+    pub fn from_be_bytes(bytes: &[u8]) -> Keyframe {
+        read_frame(bytes, u16::from_be_bytes, true)
+    }
+}
+
+// `degrees` scales angles held in whole degrees up to tenths
+fn read_frame(bytes: &[u8], read_u16: fn([u8; 2]) -> u16, degrees: bool) -> Keyframe {
+    let u16_at = |i: usize| read_u16([bytes[i], bytes[i + 1]]);
+    let mut angles = [0u16; SERVO_COUNT];
+    for (i, angle) in angles.iter_mut().enumerate() {
+        *angle = match degrees {
+            true => servo::from_degrees(u16_at(i * 2)),
+            false => u16_at(i * 2),
+        };
+    }
+    Keyframe {
+        angles,
+        dwell_ms: u16_at(SERVO_COUNT * 2),
+    }
+}
+
+// Layout: version, frame count, each frame's angles and dwell time, then whether the capture time is known and the
+// Unix time in ms, all little-endian like the calibration blob
+pub fn to_bytes(frames: &[Keyframe], captured_ms: Option<u64>) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(2 + frames.len() * FRAME_SIZE + 9);
+    bytes.push(BLOB_VERSION);
+    bytes.push(frames.len() as u8);
+    for frame in frames {
+        for angle in frame.angles {
+            bytes.extend_from_slice(&angle.to_le_bytes());
+        }
+        bytes.extend_from_slice(&frame.dwell_ms.to_le_bytes());
+    }
+    bytes.push(captured_ms.is_some() as u8);
+    bytes.extend_from_slice(&captured_ms.unwrap_or(0).to_le_bytes());
+    bytes
+}
+
+// The frames and their capture time, None for blobs of an unknown version or with a frame count that doesn't match
+// their size. Blobs from before the capture time was stored have none, and older blobs' angles are scaled to tenths
+pub fn from_bytes(bytes: &[u8]) -> Option<Timed> {
+    let (&version, rest) = bytes.split_first()?;
+    let (&count, rest) = rest.split_first()?;
+    let count = count as usize;
+    let (timed, degrees) = match version {
+        BLOB_VERSION => (true, false),
+        DEGREES_BLOB_VERSION => (true, true),
+        UNTIMED_BLOB_VERSION => (false, true),
+        _ => return None,
+    };
+    let (frames, captured) = match timed {
+        true if rest.len() >= 9 => rest.split_at(rest.len() - 9),
+        true => return None,
+        false => (rest, &[][..]),
+    };
+    if count == 0 || count > MAX_FRAMES || frames.len() != count * FRAME_SIZE {
+        return None;
+    }
+    let captured_ms = match captured {
+        [1, time @ ..] => time.try_into().ok().map(u64::from_le_bytes),
+        _ => None,
+    };
+    let frames = frames.chunks_exact(FRAME_SIZE).map(|frame| read_frame(frame, u16::from_le_bytes, degrees)).collect();
+    Some((frames, captured_ms))
+}
+
+// A stored trajectory being played back, one group move per frame
+pub struct Playback {
+    slot: u8,
+    frames: Vec<Keyframe>,
+    looping: bool,
+    frame: usize,
+    frame_end: Option<Instant>, // None until the first frame has been started
+    paused_at: Option<Instant>, // Set while paused, the frame's time is extended by the pause on resuming
+}
+
+impl Playback {
+    pub fn new(slot: u8, frames: Vec<Keyframe>, looping: bool) -> Playback {
+        Playback {
+            slot,
+            frames,
+            looping,
+            frame: 0,
+            frame_end: None,
+            paused_at: None,
+        }
+    }
+
+    pub fn get_slot(&self) -> u8 {
+        self.slot
+    }
+
+    pub fn get_frame(&self) -> usize {
+        self.frame
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    // The next frame to move to once the current one's dwell time is up and the servos have arrived,
+    // None while waiting. Finished playbacks return None forever, see is_finished()
+    pub fn next_frame(&mut self, now: Instant, moving: bool) -> Option<Keyframe> {
+        if self.is_finished() || self.paused_at.is_some() {
+            return None;
+        }
+        if let Some(frame_end) = self.frame_end {
+            if now < frame_end || moving {
+                return None;
+            }
+            self.frame += 1;
+            if self.frame >= self.frames.len() {
+                if !self.looping {
+                    return None;
+                }
+                self.frame = 0;
+            }
+        }
+        let frame = *self.frames.get(self.frame)?;
+        self.frame_end = Some(now + Duration::from_millis(frame.dwell_ms as u64));
+        Some(frame)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frame >= self.frames.len()
+    }
+
+    pub fn pause(&mut self, now: Instant) {
+        self.paused_at.get_or_insert(now);
+    }
+
+    pub fn resume(&mut self, now: Instant) {
+        if let (Some(paused_at), Some(frame_end)) = (self.paused_at.take(), self.frame_end.as_mut()) {
+            *frame_end += now.duration_since(paused_at);
+        }
+    }
+}
+
+// Teach mode: the pose sampled at a fixed interval into frames that replay it with the timing it was recorded at.
+// Samples that repeat the last pose are folded into a single held frame, so a pause doesn't use up the capacity
+pub struct Recording {
+    frames: Vec<Keyframe>,
+    interval: Duration,
+    last_sample: Option<Instant>, // None until the first sample
+    started_ms: Option<u64>,      // Unix time the recording started at, None if the clock wasn't synchronized
+}
+
+impl Recording {
+    pub fn new(interval: Duration, started_ms: Option<u64>) -> Recording {
+        Recording {
+            frames: Vec::with_capacity(MAX_FRAMES),
+            interval,
+            last_sample: None,
+            started_ms,
+        }
+    }
+
+    // Whether the next sample is due
+    pub fn is_due(&self, now: Instant) -> bool {
+        match self.last_sample {
+            Some(last_sample) => now >= last_sample + self.interval,
+            None => true,
+        }
+    }
+
+    // Adds the pose at `now`, the first frame moves to it at each servo's own speed. Returns false once full
+    pub fn sample(&mut self, angles: [u16; SERVO_COUNT], now: Instant) -> bool {
+        let elapsed_ms = self.last_sample.map_or(0, |last_sample| {
+            now.duration_since(last_sample).as_millis().min(u16::MAX as u128) as u16
+        });
+        self.last_sample = Some(now);
+        let held = match self.frames.as_slice() {
+            [.., before, last] => before.angles == angles && last.angles == angles,
+            _ => false,
+        };
+        if let (true, Some(last)) = (held, self.frames.last_mut()) {
+            last.dwell_ms = last.dwell_ms.saturating_add(elapsed_ms);
+        } else if self.frames.len() < MAX_FRAMES {
+            self.frames.push(Keyframe { angles, dwell_ms: elapsed_ms });
+        } else {
+            return false;
+        }
+        true
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn into_frames(self) -> Timed {
+        (self.frames, self.started_ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn blobs_round_trip_with_their_capture_time_and_old_ones_still_load() {
+        let frames = vec![
+            Keyframe { angles: [905; SERVO_COUNT], dwell_ms: 0 },
+            Keyframe { angles: [100; SERVO_COUNT], dwell_ms: 500 },
+        ];
+        for captured_ms in [None, Some(1_760_000_000_000)] {
+            let bytes = to_bytes(&frames, captured_ms);
+            assert_eq!(from_bytes(&bytes), Some((frames.clone(), captured_ms)));
+            assert_eq!(from_bytes(&bytes[..bytes.len() - 1]), None);
+        }
+        // In whole degrees, from before angles were held in tenths
+        let frames = vec![
+            Keyframe { angles: [90; SERVO_COUNT], dwell_ms: 0 },
+            Keyframe { angles: [10; SERVO_COUNT], dwell_ms: 500 },
+        ];
+        let tenths: Vec<Keyframe> =
+            frames.iter().map(|frame| Keyframe { angles: frame.angles.map(servo::from_degrees), ..*frame }).collect();
+        let mut degrees = to_bytes(&frames, Some(1_760_000_000_000));
+        degrees[0] = DEGREES_BLOB_VERSION;
+        assert_eq!(from_bytes(&degrees), Some((tenths.clone(), Some(1_760_000_000_000))));
+        // Untimed, from before the capture time was kept
+        let mut untimed = to_bytes(&frames, None);
+        untimed.truncate(untimed.len() - 9);
+        untimed[0] = UNTIMED_BLOB_VERSION;
+        assert_eq!(from_bytes(&untimed), Some((tenths, None)));
+        untimed[0] = BLOB_VERSION + 1;
+        assert_eq!(from_bytes(&untimed), None);
+    }
+
+    #[test]
+    fn recording_folds_a_held_pose_into_one_frame() {
+        let start = Instant::now();
+        let mut recording = Recording::new(MS * 250, Some(1_760_000_000_000));
+        assert!(recording.is_due(start));
+        assert!(recording.sample([90; SERVO_COUNT], start));
+        assert!(!recording.is_due(start + MS * 249));
+        for sample in 1..=4 {
+            assert!(recording.sample([100; SERVO_COUNT], start + MS * 250 * sample));
+        }
+        let (frames, started_ms) = recording.into_frames();
+        assert_eq!(started_ms, Some(1_760_000_000_000));
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0], Keyframe { angles: [90; SERVO_COUNT], dwell_ms: 0 });
+        assert_eq!(frames[1], Keyframe { angles: [100; SERVO_COUNT], dwell_ms: 250 });
+        assert_eq!(frames[2], Keyframe { angles: [100; SERVO_COUNT], dwell_ms: 750 });
+    }
+
+    #[test]
+    fn recording_stops_once_full() {
+        let start = Instant::now();
+        let mut recording = Recording::new(MS, None);
+        for frame in 0..MAX_FRAMES as u16 {
+            assert!(recording.sample([frame; SERVO_COUNT], start + MS * frame as u32));
+        }
+        assert!(!recording.sample([0; SERVO_COUNT], start + MS * 100));
+        assert_eq!(recording.frame_count(), MAX_FRAMES);
+    }
+}
@@ -0,0 +1,66 @@
+// Task watchdog for the control loop. sdkconfig.defaults makes the watchdog panic, so a hung loop resets the chip
+// and the servo outputs with it rather than leaving them driven at their last duty.
+#[cfg(not(feature = "sim"))]
+use esp_idf_hal::reset;
+#[cfg(not(feature = "sim"))]
+use log::{error, info};
+
+// Why the chip last started, as reported in telemetry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "sim", allow(dead_code))] // The simulator always powers on
+pub enum ResetReason {
+    PowerOn = 0,
+    Software = 1, // Restarted by the reboot command or the setup portal
+    Panic = 2,
+    Watchdog = 3, // Task, interrupt or other watchdog
+    Brownout = 4,
+    Other = 5,
+}
+
+impl ResetReason {
+    pub fn is_watchdog(self) -> bool {
+        self == ResetReason::Watchdog
+    }
+}
+
+#[cfg(not(feature = "sim"))]
+pub fn reset_reason() -> ResetReason {
+    match reset::ResetReason::get() {
+        reset::ResetReason::PowerOn => ResetReason::PowerOn,
+        reset::ResetReason::Software => ResetReason::Software,
+        reset::ResetReason::Panic => ResetReason::Panic,
+        reset::ResetReason::Watchdog | reset::ResetReason::InterruptWatchdog | reset::ResetReason::TaskWatchdog => {
+            ResetReason::Watchdog
+        }
+        reset::ResetReason::Brownout => ResetReason::Brownout,
+        _ => ResetReason::Other,
+    }
+}
+
+#[cfg(feature = "sim")]
+pub fn reset_reason() -> ResetReason {
+    ResetReason::PowerOn
+}
+
+// Subscribes the calling task, which must then feed the watchdog within the sdkconfig timeout
+#[cfg(not(feature = "sim"))]
+pub fn watch_current_task() {
+    match esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_task_wdt_add(core::ptr::null_mut()) }) {
+        Ok(_) => info!("Control task watched by the task watchdog"),
+        Err(e) => error!("Failed to subscribe to the task watchdog, a hung loop won't be caught: {}", e),
+    }
+}
+
+#[cfg(feature = "sim")]
+pub fn watch_current_task() {}
+
+#[cfg(not(feature = "sim"))]
+pub fn feed() {
+    // Only fails for a task that isn't subscribed, which was already reported
+    unsafe {
+        esp_idf_sys::esp_task_wdt_reset();
+    }
+}
+
+#[cfg(feature = "sim")]
+pub fn feed() {}
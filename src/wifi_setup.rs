@@ -1,34 +1,105 @@
+#[cfg(not(feature = "sim"))]
 use anyhow::{bail, Error};
 
-use embedded_svc::wifi::{AuthMethod, Configuration, ClientConfiguration, AccessPointConfiguration};
+#[cfg(not(feature = "sim"))]
+use embedded_svc::wifi::{AccessPointConfiguration, AccessPointInfo, AuthMethod, ClientConfiguration, Configuration};
+#[cfg(not(feature = "sim"))]
 use esp_idf_hal::delay::FreeRtos;
+#[cfg(not(feature = "sim"))]
 use esp_idf_hal::peripheral;
+#[cfg(not(feature = "sim"))]
 use esp_idf_svc::eventloop::EspSystemEventLoop;
-use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
+#[cfg(not(feature = "sim"))]
+use esp_idf_svc::mdns::EspMdns;
+#[cfg(not(feature = "sim"))]
+use esp_idf_svc::wifi::{BlockingWifi, EspWifi, WifiEvent};
+#[cfg(not(feature = "sim"))]
+use esp_idf_sys::{esp, esp_wifi_set_channel, wifi_second_chan_t_WIFI_SECOND_CHAN_NONE};
+#[cfg(not(feature = "sim"))]
+use std::cmp::Reverse;
+#[cfg(not(feature = "sim"))]
+use std::fmt::Display;
+#[cfg(not(feature = "sim"))]
+use std::sync::mpsc::{self, Receiver};
 use log::{info, error};
+#[cfg(not(feature = "sim"))]
+use log::warn;
 use core::time::Duration;
+use std::io;
+use std::net::{Ipv4Addr, UdpSocket};
 
+#[cfg(not(feature = "sim"))]
+use crate::link::Link;
+#[cfg(not(feature = "sim"))]
+use crate::ota;
+#[cfg(not(feature = "sim"))]
+use crate::protocol;
+#[cfg(not(feature = "sim"))]
+use crate::provisioning;
+#[cfg(not(feature = "sim"))]
+use crate::settings::Settings;
+#[cfg(not(feature = "sim"))]
+use crate::settings::WifiCredentials;
+#[cfg(not(feature = "sim"))]
+use crate::{VERSION_MAJ, VERSION_MIN};
 
+// The simulator only listens on localhost
+#[cfg(not(feature = "sim"))]
+const BIND_IP: Ipv4Addr = Ipv4Addr::UNSPECIFIED;
+#[cfg(feature = "sim")]
+const BIND_IP: Ipv4Addr = Ipv4Addr::LOCALHOST;
 
 
-pub fn wifi(
-    ssid: &str,
-    pass: &str,
+
+
+// Name of the open access point the setup portal runs on
+#[cfg(not(feature = "sim"))]
+pub const AP_SSID: &str = "limb-setup";
+
+// The limb's own access point, broadcast alongside the station when running in mixed mode
+#[cfg(not(feature = "sim"))]
+#[derive(Clone, Copy)]
+pub struct AccessPoint<'a> {
+    pub ssid: &'a str,
+    pub password: &'a str, // Empty for an open access point, otherwise 8 to 64 characters for WPA2
+    pub channel: Option<u8>, // None follows the station's channel from the scan
+}
+
+// Created once, so a failed connection can hand the driver over to the setup portal
+#[cfg(not(feature = "sim"))]
+pub fn new_wifi(
     modem: impl peripheral::Peripheral<P = esp_idf_hal::modem::Modem> + 'static,
     sysloop: EspSystemEventLoop,
-    max_retries: u8,
 ) -> Result<Box<EspWifi<'static>>, Error> {
-    let mut auth_method = AuthMethod::WPA2Personal;
-    if ssid.is_empty() {
+    Ok(Box::new(EspWifi::new(modem, sysloop, None)?))
+}
+
+// Each stage of joining as it starts, so start-up can show how far it got
+#[cfg(not(feature = "sim"))]
+pub enum WifiStage<'a> {
+    Scanning,
+    Scanned { seen: usize }, // Configured networks found in range
+    Connecting { ssid: &'a str, attempt: u8, max_attempts: u8 },
+    Dhcp { ssid: &'a str },
+    GaveUp { ssid: &'a str, error: &'a Error }, // The next network is tried, if there is one
+}
+
+// Scans once and joins the strongest of the networks in range, falling back to the next if one can't be joined.
+// Returns the SSID that was joined
+#[cfg(not(feature = "sim"))]
+pub fn wifi(
+    networks: &[WifiCredentials],
+    esp_wifi: &mut EspWifi<'static>,
+    sysloop: EspSystemEventLoop,
+    max_retries: u8,
+    access_point: Option<AccessPoint>, // None for station only
+    progress: &mut dyn FnMut(WifiStage),
+) -> Result<String, Error> {
+    progress(WifiStage::Scanning);
+    if networks.is_empty() {
         bail!("Missing WiFi name")
     }
-    if pass.is_empty() {
-        auth_method = AuthMethod::None;
-        info!("Wifi password is empty");
-    }
-    let mut esp_wifi = EspWifi::new(modem, sysloop.clone(), None)?;
-
-    let mut wifi = BlockingWifi::wrap(&mut esp_wifi, sysloop)?;
+    let mut wifi = BlockingWifi::wrap(esp_wifi, sysloop)?;
 
     wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
 
@@ -40,44 +111,95 @@ pub fn wifi(
 
     let ap_infos = wifi.scan()?;
 
-    let ours = ap_infos.into_iter().find(|a| a.ssid == ssid);
+    // Networks seen in the scan strongest first, then the rest in their configured order in case they are hidden
+    let mut candidates: Vec<(&WifiCredentials, Option<&AccessPointInfo>)> = networks
+        .iter()
+        .map(|network| {
+            let seen = ap_infos
+                .iter()
+                .filter(|a| a.ssid == network.ssid.as_str())
+                .max_by_key(|a| a.signal_strength);
+            (network, seen)
+        })
+        .collect();
+    candidates.sort_by_key(|(_, seen)| Reverse(seen.map(|a| a.signal_strength)));
+    progress(WifiStage::Scanned { seen: candidates.iter().filter(|(_, seen)| seen.is_some()).count() });
 
-    let channel = if let Some(ours) = ours {
-        info!(
-            "Found configured access point {} on channel {}",
-            ssid, ours.channel
-        );
-        Some(ours.channel)
-    } else {
-        info!(
-            "Configured access point {} not found during scanning, will go with unknown channel",
-            ssid
-        );
-        None
-    };
+    for (network, seen) in candidates {
+        let channel = match seen {
+            Some(seen) => {
+                info!(
+                    "Found configured access point {} on channel {} at {} dBm",
+                    network.ssid, seen.channel, seen.signal_strength
+                );
+                Some(seen.channel)
+            }
+            None => {
+                info!(
+                    "Configured access point {} not found during scanning, will go with unknown channel",
+                    network.ssid
+                );
+                None
+            }
+        };
+        match join(&mut wifi, network, channel, max_retries, access_point.as_ref(), progress) {
+            Ok(_) => {
+                info!("Joined WiFi profile {}", network.ssid);
+                return Ok(network.ssid.clone());
+            }
+            Err(e) => {
+                error!("Giving up on {}: {}", network.ssid, e);
+                progress(WifiStage::GaveUp { ssid: &network.ssid, error: &e });
+            }
+        }
+    }
+    bail!("None of the {} configured networks could be joined", networks.len())
+}
 
-    wifi.set_configuration(&Configuration::Mixed(
-        ClientConfiguration {
-            ssid: ssid.into(),
-            password: pass.into(),
-            channel,
-            auth_method,
-            ..Default::default()
-        },
-        AccessPointConfiguration {
-            ssid: "aptest".into(),
-            channel: channel.unwrap_or(1),
-            ..Default::default()
-        },
-    ))?;
+#[cfg(not(feature = "sim"))]
+fn join(
+    wifi: &mut BlockingWifi<&mut EspWifi<'static>>,
+    network: &WifiCredentials,
+    channel: Option<u8>,
+    max_retries: u8,
+    access_point: Option<&AccessPoint>,
+    progress: &mut dyn FnMut(WifiStage),
+) -> Result<(), Error> {
+    let client = client_configuration(network, channel);
+    match access_point {
+        Some(access_point) => {
+            let ap_auth_method = if access_point.password.is_empty() {
+                warn!("Access point {} is open", access_point.ssid);
+                AuthMethod::None
+            } else {
+                AuthMethod::WPA2Personal
+            };
+            // There is one radio, once the station joins the access point moves to its channel regardless
+            let ap_channel = access_point.channel.or(channel).unwrap_or(1);
+            info!("Broadcasting access point {} on channel {}", access_point.ssid, ap_channel);
+            wifi.set_configuration(&Configuration::Mixed(
+                client,
+                AccessPointConfiguration {
+                    ssid: access_point.ssid.into(),
+                    password: access_point.password.into(),
+                    auth_method: ap_auth_method,
+                    channel: ap_channel,
+                    ..Default::default()
+                },
+            ))?;
+        }
+        None => wifi.set_configuration(&Configuration::Client(client))?,
+    }
 
     // Due to EspError(263) we need to retry connecting to wifi. ESP_ERR_TIMEOUT (0x107): Operation timed out
     let mut retry_count = 0;
     loop {
         info!(
-            "Attempting to connect to wifi... Attempt: {}",
+            "Attempting to connect to {}... Attempt: {}",
+            network.ssid,
             retry_count + 1
         );
+        progress(WifiStage::Connecting { ssid: &network.ssid, attempt: retry_count + 1, max_attempts: max_retries });
 
         match wifi.connect() {
             Ok(_) => {
@@ -96,6 +218,7 @@ pub fn wifi(
     }
 
     info!("Waiting for DHCP lease...");
+    progress(WifiStage::Dhcp { ssid: &network.ssid });
 
     wifi.wait_netif_up()?;
 
@@ -103,48 +226,281 @@ pub fn wifi(
 
     info!("Wifi DHCP info: {:?}", ip_info);
 
-    Ok(Box::new(esp_wifi))
+    Ok(())
+}
+
+
+#[cfg(not(feature = "sim"))]
+fn client_configuration(network: &WifiCredentials, channel: Option<u8>) -> ClientConfiguration {
+    let mut auth_method = AuthMethod::WPA2Personal;
+    if network.psk.is_empty() {
+        auth_method = AuthMethod::None;
+        info!("Wifi password is empty");
+    }
+    ClientConfiguration {
+        ssid: network.ssid.as_str().into(),
+        password: network.psk.as_str().into(),
+        channel,
+        auth_method,
+        ..Default::default()
+    }
+}
+
+// Leaves the station started but unjoined, set up for the first network for the reconnect task to keep trying. With
+// the portal, its open access point runs beside the station until the network is joined
+#[cfg(not(feature = "sim"))]
+pub fn offline(
+    esp_wifi: &mut EspWifi<'static>,
+    sysloop: EspSystemEventLoop,
+    network: Option<&WifiCredentials>,
+    portal: bool,
+) -> Result<(), Error> {
+    let mut wifi = BlockingWifi::wrap(esp_wifi, sysloop)?;
+    if wifi.is_started()? {
+        wifi.stop()?;
+    }
+    let client = network.map_or_else(ClientConfiguration::default, |network| client_configuration(network, None));
+    match portal {
+        true => wifi.set_configuration(&Configuration::Mixed(
+            client,
+            AccessPointConfiguration { ssid: AP_SSID.into(), auth_method: AuthMethod::None, ..Default::default() },
+        ))?,
+        false => wifi.set_configuration(&Configuration::Client(client))?,
+    }
+    wifi.start()?;
+    Ok(())
+}
+
+// What the reconnect task takes over when start-up carried on without the network
+#[cfg(not(feature = "sim"))]
+pub struct Offline {
+    pub portal: Option<Settings>, // Where the setup portal stores what it is given, None without NVS
+    pub on_joined: Box<dyn FnOnce() -> Option<EspMdns> + Send>, // Whatever start-up left for the network, mDNS
 }
 
+// Leaves the station started but unjoined on a fixed channel, for ESP-NOW when there is no access point to join
+#[cfg(not(feature = "sim"))]
+pub fn radio_only(esp_wifi: &mut EspWifi<'static>, sysloop: EspSystemEventLoop, channel: u8) -> Result<(), Error> {
+    let mut wifi = BlockingWifi::wrap(esp_wifi, sysloop)?;
+    if wifi.is_started()? {
+        wifi.stop()?;
+    }
+    wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
+    wifi.start()?;
+    esp!(unsafe { esp_wifi_set_channel(channel, wifi_second_chan_t_WIFI_SECOND_CHAN_NONE) })?;
+    Ok(())
+}
+
+// Between attempts to join while the network has never been joined
+#[cfg(not(feature = "sim"))]
+pub const OFFLINE_RETRY: Duration = Duration::from_secs(30);
+
+// Longest wait between reconnect attempts, the backoff doubles up to this
+#[cfg(not(feature = "sim"))]
+const MAX_RECONNECT_DELAY_MS: u32 = 30_000;
+
+// Rejoins the network whenever the station drops, the configuration from wifi() is reused. Started offline, it first
+// joins every OFFLINE_RETRY with the setup portal up meanwhile
+#[cfg(not(feature = "sim"))]
+pub fn reconnect_task(
+    mut esp_wifi: EspWifi<'static>,
+    sysloop: EspSystemEventLoop,
+    link: Link,
+    offline: Option<Offline>,
+) {
+    let (sender, disconnects) = mpsc::channel();
+    let _subscription = match sysloop.subscribe::<WifiEvent, _>(move |event| {
+        if *event == WifiEvent::StaDisconnected {
+            let _ = sender.send(());
+        }
+    }) {
+        Ok(subscription) => subscription,
+        Err(e) => {
+            error!("Failed to subscribe to WiFi events, the connection won't be restored if it drops: {}", e);
+            return;
+        }
+    };
+    let mut wifi = match BlockingWifi::wrap(&mut esp_wifi, sysloop) {
+        Ok(wifi) => wifi,
+        Err(e) => {
+            error!("Failed to wrap WiFi for reconnecting: {}", e);
+            return;
+        }
+    };
+    let _mdns = offline.and_then(|offline| {
+        join_offline(&mut wifi, &link, offline.portal, &disconnects);
+        // Reachable to update it again at last, hardware.rs held off keeping a freshly flashed image until now
+        ota::mark_valid();
+        (offline.on_joined)()
+    });
+
+    while disconnects.recv().is_ok() {
+        let mut attempt: u32 = 0;
+        loop {
+            attempt += 1;
+            link.set_down(attempt);
+            warn!("WiFi lost, reconnecting (attempt {})", attempt);
+            match wifi.connect().and_then(|_| wifi.wait_netif_up()) {
+                Ok(_) => break,
+                Err(e) => {
+                    error!("Failed to reconnect to wifi: {:?}", e);
+                    FreeRtos::delay_ms((1000 << attempt.min(5)).min(MAX_RECONNECT_DELAY_MS));
+                }
+            }
+        }
+        match wifi.wifi().sta_netif().get_ip_info() {
+            Ok(ip_info) => {
+                info!("Wifi reconnected, DHCP info: {:?}", ip_info);
+                link.set_up(ip_info.ip);
+            }
+            Err(e) => error!("Reconnected but failed to read the address: {}", e),
+        }
+        // Failed attempts raise their own disconnect events, none of them mean the new connection dropped
+        disconnects.try_iter().for_each(drop);
+    }
+}
 
-pub fn init_mdns() -> Result<esp_idf_svc::mdns::EspMdns, esp_idf_sys::EspError> {
+// What the display shows while offline, under the "WiFi failed" banner
+#[cfg(not(feature = "sim"))]
+pub fn failure_text(error: &dyn Display, portal: bool) -> String {
+    let retry = format!("{}\nretrying in {} s", error, OFFLINE_RETRY.as_secs());
+    match portal {
+        true => format!("{}\nor set up on\n{}", retry, AP_SSID),
+        false => retry,
+    }
+}
+
+// Returns once the network is joined, the portal is closed first so nobody on the network can change the credentials.
+// Should taking its access point down drop the station, that is left to the reconnect loop like any other drop
+#[cfg(not(feature = "sim"))]
+fn join_offline(
+    wifi: &mut BlockingWifi<&mut EspWifi<'static>>,
+    link: &Link,
+    portal: Option<Settings>,
+    disconnects: &Receiver<()>,
+) {
+    let mut portal = portal.and_then(|settings| match provisioning::serve() {
+        Ok((server, submissions)) => {
+            info!("Setup portal running on {}", AP_SSID);
+            Some((server, submissions, settings))
+        }
+        Err(e) => {
+            error!("Failed to start the setup portal: {}", e);
+            None
+        }
+    });
+    let mut attempt: u32 = 1; // Start-up's was the first
+    loop {
+        // The wait is spent on the portal, a submission restarts the chip to join with it
+        match portal.as_mut() {
+            Some((_, submissions, settings)) => {
+                if let Ok(submission) = submissions.recv_timeout(OFFLINE_RETRY) {
+                    provisioning::save(settings, submission);
+                }
+            }
+            None => FreeRtos::delay_ms(OFFLINE_RETRY.as_millis() as u32),
+        }
+        attempt += 1;
+        match wifi.connect().and_then(|_| wifi.wait_netif_up()) {
+            Ok(_) => break,
+            Err(e) => {
+                warn!("Failed to join WiFi (attempt {}), retrying in {} s: {}", attempt, OFFLINE_RETRY.as_secs(), e);
+                link.set_failed(attempt, failure_text(&e, portal.is_some()));
+            }
+        }
+    }
+    // The failed attempts raised disconnect events of their own
+    disconnects.try_iter().for_each(drop);
+
+    if portal.take().is_some() {
+        let client = match wifi.get_configuration() {
+            Ok(Configuration::Mixed(client, _)) => Some(client),
+            _ => None,
+        };
+        match client.map(|client| wifi.set_configuration(&Configuration::Client(client))) {
+            Some(Ok(_)) => info!("Setup portal closed"),
+            Some(Err(e)) => error!("Failed to take down the setup access point: {}", e),
+            None => {},
+        }
+    }
+    match wifi.wifi().sta_netif().get_ip_info() {
+        Ok(ip_info) => {
+            info!("Wifi joined, DHCP info: {:?}", ip_info);
+            link.set_up(ip_info.ip);
+        }
+        Err(e) => error!("Joined but failed to read the address: {}", e),
+    }
+}
+
+// Advertises the control socket. An empty hostname or instance name gets a default ending in the last three bytes
+// of the MAC, so several limbs can share a network
+#[cfg(not(feature = "sim"))]
+pub fn init_mdns(
+    port: u16,
+    hostname: &str,
+    instance: &str,
+    mac: [u8; 6],
+    servo_names: &[&str],
+) -> Result<esp_idf_svc::mdns::EspMdns, esp_idf_sys::EspError> {
+    let suffix = format!("{:02x}{:02x}{:02x}", mac[3], mac[4], mac[5]);
+    let hostname = match hostname {
+        "" => format!("limbcontroller-{}", suffix),
+        hostname => hostname.to_string(),
+    };
+    let instance = match instance {
+        "" => format!("Limb Controller {}", suffix),
+        instance => instance.to_string(),
+    };
     let mut mdns = esp_idf_svc::mdns::EspMdns::take()?;
-    mdns.set_hostname("limbcontroller")?;
+    mdns.set_hostname(&hostname)?;
+    mdns.set_instance_name(&instance)?;
+
+    let firmware = format!("{}.{}", VERSION_MAJ, VERSION_MIN);
+    let protocol_version = protocol::PROTOCOL_VERSION.to_string();
+    let controls = servo_names.len().to_string();
+    // Sequence number header plus the pose command, its count, angles and duration
+    let bytes = (protocol::HEADER_SIZE + 2 + servo_names.len() * 2 + 2).to_string();
+    let servos = servo_names.join(","); // In protocol order
     // add a custom udp service
     mdns.add_service(
-        Some("Limb Controller ESP32"),
+        Some(&instance),
         "_controller",
         "_udp",
-        8080,
+        port, // Always the port init_socket was given, so clients find the socket that is actually bound
         &[
-            ("controls", "5"), // 8 controls
-            ("bytes", "11"),
+            ("firmware", &firmware),
+            ("protocol", &protocol_version),
+            ("controls", &controls),
+            ("bytes", &bytes),
+            ("servos", &servos),
         ]
     )?;
+    info!("Advertising {} as {}.local", instance, hostname);
     Ok(mdns)
 }
 
-pub fn init_socket(read_timeout: Option<Duration>) -> std::net::UdpSocket {
-    let socket = match std::net::UdpSocket::bind("0.0.0.0:8080") {
-        Ok(socket) => socket,
-        Err(e) => panic!("Unable to bind socket on 0.0.0.0:8080 with error: {}", e), // Serious error, robot is effectively unusable
-    };
+// Binds the control socket, leaving it to the caller whether a failure is fatal
+pub fn init_socket(port: u16, read_timeout: Option<Duration>) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind((BIND_IP, port))?;
 
     match socket.set_read_timeout(read_timeout) {
         Ok(_) => {
             match read_timeout {
-                Some(timeout) => info!("Set socket read timeout to {}", timeout.as_millis()),
+                Some(timeout) => info!("Set socket read timeout to {} ms", timeout.as_millis()),
                 None => info!("Read timeout is not set"),
             }
         },
-        Err(e) => error!("Failed to set socket timeout to {}: {}", read_timeout.unwrap().as_millis(), e)
+        Err(e) => match read_timeout {
+            Some(timeout) => error!("Failed to set socket timeout to {} ms: {}", timeout.as_millis(), e),
+            None => error!("Failed to clear socket timeout: {}", e),
+        },
     };
 
-
     match socket.set_nonblocking(false) {
-        Ok(_) => info!("Socket set to non-blocking."),
-        Err(e) => error!("Failed to set the socket to non-blocking: {}", e),
+        Ok(_) => info!("Socket set to blocking."),
+        Err(e) => error!("Failed to set the socket to blocking: {}", e),
     };
 
-    socket
-}
\ No newline at end of file
+    info!("Socket bound to {}:{}", BIND_IP, port);
+    Ok(socket)
+}
@@ -0,0 +1,159 @@
+// WebSocket control channel at /ws for browser clients, taking the same frames as UDP packets under the browser's
+// own address and pushing telemetry to every open socket once a second
+use std::mem::ManuallyDrop;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::os::fd::FromRawFd;
+use std::sync::mpsc::SyncSender;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use embedded_svc::ws::FrameType;
+use esp_idf_svc::http::server::ws::{EspHttpWsConnection, EspHttpWsDetachedSender};
+use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_sys::EspError;
+use log::{info, warn};
+
+use crate::auth::{self, Authenticator};
+use crate::network::{self, Command, NO_ADDR};
+use crate::protocol::{self, ControlPacket, ReplyPacket, ReplyPayload, Status};
+use crate::sequence::SequenceTracker;
+use crate::tasks;
+
+const MAX_FRAME_SIZE: usize = protocol::HEADER_SIZE + protocol::MAX_COMMAND_SIZE + auth::AUTH_SIZE + protocol::CRC_SIZE;
+const TELEMETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+// Open sockets by session, the detached senders let the telemetry task push to them
+type Sockets = Arc<Mutex<Vec<(i32, EspHttpWsDetachedSender)>>>;
+
+pub fn register(
+    server: &mut EspHttpServer<'static>,
+    commands: SyncSender<Command>,
+    authenticator: Option<Arc<Mutex<Authenticator>>>,
+) -> Result<(), EspError> {
+    let sockets = Sockets::default();
+    let sequences = Mutex::new(SequenceTracker::new());
+    let handler_sockets = sockets.clone();
+    let handler_commands = commands.clone();
+    server.ws_handler("/ws", move |ws| {
+        handle(ws, &handler_commands, authenticator.as_deref(), &sequences, &handler_sockets)
+    })?;
+    tasks::spawn(&tasks::WS_TASK, move || telemetry_task(commands, sockets));
+    info!("WebSocket control channel on /ws");
+    Ok(())
+}
+
+// Called by the server for each opened socket, each message and each close
+fn handle(
+    ws: &mut EspHttpWsConnection,
+    commands: &SyncSender<Command>,
+    authenticator: Option<&Mutex<Authenticator>>,
+    sequences: &Mutex<SequenceTracker>,
+    sockets: &Sockets,
+) -> Result<(), EspError> {
+    let session = ws.session();
+    if ws.is_new() {
+        info!("WebSocket client {} connected", describe(peer_addr(session)));
+        let sender = ws.create_detached_sender()?;
+        lock(sockets).push((session, sender));
+        return Ok(());
+    }
+    if ws.is_closed() {
+        lock(sockets).retain(|(open, _)| *open != session);
+        info!("WebSocket client {} disconnected", describe(peer_addr(session)));
+        return Ok(());
+    }
+
+    // The length comes first, a message too large for any frame closes the socket rather than being read
+    let (frame_type, len) = ws.recv(&mut [])?;
+    if len > MAX_FRAME_SIZE {
+        warn!("WebSocket message of {} bytes from {}, closing", len, describe(peer_addr(session)));
+        return ws.send(FrameType::Close, &[]);
+    }
+    let mut buf = [0u8; MAX_FRAME_SIZE];
+    ws.recv(&mut buf[..len])?;
+    if frame_type != FrameType::Binary(false) {
+        return Ok(()); // Text and fragmented messages aren't frames
+    }
+    let Some(addr) = peer_addr(session) else {
+        warn!("No address for WebSocket session {}, ignoring its message", session);
+        return Ok(());
+    };
+
+    let (sequence, reply) = match network::decode_frame(&buf[..len], authenticator) {
+        Ok((sequence, control)) => {
+            {
+                let mut sequences = lock(sequences);
+                // A ping starts a fresh sequence, like it does for UDP
+                if control == ControlPacket::Ping {
+                    sequences.reset(addr);
+                }
+                if !sequences.accept(addr, sequence) {
+                    warn!("Discarding stale WebSocket frame {} from {}", sequence, addr);
+                    return Ok(());
+                }
+            }
+            (sequence, answer(commands, addr, control))
+        }
+        Err(refused) => {
+            warn!("Refused WebSocket frame from {}: {:?}", addr, refused.error);
+            (refused.sequence, ReplyPacket::new(refused.command, refused.status(), ReplyPayload::Empty))
+        }
+    };
+    ws.send(FrameType::Binary(false), &network::encode_reply(sequence, &reply))
+}
+
+// Heartbeats are sent to the subscriber's UDP port, which a browser doesn't have. It gets the pushed telemetry instead
+fn answer(commands: &SyncSender<Command>, addr: SocketAddr, control: ControlPacket) -> ReplyPacket {
+    let command = control.command();
+    if matches!(control, ControlPacket::Subscribe { .. }) {
+        return ReplyPacket::new(command, Status::BadCommand, ReplyPayload::Empty);
+    }
+    match network::request(commands, addr, control) {
+        Ok(reply) => reply,
+        Err(e) => {
+            warn!("WebSocket command {} from {} got no answer: {:?}", command, addr, e);
+            ReplyPacket::new(command, Status::Busy, ReplyPayload::Empty)
+        }
+    }
+}
+
+// Sockets that fail a send are dropped, the server reports the close to the handler as well
+fn telemetry_task(commands: SyncSender<Command>, sockets: Sockets) {
+    loop {
+        thread::sleep(TELEMETRY_INTERVAL);
+        if lock(&sockets).is_empty() {
+            continue;
+        }
+        let frame = match network::request(&commands, NO_ADDR, ControlPacket::Telemetry { tenths: false }) {
+            Ok(reply) => network::encode_reply(0, &reply),
+            Err(e) => {
+                warn!("No telemetry to push: {:?}", e);
+                continue;
+            }
+        };
+        lock(&sockets).retain_mut(|(_, sender)| sender.send(FrameType::Binary(false), &frame).is_ok());
+    }
+}
+
+// The server listens on IPv6 as well, so IPv4 clients show up mapped and are turned back into the address UDP sees
+fn peer_addr(session: i32) -> Option<SocketAddr> {
+    // The session is the connection's socket, borrowed here and left open for the server
+    let stream = ManuallyDrop::new(unsafe { TcpStream::from_raw_fd(session) });
+    let addr = stream.peer_addr().ok()?;
+    match addr.ip() {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => Some(SocketAddr::new(IpAddr::V4(ip), addr.port())),
+            None => Some(addr),
+        },
+        IpAddr::V4(_) => Some(addr),
+    }
+}
+
+fn describe(addr: Option<SocketAddr>) -> String {
+    addr.map_or_else(|| "?".to_string(), |addr| addr.to_string())
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}